@@ -1,14 +1,45 @@
 use criterion::{criterion_group, criterion_main, BenchmarkGroup, Criterion};
 use keyword_pir_lwe::api::{
-  generate_index_query_params, generate_kv_query_params, BaseParams,
-  CommonParams, KVShard, Response, Shard,
+  bytes_from_u32_slice, generate_index_query_params, generate_kv_query_params,
+  split_bits_le, BaseParams, CommonParams, KVShard, Modulus, Response,
+  RoundingMode, Shard,
 };
 use keyword_pir_lwe::db::{DatabaseMatrix, KeyValue};
+use keyword_pir_lwe::linalg::{
+  generate_lwe_matrix_flat, generate_lwe_matrix_from_seed, FillOrder,
+};
 use pi_rs_cli_utils::*;
 use std::time::Duration;
 
 use keyword_pir_lwe::db::FilterParams;
 
+/// The number of secrets used when benchmarking `mult_left_batch`
+const QUERY_PARAMS_BATCH: usize = 8;
+
+/// The width of the rows decoded by `_bench_client_decode`, i.e. a wide
+/// record of a constrained client
+const DECODE_ROW_WIDTH: usize = 1 << 16;
+
+/// The client memory budget of a mobile-class device, against which the
+/// memory used by in-place query generation is reported
+const LOWMEM_BUDGET_BYTES: usize = 64 << 20;
+
+/// Reports the client memory needed to generate queries, with and
+/// without the expanded `CommonParams`. In-place query generation (see
+/// `generate_query_into`) needs the LHS and RHS of the `QueryParams`
+/// (which can be precomputed with a `ClientHint`) and one query buffer.
+fn report_lowmem_budget(dim: usize, height: usize, width: usize) {
+  let lowmem = 4 * (2 * height + width);
+  let with_cp = lowmem + 4 * dim * height;
+  println!(
+    "Client query memory: {} KiB in place, {} KiB with CommonParams (budget: {} KiB, fits: {})",
+    lowmem >> 10,
+    with_cp >> 10,
+    LOWMEM_BUDGET_BYTES >> 10,
+    lowmem <= LOWMEM_BUDGET_BYTES
+  );
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
   let CLIFlags {
     m,
@@ -34,7 +65,6 @@ fn criterion_benchmark(c: &mut Criterion) {
       &keys,
       &values,
       lwe_dim,
-      m,
       elem_size,
       plaintext_bits,
     )
@@ -67,7 +97,10 @@ fn criterion_benchmark(c: &mut Criterion) {
     println!("[I] Setup complete, starting benchmarks");
 
     println!("[I] Benchmarking online steps...");
+    _bench_matrix_generation(&mut lwe_group, &shard);
+    _bench_bit_conversion(&mut lwe_group, &db_eles, elem_size, plaintext_bits);
     _bench_client_query(&mut lwe_group, &shard);
+    _bench_client_decode(&mut lwe_group, plaintext_bits);
 
     if offline {
       println!("[I] Benchmarking offline steps...");
@@ -160,7 +193,6 @@ fn _bench_kv_db_generation(
           keys,
           values,
           bp.get_dim(),
-          db.get_matrix_height(),
           db.get_elem_size(),
           db.get_plaintext_bits(),
         )
@@ -171,6 +203,83 @@ fn _bench_kv_db_generation(
   println!("[KV] Finished DB generation benchmarks");
 }
 
+fn _bench_matrix_generation(
+  c: &mut BenchmarkGroup<criterion::measurement::WallTime>,
+  shard: &Shard,
+) {
+  let bp = shard.get_base_params();
+  let (seed, dim, m) = (
+    bp.get_public_seed(),
+    bp.get_dim(),
+    shard.get_db().get_matrix_height(),
+  );
+  c.bench_function(
+    format!("expand lwe matrix nested, lwe_dim: {}, m: {}", dim, m),
+    |b| {
+      b.iter(|| generate_lwe_matrix_from_seed(seed, dim, m));
+    },
+  );
+  for order in [FillOrder::ColumnMajor, FillOrder::RowMajor] {
+    c.bench_function(
+      format!(
+        "expand lwe matrix flat {:?}, lwe_dim: {}, m: {}",
+        order, dim, m
+      ),
+      |b| {
+        b.iter(|| generate_lwe_matrix_flat(seed, dim, m, order));
+      },
+    );
+  }
+}
+
+/// Benchmarks splitting the DB elements into the entries of their rows
+/// (as when building the DB), and packing the entries back into bytes (as
+/// when decoding responses)
+fn _bench_bit_conversion(
+  c: &mut BenchmarkGroup<criterion::measurement::WallTime>,
+  db_eles: &[String],
+  elem_size: usize,
+  plaintext_bits: usize,
+) {
+  let elements: Vec<Vec<u8>> =
+    db_eles.iter().map(|e| base64::decode(e).unwrap()).collect();
+  let column_bits = vec![plaintext_bits; elem_size.div_ceil(plaintext_bits)];
+  c.bench_function(
+    format!(
+      "split elements into rows, m: {}, elem_size: {}, plaintext_bits: {}",
+      elements.len(),
+      elem_size,
+      plaintext_bits
+    ),
+    |b| {
+      b.iter(|| {
+        for e in &elements {
+          split_bits_le(e, &column_bits).unwrap();
+        }
+      });
+    },
+  );
+  let rows: Vec<Vec<u32>> = elements
+    .iter()
+    .map(|e| split_bits_le(e, &column_bits).unwrap())
+    .collect();
+  c.bench_function(
+    format!(
+      "pack rows into bytes, m: {}, elem_size: {}, plaintext_bits: {}",
+      rows.len(),
+      elem_size,
+      plaintext_bits
+    ),
+    |b| {
+      b.iter(|| {
+        for row in &rows {
+          bytes_from_u32_slice(row, plaintext_bits, elem_size);
+        }
+      });
+    },
+  );
+}
+
 fn _bench_client_query(
   c: &mut BenchmarkGroup<criterion::measurement::WallTime>,
   shard: &Shard,
@@ -197,6 +306,37 @@ fn _bench_client_query(
     },
   );
 
+  let secrets: Vec<Vec<u32>> = (0..QUERY_PARAMS_BATCH)
+    .map(|j| (0..bp.get_dim()).map(|i| ((i + j) % 3) as u32).collect())
+    .collect();
+  c.bench_function(
+    format!(
+      "create {} client query lhs one by one, lwe_dim: {}, m: {}",
+      QUERY_PARAMS_BATCH,
+      bp.get_dim(),
+      db.get_matrix_height(),
+    ),
+    |b| {
+      b.iter(|| {
+        for s in &secrets {
+          cp.mult_left(s).unwrap();
+        }
+      });
+    },
+  );
+
+  c.bench_function(
+    format!(
+      "create {} client query lhs batched, lwe_dim: {}, m: {}",
+      QUERY_PARAMS_BATCH,
+      bp.get_dim(),
+      db.get_matrix_height(),
+    ),
+    |b| {
+      b.iter(|| cp.mult_left_batch(&secrets).unwrap());
+    },
+  );
+
   c.bench_function(
     format!(
       "client query prepare, lwe_dim: {}, m: {}, w: {}",
@@ -206,12 +346,29 @@ fn _bench_client_query(
     ),
     |b| {
       b.iter(|| {
-        _qp.used = false;
+        _qp.unsafe_reset_for_benchmarks();
         _qp.generate_query(idx).unwrap();
       });
     },
   );
 
+  report_lowmem_budget(bp.get_dim(), db.get_matrix_height(), w);
+  let mut buf = vec![0u32; db.get_matrix_height()];
+  c.bench_function(
+    format!(
+      "client query prepare in place, lwe_dim: {}, m: {}, w: {}",
+      bp.get_dim(),
+      db.get_matrix_height(),
+      w
+    ),
+    |b| {
+      b.iter(|| {
+        _qp.unsafe_reset_for_benchmarks();
+        _qp.generate_query_into(idx, &mut buf).unwrap();
+      });
+    },
+  );
+
   c.bench_function(
     format!(
       "server response compute, lwe_dim: {}, m: {}, w: {}",
@@ -243,6 +400,42 @@ fn _bench_client_query(
   println!("Finished client query benchmarks");
 }
 
+fn _bench_client_decode(
+  c: &mut BenchmarkGroup<criterion::measurement::WallTime>,
+  plaintext_bits: usize,
+) {
+  let modulus = Modulus::default();
+  let residues: Vec<u32> = (0..DECODE_ROW_WIDTH as u32)
+    .map(|i| i.wrapping_mul(0x9e37_79b9))
+    .collect();
+  for mode in [RoundingMode::Nearest, RoundingMode::Floor] {
+    c.bench_function(
+      format!(
+        "client decode row per slot, w: {}, rounding: {:?}",
+        DECODE_ROW_WIDTH, mode
+      ),
+      |b| {
+        b.iter(|| {
+          residues
+            .iter()
+            .map(|&r| modulus.round_residue(r, plaintext_bits, mode))
+            .collect::<Vec<u32>>()
+        });
+      },
+    );
+
+    c.bench_function(
+      format!(
+        "client decode row batched, w: {}, rounding: {:?}",
+        DECODE_ROW_WIDTH, mode
+      ),
+      |b| {
+        b.iter(|| modulus.round_residues(&residues, plaintext_bits, mode));
+      },
+    );
+  }
+}
+
 fn _bench_client_kv_query(
   c: &mut BenchmarkGroup<criterion::measurement::WallTime>,
   shard: &KVShard,
@@ -254,6 +447,7 @@ fn _bench_client_kv_query(
     segment_length,
     segment_length_mask,
     segment_count_length,
+    ..
   } = db.get_filter_params();
 
   println!(
@@ -298,12 +492,29 @@ fn _bench_client_kv_query(
     ),
     |b| {
       b.iter(|| {
-        _qp.used = false;
+        _qp.unsafe_reset_for_benchmarks();
         _qp.generate_query(&kv.key).unwrap();
       });
     },
   );
 
+  report_lowmem_budget(bp.get_dim(), db.get_matrix_height(), w);
+  let mut buf = vec![0u32; db.get_matrix_height()];
+  c.bench_function(
+    format!(
+      "[KV] create client query prepare in place, lwe_dim: {}, matrix_height: {}, omega: {}",
+      bp.get_dim(),
+      db.get_matrix_height(),
+      w
+    ),
+    |b| {
+      b.iter(|| {
+        _qp.unsafe_reset_for_benchmarks();
+        _qp.generate_query_into(&kv.key, &mut buf).unwrap();
+      });
+    },
+  );
+
   c.bench_function(
     format!(
       "[KV] server response, lwe_dim: {}, matrix_height: {}, omega: {}",
@@ -336,6 +547,7 @@ fn _bench_client_kv_query(
 }
 
 mod bench_utils {
+  use keyword_pir_lwe::synthetic::{SyntheticConfig, ValueSize};
   use rand_core::{OsRng, RngCore};
 
   pub fn generate_db_eles(num_eles: usize, ele_byte_len: usize) -> Vec<String> {
@@ -353,16 +565,15 @@ mod bench_utils {
     num_eles: usize,
     ele_byte_len: usize,
   ) -> Vec<(String, String)> {
-    let mut v = Vec::with_capacity(num_eles);
-    for _ in 0..num_eles {
-      let mut key = vec![0u8; 32];
-      let mut ele = vec![0u8; ele_byte_len];
-      OsRng.fill_bytes(&mut key);
-      OsRng.fill_bytes(&mut ele);
-      let key_str = base64::encode(key);
-      let ele_str = base64::encode(ele);
-      v.push((key_str, ele_str));
-    }
-    v
+    let dataset =
+      SyntheticConfig::new(num_eles, ValueSize::Fixed(ele_byte_len))
+        .generate()
+        .unwrap();
+    dataset
+      .get_keys()
+      .iter()
+      .cloned()
+      .zip(dataset.padded_values())
+      .collect()
   }
 }
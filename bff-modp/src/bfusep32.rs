@@ -1,11 +1,94 @@
 //! Implements BinaryFuse16 filters.
 
-use crate::{bfusep_retrieve_impl, bfusep_from_impl, bfusep_hash_eval_impl, Filter, bfusep_key_fingerprint_impl};
+use crate::{bfusep_retrieve_impl, bfusep_from_impl, bfusep_hash_eval_impl, RetrievalFilter, bfusep_key_fingerprint_impl};
 use alloc::{boxed::Box, vec::Vec};
+use core::convert::TryInto;
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
+/// The number of construction attempts made by `BinaryFuseP32::from_slice`
+pub const DEFAULT_MAX_ITERATIONS: usize = 100_000;
+
+/// The magic bytes that start a serialized `BinaryFuseP32`
+const MAGIC: [u8; 4] = *b"BFP3";
+
+/// The version of the serialization of `BinaryFuseP32`s
+pub const SERIALIZATION_VERSION: u32 = 1;
+
+/// The length of the header of a serialized `BinaryFuseP32`: the magic bytes, the version, the
+/// seed, the three segment parameters, the plaintext modulus and the number of fingerprints
+const HEADER_LEN: usize = 4 + 4 + 32 + 3 * 4 + 8 + 8;
+
+/// The length of the checksum that ends a serialized `BinaryFuseP32`
+const CHECKSUM_LEN: usize = 8;
+
+/// Computes the checksum of serialized bytes by mixing them in as little-endian 64-bit words.
+/// It detects accidental modifications (e.g. truncation or bit flips), not malicious ones.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.chunks(8).fold(bytes.len() as u64, |acc, chunk| {
+        let mut word = [0u8; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        crate::prelude::mix(acc ^ u64::from_le_bytes(word), 0x9e37_79b9_7f4a_7c15)
+    })
+}
+
+/// A keyed function deriving the mask that is added to the data of a key from the hash of the
+/// key and a label. Filters must be queried using the same function (and key) they were
+/// constructed with.
+pub trait FingerprintPrf {
+    /// Returns the mask for the key hash `hash` and the `label`
+    fn fingerprint(&self, hash: u64, label: u64) -> u64;
+}
+
+/// The default `FingerprintPrf`, mixing the hash and label using the MurmurHash3 finalizer. It
+/// is unkeyed, so masks only depend on the (public) filter seed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MixPrf;
+
+impl FingerprintPrf for MixPrf {
+    fn fingerprint(&self, hash: u64, label: u64) -> u64 {
+        crate::prelude::mix(hash, label)
+    }
+}
+
+/// A `FingerprintPrf` evaluating SipHash-2-4, keyed by `key`, on the hash and label
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SipHashPrf {
+    /// The SipHash key
+    pub key: [u8; 16],
+}
+
+impl FingerprintPrf for SipHashPrf {
+    fn fingerprint(&self, hash: u64, label: u64) -> u64 {
+        crate::siphash::siphash24(&self.key, &[hash, label])
+    }
+}
+
+/// Configures the construction of a `BinaryFuseP32` filter.
+///
+/// Each construction attempt uses a new seed, and fails with (small) probability depending on
+/// the number of keys and the size factor (the ratio of fingerprints to keys). For pathological
+/// key sets, a larger size factor makes each attempt more likely to succeed at the cost of a
+/// larger filter, and a smaller number of iterations bounds the construction time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FilterConfig {
+    /// The maximum number of construction attempts
+    pub max_iterations: usize,
+    /// The size factor to use instead of the one derived from the number of keys. Must be at
+    /// least `1.0`.
+    pub size_factor_override: Option<f64>,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            size_factor_override: None,
+        }
+    }
+}
+
 /// A `BinaryFuseP32` filter is an Xor-like filter with 32-bit fingerprints arranged in a binary-partitioned [fuse graph].
 /// `BinaryFuseP32`s are similar to [`Fuse32`]s, but their construction is faster, uses less
 /// memory, and is more likely to succeed.
@@ -20,7 +103,7 @@ use serde::{Deserialize, Serialize};
 ///
 /// ```
 /// # extern crate alloc;
-/// use xorf::{Filter, BinaryFuseP32};
+/// use xorf::{RetrievalFilter, BinaryFuseP32};
 /// # use alloc::vec::Vec;
 /// # use rand::Rng;
 /// # use rand_core::{OsRng, RngCore};
@@ -61,10 +144,9 @@ pub struct BinaryFuseP32 {
     ptxt_mod: u64,
 }
 
-impl Filter<u64> for BinaryFuseP32 {
-    /// unimplemented
-    fn contains(&self, _: &u64) -> bool {
-        unimplemented!();
+impl RetrievalFilter<[u64; 4]> for BinaryFuseP32 {
+    fn retrieve(&self, key: &[u64; 4], label: u64) -> u32 {
+        bfusep_retrieve_impl!(key, label, self)
     }
 
     fn len(&self) -> usize {
@@ -78,23 +160,80 @@ impl BinaryFuseP32 {
         if data.len() != keys.len() {
             return Err("The data should correspond to the number of keys");
         }
-        bfusep_from_impl!(seed, keys, data, ptxt_mod, label, max iter 100_000)
+        bfusep_from_impl!(seed, keys, data, ptxt_mod, label, max iter DEFAULT_MAX_ITERATIONS)
+    }
+
+    /// Same as `from_slice`, filling the unused fingerprint entries from an RNG seeded with
+    /// `fill_seed` rather than a thread-local RNG, so that construction is deterministic. The fill
+    /// seed should be secret and distinct per filter, as it determines the unused entries.
+    pub fn from_slice_with_fill_seed(seed: [u8; 32], keys: &[[u64; 4]], data: &[u32], label: u64, ptxt_mod: u64, fill_seed: [u8; 32]) -> Result<Self, &'static str> {
+        if data.len() != keys.len() {
+            return Err("The data should correspond to the number of keys");
+        }
+        bfusep_from_impl!(seed, keys, data, ptxt_mod, label, Some(fill_seed), max iter DEFAULT_MAX_ITERATIONS)
+    }
+
+    /// Same as `from_slice`, constructing the filter according to `config`. If `fill_seed` is
+    /// set, the unused fingerprint entries are filled as in `from_slice_with_fill_seed`.
+    pub fn from_slice_with_config(seed: [u8; 32], keys: &[[u64; 4]], data: &[u32], label: u64, ptxt_mod: u64, fill_seed: Option<[u8; 32]>, config: &FilterConfig) -> Result<Self, &'static str> {
+        if data.len() != keys.len() {
+            return Err("The data should correspond to the number of keys");
+        }
+        Self::from_slice_with_prf(seed, keys, data, label, ptxt_mod, fill_seed, config, &MixPrf)
+    }
+
+    /// Same as `from_slice_with_config`, masking the data using `prf` rather than `MixPrf`. The
+    /// filter must then be queried using `retrieve_with_prf`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_slice_with_prf<P: FingerprintPrf>(seed: [u8; 32], keys: &[[u64; 4]], data: &[u32], label: u64, ptxt_mod: u64, fill_seed: Option<[u8; 32]>, config: &FilterConfig, prf: &P) -> Result<Self, &'static str> {
+        if data.len() != keys.len() {
+            return Err("The data should correspond to the number of keys");
+        }
+        if let Some(factor) = config.size_factor_override {
+            if !(factor >= 1.0 && factor.is_finite()) {
+                return Err("The size factor must be a finite value of at least 1");
+            }
+        }
+        bfusep_from_impl!(seed, keys, data, ptxt_mod, label, fill_seed, config.size_factor_override, prf, max iter config.max_iterations)
     }
 
     /// Creates a new `BinaryFuseP32` filter from the specified `keys` (as a vector), `data`, `ptxt_mod`
     pub fn from_vec(seed: [u8; 32], keys: Vec<[u64; 4]>, data: &[u32], label: u64, ptxt_mod: u64) -> Result<Self, &'static str> {
         let slice = keys.as_slice();
-        bfusep_from_impl!(seed, slice, data, ptxt_mod, label, max iter 100_000)
+        bfusep_from_impl!(seed, slice, data, ptxt_mod, label, max iter DEFAULT_MAX_ITERATIONS)
     }
 
-    /// Retrieves the `data` modulo the plaintext modulus for a given `key`
-    pub fn retrieve(&self, key: &[u64; 4], label: u64) -> u32 {
-        bfusep_retrieve_impl!(key, label, self)
+    /// Returns the number of fingerprints of a filter constructed from `num_keys` keys according
+    /// to `config`, without constructing it. This follows the sizing of `bfusep_from_impl`.
+    pub fn array_len(num_keys: usize, config: &FilterConfig) -> usize {
+        use crate::prelude::bfuse::{segment_length, size_factor};
+        let arity = 3u32;
+        let segment_length = segment_length(arity, num_keys as u32).min(262144);
+        let size_factor = config.size_factor_override.unwrap_or_else(|| size_factor(arity, num_keys as u32));
+        let capacity = if num_keys > 1 { libm::round(num_keys as f64 * size_factor) as u32 } else { 0 };
+        let segment_count = capacity.div_ceil(segment_length).saturating_sub(arity - 1).max(1);
+        ((segment_count + arity - 1) * segment_length) as usize
+    }
+
+    /// Returns the plaintext modulus that data is retrieved modulo
+    pub fn ptxt_mod(&self) -> u64 {
+        self.ptxt_mod
+    }
+
+    /// Returns the seed used by the filter. This may differ from the seed passed on construction,
+    /// as every failed construction attempt derives a new seed.
+    pub fn seed(&self) -> [u8; 32] {
+        self.seed
+    }
+
+    /// Same as `retrieve`, for filters constructed using `prf`
+    pub fn retrieve_with_prf<P: FingerprintPrf>(&self, key: &[u64; 4], label: u64, prf: &P) -> u32 {
+        bfusep_retrieve_impl!(key, label, self, prf)
     }
 
     /// Returns the `fingerprints`, of the filter, but modulo the plaintetx modulus
     pub fn get_fingerprints_mod(&self) -> Vec<u32> {
-        self.fingerprints.into_iter().map(|f| f % (self.ptxt_mod as u32)).collect()
+        self.fingerprints.into_iter().map(|f| (*f as u64 % self.ptxt_mod) as u32).collect()
     }
 
     /// Static function that retrieves the hash function evaluations for a given storage filter
@@ -102,15 +241,109 @@ impl BinaryFuseP32 {
         bfusep_hash_eval_impl!(key, seed, segment_length, segment_length_mask, segment_count_length)
     }
     
+    /// Serializes the filter into a compact little-endian layout: the magic bytes `BFP3` and
+    /// the serialization version, the seed, `segment_length`, `segment_length_mask`,
+    /// `segment_count_length` (as `u32`s), the plaintext modulus and the number of fingerprints
+    /// (as `u64`s), followed by the fingerprints (as `u32`s) and a `u64` checksum of all
+    /// preceding bytes. Unlike the `serde` serialization, this does not depend on any feature.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + 4 * self.fingerprints.len() + CHECKSUM_LEN);
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&SERIALIZATION_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.seed);
+        out.extend_from_slice(&self.segment_length.to_le_bytes());
+        out.extend_from_slice(&self.segment_length_mask.to_le_bytes());
+        out.extend_from_slice(&self.segment_count_length.to_le_bytes());
+        out.extend_from_slice(&self.ptxt_mod.to_le_bytes());
+        out.extend_from_slice(&(self.fingerprints.len() as u64).to_le_bytes());
+        for f in self.fingerprints.iter() {
+            out.extend_from_slice(&f.to_le_bytes());
+        }
+        let checksum = checksum(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    /// Deserializes a filter serialized using `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        if bytes.len() < HEADER_LEN + CHECKSUM_LEN {
+            return Err("Serialized filter is too short");
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        if bytes[..4] != MAGIC {
+            return Err("Serialized filter has unknown magic bytes");
+        }
+        if u32_at(4) != SERIALIZATION_VERSION {
+            return Err("Serialized filter has an unsupported version");
+        }
+        let (body, tail) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+        if checksum(body) != u64::from_le_bytes(tail.try_into().unwrap()) {
+            return Err("Serialized filter has an invalid checksum");
+        }
+        let seed: [u8; 32] = bytes[8..40].try_into().unwrap();
+        let segment_length = u32_at(40);
+        let segment_length_mask = u32_at(44);
+        let segment_count_length = u32_at(48);
+        let ptxt_mod = u64_at(52);
+        let len = u64_at(60);
+        if !segment_length.is_power_of_two() || segment_length_mask != segment_length - 1 {
+            return Err("Serialized filter has invalid segment parameters");
+        }
+        if ptxt_mod == 0 || ptxt_mod > 1 << 32 {
+            return Err("Serialized filter has an invalid plaintext modulus");
+        }
+        // the hash evaluations index up to two segments past `segment_count_length`
+        if len != segment_count_length as u64 + 2 * segment_length as u64 {
+            return Err("Serialized filter has an invalid number of fingerprints");
+        }
+        if (body.len() - HEADER_LEN) as u64 != 4 * len {
+            return Err("Serialized filter has an invalid length");
+        }
+        let fingerprints: Box<[u32]> = body[HEADER_LEN..]
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        Ok(Self {
+            seed,
+            segment_length,
+            segment_length_mask,
+            segment_count_length,
+            fingerprints,
+            ptxt_mod,
+        })
+    }
+
+    /// Same as `hash_eval`, for many keys at once. The seed is parsed once, and the hashes of
+    /// all keys are computed in a single pass before they are mapped to indices, so that the
+    /// per-key overhead is avoided.
+    pub fn hash_eval_batch(keys: &[[u64; 4]], seed: [u8; 32], segment_length: u32, segment_length_mask: u32, segment_count_length: u32) -> Vec<Vec<usize>> {
+        use crate::prelude::{bfuse::hash_of_hash, mix256_words, seed_words};
+        let seeds = seed_words(&seed);
+        let hashes: Vec<u64> = keys.iter().map(|k| mix256_words(k, &seeds)).collect();
+        hashes
+            .into_iter()
+            .map(|hash| {
+                let (h0, h1, h2) = hash_of_hash(hash, segment_length, segment_length_mask, segment_count_length);
+                vec![h0 as usize, h1 as usize, h2 as usize]
+            })
+            .collect()
+    }
+
     /// Static function that outputs the `u64` fingerprint of a `key`, wrt to a `seed` and a `label`
     pub fn get_key_fingerprint(key: &[u64; 4], seed: [u8; 32], label: u64) -> u64 {
         bfusep_key_fingerprint_impl!(key, seed, label)
     }
+
+    /// Same as `get_key_fingerprint`, for filters constructed using `prf`
+    pub fn get_key_fingerprint_with_prf<P: FingerprintPrf>(key: &[u64; 4], seed: [u8; 32], label: u64, prf: &P) -> u64 {
+        bfusep_key_fingerprint_impl!(key, seed, label, prf)
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{BinaryFuseP32, Filter};
+    use crate::{BinaryFuseP32, FilterConfig, RetrievalFilter, SipHashPrf};
 
     use alloc::vec::Vec;
     use rand::{Rng, RngCore};
@@ -134,6 +367,31 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_retrieval_filter_trait() {
+        const SAMPLE_SIZE: usize = 10_000;
+        const PTXT_MOD: u64 = 1024;
+        let mut rng = rand::thread_rng();
+        let keys: Vec<[u64; 4]> = (0..SAMPLE_SIZE).map(|_| [rng.gen(); 4]).collect();
+        let data: Vec<u32> = (0..SAMPLE_SIZE).map(|_| rng.gen_range(0..PTXT_MOD as u32)).collect();
+        let label = 7u64;
+        let filter = BinaryFuseP32::from_slice([3u8; 32], &keys, &data, label, PTXT_MOD).unwrap();
+
+        // generic users retrieve through the trait, including as a trait object
+        fn retrieve_all<F: RetrievalFilter<[u64; 4]> + ?Sized>(filter: &F, keys: &[[u64; 4]], label: u64) -> Vec<u32> {
+            keys.iter().map(|key| filter.retrieve(key, label)).collect()
+        }
+        let dyn_filter: &dyn RetrievalFilter<[u64; 4]> = &filter;
+        assert_eq!(retrieve_all(&filter, &keys, label), data);
+        assert_eq!(retrieve_all(dyn_filter, &keys, label), data);
+        assert_eq!(dyn_filter.len(), filter.fingerprints.len());
+        assert!(dyn_filter.len() >= SAMPLE_SIZE);
+
+        // other keys retrieve arbitrary data, which is still reduced modulo the plaintext modulus
+        let others: Vec<[u64; 4]> = (0..1000).map(|_| [rng.gen(); 4]).collect();
+        assert!(retrieve_all(&filter, &others, label).iter().all(|&v| (v as u64) < PTXT_MOD));
+    }
+
     #[test]
     fn test_hashes() {
         const SAMPLE_SIZE: usize = 1_000_000;
@@ -147,6 +405,7 @@ mod test {
 
         let filter = BinaryFuseP32::from_slice(seed, &keys, &data, label, PTXT_MOD).unwrap();
 
+        let seed = filter.seed();
         for i in 0..keys.len() {
             let h = BinaryFuseP32::hash_eval(&keys[i], seed, filter.segment_length, filter.segment_length_mask, filter.segment_count_length);
             let entry = h.iter().fold(0u32, |acc, r| acc.wrapping_add(filter.fingerprints[*r]));
@@ -155,6 +414,173 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_fill_seed_is_deterministic() {
+        const SAMPLE_SIZE: usize = 10_000;
+        const PTXT_MOD: u64 = 1024;
+        let mut rng = rand::thread_rng();
+        let keys: Vec<[u64; 4]> = (0..SAMPLE_SIZE).map(|_| [rng.gen(); 4]).collect();
+        let label = 1u64;
+        let data: Vec<u32> = (0..SAMPLE_SIZE).map(|i| (i as u32) % (PTXT_MOD as u32)).collect();
+        let seed = [3u8; 32];
+
+        let build = |fill_seed| BinaryFuseP32::from_slice_with_fill_seed(seed, &keys, &data, label, PTXT_MOD, fill_seed).unwrap();
+        let (filter, same, other) = (build([1u8; 32]), build([1u8; 32]), build([2u8; 32]));
+        assert_eq!(filter.fingerprints, same.fingerprints);
+        assert_ne!(filter.fingerprints, other.fingerprints);
+        for i in 0..keys.len() {
+            assert_eq!(data[i], filter.retrieve(&keys[i], label));
+            assert_eq!(data[i], other.retrieve(&keys[i], label));
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        const SAMPLE_SIZE: usize = 10_000;
+        const PTXT_MOD: u64 = 1024;
+        let mut rng = rand::thread_rng();
+        let keys: Vec<[u64; 4]> = (0..SAMPLE_SIZE).map(|_| [rng.gen(); 4]).collect();
+        let label = 1u64;
+        let data: Vec<u32> = (0..SAMPLE_SIZE).map(|i| (i as u32) % (PTXT_MOD as u32)).collect();
+        let seed = [5u8; 32];
+
+        let default = BinaryFuseP32::from_slice_with_config(seed, &keys, &data, label, PTXT_MOD, None, &FilterConfig::default()).unwrap();
+        let config = FilterConfig { size_factor_override: Some(2.0), ..Default::default() };
+        let larger = BinaryFuseP32::from_slice_with_config(seed, &keys, &data, label, PTXT_MOD, None, &config).unwrap();
+        assert!(larger.len() > default.len());
+        for i in 0..keys.len() {
+            assert_eq!(data[i], default.retrieve(&keys[i], label));
+            assert_eq!(data[i], larger.retrieve(&keys[i], label));
+        }
+
+        let config = FilterConfig { max_iterations: 0, ..Default::default() };
+        assert!(BinaryFuseP32::from_slice_with_config(seed, &keys, &data, label, PTXT_MOD, None, &config).is_err());
+        let config = FilterConfig { size_factor_override: Some(0.5), ..Default::default() };
+        assert!(BinaryFuseP32::from_slice_with_config(seed, &keys, &data, label, PTXT_MOD, None, &config).is_err());
+    }
+
+    #[test]
+    fn test_reseeds_failed_attempts() {
+        use crate::prelude::bfusep::next_seed;
+
+        const SAMPLE_SIZE: usize = 10_000;
+        const PTXT_MOD: u64 = 1024;
+        let keys: Vec<[u64; 4]> = (0..SAMPLE_SIZE).map(|i| [i as u64, 1, 2, 3]).collect();
+        let data: Vec<u32> = (0..SAMPLE_SIZE).map(|i| (i as u32) % (PTXT_MOD as u32)).collect();
+        let other: Vec<u32> = (0..SAMPLE_SIZE).map(|i| (i as u32 + 1) % (PTXT_MOD as u32)).collect();
+        // a size factor close to the threshold makes a good share of the attempts fail
+        let config = FilterConfig { size_factor_override: Some(1.2), ..Default::default() };
+
+        let mut reseeded = 0;
+        for s in 0..16u8 {
+            let seed = [s; 32];
+            let filter = BinaryFuseP32::from_slice_with_config(seed, &keys, &data, 1, PTXT_MOD, None, &config).unwrap();
+            // the seeds of all attempts are derived from the initial seed
+            let mut attempts = core::iter::successors(Some(seed), |s| Some(next_seed(*s)));
+            assert!(attempts.by_ref().take(config.max_iterations).any(|s| s == filter.seed()));
+            if filter.seed() != seed {
+                reseeded += 1;
+            }
+            // filters of the same keys end up with the same seed, whatever their data
+            let column = BinaryFuseP32::from_slice_with_config(seed, &keys, &other, 2, PTXT_MOD, None, &config).unwrap();
+            assert_eq!(column.seed(), filter.seed());
+            for i in 0..keys.len() {
+                assert_eq!(data[i], filter.retrieve(&keys[i], 1));
+                assert_eq!(other[i], column.retrieve(&keys[i], 2));
+            }
+        }
+        assert!(reseeded > 0);
+    }
+
+    #[test]
+    fn test_array_len() {
+        const PTXT_MOD: u64 = 1024;
+        let mut rng = rand::thread_rng();
+        let larger = FilterConfig { size_factor_override: Some(1.5), ..Default::default() };
+        for (size, config) in [(1, FilterConfig::default()), (100, FilterConfig::default()), (10_000, FilterConfig::default()), (10_000, larger)] {
+            let keys: Vec<[u64; 4]> = (0..size).map(|_| [rng.gen(); 4]).collect();
+            let data: Vec<u32> = (0..size).map(|i| (i as u32) % (PTXT_MOD as u32)).collect();
+            let filter = BinaryFuseP32::from_slice_with_config([3u8; 32], &keys, &data, 1, PTXT_MOD, None, &config).unwrap();
+            assert_eq!(BinaryFuseP32::array_len(size, &config), filter.len());
+        }
+    }
+
+    #[test]
+    fn test_hash_eval_batch() {
+        const SAMPLE_SIZE: usize = 10_000;
+        const PTXT_MOD: u64 = 1024;
+        let mut rng = rand::thread_rng();
+        let keys: Vec<[u64; 4]> = (0..SAMPLE_SIZE).map(|_| [rng.gen(); 4]).collect();
+        let data: Vec<u32> = (0..SAMPLE_SIZE).map(|i| (i as u32) % (PTXT_MOD as u32)).collect();
+        let filter = BinaryFuseP32::from_slice([8u8; 32], &keys, &data, 0, PTXT_MOD).unwrap();
+
+        let batch = BinaryFuseP32::hash_eval_batch(&keys, filter.seed(), filter.segment_length, filter.segment_length_mask, filter.segment_count_length);
+        assert_eq!(batch.len(), keys.len());
+        for (key, evals) in keys.iter().zip(batch) {
+            let h = BinaryFuseP32::hash_eval(key, filter.seed(), filter.segment_length, filter.segment_length_mask, filter.segment_count_length);
+            assert_eq!(evals, h);
+        }
+        assert!(BinaryFuseP32::hash_eval_batch(&[], filter.seed(), filter.segment_length, filter.segment_length_mask, filter.segment_count_length).is_empty());
+    }
+
+    #[test]
+    fn test_keyed_prf() {
+        const SAMPLE_SIZE: usize = 10_000;
+        const PTXT_MOD: u64 = 1024;
+        let mut rng = rand::thread_rng();
+        let keys: Vec<[u64; 4]> = (0..SAMPLE_SIZE).map(|_| [rng.gen(); 4]).collect();
+        let label = 3u64;
+        let data: Vec<u32> = (0..SAMPLE_SIZE).map(|i| (i as u32) % (PTXT_MOD as u32)).collect();
+        let seed = [7u8; 32];
+        let prf = SipHashPrf { key: [9u8; 16] };
+
+        let filter = BinaryFuseP32::from_slice_with_prf(seed, &keys, &data, label, PTXT_MOD, None, &FilterConfig::default(), &prf).unwrap();
+        let seed = filter.seed();
+        let mut mismatches = 0;
+        for i in 0..keys.len() {
+            assert_eq!(data[i], filter.retrieve_with_prf(&keys[i], label, &prf));
+            let h = BinaryFuseP32::hash_eval(&keys[i], seed, filter.segment_length, filter.segment_length_mask, filter.segment_count_length);
+            let entry = h.iter().fold(0u32, |acc, r| acc.wrapping_add(filter.fingerprints[*r]));
+            let mask = BinaryFuseP32::get_key_fingerprint_with_prf(&keys[i], seed, label, &prf);
+            assert_eq!(data[i], entry.wrapping_add(mask as u32) % PTXT_MOD as u32);
+            if filter.retrieve(&keys[i], label) != data[i] {
+                mismatches += 1;
+            }
+        }
+        // the default PRF yields unrelated masks
+        assert!(mismatches > SAMPLE_SIZE / 2);
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        const SAMPLE_SIZE: usize = 10_000;
+        const PTXT_MOD: u64 = 1024;
+        let mut rng = rand::thread_rng();
+        let keys: Vec<[u64; 4]> = (0..SAMPLE_SIZE).map(|_| [rng.gen(); 4]).collect();
+        let label = 1u64;
+        let data: Vec<u32> = (0..SAMPLE_SIZE).map(|i| (i as u32) % (PTXT_MOD as u32)).collect();
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+
+        let filter = BinaryFuseP32::from_slice(seed, &keys, &data, label, PTXT_MOD).unwrap();
+        let bytes = filter.to_bytes();
+        assert_eq!(bytes.len(), 68 + 4 * filter.len() + 8);
+        let decoded = BinaryFuseP32::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.seed(), filter.seed());
+        assert_eq!(decoded.fingerprints, filter.fingerprints);
+        for i in 0..keys.len() {
+            assert_eq!(data[i], decoded.retrieve(&keys[i], label));
+        }
+
+        assert!(BinaryFuseP32::from_bytes(&bytes[..bytes.len() - 4]).is_err());
+        assert!(BinaryFuseP32::from_bytes(&bytes[..40]).is_err());
+        for i in [0, 4, 44, 100, bytes.len() - 1] {
+            let mut bad = bytes.clone();
+            bad[i] ^= 1;
+            assert!(BinaryFuseP32::from_bytes(&bad).is_err());
+        }
+    }
+
     #[test]
     fn test_bits_per_entry() {
         const SAMPLE_SIZE: usize = 1_000_000;
@@ -77,6 +77,7 @@ extern crate alloc;
 
 mod murmur3;
 mod prelude;
+mod siphash;
 mod splitmix64;
 
 #[cfg(feature = "binary-fuse-modp")]
@@ -96,7 +97,10 @@ mod xor32;
 mod xor8;
 
 #[cfg(feature = "binary-fuse-modp")]
-pub use bfusep32::BinaryFuseP32;
+pub use bfusep32::{
+    BinaryFuseP32, FilterConfig, FingerprintPrf, MixPrf, SipHashPrf, DEFAULT_MAX_ITERATIONS,
+    SERIALIZATION_VERSION,
+};
 #[cfg(feature = "binary-fuse")]
 pub use bfuse16::BinaryFuse16;
 #[cfg(feature = "binary-fuse")]
@@ -125,3 +129,16 @@ pub trait Filter<Type> {
     /// Returns the number of fingerprints in the filter.
     fn len(&self) -> usize;
 }
+
+/// Methods common to retrieval filters.
+///
+/// Retrieval filters map each key they were constructed from to its data, rather than testing
+/// membership. Unlike `Filter`, there is no membership check: the data retrieved for keys the
+/// filter was not constructed from is arbitrary.
+pub trait RetrievalFilter<Key> {
+    /// Retrieves the data for `key` under `label`, modulo the plaintext modulus of the filter.
+    fn retrieve(&self, key: &Key, label: u64) -> u32;
+
+    /// Returns the number of fingerprints in the filter.
+    fn len(&self) -> usize;
+}
@@ -1,24 +1,49 @@
 //! Implements Binary Fuse filters.
 // Port of https://github.com/FastFilter/xorfilter/blob/master/binaryfusefilter.go
 
+use core::convert::TryInto;
+
+use crate::splitmix64::splitmix64;
+
+/// Derives the seed used for the next construction attempt. The derivation is deterministic, so
+/// that all filters constructed from the same keys and seed (e.g. one filter per column of a
+/// database) end up using the same final seed.
+#[inline]
+pub fn next_seed(seed: [u8; 32]) -> [u8; 32] {
+    let mut next = [0u8; 32];
+    for i in 0..4 {
+        let mut word = u64::from_le_bytes(seed[8 * i..8 * (i + 1)].try_into().unwrap());
+        next[8 * i..8 * (i + 1)].copy_from_slice(&splitmix64(&mut word).to_le_bytes());
+    }
+    next
+}
+
 /// Implements `try_from(&[u64])` for an binary fuse filter of fingerprint type `$fpty`.
 #[doc(hidden)]
 #[macro_export]
 macro_rules! bfusep_from_impl(
     ($seed:ident, $keys:ident, $data:ident, $ptxt_mod:ident, $label:ident, max iter $max_iter:expr) => {
+        $crate::bfusep_from_impl!($seed, $keys, $data, $ptxt_mod, $label, None, max iter $max_iter)
+    };
+    ($seed:ident, $keys:ident, $data:ident, $ptxt_mod:ident, $label:ident, $fill_seed:expr, max iter $max_iter:expr) => {
+        $crate::bfusep_from_impl!($seed, $keys, $data, $ptxt_mod, $label, $fill_seed, None, &$crate::MixPrf, max iter $max_iter)
+    };
+    ($seed:ident, $keys:ident, $data:ident, $ptxt_mod:ident, $label:ident, $fill_seed:expr, $size_factor:expr, $prf:expr, max iter $max_iter:expr) => {
         {
             use libm::round;
             use $crate::{
                 make_block,
                 make_fp_block,
                 prelude::{
-                    mix,
                     mix256,
                     bfuse::{segment_length, size_factor, hash_of_hash, mod3},
+                    bfusep::next_seed,
                 },
             };
             use hashbrown::HashMap;
 
+            let mut $seed = $seed;
+
             #[cfg(debug_assertions)] {
                 use $crate::prelude::all_distinct256;
                 debug_assert!(all_distinct256($keys), "Binary Fuse filters must be constructed from a collection containing all distinct keys.");
@@ -29,7 +54,8 @@ macro_rules! bfusep_from_impl(
             let size: usize = $keys.len();
             let segment_length: u32 = segment_length(arity, size as u32).min(262144);
             let segment_length_mask: u32 = segment_length - 1;
-            let size_factor: f64 = size_factor(arity, size as u32);
+            let size_factor_override: Option<f64> = $size_factor;
+            let size_factor: f64 = size_factor_override.unwrap_or_else(|| size_factor(arity, size as u32));
             let capacity: u32 = if size > 1 {
                 round(size as f64 * size_factor) as u32
             } else { 0 };
@@ -49,7 +75,11 @@ macro_rules! bfusep_from_impl(
             };
             let segment_count_length = segment_count * segment_length;
 
-            let mut fingerprints: Box<[u32]> = make_fp_block!(fp_array_len);
+            let fill_seed: Option<[u8; 32]> = $fill_seed;
+            let mut fingerprints: Box<[u32]> = match fill_seed {
+                Some(fill_seed) => make_fp_block!(fp_array_len, seeded by fill_seed),
+                None => make_fp_block!(fp_array_len),
+            };
 
             let capacity = fingerprints.len();
             let mut alone: Box<[u32]> = make_block!(with capacity sets);
@@ -125,6 +155,16 @@ macro_rules! bfusep_from_impl(
                     error = t2count[index1] < 4 || t2count[index2] < 4 || t2count[index3] < 4;
                 }
                 if error {
+                    // Reset and reseed for a retry.
+                    for i in 0..size {
+                        reverse_order[i] = 0;
+                    }
+                    for i in 0..capacity {
+                        t2count[i] = 0;
+                        t2hash[i] = 0;
+                    }
+                    hm_keys.clear();
+                    $seed = next_seed($seed);
                     continue;
                 }
 
@@ -181,7 +221,7 @@ macro_rules! bfusep_from_impl(
                     break
                 }
 
-                // Filter failed to be created; reset for a retry.
+                // Filter failed to be created; reset and reseed for a retry.
                 for i in 0..size {
                     reverse_order[i] = 0;
                 }
@@ -189,6 +229,8 @@ macro_rules! bfusep_from_impl(
                     t2count[i] = 0;
                     t2hash[i] = 0;
                 }
+                hm_keys.clear();
+                $seed = next_seed($seed);
             }
             if !done {
                 return Err("Failed to construct binary fuse filter.");
@@ -206,12 +248,13 @@ macro_rules! bfusep_from_impl(
 		            h012[2] = index3;
 		            h012[3] = h012[0];
 		            h012[4] = h012[1];
+                // reduced in u64, as a plaintext modulus of 2^32 does not fit a u32
 		        let entry =
-                (data % ($ptxt_mod as u32))
+                ((*data as u64 % $ptxt_mod) as u32)
                 .wrapping_sub(fingerprints[h012[found + 1] as usize])
-                .wrapping_sub(fingerprints[h012[found + 2] as usize]) % ($ptxt_mod as u32);
-                let mask = (mix(hash, $label) % $ptxt_mod) as u32;
-                fingerprints[h012[found] as usize] = entry.wrapping_sub(mask) % ($ptxt_mod as u32);
+                .wrapping_sub(fingerprints[h012[found + 2] as usize]);
+                let mask = ($crate::FingerprintPrf::fingerprint($prf, hash, $label) % $ptxt_mod) as u32;
+                fingerprints[h012[found] as usize] = (entry.wrapping_sub(mask) as u64 % $ptxt_mod) as u32;
             }
 
             Ok(Self {
@@ -232,10 +275,12 @@ macro_rules! bfusep_from_impl(
 #[macro_export]
 macro_rules! bfusep_retrieve_impl(
     ($key:expr, $label:expr, $self:expr) => {
+        $crate::bfusep_retrieve_impl!($key, $label, $self, &$crate::MixPrf)
+    };
+    ($key:expr, $label:expr, $self:expr, $prf:expr) => {
         {
             use $crate::{
                 prelude::{
-                    mix,
                     mix256,
                     bfuse::hash_of_hash
                 },
@@ -243,8 +288,8 @@ macro_rules! bfusep_retrieve_impl(
             let hash = mix256($key, &$self.seed);
             let (h0, h1, h2) = hash_of_hash(hash, $self.segment_length, $self.segment_length_mask, $self.segment_count_length);
             let data = $self.fingerprints[h0 as usize].wrapping_add($self.fingerprints[h1 as usize]).wrapping_add($self.fingerprints[h2 as usize]);
-            let mask = (mix(hash, $label) % $self.ptxt_mod) as u32;
-            data.wrapping_add(mask) % ($self.ptxt_mod as u32)
+            let mask = ($crate::FingerprintPrf::fingerprint($prf, hash, $label) % $self.ptxt_mod) as u32;
+            (data.wrapping_add(mask) as u64 % $self.ptxt_mod) as u32
         }
     };
 );
@@ -274,15 +319,13 @@ macro_rules! bfusep_hash_eval_impl(
 #[macro_export]
 macro_rules! bfusep_key_fingerprint_impl(
     ($key:expr, $seed:expr, $label:expr) => {
+        $crate::bfusep_key_fingerprint_impl!($key, $seed, $label, &$crate::MixPrf)
+    };
+    ($key:expr, $seed:expr, $label:expr, $prf:expr) => {
         {
-            use $crate::{
-                prelude::{
-                    mix,
-                    mix256,
-                },
-            };
+            use $crate::prelude::mix256;
             let hash = mix256($key, &$seed);
-            mix(hash, $label)
+            $crate::FingerprintPrf::fingerprint($prf, hash, $label)
         }
     };
 );
\ No newline at end of file
@@ -43,14 +43,26 @@ pub const fn mix(key: u64, seed: u64) -> u64 {
 /// Applies a finalization mix to a randomly-seeded key of multiple u64 values
 #[inline]
 pub fn mix256<'a>(key: &[u64; 4], seed: &[u8]) -> u64 {
-    let mut seeds = vec![0u64; 4];
+    mix256_words(key, &seed_words(seed))
+}
+
+/// Parses a 32-byte seed into the words used by `mix256_words`
+#[inline]
+pub fn seed_words(seed: &[u8]) -> [u64; 4] {
+    let mut seeds = [0u64; 4];
     if seed.len() != 32 {
         panic!("Incompatible seed length: {}", seed.len());
     }
     for i in 0..4 {
         seeds[i] = u64::from_le_bytes(seed[8 * i..8 * (i + 1)].try_into().unwrap());
     }
-    key.into_iter()
+    seeds
+}
+
+/// Same as `mix256`, for a seed that was already parsed using `seed_words`
+#[inline]
+pub fn mix256_words(key: &[u64; 4], seeds: &[u64; 4]) -> u64 {
+    key.iter()
         .map(|k| {
             let mut mixed = 0u64;
             for i in 0..4 {
@@ -131,6 +143,24 @@ macro_rules! make_fp_block(
                 make_block!(with $size sets)
             }
         }
+    };
+    ($size:ident, seeded by $fill_seed:expr) => {
+        {
+            #[cfg(feature = "uniform-random")] {
+                use rand::{Rng, SeedableRng};
+                let mut rng = rand::rngs::StdRng::from_seed($fill_seed);
+                let mut block = Vec::with_capacity($size);
+                for _ in 0..$size {
+                    block.push(rng.gen());
+                }
+                block.into_boxed_slice()
+            }
+
+            #[cfg(not(feature = "uniform-random"))] {
+                let _ = $fill_seed;
+                make_block!(with $size sets)
+            }
+        }
     }
 );
 
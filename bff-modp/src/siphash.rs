@@ -0,0 +1,66 @@
+/// [`SipHash-2-4`] of a message consisting of whole little-endian 64-bit `words`, keyed by
+/// `key`. The output equals SipHash-2-4 of the byte serialization of `words`.
+///
+/// [`SipHash-2-4`]: https://www.aumasson.jp/siphash/siphash.pdf
+pub fn siphash24(key: &[u8; 16], words: &[u64]) -> u64 {
+    let mut k = [0u8; 8];
+    k.copy_from_slice(&key[..8]);
+    let k0 = u64::from_le_bytes(k);
+    k.copy_from_slice(&key[8..]);
+    let k1 = u64::from_le_bytes(k);
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+    let last = ((words.len() as u64 * 8) & 0xff) << 56;
+    for &m in words.iter().chain(core::iter::once(&last)) {
+        v[3] ^= m;
+        v = sip_round(v);
+        v = sip_round(v);
+        v[0] ^= m;
+    }
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        v = sip_round(v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[inline]
+const fn sip_round(mut v: [u64; 4]) -> [u64; 4] {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+    v
+}
+
+#[cfg(test)]
+mod test {
+    use super::siphash24;
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_matches_core_siphasher() {
+        use core::hash::{Hasher, SipHasher};
+        let key: [u8; 16] = core::array::from_fn(|i| i as u8);
+        let words = [0x0706_0504_0302_0100u64, 0x0f0e_0d0c_0b0a_0908, 42];
+        for n in 0..=words.len() {
+            let mut hasher = SipHasher::new_with_keys(0x0706_0504_0302_0100, 0x0f0e_0d0c_0b0a_0908);
+            for w in &words[..n] {
+                hasher.write(&w.to_le_bytes());
+            }
+            assert_eq!(siphash24(&key, &words[..n]), hasher.finish());
+        }
+        // reference test vector for the 16-byte message 00..0f
+        assert_eq!(siphash24(&key, &words[..2]), 0x3f2a_cc7f_57c2_9bdb);
+    }
+}
@@ -0,0 +1,69 @@
+//! A small DNS-over-PIR resolver. The server holds a zone of A and AAAA
+//! record sets in a `KVShard`, and the resolver looks up names without
+//! revealing them, reporting the time taken by each step.
+//!
+//! Run with `cargo run --release --example dns_resolver [domain...]`.
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Instant;
+
+use keyword_pir_lwe::adapters::{DnsAdapter, DnsRecordSet};
+use keyword_pir_lwe::api::*;
+use keyword_pir_lwe::errors::ResultBoxedError;
+
+fn zone() -> Vec<(String, DnsRecordSet)> {
+  (0..1024u16)
+    .map(|i| {
+      let [hi, lo] = i.to_be_bytes();
+      let set = DnsRecordSet {
+        ttl: 300,
+        a: vec![Ipv4Addr::new(192, 0, hi, lo)],
+        aaaa: vec![Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i)],
+      };
+      (format!("host{}.example.com", i), set)
+    })
+    .collect()
+}
+
+fn main() -> ResultBoxedError<()> {
+  let mut domains: Vec<String> = std::env::args().skip(1).collect();
+  if domains.is_empty() {
+    domains = vec!["host1.example.com".into(), "unknown.example.com".into()];
+  }
+
+  let start = Instant::now();
+  let shard = DnsAdapter::recommended()
+    .with_capacity(4, 4)
+    .build(&zone())?;
+  println!("Built zone in {:?}", start.elapsed());
+
+  let bp = shard.get_base_params();
+  let cp = CommonParams::from(bp);
+  for domain in domains {
+    let start = Instant::now();
+    let mut qp = generate_kv_query_params(&cp, bp)?;
+    let q = qp.generate_query(&DnsAdapter::lookup_key(&domain)?)?;
+    let query_time = start.elapsed();
+
+    let start = Instant::now();
+    let resp: Response = bincode::deserialize(&shard.respond(&q)?)?;
+    let respond_time = start.elapsed();
+
+    let start = Instant::now();
+    let records = DnsAdapter::parse_records(&qp, &resp, &domain)?;
+    let parse_time = start.elapsed();
+
+    match records {
+      Some(set) => {
+        println!("{} (ttl {}):", domain, set.ttl);
+        set.a.iter().for_each(|ip| println!("  A     {}", ip));
+        set.aaaa.iter().for_each(|ip| println!("  AAAA  {}", ip));
+      }
+      None => println!("{}: NXDOMAIN", domain),
+    }
+    println!(
+      "  query {:?}, respond {:?}, parse {:?}",
+      query_time, respond_time, parse_time
+    );
+  }
+  Ok(())
+}
@@ -0,0 +1,136 @@
+//! A KV server whose DB is rebuilt for every epoch of a rotation schedule
+//! (e.g. daily, with a fresh public seed), and a client that keeps looking
+//! up a key over HTTP across rotations. The params served for each epoch
+//! expire when it ends, so the client downloads new params on its own.
+//!
+//! Run with `cargo run --release --example rotating_server [period_secs]
+//! [lookups]`. The period defaults to a few seconds, so that rotations
+//! can be observed; production servers use `RotationSchedule::daily()`.
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use keyword_pir_lwe::prelude::*;
+use keyword_pir_lwe::serving::{RotatingShard, RotationSchedule};
+
+const NUM_KEYS: usize = 256;
+const ELEM_SIZE: usize = 256;
+
+/// Builds the DB of `epoch`, whose values record the epoch
+fn build(epoch: u64) -> ResultBoxedError<KVShard> {
+  let keys: Vec<String> =
+    (0..NUM_KEYS).map(|i| format!("user-{}", i)).collect();
+  let values: Vec<String> = (0..NUM_KEYS)
+    .map(|i| {
+      let mut value = format!("user-{} in epoch {}", i, epoch).into_bytes();
+      value.resize(ELEM_SIZE / 8, b' ');
+      base64::encode(value)
+    })
+    .collect();
+  KVShard::from_base64_strings(&keys, &values, 512, ELEM_SIZE, 10)
+}
+
+/// Rebuilds the DB ahead of every rotation, and swaps it in when the next
+/// epoch starts
+fn rotate(shard: &RotatingShard<KVShard>) -> ResultBoxedError<()> {
+  loop {
+    shard.prepare_next(build)?;
+    thread::sleep(shard.get_schedule().until_next(SystemTime::now()));
+    if shard.rotate_if_due(SystemTime::now(), build)? {
+      println!("[server] rotated to epoch {}", shard.epoch());
+    }
+  }
+}
+
+/// Answers a single HTTP request: `GET /params` returns the params of the
+/// current epoch, and `POST /query` the response to a bincode query
+fn handle(
+  shard: &RotatingShard<KVShard>,
+  stream: TcpStream,
+) -> ResultBoxedError<()> {
+  let mut reader = BufReader::new(stream.try_clone()?);
+  let mut request_line = String::new();
+  reader.read_line(&mut request_line)?;
+  let mut content_len = 0;
+  loop {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if line == "\r\n" || line.is_empty() {
+      break;
+    }
+    if let Some((name, value)) = line.split_once(':') {
+      if name.eq_ignore_ascii_case("content-length") {
+        content_len = value.trim().parse()?;
+      }
+    }
+  }
+  let mut body = vec![0u8; content_len];
+  reader.read_exact(&mut body)?;
+  let (status, out) = if request_line.starts_with("GET /params ") {
+    ("200 OK", shard.params_json()?)
+  } else if request_line.starts_with("POST /query ") {
+    ("200 OK", shard.respond(&bincode::deserialize(&body)?)?)
+  } else {
+    ("404 Not Found", Vec::new())
+  };
+  let mut stream = stream;
+  write!(
+    stream,
+    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+    status,
+    out.len()
+  )?;
+  stream.write_all(&out)?;
+  Ok(())
+}
+
+fn main() -> ResultBoxedError<()> {
+  let mut args = std::env::args().skip(1);
+  let period_secs = args.next().map_or(Ok(5), |a| a.parse())?;
+  let lookups: usize = args.next().map_or(Ok(12), |a| a.parse())?;
+
+  let schedule = RotationSchedule::new(period_secs)?;
+  let shard = Arc::new(RotatingShard::new(schedule, SystemTime::now(), build)?);
+  println!("[server] serving epoch {}", shard.epoch());
+
+  let rotating = shard.clone();
+  thread::spawn(move || {
+    if let Err(e) = rotate(&rotating) {
+      eprintln!("[server] rotation failed: {}", e);
+    }
+  });
+
+  let listener = TcpListener::bind("127.0.0.1:0")?;
+  let addr = listener.local_addr()?.to_string();
+  thread::spawn(move || {
+    for stream in listener.incoming().flatten() {
+      let shard = shard.clone();
+      thread::spawn(move || {
+        if let Err(e) = handle(&shard, stream) {
+          eprintln!("[server] failed to answer request: {}", e);
+        }
+      });
+    }
+  });
+
+  // refreshing a little early tolerates clock skew between the client
+  // and the server
+  let transport =
+    HttpTransport::new(&addr).with_timeout(Duration::from_secs(5));
+  let mut client = PirClient::connect_kv(transport)?
+    .with_refresh_margin(Duration::from_millis(200));
+  for _ in 0..lookups {
+    match client.get(b"user-7") {
+      Ok(KvValue::Found(value)) => {
+        println!("[client] {}", String::from_utf8_lossy(&value).trim_end())
+      }
+      Ok(KvValue::Expired(at)) => println!("[client] user-7 expired at {}", at),
+      Ok(KvValue::Missing) => println!("[client] user-7 not found"),
+      Err(e) => println!("[client] lookup failed: {}", e),
+    }
+    thread::sleep(Duration::from_secs(1));
+  }
+  Ok(())
+}
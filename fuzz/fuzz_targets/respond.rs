@@ -0,0 +1,119 @@
+//! Feeds untrusted queries into the respond paths of an index shard and a
+//! KV shard, which must reject malformed queries with an error rather
+//! than panic. Each input is tried both as a serialized `Query`, and as
+//! the values of a query whose length is chosen by the first two bytes
+//! (mostly close to the heights of the shards).
+#![no_main]
+
+use std::io::sink;
+use std::sync::OnceLock;
+
+use keyword_pir_lwe::api::{KVShard, PirShard, Query, Shard};
+use libfuzzer_sys::fuzz_target;
+
+const NUM_ELES: usize = 64;
+const LWE_DIM: usize = 64;
+const ELEM_SIZE: usize = 64;
+const PLAINTEXT_BITS: usize = 10;
+
+fn shards() -> &'static (Shard, KVShard) {
+  static SHARDS: OnceLock<(Shard, KVShard)> = OnceLock::new();
+  SHARDS.get_or_init(|| {
+    let eles: Vec<String> = (0..NUM_ELES)
+      .map(|i| base64::encode([i as u8; ELEM_SIZE / 8]))
+      .collect();
+    let keys: Vec<String> =
+      (0..NUM_ELES).map(|i| format!("key-{}", i)).collect();
+    let shard = Shard::from_base64_strings(
+      &eles,
+      LWE_DIM,
+      NUM_ELES,
+      ELEM_SIZE,
+      PLAINTEXT_BITS,
+    )
+    .unwrap();
+    let kv_shard = KVShard::from_base64_strings(
+      &keys,
+      &eles,
+      LWE_DIM,
+      ELEM_SIZE,
+      PLAINTEXT_BITS,
+    )
+    .unwrap();
+    (shard, kv_shard)
+  })
+}
+
+/// Builds a query of `len` values taken (cyclically) from `data`
+fn query_of_len(len: usize, data: &[u8]) -> Query {
+  let values: Vec<u32> = (0..len)
+    .map(|i| match data.len() {
+      0 => 0,
+      n => u32::from_le_bytes([
+        data[(4 * i) % n],
+        data[(4 * i + 1) % n],
+        data[(4 * i + 2) % n],
+        data[(4 * i + 3) % n],
+      ]),
+    })
+    .collect();
+  // a query serializes as its values
+  bincode::deserialize(&bincode::serialize(&values).unwrap()).unwrap()
+}
+
+/// Checks that all respond paths of `shard` agree with `check_query` on
+/// whether `q` is accepted
+fn respond_all<S: PirShard>(
+  shard: &S,
+  q: &Query,
+  respond_stream: impl Fn(&Query) -> bool,
+  respond_frames: impl Fn(&Query) -> bool,
+) {
+  let valid = shard.check_query(q).is_ok();
+  assert_eq!(shard.respond(q).is_ok(), valid);
+  assert_eq!(shard.respond_par(q).is_ok(), valid);
+  assert_eq!(respond_stream(q), valid);
+  assert_eq!(respond_frames(q), valid);
+}
+
+fn respond_both(q: &Query) {
+  let (shard, kv_shard) = shards();
+  respond_all(
+    shard,
+    q,
+    |q| shard.respond_stream(q, sink()).is_ok(),
+    |q| {
+      shard
+        .respond_frames(q, 3)
+        .is_ok_and(|mut f| f.all(|f| f.is_ok()))
+    },
+  );
+  respond_all(
+    kv_shard,
+    q,
+    |q| kv_shard.respond_stream(q, sink()).is_ok(),
+    |q| {
+      kv_shard
+        .respond_frames(q, 3)
+        .is_ok_and(|mut f| f.all(|f| f.is_ok()))
+    },
+  );
+}
+
+fuzz_target!(|data: &[u8]| {
+  if let Ok(q) = bincode::deserialize::<Query>(data) {
+    respond_both(&q);
+  }
+  if let [lo, hi, rest @ ..] = data {
+    let (shard, kv_shard) = shards();
+    let selector = u16::from_le_bytes([*lo, *hi]) as usize;
+    // mostly lengths within a few entries of either height, and
+    // sometimes arbitrary lengths up to 2^15
+    let len = match selector % 4 {
+      0 => (shard.height() + selector / 4 % 8).saturating_sub(4),
+      1 => (kv_shard.height() + selector / 4 % 8).saturating_sub(4),
+      _ => selector / 2,
+    };
+    respond_both(&query_of_len(len, rest));
+  }
+});
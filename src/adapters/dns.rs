@@ -0,0 +1,242 @@
+//! An adapter for DNS-over-PIR: A and AAAA record sets keyed by domain
+//! name, so that a resolver can look up a name without revealing it to
+//! the server.
+//!
+//! Each value holds a short checksum of the domain name, the TTL, and the
+//! addresses of the record set, padded to the capacity of the largest
+//! record set. The checksum lets clients distinguish names that are not
+//! in the zone, for which the response decodes to random bytes.
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use sha2::{Digest, Sha256};
+
+use super::{RECOMMENDED_LWE_DIM, RECOMMENDED_PLAINTEXT_BITS};
+use crate::api::{KVShard, QueryParams, Response};
+use crate::db::{FilterParams, HashedKey, KVDatabase};
+use crate::errors::{ErrorUnexpectedInputSize, ResultBoxedError};
+
+/// The number of checksum bytes stored with each record set
+const CHECKSUM_BYTES: usize = 4;
+
+/// The number of bytes of the header of a value: the checksum, the TTL,
+/// and the number of A and AAAA records
+const HEADER_BYTES: usize = CHECKSUM_BYTES + 4 + 2;
+
+/// The A and AAAA records of a domain name
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DnsRecordSet {
+  pub ttl: u32,
+  pub a: Vec<Ipv4Addr>,
+  pub aaaa: Vec<Ipv6Addr>,
+}
+
+/// The `DnsAdapter` builds a `KVShard` from (domain name, record set)
+/// pairs, and helps resolvers query it.
+///
+/// By default the capacity for A and AAAA records is the largest number
+/// of each in the zone, so the element size adapts to the data. Fixing
+/// the capacity with `with_capacity` keeps the element size (and thus
+/// the response size) stable when the zone is rebuilt.
+#[derive(Clone, Debug)]
+pub struct DnsAdapter {
+  lwe_dim: usize,
+  plaintext_bits: usize,
+  capacity: Option<(usize, usize)>,
+}
+
+impl Default for DnsAdapter {
+  fn default() -> Self {
+    Self::recommended()
+  }
+}
+
+impl DnsAdapter {
+  /// Returns an adapter using the recommended parameters
+  pub fn recommended() -> Self {
+    Self {
+      lwe_dim: RECOMMENDED_LWE_DIM,
+      plaintext_bits: RECOMMENDED_PLAINTEXT_BITS,
+      capacity: None,
+    }
+  }
+
+  /// Sets the LWE dimension
+  pub fn with_lwe_dim(mut self, lwe_dim: usize) -> Self {
+    self.lwe_dim = lwe_dim;
+    self
+  }
+
+  /// Sets the number of plaintext bits per DB matrix entry
+  pub fn with_plaintext_bits(mut self, plaintext_bits: usize) -> Self {
+    self.plaintext_bits = plaintext_bits;
+    self
+  }
+
+  /// Fixes the maximum number of A and AAAA records per domain name
+  pub fn with_capacity(mut self, max_a: usize, max_aaaa: usize) -> Self {
+    self.capacity = Some((max_a, max_aaaa));
+    self
+  }
+
+  /// Normalizes a domain name (lowercase, without the trailing dot)
+  pub fn normalize_domain(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_lowercase()
+  }
+
+  /// Returns the key under which the record set of `domain` is stored
+  pub fn lookup_key(domain: &str) -> ResultBoxedError<HashedKey> {
+    Ok(HashedKey::from_bytes(
+      Self::normalize_domain(domain).as_bytes(),
+    ))
+  }
+
+  /// Builds a `KVShard` from (domain name, record set) pairs
+  pub fn build(
+    &self,
+    records: &[(String, DnsRecordSet)],
+  ) -> ResultBoxedError<KVShard> {
+    let (max_a, max_aaaa) = match self.capacity {
+      Some(capacity) => capacity,
+      None => (
+        records.iter().map(|(_, r)| r.a.len()).max().unwrap_or(0),
+        records.iter().map(|(_, r)| r.aaaa.len()).max().unwrap_or(0),
+      ),
+    };
+    if max_a > u8::MAX as usize || max_aaaa > u8::MAX as usize {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "At most {} records of each type are supported",
+        u8::MAX
+      ))));
+    }
+    let value_bytes = HEADER_BYTES + 4 * max_a + 16 * max_aaaa;
+    let mut keys = Vec::with_capacity(records.len());
+    let mut values = Vec::with_capacity(records.len());
+    for (domain, set) in records {
+      if set.a.len() > max_a || set.aaaa.len() > max_aaaa {
+        return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+          "{} has {} A and {} AAAA records, but the capacity is {} and {}",
+          domain,
+          set.a.len(),
+          set.aaaa.len(),
+          max_a,
+          max_aaaa
+        ))));
+      }
+      let domain = Self::normalize_domain(domain);
+      let mut value = checksum(&domain).to_vec();
+      value.extend(set.ttl.to_le_bytes());
+      value.extend([set.a.len() as u8, set.aaaa.len() as u8]);
+      set.a.iter().for_each(|ip| value.extend(ip.octets()));
+      set.aaaa.iter().for_each(|ip| value.extend(ip.octets()));
+      value.resize(value_bytes, 0);
+      keys.push(domain);
+      values.push(base64::encode(value));
+    }
+    KVShard::from_base64_strings(
+      &keys,
+      &values,
+      self.lwe_dim,
+      value_bytes * 8,
+      self.plaintext_bits,
+    )
+  }
+
+  /// Parses a response to a query for `domain` into its record set, or
+  /// `None` if the domain name is not in the zone
+  pub fn parse_records(
+    qp: &QueryParams<KVDatabase, FilterParams>,
+    resp: &Response,
+    domain: &str,
+  ) -> ResultBoxedError<Option<DnsRecordSet>> {
+    let value = qp.parse_resp_as_bytes(resp, &Self::lookup_key(domain)?)?;
+    if value.len() < HEADER_BYTES
+      || value[..CHECKSUM_BYTES]
+        != checksum(&Self::normalize_domain(domain))[..]
+    {
+      return Ok(None);
+    }
+    let ttl = u32::from_le_bytes([value[4], value[5], value[6], value[7]]);
+    let (n_a, n_aaaa) = (value[8] as usize, value[9] as usize);
+    let body = &value[HEADER_BYTES..];
+    if body.len() < 4 * n_a + 16 * n_aaaa {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "{} A and {} AAAA records do not fit in {} bytes",
+        n_a,
+        n_aaaa,
+        body.len()
+      ))));
+    }
+    let a = body[..4 * n_a]
+      .chunks_exact(4)
+      .map(|c| Ipv4Addr::new(c[0], c[1], c[2], c[3]))
+      .collect();
+    let aaaa = body[4 * n_a..4 * n_a + 16 * n_aaaa]
+      .chunks_exact(16)
+      .map(|c| {
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(c);
+        Ipv6Addr::from(octets)
+      })
+      .collect();
+    Ok(Some(DnsRecordSet { ttl, a, aaaa }))
+  }
+}
+
+/// Returns the checksum of a normalized domain name
+fn checksum(domain: &str) -> [u8; CHECKSUM_BYTES] {
+  let digest = Sha256::new()
+    .chain_update(b"dns-checksum")
+    .chain_update(domain);
+  let mut out = [0u8; CHECKSUM_BYTES];
+  out.copy_from_slice(&digest.finalize()[..CHECKSUM_BYTES]);
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::*;
+
+  #[test]
+  fn dns_record_lookup() {
+    let records: Vec<(String, DnsRecordSet)> = (0..64u8)
+      .map(|i| {
+        let set = DnsRecordSet {
+          ttl: 300 + i as u32,
+          a: (0..i % 3).map(|j| Ipv4Addr::new(10, 0, i, j)).collect(),
+          aaaa: (0..i % 2)
+            .map(|j| {
+              Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, i as u16, j as u16)
+            })
+            .collect(),
+        };
+        (format!("host{}.example.org.", i), set)
+      })
+      .collect();
+    let shard = DnsAdapter::recommended()
+      .with_lwe_dim(512)
+      .build(&records)
+      .unwrap();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+
+    let cases = [
+      ("HOST5.example.org", Some(records[5].1.clone())),
+      ("host6.example.org.", Some(records[6].1.clone())),
+      ("missing.example.org", None),
+    ];
+    for (domain, expected) in cases {
+      let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+      let q = qp
+        .generate_query(&DnsAdapter::lookup_key(domain).unwrap())
+        .unwrap();
+      let resp: Response =
+        bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+      let parsed = DnsAdapter::parse_records(&qp, &resp, domain).unwrap();
+      assert_eq!(parsed, expected);
+    }
+
+    let too_small = DnsAdapter::recommended().with_capacity(1, 1);
+    assert!(too_small.build(&records).is_err());
+  }
+}
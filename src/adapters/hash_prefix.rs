@@ -0,0 +1,190 @@
+//! An adapter for Safe Browsing-style hash-prefix lists: a server holds a
+//! list of full (32-byte) hashes, e.g. of unsafe URLs, and clients check
+//! whether the hash of a URL is on the list without revealing the URL.
+//!
+//! Hashes are bucketed by their 4-byte prefix, and the DB maps each
+//! prefix to the suffixes of all hashes sharing it. Clients query by
+//! prefix and check membership of the full hash locally, so the server
+//! learns neither the prefix nor the result.
+use std::collections::BTreeMap;
+
+use super::{RECOMMENDED_LWE_DIM, RECOMMENDED_PLAINTEXT_BITS};
+use crate::api::{KVShard, QueryParams, Response};
+use crate::db::{FilterParams, HashedKey, KVDatabase};
+use crate::errors::{ErrorUnexpectedInputSize, ResultBoxedError};
+use crate::utils::format::EncodingConfig;
+
+/// The length of a full hash in bytes
+pub const HASH_BYTES: usize = 32;
+
+/// The length of the prefix that hashes are bucketed by, in bytes
+pub const PREFIX_BYTES: usize = 4;
+
+/// The length of the suffix stored for each hash, in bytes
+pub const SUFFIX_BYTES: usize = HASH_BYTES - PREFIX_BYTES;
+
+/// The number of bytes used for the suffix count of a bucket
+const COUNT_BYTES: usize = 2;
+
+/// The `HashPrefixAdapter` builds a `KVShard` mapping 4-byte hash
+/// prefixes to the concatenated suffixes of the hashes sharing them, and
+/// helps clients check membership of a hash.
+///
+/// All buckets are padded to the capacity of the largest bucket, so the
+/// element size (and response size) grows with the largest number of
+/// hashes that share a prefix.
+#[derive(Clone, Debug)]
+pub struct HashPrefixAdapter {
+  lwe_dim: usize,
+  plaintext_bits: usize,
+}
+
+impl Default for HashPrefixAdapter {
+  fn default() -> Self {
+    Self::recommended()
+  }
+}
+
+impl HashPrefixAdapter {
+  /// Returns an adapter using the recommended parameters
+  pub fn recommended() -> Self {
+    Self {
+      lwe_dim: RECOMMENDED_LWE_DIM,
+      plaintext_bits: RECOMMENDED_PLAINTEXT_BITS,
+    }
+  }
+
+  /// Sets the LWE dimension
+  pub fn with_lwe_dim(mut self, lwe_dim: usize) -> Self {
+    self.lwe_dim = lwe_dim;
+    self
+  }
+
+  /// Sets the number of plaintext bits per DB matrix entry
+  pub fn with_plaintext_bits(mut self, plaintext_bits: usize) -> Self {
+    self.plaintext_bits = plaintext_bits;
+    self
+  }
+
+  /// Returns the key under which the bucket of `hash` is stored
+  pub fn lookup_key(hash: &[u8; HASH_BYTES]) -> ResultBoxedError<HashedKey> {
+    Ok(HashedKey::from_bytes(prefix_key(hash).as_bytes()))
+  }
+
+  /// Builds a `KVShard` from a list of hashes
+  pub fn build(
+    &self,
+    hashes: &[[u8; HASH_BYTES]],
+  ) -> ResultBoxedError<KVShard> {
+    let mut buckets: BTreeMap<String, Vec<&[u8]>> = BTreeMap::new();
+    for hash in hashes {
+      let suffixes = buckets.entry(prefix_key(hash)).or_default();
+      if !suffixes.contains(&&hash[PREFIX_BYTES..]) {
+        suffixes.push(&hash[PREFIX_BYTES..]);
+      }
+    }
+    let capacity = buckets.values().map(|b| b.len()).max().unwrap_or(0);
+    if capacity > u16::MAX as usize {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "{} hashes share a prefix, but at most {} are supported",
+        capacity,
+        u16::MAX
+      ))));
+    }
+    let value_bytes = COUNT_BYTES + capacity * SUFFIX_BYTES;
+    let mut keys = Vec::with_capacity(buckets.len());
+    let mut values = Vec::with_capacity(buckets.len());
+    for (prefix, suffixes) in buckets {
+      let mut value = (suffixes.len() as u16).to_le_bytes().to_vec();
+      suffixes.iter().for_each(|s| value.extend_from_slice(s));
+      value.resize(value_bytes, 0);
+      keys.push(prefix);
+      values.push(base64::encode(value));
+    }
+    KVShard::from_base64_strings(
+      &keys,
+      &values,
+      self.lwe_dim,
+      value_bytes * 8,
+      self.plaintext_bits,
+    )
+  }
+
+  /// Parses a response to a query for the prefix of `hash`, and checks
+  /// whether `hash` is on the list.
+  ///
+  /// If no listed hash shares the prefix, the response decodes to random
+  /// bytes, which match the suffix of `hash` with negligible probability.
+  pub fn contains(
+    qp: &QueryParams<KVDatabase, FilterParams>,
+    resp: &Response,
+    hash: &[u8; HASH_BYTES],
+  ) -> ResultBoxedError<bool> {
+    let value = qp.parse_resp_as_bytes(resp, &Self::lookup_key(hash)?)?;
+    if value.len() < COUNT_BYTES {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "Value has {} bytes, but the suffix count needs {}",
+        value.len(),
+        COUNT_BYTES
+      ))));
+    }
+    let count = u16::from_le_bytes([value[0], value[1]]) as usize;
+    let suffix = &hash[PREFIX_BYTES..];
+    Ok(
+      value[COUNT_BYTES..]
+        .chunks_exact(SUFFIX_BYTES)
+        .take(count)
+        .any(|s| s == suffix),
+    )
+  }
+}
+
+/// Returns the (hex-encoded) DB key for the prefix of `hash`
+fn prefix_key(hash: &[u8; HASH_BYTES]) -> String {
+  EncodingConfig::Hex.encode(&hash[..PREFIX_BYTES])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::*;
+  use rand_core::{OsRng, RngCore};
+
+  #[test]
+  fn hash_prefix_membership() {
+    let mut hashes: Vec<[u8; HASH_BYTES]> = (0..128)
+      .map(|_| {
+        let mut hash = [0u8; HASH_BYTES];
+        OsRng.fill_bytes(&mut hash);
+        hash
+      })
+      .collect();
+    // a hash sharing the prefix of another
+    let mut sibling = hashes[0];
+    sibling[HASH_BYTES - 1] ^= 1;
+    hashes.push(sibling);
+
+    let shard = HashPrefixAdapter::recommended()
+      .with_lwe_dim(512)
+      .build(&hashes)
+      .unwrap();
+    assert_eq!(shard.num_keys(), 128);
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+
+    let mut unlisted = hashes[5];
+    unlisted[HASH_BYTES - 1] ^= 1;
+    let cases = [(hashes[0], true), (sibling, true), (unlisted, false)];
+    for (hash, listed) in cases {
+      let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+      let key = HashPrefixAdapter::lookup_key(&hash).unwrap();
+      let q = qp.generate_query(&key).unwrap();
+      let resp: Response =
+        bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+      assert_eq!(
+        HashPrefixAdapter::contains(&qp, &resp, &hash).unwrap(),
+        listed
+      );
+    }
+  }
+}
@@ -0,0 +1,184 @@
+//! An adapter for key directories (e.g. the key server of a messaging
+//! service, or a Certificate Transparency-style log of identity keys),
+//! mapping usernames to public key bundles. Clients look up the bundle of
+//! a contact without revealing to the directory who they are looking up.
+use super::{RECOMMENDED_LWE_DIM, RECOMMENDED_PLAINTEXT_BITS};
+use crate::api::{KVShard, QueryParams, Response};
+use crate::db::{BaseParams, FilterParams, HashedKey, KVDatabase, KVParams};
+use crate::errors::{ErrorUnexpectedInputSize, ResultBoxedError};
+
+/// The recommended bundle capacity in bytes, which fits e.g. an identity
+/// key, a signed prekey and its signature, and a handful of one-time keys
+pub const RECOMMENDED_BUNDLE_BYTES: usize = 256;
+
+/// The number of bytes used for the length prefix of a stored bundle
+const BUNDLE_LEN_BYTES: usize = 2;
+
+/// The `KeyDirectoryAdapter` builds a `KVShard` from (username, key
+/// bundle) pairs, and helps clients query it.
+///
+/// Usernames are normalized (trimmed and lowercased) before hashing, so
+/// that lookups are case-insensitive. Bundles may have different lengths,
+/// so each one is stored with a length prefix and zero-padded to the
+/// configured capacity.
+#[derive(Clone, Debug)]
+pub struct KeyDirectoryAdapter {
+  lwe_dim: usize,
+  plaintext_bits: usize,
+  bundle_bytes: usize,
+}
+
+impl Default for KeyDirectoryAdapter {
+  fn default() -> Self {
+    Self::recommended()
+  }
+}
+
+impl KeyDirectoryAdapter {
+  /// Returns an adapter using the recommended parameters
+  pub fn recommended() -> Self {
+    Self {
+      lwe_dim: RECOMMENDED_LWE_DIM,
+      plaintext_bits: RECOMMENDED_PLAINTEXT_BITS,
+      bundle_bytes: RECOMMENDED_BUNDLE_BYTES,
+    }
+  }
+
+  /// Sets the LWE dimension
+  pub fn with_lwe_dim(mut self, lwe_dim: usize) -> Self {
+    self.lwe_dim = lwe_dim;
+    self
+  }
+
+  /// Sets the number of plaintext bits per DB matrix entry
+  pub fn with_plaintext_bits(mut self, plaintext_bits: usize) -> Self {
+    self.plaintext_bits = plaintext_bits;
+    self
+  }
+
+  /// Sets the maximum size of a key bundle in bytes
+  pub fn with_bundle_bytes(mut self, bundle_bytes: usize) -> Self {
+    self.bundle_bytes = bundle_bytes;
+    self
+  }
+
+  /// Normalizes a username, such that lookups are case-insensitive
+  pub fn normalize_username(username: &str) -> String {
+    username.trim().to_lowercase()
+  }
+
+  /// Returns the key under which the bundle of `username` is stored
+  pub fn lookup_key(username: &str) -> ResultBoxedError<HashedKey> {
+    Ok(HashedKey::from_bytes(
+      Self::normalize_username(username).as_bytes(),
+    ))
+  }
+
+  /// Builds a `KVShard` from (username, key bundle) pairs
+  pub fn build(
+    &self,
+    entries: &[(String, Vec<u8>)],
+  ) -> ResultBoxedError<KVShard> {
+    let max_len = (1 << (8 * BUNDLE_LEN_BYTES)) - 1;
+    let mut keys = Vec::with_capacity(entries.len());
+    let mut values = Vec::with_capacity(entries.len());
+    for (username, bundle) in entries {
+      if bundle.len() > self.bundle_bytes || bundle.len() > max_len {
+        return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+          "Bundle of {} has {} bytes, but the capacity is {} bytes",
+          username,
+          bundle.len(),
+          self.bundle_bytes.min(max_len)
+        ))));
+      }
+      let mut value = (bundle.len() as u16).to_le_bytes().to_vec();
+      value.extend(bundle);
+      value.resize(BUNDLE_LEN_BYTES + self.bundle_bytes, 0);
+      keys.push(Self::normalize_username(username));
+      values.push(base64::encode(value));
+    }
+    KVShard::from_base64_strings(
+      &keys,
+      &values,
+      self.lwe_dim,
+      (BUNDLE_LEN_BYTES + self.bundle_bytes) * 8,
+      self.plaintext_bits,
+    )
+  }
+
+  /// Parses a response to a query for `username` into its key bundle
+  pub fn parse_bundle(
+    qp: &QueryParams<KVDatabase, FilterParams>,
+    resp: &Response,
+    username: &str,
+  ) -> ResultBoxedError<Vec<u8>> {
+    let value = qp.parse_resp_as_bytes(resp, &Self::lookup_key(username)?)?;
+    if value.len() < BUNDLE_LEN_BYTES {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "Value has {} bytes, but the length prefix needs {}",
+        value.len(),
+        BUNDLE_LEN_BYTES
+      ))));
+    }
+    let len = u16::from_le_bytes([value[0], value[1]]) as usize;
+    let bundle = &value[BUNDLE_LEN_BYTES..];
+    if len > bundle.len() {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "Bundle length: {}, available bytes: {}",
+        len,
+        bundle.len()
+      ))));
+    }
+    Ok(bundle[..len].to_vec())
+  }
+
+  /// Returns the size of a response from a directory with `params`, in
+  /// bytes, which is useful for capacity planning
+  pub fn response_bytes(params: &KVParams) -> usize {
+    params.get_rhs().len() * std::mem::size_of::<u32>()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::*;
+  use rand_core::{OsRng, RngCore};
+
+  #[test]
+  fn key_directory_lookup() {
+    let entries: Vec<(String, Vec<u8>)> = (0..128)
+      .map(|i| {
+        let mut bundle = vec![0u8; 32 + (i % 4) * 32];
+        OsRng.fill_bytes(&mut bundle);
+        (format!("User{}@example.org", i), bundle)
+      })
+      .collect();
+    let adapter = KeyDirectoryAdapter::recommended()
+      .with_lwe_dim(512)
+      .with_bundle_bytes(128);
+    let shard = adapter.build(&entries).unwrap();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    assert_eq!(
+      KeyDirectoryAdapter::response_bytes(bp),
+      shard.get_db().get_row_width_self() * 4
+    );
+
+    for (username, bundle) in [&entries[3], &entries[42]] {
+      // lookups are case-insensitive
+      let lookup = username.to_uppercase();
+      let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+      let q = qp
+        .generate_query(&KeyDirectoryAdapter::lookup_key(&lookup).unwrap())
+        .unwrap();
+      let resp: Response =
+        bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+      let parsed = KeyDirectoryAdapter::parse_bundle(&qp, &resp, &lookup);
+      assert_eq!(&parsed.unwrap(), bundle);
+    }
+
+    let oversized = vec![("big".to_string(), vec![0u8; 129])];
+    assert!(adapter.build(&oversized).is_err());
+  }
+}
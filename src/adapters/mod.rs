@@ -0,0 +1,19 @@
+//! The `adapters` module converts common dataset shapes into shards,
+//! choosing record layouts and parameters that suit each use case. They
+//! also serve as reference implementations for building applications on
+//! top of the lower-level `api` module.
+pub mod dns;
+pub mod hash_prefix;
+pub mod key_directory;
+
+pub use dns::{DnsAdapter, DnsRecordSet};
+pub use hash_prefix::HashPrefixAdapter;
+pub use key_directory::KeyDirectoryAdapter;
+
+/// The recommended LWE dimension for the adapters, which is secure for
+/// DBs of up to 2^16 rows with `RECOMMENDED_PLAINTEXT_BITS` (see the
+/// README)
+pub const RECOMMENDED_LWE_DIM: usize = 1774;
+
+/// The recommended number of plaintext bits per DB matrix entry
+pub const RECOMMENDED_PLAINTEXT_BITS: usize = 10;
@@ -1,27 +1,140 @@
 //! The `api` module is the public entry point for all PIR operations.
 use std::fs;
+use std::io::{BufWriter, Read, Write};
+use std::ops::Range;
 use std::str;
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use core::marker::PhantomData;
-use serde::{Deserialize, Serialize};
+use memmap2::Mmap;
+use rand_core::{OsRng, RngCore};
+use rayon::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use static_assertions::assert_impl_all;
 
-pub use crate::db::{BaseParams, CommonParams, DatabaseMatrix};
-use crate::db::{FilterParams, KVDatabase, KVParams, KeyValue};
+use crate::audit::{
+  committed_records, DbCommitment, InclusionProof, RecordTree,
+};
+use crate::compression::{decompress_record, Compression};
+use crate::db::{
+  epoch_seed, split_into_chunks, FilterParams, KVDatabase, KVParams, KeyValue,
+};
+pub use crate::db::{
+  BaseParams, CommonParams, DatabaseMatrix, DatabaseMatrixLayout, FilterConfig,
+  FilterPrf, HashedKey, KeyBytes, RecordBytes,
+};
 use crate::db::{IndexDatabase, IndexParams};
+use crate::entropy::Entropy;
+use crate::expiry::unstamp_record;
 
 use crate::errors::{
-  ErrorOverflownAdd, ErrorQueryParamsReused, ResultBoxedError,
+  ErrorHintExhausted, ErrorHintMismatch, ErrorLoadFile, ErrorOverflownAdd,
+  ErrorParamsExpired, ErrorQueryParamsReused, ErrorSnapshotFormat,
+  ErrorUnexpectedInputSize, ResultBoxedError,
+};
+use crate::proxy::{AggregatedQuery, AggregatedResponse};
+pub use crate::tuning::RespondConfig;
+use crate::tuning::{respond_columns, tune};
+use crate::utils::arith::*;
+#[cfg(feature = "object-store")]
+use crate::utils::files::fetch_persisted;
+use crate::utils::files::{
+  read_json_file, read_jsonl_file, read_persisted, write_flat_matrix,
+  write_persisted, FlatMatrix, Persist, FLAT_MATRIX_HEADER_LEN,
 };
 pub use crate::utils::format::*;
 use crate::utils::lwe::*;
+pub use crate::utils::lwe::{Modulus, RoundingMode};
 use crate::utils::matrices::*;
+use crate::utils::random::{derive_seed, generate_seed};
+
+/// Identifies snapshots written by `Shard::write_snapshot`
+const SNAPSHOT_MAGIC: &[u8] = b"chalamet-snapshot";
+
+/// The version of the snapshot layout. Snapshots of other versions are
+/// rejected on load.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// The fields of a `Shard` that are stored as JSON in a snapshot, i.e.
+/// all but the DB matrix and the RHS of the params
+#[derive(Serialize, Deserialize)]
+struct SnapshotMeta {
+  db: IndexDatabase,
+  base_params: IndexParams,
+  respond_config: RespondConfig,
+}
+
+/// The length of the magic, version and JSON length that precede the JSON
+/// fields of a snapshot
+const SNAPSHOT_PREFIX_LEN: usize = SNAPSHOT_MAGIC.len() + 4 + 8;
+
+/// Parses a snapshot written by `Shard::write_snapshot` into its JSON
+/// fields and the positions of the DB matrix and the RHS of the params,
+/// checking that `bytes` holds exactly the lengths it claims
+fn parse_snapshot(
+  bytes: &[u8],
+) -> ResultBoxedError<(SnapshotMeta, FlatMatrix, FlatMatrix)> {
+  let invalid = ErrorSnapshotFormat::new;
+  let prefix = bytes
+    .get(..SNAPSHOT_PREFIX_LEN)
+    .ok_or_else(|| invalid("truncated header".to_string()))?;
+  let (magic, prefix) = prefix.split_at(SNAPSHOT_MAGIC.len());
+  if magic != SNAPSHOT_MAGIC {
+    return Err(Box::new(invalid("not a snapshot".to_string())));
+  }
+  let (version, meta_len) = prefix.split_at(4);
+  let version = u32::from_le_bytes(version.try_into()?);
+  if version != SNAPSHOT_VERSION {
+    return Err(Box::new(invalid(format!(
+      "unsupported version {} (expected {})",
+      version, SNAPSHOT_VERSION
+    ))));
+  }
+  let meta_len = u64::from_le_bytes(meta_len.try_into()?);
+  let meta_end = usize::try_from(meta_len)
+    .ok()
+    .and_then(|len| SNAPSHOT_PREFIX_LEN.checked_add(len))
+    .filter(|&end| end <= bytes.len())
+    .ok_or_else(|| {
+      invalid(format!(
+        "JSON fields of {} bytes exceed the snapshot of {} bytes",
+        meta_len,
+        bytes.len()
+      ))
+    })?;
+  let meta = serde_json::from_slice(&bytes[SNAPSHOT_PREFIX_LEN..meta_end])?;
+  let entries = FlatMatrix::parse(bytes, meta_end)
+    .ok_or_else(|| invalid("truncated DB matrix".to_string()))?;
+  let rhs = FlatMatrix::parse(bytes, entries.end())
+    .ok_or_else(|| invalid("truncated params".to_string()))?;
+  if rhs.end() != bytes.len() {
+    return Err(Box::new(invalid(format!(
+      "{} trailing bytes",
+      bytes.len() - rhs.end()
+    ))));
+  }
+  Ok((meta, entries, rhs))
+}
 
 /// A `Shard` is an instance of a database, where each row corresponds
 /// to a single element, that has been preprocessed by the server.
+///
+/// Shards are `Send + Sync`, and are shared by server threads by cloning
+/// them, which is cheap since the DB and params are held behind an `Arc`
+/// and only copied when a clone is modified (see `deep_clone`).
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Shard {
-  db: IndexDatabase,
-  base_params: IndexParams,
+  db: Arc<IndexDatabase>,
+  base_params: Arc<IndexParams>,
+  /// The loop structure used by `respond`, see `tune`
+  #[serde(skip)]
+  respond_config: RespondConfig,
+  /// The Merkle tree over the records of the DB, built by the first call
+  /// to `commitment` or `prove_inclusion` and shared with clones
+  #[serde(skip)]
+  record_tree: Arc<OnceLock<RecordTree>>,
 }
 impl Shard {
   /// Expects a JSON file of base64-encoded strings in file path. It also
@@ -35,9 +148,20 @@ impl Shard {
     elem_size: usize,
     plaintext_bits: usize,
   ) -> ResultBoxedError<Self> {
-    let file_contents: String =
-      fs::read_to_string(file_path).unwrap().parse().unwrap();
-    let elements: Vec<String> = serde_json::from_str(&file_contents).unwrap();
+    let elements: Vec<String> = read_json_file(file_path)?;
+    Shard::from_base64_strings(&elements, lwe_dim, m, elem_size, plaintext_bits)
+  }
+
+  /// Same as `from_json_file`, for a line-delimited JSON file holding one
+  /// base64-encoded string per line, which is parsed in parallel
+  pub fn from_jsonl_file(
+    file_path: &str,
+    lwe_dim: usize,
+    m: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+  ) -> ResultBoxedError<Self> {
+    let elements: Vec<String> = read_jsonl_file(file_path)?;
     Shard::from_base64_strings(&elements, lwe_dim, m, elem_size, plaintext_bits)
   }
 
@@ -50,9 +174,121 @@ impl Shard {
     elem_size: usize,
     plaintext_bits: usize,
   ) -> ResultBoxedError<Self> {
-    let db = IndexDatabase::new(base64_strs, m, elem_size, plaintext_bits)?;
+    Shard::from_encoded_strings(
+      base64_strs,
+      lwe_dim,
+      m,
+      elem_size,
+      plaintext_bits,
+      EncodingConfig::Standard,
+    )
+  }
+
+  /// Expects an array of strings serialized using `encoding` and converts
+  /// into a database that can process client queries
+  pub fn from_encoded_strings(
+    encoded_strs: &[String],
+    lwe_dim: usize,
+    m: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+    encoding: EncodingConfig,
+  ) -> ResultBoxedError<Self> {
+    Shard::from_encoded_strings_with_seed(
+      encoded_strs,
+      lwe_dim,
+      m,
+      elem_size,
+      plaintext_bits,
+      encoding,
+      generate_seed(),
+    )
+  }
+
+  /// Same as `from_encoded_strings`, deriving all server randomness from
+  /// `seed`. The resulting shard (and its serialization) is fully
+  /// determined by the inputs, which is useful for tests and reproducible
+  /// builds. The seed is not a secret: the only value derived from it is
+  /// the seed of the public matrix (`derive_seed(seed, b"public")`), which
+  /// clients receive with the params.
+  pub fn from_encoded_strings_with_seed(
+    encoded_strs: &[String],
+    lwe_dim: usize,
+    m: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+    encoding: EncodingConfig,
+    seed: [u8; 32],
+  ) -> ResultBoxedError<Self> {
+    let db = IndexDatabase::new_with_encoding(
+      encoded_strs,
+      m,
+      elem_size,
+      plaintext_bits,
+      encoding,
+    )?;
+    let base_params =
+      IndexParams::new_with_seed(&db, lwe_dim, derive_seed(seed, b"public"));
+    Ok(Self {
+      db: Arc::new(db),
+      base_params: Arc::new(base_params),
+      respond_config: RespondConfig::default(),
+      record_tree: Default::default(),
+    })
+  }
+
+  /// Same as `from_encoded_strings`, storing duplicate elements in a
+  /// single matrix row (see `IndexDatabase::new_deduplicated`). Clients
+  /// query elements by their index, which `QueryParams` map to the row
+  /// holding the element (see `IndexParams::get_record_row`).
+  pub fn from_encoded_strings_deduplicated(
+    encoded_strs: &[String],
+    lwe_dim: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+    encoding: EncodingConfig,
+  ) -> ResultBoxedError<Self> {
+    let db = IndexDatabase::new_deduplicated(
+      encoded_strs,
+      elem_size,
+      plaintext_bits,
+      encoding,
+    )?;
+    let base_params = IndexParams::new(&db, lwe_dim);
+    Ok(Self {
+      db: Arc::new(db),
+      base_params: Arc::new(base_params),
+      respond_config: RespondConfig::default(),
+      record_tree: Default::default(),
+    })
+  }
+
+  /// Same as `from_encoded_strings`, splitting each element into columns
+  /// of `column_bits[i]` bits (see `IndexDatabase::new_with_column_bits`).
+  /// The widths are stored in the params, so that clients decode
+  /// responses accordingly.
+  pub fn from_encoded_strings_with_column_bits(
+    encoded_strs: &[String],
+    lwe_dim: usize,
+    m: usize,
+    elem_size: usize,
+    column_bits: &[usize],
+    encoding: EncodingConfig,
+  ) -> ResultBoxedError<Self> {
+    let db = IndexDatabase::new_with_column_bits(
+      encoded_strs,
+      m,
+      elem_size,
+      column_bits,
+      encoding,
+    )?;
     let base_params = IndexParams::new(&db, lwe_dim);
-    Ok(Self { db, base_params })
+    Ok(Self {
+      db: Arc::new(db),
+      base_params: Arc::new(base_params),
+      respond_config: RespondConfig::default(),
+      record_tree: Default::default(),
+    })
   }
 
   /// Write base_params and DB to file
@@ -66,20 +302,325 @@ impl Shard {
     Ok(())
   }
 
+  /// Loads a DB and base_params written using `write_to_file`, checking
+  /// that the params were generated for the DB
+  pub fn load(db_path: &str, params_path: &str) -> ResultBoxedError<Self> {
+    let db = IndexDatabase::load(db_path)?;
+    let base_params = IndexParams::load(params_path)?;
+    base_params.verify_against(&db)?;
+    Ok(Self {
+      db: Arc::new(db),
+      base_params: Arc::new(base_params),
+      respond_config: RespondConfig::default(),
+      record_tree: Default::default(),
+    })
+  }
+
+  /// Same as `load`, for a DB and base_params written in the legacy
+  /// format, which does not record the element size and plaintext bits
+  /// (see `IndexDatabase::load_legacy`). Writing the shard again using
+  /// `write_to_file` migrates the files to the current format.
+  pub fn load_legacy(
+    db_path: &str,
+    params_path: &str,
+    elem_size: usize,
+    plaintext_bits: usize,
+  ) -> ResultBoxedError<Self> {
+    let db = IndexDatabase::load_legacy(db_path, elem_size, plaintext_bits)?;
+    let base_params = IndexParams::load_legacy(params_path, &db)?;
+    base_params.verify_against(&db)?;
+    Ok(Self {
+      db: Arc::new(db),
+      base_params: Arc::new(base_params),
+      respond_config: RespondConfig::default(),
+      record_tree: Default::default(),
+    })
+  }
+
+  /// Same as `load`, fetching the DB and base_params from the object
+  /// stores (e.g. S3 or GCS) addressed by `db_url` and `params_url`, such
+  /// as `s3://bucket/db.json`. Each store is configured with `options`
+  /// (see `utils::files::fetch_persisted`).
+  #[cfg(feature = "object-store")]
+  pub fn from_object_store(
+    db_url: &str,
+    params_url: &str,
+    options: &[(&str, &str)],
+  ) -> ResultBoxedError<Self> {
+    let db: IndexDatabase = fetch_persisted(db_url, options)?;
+    let base_params: IndexParams = fetch_persisted(params_url, options)?;
+    base_params.verify_against(&db)?;
+    Ok(Self {
+      db: Arc::new(db),
+      base_params: Arc::new(base_params),
+      respond_config: RespondConfig::default(),
+      record_tree: Default::default(),
+    })
+  }
+
+  /// Serializes the DB and base_params as a single snapshot (see
+  /// `write_snapshot`)
+  pub fn serialize_snapshot(&self) -> ResultBoxedError<Vec<u8>> {
+    let mut out = Vec::new();
+    self.write_snapshot(&mut out)?;
+    Ok(out)
+  }
+
+  /// Parses a snapshot produced by `serialize_snapshot`
+  pub fn deserialize_snapshot(bytes: &[u8]) -> ResultBoxedError<Self> {
+    let (meta, entries, rhs) = parse_snapshot(bytes)?;
+    Self::from_snapshot_parts(
+      meta,
+      entries.read_vecs(bytes).into(),
+      rhs.read_vecs(bytes),
+    )
+  }
+
+  /// Writes the DB, base_params and respond configuration to `w` as a
+  /// snapshot: the small fields as JSON, followed by the DB matrix and the
+  /// RHS of the params in a flat layout of little-endian u32 columns. The
+  /// JSON is padded so that the columns of the DB matrix start at a
+  /// multiple of 4 bytes, and can be mapped by `load_snapshot`.
+  ///
+  /// Unlike with `write_to_file`, loading a snapshot does not parse the
+  /// matrices, so that servers start in about the time it takes to map
+  /// the file. Snapshots are meant for the server that wrote them, and
+  /// are not checked against the params on load (see `load`).
+  pub fn write_snapshot<W: Write>(&self, w: W) -> ResultBoxedError<()> {
+    let mut w = BufWriter::new(w);
+    let (entries, db) = self.db.split_entries();
+    let (rhs, base_params) = self.base_params.split_rhs();
+    let mut meta = serde_json::to_vec(&SnapshotMeta {
+      db,
+      base_params,
+      respond_config: self.respond_config,
+    })?;
+    while (SNAPSHOT_PREFIX_LEN + meta.len() + FLAT_MATRIX_HEADER_LEN) % 4 != 0 {
+      meta.push(b' ');
+    }
+    w.write_all(SNAPSHOT_MAGIC)?;
+    w.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    w.write_all(&(meta.len() as u64).to_le_bytes())?;
+    w.write_all(&meta)?;
+    write_flat_matrix(&mut w, &entries.slices())?;
+    write_flat_matrix(&mut w, rhs)?;
+    Ok(w.flush()?)
+  }
+
+  /// Reads a snapshot written using `write_snapshot` from `r`. The DB
+  /// matrix is copied into memory, use `load_snapshot` to map it instead.
+  pub fn read_snapshot<R: Read>(mut r: R) -> ResultBoxedError<Self> {
+    let mut bytes = Vec::new();
+    r.read_to_end(&mut bytes)?;
+    Self::deserialize_snapshot(&bytes)
+  }
+
+  /// Writes a snapshot (see `write_snapshot`) to the file at `path`
+  pub fn write_snapshot_file(&self, path: &str) -> ResultBoxedError<()> {
+    self.write_snapshot(fs::File::create(path)?)
+  }
+
+  /// Loads a snapshot written using `write_snapshot_file` by memory-mapping
+  /// the file: the DB matrix is served from the mapping without being
+  /// copied (until the DB is modified), and only the pages that queries
+  /// touch are read from disk (see `prefault`). The file must not be
+  /// modified while the shard, or any clone of it, is alive.
+  pub fn load_snapshot(path: &str) -> ResultBoxedError<Self> {
+    let file =
+      fs::File::open(path).map_err(|e| ErrorLoadFile::from_io(path, &e))?;
+    // SAFETY: the mapping is read-only, and the file is not modified
+    // while it is mapped, as documented above
+    let map = unsafe { Mmap::map(&file) }
+      .map_err(|e| ErrorLoadFile::from_io(path, &e))?;
+    let map = Arc::new(map);
+    let (meta, entries, rhs) = parse_snapshot(&map)?;
+    Self::from_snapshot_parts(
+      meta,
+      ColumnMatrix::mapped(map.clone(), entries),
+      rhs.read_vecs(&map),
+    )
+  }
+
+  /// Builds a shard from the parts of a snapshot (see `parse_snapshot`)
+  fn from_snapshot_parts(
+    meta: SnapshotMeta,
+    entries: ColumnMatrix,
+    rhs: Vec<Vec<u32>>,
+  ) -> ResultBoxedError<Self> {
+    Ok(Self {
+      db: Arc::new(meta.db.join_entries(entries)?),
+      base_params: Arc::new(meta.base_params.join_rhs(rhs)?),
+      respond_config: meta.respond_config,
+      record_tree: Default::default(),
+    })
+  }
+
+  /// Builds the next version of the shard from this one and a changeset
+  /// of base64-encoded `added` elements and `removed` row indices (see
+  /// `IndexDatabase::with_changes` for where rows end up). The public
+  /// seed is kept, so that only the RHS contribution of changed rows is
+  /// recomputed rather than the full `A*DB` product.
+  pub fn rebuild_with_changes(
+    &self,
+    added: &[String],
+    removed: &[usize],
+  ) -> ResultBoxedError<Self> {
+    let (db, deltas) = self.db.with_changes(added, removed)?;
+    let base_params = self.base_params.with_row_deltas(&db, &deltas);
+    Ok(Self {
+      db: Arc::new(db),
+      base_params: Arc::new(base_params),
+      respond_config: self.respond_config,
+      record_tree: Default::default(),
+    })
+  }
+
   /// Produces a serialized response (base64-encoded) to a serialized
   /// client query
   pub fn respond(&self, q: &Query) -> ResultBoxedError<Vec<u8>> {
-    let q = q.as_slice();
-    let resp = Response(
-      (0..self.db.get_row_width_self())
-        .map(|i| self.db.vec_mult(q, i))
-        .collect(),
-    );
+    let resp = Response(checked_inner_products(
+      &*self.db,
+      q.as_slice(),
+      self.respond_config,
+    )?);
     let ser = bincode::serialize(&resp);
 
     Ok(ser?)
   }
 
+  /// Returns the inner products of `vector` with every column of the DB
+  /// matrix, i.e. the raw values of a response to `vector`, computed with
+  /// the configuration used by `respond`. No query semantics are applied,
+  /// so that variants of the protocol (e.g. other encodings or batch
+  /// codes) can reuse the multiplication kernel. Products wrap modulo
+  /// 2^32, and `vector` must have `height()` entries.
+  pub fn inner_products(&self, vector: &[u32]) -> ResultBoxedError<Vec<u32>> {
+    checked_inner_products(&*self.db, vector, self.respond_config)
+  }
+
+  /// Runs a quick micro-benchmark of the ways of computing responses
+  /// (see `tuning`) on this machine, and uses the fastest one for
+  /// subsequent calls to `respond`. This is meant to be called once at
+  /// startup, and returns the selected configuration.
+  pub fn tune(&mut self) -> ResultBoxedError<RespondConfig> {
+    self.respond_config = tune(&*self.db)?;
+    Ok(self.respond_config)
+  }
+
+  /// Returns the configuration used by `respond`
+  pub fn get_respond_config(&self) -> RespondConfig {
+    self.respond_config
+  }
+
+  /// Sets the configuration used by `respond`, e.g. to one that was
+  /// selected by `tune` on an identical machine
+  pub fn set_respond_config(&mut self, config: RespondConfig) {
+    self.respond_config = config;
+  }
+
+  /// Same as `respond`, computing the response columns in parallel on
+  /// the current rayon thread pool (see `serving::ServingShard` for
+  /// running it on a configured pool)
+  pub fn respond_par(&self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    Ok(bincode::serialize(&respond_columns_par(&*self.db, q)?)?)
+  }
+
+  /// Computes the response to a client query column by column, writing
+  /// each value to `w` as soon as it is computed. The bytes written are
+  /// identical to the output of `respond`, but the full response is never
+  /// held in memory. Writes are small, so `w` should be buffered.
+  pub fn respond_stream<W: Write>(
+    &self,
+    q: &Query,
+    w: W,
+  ) -> ResultBoxedError<()> {
+    write_response_stream(&*self.db, q, w)
+  }
+
+  /// Computes the response to a client query in frames of `chunk_cols`
+  /// consecutive columns, serializing each `ResponseFrame` as soon as it
+  /// is computed. Clients decode each frame independently as it arrives
+  /// (see `QueryParams::parse_frame_as_row`).
+  pub fn respond_frames<'a>(
+    &'a self,
+    q: &'a Query,
+    chunk_cols: usize,
+  ) -> ResultBoxedError<impl Iterator<Item = ResultBoxedError<Vec<u8>>> + 'a>
+  {
+    response_frames(&*self.db, q, chunk_cols)
+  }
+
+  /// Produces a serialized response to a `SparseQuery`, after expanding
+  /// it using `cp`
+  pub fn respond_sparse(
+    &self,
+    cp: &CommonParams,
+    q: &SparseQuery,
+  ) -> ResultBoxedError<Vec<u8>> {
+    self.respond(&q.expand(cp)?)
+  }
+
+  /// Touches every memory page of the DB matrix, so that the first
+  /// queries do not incur page-fault latency (e.g. after loading)
+  pub fn prefault(&self) {
+    self.db.prefault();
+  }
+
+  /// Maintains a row-major copy of the DB matrix for `respond_batch` (see
+  /// `IndexDatabase::set_dual_layout`). If the DB is shared with clones of
+  /// the shard, it is copied first (see `Arc::make_mut`), which costs as
+  /// much as `deep_clone`, so this is best called before the shard is
+  /// cloned.
+  pub fn set_dual_layout(&mut self, enabled: bool) {
+    Arc::make_mut(&mut self.db).set_dual_layout(enabled);
+  }
+
+  /// Produces serialized responses to a batch of client queries, each
+  /// identical to the output of `respond`. If the shard maintains a
+  /// row-major copy of the DB matrix (see `set_dual_layout`), all
+  /// queries are answered in a single pass over the rows.
+  pub fn respond_batch(
+    &self,
+    queries: &[Query],
+  ) -> ResultBoxedError<Vec<Vec<u8>>> {
+    respond_batch_with(&*self.db, queries, self.respond_config)
+  }
+
+  /// Returns a copy of the shard that shares no memory with it, unlike
+  /// `clone`, which shares the DB and params until either is modified
+  pub fn deep_clone(&self) -> Self {
+    Self {
+      db: Arc::new(IndexDatabase::clone(&self.db)),
+      base_params: Arc::new(IndexParams::clone(&self.base_params)),
+      respond_config: self.respond_config,
+      record_tree: Default::default(),
+    }
+  }
+
+  /// Returns the commitment to the records of the DB, as clients decode
+  /// them (see `audit::decode_all`), which deployments publish so that
+  /// auditors can compare it to the commitment of the published dataset
+  /// (see `audit::commit_records`). Expiry prefixes are not committed to.
+  pub fn commitment(&self) -> ResultBoxedError<DbCommitment> {
+    Ok(self.record_tree()?.commitment())
+  }
+
+  /// Returns the proof that the ith record of the DB is part of the DB
+  /// committed to by `commitment` (see `audit::verify_inclusion`)
+  pub fn prove_inclusion(&self, i: usize) -> ResultBoxedError<InclusionProof> {
+    self.record_tree()?.prove(i)
+  }
+
+  /// Returns the Merkle tree over the records of the DB, building it on
+  /// the first call
+  fn record_tree(&self) -> ResultBoxedError<&RecordTree> {
+    if let Some(tree) = self.record_tree.get() {
+      return Ok(tree);
+    }
+    let records = committed_records(&self.db, &self.base_params)?;
+    Ok(self.record_tree.get_or_init(|| RecordTree::new(&records)))
+  }
+
   /// Returns the database
   pub fn get_db(&self) -> &IndexDatabase {
     &self.db
@@ -90,6 +631,34 @@ impl Shard {
     &self.base_params
   }
 
+  /// Sets the Unix timestamp (in seconds) at which the base parameters
+  /// expire, after which clients refuse to use them
+  pub fn set_params_expires_at(&mut self, expires_at: Option<u64>) {
+    Arc::make_mut(&mut self.base_params).set_expires_at(expires_at);
+  }
+
+  /// Records the compression that was applied to each record in the
+  /// base parameters
+  pub(crate) fn set_params_compression(&mut self, compression: Compression) {
+    Arc::make_mut(&mut self.base_params).set_compression(compression);
+    self.record_tree = Default::default();
+  }
+
+  /// Records whether each record is prefixed with its expiry timestamp
+  /// in the base parameters
+  pub(crate) fn set_params_record_expiry(&mut self, record_expiry: bool) {
+    Arc::make_mut(&mut self.base_params).set_record_expiry(record_expiry);
+    self.record_tree = Default::default();
+  }
+
+  /// Sets the ciphertext modulus in the base parameters
+  pub(crate) fn set_params_modulus(
+    &mut self,
+    modulus: Modulus,
+  ) -> ResultBoxedError<()> {
+    Arc::make_mut(&mut self.base_params).set_modulus(modulus)
+  }
+
   pub fn into_row_iter(&self) -> std::vec::IntoIter<std::string::String> {
     (0..self.get_db().get_matrix_height())
       .map(|i| self.get_db().get_db_entry(i))
@@ -108,11 +677,12 @@ pub struct EncodedKV {
 /// A `KVShard` is an instance of a key-value database, where each row
 /// corresponds to a single entry of multiple filter structures. The
 /// mathematical interaction between client queries and the KV database
-/// is the same as for standard databases.
+/// is the same as for standard databases. As for `Shard`, clones share
+/// the DB and params (see `deep_clone`).
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct KVShard {
-  db: KVDatabase,
-  base_params: KVParams,
+  db: Arc<KVDatabase>,
+  base_params: Arc<KVParams>,
 }
 
 impl KVShard {
@@ -120,16 +690,36 @@ impl KVShard {
   pub fn new(
     kvs: &[KeyValue],
     lwe_dim: usize,
-    m: usize,
     elem_size: usize,
     plaintext_bits: usize,
   ) -> ResultBoxedError<Self> {
-    let db = KVDatabase::new(kvs, m, elem_size, plaintext_bits)?;
+    let db = KVDatabase::new(kvs, elem_size, plaintext_bits)?;
+    Ok(Self::from_db(db, lwe_dim))
+  }
+
+  /// Creates a new `KVShard` from keys that were already hashed (i.e. the
+  /// words of the SHA-256 digests of the key strings, see
+  /// `KVDatabase::from_hashed_keys`) and the raw bytes of the values
+  pub fn from_hashed_keys(
+    keys: &[[u64; 4]],
+    values: &[Vec<u8>],
+    lwe_dim: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+  ) -> ResultBoxedError<Self> {
+    let db =
+      KVDatabase::from_hashed_keys(keys, values, elem_size, plaintext_bits)?;
+    Ok(Self::from_db(db, lwe_dim))
+  }
+
+  /// Generates the base params for `db`
+  fn from_db(db: KVDatabase, lwe_dim: usize) -> Self {
     let &FilterParams {
       seed,
       segment_length,
       segment_length_mask,
       segment_count_length,
+      ..
     } = db.get_filter_params();
     let base_params = KVParams::new(
       &db,
@@ -139,93 +729,379 @@ impl KVShard {
       segment_length_mask,
       segment_count_length,
     );
-    Ok(Self { db, base_params })
+    Self {
+      db: Arc::new(db),
+      base_params: Arc::new(base_params),
+    }
   }
 
-  /// Expects a JSON file of base64-encoded strings in file path. It also
-  /// expects the lwe dimension, m (the number of DB elements), element size
-  /// (in bytes) of the database elements, and plaintext bits.
+  /// Builds the next version of the shard, given all KV pairs `kvs` of
+  /// the DB after the values at the indices `changed` were modified. The
+  /// filter slots affected by the changes are re-solved in place (see
+  /// `KVDatabase::with_value_changes`), keeping the filter and public
+  /// seeds, so that only the RHS contribution of the changed rows is
+  /// recomputed and `ParamsDelta::between` yields a small delta for
+  /// clients. If the keys of `kvs` are not the keys of the DB, the shard
+  /// is rebuilt from scratch with fresh seeds instead, keeping its
+  /// configuration and filter epoch. The chunk count of shards whose
+  /// values were split into chunks (see `KVParams::get_chunks`) cannot be
+  /// derived from `kvs`, so such shards are not rebuilt from scratch, and
+  /// must be built from their values again instead.
+  pub fn rebuild_with_value_changes(
+    &self,
+    kvs: &[KeyValue],
+    changed: &[usize],
+  ) -> ResultBoxedError<Self> {
+    let changes = changed
+      .iter()
+      .map(|&i| {
+        kvs.get(i).cloned().ok_or_else(|| {
+          ErrorUnexpectedInputSize::new(format!(
+            "changed index {} out of {} KV pairs",
+            i,
+            kvs.len()
+          ))
+          .into()
+        })
+      })
+      .collect::<ResultBoxedError<Vec<KeyValue>>>()?;
+    let keys: Vec<HashedKey> = kvs.iter().map(|kv| kv.key).collect();
+    if let Some((db, deltas)) = self.db.with_value_changes(&keys, &changes)? {
+      return Ok(Self {
+        db: Arc::new(db),
+        base_params: Arc::new(self.base_params.with_row_deltas(&deltas)),
+      });
+    }
+    if self.base_params.get_chunks().is_some() {
+      return Err(
+        "Shards of chunked values must be rebuilt from the values".into(),
+      );
+    }
+    let mut db = KVDatabase::new_with_prf(
+      kvs,
+      self.db.get_elem_size(),
+      self.db.get_plaintext_bits(),
+      generate_seed(),
+      &self.db.get_filter_config(),
+      self.db.get_filter_params().prf,
+    )?;
+    db.set_filter_epoch(self.db.get_filter_params().epoch);
+    db.set_dual_layout(self.db.get_row_major().is_some());
+    let mut shard = Self::from_db(db, self.base_params.get_dim());
+    shard.set_params_expires_at(self.base_params.get_expires_at());
+    shard.set_params_compression(self.base_params.get_compression());
+    shard.set_params_record_expiry(self.base_params.get_record_expiry());
+    shard.set_params_modulus(self.base_params.get_modulus())?;
+    Ok(shard)
+  }
+
+  /// Expects a JSON file of base64-encoded KV pairs in file path. It also
+  /// expects the lwe dimension, element size (in bytes) of the database
+  /// elements, and plaintext bits. The number of DB rows is derived from
+  /// the number of KV pairs.
   /// It will call the 'from_base64_strings' function to generate the database.
   pub fn from_json_file(
     file_path: &str,
     lwe_dim: usize,
-    m: usize,
     elem_size: usize,
     plaintext_bits: usize,
   ) -> ResultBoxedError<Self> {
-    let file_contents: String =
-      fs::read_to_string(file_path).unwrap().parse().unwrap();
-    let enc_kvs: Vec<EncodedKV> = serde_json::from_str(&file_contents).unwrap();
-    let keys: Vec<String> = enc_kvs.iter().map(|e| e.key.clone()).collect();
-    let values: Vec<String> = enc_kvs.iter().map(|e| e.value.clone()).collect();
+    let enc_kvs: Vec<EncodedKV> = read_json_file(file_path)?;
+    Self::from_encoded_kvs(enc_kvs, lwe_dim, elem_size, plaintext_bits)
+  }
+
+  /// Same as `from_json_file`, for a line-delimited JSON file holding one
+  /// KV pair object per line, which is parsed in parallel
+  pub fn from_jsonl_file(
+    file_path: &str,
+    lwe_dim: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+  ) -> ResultBoxedError<Self> {
+    let enc_kvs: Vec<EncodedKV> = read_jsonl_file(file_path)?;
+    Self::from_encoded_kvs(enc_kvs, lwe_dim, elem_size, plaintext_bits)
+  }
+
+  fn from_encoded_kvs(
+    enc_kvs: Vec<EncodedKV>,
+    lwe_dim: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+  ) -> ResultBoxedError<Self> {
+    let (keys, values): (Vec<String>, Vec<String>) =
+      enc_kvs.into_iter().map(|e| (e.key, e.value)).unzip();
     KVShard::from_base64_strings(
       &keys,
       &values,
       lwe_dim,
-      m,
       elem_size,
       plaintext_bits,
     )
   }
 
   /// Expects an array of base64-encoded strings and converts into a
-  /// database that can process client queries
+  /// database that can process client queries. Values longer than
+  /// `elem_size` bits are split into chunks (see
+  /// `from_encoded_strings_for_epoch`).
   pub fn from_base64_strings(
     keys: &[String],
     values: &[String],
     lwe_dim: usize,
-    m: usize,
     elem_size: usize,
     plaintext_bits: usize,
   ) -> ResultBoxedError<Self> {
-    let db = KVDatabase::from_base64_strings(
+    KVShard::from_encoded_strings(
       keys,
       values,
-      m,
+      lwe_dim,
       elem_size,
       plaintext_bits,
-    )?;
-    let &FilterParams {
-      seed,
-      segment_length,
-      segment_length_mask,
-      segment_count_length,
-    } = db.get_filter_params();
-    let base_params = KVParams::new(
-      &db,
-      lwe_dim,
-      seed,
-      segment_length,
-      segment_length_mask,
-      segment_count_length,
-    );
-    Ok(Self { db, base_params })
+      EncodingConfig::Standard,
+    )
   }
 
-  /// Write base_params and DB to file
-  pub fn write_to_file(
-    &self,
-    db_path: &str,
-    params_path: &str,
-  ) -> ResultBoxedError<()> {
-    self.db.write_to_file(db_path)?;
-    self.base_params.write_to_file(params_path)?;
-    Ok(())
+  /// Expects an array of keys and an array of values serialized using
+  /// `encoding`, and converts into a database that can process client
+  /// queries
+  pub fn from_encoded_strings(
+    keys: &[String],
+    values: &[String],
+    lwe_dim: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+    encoding: EncodingConfig,
+  ) -> ResultBoxedError<Self> {
+    KVShard::from_encoded_strings_with_seed(
+      keys,
+      values,
+      lwe_dim,
+      elem_size,
+      plaintext_bits,
+      encoding,
+      generate_seed(),
+    )
   }
 
-  /// Produces a serialized response (base64-encoded) to a serialized
-  /// client query
-  pub fn respond(&self, q: &Query) -> ResultBoxedError<Vec<u8>> {
-    let resp = Response(
-      (0..self.db.get_row_width_self())
-        .map(|i| self.db.vec_mult(q.as_slice(), i))
-        .collect(),
+  /// Same as `from_encoded_strings`, deriving all server randomness from
+  /// `seed` (see `Shard::from_encoded_strings_with_seed`). Besides the
+  /// seed of the public matrix, the seed of the filters is derived from
+  /// it, which clients also receive with the params.
+  pub fn from_encoded_strings_with_seed(
+    keys: &[String],
+    values: &[String],
+    lwe_dim: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+    encoding: EncodingConfig,
+    seed: [u8; 32],
+  ) -> ResultBoxedError<Self> {
+    KVShard::from_encoded_strings_for_epoch(
+      keys,
+      values,
+      lwe_dim,
+      elem_size,
+      plaintext_bits,
+      encoding,
+      seed,
+      0,
+    )
+  }
+
+  /// Same as `from_encoded_strings_with_seed`, re-seeding the filters
+  /// (and the public matrix) for `epoch` (see `epoch_seed`), e.g. when
+  /// rebuilding the shard for every epoch of a `RotatingShard`. The epoch
+  /// is recorded in the filter params, so that clients holding the params
+  /// of another epoch get an `ErrorFilterEpochMismatch` (see
+  /// `KvPirClient::check_shard_info`) instead of decoding garbage.
+  ///
+  /// If any value is longer than `elem_size` bits (a multiple of 8), all
+  /// values are split into chunks of `elem_size` bits (less the length
+  /// prefix, see `split_into_chunks`), which are stored under the keys
+  /// `key#0`, `key#1`, ... (see `chunk_key`). The largest number of
+  /// chunks of a value is recorded in the params (see
+  /// `KVParams::get_chunks`), and `KvPirClient` queries that many chunks
+  /// for every key and reassembles the values.
+  #[allow(clippy::too_many_arguments)]
+  pub fn from_encoded_strings_for_epoch(
+    keys: &[String],
+    values: &[String],
+    lwe_dim: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+    encoding: EncodingConfig,
+    seed: [u8; 32],
+    epoch: u64,
+  ) -> ResultBoxedError<Self> {
+    if let Some(chunked) =
+      split_oversized_values(keys, values, elem_size, encoding)?
+    {
+      let mut shard = KVShard::from_encoded_strings_for_epoch(
+        &chunked.keys,
+        &chunked.values,
+        lwe_dim,
+        elem_size,
+        plaintext_bits,
+        encoding,
+        seed,
+        epoch,
+      )?;
+      Arc::make_mut(&mut shard.base_params)
+        .set_chunks(Some(chunked.max_chunks));
+      return Ok(shard);
+    }
+    let seed = epoch_seed(seed, epoch);
+    let mut db = KVDatabase::from_encoded_strings_with_seed(
+      keys,
+      values,
+      elem_size,
+      plaintext_bits,
+      encoding,
+      seed,
+    )?;
+    db.set_filter_epoch(epoch);
+    let &FilterParams {
+      seed: filter_seed,
+      segment_length,
+      segment_length_mask,
+      segment_count_length,
+      ..
+    } = db.get_filter_params();
+    let base_params = KVParams::new_with_seed(
+      &db,
+      lwe_dim,
+      filter_seed,
+      segment_length,
+      segment_length_mask,
+      segment_count_length,
+      derive_seed(seed, b"public"),
     );
+    Ok(Self {
+      db: Arc::new(db),
+      base_params: Arc::new(base_params),
+    })
+  }
+
+  /// Write base_params and DB to file
+  pub fn write_to_file(
+    &self,
+    db_path: &str,
+    params_path: &str,
+  ) -> ResultBoxedError<()> {
+    self.db.write_to_file(db_path)?;
+    self.base_params.write_to_file(params_path)?;
+    Ok(())
+  }
+
+  /// Loads a DB and base_params written using `write_to_file`, checking
+  /// that the params were generated for the DB
+  pub fn load(db_path: &str, params_path: &str) -> ResultBoxedError<Self> {
+    let db = KVDatabase::load(db_path)?;
+    let base_params = KVParams::load(params_path)?;
+    base_params.verify_against(&db)?;
+    Ok(Self {
+      db: Arc::new(db),
+      base_params: Arc::new(base_params),
+    })
+  }
+
+  /// Same as `load`, fetching the DB and base_params from object storage
+  /// (see `Shard::from_object_store`)
+  #[cfg(feature = "object-store")]
+  pub fn from_object_store(
+    db_url: &str,
+    params_url: &str,
+    options: &[(&str, &str)],
+  ) -> ResultBoxedError<Self> {
+    let db: KVDatabase = fetch_persisted(db_url, options)?;
+    let base_params: KVParams = fetch_persisted(params_url, options)?;
+    base_params.verify_against(&db)?;
+    Ok(Self {
+      db: Arc::new(db),
+      base_params: Arc::new(base_params),
+    })
+  }
+
+  /// Produces a serialized response (base64-encoded) to a serialized
+  /// client query
+  pub fn respond(&self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    let resp = Response(checked_inner_products(
+      &*self.db,
+      q.as_slice(),
+      RespondConfig::default(),
+    )?);
     let se = bincode::serialize(&resp);
 
     Ok(se?)
   }
 
+  /// Returns the inner products of `vector` with every column of the DB
+  /// matrix (see `Shard::inner_products`)
+  pub fn inner_products(&self, vector: &[u32]) -> ResultBoxedError<Vec<u32>> {
+    checked_inner_products(&*self.db, vector, RespondConfig::default())
+  }
+
+  /// Same as `respond`, computing the response columns in parallel on
+  /// the current rayon thread pool
+  pub fn respond_par(&self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    Ok(bincode::serialize(&respond_columns_par(&*self.db, q)?)?)
+  }
+
+  /// Computes the response to a client query column by column, writing
+  /// each value to `w` as soon as it is computed. The bytes written are
+  /// identical to the output of `respond`, but the full response is never
+  /// held in memory. Writes are small, so `w` should be buffered.
+  pub fn respond_stream<W: Write>(
+    &self,
+    q: &Query,
+    w: W,
+  ) -> ResultBoxedError<()> {
+    write_response_stream(&*self.db, q, w)
+  }
+
+  /// Computes the response to a client query in frames of `chunk_cols`
+  /// consecutive columns (see `Shard::respond_frames`)
+  pub fn respond_frames<'a>(
+    &'a self,
+    q: &'a Query,
+    chunk_cols: usize,
+  ) -> ResultBoxedError<impl Iterator<Item = ResultBoxedError<Vec<u8>>> + 'a>
+  {
+    response_frames(&*self.db, q, chunk_cols)
+  }
+
+  /// Touches every memory page of the DB matrix, so that the first
+  /// queries do not incur page-fault latency (e.g. after loading)
+  pub fn prefault(&self) {
+    self.db.prefault();
+  }
+
+  /// Maintains a row-major copy of the DB matrix for `respond_batch` (see
+  /// `IndexDatabase::set_dual_layout`). If the DB is shared with clones of
+  /// the shard, it is copied first (see `Arc::make_mut`), which costs as
+  /// much as `deep_clone`, so this is best called before the shard is
+  /// cloned.
+  pub fn set_dual_layout(&mut self, enabled: bool) {
+    Arc::make_mut(&mut self.db).set_dual_layout(enabled);
+  }
+
+  /// Produces serialized responses to a batch of client queries (see
+  /// `Shard::respond_batch`)
+  pub fn respond_batch(
+    &self,
+    queries: &[Query],
+  ) -> ResultBoxedError<Vec<Vec<u8>>> {
+    respond_batch_with(&*self.db, queries, RespondConfig::default())
+  }
+
+  /// Returns a copy of the shard that shares no memory with it (see
+  /// `Shard::deep_clone`)
+  pub fn deep_clone(&self) -> Self {
+    Self {
+      db: Arc::new(KVDatabase::clone(&self.db)),
+      base_params: Arc::new(KVParams::clone(&self.base_params)),
+    }
+  }
+
   /// Returns the database
   pub fn get_db(&self) -> &KVDatabase {
     &self.db
@@ -236,6 +1112,48 @@ impl KVShard {
     &self.base_params
   }
 
+  /// Sets the Unix timestamp (in seconds) at which the base parameters
+  /// expire, after which clients refuse to use them
+  pub fn set_params_expires_at(&mut self, expires_at: Option<u64>) {
+    Arc::make_mut(&mut self.base_params).set_expires_at(expires_at);
+  }
+
+  /// Records the compression that was applied to each record in the
+  /// base parameters
+  pub(crate) fn set_params_compression(&mut self, compression: Compression) {
+    Arc::make_mut(&mut self.base_params).set_compression(compression);
+  }
+
+  /// Records whether each record is prefixed with its expiry timestamp
+  /// in the base parameters
+  pub(crate) fn set_params_record_expiry(&mut self, record_expiry: bool) {
+    Arc::make_mut(&mut self.base_params).set_record_expiry(record_expiry);
+  }
+
+  /// Sets the ciphertext modulus in the base parameters
+  pub(crate) fn set_params_modulus(
+    &mut self,
+    modulus: Modulus,
+  ) -> ResultBoxedError<()> {
+    Arc::make_mut(&mut self.base_params).set_modulus(modulus)
+  }
+
+  /// Returns the number of KV pairs stored in the shard
+  pub fn num_keys(&self) -> usize {
+    self.db.num_keys()
+  }
+
+  /// Returns the number of rows in the DB matrix that clients query over,
+  /// which is larger than `num_keys` due to the filter construction
+  pub fn matrix_height(&self) -> usize {
+    self.db.matrix_height()
+  }
+
+  /// Returns the ratio of stored KV pairs to matrix rows
+  pub fn load_factor(&self) -> f64 {
+    self.db.load_factor()
+  }
+
   pub fn into_row_iter(&self) -> std::vec::IntoIter<std::string::String> {
     (0..self.get_db().get_matrix_height())
       .map(|i| self.get_db().get_db_entry(i))
@@ -244,242 +1162,3324 @@ impl KVShard {
   }
 }
 
-/// The `QueryParams` struct is initialized to be used for a client
-/// query.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct QueryParams<DB, EP> {
-  lhs: Vec<u32>,
-  rhs: Vec<u32>,
-  elem_size: usize,
-  plaintext_bits: usize,
-  db: PhantomData<DB>,
-  extra_params: Option<EP>,
-  pub used: bool,
+/// The `PirShard` trait is the interface that is common to `Shard` and
+/// `KVShard`, so that serving code (see `serving`) can operate on either
+pub trait PirShard {
+  /// Produces a serialized response to a client query
+  fn respond(&self, q: &Query) -> ResultBoxedError<Vec<u8>>;
+  /// Same as `respond`, computing the response columns in parallel
+  fn respond_par(&self, q: &Query) -> ResultBoxedError<Vec<u8>>;
+  /// Produces serialized responses to a batch of client queries
+  fn respond_batch(&self, queries: &[Query]) -> ResultBoxedError<Vec<Vec<u8>>> {
+    queries.iter().map(|q| self.respond(q)).collect()
+  }
+  /// Writes the DB and base params to file
+  fn write_to_file(
+    &self,
+    db_path: &str,
+    params_path: &str,
+  ) -> ResultBoxedError<()>;
+  /// Returns the SHA-256 digest of the serialized base params, which
+  /// identifies the DB snapshot that is served
+  fn params_digest(&self) -> ResultBoxedError<[u8; 32]>;
+  /// Returns the base params serialized as JSON, without the
+  /// `FileHeader` of the files written by `write_to_file`, e.g. for
+  /// serving them to clients
+  fn params_json(&self) -> ResultBoxedError<Vec<u8>>;
+  /// Returns the height of the DB matrix, i.e. the length of queries
+  fn height(&self) -> usize;
+  /// Returns the width of the DB matrix, i.e. the length of responses
+  fn width(&self) -> usize;
+  /// Returns the number of plaintext bits packed into each DB entry
+  fn plaintext_bits(&self) -> usize;
+  /// Returns the ciphertext modulus of the base params
+  fn modulus(&self) -> Modulus;
+  /// Touches every memory page of the DB matrix
+  fn prefault(&self);
+  /// Sets the time (in seconds since the Unix epoch) after which clients
+  /// must stop using the base params
+  fn set_params_expires_at(&mut self, expires_at: Option<u64>);
+  /// Returns the epoch of the filter seed (see `epoch_seed`), for shards
+  /// with filters
+  fn filter_epoch(&self) -> Option<u64> {
+    None
+  }
+
+  /// Checks that `q` has one entry per row of the DB matrix. All respond
+  /// methods reject other queries with an error, so servers only need
+  /// this to reject untrusted queries before e.g. queueing them.
+  fn check_query(&self, q: &Query) -> ResultBoxedError<()> {
+    if q.as_slice().len() != self.height() {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "query_len: {}, db height: {}",
+        q.as_slice().len(),
+        self.height()
+      ))));
+    }
+    Ok(())
+  }
+
+  /// Produces a serialized `AggregatedResponse` to the queries of many
+  /// clients that a gateway collected into an `AggregatedQuery` (see
+  /// `proxy`), answering them as a single batch
+  fn respond_many(&self, batch: &AggregatedQuery) -> ResultBoxedError<Vec<u8>> {
+    let responses = self.respond_batch(&batch.queries)?;
+    Ok(bincode::serialize(&AggregatedResponse { responses })?)
+  }
+
+  /// Produces a serialized `ResponseEnvelope` to a `QueryEnvelope`,
+  /// carrying over its id
+  fn respond_envelope(
+    &self,
+    envelope: &QueryEnvelope,
+  ) -> ResultBoxedError<Vec<u8>> {
+    let response = bincode::deserialize(&self.respond(&envelope.query)?)?;
+    Ok(bincode::serialize(&ResponseEnvelope {
+      id: envelope.id,
+      response,
+    })?)
+  }
 }
 
-impl QueryParams<IndexDatabase, EmptyAuxParams> {
-  /// Generates `QueryParams` for a `Database` that is not KV
-  fn new(cp: &CommonParams, params: &IndexParams) -> ResultBoxedError<Self> {
-    let s = random_ternary_vector(params.get_dim());
-    Ok(Self {
-      lhs: cp.mult_left(&s)?,
-      rhs: params.mult_right(&s)?,
-      elem_size: params.get_elem_size(),
-      plaintext_bits: params.get_plaintext_bits(),
-      db: Default::default(),
-      extra_params: None,
-      used: false,
-    })
+impl PirShard for Shard {
+  fn respond(&self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    Shard::respond(self, q)
   }
 
-  /// Prepares a new client query based on an input row_inde that is a digit
-  pub fn generate_query(
-    &mut self,
-    row_index: usize,
-  ) -> ResultBoxedError<Query> {
-    if self.used {
-      return Err(Box::new(ErrorQueryParamsReused {}));
-    }
-    self.used = true;
-    let query_indicator = get_rounding_factor(self.plaintext_bits);
-    let mut lhs = Vec::new();
-    lhs.clone_from(&self.lhs.clone());
-    let (result, check) = lhs[row_index].overflowing_add(query_indicator);
-    if !check {
-      lhs[row_index] = result;
-    } else {
-      return Err(Box::new(ErrorOverflownAdd {}));
-    }
-    Ok(Query(lhs))
+  fn respond_par(&self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    Shard::respond_par(self, q)
   }
 
-  /// Parses the output as a row of u32 values
-  pub fn parse_resp_as_row(&self, resp: &Response) -> Vec<u32> {
-    // get parameters for rounding
-    let rounding_factor = get_rounding_factor(self.plaintext_bits);
-    let rounding_floor = get_rounding_floor(self.plaintext_bits);
-    let plaintext_size = get_plaintext_size(self.plaintext_bits);
+  fn respond_batch(&self, queries: &[Query]) -> ResultBoxedError<Vec<Vec<u8>>> {
+    Shard::respond_batch(self, queries)
+  }
 
-    // perform division and rounding
-    (0..IndexDatabase::get_row_width(self.elem_size, self.plaintext_bits))
-      .map(|i| {
-        let left = resp.0[i];
-        let right = self.rhs[i];
-        let unscaled_res = left.wrapping_sub(right);
-        let scaled_res = unscaled_res / rounding_factor;
-        let scaled_rem = unscaled_res % rounding_factor;
-        let mut rounded_res = scaled_res;
-        if scaled_rem > rounding_floor {
-          rounded_res += 1;
-        }
-        rounded_res % plaintext_size
-      })
-      .collect()
+  fn write_to_file(
+    &self,
+    db_path: &str,
+    params_path: &str,
+  ) -> ResultBoxedError<()> {
+    Shard::write_to_file(self, db_path, params_path)
   }
 
-  /// Parses the output as bytes
-  pub fn parse_resp_as_bytes(&self, resp: &Response) -> Vec<u8> {
-    let row = self.parse_resp_as_row(resp);
-    bytes_from_u32_slice(&row, self.plaintext_bits, self.elem_size)
+  fn params_digest(&self) -> ResultBoxedError<[u8; 32]> {
+    Ok(Sha256::digest(bincode::serialize(&self.base_params)?).into())
   }
 
-  /// Parses the output as a base64-encoded string
-  pub fn parse_resp_as_base64(&self, resp: &Response) -> String {
-    let row = self.parse_resp_as_row(resp);
-    base64_from_u32_slice(&row, self.plaintext_bits, self.elem_size)
+  fn params_json(&self) -> ResultBoxedError<Vec<u8>> {
+    Ok(serde_json::to_vec(&self.base_params)?)
+  }
+
+  fn height(&self) -> usize {
+    self.db.get_matrix_height()
+  }
+
+  fn width(&self) -> usize {
+    self.db.get_row_width_self()
+  }
+
+  fn plaintext_bits(&self) -> usize {
+    self.base_params.get_plaintext_bits()
+  }
+
+  fn modulus(&self) -> Modulus {
+    self.base_params.get_modulus()
+  }
+
+  fn prefault(&self) {
+    Shard::prefault(self)
+  }
+
+  fn set_params_expires_at(&mut self, expires_at: Option<u64>) {
+    Shard::set_params_expires_at(self, expires_at)
   }
 }
-impl QueryParams<KVDatabase, FilterParams> {
-  /// Generates `QueryParams` for a `Database` that is KV
-  fn new(cp: &CommonParams, params: &KVParams) -> ResultBoxedError<Self> {
-    let s = random_ternary_vector(params.get_dim());
-    Ok(Self {
-      lhs: cp.mult_left(&s)?,
-      rhs: params.mult_right(&s)?,
-      elem_size: params.get_elem_size(),
-      plaintext_bits: params.get_plaintext_bits(),
-      db: Default::default(),
-      extra_params: Some(params.get_filter_params()),
-      used: false,
-    })
+
+impl PirShard for KVShard {
+  fn respond(&self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    KVShard::respond(self, q)
   }
 
-  /// Prepares a new client query based on an input row_index that is a key
-  pub fn generate_query(&mut self, key: &[u64; 4]) -> ResultBoxedError<Query> {
-    if self.used {
-      return Err(Box::new(ErrorQueryParamsReused {}));
-    }
-    self.used = true;
-    let query_indicator = get_rounding_factor(self.plaintext_bits);
-    let mut lhs = Vec::new();
-    lhs.clone_from(&self.lhs.clone());
-    if self.extra_params.is_none() {
-      return Err("No filter parameters set for KV QueryParams".into());
-    }
-    let indices = self.extra_params.as_ref().unwrap().get_hash_evals(key);
-    for row_index in indices {
-      lhs[row_index] = lhs[row_index].wrapping_add(query_indicator);
-    }
-    Ok(Query(lhs))
+  fn respond_par(&self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    KVShard::respond_par(self, q)
   }
 
-  /// Parses the output as a row of u32 values
-  pub fn parse_resp_as_row(
+  fn respond_batch(&self, queries: &[Query]) -> ResultBoxedError<Vec<Vec<u8>>> {
+    KVShard::respond_batch(self, queries)
+  }
+
+  fn write_to_file(
     &self,
-    resp: &Response,
-    key: &[u64; 4],
-  ) -> ResultBoxedError<Vec<u32>> {
-    // get parameters for rounding
-    let rounding_factor = get_rounding_factor(self.plaintext_bits);
-    let rounding_floor = get_rounding_floor(self.plaintext_bits);
-    let plaintext_size = get_plaintext_size(self.plaintext_bits);
+    db_path: &str,
+    params_path: &str,
+  ) -> ResultBoxedError<()> {
+    KVShard::write_to_file(self, db_path, params_path)
+  }
 
-    // check FilterParams exst
-    if self.extra_params.is_none() {
-      return Err("No filter parameters set for KV QueryParams".into());
-    }
-    let fp = self.extra_params.as_ref().unwrap();
+  fn params_digest(&self) -> ResultBoxedError<[u8; 32]> {
+    Ok(Sha256::digest(bincode::serialize(&self.base_params)?).into())
+  }
 
-    // perform division and rounding
-    Ok(
-      (0..KVDatabase::get_row_width(self.elem_size, self.plaintext_bits))
-        .map(|i| {
-          let left = resp.0[i];
-          let right = self.rhs[i];
-          let unscaled_res = left.wrapping_sub(right);
-          let scaled_res = unscaled_res / rounding_factor;
-          let scaled_rem = unscaled_res % rounding_factor;
-          let mut rounded_res = scaled_res;
-          if scaled_rem > rounding_floor {
-            rounded_res += 1;
-          }
-          let masked = rounded_res;
-          let unmasked = fp.unmask_value(masked, key, i as u64);
-          unmasked % plaintext_size
-        })
-        .collect(),
-    )
+  fn params_json(&self) -> ResultBoxedError<Vec<u8>> {
+    Ok(serde_json::to_vec(&self.base_params)?)
   }
 
-  /// Parses the output as bytes
-  pub fn parse_resp_as_bytes(
-    &self,
-    resp: &Response,
-    key: &[u64; 4],
-  ) -> ResultBoxedError<Vec<u8>> {
-    let row = self.parse_resp_as_row(resp, key)?;
-    Ok(bytes_from_u32_slice(
-      &row,
-      self.plaintext_bits,
-      self.elem_size,
-    ))
+  fn height(&self) -> usize {
+    self.db.get_matrix_height()
   }
 
-  /// Parses the output as a base64-encoded string
-  pub fn parse_resp_as_base64(
-    &self,
-    resp: &Response,
-    key: &[u64; 4],
-  ) -> ResultBoxedError<String> {
-    let row = self.parse_resp_as_row(resp, key)?;
-    Ok(base64_from_u32_slice(
-      &row,
-      self.plaintext_bits,
-      self.elem_size,
-    ))
+  fn width(&self) -> usize {
+    self.db.get_row_width_self()
+  }
+
+  fn plaintext_bits(&self) -> usize {
+    self.base_params.get_plaintext_bits()
+  }
+
+  fn modulus(&self) -> Modulus {
+    self.base_params.get_modulus()
+  }
+
+  fn prefault(&self) {
+    KVShard::prefault(self)
+  }
+
+  fn set_params_expires_at(&mut self, expires_at: Option<u64>) {
+    KVShard::set_params_expires_at(self, expires_at)
+  }
+
+  fn filter_epoch(&self) -> Option<u64> {
+    Some(self.db.get_filter_params().epoch)
   }
 }
 
-/// Returns `QueryParams` for an Index-based DB (`IndexDatabase`)
-pub fn generate_index_query_params(
-  cp: &CommonParams,
-  params: &IndexParams,
-) -> ResultBoxedError<QueryParams<IndexDatabase, EmptyAuxParams>> {
-  QueryParams::<IndexDatabase, EmptyAuxParams>::new(cp, params)
+/// An `AnyShard` holds either a `Shard` or a `KVShard`, for collections
+/// of shards of both types
+#[derive(Clone, Debug)]
+pub enum AnyShard {
+  Index(Shard),
+  KV(KVShard),
 }
+impl AnyShard {
+  /// Returns the `Shard`, if this is an index shard
+  pub fn as_index(&self) -> Option<&Shard> {
+    match self {
+      Self::Index(shard) => Some(shard),
+      Self::KV(_) => None,
+    }
+  }
+
+  /// Returns the `KVShard`, if this is a KV shard
+  pub fn as_kv(&self) -> Option<&KVShard> {
+    match self {
+      Self::Index(_) => None,
+      Self::KV(shard) => Some(shard),
+    }
+  }
+
+  fn as_pir_shard(&self) -> &dyn PirShard {
+    match self {
+      Self::Index(shard) => shard,
+      Self::KV(shard) => shard,
+    }
+  }
+}
+impl From<Shard> for AnyShard {
+  fn from(shard: Shard) -> Self {
+    Self::Index(shard)
+  }
+}
+impl From<KVShard> for AnyShard {
+  fn from(shard: KVShard) -> Self {
+    Self::KV(shard)
+  }
+}
+impl PirShard for AnyShard {
+  fn respond(&self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    self.as_pir_shard().respond(q)
+  }
+
+  fn respond_par(&self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    self.as_pir_shard().respond_par(q)
+  }
+
+  fn respond_batch(&self, queries: &[Query]) -> ResultBoxedError<Vec<Vec<u8>>> {
+    self.as_pir_shard().respond_batch(queries)
+  }
+
+  fn write_to_file(
+    &self,
+    db_path: &str,
+    params_path: &str,
+  ) -> ResultBoxedError<()> {
+    self.as_pir_shard().write_to_file(db_path, params_path)
+  }
+
+  fn params_digest(&self) -> ResultBoxedError<[u8; 32]> {
+    self.as_pir_shard().params_digest()
+  }
+
+  fn params_json(&self) -> ResultBoxedError<Vec<u8>> {
+    self.as_pir_shard().params_json()
+  }
+
+  fn height(&self) -> usize {
+    self.as_pir_shard().height()
+  }
+
+  fn width(&self) -> usize {
+    self.as_pir_shard().width()
+  }
+
+  fn plaintext_bits(&self) -> usize {
+    self.as_pir_shard().plaintext_bits()
+  }
+
+  fn modulus(&self) -> Modulus {
+    self.as_pir_shard().modulus()
+  }
+
+  fn prefault(&self) {
+    self.as_pir_shard().prefault()
+  }
+
+  fn set_params_expires_at(&mut self, expires_at: Option<u64>) {
+    match self {
+      Self::Index(shard) => shard.set_params_expires_at(expires_at),
+      Self::KV(shard) => shard.set_params_expires_at(expires_at),
+    }
+  }
+
+  fn filter_epoch(&self) -> Option<u64> {
+    self.as_pir_shard().filter_epoch()
+  }
+}
+
+// Shards, params and `CommonParams` hold no interior mutability, so that
+// servers can share them across threads by reference
+assert_impl_all!(Shard: Send, Sync);
+assert_impl_all!(KVShard: Send, Sync);
+assert_impl_all!(AnyShard: Send, Sync);
+assert_impl_all!(IndexParams: Send, Sync);
+assert_impl_all!(KVParams: Send, Sync);
+assert_impl_all!(CommonParams: Send, Sync);
+
+/// The `QueryParams` struct is initialized to be used for a client
+/// query. Deserialized `QueryParams` (e.g. of a persisted
+/// `ClientSession`) are checked like the params they were built from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(remote = "Self")]
+pub struct QueryParams<DB, EP> {
+  lhs: Vec<u32>,
+  rhs: Vec<u32>,
+  elem_size: usize,
+  plaintext_bits: usize,
+  db: PhantomData<DB>,
+  extra_params: Option<EP>,
+  #[serde(default)]
+  query_seed: Option<[u8; 32]>,
+  #[serde(default)]
+  rounding: RoundingMode,
+  #[serde(default)]
+  compression: Compression,
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  record_expiry: bool,
+  #[serde(default)]
+  modulus: Modulus,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  column_bits: Option<Vec<usize>>,
+  /// The matrix row holding each record, if the DB is deduplicated (see
+  /// `IndexParams::get_record_row`)
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  record_rows: Option<Vec<usize>>,
+  used: bool,
+}
+
+impl<DB: Serialize, EP: Serialize> Serialize for QueryParams<DB, EP> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    Self::serialize(self, serializer)
+  }
+}
+
+impl<'de, DB, EP> Deserialize<'de> for QueryParams<DB, EP>
+where
+  DB: Deserialize<'de>,
+  EP: Deserialize<'de>,
+{
+  fn deserialize<D: Deserializer<'de>>(
+    deserializer: D,
+  ) -> Result<Self, D::Error> {
+    let qp = Self::deserialize(deserializer)?;
+    check_decoding(
+      qp.modulus,
+      qp.plaintext_bits,
+      qp.column_bits.as_deref(),
+      qp.elem_size,
+      qp.rhs.len(),
+    )
+    .map_err(serde::de::Error::custom)?;
+    Ok(qp)
+  }
+}
+
+/// Checks that `modulus` leaves room for the plaintext bits, and that the
+/// column bits (if any) fit elements of `elem_size` bits in a row of
+/// `width` entries, before responses are decoded with them
+fn check_decoding(
+  modulus: Modulus,
+  plaintext_bits: usize,
+  column_bits: Option<&[usize]>,
+  elem_size: usize,
+  width: usize,
+) -> ResultBoxedError<()> {
+  modulus.check_plaintext_bits(plaintext_bits)?;
+  if let Some(column_bits) = column_bits {
+    check_column_bits(column_bits, elem_size, width)?;
+  }
+  Ok(())
+}
+
+impl<DB, EP> QueryParams<DB, EP> {
+  /// Builds `QueryParams` from a secret `s` and its corresponding
+  /// `s*A + e`, computing `s*(A*DB)` from the RHS of the public
+  /// parameters
+  fn from_secret<T: BaseParams>(
+    lhs: Vec<u32>,
+    s: &[u32],
+    params: &T,
+    extra_params: Option<EP>,
+  ) -> ResultBoxedError<Self> {
+    if params.is_expired_at(SystemTime::now()) {
+      return Err(Box::new(ErrorParamsExpired {}));
+    }
+    let modulus = params.get_modulus();
+    check_decoding(
+      modulus,
+      params.get_plaintext_bits(),
+      params.get_column_bits(),
+      params.get_elem_size(),
+      params.get_rhs().len(),
+    )?;
+    Ok(Self {
+      lhs: lhs.into_iter().map(|x| modulus.reduce(x)).collect(),
+      rhs: params.mult_right(s)?,
+      elem_size: params.get_elem_size(),
+      plaintext_bits: params.get_plaintext_bits(),
+      db: Default::default(),
+      extra_params,
+      query_seed: None,
+      rounding: RoundingMode::default(),
+      compression: params.get_compression(),
+      record_expiry: params.get_record_expiry(),
+      modulus,
+      column_bits: params.get_column_bits().map(<[usize]>::to_vec),
+      record_rows: params.get_record_rows().map(<[usize]>::to_vec),
+      used: false,
+    })
+  }
+
+  /// Returns whether a query was already generated from the params.
+  /// Used params refuse to generate further queries, since queries under
+  /// the same secret would reveal the relation between the queried rows.
+  pub fn is_used(&self) -> bool {
+    self.used
+  }
+
+  /// Marks the params as unused, so that they can generate another query
+  /// under the same secret. This is insecure, and only exists so that
+  /// benches can reuse params (see the `bench-internals` feature).
+  #[cfg(feature = "bench-internals")]
+  pub fn unsafe_reset_for_benchmarks(&mut self) {
+    self.used = false;
+  }
+
+  /// Sets the `RoundingMode` used when parsing responses
+  pub fn set_rounding_mode(&mut self, mode: RoundingMode) {
+    self.rounding = mode;
+  }
+
+  /// Returns the `RoundingMode` used when parsing responses
+  pub fn get_rounding_mode(&self) -> RoundingMode {
+    self.rounding
+  }
+
+  /// Returns the ciphertext modulus of the params the `QueryParams`
+  /// were generated for
+  pub fn get_modulus(&self) -> Modulus {
+    self.modulus
+  }
+
+  /// Returns the unscaled residues of the response (i.e. the response
+  /// minus `s*(A*DB)`, reduced by the modulus) before any rounding is
+  /// applied. For KV databases the residues are still masked by the key
+  /// fingerprints.
+  pub fn parse_resp_raw(&self, resp: &Response) -> Vec<u32> {
+    check_response_width(resp.0.len(), self.rhs.len());
+    resp
+      .0
+      .iter()
+      .zip(self.rhs.iter())
+      .map(|(left, right)| self.modulus.reduce(left.wrapping_sub(*right)))
+      .collect()
+  }
+
+  /// Rounds the unscaled residues of a response to plaintext values
+  /// (see `Modulus::round_residues`)
+  fn round_row(&self, unscaled: &[u32]) -> Vec<u32> {
+    self
+      .modulus
+      .round_residues(unscaled, self.plaintext_bits, self.rounding)
+  }
+
+  /// Recovers the original record from its decoded bytes (see
+  /// `unframe_record`)
+  fn unframe_record(&self, decoded: &[u8]) -> ResultBoxedError<Vec<u8>> {
+    unframe_record(decoded, self.compression, self.record_expiry)
+  }
+
+  /// Returns the `DecodeKey` for parsing responses to the query
+  fn decode_key_with(&self, filter_params: Option<FilterParams>) -> DecodeKey {
+    DecodeKey {
+      rhs: self.rhs.clone(),
+      elem_size: self.elem_size,
+      plaintext_bits: self.plaintext_bits,
+      filter_params,
+      rounding: self.rounding,
+      compression: self.compression,
+      record_expiry: self.record_expiry,
+      modulus: self.modulus,
+      column_bits: self.column_bits.clone(),
+    }
+  }
+
+  /// Copies the LHS of the query into `buf`, which must be as long
+  fn copy_lhs_into(&self, buf: &mut [u32]) -> ResultBoxedError<()> {
+    if buf.len() != self.lhs.len() {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "buffer length: {}, query length: {}",
+        buf.len(),
+        self.lhs.len()
+      ))));
+    }
+    buf.copy_from_slice(&self.lhs);
+    Ok(())
+  }
+
+  /// Same as `parse_resp_raw`, for the columns held by `frame`
+  pub fn parse_frame_raw(
+    &self,
+    frame: &ResponseFrame,
+  ) -> ResultBoxedError<Vec<u32>> {
+    let columns = frame.columns()?;
+    if columns.end > self.rhs.len() {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "frame columns: {:?}, response width: {}",
+        columns,
+        self.rhs.len()
+      ))));
+    }
+    Ok(
+      frame
+        .values
+        .iter()
+        .zip(&self.rhs[frame.start..])
+        .map(|(left, right)| self.modulus.reduce(left.wrapping_sub(*right)))
+        .collect(),
+    )
+  }
+
+  /// Prepares a dummy query that does not target any row, for use as
+  /// cover traffic. To the server it is indistinguishable from a real
+  /// query. This uses up the `QueryParams`, since sending a dummy and a
+  /// real query under the same secret would reveal the queried row.
+  pub fn generate_dummy_query(&mut self) -> ResultBoxedError<Query> {
+    if self.used {
+      return Err(Box::new(ErrorQueryParamsReused {}));
+    }
+    self.used = true;
+    Ok(Query(self.lhs.clone()))
+  }
+
+  /// Returns the noise margins of the first `width` slots of the response
+  pub(crate) fn parse_resp_margins(
+    &self,
+    resp: &Response,
+    width: usize,
+  ) -> Vec<u32> {
+    self
+      .parse_resp_raw(resp)
+      .into_iter()
+      .take(width)
+      .map(|unscaled_res| {
+        self
+          .modulus
+          .rounding_margin(unscaled_res, self.plaintext_bits)
+      })
+      .collect()
+  }
+}
+
+/// A `QueryTarget` identifies what a query asked for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QueryTarget {
+  /// The row of an index DB
+  Row(usize),
+  /// The hashed key of a KV DB
+  Key(HashedKey),
+}
+
+/// A `QueryReceipt` records what a query asked for, of which params
+/// (identified as by `PirShard::params_digest`), and when it was
+/// generated, so that applications can audit their queries and match
+/// responses to them (e.g. after a crash) without the `QueryParams`.
+///
+/// Like the `QueryParams`, receipts reveal the queried target, and must be
+/// stored with the same care.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryReceipt {
+  pub target: QueryTarget,
+  pub params_digest: [u8; 32],
+  /// The Unix timestamp (in seconds) at which the query was generated
+  pub timestamp: u64,
+}
+impl QueryReceipt {
+  fn new<T: Serialize>(
+    target: QueryTarget,
+    params: &T,
+  ) -> ResultBoxedError<Self> {
+    Ok(Self {
+      target,
+      params_digest: Sha256::digest(bincode::serialize(params)?).into(),
+      timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    })
+  }
+}
+
+/// A `DecodedRow` holds a decoded response, along with the noise margin of
+/// each slot: the distance of the unscaled residue to the closest boundary
+/// at which rounding would produce a different value. Small margins
+/// indicate a near-failure of decoding, in which case the query should be
+/// retried with fresh `QueryParams`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodedRow {
+  pub row: Vec<u32>,
+  pub margins: Vec<u32>,
+}
+impl DecodedRow {
+  /// Returns the smallest margin over all slots
+  pub fn min_margin(&self) -> u32 {
+    self.margins.iter().copied().min().unwrap_or(u32::MAX)
+  }
+}
+
+impl QueryParams<IndexDatabase, EmptyAuxParams> {
+  /// Generates `QueryParams` for a `Database` that is not KV
+  fn new(cp: &CommonParams, params: &IndexParams) -> ResultBoxedError<Self> {
+    Self::new_with_entropy(cp, params, &mut OsRng)
+  }
+
+  /// Generates `QueryParams` for a `Database` that is not KV, sampling
+  /// the secret and error from `entropy`
+  fn new_with_entropy<E: Entropy>(
+    cp: &CommonParams,
+    params: &IndexParams,
+    entropy: &mut E,
+  ) -> ResultBoxedError<Self> {
+    let s = random_ternary_vector_from(entropy, params.get_dim());
+    Self::from_secret(cp.mult_left_from_rng(&s, entropy)?, &s, params, None)
+  }
+
+  /// Generates `QueryParams` for a `Database` that is not KV from an
+  /// externally supplied secret `s` (e.g. one held in an HSM, or derived
+  /// deterministically), instead of sampling it. The secret must be
+  /// ternary and of the LWE dimension (see `check_ternary_secret`), and
+  /// the error is still sampled from `OsRng`.
+  pub fn new_with_secret(
+    cp: &CommonParams,
+    params: &IndexParams,
+    s: &[u32],
+  ) -> ResultBoxedError<Self> {
+    check_ternary_secret(s, params.get_dim())?;
+    Self::from_secret(cp.mult_left_from_rng(s, &mut OsRng)?, s, params, None)
+  }
+
+  /// Generates `QueryParams` for a `Database` that is not KV without a
+  /// `CommonParams`, expanding the LHS matrix from the public seed of
+  /// `params` column by column (see `BaseParams::mult_left_streamed`).
+  /// The secret and error are sampled from `entropy`. This trades the
+  /// memory of the full matrix for expanding it on every call.
+  pub fn from_seed<E: Entropy>(
+    params: &IndexParams,
+    entropy: &mut E,
+  ) -> ResultBoxedError<Self> {
+    let s = random_ternary_vector_from(entropy, params.get_dim());
+    Self::from_secret(params.mult_left_streamed(&s, entropy)?, &s, params, None)
+  }
+
+  /// Generates `QueryParams` for a `Database` that is not KV, where the
+  /// secret and error are derived from `query_seed`, allowing queries to
+  /// be sent as a `SparseQuery`
+  fn from_query_seed(
+    cp: &CommonParams,
+    params: &IndexParams,
+    query_seed: [u8; 32],
+  ) -> ResultBoxedError<Self> {
+    let (s, lhs) = SparseQuery::derive_lhs(cp, query_seed)?;
+    let mut qp = Self::from_secret(lhs, &s, params, None)?;
+    qp.query_seed = Some(query_seed);
+    Ok(qp)
+  }
+
+  /// Generates `QueryParams` for a `Database` that is not KV, using the
+  /// next precomputed entry of a `ClientHint`
+  fn from_hint(
+    hint: &mut ClientHint,
+    params: &IndexParams,
+  ) -> ResultBoxedError<Self> {
+    let entry = hint.next_entry(params)?;
+    Self::from_secret(entry.lhs, &entry.s, params, None)
+  }
+
+  /// Returns the matrix row holding the record at `row_index`, which is
+  /// `row_index` itself unless the DB is deduplicated
+  fn matrix_row(&self, row_index: usize) -> ResultBoxedError<usize> {
+    let row = match &self.record_rows {
+      Some(rows) => rows.get(row_index).copied(),
+      None => (row_index < self.lhs.len()).then_some(row_index),
+    };
+    row.ok_or_else(|| {
+      ErrorUnexpectedInputSize::new(format!(
+        "row_index: {}, m: {}",
+        row_index,
+        self.record_rows.as_ref().map_or(self.lhs.len(), Vec::len)
+      ))
+      .into()
+    })
+  }
+
+  /// Prepares a new client query based on an input row_inde that is a digit.
+  /// For a deduplicated DB, the matrix row holding the record is queried.
+  pub fn generate_query(
+    &mut self,
+    row_index: usize,
+  ) -> ResultBoxedError<Query> {
+    let mut lhs = vec![0u32; self.lhs.len()];
+    self.generate_query_into(row_index, &mut lhs)?;
+    Ok(Query(lhs))
+  }
+
+  /// Consumes the params to prepare a single query for `row_index`,
+  /// returning the `DecodeKey` for parsing the response. Unlike
+  /// `generate_query`, reuse is ruled out at compile time.
+  pub fn into_query(
+    mut self,
+    row_index: usize,
+  ) -> ResultBoxedError<(Query, DecodeKey)> {
+    self.generate_detached_query(row_index)
+  }
+
+  /// Same as `generate_query`, additionally returning the `DecodeKey` for
+  /// parsing the response, so that the `QueryParams` can be dropped
+  pub fn generate_detached_query(
+    &mut self,
+    row_index: usize,
+  ) -> ResultBoxedError<(Query, DecodeKey)> {
+    Ok((self.generate_query(row_index)?, self.decode_key()))
+  }
+
+  /// Returns the `DecodeKey` for parsing responses to the query
+  pub fn decode_key(&self) -> DecodeKey {
+    self.decode_key_with(None)
+  }
+
+  /// Same as `generate_query`, additionally returning a `QueryReceipt`
+  /// for the query. The digest of `params` (the params that the
+  /// `QueryParams` were generated for) is recorded in the receipt, which
+  /// hashes the whole params, so callers that generate many queries should
+  /// keep the digest of their params and use `generate_query` instead.
+  pub fn generate_query_with_receipt(
+    &mut self,
+    row_index: usize,
+    params: &IndexParams,
+  ) -> ResultBoxedError<(Query, QueryReceipt)> {
+    let receipt = QueryReceipt::new(QueryTarget::Row(row_index), params)?;
+    Ok((self.generate_query(row_index)?, receipt))
+  }
+
+  /// Same as `generate_query`, writing the query into `buf` instead of
+  /// allocating it, for devices with little memory. `buf` must hold one
+  /// entry per DB row, and can be reused across queries. Its bincode
+  /// serialization is that of the corresponding `Query`.
+  pub fn generate_query_into(
+    &mut self,
+    row_index: usize,
+    buf: &mut [u32],
+  ) -> ResultBoxedError<()> {
+    if self.used {
+      return Err(Box::new(ErrorQueryParamsReused {}));
+    }
+    let row_index = self.matrix_row(row_index)?;
+    self.copy_lhs_into(buf)?;
+    self.used = true;
+    let query_indicator = self.modulus.rounding_factor(self.plaintext_bits);
+    let (result, check) = self
+      .modulus
+      .overflowing_add(buf[row_index], query_indicator);
+    if check {
+      return Err(Box::new(ErrorOverflownAdd {}));
+    }
+    buf[row_index] = result;
+    Ok(())
+  }
+
+  /// Prepares a new client query for `row_index` in its sparse form. This
+  /// is only possible for `QueryParams` generated with
+  /// `generate_sparse_index_query_params`.
+  pub fn generate_sparse_query(
+    &mut self,
+    row_index: usize,
+  ) -> ResultBoxedError<SparseQuery> {
+    if self.used {
+      return Err(Box::new(ErrorQueryParamsReused {}));
+    }
+    let seed = match self.query_seed {
+      Some(seed) => seed,
+      None => return Err("No query seed set for sparse QueryParams".into()),
+    };
+    let row_index = self.matrix_row(row_index)?;
+    self.used = true;
+    Ok(SparseQuery {
+      seed,
+      deltas: vec![(
+        row_index,
+        self.modulus.rounding_factor(self.plaintext_bits),
+      )],
+    })
+  }
+
+  /// Parses the output as a row of u32 values
+  pub fn parse_resp_as_row(&self, resp: &Response) -> Vec<u32> {
+    let mut unscaled = self.parse_resp_raw(resp);
+    unscaled.truncate(self.get_column_bits().len());
+    self.round_row(&unscaled)
+  }
+
+  /// Parses a single frame of a response (see `Shard::respond_frames`)
+  /// as the u32 values of its columns
+  pub fn parse_frame_as_row(
+    &self,
+    frame: &ResponseFrame,
+  ) -> ResultBoxedError<Vec<u32>> {
+    Ok(self.round_row(&self.parse_frame_raw(frame)?))
+  }
+
+  /// Parses the output as a row of u32 values, additionally reporting the
+  /// noise margin of each decoded slot
+  pub fn decode_with_margin(&self, resp: &Response) -> DecodedRow {
+    let row = self.parse_resp_as_row(resp);
+    let margins = self.parse_resp_margins(resp, row.len());
+    DecodedRow { row, margins }
+  }
+
+  /// Parses the output as bytes
+  pub fn parse_resp_as_bytes(&self, resp: &Response) -> Vec<u8> {
+    let row = self.parse_resp_as_row(resp);
+    bytes_from_u32_slice_with_column_bits(
+      &row,
+      &self.get_column_bits(),
+      self.elem_size,
+    )
+  }
+
+  /// Parses the output as the original record, removing the framing and
+  /// decompressing it if the DB records were compressed (see
+  /// `compression`). If the DB records carry expiry timestamps (see
+  /// `expiry`), the timestamp is removed, and records that have expired
+  /// are reported as an `ErrorRecordExpired`. For uncompressed DBs
+  /// without expiry this is equivalent to `parse_resp_as_bytes`.
+  pub fn parse_resp_as_record(
+    &self,
+    resp: &Response,
+  ) -> ResultBoxedError<Vec<u8>> {
+    self.unframe_record(&self.parse_resp_as_bytes(resp))
+  }
+
+  /// Parses the output as a base64-encoded string
+  pub fn parse_resp_as_base64(&self, resp: &Response) -> String {
+    self.parse_resp_as_encoded(resp, EncodingConfig::Standard)
+  }
+
+  /// Parses the output as a string serialized using `encoding`
+  pub fn parse_resp_as_encoded(
+    &self,
+    resp: &Response,
+    encoding: EncodingConfig,
+  ) -> String {
+    encoding.encode(&self.parse_resp_as_bytes(resp))
+  }
+
+  /// Returns the number of plaintext bits held by each column of the DB
+  fn get_column_bits(&self) -> Vec<usize> {
+    column_bits_or_uniform(
+      self.column_bits.as_deref(),
+      self.elem_size,
+      self.plaintext_bits,
+    )
+  }
+}
+impl QueryParams<KVDatabase, FilterParams> {
+  /// Generates `QueryParams` for a `Database` that is KV
+  fn new(cp: &CommonParams, params: &KVParams) -> ResultBoxedError<Self> {
+    Self::new_with_entropy(cp, params, &mut OsRng)
+  }
+
+  /// Generates `QueryParams` for a `Database` that is KV, sampling the
+  /// secret and error from `entropy`
+  fn new_with_entropy<E: Entropy>(
+    cp: &CommonParams,
+    params: &KVParams,
+    entropy: &mut E,
+  ) -> ResultBoxedError<Self> {
+    let s = random_ternary_vector_from(entropy, params.get_dim());
+    Self::from_secret(
+      cp.mult_left_from_rng(&s, entropy)?,
+      &s,
+      params,
+      Some(kv_filter_params(params)?),
+    )
+  }
+
+  /// Generates `QueryParams` for a `Database` that is KV from an
+  /// externally supplied secret `s` (see the index variant of
+  /// `new_with_secret`)
+  pub fn new_with_secret(
+    cp: &CommonParams,
+    params: &KVParams,
+    s: &[u32],
+  ) -> ResultBoxedError<Self> {
+    check_ternary_secret(s, params.get_dim())?;
+    Self::from_secret(
+      cp.mult_left_from_rng(s, &mut OsRng)?,
+      s,
+      params,
+      Some(kv_filter_params(params)?),
+    )
+  }
+
+  /// Generates `QueryParams` for a `Database` that is KV without a
+  /// `CommonParams` (see the index variant of `from_seed`)
+  pub fn from_seed<E: Entropy>(
+    params: &KVParams,
+    entropy: &mut E,
+  ) -> ResultBoxedError<Self> {
+    let s = random_ternary_vector_from(entropy, params.get_dim());
+    Self::from_secret(
+      params.mult_left_streamed(&s, entropy)?,
+      &s,
+      params,
+      Some(kv_filter_params(params)?),
+    )
+  }
+
+  /// Generates `QueryParams` for a `Database` that is KV, deriving the
+  /// secret and error from `query_seed`
+  fn from_query_seed(
+    cp: &CommonParams,
+    params: &KVParams,
+    query_seed: [u8; 32],
+  ) -> ResultBoxedError<Self> {
+    let (s, lhs) = SparseQuery::derive_lhs(cp, query_seed)?;
+    Self::from_secret(lhs, &s, params, Some(kv_filter_params(params)?))
+  }
+
+  /// Generates `QueryParams` for a `Database` that is KV, using the next
+  /// precomputed entry of a `ClientHint`
+  fn from_hint(
+    hint: &mut ClientHint,
+    params: &KVParams,
+  ) -> ResultBoxedError<Self> {
+    let entry = hint.next_entry(params)?;
+    Self::from_secret(
+      entry.lhs,
+      &entry.s,
+      params,
+      Some(kv_filter_params(params)?),
+    )
+  }
+
+  /// Prepares a new client query based on an input row_index that is a key
+  pub fn generate_query(&mut self, key: &HashedKey) -> ResultBoxedError<Query> {
+    let mut lhs = vec![0u32; self.lhs.len()];
+    self.generate_query_into(key, &mut lhs)?;
+    Ok(Query(lhs))
+  }
+
+  /// Consumes the params to prepare a single query for `key`, returning
+  /// the `DecodeKey` for parsing the response (as for index DBs)
+  pub fn into_query(
+    mut self,
+    key: &HashedKey,
+  ) -> ResultBoxedError<(Query, DecodeKey)> {
+    self.generate_detached_query(key)
+  }
+
+  /// Same as `generate_query`, additionally returning the `DecodeKey` for
+  /// parsing the response (as for index DBs)
+  pub fn generate_detached_query(
+    &mut self,
+    key: &HashedKey,
+  ) -> ResultBoxedError<(Query, DecodeKey)> {
+    Ok((self.generate_query(key)?, self.decode_key()))
+  }
+
+  /// Returns the `DecodeKey` for parsing responses to the query
+  pub fn decode_key(&self) -> DecodeKey {
+    self.decode_key_with(self.extra_params.clone())
+  }
+
+  /// Same as `generate_query`, additionally returning a `QueryReceipt`
+  /// for the query (as for index DBs)
+  pub fn generate_query_with_receipt(
+    &mut self,
+    key: &HashedKey,
+    params: &KVParams,
+  ) -> ResultBoxedError<(Query, QueryReceipt)> {
+    let receipt = QueryReceipt::new(QueryTarget::Key(*key), params)?;
+    Ok((self.generate_query(key)?, receipt))
+  }
+
+  /// Same as `generate_query`, writing the query into `buf` instead of
+  /// allocating it (as for index DBs)
+  pub fn generate_query_into(
+    &mut self,
+    key: &HashedKey,
+    buf: &mut [u32],
+  ) -> ResultBoxedError<()> {
+    if self.extra_params.is_none() {
+      return Err("No filter parameters set for KV QueryParams".into());
+    }
+    let indices = self.extra_params.as_ref().unwrap().get_hash_evals(key);
+    self.generate_query_for_hash_evals_into(&indices, buf)
+  }
+
+  /// Prepares a new client query for a key whose hash evaluations (see
+  /// `FilterParams::get_hash_evals_batch`) were already computed
+  pub(crate) fn generate_query_for_hash_evals(
+    &mut self,
+    indices: &[usize],
+  ) -> ResultBoxedError<Query> {
+    let mut lhs = vec![0u32; self.lhs.len()];
+    self.generate_query_for_hash_evals_into(indices, &mut lhs)?;
+    Ok(Query(lhs))
+  }
+
+  fn generate_query_for_hash_evals_into(
+    &mut self,
+    indices: &[usize],
+    buf: &mut [u32],
+  ) -> ResultBoxedError<()> {
+    if self.used {
+      return Err(Box::new(ErrorQueryParamsReused {}));
+    }
+    self.copy_lhs_into(buf)?;
+    self.used = true;
+    let query_indicator = self.modulus.rounding_factor(self.plaintext_bits);
+    for &row_index in indices {
+      buf[row_index] = self
+        .modulus
+        .overflowing_add(buf[row_index], query_indicator)
+        .0;
+    }
+    Ok(())
+  }
+
+  /// Prepares a new client query for the raw row `row_index` of the DB
+  /// matrix (i.e. a single slot of the storage filters) rather than for a
+  /// key, e.g. for audits and debugging. The response is parsed using
+  /// `parse_resp_by_index`.
+  pub fn generate_query_by_index(
+    &mut self,
+    row_index: usize,
+  ) -> ResultBoxedError<Query> {
+    if row_index >= self.lhs.len() {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "row_index: {}, m: {}",
+        row_index,
+        self.lhs.len()
+      ))));
+    }
+    self.generate_query_for_hash_evals(&[row_index])
+  }
+
+  /// Parses the response to a query generated by `generate_query_by_index`
+  /// as the u32 values of the row, which are stored masked: no key is
+  /// involved, so the values are not unmasked
+  pub fn parse_resp_by_index(&self, resp: &Response) -> Vec<u32> {
+    let mut unscaled = self.parse_resp_raw(resp);
+    unscaled.truncate(KVDatabase::get_row_width(
+      self.elem_size,
+      self.plaintext_bits,
+    ));
+    self.round_row(&unscaled)
+  }
+
+  /// Prepares a new client query for an unhashed key, applying the same
+  /// SHA-256 mapping that is used by `KeyValue::from_base64_strings` (i.e.
+  /// `key` should be the bytes of the key string used to build the DB)
+  pub fn generate_query_for_key(
+    &mut self,
+    key: &KeyBytes,
+  ) -> ResultBoxedError<Query> {
+    self.generate_query(&key.hash())
+  }
+
+  /// Parses the output as a row of u32 values
+  pub fn parse_resp_as_row(
+    &self,
+    resp: &Response,
+    key: &HashedKey,
+  ) -> ResultBoxedError<Vec<u32>> {
+    let plaintext_size = get_plaintext_size(self.plaintext_bits);
+
+    // check FilterParams exst
+    if self.extra_params.is_none() {
+      return Err("No filter parameters set for KV QueryParams".into());
+    }
+    let fp = self.extra_params.as_ref().unwrap();
+
+    let mut unscaled = self.parse_resp_raw(resp);
+    unscaled.truncate(KVDatabase::get_row_width(
+      self.elem_size,
+      self.plaintext_bits,
+    ));
+    Ok(
+      self
+        .round_row(&unscaled)
+        .into_iter()
+        .enumerate()
+        .map(|(i, masked)| {
+          fp.unmask_value(masked, key, i as u64) % plaintext_size
+        })
+        .collect(),
+    )
+  }
+
+  /// Parses a single frame of a response (see `KVShard::respond_frames`)
+  /// as the u32 values of its columns
+  pub fn parse_frame_as_row(
+    &self,
+    frame: &ResponseFrame,
+    key: &HashedKey,
+  ) -> ResultBoxedError<Vec<u32>> {
+    let plaintext_size = get_plaintext_size(self.plaintext_bits);
+    let fp = self
+      .extra_params
+      .as_ref()
+      .ok_or("No filter parameters set for KV QueryParams")?;
+    Ok(
+      self
+        .round_row(&self.parse_frame_raw(frame)?)
+        .into_iter()
+        .zip(frame.columns()?)
+        .map(|(masked, i)| {
+          fp.unmask_value(masked, key, i as u64) % plaintext_size
+        })
+        .collect(),
+    )
+  }
+
+  /// Parses the output as a row of u32 values, additionally reporting the
+  /// noise margin of each decoded slot
+  pub fn decode_with_margin(
+    &self,
+    resp: &Response,
+    key: &HashedKey,
+  ) -> ResultBoxedError<DecodedRow> {
+    let row = self.parse_resp_as_row(resp, key)?;
+    let margins = self.parse_resp_margins(resp, row.len());
+    Ok(DecodedRow { row, margins })
+  }
+
+  /// Parses the output as bytes
+  pub fn parse_resp_as_bytes(
+    &self,
+    resp: &Response,
+    key: &HashedKey,
+  ) -> ResultBoxedError<Vec<u8>> {
+    let row = self.parse_resp_as_row(resp, key)?;
+    Ok(bytes_from_u32_slice(
+      &row,
+      self.plaintext_bits,
+      self.elem_size,
+    ))
+  }
+
+  /// Parses the output as the original value, removing the framing and
+  /// decompressing it if the DB values were compressed (see
+  /// `compression`), and checking its expiry if the DB values carry
+  /// expiry timestamps (see `expiry`). For uncompressed DBs without
+  /// expiry this is equivalent to `parse_resp_as_bytes`.
+  pub fn parse_resp_as_record(
+    &self,
+    resp: &Response,
+    key: &HashedKey,
+  ) -> ResultBoxedError<Vec<u8>> {
+    self.unframe_record(&self.parse_resp_as_bytes(resp, key)?)
+  }
+
+  /// Parses the output as a base64-encoded string
+  pub fn parse_resp_as_base64(
+    &self,
+    resp: &Response,
+    key: &HashedKey,
+  ) -> ResultBoxedError<String> {
+    self.parse_resp_as_encoded(resp, key, EncodingConfig::Standard)
+  }
+
+  /// Parses the output as a string serialized using `encoding`
+  pub fn parse_resp_as_encoded(
+    &self,
+    resp: &Response,
+    key: &HashedKey,
+    encoding: EncodingConfig,
+  ) -> ResultBoxedError<String> {
+    let row = self.parse_resp_as_row(resp, key)?;
+    Ok(encoded_from_u32_slice(
+      &row,
+      self.plaintext_bits,
+      self.elem_size,
+      encoding,
+    ))
+  }
+}
+
+/// Computes the inner products of `vector` with every column of `db`
+/// using `config`, rejecting vectors whose length differs from the height
+/// of the DB matrix
+fn checked_inner_products<T: DatabaseMatrix + ?Sized>(
+  db: &T,
+  vector: &[u32],
+  config: RespondConfig,
+) -> ResultBoxedError<Vec<u32>> {
+  check_query_len(db, vector)?;
+  respond_columns(db, vector, config)
+}
+
+/// The chunks of the values of a KV DB (see `split_oversized_values`)
+struct ChunkedValues {
+  keys: Vec<String>,
+  values: Vec<String>,
+  max_chunks: usize,
+}
+
+/// Splits all `values` into chunks of `elem_size` bits under the keys
+/// `key#i` if any of them is longer than `elem_size` bits. Returns `None`
+/// if all values fit, or `elem_size` is not a multiple of 8.
+fn split_oversized_values(
+  keys: &[String],
+  values: &[String],
+  elem_size: usize,
+  encoding: EncodingConfig,
+) -> ResultBoxedError<Option<ChunkedValues>> {
+  if keys.len() != values.len() || elem_size % 8 != 0 {
+    return Ok(None);
+  }
+  let decoded = values
+    .iter()
+    .map(|v| encoding.decode(v))
+    .collect::<ResultBoxedError<Vec<Vec<u8>>>>()?;
+  if decoded.iter().all(|v| v.len() <= elem_size / 8) {
+    return Ok(None);
+  }
+  let mut chunked = ChunkedValues {
+    keys: Vec::with_capacity(keys.len()),
+    values: Vec::with_capacity(values.len()),
+    max_chunks: 0,
+  };
+  for (key, value) in keys.iter().zip(decoded) {
+    let chunks = split_into_chunks(&value, elem_size / 8)?;
+    chunked.max_chunks = chunked.max_chunks.max(chunks.len());
+    for (i, chunk) in chunks.iter().enumerate() {
+      chunked.keys.push(format!("{}#{}", key, i));
+      chunked.values.push(encoding.encode(chunk));
+    }
+  }
+  Ok(Some(chunked))
+}
+
+/// Checks that the query `q` has one entry per row of `db`, since the
+/// inner products of `DatabaseMatrix::vec_mult` panic otherwise
+fn check_query_len<T: DatabaseMatrix + ?Sized>(
+  db: &T,
+  q: &[u32],
+) -> ResultBoxedError<()> {
+  if q.len() != db.get_matrix_height() {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "query_len: {}, db height: {}",
+      q.len(),
+      db.get_matrix_height()
+    ))));
+  }
+  Ok(())
+}
+
+/// The number of DB rows whose products with a batch of queries are
+/// accumulated by each task of `respond_rows_batch`
+const BATCH_ROW_CHUNK: usize = 1024;
+
+/// Computes the serialized responses to `queries`, from the row-major
+/// copy of the DB matrix if `db` maintains one, and otherwise column by
+/// column using `config`
+fn respond_batch_with<T: DatabaseMatrix + Sync + ?Sized>(
+  db: &T,
+  queries: &[Query],
+  config: RespondConfig,
+) -> ResultBoxedError<Vec<Vec<u8>>> {
+  for q in queries {
+    check_query_len(db, q.as_slice())?;
+  }
+  let responses = match db.get_row_major() {
+    Some(rows) => respond_rows_batch(rows, db.get_row_width_self(), queries),
+    None => queries
+      .iter()
+      .map(|q| respond_columns(db, q.as_slice(), config))
+      .collect::<ResultBoxedError<_>>()?,
+  };
+  responses
+    .into_iter()
+    .map(|resp| Ok(bincode::serialize(&Response(resp))?))
+    .collect()
+}
+
+/// Computes the responses to `queries` in a single pass over the rows of
+/// the row-major DB matrix `rows`, accumulating the products of chunks
+/// of rows in parallel. Products wrap modulo 2^32, as in `respond`.
+fn respond_rows_batch(
+  rows: &[Vec<u32>],
+  width: usize,
+  queries: &[Query],
+) -> Vec<Vec<u32>> {
+  let zeros = || vec![vec![0u32; width]; queries.len()];
+  rows
+    .par_chunks(BATCH_ROW_CHUNK)
+    .enumerate()
+    .fold(zeros, |mut acc, (chunk_idx, chunk)| {
+      let offset = chunk_idx * BATCH_ROW_CHUNK;
+      for (i, row) in chunk.iter().enumerate() {
+        for (q, out) in queries.iter().zip(acc.iter_mut()) {
+          let x = q.as_slice()[offset + i];
+          for (o, &d) in out.iter_mut().zip(row) {
+            *o = o.wrapping_add(x.wrapping_mul(d));
+          }
+        }
+      }
+      acc
+    })
+    .reduce(zeros, |mut acc, other| {
+      for (out, other) in acc.iter_mut().zip(other) {
+        for (o, x) in out.iter_mut().zip(other) {
+          *o = o.wrapping_add(x);
+        }
+      }
+      acc
+    })
+}
+
+/// Computes the columns of the response to `q` in parallel
+fn respond_columns_par<T: DatabaseMatrix + Sync + ?Sized>(
+  db: &T,
+  q: &Query,
+) -> ResultBoxedError<Response> {
+  let q = q.as_slice();
+  check_query_len(db, q)?;
+  Ok(Response(
+    (0..db.get_row_width_self())
+      .into_par_iter()
+      .map(|i| db.vec_mult(q, i))
+      .collect(),
+  ))
+}
+
+/// Writes the response to `q` in the serialized `Response` layout: the
+/// number of values as a little-endian u64, followed by each value as a
+/// little-endian u32
+fn write_response_stream<T: DatabaseMatrix + ?Sized, W: Write>(
+  db: &T,
+  q: &Query,
+  mut w: W,
+) -> ResultBoxedError<()> {
+  check_query_len(db, q.as_slice())?;
+  let width = db.get_row_width_self();
+  w.write_all(&(width as u64).to_le_bytes())?;
+  for i in 0..width {
+    w.write_all(&db.vec_mult(q.as_slice(), i).to_le_bytes())?;
+  }
+  Ok(w.flush()?)
+}
+
+/// Returns an iterator that computes the response to `q` in serialized
+/// frames of `chunk_cols` columns
+fn response_frames<'a, T: DatabaseMatrix + ?Sized>(
+  db: &'a T,
+  q: &'a Query,
+  chunk_cols: usize,
+) -> ResultBoxedError<impl Iterator<Item = ResultBoxedError<Vec<u8>>> + 'a> {
+  if chunk_cols == 0 {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(
+      "frames must hold at least one column".to_string(),
+    )));
+  }
+  check_query_len(db, q.as_slice())?;
+  let width = db.get_row_width_self();
+  Ok((0..width).step_by(chunk_cols).map(move |start| {
+    let end = (start + chunk_cols).min(width);
+    let frame = ResponseFrame {
+      start,
+      values: (start..end).map(|i| db.vec_mult(q.as_slice(), i)).collect(),
+    };
+    Ok(bincode::serialize(&frame)?)
+  }))
+}
+
+/// Returns a dummy query that does not target any row, without computing
+/// the client-side state needed to parse a response (see
+/// `QueryParams::generate_dummy_query`)
+pub fn generate_dummy_query(cp: &CommonParams) -> ResultBoxedError<Query> {
+  let s = random_ternary_vector(cp.get_dim());
+  Ok(Query(cp.mult_left(&s)?))
+}
+
+/// Returns `QueryParams` for an Index-based DB (`IndexDatabase`)
+pub fn generate_index_query_params(
+  cp: &CommonParams,
+  params: &IndexParams,
+) -> ResultBoxedError<QueryParams<IndexDatabase, EmptyAuxParams>> {
+  QueryParams::<IndexDatabase, EmptyAuxParams>::new(cp, params)
+}
+
+/// Same as `generate_index_query_params`, sampling the client secret and
+/// error from `entropy`
+pub fn generate_index_query_params_with_entropy<E: Entropy>(
+  cp: &CommonParams,
+  params: &IndexParams,
+  entropy: &mut E,
+) -> ResultBoxedError<QueryParams<IndexDatabase, EmptyAuxParams>> {
+  QueryParams::<IndexDatabase, EmptyAuxParams>::new_with_entropy(
+    cp, params, entropy,
+  )
+}
+
+/// Recovers the original record from its decoded bytes, decompressing it
+/// (see `compression`) and checking its expiry (see `expiry`) if the DB
+/// records were built with either
+pub(crate) fn unframe_record(
+  decoded: &[u8],
+  compression: Compression,
+  record_expiry: bool,
+) -> ResultBoxedError<Vec<u8>> {
+  let record = decompress_record(decoded, compression)?;
+  if !record_expiry {
+    return Ok(record);
+  }
+  unstamp_record(&record, SystemTime::now())
+}
+
+/// Returns the `FilterParams` of `params`, checking that they match the
+/// plaintext modulus that the params were generated for
+fn kv_filter_params(params: &KVParams) -> ResultBoxedError<FilterParams> {
+  let fp = params.get_filter_params();
+  fp.check_plaintext_bits(params.get_plaintext_bits())?;
+  Ok(fp)
+}
+
+/// Checks that an externally supplied secret `s` could have been sampled
+/// by `random_ternary_vector` for the LWE dimension `dim`: it must have
+/// `dim` entries from {0, 1, u32::MAX}, each of the three values making up
+/// at least a sixth of them. Sampled secrets of dimension 512 or more fail
+/// the latter check with probability below 2^-50, whereas skewed secrets
+/// (e.g. all zeroes) would undermine the privacy of the queries.
+fn check_ternary_secret(s: &[u32], dim: usize) -> ResultBoxedError<()> {
+  if s.len() != dim {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "Secret length: {}, LWE dimension: {}",
+      s.len(),
+      dim
+    ))));
+  }
+  let mut counts = [0usize; 3];
+  for &x in s {
+    match x {
+      0 => counts[0] += 1,
+      1 => counts[1] += 1,
+      u32::MAX => counts[2] += 1,
+      _ => {
+        return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+          "Secret entry {} is not ternary",
+          x
+        ))))
+      }
+    }
+  }
+  if counts.iter().any(|&c| c < dim / 6) {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "Secret with {} zero, {} one and {} minus one entries is not \
+       uniformly ternary",
+      counts[0], counts[1], counts[2]
+    ))));
+  }
+  Ok(())
+}
+
+/// Returns `QueryParams` for an KV-based DB (`KVDatabase`)
+pub fn generate_kv_query_params(
+  cp: &CommonParams,
+  params: &KVParams,
+) -> ResultBoxedError<QueryParams<KVDatabase, FilterParams>> {
+  QueryParams::<KVDatabase, FilterParams>::new(cp, params)
+}
+
+/// Same as `generate_kv_query_params`, sampling the client secret and
+/// error from `entropy`
+pub fn generate_kv_query_params_with_entropy<E: Entropy>(
+  cp: &CommonParams,
+  params: &KVParams,
+  entropy: &mut E,
+) -> ResultBoxedError<QueryParams<KVDatabase, FilterParams>> {
+  QueryParams::<KVDatabase, FilterParams>::new_with_entropy(cp, params, entropy)
+}
+
+/// Returns `QueryParams` for an Index-based DB (`IndexDatabase`) that
+/// can produce a `SparseQuery`
+pub fn generate_sparse_index_query_params(
+  cp: &CommonParams,
+  params: &IndexParams,
+) -> ResultBoxedError<QueryParams<IndexDatabase, EmptyAuxParams>> {
+  QueryParams::<IndexDatabase, EmptyAuxParams>::from_query_seed(
+    cp,
+    params,
+    generate_seed(),
+  )
+}
+
+/// Returns `QueryParams` for an Index-based DB (`IndexDatabase`),
+/// deriving the client secret and error from `query_seed`. Queries are
+/// then reproducible, so each seed must only be used once outside of
+/// tests.
+pub fn generate_index_query_params_from_seed(
+  cp: &CommonParams,
+  params: &IndexParams,
+  query_seed: [u8; 32],
+) -> ResultBoxedError<QueryParams<IndexDatabase, EmptyAuxParams>> {
+  QueryParams::<IndexDatabase, EmptyAuxParams>::from_query_seed(
+    cp, params, query_seed,
+  )
+}
+
+/// Returns `QueryParams` for an KV-based DB (`KVDatabase`), deriving the
+/// client secret and error from `query_seed` (see
+/// `generate_index_query_params_from_seed`)
+pub fn generate_kv_query_params_from_seed(
+  cp: &CommonParams,
+  params: &KVParams,
+  query_seed: [u8; 32],
+) -> ResultBoxedError<QueryParams<KVDatabase, FilterParams>> {
+  QueryParams::<KVDatabase, FilterParams>::from_query_seed(
+    cp, params, query_seed,
+  )
+}
+
+/// Returns `QueryParams` for an Index-based DB (`IndexDatabase`), using
+/// the next precomputed entry of `hint`
+pub fn generate_index_query_params_from_hint(
+  hint: &mut ClientHint,
+  params: &IndexParams,
+) -> ResultBoxedError<QueryParams<IndexDatabase, EmptyAuxParams>> {
+  QueryParams::<IndexDatabase, EmptyAuxParams>::from_hint(hint, params)
+}
+
+/// Returns `QueryParams` for an KV-based DB (`KVDatabase`), using the
+/// next precomputed entry of `hint`
+pub fn generate_kv_query_params_from_hint(
+  hint: &mut ClientHint,
+  params: &KVParams,
+) -> ResultBoxedError<QueryParams<KVDatabase, FilterParams>> {
+  QueryParams::<KVDatabase, FilterParams>::from_hint(hint, params)
+}
+
+/// A `DecodeKey` holds the part of `QueryParams` that is needed to parse
+/// the response to a query: the client secret multiplied into the RHS of
+/// the params, and the decoding parameters. It is much smaller than the
+/// `QueryParams`, and can be serialized, so that a response can be parsed
+/// later or on another device than the one that generated the query.
+///
+/// The key must be kept as secret as the `QueryParams`, since it reveals
+/// the queried row to anyone who also sees the query.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(remote = "Self")]
+pub struct DecodeKey {
+  rhs: Vec<u32>,
+  elem_size: usize,
+  plaintext_bits: usize,
+  filter_params: Option<FilterParams>,
+  #[serde(default)]
+  rounding: RoundingMode,
+  #[serde(default)]
+  compression: Compression,
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  record_expiry: bool,
+  #[serde(default)]
+  modulus: Modulus,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  column_bits: Option<Vec<usize>>,
+}
+impl Serialize for DecodeKey {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    Self::serialize(self, serializer)
+  }
+}
+
+impl<'de> Deserialize<'de> for DecodeKey {
+  fn deserialize<D: Deserializer<'de>>(
+    deserializer: D,
+  ) -> Result<Self, D::Error> {
+    let key = Self::deserialize(deserializer)?;
+    check_decoding(
+      key.modulus,
+      key.plaintext_bits,
+      key.column_bits.as_deref(),
+      key.elem_size,
+      key.rhs.len(),
+    )
+    .map_err(serde::de::Error::custom)?;
+    Ok(key)
+  }
+}
+
+impl DecodeKey {
+  /// Returns `QueryParams` that can only parse responses, for an index DB
+  pub fn into_index_params(self) -> QueryParams<IndexDatabase, EmptyAuxParams> {
+    self.into_query_params(None)
+  }
+
+  /// Returns `QueryParams` that can only parse responses, for a KV DB.
+  /// Fails if the key was not generated by KV `QueryParams`.
+  pub fn into_kv_params(
+    mut self,
+  ) -> ResultBoxedError<QueryParams<KVDatabase, FilterParams>> {
+    let fp = self
+      .filter_params
+      .take()
+      .ok_or("No filter parameters set for KV DecodeKey")?;
+    Ok(self.into_query_params(Some(fp)))
+  }
+
+  fn into_query_params<DB, EP>(
+    self,
+    extra_params: Option<EP>,
+  ) -> QueryParams<DB, EP> {
+    QueryParams {
+      lhs: Vec::new(),
+      rhs: self.rhs,
+      elem_size: self.elem_size,
+      plaintext_bits: self.plaintext_bits,
+      db: PhantomData,
+      extra_params,
+      query_seed: None,
+      rounding: self.rounding,
+      compression: self.compression,
+      record_expiry: self.record_expiry,
+      modulus: self.modulus,
+      column_bits: self.column_bits,
+      record_rows: None,
+      used: true,
+    }
+  }
+}
+
+/// `IndexQueryParams` are the `QueryParams` for an index DB, without the
+/// type parameters. Each instance prepares a single query.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct IndexQueryParams(QueryParams<IndexDatabase, EmptyAuxParams>);
+impl IndexQueryParams {
+  /// Generates fresh query params (see `generate_index_query_params`)
+  pub fn new(
+    cp: &CommonParams,
+    params: &IndexParams,
+  ) -> ResultBoxedError<Self> {
+    Ok(Self(QueryParams::<IndexDatabase, EmptyAuxParams>::new(
+      cp, params,
+    )?))
+  }
+
+  /// Generates query params whose queries can be sent as a `SparseQuery`
+  /// (see `generate_sparse_index_query_params`)
+  pub fn new_sparse(
+    cp: &CommonParams,
+    params: &IndexParams,
+  ) -> ResultBoxedError<Self> {
+    Ok(Self(generate_sparse_index_query_params(cp, params)?))
+  }
+
+  /// Derives the query params from `query_seed` (see
+  /// `generate_index_query_params_from_seed`)
+  pub fn from_seed(
+    cp: &CommonParams,
+    params: &IndexParams,
+    query_seed: [u8; 32],
+  ) -> ResultBoxedError<Self> {
+    Ok(Self(
+      QueryParams::<IndexDatabase, EmptyAuxParams>::from_query_seed(
+        cp, params, query_seed,
+      )?,
+    ))
+  }
+
+  /// Uses the next precomputed entry of `hint`
+  pub fn from_hint(
+    hint: &mut ClientHint,
+    params: &IndexParams,
+  ) -> ResultBoxedError<Self> {
+    Ok(Self(
+      QueryParams::<IndexDatabase, EmptyAuxParams>::from_hint(hint, params)?,
+    ))
+  }
+
+  /// Prepares a query for `row_index`
+  pub fn generate_query(
+    &mut self,
+    row_index: usize,
+  ) -> ResultBoxedError<Query> {
+    self.0.generate_query(row_index)
+  }
+
+  /// Consumes the params to prepare a single query (see
+  /// `QueryParams::into_query`)
+  pub fn into_query(
+    self,
+    row_index: usize,
+  ) -> ResultBoxedError<(Query, DecodeKey)> {
+    self.0.into_query(row_index)
+  }
+
+  /// Prepares a query for `row_index` into `buf` (see
+  /// `QueryParams::generate_query_into`)
+  pub fn generate_query_into(
+    &mut self,
+    row_index: usize,
+    buf: &mut [u32],
+  ) -> ResultBoxedError<()> {
+    self.0.generate_query_into(row_index, buf)
+  }
+
+  /// Prepares a query for `row_index` in its sparse form
+  pub fn generate_sparse_query(
+    &mut self,
+    row_index: usize,
+  ) -> ResultBoxedError<SparseQuery> {
+    self.0.generate_sparse_query(row_index)
+  }
+
+  /// Prepares a dummy query for cover traffic
+  pub fn generate_dummy_query(&mut self) -> ResultBoxedError<Query> {
+    self.0.generate_dummy_query()
+  }
+
+  /// Parses the response as a row of u32 values
+  pub fn parse_resp_as_row(&self, resp: &Response) -> Vec<u32> {
+    self.0.parse_resp_as_row(resp)
+  }
+
+  /// Parses the response as bytes
+  pub fn parse_resp_as_bytes(&self, resp: &Response) -> Vec<u8> {
+    self.0.parse_resp_as_bytes(resp)
+  }
+
+  /// Parses the response as a base64-encoded string
+  pub fn parse_resp_as_base64(&self, resp: &Response) -> String {
+    self.0.parse_resp_as_base64(resp)
+  }
+
+  /// Parses the response as the original, decompressed record
+  pub fn parse_resp_as_record(
+    &self,
+    resp: &Response,
+  ) -> ResultBoxedError<Vec<u8>> {
+    self.0.parse_resp_as_record(resp)
+  }
+
+  /// Parses a single frame of a response as a row of u32 values
+  pub fn parse_frame_as_row(
+    &self,
+    frame: &ResponseFrame,
+  ) -> ResultBoxedError<Vec<u32>> {
+    self.0.parse_frame_as_row(frame)
+  }
+
+  /// Parses the response, reporting the noise margin of each slot
+  pub fn decode_with_margin(&self, resp: &Response) -> DecodedRow {
+    self.0.decode_with_margin(resp)
+  }
+
+  /// Returns the `DecodeKey` for parsing responses to the query
+  pub fn decode_key(&self) -> DecodeKey {
+    self.0.decode_key()
+  }
+
+  /// Returns the wrapped `QueryParams`
+  pub fn into_inner(self) -> QueryParams<IndexDatabase, EmptyAuxParams> {
+    self.0
+  }
+}
+impl From<QueryParams<IndexDatabase, EmptyAuxParams>> for IndexQueryParams {
+  fn from(qp: QueryParams<IndexDatabase, EmptyAuxParams>) -> Self {
+    Self(qp)
+  }
+}
+
+/// `KvQueryParams` are the `QueryParams` for a KV DB, without the type
+/// parameters. Each instance prepares a single query.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct KvQueryParams(QueryParams<KVDatabase, FilterParams>);
+impl KvQueryParams {
+  /// Generates fresh query params (see `generate_kv_query_params`)
+  pub fn new(cp: &CommonParams, params: &KVParams) -> ResultBoxedError<Self> {
+    Ok(Self(QueryParams::<KVDatabase, FilterParams>::new(
+      cp, params,
+    )?))
+  }
+
+  /// Derives the query params from `query_seed` (see
+  /// `generate_kv_query_params_from_seed`)
+  pub fn from_seed(
+    cp: &CommonParams,
+    params: &KVParams,
+    query_seed: [u8; 32],
+  ) -> ResultBoxedError<Self> {
+    Ok(Self(
+      QueryParams::<KVDatabase, FilterParams>::from_query_seed(
+        cp, params, query_seed,
+      )?,
+    ))
+  }
+
+  /// Uses the next precomputed entry of `hint`
+  pub fn from_hint(
+    hint: &mut ClientHint,
+    params: &KVParams,
+  ) -> ResultBoxedError<Self> {
+    Ok(Self(QueryParams::<KVDatabase, FilterParams>::from_hint(
+      hint, params,
+    )?))
+  }
+
+  /// Prepares a query for a hashed `key`
+  pub fn generate_query(&mut self, key: &HashedKey) -> ResultBoxedError<Query> {
+    self.0.generate_query(key)
+  }
+
+  /// Consumes the params to prepare a single query (see
+  /// `QueryParams::into_query`)
+  pub fn into_query(
+    self,
+    key: &HashedKey,
+  ) -> ResultBoxedError<(Query, DecodeKey)> {
+    self.0.into_query(key)
+  }
+
+  /// Prepares a query for the bytes of an unhashed key (see
+  /// `QueryParams::generate_query_for_key`)
+  pub fn generate_query_for_key(
+    &mut self,
+    key: &KeyBytes,
+  ) -> ResultBoxedError<Query> {
+    self.0.generate_query_for_key(key)
+  }
+
+  /// Prepares a query for a hashed `key` into `buf` (see
+  /// `QueryParams::generate_query_into`)
+  pub fn generate_query_into(
+    &mut self,
+    key: &HashedKey,
+    buf: &mut [u32],
+  ) -> ResultBoxedError<()> {
+    self.0.generate_query_into(key, buf)
+  }
+
+  /// Prepares a dummy query for cover traffic
+  pub fn generate_dummy_query(&mut self) -> ResultBoxedError<Query> {
+    self.0.generate_dummy_query()
+  }
+
+  /// Prepares a query for the raw row `row_index` of the DB matrix (see
+  /// `QueryParams::generate_query_by_index`)
+  pub fn generate_query_by_index(
+    &mut self,
+    row_index: usize,
+  ) -> ResultBoxedError<Query> {
+    self.0.generate_query_by_index(row_index)
+  }
+
+  /// Parses the response to a query by index as the masked row
+  pub fn parse_resp_by_index(&self, resp: &Response) -> Vec<u32> {
+    self.0.parse_resp_by_index(resp)
+  }
+
+  /// Parses the response for `key` as a row of u32 values
+  pub fn parse_resp_as_row(
+    &self,
+    resp: &Response,
+    key: &HashedKey,
+  ) -> ResultBoxedError<Vec<u32>> {
+    self.0.parse_resp_as_row(resp, key)
+  }
+
+  /// Parses the response for `key` as bytes
+  pub fn parse_resp_as_bytes(
+    &self,
+    resp: &Response,
+    key: &HashedKey,
+  ) -> ResultBoxedError<Vec<u8>> {
+    self.0.parse_resp_as_bytes(resp, key)
+  }
+
+  /// Parses the response for `key` as a base64-encoded string
+  pub fn parse_resp_as_base64(
+    &self,
+    resp: &Response,
+    key: &HashedKey,
+  ) -> ResultBoxedError<String> {
+    self.0.parse_resp_as_base64(resp, key)
+  }
+
+  /// Parses the response for `key` as the original, decompressed value
+  pub fn parse_resp_as_record(
+    &self,
+    resp: &Response,
+    key: &HashedKey,
+  ) -> ResultBoxedError<Vec<u8>> {
+    self.0.parse_resp_as_record(resp, key)
+  }
+
+  /// Parses a single frame of a response for `key` as a row of u32 values
+  pub fn parse_frame_as_row(
+    &self,
+    frame: &ResponseFrame,
+    key: &HashedKey,
+  ) -> ResultBoxedError<Vec<u32>> {
+    self.0.parse_frame_as_row(frame, key)
+  }
+
+  /// Parses the response for `key`, reporting the noise margin of each
+  /// slot
+  pub fn decode_with_margin(
+    &self,
+    resp: &Response,
+    key: &HashedKey,
+  ) -> ResultBoxedError<DecodedRow> {
+    self.0.decode_with_margin(resp, key)
+  }
+
+  /// Returns the `DecodeKey` for parsing responses to the query
+  pub fn decode_key(&self) -> DecodeKey {
+    self.0.decode_key()
+  }
+
+  /// Returns the wrapped `QueryParams`
+  pub fn into_inner(self) -> QueryParams<KVDatabase, FilterParams> {
+    self.0
+  }
+}
+impl From<QueryParams<KVDatabase, FilterParams>> for KvQueryParams {
+  fn from(qp: QueryParams<KVDatabase, FilterParams>) -> Self {
+    Self(qp)
+  }
+}
+
+/// A `ClientHint` holds the offline work of a client for a batch of
+/// future queries: the expansion of `A` from the public seed and the
+/// computation of `s*A + e` for a number of fresh secrets `s`. It can be
+/// computed ahead of time (e.g. while a mobile device is charging),
+/// written to file, and later combined with `BaseParams::mult_right` to
+/// produce `QueryParams` without touching `A` again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientHint {
+  public_seed: [u8; 32],
+  dim: usize,
+  m: usize,
+  entries: Vec<HintEntry>,
+}
+
+impl Persist for ClientHint {
+  const KIND: &'static str = "ClientHint";
+
+  fn summary(&self) -> serde_json::Value {
+    serde_json::json!({
+      "dim": self.dim,
+      "m": self.m,
+      "entries": self.entries.len(),
+    })
+  }
+}
+
+/// A single precomputed secret, along with `s*A + e`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct HintEntry {
+  s: Vec<u32>,
+  lhs: Vec<u32>,
+}
+
+impl ClientHint {
+  /// Expands `A` from the seed in `params` and precomputes `count`
+  /// entries
+  pub fn new<T: BaseParams>(
+    params: &T,
+    count: usize,
+  ) -> ResultBoxedError<Self> {
+    Self::from_common_params(&CommonParams::from(params), params, count)
+  }
+
+  /// Precomputes `count` entries using an already expanded `A`
+  pub fn from_common_params<T: BaseParams>(
+    cp: &CommonParams,
+    params: &T,
+    count: usize,
+  ) -> ResultBoxedError<Self> {
+    let secrets: Vec<Vec<u32>> = (0..count)
+      .map(|_| random_ternary_vector(params.get_dim()))
+      .collect();
+    let entries = cp
+      .mult_left_batch(&secrets)?
+      .into_iter()
+      .zip(secrets)
+      .map(|(lhs, s)| HintEntry { s, lhs })
+      .collect();
+    Ok(Self {
+      public_seed: params.get_public_seed(),
+      dim: params.get_dim(),
+      m: params.get_total_records(),
+      entries,
+    })
+  }
+
+  /// Returns the number of unused precomputed entries
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  /// Returns whether all precomputed entries were used already
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  /// Writes the hint as JSON to file, preceded by a `FileHeader`
+  pub fn write_to_file(&self, path: &str) -> ResultBoxedError<()> {
+    write_persisted(path, self)
+  }
+
+  /// Load a hint that was written using `write_to_file`
+  pub fn load(path: &str) -> ResultBoxedError<Self> {
+    Ok(read_persisted(path)?)
+  }
+
+  /// Removes and returns the next entry, checking that the hint was
+  /// computed for `params`
+  fn next_entry<T: BaseParams>(
+    &mut self,
+    params: &T,
+  ) -> ResultBoxedError<HintEntry> {
+    if self.public_seed != params.get_public_seed()
+      || self.dim != params.get_dim()
+      || self.m != params.get_total_records()
+    {
+      return Err(Box::new(ErrorHintMismatch {}));
+    }
+    self
+      .entries
+      .pop()
+      .ok_or_else(|| ErrorHintExhausted {}.into())
+  }
+}
+
+/// The `Query` struct holds the necessary information encoded in
+/// a client PIR query to the server DB for a particular `row_index`. It
+/// provides methods for parsing server responses.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Query(Vec<u32>);
+impl Query {
+  pub fn as_slice(&self) -> &[u32] {
+    &self.0
+  }
+
+  /// Pads the query with uniformly random values up to `len` entries, so
+  /// that its length does not reveal the targeted shard (see
+  /// `ShardCluster`). Servers ignore the padding.
+  pub fn pad_to(&mut self, len: usize) {
+    while self.0.len() < len {
+      self.0.push(OsRng.next_u32());
+    }
+  }
+
+  pub(crate) fn from_values(values: Vec<u32>) -> Self {
+    Self(values)
+  }
+
+  /// Returns a query of `len` uniformly random values, e.g. for warming
+  /// up a server (see `ServingShard::prime`)
+  pub(crate) fn random(len: usize) -> Query {
+    let mut q = Query(Vec::with_capacity(len));
+    q.pad_to(len);
+    q
+  }
+
+  /// Encodes the query using `modulus.bits()` bits per entry (see
+  /// `pack_entries`), which saves bandwidth over the default encoding
+  /// for moduli below 2^32. Entries are reduced by the modulus, which
+  /// does not change the response modulo the modulus.
+  pub fn to_packed_bytes(&self, modulus: Modulus) -> Vec<u8> {
+    pack_entries(&self.0, modulus)
+  }
+
+  /// Decodes a query encoded by `to_packed_bytes`
+  pub fn from_packed_bytes(
+    bytes: &[u8],
+    modulus: Modulus,
+  ) -> ResultBoxedError<Self> {
+    Ok(Self(unpack_entries(bytes, modulus)?))
+  }
+
+  /// Returns the first `len` entries of the query, i.e. the query with
+  /// any padding removed
+  pub(crate) fn truncated(&self, len: usize) -> ResultBoxedError<Query> {
+    if self.0.len() < len {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "query_len: {}, expected at least: {}",
+        self.0.len(),
+        len
+      ))));
+    }
+    Ok(Query(self.0[..len].to_vec()))
+  }
+}
+
+/// A `SparseQuery` is a compact representation of an index `Query`: the
+/// seed that the secret and error of the query are derived from, plus the
+/// coordinates that were perturbed to target a row. The server expands
+/// it into a dense `Query` using `CommonParams`.
+///
+/// *Warning*: the seed reveals the client secret, and hence the queried
+/// row, so a `SparseQuery` must only be used in-process or over a trusted
+/// channel (e.g. within a datacenter).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SparseQuery {
+  seed: [u8; 32],
+  deltas: Vec<(usize, u32)>,
+}
+impl SparseQuery {
+  /// Expands the sparse query into the dense `Query` expected by the
+  /// server
+  pub fn expand(&self, cp: &CommonParams) -> ResultBoxedError<Query> {
+    let (_, mut lhs) = Self::derive_lhs(cp, self.seed)?;
+    for &(i, delta) in &self.deltas {
+      if i >= lhs.len() {
+        return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+          "delta index: {}, m: {}",
+          i,
+          lhs.len()
+        ))));
+      }
+      lhs[i] = lhs[i].wrapping_add(delta);
+    }
+    Ok(Query(lhs))
+  }
+
+  /// Derives the secret `s` and `s*A + e` from a seed
+  fn derive_lhs(
+    cp: &CommonParams,
+    seed: [u8; 32],
+  ) -> ResultBoxedError<(Vec<u32>, Vec<u32>)> {
+    let mut rng = get_seeded_rng(seed);
+    let s = random_ternary_vector_from(&mut rng, cp.get_dim());
+    let lhs = cp.mult_left_from_rng(&s, &mut rng)?;
+    Ok((s, lhs))
+  }
+}
+
+/// The `Response` object wraps a response from a single shard
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Response(Vec<u32>);
+impl Response {
+  pub fn as_slice(&self) -> &[u32] {
+    &self.0
+  }
+
+  pub(crate) fn from_values(values: Vec<u32>) -> Self {
+    Self(values)
+  }
+
+  /// Pads the response with uniformly random values up to `len` entries.
+  /// Clients ignore the padding when parsing responses.
+  pub(crate) fn pad_to(&mut self, len: usize) {
+    while self.0.len() < len {
+      self.0.push(OsRng.next_u32());
+    }
+  }
+
+  /// Encodes the response using `modulus.bits()` bits per entry (see
+  /// `Query::to_packed_bytes`)
+  pub fn to_packed_bytes(&self, modulus: Modulus) -> Vec<u8> {
+    pack_entries(&self.0, modulus)
+  }
+
+  /// Decodes a response encoded by `to_packed_bytes`
+  pub fn from_packed_bytes(
+    bytes: &[u8],
+    modulus: Modulus,
+  ) -> ResultBoxedError<Self> {
+    Ok(Self(unpack_entries(bytes, modulus)?))
+  }
+}
+
+/// Encodes `values` reduced by `modulus` using `modulus.bits()` bits
+/// each, prefixed with their number as a little-endian u64
+fn pack_entries(values: &[u32], modulus: Modulus) -> Vec<u8> {
+  let reduced: Vec<u32> = values.iter().map(|&v| modulus.reduce(v)).collect();
+  let column_bits = vec![modulus.bits() as usize; values.len()];
+  let mut bytes = (values.len() as u64).to_le_bytes().to_vec();
+  bytes.extend(bytes_from_u32_slice_with_column_bits(
+    &reduced,
+    &column_bits,
+    usize::MAX,
+  ));
+  bytes
+}
+
+/// Decodes entries encoded by `pack_entries`, failing if the number of
+/// bytes does not match the number of entries
+fn unpack_entries(
+  bytes: &[u8],
+  modulus: Modulus,
+) -> ResultBoxedError<Vec<u32>> {
+  let bits = modulus.bits() as usize;
+  if bytes.len() < 8 {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "packed entries of {} bytes lack their length prefix",
+      bytes.len()
+    ))));
+  }
+  let (prefix, packed) = bytes.split_at(8);
+  let len = usize::try_from(u64::from_le_bytes(prefix.try_into()?))?;
+  let expected = len.checked_mul(bits).map(|b| b.div_ceil(8));
+  if expected != Some(packed.len()) {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "{} packed bytes for {} entries of {} bits",
+      packed.len(),
+      len,
+      bits
+    ))));
+  }
+  (0..len)
+    .map(|i| Ok(read_bits_le(packed, i * bits, bits)?))
+    .collect()
+}
+
+/// A `ResponseFrame` holds the values of a range of consecutive columns of
+/// a response, starting at column `start` (see `Shard::respond_frames`).
+/// The decoded row of a response is the concatenation of the decoded
+/// rows of its frames, in order of `start`. Deserializing a frame whose
+/// columns would overflow fails.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "ResponseFrameFields")]
+pub struct ResponseFrame {
+  pub start: usize,
+  pub values: Vec<u32>,
+}
+impl ResponseFrame {
+  /// Returns the range of response columns held by the frame, failing if
+  /// the end of the range overflows
+  pub fn columns(&self) -> ResultBoxedError<Range<usize>> {
+    match self.start.checked_add(self.values.len()) {
+      Some(end) => Ok(self.start..end),
+      None => Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "frame of {} columns starting at column {} overflows",
+        self.values.len(),
+        self.start
+      )))),
+    }
+  }
+}
+
+/// The serialized fields of a `ResponseFrame`, before they are validated
+#[derive(Deserialize)]
+struct ResponseFrameFields {
+  start: usize,
+  values: Vec<u32>,
+}
+
+impl TryFrom<ResponseFrameFields> for ResponseFrame {
+  type Error = String;
+
+  fn try_from(fields: ResponseFrameFields) -> Result<Self, Self::Error> {
+    let frame = Self {
+      start: fields.start,
+      values: fields.values,
+    };
+    frame.columns().map_err(|e| e.to_string())?;
+    Ok(frame)
+  }
+}
+
+/// A `QueryEnvelope` carries a `Query` on the wire together with an
+/// optional id, which the server copies into the `ResponseEnvelope`. This
+/// lets clients with many outstanding queries over one connection match
+/// responses to queries without external bookkeeping.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QueryEnvelope {
+  pub id: Option<u128>,
+  pub query: Query,
+}
+impl QueryEnvelope {
+  /// Wraps `query` with a fresh random id
+  pub fn new(query: Query) -> Self {
+    let id = ((OsRng.next_u64() as u128) << 64) | OsRng.next_u64() as u128;
+    Self {
+      id: Some(id),
+      query,
+    }
+  }
+
+  /// Wraps `query` without an id
+  pub fn without_id(query: Query) -> Self {
+    Self { id: None, query }
+  }
+}
+
+/// A `ResponseEnvelope` carries a `Response` on the wire together with
+/// the id of the `QueryEnvelope` it answers
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ResponseEnvelope {
+  pub id: Option<u128>,
+  pub response: Response,
+}
+impl ResponseEnvelope {
+  /// Checks whether this is the response to `envelope`
+  pub fn answers(&self, envelope: &QueryEnvelope) -> bool {
+    self.id.is_some() && self.id == envelope.id
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmptyAuxParams {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::audit::{commit_records, verify_inclusion};
+  use crate::builder::ShardBuilder;
+  use crate::client::{ClientSession, HintManager};
+  use crate::db::FilterParams;
+  use crate::delta::{params_digest, ParamsDelta};
+  use crate::errors::{
+    ErrorDeduplicatedDb, ErrorInclusionInvalid, ErrorParamsMismatch,
+  };
+  use crate::expiry::TOMBSTONE;
+  use crate::test_utils::{
+    generate_db_bytes, generate_db_eles, generate_kv_db_elems, index_shard,
+    kv_shard, TempPath, ELEM_SIZE, LWE_DIM, M, PLAINTEXT_BITS,
+  };
+  use rand_core::{OsRng, RngCore};
+  use std::fs;
+
+  #[test]
+  fn client_query_to_server_10_times() {
+    let m = 2u32.pow(12) as usize;
+    let elem_size = 2u32.pow(8) as usize;
+    let plaintext_bits = 11usize;
+    let lwe_dim = 512;
+    let db_eles = generate_db_eles(m, (elem_size + 7) / 8);
+    let shard = Shard::from_base64_strings(
+      &db_eles,
+      lwe_dim,
+      m,
+      elem_size,
+      plaintext_bits,
+    )
+    .unwrap();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    #[allow(clippy::needless_range_loop)]
+    for i in 0..10 {
+      let mut qp =
+        QueryParams::<IndexDatabase, EmptyAuxParams>::new(&cp, bp).unwrap();
+      let q = qp.generate_query(i).unwrap();
+      let d_resp = shard.respond(&q).unwrap();
+      let resp: Response = bincode::deserialize(&d_resp).unwrap();
+      let output = qp.parse_resp_as_base64(&resp);
+      assert_eq!(output, db_eles[i]);
+    }
+  }
+
+  #[test]
+  fn shard_entries_are_decoded_without_base64() {
+    let (db_eles, shard) = index_shard();
+    for i in [0, 10, M - 1] {
+      let record = shard.get_db().get_db_entry_decoded(i).unwrap();
+      assert_eq!(record.as_bytes(), base64::decode(&db_eles[i]).unwrap());
+      assert_eq!(record.encode(EncodingConfig::Standard), db_eles[i]);
+    }
+    assert!(shard.get_db().get_db_entry_bytes(M).is_err());
+  }
+
+  #[test]
+  fn client_kv_query_to_server_10_times() {
+    let m = 2u32.pow(12) as usize;
+    let elem_size = 2u32.pow(8) as usize;
+    let plaintext_bits = 11usize;
+    let lwe_dim = 512;
+    let db_eles = generate_kv_db_elems(m, (elem_size + 7) / 8);
+    let res: ResultBoxedError<Vec<KeyValue>> = db_eles
+      .iter()
+      .map(|e| {
+        KeyValue::from_base64_strings(&e.0, &e.1, elem_size, plaintext_bits)
+      })
+      .collect::<Vec<ResultBoxedError<KeyValue>>>()
+      .into_iter()
+      .collect();
+    let kvs = res.unwrap();
+    let shard = KVShard::new(&kvs, lwe_dim, elem_size, plaintext_bits).unwrap();
+    assert_eq!(shard.num_keys(), m);
+    assert!(shard.matrix_height() > m);
+    assert!(shard.load_factor() > 0.0 && shard.load_factor() < 1.0);
+    let bp = shard.get_base_params();
+
+    // Compute the real values for testing purposes
+    for kv in &kvs {
+      let v: Vec<Vec<usize>> = (0..shard.get_db().entries.len())
+        .map(|_| shard.get_db().get_filter_params().get_hash_evals(&kv.key))
+        .collect();
+      for (j, col) in v.iter().enumerate() {
+        let masked = col.iter().fold(0u32, |acc, r| {
+          acc.wrapping_add(shard.get_db().entries[j][*r])
+        });
+        let unmasked = shard
+          .get_db()
+          .get_filter_params()
+          .unmask_value(masked, &kv.key, j as u64);
+        let add_modp = unmasked % 2u32.pow(plaintext_bits as u32);
+        assert_eq!(kv.value[j], add_modp);
+      }
+    }
+
+    let cp = CommonParams::from(bp);
+
+    #[allow(clippy::needless_range_loop)]
+    for i in 0..10 {
+      let mut qp =
+        QueryParams::<KVDatabase, FilterParams>::new(&cp, bp).unwrap();
+      let q = qp.generate_query(&kvs[i].key).unwrap();
+
+      let d_resp = shard.respond(&q).unwrap();
+      let resp: Response = bincode::deserialize(&d_resp).unwrap();
+
+      let output = qp.parse_resp_as_row(&resp, &kvs[i].key).unwrap();
+      assert_eq!(output, kvs[i].value);
+    }
+  }
+
+  #[test]
+  fn client_kv_query_for_unhashed_key() {
+    let (keys, values, shard) = kv_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+    let q = qp
+      .generate_query_for_key(&KeyBytes::from(keys[0].as_str()))
+      .unwrap();
+    let d_resp = shard.respond(&q).unwrap();
+    let resp: Response = bincode::deserialize(&d_resp).unwrap();
+    let key = HashedKey::from_bytes(keys[0].as_bytes());
+    assert_eq!(qp.parse_resp_as_base64(&resp, &key).unwrap(), values[0]);
+  }
+
+  #[test]
+  fn decoded_margins_flag_perturbed_responses() {
+    let floor = Modulus::default().rounding_floor(PLAINTEXT_BITS);
+    // shifting every entry by half the rounding factor moves the residues
+    // next to a rounding boundary
+    let perturb = |resp: &Response| {
+      let shifted = resp.as_slice().iter().map(|v| v.wrapping_add(floor));
+      Response::from_values(shifted.collect())
+    };
+
+    let (_, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let mut qp =
+      generate_index_query_params(&CommonParams::from(bp), bp).unwrap();
+    let q = qp.generate_query(11).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    let decoded = qp.decode_with_margin(&resp);
+    assert_eq!(decoded.row, qp.parse_resp_as_row(&resp));
+    assert!(decoded.min_margin() > floor / 2);
+    assert!(qp.decode_with_margin(&perturb(&resp)).min_margin() < floor / 2);
+
+    let (keys, _, shard) = kv_shard();
+    let bp = shard.get_base_params();
+    let mut qp = generate_kv_query_params(&CommonParams::from(bp), bp).unwrap();
+    let key = HashedKey::from_bytes(keys[3].as_bytes());
+    let q = qp.generate_query(&key).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    let decoded = qp.decode_with_margin(&resp, &key).unwrap();
+    assert_eq!(decoded.row, qp.parse_resp_as_row(&resp, &key).unwrap());
+    assert_eq!(decoded.margins.len(), decoded.row.len());
+    assert!(decoded.min_margin() > floor / 2);
+    let perturbed = qp.decode_with_margin(&perturb(&resp), &key).unwrap();
+    assert!(perturbed.min_margin() < floor / 2);
+
+    // rows without slots have no margin to violate
+    let empty = DecodedRow {
+      row: Vec::new(),
+      margins: Vec::new(),
+    };
+    assert_eq!(empty.min_margin(), u32::MAX);
+  }
+
+  #[test]
+  fn kv_shards_report_their_capacity() {
+    let (keys, _, shard) = kv_shard();
+    assert_eq!(shard.num_keys(), keys.len());
+    assert_eq!(shard.num_keys(), shard.get_db().num_keys());
+    assert_eq!(shard.matrix_height(), shard.get_db().matrix_height());
+    assert_eq!(shard.load_factor(), shard.get_db().load_factor());
+
+    // queries span the derived matrix height rather than the key count
+    let bp = shard.get_base_params();
+    let mut qp = generate_kv_query_params(&CommonParams::from(bp), bp).unwrap();
+    let key = HashedKey::from_bytes(keys[0].as_bytes());
+    let q = qp.generate_query(&key).unwrap();
+    assert_eq!(q.as_slice().len(), shard.matrix_height());
+    assert!(shard.matrix_height() > shard.num_keys());
+  }
+
+  #[test]
+  fn columns_hold_their_allocated_bits() {
+    let elem_size = 64;
+    let column_bits = [10, 1, 3, 10, 10, 10, 10, 9, 4];
+    let eles = generate_db_eles(64, elem_size / 8);
+    let shard = Shard::from_encoded_strings_with_column_bits(
+      &eles,
+      512,
+      64,
+      elem_size,
+      &column_bits,
+      EncodingConfig::Standard,
+    )
+    .unwrap();
+    let db = shard.get_db();
+    assert_eq!(db.get_row_width_self(), column_bits.len());
+    assert_eq!(db.get_plaintext_bits(), 10);
+    for (i, &bits) in column_bits.iter().enumerate() {
+      assert!(db.get_row_slice(i).iter().all(|&x| x < 1 << bits));
+    }
+    assert_eq!(db.get_db_entry(5), eles[5]);
+
+    let params: IndexParams =
+      serde_json::from_slice(&shard.params_json().unwrap()).unwrap();
+    assert_eq!(params.get_column_bits(), Some(&column_bits[..]));
+    params.verify_against(db).unwrap();
+    let cp = CommonParams::from(&params);
+    for i in [0, 17, 63] {
+      let mut qp = generate_index_query_params(&cp, &params).unwrap();
+      let q = qp.generate_query(i).unwrap();
+      let resp: Response =
+        bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+      assert_eq!(qp.parse_resp_as_base64(&resp), eles[i]);
+    }
+
+    let uniform = Shard::from_base64_strings(&eles, 512, 64, 64, 10).unwrap();
+    assert!(uniform.get_base_params().verify_against(db).is_err());
+    for column_bits in [&[32, 32][..], &[30, 30], &[30, 30, 4, 1], &[0, 64]] {
+      assert!(IndexDatabase::new_with_column_bits(
+        &eles,
+        64,
+        elem_size,
+        column_bits,
+        EncodingConfig::Standard,
+      )
+      .is_err());
+    }
+
+    // tampered column bits are rejected by clients
+    let json: serde_json::Value =
+      serde_json::from_slice(&shard.params_json().unwrap()).unwrap();
+    for column_bits in [
+      serde_json::json!([40, 1, 3, 10, 10, 10, 10, 9, 4]),
+      serde_json::json!([10, 1, 3, 10, 10, 10, 10, 9]),
+      serde_json::json!([10, 1, 3, 10, 10, 10, 10, 9, 4, 10]),
+      serde_json::json!([usize::MAX, 1, 3, 10, 10, 10, 10, 9, 4]),
+    ] {
+      let mut tampered = json.clone();
+      tampered["column_bits"] = column_bits;
+      assert!(serde_json::from_value::<IndexParams>(tampered).is_err());
+    }
+    let qp = generate_index_query_params(&cp, &params).unwrap();
+    let mut tampered = serde_json::to_value(&qp).unwrap();
+    serde_json::from_value::<QueryParams<IndexDatabase, EmptyAuxParams>>(
+      tampered.clone(),
+    )
+    .unwrap();
+    tampered["column_bits"] = serde_json::json!([64, 64]);
+    assert!(
+      serde_json::from_value::<QueryParams<IndexDatabase, EmptyAuxParams>>(
+        tampered
+      )
+      .is_err()
+    );
+  }
+
+  #[test]
+  fn shard_commitments_prove_record_inclusion() {
+    let elem_size = 2u32.pow(8) as usize;
+    let eles = generate_db_eles(13, elem_size / 8);
+    let shard =
+      Shard::from_base64_strings(&eles, 512, 13, elem_size, 10).unwrap();
+    let records: Vec<Vec<u8>> =
+      eles.iter().map(|e| base64::decode(e).unwrap()).collect();
+    let commitment = shard.commitment().unwrap();
+    assert_eq!(commitment, commit_records(&records));
+    for (i, record) in records.iter().enumerate() {
+      let proof = shard.prove_inclusion(i).unwrap();
+      verify_inclusion(&commitment, record, &proof).unwrap();
+      assert!(
+        verify_inclusion(&commitment, &records[(i + 1) % 13], &proof)
+          .unwrap_err()
+          .is::<ErrorInclusionInvalid>()
+      );
+    }
+    assert!(shard.prove_inclusion(13).is_err());
+
+    let mut changed = records.clone();
+    changed[4][0] ^= 1;
+    assert_ne!(commit_records(&changed), commitment);
+
+    // the tree is built once, and shared with clones
+    let clone = shard.clone();
+    assert!(std::ptr::eq(
+      shard.record_tree().unwrap(),
+      clone.record_tree().unwrap()
+    ));
+  }
+
+  #[test]
+  fn shard_commitments_cover_records_as_clients_decode_them() {
+    let elem_size = 64;
+    let distinct = generate_db_eles(8, elem_size / 8);
+    let eles: Vec<String> =
+      (0..64).map(|i| distinct[(i * 5) % 8].clone()).collect();
+    let records: Vec<Vec<u8>> =
+      eles.iter().map(|e| base64::decode(e).unwrap()).collect();
+
+    // records of a deduplicated DB are committed to, rather than its rows
+    let shard = Shard::from_encoded_strings_deduplicated(
+      &eles,
+      512,
+      elem_size,
+      10,
+      EncodingConfig::Standard,
+    )
+    .unwrap();
+    let commitment = shard.commitment().unwrap();
+    assert_eq!(commitment, commit_records(&records));
+    let proof = shard.prove_inclusion(42).unwrap();
+    verify_inclusion(&commitment, &records[42], &proof).unwrap();
+
+    // so are records with an expiry prefix, whether or not they expired
+    let expiries: Vec<Option<u64>> = (0..64)
+      .map(|i| match i % 3 {
+        0 => None,
+        1 => Some(TOMBSTONE),
+        _ => Some(1),
+      })
+      .collect();
+    let shard = ShardBuilder::new(512, elem_size)
+      .build_with_expiry(&eles, &expiries)
+      .unwrap();
+    assert_eq!(shard.commitment().unwrap(), commitment);
+    let proof = shard.prove_inclusion(4).unwrap();
+    verify_inclusion(&commitment, &records[4], &proof).unwrap();
+  }
+
+  #[test]
+  fn shard_clones_share_the_db_until_modified() {
+    let (_, shard) = index_shard();
+    let mut clone = shard.clone();
+    assert!(std::ptr::eq(shard.get_db(), clone.get_db()));
+    assert!(!std::ptr::eq(shard.get_db(), shard.deep_clone().get_db()));
+    clone.set_params_expires_at(Some(1));
+    assert!(std::ptr::eq(shard.get_db(), clone.get_db()));
+    assert_eq!(shard.get_base_params().get_expires_at(), None);
+    clone.set_dual_layout(true);
+    assert!(!std::ptr::eq(shard.get_db(), clone.get_db()));
+    assert!(shard.get_db().get_row_major().is_none());
+  }
+
+  #[test]
+  fn deduplicated_shards_share_rows() {
+    let elem_size = 64;
+    let distinct = generate_db_eles(8, elem_size / 8);
+    let eles: Vec<String> =
+      (0..64).map(|i| distinct[(i * 5) % 8].clone()).collect();
+    let shard = Shard::from_encoded_strings_deduplicated(
+      &eles,
+      512,
+      elem_size,
+      10,
+      EncodingConfig::Standard,
+    )
+    .unwrap();
+    let db = shard.get_db();
+    assert_eq!(db.get_matrix_height(), 8);
+    let row_map = db.get_row_map().unwrap();
+    assert_eq!(row_map.num_records(), 64);
+    assert!((0..8).all(|i| row_map.get_ref_count(i) == 8));
+    assert!(db.with_changes(&[], &[0]).is_err());
+
+    let params: IndexParams =
+      serde_json::from_slice(&shard.params_json().unwrap()).unwrap();
+    assert_eq!(params.get_total_records(), 8);
+    assert_eq!(params.num_records(), 64);
+    assert!(params.get_record_row(64).is_err());
+    let cp = CommonParams::from(&params);
+    for i in [0, 17, 63] {
+      let mut qp = generate_index_query_params(&cp, &params).unwrap();
+      let q = qp.generate_query(i).unwrap();
+      let resp: Response =
+        bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+      assert_eq!(qp.parse_resp_as_base64(&resp), eles[i]);
+    }
+    let mut qp = generate_index_query_params(&cp, &params).unwrap();
+    assert!(qp.generate_query(64).is_err());
+
+    // sessions and hint managers query records through the row map too
+    let mut session = ClientSession::new();
+    let (id, q) = session.query_index(&cp, &params, 42).unwrap();
+    let resp = shard.respond(&q).unwrap();
+    let record = session.complete(id, &resp).unwrap();
+    assert_eq!(base64::encode(record), eles[42]);
+    let mut manager = HintManager::new(move || Ok(params.clone())).unwrap();
+    let mut qp = manager.generate_query_params().unwrap();
+    let q = qp.generate_query(5).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), eles[5]);
+
+    // the params of a deduplicated DB do not verify against another DB
+    let plain =
+      Shard::from_base64_strings(&distinct, 512, 8, elem_size, 10).unwrap();
+    let bp = shard.get_base_params();
+    assert!(bp.verify_against(plain.get_db()).is_err());
+    let err = db.with_changes(&[], &[0]).unwrap_err();
+    assert!(err.downcast_ref::<ErrorDeduplicatedDb>().is_some());
+  }
+
+  #[test]
+  fn kv_shards_accept_hashed_keys() {
+    let elem_size = 2u32.pow(8) as usize;
+    let keys: Vec<String> = (0..64).map(|i| format!("key-{}", i)).collect();
+    let values: Vec<Vec<u8>> = generate_db_eles(64, elem_size / 8)
+      .iter()
+      .map(|v| base64::decode(v).unwrap())
+      .collect();
+    let hashed: Vec<[u64; 4]> = keys
+      .iter()
+      .map(|k| *HashedKey::from_bytes(k.as_bytes()).as_words())
+      .collect();
+    let shard =
+      KVShard::from_hashed_keys(&hashed, &values, 512, elem_size, 10).unwrap();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+    let q = qp
+      .generate_query_for_key(&KeyBytes::from(keys[9].as_str()))
+      .unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    let key = HashedKey::from_bytes(keys[9].as_bytes());
+    assert_eq!(qp.parse_resp_as_record(&resp, &key).unwrap(), values[9]);
+
+    assert!(KVShard::from_hashed_keys(
+      &hashed[1..],
+      &values,
+      512,
+      elem_size,
+      10
+    )
+    .is_err());
+  }
+
+  #[test]
+  fn kv_value_changes_update_few_filter_slots() {
+    let elem_size = 2u32.pow(8) as usize;
+    let plaintext_bits = 10;
+    let row_width = KVDatabase::get_row_width(elem_size, plaintext_bits);
+    let random_value = || -> Vec<u32> {
+      (0..row_width)
+        .map(|_| OsRng.next_u32() % (1 << plaintext_bits))
+        .collect()
+    };
+    let mut kvs: Vec<KeyValue> = (0..512)
+      .map(|i| KeyValue {
+        key: HashedKey::from_bytes(format!("key-{}", i).as_bytes()),
+        value: random_value(),
+      })
+      .collect();
+    let shard = KVShard::new(&kvs, 512, elem_size, plaintext_bits).unwrap();
+    let changed = [3, 100, 400];
+    for &i in &changed {
+      kvs[i].value = random_value();
+    }
+    let rebuilt = shard.rebuild_with_value_changes(&kvs, &changed).unwrap();
+    let (old_db, new_db) = (shard.get_db(), rebuilt.get_db());
+    assert_eq!(new_db.get_filter_params(), old_db.get_filter_params());
+    let changed_rows = (0..new_db.get_matrix_height())
+      .filter(|&r| {
+        old_db
+          .entries
+          .iter()
+          .zip(&new_db.entries)
+          .any(|(o, n)| o[r] != n[r])
+      })
+      .count();
+    assert!(changed_rows > 0 && changed_rows < new_db.get_matrix_height());
+
+    // every value decodes from the updated filters
+    let fp = new_db.get_filter_params();
+    for kv in &kvs {
+      let evals = fp.get_hash_evals(&kv.key);
+      for (i, col) in new_db.entries.iter().enumerate() {
+        let masked =
+          evals.iter().fold(0u32, |acc, r| acc.wrapping_add(col[*r]));
+        let unmasked = fp.unmask_value(masked, &kv.key, i as u64);
+        assert_eq!(unmasked % (1 << plaintext_bits), kv.value[i]);
+      }
+    }
+
+    // clients update their params using a delta
+    let old_bp = shard.get_base_params();
+    let delta = ParamsDelta::between(old_bp, rebuilt.get_base_params(), 1)
+      .unwrap()
+      .unwrap();
+    let bp = delta.apply(old_bp).unwrap();
+    assert_eq!(
+      params_digest(&bp).unwrap(),
+      rebuilt.params_digest().unwrap()
+    );
+    let cp = CommonParams::from(&bp);
+    for &i in &changed {
+      let mut qp = generate_kv_query_params(&cp, &bp).unwrap();
+      let q = qp.generate_query(&kvs[i].key).unwrap();
+      let resp: Response =
+        bincode::deserialize(&rebuilt.respond(&q).unwrap()).unwrap();
+      assert_eq!(
+        qp.parse_resp_as_row(&resp, &kvs[i].key).unwrap(),
+        kvs[i].value
+      );
+    }
+
+    // removing a key requires a full rebuild
+    let rebuilt = rebuilt.rebuild_with_value_changes(&kvs[1..], &[]).unwrap();
+    assert_eq!(rebuilt.num_keys(), kvs.len() - 1);
+    assert!(ParamsDelta::between(&bp, rebuilt.get_base_params(), 2)
+      .unwrap()
+      .is_none());
+    assert!(shard.rebuild_with_value_changes(&kvs, &[512]).is_err());
+
+    // so does replacing a key, which keeps the filter config
+    let config = FilterConfig {
+      max_iterations: 64,
+      size_factor_override: Some(1.5),
+    };
+    let db = KVDatabase::new_with_config(
+      &kvs,
+      elem_size,
+      plaintext_bits,
+      generate_seed(),
+      &config,
+    )
+    .unwrap();
+    let shard = KVShard::from_db(db, 512);
+    let mut swapped = kvs.clone();
+    swapped[7].key = HashedKey::from_bytes(b"replacement");
+    let rebuilt = shard.rebuild_with_value_changes(&swapped, &[]).unwrap();
+    let (old_db, new_db) = (shard.get_db(), rebuilt.get_db());
+    assert_ne!(new_db.get_filter_params(), old_db.get_filter_params());
+    assert_eq!(new_db.get_filter_config(), config);
+    let fp = new_db.get_filter_params();
+    let evals = fp.get_hash_evals(&swapped[7].key);
+    for (i, col) in new_db.entries.iter().enumerate() {
+      let masked = evals.iter().fold(0u32, |acc, r| acc.wrapping_add(col[*r]));
+      let unmasked = fp.unmask_value(masked, &swapped[7].key, i as u64);
+      assert_eq!(unmasked % (1 << plaintext_bits), swapped[7].value[i]);
+    }
+
+    // DBs serialized without a digest of their keys are rebuilt
+    let keys: Vec<HashedKey> = kvs.iter().map(|kv| kv.key).collect();
+    assert!(old_db.with_value_changes(&keys, &[]).unwrap().is_some());
+    let mut json = serde_json::to_value(old_db).unwrap();
+    json.as_object_mut().unwrap().remove("key_digest");
+    let legacy: KVDatabase = serde_json::from_value(json).unwrap();
+    assert!(legacy.with_value_changes(&keys, &[]).unwrap().is_none());
+  }
+
+  #[test]
+  fn kv_full_rebuilds_keep_the_filter_epoch() {
+    let (keys, values, _) = kv_shard();
+    let shard = KVShard::from_encoded_strings_for_epoch(
+      &keys,
+      &values,
+      LWE_DIM,
+      ELEM_SIZE,
+      PLAINTEXT_BITS,
+      EncodingConfig::Standard,
+      generate_seed(),
+      3,
+    )
+    .unwrap();
+    let mut kvs: Vec<KeyValue> = keys
+      .iter()
+      .zip(&values)
+      .map(|(k, v)| {
+        KeyValue::from_base64_strings(k, v, ELEM_SIZE, PLAINTEXT_BITS).unwrap()
+      })
+      .collect();
+    // adding a key forces a rebuild from scratch
+    let added = generate_db_eles(1, ELEM_SIZE / 8).remove(0);
+    kvs.push(
+      KeyValue::from_base64_strings("added", &added, ELEM_SIZE, PLAINTEXT_BITS)
+        .unwrap(),
+    );
+    let rebuilt = shard.rebuild_with_value_changes(&kvs, &[]).unwrap();
+    assert_ne!(
+      rebuilt.get_db().get_filter_params().seed,
+      shard.get_db().get_filter_params().seed
+    );
+    assert_eq!(rebuilt.filter_epoch(), Some(3));
+    assert_eq!(rebuilt.get_base_params().get_chunks(), None);
+    let bp = rebuilt.get_base_params();
+    let mut qp = generate_kv_query_params(&CommonParams::from(bp), bp).unwrap();
+    let q = qp.generate_query_for_key(&KeyBytes::from("added")).unwrap();
+    let resp: Response =
+      bincode::deserialize(&rebuilt.respond(&q).unwrap()).unwrap();
+    let key = HashedKey::from_bytes(b"added");
+    assert_eq!(qp.parse_resp_as_base64(&resp, &key).unwrap(), added);
+
+    // the chunk count of chunked shards is not derived from KV pairs
+    let long = generate_db_eles(keys.len(), ELEM_SIZE / 4);
+    let chunked = KVShard::from_base64_strings(
+      &keys,
+      &long,
+      LWE_DIM,
+      ELEM_SIZE,
+      PLAINTEXT_BITS,
+    )
+    .unwrap();
+    assert!(chunked.get_base_params().get_chunks().is_some());
+    assert!(chunked.rebuild_with_value_changes(&kvs, &[]).is_err());
+  }
+
+  #[test]
+  fn kv_shards_are_queried_by_index() {
+    let (keys, _, shard) = kv_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let db = shard.get_db();
+    let width = KVDatabase::get_row_width(ELEM_SIZE, PLAINTEXT_BITS);
+    let query_row = |row: usize| {
+      let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+      let q = qp.generate_query_by_index(row).unwrap();
+      let resp: Response =
+        bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+      qp.parse_resp_by_index(&resp)
+    };
+
+    // the rows selected by a key sum to the masked value
+    let key = HashedKey::from_bytes(keys[0].as_bytes());
+    let fp = db.get_filter_params().clone();
+    let mut masked = vec![0u32; width];
+    for row in fp.get_hash_evals(&key) {
+      let decoded = query_row(row);
+      let expected: Vec<u32> =
+        (0..width).map(|c| db.get_row_slice(c)[row]).collect();
+      assert_eq!(decoded, expected);
+      for (m, d) in masked.iter_mut().zip(decoded) {
+        *m = m.wrapping_add(d);
+      }
+    }
+    let plaintext_size = get_plaintext_size(PLAINTEXT_BITS);
+    let row: Vec<u32> = masked
+      .into_iter()
+      .enumerate()
+      .map(|(i, m)| fp.unmask_value(m, &key, i as u64) % plaintext_size)
+      .collect();
+    let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+    let q = qp.generate_query_for_key(&KeyBytes::from(keys[0].as_str()));
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q.unwrap()).unwrap()).unwrap();
+    assert_eq!(row, qp.parse_resp_as_row(&resp, &key).unwrap());
+
+    let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+    assert!(qp.generate_query_by_index(db.get_matrix_height()).is_err());
+  }
+
+  #[test]
+  fn responses_decode_with_detached_keys() {
+    let (eles, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_index_query_params(&cp, bp).unwrap();
+    let (q, dk) = qp.generate_detached_query(12).unwrap();
+    drop(qp);
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    // the key is decoded on another device
+    let dk: DecodeKey =
+      serde_json::from_str(&serde_json::to_string(&dk).unwrap()).unwrap();
+    assert!(dk.clone().into_kv_params().is_err());
+    let mut decoder = dk.into_index_params();
+    assert_eq!(decoder.parse_resp_as_base64(&resp), eles[12]);
+    assert!(decoder.generate_query(0).is_err());
+
+    let (keys, values, kv_shard) = kv_shard();
+    let bp = kv_shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+    let key = HashedKey::from_bytes(keys[6].as_bytes());
+    let (q, dk) = qp.generate_detached_query(&key).unwrap();
+    let resp: Response =
+      bincode::deserialize(&kv_shard.respond(&q).unwrap()).unwrap();
+    let decoder = dk.into_kv_params().unwrap();
+    assert_eq!(
+      decoder.parse_resp_as_base64(&resp, &key).unwrap(),
+      values[6]
+    );
+  }
+
+  #[test]
+  fn query_params_are_consumed_by_into_query() {
+    let (eles, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let qp = IndexQueryParams::new(&cp, bp).unwrap();
+    let (q, dk) = qp.into_query(40).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(dk.into_index_params().parse_resp_as_base64(&resp), eles[40]);
+
+    let mut qp = generate_index_query_params(&cp, bp).unwrap();
+    assert!(!qp.is_used());
+    qp.generate_query(1).unwrap();
+    assert!(qp.is_used());
+    assert!(qp.into_query(2).is_err());
+  }
+
+  #[test]
+  fn queries_generated_with_receipts() {
+    let (eles, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_index_query_params(&cp, bp).unwrap();
+    let (q, receipt) = qp.generate_query_with_receipt(17, bp).unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    assert!(now.as_secs() - receipt.timestamp < 60);
+    assert_eq!(receipt.params_digest, shard.params_digest().unwrap());
+    // the receipt survives a crash, and identifies the response
+    let json = serde_json::to_string(&receipt).unwrap();
+    let receipt: QueryReceipt = serde_json::from_str(&json).unwrap();
+    assert_eq!(receipt.target, QueryTarget::Row(17));
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), eles[17]);
+    assert!(qp.generate_query_with_receipt(17, bp).is_err());
+
+    let (keys, _, kv_shard) = kv_shard();
+    let bp = kv_shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+    let key = HashedKey::from_bytes(keys[3].as_bytes());
+    let (_, receipt) = qp.generate_query_with_receipt(&key, bp).unwrap();
+    assert_eq!(receipt.target, QueryTarget::Key(key));
+    assert_eq!(receipt.params_digest, kv_shard.params_digest().unwrap());
+  }
+
+  #[test]
+  fn query_params_wrappers_round_trip() {
+    let (eles, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = IndexQueryParams::new(&cp, bp).unwrap();
+    let q = qp.generate_query(11).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), eles[11]);
+    let inner = qp.clone().into_inner();
+    assert_eq!(
+      serde_json::to_string(&qp).unwrap(),
+      serde_json::to_string(&inner).unwrap()
+    );
+
+    let (keys, values, kv_shard) = kv_shard();
+    let bp = kv_shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = KvQueryParams::new(&cp, bp).unwrap();
+    let q = qp
+      .generate_query_for_key(&KeyBytes::from(keys[4].as_str()))
+      .unwrap();
+    let resp: Response =
+      bincode::deserialize(&kv_shard.respond(&q).unwrap()).unwrap();
+    let key = HashedKey::from_bytes(keys[4].as_bytes());
+    assert_eq!(qp.parse_resp_as_base64(&resp, &key).unwrap(), values[4]);
+  }
+
+  #[test]
+  fn queries_generated_into_buffers() {
+    let (eles, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_index_query_params(&cp, bp).unwrap();
+    let mut buf = vec![0u32; M - 1];
+    assert!(qp.generate_query_into(5, &mut buf).is_err());
+    assert!(!qp.used);
+    buf.resize(M, 0);
+    qp.generate_query_into(5, &mut buf).unwrap();
+    let err = qp.generate_query_into(5, &mut buf).unwrap_err();
+    assert!(err.is::<ErrorQueryParamsReused>());
+    // the buffer is sent exactly as a `Query`
+    let d_resp = shard
+      .respond(
+        &bincode::deserialize(&bincode::serialize(&buf).unwrap()).unwrap(),
+      )
+      .unwrap();
+    let resp: Response = bincode::deserialize(&d_resp).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), eles[5]);
+
+    let (keys, values, kv_shard) = kv_shard();
+    let bp = kv_shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+    let key = HashedKey::from_bytes(keys[2].as_bytes());
+    let mut buf = vec![0u32; kv_shard.get_db().get_matrix_height()];
+    qp.generate_query_into(&key, &mut buf).unwrap();
+    let q: Query =
+      bincode::deserialize(&bincode::serialize(&buf).unwrap()).unwrap();
+    let resp: Response =
+      bincode::deserialize(&kv_shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp, &key).unwrap(), values[2]);
+  }
+
+  #[test]
+  fn chunked_responses_decode_incrementally() {
+    let (_, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_index_query_params(&cp, bp).unwrap();
+    let q = qp.generate_query(9).unwrap();
+    assert!(shard.respond_frames(&q, 0).is_err());
+    let mut row = Vec::new();
+    let mut frames = 0;
+    for d_frame in shard.respond_frames(&q, 7).unwrap() {
+      let frame: ResponseFrame =
+        bincode::deserialize(&d_frame.unwrap()).unwrap();
+      assert_eq!(frame.start, row.len());
+      row.extend(qp.parse_frame_as_row(&frame).unwrap());
+      frames += 1;
+    }
+    assert_eq!(frames, shard.get_db().get_row_width_self().div_ceil(7));
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(row, qp.parse_resp_as_row(&resp));
+    let out_of_range = ResponseFrame {
+      start: row.len(),
+      values: vec![0],
+    };
+    assert!(qp.parse_frame_as_row(&out_of_range).is_err());
+    let overflowing = ResponseFrame {
+      start: usize::MAX,
+      values: vec![0],
+    };
+    assert!(overflowing.columns().is_err());
+    assert!(qp.parse_frame_as_row(&overflowing).is_err());
+    let d_overflowing = bincode::serialize(&overflowing).unwrap();
+    assert!(bincode::deserialize::<ResponseFrame>(&d_overflowing).is_err());
+
+    let (keys, _, kv_shard) = kv_shard();
+    let bp = kv_shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+    let q = qp
+      .generate_query_for_key(&KeyBytes::from(keys[3].as_str()))
+      .unwrap();
+    let key = HashedKey::from_bytes(keys[3].as_bytes());
+    let mut row = Vec::new();
+    for d_frame in kv_shard.respond_frames(&q, 5).unwrap() {
+      let frame: ResponseFrame =
+        bincode::deserialize(&d_frame.unwrap()).unwrap();
+      row.extend(qp.parse_frame_as_row(&frame, &key).unwrap());
+    }
+    let resp: Response =
+      bincode::deserialize(&kv_shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(row, qp.parse_resp_as_row(&resp, &key).unwrap());
+  }
+
+  #[test]
+  fn kv_query_params_check_filter_plaintext_modulus() {
+    let (_, _, shard) = kv_shard();
+    let bp = shard.get_base_params();
+    assert_eq!(bp.get_filter_params().ptxt_mod, Some(1024));
+    let cp = CommonParams::from(bp);
+
+    let mut json = serde_json::to_value(bp).unwrap();
+    json["plaintext_bits"] = 9.into();
+    let tampered: KVParams = serde_json::from_value(json.clone()).unwrap();
+    let err = generate_kv_query_params(&cp, &tampered).unwrap_err();
+    assert!(err.is::<ErrorParamsMismatch>());
+    // params serialized before the modulus was recorded are accepted
+    json["plaintext_bits"] = 10.into();
+    json["filter_params"]
+      .as_object_mut()
+      .unwrap()
+      .remove("ptxt_mod");
+    let legacy: KVParams = serde_json::from_value(json).unwrap();
+    assert_eq!(legacy.get_filter_params().ptxt_mod, None);
+    assert!(generate_kv_query_params(&cp, &legacy).is_ok());
+
+    let fp = bp.get_filter_params();
+    assert!(fp.check_plaintext_bits(10).is_ok());
+    for plaintext_bits in [64, 200, usize::MAX] {
+      assert!(fp.check_plaintext_bits(plaintext_bits).is_err());
+    }
+  }
+
+  #[test]
+  fn client_sparse_query_to_server() {
+    let (db_eles, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_sparse_index_query_params(&cp, bp).unwrap();
+    let sq = qp.generate_sparse_query(5).unwrap();
+    let d_resp = shard.respond_sparse(&cp, &sq).unwrap();
+    let resp: Response = bincode::deserialize(&d_resp).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), db_eles[5]);
+
+    // standard params cannot produce sparse queries
+    let mut qp = generate_index_query_params(&cp, bp).unwrap();
+    assert!(qp.generate_sparse_query(5).is_err());
+  }
 
-/// Returns `QueryParams` for an KV-based DB (`KVDatabase`)
-pub fn generate_kv_query_params(
-  cp: &CommonParams,
-  params: &KVParams,
-) -> ResultBoxedError<QueryParams<KVDatabase, FilterParams>> {
-  QueryParams::<KVDatabase, FilterParams>::new(cp, params)
-}
+  #[test]
+  fn client_query_with_alternative_encodings() {
+    for encoding in [EncodingConfig::UrlSafeNoPad, EncodingConfig::Hex] {
+      let db_eles: Vec<String> = generate_db_bytes(M, ELEM_SIZE / 8)
+        .iter()
+        .map(|e| encoding.encode(e))
+        .collect();
+      let shard = Shard::from_encoded_strings(
+        &db_eles,
+        LWE_DIM,
+        M,
+        ELEM_SIZE,
+        PLAINTEXT_BITS,
+        encoding,
+      )
+      .unwrap();
+      let bp = shard.get_base_params();
+      let cp = CommonParams::from(bp);
+      let mut qp = generate_index_query_params(&cp, bp).unwrap();
+      let q = qp.generate_query(3).unwrap();
+      let d_resp = shard.respond(&q).unwrap();
+      let resp: Response = bincode::deserialize(&d_resp).unwrap();
+      assert_eq!(qp.parse_resp_as_encoded(&resp, encoding), db_eles[3]);
+    }
+  }
 
-/// The `Query` struct holds the necessary information encoded in
-/// a client PIR query to the server DB for a particular `row_index`. It
-/// provides methods for parsing server responses.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Query(Vec<u32>);
-impl Query {
-  pub fn as_slice(&self) -> &[u32] {
-    &self.0
+  #[test]
+  fn malformed_queries_are_rejected() {
+    let shards: Vec<AnyShard> =
+      vec![index_shard().1.into(), kv_shard().2.into()];
+    for shard in &shards {
+      let height = shard.height();
+      for len in [0, 1, height - 1, height + 1, 4 * height] {
+        let q = Query::random(len);
+        assert!(shard.check_query(&q).is_err());
+        assert!(shard.respond(&q).is_err());
+        assert!(shard.respond_par(&q).is_err());
+        let stream = match shard {
+          AnyShard::Index(s) => s.respond_stream(&q, std::io::sink()),
+          AnyShard::KV(s) => s.respond_stream(&q, std::io::sink()),
+        };
+        assert!(stream.is_err());
+      }
+      shard.check_query(&Query::random(height)).unwrap();
+    }
+    assert!(bincode::deserialize::<Query>(&[0xff; 12]).is_err());
   }
-}
 
-/// The `Response` object wraps a response from a single shard
-#[derive(Clone, Serialize, Deserialize)]
-pub struct Response(Vec<u32>);
-impl Response {
-  pub fn as_slice(&self) -> &[u32] {
-    &self.0
+  #[test]
+  fn respond_stream_matches_respond() {
+    let (db_eles, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_index_query_params(&cp, bp).unwrap();
+    let q = qp.generate_query(2).unwrap();
+    let mut streamed = Vec::new();
+    shard.respond_stream(&q, &mut streamed).unwrap();
+    assert_eq!(streamed, shard.respond(&q).unwrap());
+    let resp: Response = bincode::deserialize(&streamed).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), db_eles[2]);
   }
-}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct EmptyAuxParams {}
+  #[test]
+  fn client_query_rounding_modes() {
+    let (_, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_index_query_params(&cp, bp).unwrap();
+    assert_eq!(qp.get_rounding_mode(), RoundingMode::Nearest);
+    let q = qp.generate_query(3).unwrap();
+    let d_resp = shard.respond(&q).unwrap();
+    let resp: Response = bincode::deserialize(&d_resp).unwrap();
+    let expected = qp.parse_resp_as_row(&resp);
+    let plaintext_size = get_plaintext_size(PLAINTEXT_BITS);
 
-#[cfg(test)]
-mod tests {
-  use super::*;
-  use crate::db::FilterParams;
-  use rand_core::{OsRng, RngCore};
+    // floor rounding is at most one away from the nearest plaintext
+    qp.set_rounding_mode(RoundingMode::Floor);
+    for (floored, nearest) in qp.parse_resp_as_row(&resp).iter().zip(&expected)
+    {
+      assert!(floored == nearest || (floored + 1) % plaintext_size == *nearest);
+    }
+
+    // margins are reported for every slot, and are far from the boundary
+    qp.set_rounding_mode(RoundingMode::Nearest);
+    let decoded = qp.decode_with_margin(&resp);
+    assert_eq!(decoded.row, expected);
+    assert_eq!(decoded.margins.len(), expected.len());
+    let modulus = Modulus::default();
+    assert!(decoded.min_margin() > modulus.rounding_floor(PLAINTEXT_BITS) / 2);
+
+    // raw residues are close to the scaled plaintext
+    let rounding_factor = modulus.rounding_factor(PLAINTEXT_BITS);
+    for (raw, nearest) in qp.parse_resp_raw(&resp).iter().zip(&expected) {
+      let noise = raw.wrapping_sub(nearest.wrapping_mul(rounding_factor));
+      assert!(noise.min(noise.wrapping_neg()) < rounding_factor / 2);
+    }
+  }
 
   #[test]
-  fn client_query_to_server_10_times() {
-    let m = 2u32.pow(12) as usize;
+  fn client_query_to_server_attempt_params_reuse() {
+    let m = 2u32.pow(6) as usize;
     let elem_size = 2u32.pow(8) as usize;
-    let plaintext_bits = 11usize;
+    let plaintext_bits = 10usize;
     let lwe_dim = 512;
     let db_eles = generate_db_eles(m, (elem_size + 7) / 8);
     let shard = Shard::from_base64_strings(
@@ -492,128 +4492,667 @@ mod tests {
     .unwrap();
     let bp = shard.get_base_params();
     let cp = CommonParams::from(bp);
+    let mut qp =
+      QueryParams::<IndexDatabase, EmptyAuxParams>::new(&cp, bp).unwrap();
+    // should be successful in generating a query
+    let res_unused = qp.generate_query(0);
+    assert!(res_unused.is_ok());
+
+    // should be "used"
+    assert!(qp.used);
+
+    // should be successful in generating a query
+    let res = qp.generate_query(0);
+    assert!(res.is_err());
+  }
+
+  #[test]
+  fn client_query_to_server_from_hint() {
+    let (db_eles, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let hint = ClientHint::new(bp, 3).unwrap();
+
+    // the hint survives a serialization round trip
+    let ser = serde_json::to_string(&hint).unwrap();
+    let mut hint: ClientHint = serde_json::from_str(&ser).unwrap();
+    assert_eq!(hint.len(), 3);
+
     #[allow(clippy::needless_range_loop)]
-    for i in 0..10 {
+    for i in 0..3 {
       let mut qp =
-        QueryParams::<IndexDatabase, EmptyAuxParams>::new(&cp, bp).unwrap();
+        generate_index_query_params_from_hint(&mut hint, bp).unwrap();
       let q = qp.generate_query(i).unwrap();
       let d_resp = shard.respond(&q).unwrap();
       let resp: Response = bincode::deserialize(&d_resp).unwrap();
-      let output = qp.parse_resp_as_base64(&resp);
-      assert_eq!(output, db_eles[i]);
+      assert_eq!(qp.parse_resp_as_base64(&resp), db_eles[i]);
     }
+    assert!(hint.is_empty());
+    assert!(generate_index_query_params_from_hint(&mut hint, bp).is_err());
   }
 
+  #[cfg(feature = "object-store")]
   #[test]
-  fn client_kv_query_to_server_10_times() {
-    let m = 2u32.pow(12) as usize;
-    let elem_size = 2u32.pow(8) as usize;
-    let plaintext_bits = 11usize;
-    let lwe_dim = 512;
-    let db_eles = generate_kv_db_elems(m, (elem_size + 7) / 8);
-    let res: ResultBoxedError<Vec<KeyValue>> = db_eles
-      .iter()
-      .map(|e| {
-        KeyValue::from_base64_strings(&e.0, &e.1, elem_size, plaintext_bits)
+  fn shards_are_fetched_from_object_stores() {
+    let (_, shard) = index_shard();
+    let db_file = TempPath::new("object-store-db.json");
+    let params_file = TempPath::new("object-store-params.json");
+    let (db_path, params_path) = (db_file.as_str(), params_file.as_str());
+    shard.write_to_file(db_path, params_path).unwrap();
+
+    let db_url = format!("file://{}", db_path);
+    let params_url = format!("file://{}", params_path);
+    let fetched = Shard::from_object_store(&db_url, &params_url, &[]).unwrap();
+    assert_eq!(
+      fetched.get_base_params().get_rhs(),
+      shard.get_base_params().get_rhs()
+    );
+    let err =
+      Shard::from_object_store(&params_url, &params_url, &[]).unwrap_err();
+    assert!(err.to_string().contains("expected a IndexDatabase"));
+    let missing = format!("file://{}", TempPath::new("missing").as_str());
+    assert!(Shard::from_object_store(&missing, &params_url, &[]).is_err());
+  }
+
+  #[test]
+  fn inner_products_match_responses() {
+    let (_, mut shard) = index_shard();
+    let bp = shard.get_base_params().clone();
+    let cp = CommonParams::from(&bp);
+    let mut qp = generate_index_query_params(&cp, &bp).unwrap();
+    let q = qp.generate_query(7).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(shard.inner_products(q.as_slice()).unwrap(), resp.as_slice());
+    shard.set_respond_config(RespondConfig {
+      row_tile: Some(16),
+      column_block: 4,
+    });
+    assert_eq!(shard.inner_products(q.as_slice()).unwrap(), resp.as_slice());
+
+    // unit vectors select the entries of a record
+    let mut unit = vec![0u32; M];
+    unit[7] = 1;
+    let entries: Vec<u32> = (0..shard.width())
+      .map(|i| shard.get_db().get_row_slice(i)[7])
+      .collect();
+    assert_eq!(shard.inner_products(&unit).unwrap(), entries);
+    assert!(shard.inner_products(&unit[1..]).is_err());
+
+    let (_, _, kv_shard) = kv_shard();
+    let q = Query::random(kv_shard.height());
+    let resp: Response =
+      bincode::deserialize(&kv_shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(
+      kv_shard.inner_products(q.as_slice()).unwrap(),
+      resp.as_slice()
+    );
+    assert!(kv_shard.inner_products(&[]).is_err());
+  }
+
+  #[test]
+  fn shards_are_shared_across_threads() {
+    let (eles, shard) = index_shard();
+    let bp = shard.get_base_params().clone();
+    let cp = CommonParams::from(&bp);
+    let handles: Vec<_> = (0..4)
+      .map(|i| {
+        let shard = shard.clone();
+        let mut qp = generate_index_query_params(&cp, &bp).unwrap();
+        let q = qp.generate_query(i).unwrap();
+        std::thread::spawn(move || {
+          (i, qp, PirShard::respond(&shard, &q).unwrap())
+        })
       })
-      .collect::<Vec<ResultBoxedError<KeyValue>>>()
-      .into_iter()
       .collect();
-    let kvs = res.unwrap();
-    let shard =
-      KVShard::new(&kvs, lwe_dim, m, elem_size, plaintext_bits).unwrap();
+    for h in handles {
+      let (i, qp, resp) = h.join().unwrap();
+      let resp: Response = bincode::deserialize(&resp).unwrap();
+      assert_eq!(qp.parse_resp_as_base64(&resp), eles[i]);
+    }
+
+    // setting the expiry of a shared shard leaves the other owners as is
+    let mut expiring = shard.clone();
+    PirShard::set_params_expires_at(&mut expiring, Some(1));
+    assert_eq!(shard.get_base_params().get_expires_at(), None);
+    assert_eq!(expiring.get_base_params().get_expires_at(), Some(1));
+  }
+
+  #[test]
+  fn batches_survive_format_switches() {
+    let (_, shard) = index_shard();
     let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let queries: Vec<Query> = (0..3)
+      .map(|i| {
+        let mut qp = generate_index_query_params(&cp, bp).unwrap();
+        qp.generate_query(i).unwrap()
+      })
+      .collect();
+    let expected = shard.respond_batch(&queries).unwrap();
 
-    // Compute the real values for testing purposes
-    for kv in &kvs {
-      let v: Vec<Vec<usize>> = (0..shard.get_db().entries.len())
-        .map(|_| shard.get_db().get_filter_params().get_hash_evals(&kv.key))
+    let mut db = shard.get_db().clone();
+    db.set_dual_layout(true);
+    for _ in 0..2 {
+      db.switch_fmt();
+      let responses =
+        respond_batch_with(&db, &queries, RespondConfig::default()).unwrap();
+      assert_eq!(responses, expected);
+    }
+
+    // the row-major copy is built from the rows of a switched DB
+    let mut db = shard.get_db().clone();
+    db.switch_fmt();
+    db.set_dual_layout(true);
+    db.switch_fmt();
+    let responses =
+      respond_batch_with(&db, &queries, RespondConfig::default()).unwrap();
+    assert_eq!(responses, expected);
+  }
+
+  #[test]
+  fn snapshots_round_trip_shards() {
+    let (eles, mut shard) = index_shard();
+    let config = shard.tune().unwrap();
+    let snapshot = shard.serialize_snapshot().unwrap();
+    let loaded = Shard::deserialize_snapshot(&snapshot).unwrap();
+    assert_eq!(loaded.get_respond_config(), config);
+    assert_eq!(
+      loaded.params_digest().unwrap(),
+      shard.params_digest().unwrap()
+    );
+    for i in [0, 17, M - 1] {
+      assert_eq!(loaded.get_db().get_db_entry(i), eles[i]);
+    }
+
+    let file = TempPath::new("snapshot.bin");
+    let path = file.as_str();
+    shard.write_snapshot_file(path).unwrap();
+    let loaded = Shard::load_snapshot(path).unwrap();
+    assert_eq!(
+      loaded.get_base_params().get_rhs(),
+      shard.get_base_params().get_rhs()
+    );
+
+    for i in [0, 17, M - 1] {
+      assert_eq!(loaded.get_db().get_db_entry(i), eles[i]);
+    }
+    let bp = shard.get_base_params();
+    let mut qp = IndexQueryParams::new(&CommonParams::from(bp), bp).unwrap();
+    let q = qp.generate_query(17).unwrap();
+    let resp: Response =
+      bincode::deserialize(&loaded.respond(&q).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), eles[17]);
+
+    // truncated snapshots and other files are rejected
+    let is_format_error = |bytes: &[u8]| {
+      Shard::deserialize_snapshot(bytes)
+        .err()
+        .is_some_and(|e| e.downcast_ref::<ErrorSnapshotFormat>().is_some())
+    };
+    assert!(is_format_error(&snapshot[..snapshot.len() - 1]));
+    assert!(is_format_error(b"chalamet-snapshot"));
+    let mut other = snapshot.clone();
+    other[0] ^= 1;
+    assert!(is_format_error(&other));
+    let mut other = snapshot.clone();
+    other.push(0);
+    assert!(is_format_error(&other));
+    // JSON lengths beyond the end of the input are rejected without
+    // reading past it
+    let meta_len = SNAPSHOT_PREFIX_LEN - 8..SNAPSHOT_PREFIX_LEN;
+    for len in [snapshot.len() as u64, u64::MAX] {
+      let mut other = snapshot.clone();
+      other[meta_len.clone()].copy_from_slice(&len.to_le_bytes());
+      assert!(is_format_error(&other));
+    }
+  }
+
+  #[test]
+  fn load_persisted_shard_verifies_params() {
+    let (_, shard) = index_shard();
+    let (_, other) = index_shard();
+    let files = [
+      TempPath::new("verify-db.json"),
+      TempPath::new("verify-params.json"),
+      TempPath::new("verify-other-params.json"),
+    ];
+    let (db_path, params_path, other_params_path) =
+      (files[0].as_str(), files[1].as_str(), files[2].as_str());
+    shard.write_to_file(db_path, params_path).unwrap();
+    other
+      .get_base_params()
+      .write_to_file(other_params_path)
+      .unwrap();
+
+    let loaded = Shard::load(db_path, params_path).unwrap();
+    assert_eq!(
+      loaded.get_base_params().get_rhs(),
+      shard.get_base_params().get_rhs()
+    );
+    let err = Shard::load(db_path, other_params_path).unwrap_err();
+    assert!(err.downcast_ref::<ErrorParamsMismatch>().is_some());
+
+    // files of another kind, modified files and files without a header
+    // are rejected
+    let err = Shard::load(params_path, params_path).unwrap_err();
+    assert!(err.to_string().contains("expected a IndexDatabase"));
+    let written = fs::read_to_string(db_path).unwrap();
+    let at = written.rfind("\"elem_size\":256").unwrap();
+    let mut modified = written.clone();
+    modified.replace_range(at..at + 15, "\"elem_size\":128");
+    fs::write(db_path, modified).unwrap();
+    let err = Shard::load(db_path, params_path).unwrap_err();
+    assert!(err.downcast_ref::<ErrorLoadFile>().is_some());
+    assert!(err.to_string().contains("checksum mismatch"));
+    fs::write(db_path, b"{\"entries\":").unwrap();
+    let err = Shard::load(db_path, params_path).unwrap_err();
+    assert!(err.downcast_ref::<ErrorLoadFile>().is_some());
+
+    // files written before the header was introduced are loaded
+    fs::write(db_path, serde_json::to_vec(shard.get_db()).unwrap()).unwrap();
+    let loaded = Shard::load(db_path, params_path).unwrap();
+    assert_eq!(
+      loaded.get_db().get_db_entry(3),
+      shard.get_db().get_db_entry(3)
+    );
+
+    // files in the legacy format are loaded given the element size and
+    // plaintext bits, and migrated by writing them again
+    let db = shard.get_db();
+    let width = db.get_row_width_self();
+    let entries: Vec<Vec<u32>> = (0..width).map(|i| db.get_row(i)).collect();
+    fs::write(db_path, serde_json::to_vec(&entries).unwrap()).unwrap();
+    let bp = shard.get_base_params();
+    let legacy_params = serde_json::json!({
+      "lhs_seed": bp.get_public_seed(),
+      "rhs": bp.get_rhs(),
+    });
+    fs::write(params_path, legacy_params.to_string()).unwrap();
+    assert!(Shard::load(db_path, params_path).is_err());
+    assert!(
+      Shard::load_legacy(db_path, params_path, 128, PLAINTEXT_BITS).is_err()
+    );
+    let loaded =
+      Shard::load_legacy(db_path, params_path, ELEM_SIZE, PLAINTEXT_BITS)
+        .unwrap();
+    assert_eq!(loaded.get_base_params().get_rhs(), bp.get_rhs());
+    assert_eq!(loaded.get_base_params().get_dim(), LWE_DIM);
+    assert_eq!(loaded.get_db().get_db_entry(7), db.get_db_entry(7));
+    loaded.write_to_file(db_path, params_path).unwrap();
+    assert!(Shard::load(db_path, params_path).is_ok());
+  }
+
+  #[test]
+  fn rebuild_with_changes_matches_full_rebuild() {
+    let (db_eles, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let cases = [
+      (3, vec![5, 17]),
+      (2, vec![0, 40, 255]),
+      (0, vec![3, 254, 100, 255]),
+      (1, vec![]),
+    ];
+    for (n_added, removed) in cases {
+      let added = generate_db_eles(n_added, ELEM_SIZE / 8);
+      let next = shard.rebuild_with_changes(&added, &removed).unwrap();
+      let db = next.get_db();
+      let new_m = M + n_added - removed.len();
+      assert_eq!(db.get_matrix_height(), new_m);
+      let full = IndexParams::new_with_seed(db, LWE_DIM, bp.get_public_seed());
+      assert_eq!(next.get_base_params().get_rhs(), full.get_rhs());
+
+      let mut expected: Vec<String> = db_eles
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !removed.contains(i))
+        .map(|(_, e)| e.clone())
+        .chain(added)
         .collect();
-      for (j, col) in v.iter().enumerate() {
-        let masked = col.iter().fold(0u32, |acc, r| {
-          acc.wrapping_add(shard.get_db().entries[j][*r])
-        });
-        let unmasked = shard
-          .get_db()
-          .get_filter_params()
-          .unmask_value(masked, &kv.key, j as u64);
-        let add_modp = unmasked % 2u32.pow(plaintext_bits as u32);
-        assert_eq!(kv.value[j], add_modp);
+      let mut rows: Vec<String> =
+        (0..new_m).map(|i| db.get_db_entry(i)).collect();
+      expected.sort();
+      rows.sort();
+      assert_eq!(rows, expected);
+
+      let nbp = next.get_base_params();
+      let mut qp =
+        generate_index_query_params(&CommonParams::from(nbp), nbp).unwrap();
+      let q = qp.generate_query(new_m - 1).unwrap();
+      let resp: Response =
+        bincode::deserialize(&next.respond(&q).unwrap()).unwrap();
+      assert_eq!(qp.parse_resp_as_base64(&resp), db.get_db_entry(new_m - 1));
+    }
+    assert!(shard.rebuild_with_changes(&[], &[M]).is_err());
+  }
+
+  #[test]
+  fn mult_left_batch_matches_mult_left() {
+    let (_, shard) = index_shard();
+    let cp = CommonParams::from(shard.get_base_params());
+    let secrets: Vec<Vec<u32>> =
+      (0..5).map(|_| random_ternary_vector(LWE_DIM)).collect();
+    let batch = cp.mult_left_batch(&secrets).unwrap();
+    assert_eq!(batch.len(), secrets.len());
+    for (s, lhs) in secrets.iter().zip(&batch) {
+      assert_eq!(lhs.len(), M);
+      // each entry differs from s*A by a ternary error
+      for (col, &x) in cp.as_matrix().iter().zip(lhs) {
+        let e = x.wrapping_sub(vec_mult_u32_u32(s, col).unwrap());
+        assert!(e <= 1 || e == u32::MAX);
       }
     }
+    assert!(cp.mult_left_batch(&[vec![0; 511]]).is_err());
+    assert!(cp.mult_left_batch(&[]).unwrap().is_empty());
+  }
 
+  #[test]
+  fn query_params_stream_the_matrix_from_the_seed() {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    let (eles, shard) = index_shard();
+    let bp = shard.get_base_params();
     let cp = CommonParams::from(bp);
+    let expanded =
+      QueryParams::<IndexDatabase, EmptyAuxParams>::new_with_entropy(
+        &cp,
+        bp,
+        &mut StdRng::seed_from_u64(3),
+      )
+      .unwrap();
+    let mut qp = QueryParams::<IndexDatabase, EmptyAuxParams>::from_seed(
+      bp,
+      &mut StdRng::seed_from_u64(3),
+    )
+    .unwrap();
+    assert_eq!(qp.lhs, expanded.lhs);
+    assert_eq!(qp.rhs, expanded.rhs);
+    let q = qp.generate_query(17).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), eles[17]);
 
-    #[allow(clippy::needless_range_loop)]
-    for i in 0..10 {
-      let mut qp =
-        QueryParams::<KVDatabase, FilterParams>::new(&cp, bp).unwrap();
-      let q = qp.generate_query(&kvs[i].key).unwrap();
+    let (keys, values, kv_shard) = kv_shard();
+    let mut qp = QueryParams::<KVDatabase, FilterParams>::from_seed(
+      kv_shard.get_base_params(),
+      &mut OsRng,
+    )
+    .unwrap();
+    let key = HashedKey::from_bytes(keys[5].as_bytes());
+    let q = qp.generate_query(&key).unwrap();
+    let resp: Response =
+      bincode::deserialize(&kv_shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp, &key).unwrap(), values[5]);
+  }
 
-      let d_resp = shard.respond(&q).unwrap();
-      let resp: Response = bincode::deserialize(&d_resp).unwrap();
+  #[test]
+  fn query_params_accept_supplied_secrets() {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    let (eles, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    // a secret derived deterministically from a seed
+    let s = random_ternary_vector_from(&mut StdRng::seed_from_u64(7), 512);
+    let mut qp = QueryParams::<IndexDatabase, EmptyAuxParams>::new_with_secret(
+      &cp, bp, &s,
+    )
+    .unwrap();
+    assert_eq!(qp.rhs, bp.mult_right(&s).unwrap());
+    let q = qp.generate_query(9).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), eles[9]);
 
-      let output = qp.parse_resp_as_row(&resp, &kvs[i].key).unwrap();
-      assert_eq!(output, kvs[i].value);
+    let (keys, values, kv_shard) = kv_shard();
+    let kv_bp = kv_shard.get_base_params();
+    let kv_cp = CommonParams::from(kv_bp);
+    let mut qp = QueryParams::<KVDatabase, FilterParams>::new_with_secret(
+      &kv_cp, kv_bp, &s,
+    )
+    .unwrap();
+    let key = HashedKey::from_bytes(keys[5].as_bytes());
+    let q = qp.generate_query(&key).unwrap();
+    let resp: Response =
+      bincode::deserialize(&kv_shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp, &key).unwrap(), values[5]);
+
+    // secrets of the wrong length, non-ternary or skewed secrets
+    let mut not_ternary = s.clone();
+    not_ternary[3] = 2;
+    let skewed: Vec<u32> = (0..512).map(|i| (i % 8 == 0) as u32).collect();
+    for bad in [&s[1..], &not_ternary[..], &skewed[..], &[0u32; 512][..]] {
+      assert!(
+        QueryParams::<IndexDatabase, EmptyAuxParams>::new_with_secret(
+          &cp, bp, bad
+        )
+        .is_err()
+      );
     }
   }
 
   #[test]
-  fn client_query_to_server_attempt_params_reuse() {
-    let m = 2u32.pow(6) as usize;
+  fn common_params_persist_for_their_params() {
+    let shards = [index_shard().1, index_shard().1];
+    let bp = shards[0].get_base_params();
+    let cp = CommonParams::from(bp);
+    assert!(cp.matches(bp));
+    assert!(!cp.matches(shards[1].get_base_params()));
+
+    let file = TempPath::new("common-params.json");
+    let path = file.as_str();
+    cp.write_to_file(path).unwrap();
+    let loaded = CommonParams::load(path, bp).unwrap();
+    assert_eq!(loaded.as_matrix(), cp.as_matrix());
+    assert_eq!(loaded.get_origin(), cp.get_origin());
+    let err = CommonParams::load(path, shards[1].get_base_params())
+      .err()
+      .unwrap();
+    assert!(err.is::<ErrorParamsMismatch>());
+
+    let written = fs::read_to_string(path).unwrap();
+    fs::write(path, written.replacen("\"matrix\":[[", "\"matrix\":[[1", 1))
+      .unwrap();
+    let err = CommonParams::load(path, bp).err().unwrap();
+    assert!(err.is::<ErrorLoadFile>());
+  }
+
+  #[test]
+  fn load_from_json_file_reports_errors() {
+    let missing = TempPath::new("missing-db.json");
+    let err =
+      Shard::from_json_file(missing.as_str(), 512, 1, 8, 10).unwrap_err();
+    let err = err.downcast_ref::<ErrorLoadFile>().unwrap();
+    assert_eq!(err.path, missing.as_str());
+    assert_eq!(err.line, None);
+
+    let malformed = TempPath::new("malformed-db.json");
+    fs::write(&malformed, "[\n  \"AA==\",\n  5\n]").unwrap();
+    let err =
+      KVShard::from_json_file(malformed.as_str(), 512, 8, 10).unwrap_err();
+    let err = err.downcast_ref::<ErrorLoadFile>().unwrap();
+    assert_eq!(err.line, Some(2));
+  }
+
+  #[test]
+  fn load_from_jsonl_file() {
     let elem_size = 2u32.pow(8) as usize;
-    let plaintext_bits = 10usize;
-    let lwe_dim = 512;
-    let db_eles = generate_db_eles(m, (elem_size + 7) / 8);
-    let shard = Shard::from_base64_strings(
-      &db_eles,
+    let eles = generate_db_eles(64, elem_size / 8);
+    let file = TempPath::new("db.jsonl");
+    let path = file.as_str();
+    let lines: Vec<String> = eles
+      .iter()
+      .map(|e| serde_json::to_string(e).unwrap())
+      .collect();
+    fs::write(path, lines.join("\n") + "\n\n").unwrap();
+    let shard = Shard::from_jsonl_file(path, 512, 64, elem_size, 10).unwrap();
+    for (i, e) in eles.iter().enumerate() {
+      assert_eq!(&shard.get_db().get_db_entry(i), e);
+    }
+
+    let kv_file = TempPath::new("kv-db.jsonl");
+    let kv_path = kv_file.as_str();
+    let kv_lines: Vec<String> = eles
+      .iter()
+      .enumerate()
+      .map(|(i, e)| format!("{{\"key\":\"key-{}\",\"value\":\"{}\"}}", i, e))
+      .collect();
+    fs::write(kv_path, kv_lines.join("\r\n")).unwrap();
+    let kv_shard =
+      KVShard::from_jsonl_file(kv_path, 512, elem_size, 10).unwrap();
+    assert_eq!(kv_shard.get_db().num_keys(), 64);
+
+    fs::write(kv_path, format!("{}\n{{\"key\":5}}", kv_lines[0])).unwrap();
+    let err =
+      KVShard::from_jsonl_file(kv_path, 512, elem_size, 10).unwrap_err();
+    let err = err.downcast_ref::<ErrorLoadFile>().unwrap();
+    assert_eq!(err.line, Some(2));
+    assert!(!err.to_string().contains("line 1"));
+  }
+
+  #[test]
+  fn database_matrix_is_object_safe() {
+    let (eles, shard) = index_shard();
+    let (_, _, kv_shard) = kv_shard();
+    let dbs: Vec<Box<dyn DatabaseMatrix>> = vec![
+      Box::new(shard.get_db().clone()),
+      Box::new(kv_shard.get_db().clone()),
+    ];
+    for db in &dbs {
+      assert_eq!(
+        db.get_row_width_self(),
+        IndexDatabase::get_row_width(ELEM_SIZE, PLAINTEXT_BITS)
+      );
+    }
+    assert_eq!(dbs[0].get_db_entry(5), eles[5]);
+    shard
+      .get_base_params()
+      .verify_against(dbs[0].as_ref())
+      .unwrap();
+    kv_shard
+      .get_base_params()
+      .verify_against(dbs[1].as_ref())
+      .unwrap();
+  }
+
+  #[test]
+  fn response_envelope_carries_query_id() {
+    let (db_eles, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+
+    let mut outstanding = Vec::new();
+    for i in 0..3 {
+      let mut qp = generate_index_query_params(&cp, bp).unwrap();
+      let envelope = QueryEnvelope::new(qp.generate_query(i).unwrap());
+      outstanding.push((envelope, qp, i));
+    }
+    // answer out of order
+    for (envelope, _, _) in outstanding.iter().rev() {
+      let d_envelope = bincode::serialize(envelope).unwrap();
+      let envelope: QueryEnvelope = bincode::deserialize(&d_envelope).unwrap();
+      let d_resp = shard.respond_envelope(&envelope).unwrap();
+      let resp: ResponseEnvelope = bincode::deserialize(&d_resp).unwrap();
+      let (_, qp, i) = outstanding
+        .iter()
+        .find(|(e, _, _)| resp.answers(e))
+        .unwrap();
+      assert_eq!(qp.parse_resp_as_base64(&resp.response), db_eles[*i]);
+    }
+
+    let envelope = QueryEnvelope::without_id(outstanding[0].0.query.clone());
+    let d_resp = shard.respond_envelope(&envelope).unwrap();
+    let resp: ResponseEnvelope = bincode::deserialize(&d_resp).unwrap();
+    assert!(resp.id.is_none() && !resp.answers(&envelope));
+  }
+
+  #[test]
+  fn golden_serialization_is_stable() {
+    let (lwe_dim, elem_size, plaintext_bits) = (32, 64, 10);
+    let eles: Vec<String> = (0..16u8).map(|i| base64::encode([i; 8])).collect();
+    let keys: Vec<String> = (0..8).map(|i| format!("key-{}", i)).collect();
+    let seed = [7u8; 32];
+    let query_seed = [9u8; 32];
+
+    let shard = Shard::from_encoded_strings_with_seed(
+      &eles,
       lwe_dim,
-      m,
+      eles.len(),
       elem_size,
       plaintext_bits,
+      EncodingConfig::Standard,
+      seed,
     )
     .unwrap();
     let bp = shard.get_base_params();
     let cp = CommonParams::from(bp);
     let mut qp =
-      QueryParams::<IndexDatabase, EmptyAuxParams>::new(&cp, bp).unwrap();
-    // should be successful in generating a query
-    let res_unused = qp.generate_query(0);
-    assert!(res_unused.is_ok());
+      generate_index_query_params_from_seed(&cp, bp, query_seed).unwrap();
+    let q = qp.generate_query(3).unwrap();
+    let d_resp = shard.respond(&q).unwrap();
+    let resp: Response = bincode::deserialize(&d_resp).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), eles[3]);
+    let (db, params) =
+      written_files(|db, params| shard.write_to_file(db, params).unwrap());
+    check_golden("index_db.json", &db);
+    check_golden("index_params.json", &params);
+    check_golden("index_query.bin", &bincode::serialize(&q).unwrap());
+    check_golden("index_response.bin", &d_resp);
 
-    // should be "used"
-    assert!(qp.used);
+    let shard = KVShard::from_encoded_strings_with_seed(
+      &keys,
+      &eles[..keys.len()],
+      lwe_dim,
+      elem_size,
+      plaintext_bits,
+      EncodingConfig::Standard,
+      seed,
+    )
+    .unwrap();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp =
+      generate_kv_query_params_from_seed(&cp, bp, query_seed).unwrap();
+    let q = qp
+      .generate_query_for_key(&KeyBytes::from(keys[2].as_str()))
+      .unwrap();
+    let d_resp = shard.respond(&q).unwrap();
+    let resp: Response = bincode::deserialize(&d_resp).unwrap();
+    let key = HashedKey::from_bytes(keys[2].as_bytes());
+    assert_eq!(qp.parse_resp_as_base64(&resp, &key).unwrap(), eles[2]);
+    let (db, params) =
+      written_files(|db, params| shard.write_to_file(db, params).unwrap());
+    check_golden("kv_db.json", &db);
+    check_golden("kv_params.json", &params);
+    check_golden("kv_query.bin", &bincode::serialize(&q).unwrap());
+    check_golden("kv_response.bin", &d_resp);
+  }
 
-    // should be successful in generating a query
-    let res = qp.generate_query(0);
-    assert!(res.is_err());
+  /// Returns the contents of the DB and params files written by `write`
+  fn written_files<F: FnOnce(&str, &str)>(write: F) -> (Vec<u8>, Vec<u8>) {
+    let db_file = TempPath::new("golden-db.json");
+    let params_file = TempPath::new("golden-params.json");
+    write(db_file.as_str(), params_file.as_str());
+    (fs::read(&db_file).unwrap(), fs::read(&params_file).unwrap())
   }
 
-  fn generate_db_eles(num_eles: usize, ele_byte_len: usize) -> Vec<String> {
-    let mut eles = Vec::with_capacity(num_eles);
-    for _ in 0..num_eles {
-      let mut ele = vec![0u8; ele_byte_len];
-      OsRng.fill_bytes(&mut ele);
-      let ele_str = base64::encode(ele);
-      eles.push(ele_str);
-    }
-    eles
-  }
-
-  fn generate_kv_db_elems(
-    num_eles: usize,
-    ele_byte_len: usize,
-  ) -> Vec<(String, String)> {
-    let mut v = Vec::with_capacity(num_eles);
-    for _ in 0..num_eles {
-      let mut key = vec![0u8; 32];
-      let mut ele = vec![0u8; ele_byte_len];
-      OsRng.fill_bytes(&mut key);
-      OsRng.fill_bytes(&mut ele);
-      let key_str = base64::encode(key);
-      let ele_str = base64::encode(ele);
-      v.push((key_str, ele_str));
-    }
-    v
+  /// Compares `bytes` against the golden file `name` in `tests/golden`.
+  /// Run with `UPDATE_GOLDEN=1` to regenerate the golden files after an
+  /// intended format change.
+  fn check_golden(name: &str, bytes: &[u8]) {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+      .join("tests/golden")
+      .join(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(&path, bytes).unwrap();
+      return;
+    }
+    let golden = fs::read(&path).unwrap();
+    assert!(
+      golden == bytes,
+      "{} differs from its golden file, rerun with UPDATE_GOLDEN=1 if the \
+       format change is intended",
+      name
+    );
   }
 }
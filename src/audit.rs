@@ -0,0 +1,356 @@
+//! The `audit` module decodes the records of a full DB snapshot on the
+//! client side, e.g. to check that a snapshot published to a transparency
+//! log holds the expected records. Auditing downloads the whole DB (see
+//! `IndexDatabase::load` and `KVDatabase::load`), so unlike queries it does
+//! not need to hide which records are read.
+//!
+//! The params that clients query with are checked against the snapshot
+//! first (see `BaseParams::verify_against`), so that an audited snapshot
+//! is also the one that the params commit to.
+//!
+//! Deployments can also publish a `DbCommitment`, the root of a Merkle
+//! tree over the records of a DB (see `Shard::commitment`), and prove to
+//! auditors that single records are part of it (see `verify_inclusion`).
+//! The tree is that of RFC 6962 with SHA-256, so that auditors compute
+//! the commitment of a published dataset using `commit_records`, and
+//! check that it equals the one of the served DB without downloading it.
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::api::unframe_record;
+use crate::compression::decompress_record;
+use crate::db::{
+  BaseParams, DatabaseMatrix, DatabaseMatrixLayout, FilterParams, HashedKey,
+  IndexDatabase, IndexParams, KVDatabase, KVParams, RecordBytes,
+};
+use crate::errors::{
+  ErrorInclusionInvalid, ErrorParamsMismatch, ErrorUnexpectedInputSize,
+  ResultBoxedError,
+};
+use crate::expiry::strip_expiry;
+use crate::utils::format::bytes_from_u32_slice;
+use crate::utils::lwe::get_plaintext_size;
+
+/// Checks that `params` were generated for `db`, and returns an iterator
+/// over all records of `db`, in order, as clients decode them. Records of
+/// a deduplicated DB are read from the row holding them (see
+/// `IndexParams::get_record_row`), and records are decompressed and
+/// stripped of their expiry prefix if the params record a compression or
+/// record expiry (see `compression` and `expiry`). Expired records are
+/// reported as an `ErrorRecordExpired`, as they are to clients.
+pub fn decode_all<'a>(
+  db: &'a IndexDatabase,
+  params: &'a IndexParams,
+) -> ResultBoxedError<impl Iterator<Item = ResultBoxedError<RecordBytes>> + 'a>
+{
+  params.verify_against(db)?;
+  let compression = params.get_compression();
+  let record_expiry = params.get_record_expiry();
+  Ok((0..params.num_records()).map(move |i| {
+    let decoded = db.get_db_entry_bytes(params.get_record_row(i)?)?;
+    Ok(RecordBytes::from(unframe_record(
+      &decoded,
+      compression,
+      record_expiry,
+    )?))
+  }))
+}
+
+/// Returns all records of `db`, in order, as clients decode them (see
+/// `decode_all`), except that the expiry prefix is stripped whether or
+/// not the record has expired, so that the records that are committed to
+/// do not change over time. `params` must have been generated for `db`.
+pub(crate) fn committed_records(
+  db: &IndexDatabase,
+  params: &IndexParams,
+) -> ResultBoxedError<Vec<Vec<u8>>> {
+  let compression = params.get_compression();
+  (0..params.num_records())
+    .map(|i| {
+      let decoded = db.get_db_entry_bytes(params.get_record_row(i)?)?;
+      let record = decompress_record(&decoded, compression)?;
+      if !params.get_record_expiry() {
+        return Ok(record);
+      }
+      Ok(strip_expiry(&record)?.to_vec())
+    })
+    .collect()
+}
+
+/// Checks that `params` were generated for `db`, and returns an iterator
+/// over the values stored for `keys` (the bytes of the key strings used
+/// to build the DB), in order. Each value is decoded from the filter rows
+/// of its key exactly as from a response to a query for the key, so keys
+/// that are not stored in the DB decode to garbage.
+pub fn decode_values<'a, K: AsRef<[u8]>>(
+  db: &'a KVDatabase,
+  params: &KVParams,
+  keys: &'a [K],
+) -> ResultBoxedError<impl Iterator<Item = ResultBoxedError<RecordBytes>> + 'a>
+{
+  params.verify_against(db)?;
+  let fp = params.get_filter_params();
+  check_filter_params(&fp, db.get_filter_params())?;
+  fp.check_plaintext_bits(db.get_plaintext_bits())?;
+  let compression = params.get_compression();
+  let plaintext_size = get_plaintext_size(db.get_plaintext_bits());
+  let width =
+    KVDatabase::get_row_width(db.get_elem_size(), db.get_plaintext_bits());
+  Ok(keys.iter().map(move |key| {
+    let key = HashedKey::from_bytes(key.as_ref());
+    let rows = fp.get_hash_evals(&key);
+    let row: Vec<u32> = (0..width)
+      .map(|i| {
+        let masked = rows
+          .iter()
+          .fold(0u32, |acc, &r| acc.wrapping_add(db.entries[i][r]))
+          % plaintext_size;
+        fp.unmask_value(masked, &key, i as u64) % plaintext_size
+      })
+      .collect();
+    let bytes =
+      bytes_from_u32_slice(&row, db.get_plaintext_bits(), db.get_elem_size());
+    Ok(RecordBytes::from(decompress_record(&bytes, compression)?))
+  }))
+}
+
+/// A `DbCommitment` is the root of the Merkle tree over the records of a
+/// DB, together with the number of records
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DbCommitment {
+  pub root: [u8; 32],
+  pub num_records: usize,
+}
+
+/// An `InclusionProof` holds the Merkle audit path of the record at
+/// `index`, from the leaf up to the root
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InclusionProof {
+  pub index: usize,
+  pub path: Vec<[u8; 32]>,
+}
+
+/// Returns the commitment to `records`, in order
+pub fn commit_records<R: AsRef<[u8]>>(records: &[R]) -> DbCommitment {
+  RecordTree::new(records).commitment()
+}
+
+/// Returns the inclusion proof of the ith of `records`
+pub fn prove_record<R: AsRef<[u8]>>(
+  records: &[R],
+  i: usize,
+) -> ResultBoxedError<InclusionProof> {
+  RecordTree::new(records).prove(i)
+}
+
+/// A `RecordTree` is the Merkle tree over the records of a DB, holding
+/// the roots of all of its subtrees, so that the commitment and the
+/// inclusion proofs of single records are looked up rather than computed
+/// from all records every time
+#[derive(Clone, Debug, Default)]
+pub struct RecordTree {
+  num_records: usize,
+  /// The subtree roots, keyed by the first leaf and the number of leaves
+  /// of the subtree
+  nodes: HashMap<(usize, usize), [u8; 32]>,
+}
+
+impl RecordTree {
+  /// Builds the tree over `records`, in order
+  pub fn new<R: AsRef<[u8]>>(records: &[R]) -> Self {
+    let leaves: Vec<[u8; 32]> =
+      records.iter().map(|r| leaf_hash(r.as_ref())).collect();
+    let mut nodes = HashMap::with_capacity(2 * leaves.len());
+    if !leaves.is_empty() {
+      insert_subtree(&leaves, 0, &mut nodes);
+    }
+    Self {
+      num_records: leaves.len(),
+      nodes,
+    }
+  }
+
+  /// Returns the commitment to the records of the tree
+  pub fn commitment(&self) -> DbCommitment {
+    let root = match self.num_records {
+      0 => Sha256::digest([]).into(),
+      n => self.nodes[&(0, n)],
+    };
+    DbCommitment {
+      root,
+      num_records: self.num_records,
+    }
+  }
+
+  /// Returns the inclusion proof of the ith record
+  pub fn prove(&self, i: usize) -> ResultBoxedError<InclusionProof> {
+    if i >= self.num_records {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "Record {} is out of range for {} records",
+        i, self.num_records
+      ))));
+    }
+    let mut path = Vec::new();
+    self.audit_path(0, self.num_records, i, &mut path);
+    Ok(InclusionProof { index: i, path })
+  }
+
+  /// Appends the audit path of the ith leaf of the subtree of `n` leaves
+  /// starting at leaf `start` to `path`, bottom up
+  fn audit_path(
+    &self,
+    start: usize,
+    n: usize,
+    i: usize,
+    path: &mut Vec<[u8; 32]>,
+  ) {
+    if n <= 1 {
+      return;
+    }
+    let k = split_point(n);
+    if i < k {
+      self.audit_path(start, k, i, path);
+      path.push(self.nodes[&(start + k, n - k)]);
+    } else {
+      self.audit_path(start + k, n - k, i - k, path);
+      path.push(self.nodes[&(start, k)]);
+    }
+  }
+}
+
+/// Checks that `proof` shows that `record` is the record at `proof.index`
+/// of the DB committed to by `commitment` (following RFC 9162, 2.1.3.2)
+pub fn verify_inclusion(
+  commitment: &DbCommitment,
+  record: &[u8],
+  proof: &InclusionProof,
+) -> ResultBoxedError<()> {
+  if proof.index >= commitment.num_records {
+    return Err(Box::new(ErrorInclusionInvalid::new(format!(
+      "record {} is out of range for {} records",
+      proof.index, commitment.num_records
+    ))));
+  }
+  let (mut fnode, mut snode) = (proof.index, commitment.num_records - 1);
+  let mut root = leaf_hash(record);
+  for sibling in &proof.path {
+    if snode == 0 {
+      return Err(Box::new(ErrorInclusionInvalid::new(
+        "audit path is too long".to_string(),
+      )));
+    }
+    if fnode & 1 == 1 || fnode == snode {
+      root = node_hash(sibling, &root);
+      while fnode & 1 == 0 && fnode != 0 {
+        fnode >>= 1;
+        snode >>= 1;
+      }
+    } else {
+      root = node_hash(&root, sibling);
+    }
+    fnode >>= 1;
+    snode >>= 1;
+  }
+  if snode != 0 || root != commitment.root {
+    return Err(Box::new(ErrorInclusionInvalid::new(format!(
+      "record {} is not part of the committed DB",
+      proof.index
+    ))));
+  }
+  Ok(())
+}
+
+/// Returns the hash of a leaf of the Merkle tree, which is domain
+/// separated from inner nodes
+fn leaf_hash(record: &[u8]) -> [u8; 32] {
+  let mut hasher = Sha256::new();
+  hasher.update([0u8]);
+  hasher.update(record);
+  hasher.finalize().into()
+}
+
+/// Returns the hash of an inner node of the Merkle tree
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+  let mut hasher = Sha256::new();
+  hasher.update([1u8]);
+  hasher.update(left);
+  hasher.update(right);
+  hasher.finalize().into()
+}
+
+/// Returns the largest power of two below `n`, at which RFC 6962 splits
+/// a tree of `n` leaves
+fn split_point(n: usize) -> usize {
+  1 << (usize::BITS - 1 - (n - 1).leading_zeros())
+}
+
+/// Computes the root of the subtree with the given (non-empty) leaf
+/// hashes, starting at leaf `start`, and inserts it into `nodes` along
+/// with the roots of all of its subtrees
+fn insert_subtree(
+  leaves: &[[u8; 32]],
+  start: usize,
+  nodes: &mut HashMap<(usize, usize), [u8; 32]>,
+) -> [u8; 32] {
+  let root = match leaves.len() {
+    1 => leaves[0],
+    n => {
+      let k = split_point(n);
+      let left = insert_subtree(&leaves[..k], start, nodes);
+      let right = insert_subtree(&leaves[k..], start + k, nodes);
+      node_hash(&left, &right)
+    }
+  };
+  nodes.insert((start, leaves.len()), root);
+  root
+}
+
+/// Checks that the filters of the params are the filters of the DB
+fn check_filter_params(
+  params: &FilterParams,
+  db: &FilterParams,
+) -> Result<(), ErrorParamsMismatch> {
+  let same_filters = params.seed == db.seed
+    && params.segment_length == db.segment_length
+    && params.segment_length_mask == db.segment_length_mask
+    && params.segment_count_length == db.segment_count_length
+    && params.prf == db.prf;
+  if !same_filters {
+    return Err(ErrorParamsMismatch::new(
+      "filter params differ from the filters of the database".to_string(),
+    ));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utils::{index_shard, kv_shard};
+
+  #[test]
+  fn decode_snapshots() {
+    let (eles, shard) = index_shard();
+    let records = decode_all(shard.get_db(), shard.get_base_params())
+      .unwrap()
+      .map(|r| base64::encode(r.unwrap().as_bytes()))
+      .collect::<Vec<String>>();
+    assert_eq!(records, eles);
+
+    let (_, other) = index_shard();
+    let err = decode_all(shard.get_db(), other.get_base_params())
+      .err()
+      .unwrap();
+    assert!(err.is::<ErrorParamsMismatch>());
+
+    let (keys, values, kv_shard) = kv_shard();
+    let decoded =
+      decode_values(kv_shard.get_db(), kv_shard.get_base_params(), &keys)
+        .unwrap()
+        .map(|r| base64::encode(r.unwrap().as_bytes()))
+        .collect::<Vec<String>>();
+    assert_eq!(decoded, values);
+  }
+}
@@ -0,0 +1,79 @@
+//! Runs the protocol conformance checks of the `conformance` module
+//! against a PIR server over HTTP, e.g. `cargo run --bin conformance --
+//! 127.0.0.1:8080 --rotation-wait 60`, and exits with a non-zero status
+//! if any check fails. Known records of an index DB are passed as
+//! `--expect-row <row>=<base64 record>`.
+use std::process::ExitCode;
+use std::time::Duration;
+
+use keyword_pir_lwe::conformance::{run_conformance, ConformanceConfig};
+use keyword_pir_lwe::errors::ResultBoxedError;
+use keyword_pir_lwe::transport::{
+  HttpTransport, DEFAULT_PARAMS_PATH, DEFAULT_QUERY_PATH,
+};
+
+const USAGE: &str = "usage: conformance <host:port> [--params-path PATH] \
+[--query-path PATH] [--info-path PATH] [--timeout SECS] \
+[--rotation-wait SECS] [--expect-row ROW=BASE64]...";
+
+fn main() -> ExitCode {
+  let (mut transport, config) = match parse_args(std::env::args().skip(1)) {
+    Ok(parsed) => parsed,
+    Err(e) => {
+      eprintln!("{}\n{}", e, USAGE);
+      return ExitCode::from(2);
+    }
+  };
+  let report = run_conformance(&mut transport, &config);
+  println!("{}", report);
+  if report.passed() {
+    ExitCode::SUCCESS
+  } else {
+    ExitCode::FAILURE
+  }
+}
+
+fn parse_args(
+  mut args: impl Iterator<Item = String>,
+) -> ResultBoxedError<(HttpTransport, ConformanceConfig)> {
+  let addr = args.next().ok_or("missing server address")?;
+  let mut params_path = None;
+  let mut query_path = None;
+  let mut info_path = None;
+  let mut timeout = Duration::from_secs(30);
+  let mut config = ConformanceConfig::default();
+  while let Some(flag) = args.next() {
+    let value = args
+      .next()
+      .ok_or_else(|| format!("missing value of {}", flag))?;
+    match flag.as_str() {
+      "--params-path" => params_path = Some(value),
+      "--query-path" => query_path = Some(value),
+      "--info-path" => info_path = Some(value),
+      "--timeout" => timeout = Duration::from_secs(value.parse()?),
+      "--rotation-wait" => {
+        config.rotation_wait = Some(Duration::from_secs(value.parse()?))
+      }
+      "--expect-row" => {
+        let (row, record) = value
+          .split_once('=')
+          .ok_or_else(|| format!("malformed expected row {}", value))?;
+        config
+          .expected_rows
+          .push((row.parse()?, base64::decode(record)?));
+      }
+      _ => return Err(format!("unknown flag {}", flag).into()),
+    }
+  }
+  let mut transport = HttpTransport::new(&addr).with_timeout(timeout);
+  if params_path.is_some() || query_path.is_some() {
+    transport = transport.with_paths(
+      params_path.as_deref().unwrap_or(DEFAULT_PARAMS_PATH),
+      query_path.as_deref().unwrap_or(DEFAULT_QUERY_PATH),
+    );
+  }
+  if let Some(info_path) = info_path {
+    transport = transport.with_info_path(&info_path);
+  }
+  Ok((transport, config))
+}
@@ -0,0 +1,7 @@
+//! Generates the Kotlin and Swift bindings of the `ffi` module, e.g.
+//! `cargo run --features uniffi-bindgen --bin uniffi-bindgen generate
+//! --library target/release/libkeyword_pir_lwe.so --language kotlin
+//! --out-dir bindings`
+fn main() {
+  uniffi::uniffi_bindgen_main()
+}
@@ -0,0 +1,905 @@
+//! The `builder` module provides `ShardBuilder`, a configurable way of
+//! constructing a `Shard` (or `KVShard`) from a set of encoded DB elements.
+use crate::api::{KVShard, Modulus, Shard};
+use crate::compression::{compress_records, Compression};
+use crate::db::{DatabaseMatrixLayout, IndexDatabase};
+use crate::entropy::{Entropy, SharedEntropy};
+use crate::errors::{ErrorUnexpectedInputSize, ResultBoxedError};
+use crate::expiry::{stamp_records, EXPIRY_BYTES};
+use crate::utils::format::{read_bits_le, EncodingConfig};
+use crate::utils::random::generate_seed_from;
+
+/// The largest `plaintext_bits` value that is considered when selecting it
+/// automatically
+pub const MAX_AUTO_PLAINTEXT_BITS: usize = 20;
+
+/// The (base 2 logarithm of the) probability of a single response slot
+/// decoding incorrectly that is tolerated when selecting `plaintext_bits`
+/// automatically
+pub const AUTO_PLAINTEXT_BITS_FAILURE_EXP: u32 = 40;
+
+/// A `Profile` is a vetted combination of LWE dimension, plaintext bits
+/// and ciphertext modulus, to be used instead of ad-hoc parameters (see
+/// `ShardBuilder::with_profile`). All profiles use the modulus 2^32 and
+/// ternary secrets and errors, the only distribution that is supported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Profile {
+  /// LWE dimension 512 and 10 plaintext bits. This is fast, but offers
+  /// no meaningful security, and is only meant for tests and local
+  /// development.
+  Tiny,
+  /// LWE dimension 1774 and 10 plaintext bits, which target 128 bits of
+  /// security (as in FrodoPIR), and leave room for the noise of DBs of
+  /// up to about 2^16 rows of arbitrary records
+  Default128,
+  /// Same as `Default128`, with 12 plaintext bits, which shrinks the
+  /// params and responses by a sixth. The noise budget only suits DBs of
+  /// few rows, or of records with low-magnitude bytes.
+  HighRate,
+}
+impl Profile {
+  /// Returns the LWE dimension of the profile
+  pub const fn lwe_dim(&self) -> usize {
+    match self {
+      Profile::Tiny => 512,
+      Profile::Default128 | Profile::HighRate => 1774,
+    }
+  }
+
+  /// Returns the number of plaintext bits packed into each DB entry
+  pub const fn plaintext_bits(&self) -> usize {
+    match self {
+      Profile::Tiny | Profile::Default128 => 10,
+      Profile::HighRate => 12,
+    }
+  }
+
+  /// Returns the ciphertext modulus of the profile
+  pub fn modulus(&self) -> Modulus {
+    Modulus::default()
+  }
+}
+
+/// A `ShardBuilder` collects the parameters for constructing a `Shard`.
+///
+/// If `auto_plaintext_bits` is enabled, the DB elements are inspected and
+/// the largest `plaintext_bits` value is chosen for which the decryption
+/// noise is still bounded below the rounding threshold, given the maximum
+/// entry magnitude that occurs in any column of the DB matrix. A larger
+/// `plaintext_bits` value reduces the row width of the DB matrix, and
+/// thus the size of the public parameters and server responses. Datasets
+/// whose bytes have low magnitude (e.g. zero-padded records) benefit most.
+#[derive(Clone, Debug)]
+pub struct ShardBuilder {
+  lwe_dim: usize,
+  elem_size: usize,
+  plaintext_bits: usize,
+  encoding: EncodingConfig,
+  auto_plaintext_bits: bool,
+  infer_elem_size: bool,
+  compression: Compression,
+  modulus: Modulus,
+  dual_layout: bool,
+  entropy: SharedEntropy,
+  profile: Option<Profile>,
+}
+
+impl ShardBuilder {
+  /// Creates a builder for a DB with `elem_size` bit elements, using the
+  /// LWE dimension `lwe_dim` and `plaintext_bits = 10` by default
+  pub fn new(lwe_dim: usize, elem_size: usize) -> Self {
+    Self {
+      lwe_dim,
+      elem_size,
+      plaintext_bits: 10,
+      encoding: EncodingConfig::default(),
+      auto_plaintext_bits: false,
+      infer_elem_size: false,
+      compression: Compression::None,
+      modulus: Modulus::default(),
+      dual_layout: false,
+      entropy: SharedEntropy::default(),
+      profile: None,
+    }
+  }
+
+  /// Creates a builder for a DB with `elem_size` bit elements using the
+  /// parameters of `profile` (see `with_profile`)
+  pub fn from_profile(profile: Profile, elem_size: usize) -> Self {
+    Self::new(profile.lwe_dim(), elem_size).with_profile(profile)
+  }
+
+  /// Sets the LWE dimension, plaintext bits and modulus to those of
+  /// `profile`. The build fails if they are changed afterwards, or if the
+  /// DB elements of an index DB exceed the noise budget of the profile.
+  pub fn with_profile(mut self, profile: Profile) -> Self {
+    self.lwe_dim = profile.lwe_dim();
+    self.plaintext_bits = profile.plaintext_bits();
+    self.modulus = profile.modulus();
+    self.auto_plaintext_bits = false;
+    self.profile = Some(profile);
+    self
+  }
+
+  /// Sets the number of plaintext bits packed into each DB matrix entry.
+  /// This is ignored if `auto_plaintext_bits` is enabled.
+  pub fn plaintext_bits(mut self, plaintext_bits: usize) -> Self {
+    self.plaintext_bits = plaintext_bits;
+    self
+  }
+
+  /// Sets the encoding of the DB elements passed to `build`
+  pub fn encoding(mut self, encoding: EncodingConfig) -> Self {
+    self.encoding = encoding;
+    self
+  }
+
+  /// Enables selecting `plaintext_bits` based on the DB elements (only
+  /// for index DBs, see `build_kv`)
+  pub fn auto_plaintext_bits(mut self, auto: bool) -> Self {
+    self.auto_plaintext_bits = auto;
+    self
+  }
+
+  /// Enables deriving the element size from the longest DB element (see
+  /// `infer_elem_size`), in which case shorter elements are explicitly
+  /// padded with zero bytes to that size (see `pad_elements`), and the
+  /// element size passed to `new` is ignored
+  pub fn infer_elem_size(mut self, infer: bool) -> Self {
+    self.infer_elem_size = infer;
+    self
+  }
+
+  /// Sets the compression applied to each record before it is packed
+  /// into the DB matrix. With compression enabled, the element size is
+  /// derived from the longest compressed record, and the element size
+  /// passed to `new` is ignored. Clients recover the original records
+  /// using `parse_resp_as_record`.
+  pub fn compression(mut self, compression: Compression) -> Self {
+    self.compression = compression;
+    self
+  }
+
+  /// Sets the ciphertext modulus (2^32 by default). A smaller modulus
+  /// leaves less room for noise, so `plaintext_bits` must be chosen
+  /// accordingly (`auto_plaintext_bits` takes the modulus into account).
+  pub fn modulus(mut self, modulus: Modulus) -> Self {
+    self.modulus = modulus;
+    self
+  }
+
+  /// Enables maintaining a row-major copy of the DB matrix next to the
+  /// column-major one, so that batched responds (see
+  /// `Shard::respond_batch`) and single responds each use their optimal
+  /// layout. This doubles the memory used by the DB matrix.
+  pub fn dual_layout(mut self, enabled: bool) -> Self {
+    self.dual_layout = enabled;
+    self
+  }
+
+  /// Sets the source of the server randomness (`OsRng` by default), e.g.
+  /// a seeded RNG for deterministic simulations. Clones of the builder
+  /// share the source.
+  pub fn entropy<E: Entropy + 'static>(mut self, entropy: E) -> Self {
+    self.entropy = SharedEntropy::new(entropy);
+    self
+  }
+
+  /// Constructs the `Shard` from the encoded DB elements
+  pub fn build(&self, elements: &[String]) -> ResultBoxedError<Shard> {
+    self.build_with(elements, None)
+  }
+
+  /// Constructs the `Shard` from the encoded DB elements, prefixing each
+  /// element with the Unix timestamp (in seconds) at which it expires
+  /// (see `expiry`). Elements with a `None` expiry never expire, and
+  /// deleted elements are kept as tombstones with `Some(TOMBSTONE)`.
+  /// Unless the element size is inferred or compression is enabled, the
+  /// element size passed to `new` is extended by the prefix.
+  pub fn build_with_expiry(
+    &self,
+    elements: &[String],
+    expiries: &[Option<u64>],
+  ) -> ResultBoxedError<Shard> {
+    self.build_with(elements, Some(expiries))
+  }
+
+  /// Constructs the `KVShard` from the keys and encoded values. The
+  /// entries of a KV DB matrix are masked values, which are uniformly
+  /// distributed regardless of the values, so `auto_plaintext_bits` is
+  /// not applied and the configured `plaintext_bits` is always used.
+  pub fn build_kv(
+    &self,
+    keys: &[String],
+    values: &[String],
+  ) -> ResultBoxedError<KVShard> {
+    self.build_kv_with(keys, values, None)
+  }
+
+  /// Constructs the `KVShard` from the keys and encoded values, prefixing
+  /// each value with its expiry timestamp (see `build_with_expiry`)
+  pub fn build_kv_with_expiry(
+    &self,
+    keys: &[String],
+    values: &[String],
+    expiries: &[Option<u64>],
+  ) -> ResultBoxedError<KVShard> {
+    self.build_kv_with(keys, values, Some(expiries))
+  }
+
+  fn build_with(
+    &self,
+    elements: &[String],
+    expiries: Option<&[Option<u64>]>,
+  ) -> ResultBoxedError<Shard> {
+    self.check_profile()?;
+    let (elements, elem_size) = self.prepare_elements(elements, expiries)?;
+    let plaintext_bits = self.plaintext_bits_for(&elements, elem_size)?;
+    if self.profile.is_some() {
+      self.check_noise(&elements, elem_size)?;
+    }
+    let mut shard = Shard::from_encoded_strings_with_seed(
+      &elements,
+      self.lwe_dim,
+      elements.len(),
+      elem_size,
+      plaintext_bits,
+      self.encoding,
+      generate_seed_from(&mut self.entropy.clone()),
+    )?;
+    shard.set_params_compression(self.compression);
+    shard.set_params_record_expiry(expiries.is_some());
+    shard.set_params_modulus(self.modulus)?;
+    shard.set_dual_layout(self.dual_layout);
+    Ok(shard)
+  }
+
+  fn build_kv_with(
+    &self,
+    keys: &[String],
+    values: &[String],
+    expiries: Option<&[Option<u64>]>,
+  ) -> ResultBoxedError<KVShard> {
+    self.check_profile()?;
+    let (values, elem_size) = self.prepare_elements(values, expiries)?;
+    let mut shard = KVShard::from_encoded_strings_with_seed(
+      keys,
+      &values,
+      self.lwe_dim,
+      elem_size,
+      self.plaintext_bits,
+      self.encoding,
+      generate_seed_from(&mut self.entropy.clone()),
+    )?;
+    shard.set_params_compression(self.compression);
+    shard.set_params_record_expiry(expiries.is_some());
+    shard.set_params_modulus(self.modulus)?;
+    shard.set_dual_layout(self.dual_layout);
+    Ok(shard)
+  }
+
+  /// Returns the largest `plaintext_bits` value (up to
+  /// `MAX_AUTO_PLAINTEXT_BITS`) for which decoding is noise-safe
+  pub fn select_plaintext_bits(
+    &self,
+    elements: &[String],
+  ) -> ResultBoxedError<usize> {
+    let elem_size = if self.infer_elem_size {
+      infer_elem_size_with(elements, self.encoding)?
+    } else {
+      self.elem_size
+    };
+    self.select_plaintext_bits_for(elements, elem_size)
+  }
+
+  /// Checks that the parameters still match those of the profile, if one
+  /// was set (see `with_profile`)
+  fn check_profile(&self) -> ResultBoxedError<()> {
+    let profile = match self.profile {
+      Some(profile) => profile,
+      None => return Ok(()),
+    };
+    let matches = self.lwe_dim == profile.lwe_dim()
+      && self.plaintext_bits == profile.plaintext_bits()
+      && self.modulus == profile.modulus()
+      && !self.auto_plaintext_bits;
+    if !matches {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "builder parameters deviate from the {:?} profile",
+        profile
+      ))));
+    }
+    Ok(())
+  }
+
+  /// Checks that decoding is noise-safe for the DB matrix of `elements`
+  /// with the configured `plaintext_bits`
+  fn check_noise(
+    &self,
+    elements: &[String],
+    elem_size: usize,
+  ) -> ResultBoxedError<()> {
+    self.modulus.check_plaintext_bits(self.plaintext_bits)?;
+    let bytes = elements
+      .iter()
+      .map(|e| self.encoding.decode(e))
+      .collect::<ResultBoxedError<Vec<Vec<u8>>>>()?;
+    let max_entry = max_column_entry(&bytes, elem_size, self.plaintext_bits)?;
+    if !is_noise_safe(
+      max_entry,
+      elements.len(),
+      self.plaintext_bits,
+      self.modulus,
+    ) {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "{} DB elements exceed the noise budget of {} plaintext bits",
+        elements.len(),
+        self.plaintext_bits
+      ))));
+    }
+    Ok(())
+  }
+
+  /// Prefixes the elements with their expiry timestamps if given, and
+  /// compresses them if compression is enabled, or pads them to the
+  /// inferred element size if enabled, returning the re-encoded elements
+  /// and their element size
+  fn prepare_elements(
+    &self,
+    elements: &[String],
+    expiries: Option<&[Option<u64>]>,
+  ) -> ResultBoxedError<(Vec<String>, usize)> {
+    let (stamped, elem_size) = match expiries {
+      Some(expiries) => {
+        let records = elements
+          .iter()
+          .map(|e| self.encoding.decode(e))
+          .collect::<ResultBoxedError<Vec<Vec<u8>>>>()?;
+        let stamped = stamp_records(&records, expiries)?
+          .iter()
+          .map(|r| self.encoding.encode(r))
+          .collect::<Vec<String>>();
+        (Some(stamped), self.elem_size + EXPIRY_BYTES * 8)
+      }
+      None => (None, self.elem_size),
+    };
+    let elements = stamped.as_deref().unwrap_or(elements);
+    if self.compression == Compression::None {
+      if !self.infer_elem_size {
+        return Ok((elements.to_vec(), elem_size));
+      }
+      let elem_size = infer_elem_size_with(elements, self.encoding)?;
+      let padded = pad_elements(elements, elem_size, self.encoding)?;
+      return Ok((padded, elem_size));
+    }
+    let records = elements
+      .iter()
+      .map(|e| self.encoding.decode(e))
+      .collect::<ResultBoxedError<Vec<Vec<u8>>>>()?;
+    let (framed, elem_size) = compress_records(&records, self.compression)?;
+    let encoded = framed.iter().map(|f| self.encoding.encode(f)).collect();
+    Ok((encoded, elem_size))
+  }
+
+  fn plaintext_bits_for(
+    &self,
+    elements: &[String],
+    elem_size: usize,
+  ) -> ResultBoxedError<usize> {
+    if self.auto_plaintext_bits {
+      self.select_plaintext_bits_for(elements, elem_size)
+    } else {
+      Ok(self.plaintext_bits)
+    }
+  }
+
+  fn select_plaintext_bits_for(
+    &self,
+    elements: &[String],
+    elem_size: usize,
+  ) -> ResultBoxedError<usize> {
+    let bytes = elements
+      .iter()
+      .map(|e| self.encoding.decode(e))
+      .collect::<ResultBoxedError<Vec<Vec<u8>>>>()?;
+    for p in (1..=MAX_AUTO_PLAINTEXT_BITS).rev() {
+      if self.modulus.check_plaintext_bits(p).is_err() {
+        continue;
+      }
+      let max_entry = max_column_entry(&bytes, elem_size, p)?;
+      if is_noise_safe(max_entry, elements.len(), p, self.modulus) {
+        return Ok(p);
+      }
+    }
+    Err("No plaintext_bits value is noise-safe for the DB elements".into())
+  }
+}
+
+/// Returns the element size (in bits) of the longest of the base64
+/// encoded `elements`, i.e. the smallest element size for which no
+/// element is truncated when it is packed into the DB matrix
+pub fn infer_elem_size(elements: &[String]) -> ResultBoxedError<usize> {
+  infer_elem_size_with(elements, EncodingConfig::Standard)
+}
+
+/// Same as `infer_elem_size`, for elements encoded using `encoding`
+pub fn infer_elem_size_with(
+  elements: &[String],
+  encoding: EncodingConfig,
+) -> ResultBoxedError<usize> {
+  let mut max_len = 0;
+  for e in elements {
+    max_len = max_len.max(encoding.decode(e)?.len());
+  }
+  if max_len == 0 {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(
+      "cannot infer the element size of empty elements".to_string(),
+    )));
+  }
+  Ok(8 * max_len)
+}
+
+/// Pads every element (encoded using `encoding`) with zero bytes up to
+/// `elem_size` bits, rejecting elements that are longer
+pub fn pad_elements(
+  elements: &[String],
+  elem_size: usize,
+  encoding: EncodingConfig,
+) -> ResultBoxedError<Vec<String>> {
+  let len = elem_size.div_ceil(8);
+  elements
+    .iter()
+    .map(|e| -> ResultBoxedError<String> {
+      let mut bytes = encoding.decode(e)?;
+      if bytes.len() > len {
+        return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+          "element of {} bytes exceeds the element size of {} bits",
+          bytes.len(),
+          elem_size
+        ))));
+      }
+      bytes.resize(len, 0);
+      Ok(encoding.encode(&bytes))
+    })
+    .collect()
+}
+
+/// Returns the maximum entry over all columns of the DB matrix that packs
+/// the bits of `elements` into `plaintext_bits` chunks
+fn max_column_entry(
+  elements: &[Vec<u8>],
+  elem_size: usize,
+  plaintext_bits: usize,
+) -> ResultBoxedError<u32> {
+  let row_width = IndexDatabase::get_row_width(elem_size, plaintext_bits);
+  let mut max = 0;
+  for bytes in elements {
+    for i in 0..row_width {
+      max = max.max(read_bits_le(bytes, i * plaintext_bits, plaintext_bits)?);
+    }
+  }
+  Ok(max)
+}
+
+/// Checks whether the noise in a response slot is below the rounding
+/// threshold, except with probability 2^-AUTO_PLAINTEXT_BITS_FAILURE_EXP
+fn is_noise_safe(
+  max_entry: u32,
+  m: usize,
+  plaintext_bits: usize,
+  modulus: Modulus,
+) -> bool {
+  noise_bound(max_entry, m) < rounding_threshold(plaintext_bits, modulus)
+}
+
+/// Returns a bound on the noise in a response slot that holds except with
+/// probability 2^-AUTO_PLAINTEXT_BITS_FAILURE_EXP.
+///
+/// The noise is the inner product of a ternary error vector with a DB
+/// column of `m` entries bounded by `max_entry`, so by Hoeffding's
+/// inequality it exceeds `t` with probability at most
+/// `2exp(-t^2 / (2m * max_entry^2))`.
+pub(crate) fn noise_bound(max_entry: u32, m: usize) -> f64 {
+  let ln_inv_failure =
+    (AUTO_PLAINTEXT_BITS_FAILURE_EXP as f64 + 1.0) * std::f64::consts::LN_2;
+  max_entry as f64 * (2.0 * m as f64 * ln_inv_failure).sqrt()
+}
+
+/// Returns the noise above which a response slot rounds to a different
+/// plaintext value
+pub(crate) fn rounding_threshold(
+  plaintext_bits: usize,
+  modulus: Modulus,
+) -> f64 {
+  modulus.rounding_floor(plaintext_bits) as f64
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::*;
+  use crate::client::{KvPirClient, KvValue, ReliableClient};
+  use crate::db::{FilterParams, IndexParams, KVDatabase, KVParams};
+  use crate::errors::ErrorRecordExpired;
+  use crate::expiry::TOMBSTONE;
+  use rand_core::{OsRng, RngCore};
+  use std::time::{SystemTime, UNIX_EPOCH};
+
+  #[test]
+  fn auto_plaintext_bits_adapts_to_entries() {
+    let m = 2u32.pow(8) as usize;
+    let elem_size = 2u32.pow(8) as usize;
+    let lwe_dim = 512;
+    let random: Vec<String> = (0..m)
+      .map(|_| {
+        let mut ele = vec![0u8; elem_size / 8];
+        OsRng.fill_bytes(&mut ele);
+        base64::encode(ele)
+      })
+      .collect();
+    let sparse: Vec<String> = (0..m)
+      .map(|i| {
+        let mut ele = vec![0u8; elem_size / 8];
+        ele[0] = i as u8 & 1;
+        base64::encode(ele)
+      })
+      .collect();
+    let builder =
+      ShardBuilder::new(lwe_dim, elem_size).auto_plaintext_bits(true);
+    let p_random = builder.select_plaintext_bits(&random).unwrap();
+    let p_sparse = builder.select_plaintext_bits(&sparse).unwrap();
+    assert!(p_sparse > p_random);
+    assert!(is_noise_safe(
+      (1 << p_random) - 1,
+      m,
+      p_random,
+      Modulus::default()
+    ));
+
+    for db_eles in [random, sparse] {
+      let shard = builder.build(&db_eles).unwrap();
+      let bp = shard.get_base_params();
+      let cp = CommonParams::from(bp);
+      let mut qp = generate_index_query_params(&cp, bp).unwrap();
+      let q = qp.generate_query(1).unwrap();
+      let d_resp = shard.respond(&q).unwrap();
+      let resp: Response = bincode::deserialize(&d_resp).unwrap();
+      assert_eq!(qp.parse_resp_as_base64(&resp), db_eles[1]);
+    }
+  }
+
+  #[test]
+  fn profiles_fix_vetted_parameters() {
+    let elem_size = 2u32.pow(8) as usize;
+    let random = |m: usize| -> Vec<String> {
+      (0..m)
+        .map(|_| {
+          let mut ele = vec![0u8; elem_size / 8];
+          OsRng.fill_bytes(&mut ele);
+          base64::encode(ele)
+        })
+        .collect()
+    };
+    let db_eles = random(256);
+    let builder = ShardBuilder::from_profile(Profile::Tiny, elem_size);
+    let shard = builder.build(&db_eles).unwrap();
+    let bp = shard.get_base_params();
+    assert_eq!(bp.get_dim(), Profile::Tiny.lwe_dim());
+    assert_eq!(bp.get_plaintext_bits(), Profile::Tiny.plaintext_bits());
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_index_query_params(&cp, bp).unwrap();
+    let q = qp.generate_query(7).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), db_eles[7]);
+
+    assert!(builder.clone().plaintext_bits(16).build(&db_eles).is_err());
+    assert!(builder.auto_plaintext_bits(true).build(&db_eles).is_err());
+    let high_rate =
+      ShardBuilder::new(512, elem_size).with_profile(Profile::HighRate);
+    assert!(high_rate.build(&random(1024)).is_err());
+  }
+
+  #[test]
+  fn compressed_records_round_trip() {
+    let lwe_dim = 512;
+    // records of 80 bytes
+    let elem_size = 640;
+    let records: Vec<String> = (0..64)
+      .map(|i| base64::encode(format!("{{\"id\":{:03}}}", i).repeat(8)))
+      .collect();
+    let keys: Vec<String> = (0..64).map(|i| format!("key-{}", i)).collect();
+    let compression = if cfg!(feature = "compression") {
+      Compression::Snappy
+    } else {
+      Compression::None
+    };
+    let builder =
+      ShardBuilder::new(lwe_dim, elem_size).compression(compression);
+
+    let shard = builder.build(&records).unwrap();
+    let bp = shard.get_base_params();
+    assert_eq!(bp.get_compression(), compression);
+    if compression == Compression::Snappy {
+      assert!(bp.get_elem_size() < elem_size);
+    }
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_index_query_params(&cp, bp).unwrap();
+    let q = qp.generate_query(11).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    let record = qp.parse_resp_as_record(&resp).unwrap();
+    assert_eq!(base64::encode(record), records[11]);
+
+    let shard = builder.build_kv(&keys, &records).unwrap();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+    let q = qp
+      .generate_query_for_key(&KeyBytes::from(keys[5].as_str()))
+      .unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    let key = HashedKey::from_bytes(keys[5].as_bytes());
+    let value = qp.parse_resp_as_record(&resp, &key).unwrap();
+    assert_eq!(base64::encode(value), records[5]);
+  }
+
+  #[test]
+  fn smaller_modulus_round_trip() {
+    let lwe_dim = 512;
+    let elem_size = 2u32.pow(6) as usize;
+    let records: Vec<String> = (0..128)
+      .map(|_| {
+        let mut ele = vec![0u8; elem_size / 8];
+        OsRng.fill_bytes(&mut ele);
+        base64::encode(ele)
+      })
+      .collect();
+    let keys: Vec<String> = (0..128).map(|i| format!("key-{}", i)).collect();
+    let modulus = Modulus::new(24).unwrap();
+    let builder = ShardBuilder::new(lwe_dim, elem_size)
+      .plaintext_bits(8)
+      .modulus(modulus);
+
+    let shard = builder.build(&records).unwrap();
+    let bp = shard.get_base_params();
+    assert_eq!(bp.get_modulus(), modulus);
+    let json = serde_json::to_string(bp).unwrap();
+    assert!(json.contains("\"modulus\":24"));
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_index_query_params(&cp, bp).unwrap();
+    let q = qp.generate_query(7).unwrap();
+    assert!(q.as_slice().iter().all(|&x| (x as u64) < modulus.value()));
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), records[7]);
+
+    // the packed encodings use 24 bits per entry
+    let packed = q.to_packed_bytes(modulus);
+    assert_eq!(packed.len(), 8 + 3 * q.as_slice().len());
+    let unpacked = Query::from_packed_bytes(&packed, modulus).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&unpacked).unwrap()).unwrap();
+    let packed = resp.to_packed_bytes(modulus);
+    assert!(packed.len() < bincode::serialize(&resp).unwrap().len());
+    let resp = Response::from_packed_bytes(&packed, modulus).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), records[7]);
+    assert!(Response::from_packed_bytes(&packed[..10], modulus).is_err());
+    assert!(Query::from_packed_bytes(&packed[..4], modulus).is_err());
+
+    let shard = builder.build_kv(&keys, &records).unwrap();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+    let q = qp
+      .generate_query_for_key(&KeyBytes::from(keys[9].as_str()))
+      .unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    let key = HashedKey::from_bytes(keys[9].as_bytes());
+    let value = qp.parse_resp_as_base64(&resp, &key).unwrap();
+    assert_eq!(value, records[9]);
+
+    assert!(Modulus::new(0).is_err());
+    let err = Modulus::new(64).unwrap_err();
+    assert!(err.to_string().contains("requires 64-bit arithmetic"));
+    assert!(Modulus::new(65).is_err());
+    assert!(serde_json::from_str::<Modulus>("33").is_err());
+    let too_small = ShardBuilder::new(lwe_dim, elem_size)
+      .plaintext_bits(8)
+      .modulus(Modulus::new(8).unwrap());
+    assert!(too_small.build(&records).is_err());
+
+    // params that do not leave room for the plaintext bits are rejected
+    let json = json.replace("\"modulus\":24", "\"modulus\":8");
+    assert!(serde_json::from_str::<IndexParams>(&json).is_err());
+    let json = serde_json::to_string(&qp).unwrap();
+    assert!(
+      serde_json::from_str::<QueryParams<KVDatabase, FilterParams>>(&json)
+        .is_ok()
+    );
+    let json = json.replace("\"modulus\":24", "\"modulus\":8");
+    assert!(
+      serde_json::from_str::<QueryParams<KVDatabase, FilterParams>>(&json)
+        .is_err()
+    );
+    let json = serde_json::to_string(bp).unwrap();
+    let json = json.replace("\"modulus\":24", "\"modulus\":8");
+    assert!(serde_json::from_str::<KVParams>(&json).is_err());
+  }
+
+  #[test]
+  fn dual_layout_batches_match_single_responds() {
+    let lwe_dim = 512;
+    let elem_size = 2u32.pow(6) as usize;
+    // spans several chunks of rows of a batched respond
+    let records: Vec<String> = (0..1500)
+      .map(|_| {
+        let mut ele = vec![0u8; elem_size / 8];
+        OsRng.fill_bytes(&mut ele);
+        base64::encode(ele)
+      })
+      .collect();
+    let keys: Vec<String> = (0..64).map(|i| format!("key-{}", i)).collect();
+    let builder = ShardBuilder::new(lwe_dim, elem_size).dual_layout(true);
+
+    let shard = builder.build(&records).unwrap();
+    assert!(shard.get_db().get_row_major().is_some());
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qps: Vec<_> = (0..3)
+      .map(|_| generate_index_query_params(&cp, bp).unwrap())
+      .collect();
+    let queries: Vec<Query> = qps
+      .iter_mut()
+      .zip([0, 1029, 1499])
+      .map(|(qp, i)| qp.generate_query(i).unwrap())
+      .collect();
+    let batch = shard.respond_batch(&queries).unwrap();
+    let single = ShardBuilder::new(lwe_dim, elem_size).build(&records);
+    assert!(single.unwrap().get_db().get_row_major().is_none());
+    for ((q, resp), (qp, i)) in queries
+      .iter()
+      .zip(&batch)
+      .zip(qps.iter().zip([0, 1029, 1499]))
+    {
+      assert_eq!(resp, &shard.respond(q).unwrap());
+      let resp: Response = bincode::deserialize(resp).unwrap();
+      assert_eq!(qp.parse_resp_as_base64(&resp), records[i]);
+    }
+    assert!(shard.respond_batch(&[Query::random(10)]).is_err());
+
+    let shard = builder.build_kv(&keys, &records[..64]).unwrap();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+    let q = qp
+      .generate_query_for_key(&KeyBytes::from(keys[3].as_str()))
+      .unwrap();
+    let batch = shard.respond_batch(std::slice::from_ref(&q)).unwrap();
+    assert_eq!(batch, vec![shard.respond(&q).unwrap()]);
+  }
+
+  #[test]
+  fn elem_size_is_inferred_from_elements() {
+    // elements of 3 to 18 bytes
+    let records: Vec<Vec<u8>> = (0..64)
+      .map(|i| {
+        let mut ele = vec![0u8; 3 + i % 16];
+        OsRng.fill_bytes(&mut ele);
+        ele
+      })
+      .collect();
+    let encoded: Vec<String> = records.iter().map(base64::encode).collect();
+    assert_eq!(infer_elem_size(&encoded).unwrap(), 18 * 8);
+    let hex: Vec<String> = records
+      .iter()
+      .map(|r| EncodingConfig::Hex.encode(r))
+      .collect();
+    assert_eq!(
+      infer_elem_size_with(&hex, EncodingConfig::Hex).unwrap(),
+      18 * 8
+    );
+    assert!(infer_elem_size(&[]).is_err());
+    assert!(infer_elem_size(&[String::new()]).is_err());
+    assert!(pad_elements(&encoded, 8, EncodingConfig::Standard).is_err());
+
+    // the element size passed to the builder is ignored
+    let builder = ShardBuilder::new(512, 8).infer_elem_size(true);
+    let shard = builder.build(&encoded).unwrap();
+    let bp = shard.get_base_params();
+    assert_eq!(bp.get_elem_size(), 18 * 8);
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_index_query_params(&cp, bp).unwrap();
+    let q = qp.generate_query(5).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    let mut padded = records[5].clone();
+    padded.resize(18, 0);
+    assert_eq!(qp.parse_resp_as_bytes(&resp), padded);
+
+    let keys: Vec<String> = (0..64).map(|i| format!("key-{}", i)).collect();
+    let shard = builder.build_kv(&keys, &encoded).unwrap();
+    assert_eq!(shard.get_base_params().get_elem_size(), 18 * 8);
+  }
+
+  #[test]
+  fn expired_records_are_reported() {
+    let records: Vec<String> = (0..64)
+      .map(|i| base64::encode(format!("record-{:09}", i)))
+      .collect();
+    let keys: Vec<String> = (0..64).map(|i| format!("key-{}", i)).collect();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    // live, aged out, deleted and not yet expired records
+    let expiries: Vec<Option<u64>> = (0..64)
+      .map(|i| match i % 4 {
+        0 => None,
+        1 => Some(now.as_secs() - 60),
+        2 => Some(TOMBSTONE),
+        _ => Some(now.as_secs() + 3600),
+      })
+      .collect();
+    let check = |i: usize, parsed: ResultBoxedError<Vec<u8>>| match i % 4 {
+      0 | 3 => assert_eq!(base64::encode(parsed.unwrap()), records[i]),
+      _ => {
+        let err = parsed.unwrap_err();
+        let expired = err.downcast_ref::<ErrorRecordExpired>().unwrap();
+        assert_eq!(expired.is_tombstone(), i % 4 == 2);
+      }
+    };
+    let builder = ShardBuilder::new(512, 128);
+
+    let shard = builder.build_with_expiry(&records, &expiries).unwrap();
+    let bp = shard.get_base_params();
+    assert!(bp.get_record_expiry());
+    assert_eq!(bp.get_elem_size(), 128 + EXPIRY_BYTES * 8);
+    let cp = CommonParams::from(bp);
+    for i in 4..8 {
+      let mut qp = generate_index_query_params(&cp, bp).unwrap();
+      let q = qp.generate_query(i).unwrap();
+      let resp: Response =
+        bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+      check(i, qp.parse_resp_as_record(&resp));
+    }
+    let client = ReliableClient::new(bp.clone());
+    for i in 4..8 {
+      check(i, client.retrieve(i, |q| shard.respond(q)));
+    }
+
+    let shard = builder
+      .build_kv_with_expiry(&keys, &records, &expiries)
+      .unwrap();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    for (i, key) in keys.iter().enumerate().take(8).skip(4) {
+      let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+      let q = qp
+        .generate_query_for_key(&KeyBytes::from(key.as_str()))
+        .unwrap();
+      let resp: Response =
+        bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+      let hashed = HashedKey::from_bytes(key.as_bytes());
+      check(i, qp.parse_resp_as_record(&resp, &hashed));
+    }
+    let requested: Vec<Vec<u8>> =
+      keys[4..8].iter().map(|k| k.as_bytes().to_vec()).collect();
+    let values_by_key = KvPirClient::new(bp.clone())
+      .get_many(&requested, |queries| {
+        queries.iter().map(|q| shard.respond(q)).collect()
+      })
+      .unwrap();
+    for (i, key) in requested.iter().enumerate().map(|(j, k)| (j + 4, k)) {
+      match (i % 4, &values_by_key[key]) {
+        (0 | 3, KvValue::Found(value)) => {
+          assert_eq!(base64::encode(value), records[i])
+        }
+        (1, KvValue::Expired(at)) => assert_eq!(*at, now.as_secs() - 60),
+        (2, KvValue::Expired(at)) => assert_eq!(*at, TOMBSTONE),
+        (_, value) => panic!("unexpected value {:?} for key {}", value, i),
+      }
+    }
+
+    assert!(builder.build_with_expiry(&records, &expiries[1..]).is_err());
+  }
+}
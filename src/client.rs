@@ -0,0 +1,1256 @@
+//! The `client` module provides higher-level helpers that manage the
+//! lifecycle of `QueryParams` on behalf of PIR clients.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::time::{Duration, Instant, SystemTime};
+
+use rand_core::{OsRng, RngCore};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::api::{
+  generate_dummy_query, generate_index_query_params, generate_kv_query_params,
+  unframe_record, DecodedRow, EmptyAuxParams, Query, QueryParams, Response,
+};
+use crate::db::{
+  chunk_key, join_chunks, BaseParams, CommonParams, FilterParams, HashedKey,
+  IndexDatabase, IndexParams, KVDatabase, KVParams,
+};
+use crate::errors::{
+  ErrorParamsExpired, ErrorRecordExpired, ErrorRetriesExhausted,
+  ErrorUnexpectedInputSize, ResultBoxedError,
+};
+use crate::manifest::{ManifestVerifier, VerifyingKey};
+use crate::serving::ShardInfo;
+use crate::transport::PirTransport;
+use crate::utils::files::{read_persisted, write_persisted, Persist};
+use crate::utils::format::{
+  bytes_from_u32_slice_with_column_bits, column_bits_or_uniform,
+};
+
+/// The default number of retries performed by a `ReliableClient`
+const DEFAULT_MAX_RETRIES: usize = 3;
+
+/// The default time before params expire at which a `HintManager`
+/// refreshes them
+const DEFAULT_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// A `ReliableClient` wraps public parameters and retries a query with
+/// fresh `QueryParams` whenever decoding fails, the noise margin of the
+/// decoded response is too low, or an optional record check (e.g. a
+/// checksum embedded in the records) rejects the decoded record.
+///
+/// Records are decompressed and have their expiry checked as by
+/// `QueryParams::parse_resp_as_record`. Expired records are reported as
+/// an `ErrorRecordExpired` without retrying.
+pub struct ReliableClient<P> {
+  cp: CommonParams,
+  params: P,
+  max_retries: usize,
+  min_margin: u32,
+  record_check: Option<fn(&[u8]) -> bool>,
+}
+
+impl<P: BaseParams> ReliableClient<P> {
+  /// Creates a new `ReliableClient`, expanding `CommonParams` from
+  /// `params`. By default, a response is accepted if the margin of all
+  /// slots is at least half of the rounding floor.
+  pub fn new(params: P) -> Self {
+    Self {
+      cp: CommonParams::from(&params),
+      min_margin: params
+        .get_modulus()
+        .rounding_floor(params.get_plaintext_bits())
+        / 2,
+      params,
+      max_retries: DEFAULT_MAX_RETRIES,
+      record_check: None,
+    }
+  }
+
+  /// Sets the number of retries after the first attempt
+  pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+    self.max_retries = max_retries;
+    self
+  }
+
+  /// Sets the minimum noise margin that a decoded response must have
+  pub fn with_min_margin(mut self, min_margin: u32) -> Self {
+    self.min_margin = min_margin;
+    self
+  }
+
+  /// Sets a check that decoded records must pass to be accepted
+  pub fn with_record_check(mut self, check: fn(&[u8]) -> bool) -> Self {
+    self.record_check = Some(check);
+    self
+  }
+
+  /// Returns the public parameters
+  pub fn get_params(&self) -> &P {
+    &self.params
+  }
+
+  /// Replaces the public parameters (e.g. after they were refreshed),
+  /// keeping the other settings. `CommonParams` are only rederived if
+  /// the public seed or dimensions changed.
+  pub fn set_params(&mut self, params: P) {
+    if needs_new_common_params(&self.params, &params) {
+      self.cp = CommonParams::from(&params);
+    }
+    self.params = params;
+  }
+
+  /// Runs `attempt` until it produces an acceptable decoded row, or the
+  /// retry budget is exhausted, returning the record recovered from it.
+  /// `attempt` returns `None` if the response could not be decoded, which
+  /// is retried like a low noise margin, whereas other errors (e.g. of
+  /// the transport) are returned immediately.
+  fn retry<F>(&self, mut attempt: F) -> ResultBoxedError<Vec<u8>>
+  where
+    F: FnMut() -> ResultBoxedError<Option<DecodedRow>>,
+  {
+    let attempts = self.max_retries + 1;
+    for _ in 0..attempts {
+      let Some(decoded) = attempt()? else {
+        continue;
+      };
+      if decoded.min_margin() < self.min_margin {
+        continue;
+      }
+      let column_bits = column_bits_or_uniform(
+        self.params.get_column_bits(),
+        self.params.get_elem_size(),
+        self.params.get_plaintext_bits(),
+      );
+      let bytes = bytes_from_u32_slice_with_column_bits(
+        &decoded.row,
+        &column_bits,
+        self.params.get_elem_size(),
+      );
+      let record = match unframe_record(
+        &bytes,
+        self.params.get_compression(),
+        self.params.get_record_expiry(),
+      ) {
+        Ok(record) => record,
+        Err(e) if e.is::<ErrorRecordExpired>() => return Err(e),
+        Err(_) => continue,
+      };
+      if passes_check(self.record_check, &record) {
+        return Ok(record);
+      }
+    }
+    Err(Box::new(ErrorRetriesExhausted { attempts }))
+  }
+}
+
+impl ReliableClient<IndexParams> {
+  /// Retrieves the DB element at `row_index`, where `respond` sends a
+  /// query to the server and returns its serialized response
+  pub fn retrieve<F>(
+    &self,
+    row_index: usize,
+    mut respond: F,
+  ) -> ResultBoxedError<Vec<u8>>
+  where
+    F: FnMut(&Query) -> ResultBoxedError<Vec<u8>>,
+  {
+    self.retry(|| {
+      let mut qp = generate_index_query_params(&self.cp, &self.params)?;
+      let q = qp.generate_query(row_index)?;
+      let resp: Response = bincode::deserialize(&respond(&q)?)?;
+      Ok(Some(qp.decode_with_margin(&resp)))
+    })
+  }
+}
+
+impl ReliableClient<KVParams> {
+  /// Retrieves the value stored for `key`, where `respond` sends a query
+  /// to the server and returns its serialized response
+  pub fn retrieve<F>(
+    &self,
+    key: &HashedKey,
+    mut respond: F,
+  ) -> ResultBoxedError<Vec<u8>>
+  where
+    F: FnMut(&Query) -> ResultBoxedError<Vec<u8>>,
+  {
+    self.retry(|| {
+      let mut qp = generate_kv_query_params(&self.cp, &self.params)?;
+      let q = qp.generate_query(key)?;
+      let resp: Response = bincode::deserialize(&respond(&q)?)?;
+      Ok(qp.decode_with_margin(&resp, key).ok())
+    })
+  }
+}
+
+/// The value retrieved for a key by a `KvPirClient`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KvValue {
+  /// The value stored for the key
+  Found(Vec<u8>),
+  /// The value failed the value check, or could not be decompressed
+  Missing,
+  /// The value expired at the given Unix timestamp (see `expiry`)
+  Expired(u64),
+}
+
+impl KvValue {
+  /// Returns the value, if it was found
+  pub fn found(&self) -> Option<&[u8]> {
+    match self {
+      Self::Found(value) => Some(value),
+      _ => None,
+    }
+  }
+
+  /// Same as `found`, taking ownership of the value
+  pub fn into_found(self) -> Option<Vec<u8>> {
+    match self {
+      Self::Found(value) => Some(value),
+      _ => None,
+    }
+  }
+}
+
+/// A `KvPirClient` retrieves the values of multiple keys from a KV DB,
+/// managing a fresh `QueryParams` for each key.
+///
+/// KV DBs return a (random-looking) value for any key, including keys
+/// that were never stored. Values are reported as `KvValue::Missing` if
+/// they fail the configured value check (e.g. a checksum embedded in the
+/// values), or cannot be decompressed. Values of DBs built with record
+/// expiry are reported as `KvValue::Expired` once they expire.
+pub struct KvPirClient {
+  cp: CommonParams,
+  params: KVParams,
+  batch_size: Option<usize>,
+  value_check: Option<fn(&[u8]) -> bool>,
+}
+
+impl KvPirClient {
+  /// Creates a new `KvPirClient`, expanding `CommonParams` from `params`.
+  /// By default all queries are sent in a single batch.
+  pub fn new(params: KVParams) -> Self {
+    Self {
+      cp: CommonParams::from(&params),
+      params,
+      batch_size: None,
+      value_check: None,
+    }
+  }
+
+  /// Limits the number of queries that are sent in a single batch
+  pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+    self.batch_size = Some(batch_size.max(1));
+    self
+  }
+
+  /// Sets a check that decoded values must pass to be reported
+  pub fn with_value_check(mut self, check: fn(&[u8]) -> bool) -> Self {
+    self.value_check = Some(check);
+    self
+  }
+
+  /// Returns the public parameters
+  pub fn get_params(&self) -> &KVParams {
+    &self.params
+  }
+
+  /// Replaces the public parameters (see `ReliableClient::set_params`)
+  pub fn set_params(&mut self, params: KVParams) {
+    if needs_new_common_params(&self.params, &params) {
+      self.cp = CommonParams::from(&params);
+    }
+    self.params = params;
+  }
+
+  /// Checks that the filter params are of the filter epoch advertised in
+  /// `info` (see `KVShard::from_encoded_strings_for_epoch`), returning an
+  /// `ErrorFilterEpochMismatch` if the params are stale and should be
+  /// downloaded again
+  pub fn check_shard_info(&self, info: &ShardInfo) -> ResultBoxedError<()> {
+    match info.filter_epoch {
+      Some(epoch) => self.params.get_filter_params().check_epoch(epoch),
+      None => Ok(()),
+    }
+  }
+
+  /// Retrieves the values stored for `keys` (the bytes of the key strings
+  /// used to build the DB), where `respond` sends a batch of queries to
+  /// the server and returns their serialized responses in order.
+  /// Duplicate keys are only queried once. Values that were split into
+  /// chunks (see `KVParams::get_chunks`) are reassembled.
+  pub fn get_many<F>(
+    &self,
+    keys: &[Vec<u8>],
+    respond: F,
+  ) -> ResultBoxedError<BTreeMap<Vec<u8>, KvValue>>
+  where
+    F: FnMut(&[Query]) -> ResultBoxedError<Vec<Vec<u8>>>,
+  {
+    match self.params.get_chunks() {
+      Some(max_chunks) => self.get_many_chunked(keys, max_chunks, respond),
+      None => self.get_many_checked(keys, respond, self.value_check),
+    }
+  }
+
+  /// Same as `get_many`, for a DB whose values were split into
+  /// `max_chunks` chunks, reassembling each value from its chunks. All
+  /// `max_chunks` chunks are queried for every key, so that the server
+  /// does not learn the length of the values. The value check applies to
+  /// the reassembled values, and a value is reported as expired if any
+  /// of its chunks is.
+  fn get_many_chunked<F>(
+    &self,
+    keys: &[Vec<u8>],
+    max_chunks: usize,
+    respond: F,
+  ) -> ResultBoxedError<BTreeMap<Vec<u8>, KvValue>>
+  where
+    F: FnMut(&[Query]) -> ResultBoxedError<Vec<Vec<u8>>>,
+  {
+    // the chunks of each key are removed once its value is reassembled,
+    // so duplicate keys are skipped
+    let unique: BTreeSet<&Vec<u8>> = keys.iter().collect();
+    let chunk_keys: Vec<Vec<u8>> = unique
+      .iter()
+      .flat_map(|key| (0..max_chunks).map(move |i| chunk_key(key, i)))
+      .collect();
+    let mut chunks = self.get_many_checked(&chunk_keys, respond, None)?;
+    let values = unique
+      .into_iter()
+      .map(|key| {
+        let mut parts = Vec::with_capacity(max_chunks);
+        let mut value = None;
+        for i in 0..max_chunks {
+          match chunks.remove(&chunk_key(key, i)) {
+            Some(KvValue::Found(part)) => parts.push(part),
+            Some(KvValue::Expired(expires_at)) => {
+              value = Some(KvValue::Expired(expires_at));
+              break;
+            }
+            _ => value = Some(KvValue::Missing),
+          }
+        }
+        let value = value.unwrap_or_else(|| {
+          join_chunks(&parts)
+            .filter(|v| passes_check(self.value_check, v))
+            .map_or(KvValue::Missing, KvValue::Found)
+        });
+        (key.to_vec(), value)
+      })
+      .collect();
+    Ok(values)
+  }
+
+  fn get_many_checked<F>(
+    &self,
+    keys: &[Vec<u8>],
+    mut respond: F,
+    value_check: Option<fn(&[u8]) -> bool>,
+  ) -> ResultBoxedError<BTreeMap<Vec<u8>, KvValue>>
+  where
+    F: FnMut(&[Query]) -> ResultBoxedError<Vec<Vec<u8>>>,
+  {
+    let unique: BTreeSet<&Vec<u8>> = keys.iter().collect();
+    let unique: Vec<&Vec<u8>> = unique.into_iter().collect();
+    let batch_size = self.batch_size.unwrap_or(unique.len()).max(1);
+    let mut values = BTreeMap::new();
+    for batch in unique.chunks(batch_size) {
+      let hashed = batch
+        .iter()
+        .map(|key| HashedKey::from_bytes(key))
+        .collect::<Vec<HashedKey>>();
+      let evals = self
+        .params
+        .get_filter_params()
+        .get_hash_evals_batch(&hashed);
+      let mut pending = Vec::with_capacity(batch.len());
+      let mut queries = Vec::with_capacity(batch.len());
+      for ((key, hashed), indices) in batch.iter().zip(hashed).zip(evals) {
+        let mut qp = generate_kv_query_params(&self.cp, &self.params)?;
+        queries.push(qp.generate_query_for_hash_evals(&indices)?);
+        pending.push((key, hashed, qp));
+      }
+      let responses = respond(&queries)?;
+      if responses.len() != queries.len() {
+        return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+          "{} responses for {} queries",
+          responses.len(),
+          queries.len()
+        ))));
+      }
+      for ((key, hashed, qp), d_resp) in pending.into_iter().zip(responses) {
+        let resp: Response = bincode::deserialize(&d_resp)?;
+        let value = match qp.parse_resp_as_record(&resp, &hashed) {
+          Ok(v) if passes_check(value_check, &v) => KvValue::Found(v),
+          Ok(_) => KvValue::Missing,
+          Err(e) => match e.downcast_ref::<ErrorRecordExpired>() {
+            Some(expired) => KvValue::Expired(expired.expires_at()),
+            None => KvValue::Missing,
+          },
+        };
+        values.insert(key.to_vec(), value);
+      }
+    }
+    Ok(values)
+  }
+}
+
+/// A `PirClient` retrieves DB elements from a server over a
+/// `PirTransport`, wrapping a `ReliableClient` (for index DBs) or a
+/// `KvPirClient` (for KV DBs).
+///
+/// The params are downloaded from the server once, and `CommonParams`
+/// are expanded from them once. Both are cached until the params
+/// expire (or are within the refresh margin of their expiry, see
+/// `with_refresh_margin`), after which they are downloaded again before
+/// the next query. Failed decodings are retried (see `ReliableClient`),
+/// and KV lookups are batched (see `KvPirClient`), as configured on the
+/// wrapped client.
+///
+/// Clients connected with a verifying key additionally check that every
+/// download of params is covered by a manifest signed with the key (see
+/// `manifest`).
+pub struct PirClient<T, C> {
+  transport: T,
+  client: C,
+  refresh_margin: Duration,
+  verifier: Option<ManifestVerifier>,
+}
+
+impl<T: PirTransport, C> PirClient<T, C> {
+  /// Returns the wrapped client
+  pub fn get_client(&self) -> &C {
+    &self.client
+  }
+
+  /// Configures the wrapped client, e.g. to set the retry budget
+  pub fn map_client<F: FnOnce(C) -> C>(mut self, f: F) -> Self {
+    self.client = f(self.client);
+    self
+  }
+
+  /// Sets how long before expiry the params are downloaded again, e.g. to
+  /// tolerate clock skew with a server that rotates its DB when the params
+  /// expire. Defaults to zero.
+  pub fn with_refresh_margin(mut self, refresh_margin: Duration) -> Self {
+    self.refresh_margin = refresh_margin;
+    self
+  }
+
+  /// Returns whether the params must be downloaded before the next query
+  fn needs_refresh<P: BaseParams>(&self, params: &P) -> bool {
+    expires_within(params, SystemTime::now(), self.refresh_margin)
+  }
+
+  /// Returns the transport
+  pub fn get_transport_mut(&mut self) -> &mut T {
+    &mut self.transport
+  }
+}
+
+impl<T: PirTransport> PirClient<T, ReliableClient<IndexParams>> {
+  /// Downloads the params of an index DB over `transport`
+  pub fn connect_index(transport: T) -> ResultBoxedError<Self> {
+    Self::connect_index_with(transport, None)
+  }
+
+  /// Same as `connect_index`, only accepting params that are covered by a
+  /// manifest signed with `key`
+  pub fn connect_index_verified(
+    transport: T,
+    key: VerifyingKey,
+  ) -> ResultBoxedError<Self> {
+    Self::connect_index_with(transport, Some(ManifestVerifier::new(key)))
+  }
+
+  fn connect_index_with(
+    mut transport: T,
+    mut verifier: Option<ManifestVerifier>,
+  ) -> ResultBoxedError<Self> {
+    let params = fetch_params(&mut transport, verifier.as_mut())?;
+    Ok(Self {
+      transport,
+      client: ReliableClient::new(params),
+      refresh_margin: Duration::ZERO,
+      verifier,
+    })
+  }
+
+  /// Downloads the params again
+  pub fn refresh_params(&mut self) -> ResultBoxedError<()> {
+    let params = fetch_params(&mut self.transport, self.verifier.as_mut())?;
+    self.client.set_params(params);
+    Ok(())
+  }
+
+  /// Retrieves the DB element at `row_index`
+  pub fn retrieve(&mut self, row_index: usize) -> ResultBoxedError<Vec<u8>> {
+    if self.needs_refresh(self.client.get_params()) {
+      self.refresh_params()?;
+    }
+    let transport = &mut self.transport;
+    self.client.retrieve(row_index, |q| transport.send_query(q))
+  }
+}
+
+impl<T: PirTransport> PirClient<T, KvPirClient> {
+  /// Downloads the params of a KV DB over `transport`
+  pub fn connect_kv(transport: T) -> ResultBoxedError<Self> {
+    Self::connect_kv_with(transport, None)
+  }
+
+  /// Same as `connect_kv`, only accepting params that are covered by a
+  /// manifest signed with `key`
+  pub fn connect_kv_verified(
+    transport: T,
+    key: VerifyingKey,
+  ) -> ResultBoxedError<Self> {
+    Self::connect_kv_with(transport, Some(ManifestVerifier::new(key)))
+  }
+
+  fn connect_kv_with(
+    mut transport: T,
+    mut verifier: Option<ManifestVerifier>,
+  ) -> ResultBoxedError<Self> {
+    let params = fetch_params(&mut transport, verifier.as_mut())?;
+    Ok(Self {
+      transport,
+      client: KvPirClient::new(params),
+      refresh_margin: Duration::ZERO,
+      verifier,
+    })
+  }
+
+  /// Downloads the params again
+  pub fn refresh_params(&mut self) -> ResultBoxedError<()> {
+    let params = fetch_params(&mut self.transport, self.verifier.as_mut())?;
+    self.client.set_params(params);
+    Ok(())
+  }
+
+  /// Retrieves the value stored for `key` (see `KvPirClient::get_many`)
+  pub fn get(&mut self, key: &[u8]) -> ResultBoxedError<KvValue> {
+    let key = key.to_vec();
+    let mut values = self.get_many(std::slice::from_ref(&key))?;
+    Ok(values.remove(&key).unwrap_or(KvValue::Missing))
+  }
+
+  /// Retrieves the values stored for `keys`, sending each batch of
+  /// queries over the transport (see `KvPirClient::get_many`)
+  pub fn get_many(
+    &mut self,
+    keys: &[Vec<u8>],
+  ) -> ResultBoxedError<BTreeMap<Vec<u8>, KvValue>> {
+    if self.needs_refresh(self.client.get_params()) {
+      self.refresh_params()?;
+    }
+    let transport = &mut self.transport;
+    self
+      .client
+      .get_many(keys, |queries| transport.send_queries(queries))
+  }
+}
+
+/// Downloads and parses the params served over `transport`, checking
+/// them against the served manifest if a `verifier` is given
+fn fetch_params<T: PirTransport, P: DeserializeOwned + Serialize>(
+  transport: &mut T,
+  verifier: Option<&mut ManifestVerifier>,
+) -> ResultBoxedError<P> {
+  let params = serde_json::from_slice(&transport.fetch_params()?)?;
+  if let Some(verifier) = verifier {
+    verifier.verify(&transport.fetch_manifest()?, &params)?;
+  }
+  Ok(params)
+}
+
+/// Returns whether `value` passes `check`, if one is set
+fn passes_check(check: Option<fn(&[u8]) -> bool>, value: &[u8]) -> bool {
+  match check {
+    Some(check) => check(value),
+    None => true,
+  }
+}
+
+/// Returns whether `CommonParams` must be rederived when switching from
+/// `old` to `new` params
+fn needs_new_common_params<P: BaseParams>(old: &P, new: &P) -> bool {
+  new.get_public_seed() != old.get_public_seed()
+    || new.get_dim() != old.get_dim()
+    || new.get_total_records() != old.get_total_records()
+}
+
+/// The client state of a query that has been sent, but whose response has
+/// not yet been received
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum PendingQuery {
+  Index {
+    qp: QueryParams<IndexDatabase, EmptyAuxParams>,
+    row_index: usize,
+  },
+  KV {
+    qp: QueryParams<KVDatabase, FilterParams>,
+    key: HashedKey,
+  },
+}
+
+/// A `ClientSession` tracks in-flight queries by id, and can be persisted
+/// so that a client (e.g. a mobile app) survives a process restart
+/// between sending a query and receiving its response.
+///
+/// The persisted session contains the `QueryParams` of every pending
+/// query, from which the queried rows or keys can be recovered, so it
+/// must be stored with the same care as the client secrets.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ClientSession {
+  next_id: u64,
+  pending: BTreeMap<u64, PendingQuery>,
+}
+
+impl Persist for ClientSession {
+  const KIND: &'static str = "ClientSession";
+
+  fn summary(&self) -> serde_json::Value {
+    serde_json::json!({ "pending": self.pending.len() })
+  }
+}
+
+impl ClientSession {
+  /// Creates a session without pending queries
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Prepares a query for `row_index` of an index DB, returning its id
+  pub fn query_index(
+    &mut self,
+    cp: &CommonParams,
+    params: &IndexParams,
+    row_index: usize,
+  ) -> ResultBoxedError<(u64, Query)> {
+    let mut qp = generate_index_query_params(cp, params)?;
+    let q = qp.generate_query(row_index)?;
+    Ok((self.insert(PendingQuery::Index { qp, row_index }), q))
+  }
+
+  /// Prepares a query for `key` (the bytes of the key string used to
+  /// build the DB) of a KV DB, returning its id
+  pub fn query_kv(
+    &mut self,
+    cp: &CommonParams,
+    params: &KVParams,
+    key: &[u8],
+  ) -> ResultBoxedError<(u64, Query)> {
+    let key = HashedKey::from_bytes(key);
+    let mut qp = generate_kv_query_params(cp, params)?;
+    let q = qp.generate_query(&key)?;
+    Ok((self.insert(PendingQuery::KV { qp, key }), q))
+  }
+
+  /// Returns the ids of the pending queries
+  pub fn pending_ids(&self) -> Vec<u64> {
+    self.pending.keys().copied().collect()
+  }
+
+  /// Returns the pending query with `id`
+  pub fn get_pending(&self, id: u64) -> Option<&PendingQuery> {
+    self.pending.get(&id)
+  }
+
+  /// Decodes the serialized response to the pending query with `id` into
+  /// its record, and removes the query from the session
+  pub fn complete(
+    &mut self,
+    id: u64,
+    resp: &[u8],
+  ) -> ResultBoxedError<Vec<u8>> {
+    let pending = self
+      .pending
+      .get(&id)
+      .ok_or_else(|| format!("No pending query with id {}", id))?;
+    let resp: Response = bincode::deserialize(resp)?;
+    let record = match pending {
+      PendingQuery::Index { qp, .. } => qp.parse_resp_as_record(&resp)?,
+      PendingQuery::KV { qp, key } => qp.parse_resp_as_record(&resp, key)?,
+    };
+    self.pending.remove(&id);
+    Ok(record)
+  }
+
+  /// Removes the pending query with `id`, e.g. after a timeout. Returns
+  /// whether the query was pending.
+  pub fn cancel(&mut self, id: u64) -> bool {
+    self.pending.remove(&id).is_some()
+  }
+
+  /// Writes the session to `path`. The session is first written to a
+  /// temporary file that is then renamed, so that a crash while writing
+  /// never leaves a truncated session behind.
+  pub fn write_to_file(&self, path: &str) -> ResultBoxedError<()> {
+    let tmp_path = format!("{}.tmp", path);
+    write_persisted(&tmp_path, self)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+  }
+
+  /// Loads a session that was written using `write_to_file`
+  pub fn load(path: &str) -> ResultBoxedError<Self> {
+    Ok(read_persisted(path)?)
+  }
+
+  fn insert(&mut self, pending: PendingQuery) -> u64 {
+    let id = self.next_id;
+    self.next_id += 1;
+    self.pending.insert(id, pending);
+    id
+  }
+}
+
+/// A `CoverTrafficScheduler` decides when a client should send dummy
+/// queries, so that the timing of real queries is hidden among decoys.
+///
+/// Dummy queries are scheduled as a Poisson process: the delays between
+/// them are sampled from an exponential distribution with the configured
+/// mean, which makes the decoy schedule memoryless.
+pub struct CoverTrafficScheduler {
+  cp: CommonParams,
+  mean_interval: Duration,
+  next_at: Instant,
+}
+
+impl CoverTrafficScheduler {
+  /// Creates a scheduler that produces dummy queries for the DB described
+  /// by `cp`, with `mean_interval` between them on average
+  pub fn new(cp: CommonParams, mean_interval: Duration) -> Self {
+    let mut scheduler = Self {
+      cp,
+      mean_interval,
+      next_at: Instant::now(),
+    };
+    scheduler.next_at += scheduler.sample_delay();
+    scheduler
+  }
+
+  /// Creates a scheduler that produces `rate` dummy queries per second
+  /// on average
+  pub fn with_rate(cp: CommonParams, rate: f64) -> ResultBoxedError<Self> {
+    if !(rate > 0.0 && rate.is_finite()) {
+      return Err(format!("Invalid cover traffic rate: {}", rate).into());
+    }
+    Ok(Self::new(cp, Duration::from_secs_f64(1.0 / rate)))
+  }
+
+  /// Returns the time at which the next dummy query is due
+  pub fn next_at(&self) -> Instant {
+    self.next_at
+  }
+
+  /// Returns a dummy query if one is due at `now`, scheduling the next
+  /// one. Clients should call this periodically (e.g. sleeping until
+  /// `next_at`) and send any returned query like a real one, discarding
+  /// the response.
+  pub fn poll(&mut self, now: Instant) -> ResultBoxedError<Option<Query>> {
+    if now < self.next_at {
+      return Ok(None);
+    }
+    self.next_at = now + self.sample_delay();
+    Ok(Some(generate_dummy_query(&self.cp)?))
+  }
+
+  /// Samples an exponentially distributed delay
+  fn sample_delay(&self) -> Duration {
+    // uniform in (0, 1]
+    let u = (OsRng.next_u64() >> 11) as f64 + 1.0;
+    let u = u / (1u64 << 53) as f64;
+    self.mean_interval.mul_f64(-u.ln())
+  }
+}
+
+/// Returns whether `params` expire within `margin` of `now`, which is
+/// the case for any margin beyond the range of `SystemTime`
+fn expires_within<P: BaseParams>(
+  params: &P,
+  now: SystemTime,
+  margin: Duration,
+) -> bool {
+  match now.checked_add(margin) {
+    Some(t) => params.is_expired_at(t),
+    None => true,
+  }
+}
+
+/// A `HintManager` keeps the public parameters (and the `CommonParams`
+/// derived from them) that a client uses for queries up to date.
+///
+/// Params are fetched from a caller-provided source (e.g. a download from
+/// the server), and refetched once they are within the refresh margin of
+/// their expiry time, or older than the configured maximum age. If a
+/// refresh fails, its error is returned instead of query params, and the
+/// refresh is retried on the next call. Expired params are never used.
+pub struct HintManager<P> {
+  source: Box<dyn FnMut() -> ResultBoxedError<P>>,
+  params: P,
+  cp: CommonParams,
+  fetched_at: Instant,
+  refresh_margin: Duration,
+  max_age: Option<Duration>,
+}
+
+impl<P: BaseParams> HintManager<P> {
+  /// Creates a new `HintManager`, fetching the initial params from
+  /// `source`
+  pub fn new<F>(mut source: F) -> ResultBoxedError<Self>
+  where
+    F: FnMut() -> ResultBoxedError<P> + 'static,
+  {
+    let params = source()?;
+    Ok(Self {
+      source: Box::new(source),
+      cp: CommonParams::from(&params),
+      params,
+      fetched_at: Instant::now(),
+      refresh_margin: DEFAULT_REFRESH_MARGIN,
+      max_age: None,
+    })
+  }
+
+  /// Sets how long before expiry the params are refreshed
+  pub fn with_refresh_margin(mut self, refresh_margin: Duration) -> Self {
+    self.refresh_margin = refresh_margin;
+    self
+  }
+
+  /// Sets an age after which the params are refreshed, regardless of
+  /// their expiry time
+  pub fn with_max_age(mut self, max_age: Duration) -> Self {
+    self.max_age = Some(max_age);
+    self
+  }
+
+  /// Returns the current params
+  pub fn get_params(&self) -> &P {
+    &self.params
+  }
+
+  /// Returns the `CommonParams` derived from the current params
+  pub fn get_common_params(&self) -> &CommonParams {
+    &self.cp
+  }
+
+  /// Returns the time since the current params were fetched
+  pub fn age(&self) -> Duration {
+    self.fetched_at.elapsed()
+  }
+
+  /// Returns whether the params should be refreshed at `now`
+  pub fn needs_refresh(&self, now: SystemTime) -> bool {
+    if self.max_age.is_some_and(|max_age| self.age() >= max_age) {
+      return true;
+    }
+    expires_within(&self.params, now, self.refresh_margin)
+  }
+
+  /// Fetches new params from the source. `CommonParams` are only
+  /// rederived if the public seed or dimensions changed.
+  pub fn refresh(&mut self) -> ResultBoxedError<()> {
+    let params = (self.source)()?;
+    if needs_new_common_params(&self.params, &params) {
+      self.cp = CommonParams::from(&params);
+    }
+    self.params = params;
+    self.fetched_at = Instant::now();
+    Ok(())
+  }
+
+  /// Refreshes the params if needed, and checks that they have not
+  /// expired
+  fn ensure_fresh(&mut self) -> ResultBoxedError<()> {
+    let now = SystemTime::now();
+    if self.needs_refresh(now) {
+      // the current params are kept on failure, so that the refresh is
+      // retried on the next call
+      self.refresh()?;
+    }
+    if self.params.is_expired_at(now) {
+      return Err(Box::new(ErrorParamsExpired {}));
+    }
+    Ok(())
+  }
+}
+
+impl HintManager<IndexParams> {
+  /// Returns fresh `QueryParams` for the current (unexpired) params
+  pub fn generate_query_params(
+    &mut self,
+  ) -> ResultBoxedError<QueryParams<IndexDatabase, EmptyAuxParams>> {
+    self.ensure_fresh()?;
+    generate_index_query_params(&self.cp, &self.params)
+  }
+}
+
+impl HintManager<KVParams> {
+  /// Returns fresh `QueryParams` for the current (unexpired) params
+  pub fn generate_query_params(
+    &mut self,
+  ) -> ResultBoxedError<QueryParams<KVDatabase, FilterParams>> {
+    self.ensure_fresh()?;
+    generate_kv_query_params(&self.cp, &self.params)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::{EncodingConfig, KVShard, PirShard, Shard};
+  use crate::errors::ErrorFilterEpochMismatch;
+  use crate::test_utils::{index_shard, kv_shard, TempPath, M};
+
+  #[test]
+  fn reliable_client_retrieve_and_exhaust() {
+    let (db_eles, shard) = index_shard();
+    let client = ReliableClient::new(shard.get_base_params().clone());
+    let bytes = client.retrieve(7, |q| shard.respond(q)).unwrap();
+    assert_eq!(base64::encode(bytes), db_eles[7]);
+
+    // errors other than decoding failures are not retried
+    let mut calls = 0;
+    let res = client.retrieve(7, |_| {
+      calls += 1;
+      Err("server unreachable".into())
+    });
+    assert!(!res.unwrap_err().is::<ErrorRetriesExhausted>());
+    assert_eq!(calls, 1);
+
+    // an unreachable margin exhausts the retry budget
+    let mut calls = 0;
+    let client = client.with_min_margin(u32::MAX).with_max_retries(2);
+    let res = client.retrieve(7, |q| {
+      calls += 1;
+      shard.respond(q)
+    });
+    assert!(res.is_err());
+    assert_eq!(calls, 3);
+  }
+
+  #[test]
+  fn kv_pir_client_get_many() {
+    let elem_size = 2u32.pow(8) as usize;
+    let keys: Vec<String> = (0..64).map(|i| format!("key-{}", i)).collect();
+    // values start with a zero byte, which is used to detect absent keys
+    let values: Vec<String> = (0..64)
+      .map(|_| {
+        let mut ele = vec![0u8; elem_size / 8];
+        OsRng.fill_bytes(&mut ele[1..]);
+        base64::encode(ele)
+      })
+      .collect();
+    let shard =
+      KVShard::from_base64_strings(&keys, &values, 512, elem_size, 10).unwrap();
+    let client = KvPirClient::new(shard.get_base_params().clone())
+      .with_batch_size(2)
+      .with_value_check(|v| v[0] == 0);
+
+    let requested: Vec<Vec<u8>> = [&keys[3], &keys[7], &keys[3]]
+      .iter()
+      .map(|k| k.as_bytes().to_vec())
+      .chain([b"missing".to_vec(), b"absent".to_vec()])
+      .collect();
+    let mut batches = Vec::new();
+    let values_by_key = client
+      .get_many(&requested, |queries| {
+        batches.push(queries.len());
+        queries.iter().map(|q| shard.respond(q)).collect()
+      })
+      .unwrap();
+    assert_eq!(batches, [2, 2]);
+    assert_eq!(values_by_key.len(), 4);
+    for i in [3, 7] {
+      let value = values_by_key[keys[i].as_bytes()].found().unwrap();
+      assert_eq!(base64::encode(value), values[i]);
+    }
+    // absent keys fail the check, except with probability 2^-8 each
+    let absent = [b"missing".as_slice(), b"absent".as_slice()]
+      .iter()
+      .filter(|k| values_by_key[**k] == KvValue::Missing)
+      .count();
+    assert!(absent >= 1);
+  }
+
+  #[test]
+  fn kv_pir_client_reassembles_chunked_values() {
+    let elem_size = 2u32.pow(8) as usize;
+    let keys: Vec<String> = (0..16).map(|i| format!("key-{}", i)).collect();
+    let values: Vec<Vec<u8>> = (0..16)
+      .map(|i| {
+        let mut ele = vec![0u8; 7 * i];
+        OsRng.fill_bytes(&mut ele);
+        ele
+      })
+      .collect();
+    let encoded: Vec<String> = values.iter().map(base64::encode).collect();
+    // values that fit are not split into chunks
+    let shard = KVShard::from_base64_strings(
+      &keys[..5],
+      &encoded[..5],
+      512,
+      elem_size,
+      10,
+    )
+    .unwrap();
+    assert_eq!(shard.get_base_params().get_chunks(), None);
+    // chunks hold 28 bytes of the value, after the length prefix
+    let shard =
+      KVShard::from_base64_strings(&keys, &encoded, 512, elem_size, 10)
+        .unwrap();
+    assert_eq!(shard.get_base_params().get_chunks(), Some(4));
+    let client = KvPirClient::new(shard.get_base_params().clone());
+
+    let requested: Vec<Vec<u8>> = [&keys[0], &keys[4], &keys[15]]
+      .iter()
+      .map(|k| k.as_bytes().to_vec())
+      .chain([b"missing".to_vec()])
+      .collect();
+    let mut queried = 0;
+    let values_by_key = client
+      .get_many(&requested, |queries| {
+        queried += queries.len();
+        queries.iter().map(|q| shard.respond(q)).collect()
+      })
+      .unwrap();
+    // every key is queried for all chunks, whatever its length
+    assert_eq!(queried, 16);
+    for i in [0, 4, 15] {
+      let value = values_by_key[keys[i].as_bytes()].found().unwrap();
+      assert_eq!(value, values[i]);
+    }
+    assert_eq!(values_by_key[b"missing".as_slice()], KvValue::Missing);
+
+    // repeated keys are queried once, and keep their value
+    let repeated = vec![keys[4].as_bytes().to_vec(); 2];
+    let mut queried = 0;
+    let values_by_key = client
+      .get_many(&repeated, |queries| {
+        queried += queries.len();
+        queries.iter().map(|q| shard.respond(q)).collect()
+      })
+      .unwrap();
+    assert_eq!(queried, 4);
+    assert_eq!(values_by_key.len(), 1);
+    assert_eq!(
+      values_by_key[keys[4].as_bytes()],
+      KvValue::Found(values[4].clone())
+    );
+  }
+
+  #[test]
+  fn stale_filter_epochs_are_detected() {
+    let elem_size = 2u32.pow(8) as usize;
+    let keys: Vec<String> = (0..32).map(|i| format!("key-{}", i)).collect();
+    let values: Vec<String> = (0..32)
+      .map(|_| {
+        let mut ele = vec![0u8; elem_size / 8];
+        OsRng.fill_bytes(&mut ele);
+        base64::encode(ele)
+      })
+      .collect();
+    let build = |epoch| {
+      KVShard::from_encoded_strings_for_epoch(
+        &keys,
+        &values,
+        512,
+        elem_size,
+        10,
+        EncodingConfig::Standard,
+        [7u8; 32],
+        epoch,
+      )
+      .unwrap()
+    };
+    let (initial, next) = (build(0), build(1));
+    let seeded = KVShard::from_encoded_strings_with_seed(
+      &keys,
+      &values,
+      512,
+      elem_size,
+      10,
+      EncodingConfig::Standard,
+      [7u8; 32],
+    )
+    .unwrap();
+    assert_eq!(
+      initial.params_digest().unwrap(),
+      seeded.params_digest().unwrap()
+    );
+    let (fp, next_fp) = (
+      initial.get_base_params().get_filter_params(),
+      next.get_base_params().get_filter_params(),
+    );
+    assert_ne!(fp.seed, next_fp.seed);
+    assert_eq!((fp.epoch, next_fp.epoch), (0, 1));
+
+    let info = ShardInfo::new(&next, 0).unwrap();
+    assert_eq!(info.filter_epoch, Some(1));
+    let mut client = KvPirClient::new(initial.get_base_params().clone());
+    let err = client.check_shard_info(&info).unwrap_err();
+    let err = err.downcast_ref::<ErrorFilterEpochMismatch>().unwrap();
+    assert_eq!((err.expected, err.found), (1, 0));
+
+    client.set_params(next.get_base_params().clone());
+    client.check_shard_info(&info).unwrap();
+    let key = keys[5].as_bytes().to_vec();
+    let values_by_key = client
+      .get_many(std::slice::from_ref(&key), |queries| {
+        queries.iter().map(|q| next.respond(q)).collect()
+      })
+      .unwrap();
+    assert_eq!(
+      base64::encode(values_by_key[&key].found().unwrap()),
+      values[5]
+    );
+  }
+
+  #[test]
+  fn client_session_survives_restart() {
+    let (db_eles, shard) = index_shard();
+    let (keys, values, kv_shard) = kv_shard();
+    let (bp, kv_bp) = (shard.get_base_params(), kv_shard.get_base_params());
+    let (cp, kv_cp) = (CommonParams::from(bp), CommonParams::from(kv_bp));
+
+    let mut session = ClientSession::new();
+    let (id, q) = session.query_index(&cp, bp, 6).unwrap();
+    let (kv_id, kv_q) =
+      session.query_kv(&kv_cp, kv_bp, keys[9].as_bytes()).unwrap();
+    let file = TempPath::new("client-session.json");
+    let path = file.as_str();
+    session.write_to_file(path).unwrap();
+    drop(session);
+
+    let mut session = ClientSession::load(path).unwrap();
+    assert_eq!(session.pending_ids(), [id, kv_id]);
+    let record = session.complete(id, &shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(base64::encode(record), db_eles[6]);
+    let record = session
+      .complete(kv_id, &kv_shard.respond(&kv_q).unwrap())
+      .unwrap();
+    assert_eq!(base64::encode(record), values[9]);
+    assert!(session.pending_ids().is_empty());
+    assert!(session.complete(id, &[]).is_err());
+  }
+
+  #[test]
+  fn cover_traffic_scheduler_emits_dummy_queries() {
+    let (_, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    assert!(
+      CoverTrafficScheduler::with_rate(CommonParams::from(bp), 0.0).is_err()
+    );
+    let mut scheduler = CoverTrafficScheduler::with_rate(cp, 10.0).unwrap();
+    let next_at = scheduler.next_at();
+    let before = next_at - Duration::from_nanos(1);
+    assert!(scheduler.poll(before).unwrap().is_none());
+    let q = scheduler.poll(next_at).unwrap().unwrap();
+    assert_eq!(q.as_slice().len(), M);
+    assert!(shard.respond(&q).is_ok());
+    assert!(scheduler.next_at() >= next_at);
+
+    // a dummy query uses up the query params
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_index_query_params(&cp, bp).unwrap();
+    assert!(qp.generate_dummy_query().is_ok());
+    assert!(qp.generate_query(0).is_err());
+  }
+
+  #[test]
+  fn hint_manager_refreshes_and_rejects_expired_params() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::UNIX_EPOCH;
+
+    let (db_eles, shard) = index_shard();
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap()
+      .as_secs();
+    let mut expired = shard.get_base_params().clone();
+    expired.set_expires_at(Some(now - 1));
+    let mut fresh = shard.get_base_params().clone();
+    fresh.set_expires_at(Some(now + 3600));
+
+    let served = Rc::new(RefCell::new(Some(expired)));
+    let source = served.clone();
+    let mut manager = HintManager::new(move || {
+      source
+        .borrow()
+        .clone()
+        .ok_or_else(|| "params unavailable".into())
+    })
+    .unwrap();
+    let err = manager.generate_query_params().err().unwrap();
+    assert!(err.is::<ErrorParamsExpired>());
+
+    // failed refreshes are reported
+    *served.borrow_mut() = None;
+    let err = manager.generate_query_params().err().unwrap();
+    assert_eq!(err.to_string(), "params unavailable");
+
+    *served.borrow_mut() = Some(fresh);
+    let mut qp = manager.generate_query_params().unwrap();
+    assert_eq!(manager.get_params().get_expires_at(), Some(now + 3600));
+    let q = qp.generate_query(9).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), db_eles[9]);
+    assert!(!manager.needs_refresh(SystemTime::now()));
+    assert!(
+      manager.needs_refresh(SystemTime::now() + Duration::from_secs(3600))
+    );
+    let mut never = shard.get_base_params().clone();
+    never.set_expires_at(Some(u64::MAX));
+    assert!(!never.is_expired_at(SystemTime::now()));
+    let manager = manager.with_refresh_margin(Duration::MAX);
+    assert!(manager.needs_refresh(SystemTime::now()));
+  }
+
+  #[test]
+  fn pir_client_over_in_memory_transport() {
+    use crate::transport::InMemoryTransport;
+    let m = 2u32.pow(8) as usize;
+    let elem_size = 2u32.pow(8) as usize;
+    let db_eles: Vec<String> =
+      (0..m).map(|i| base64::encode([i as u8; 32])).collect();
+    let shard =
+      Shard::from_base64_strings(&db_eles, 512, m, elem_size, 10).unwrap();
+    let mut client =
+      PirClient::connect_index(InMemoryTransport::new(shard.clone()))
+        .unwrap()
+        .map_client(|c| c.with_max_retries(1));
+    assert_eq!(
+      client.get_client().get_params().get_public_seed(),
+      shard.get_base_params().get_public_seed()
+    );
+    for i in [0, 42, m - 1] {
+      assert_eq!(base64::encode(client.retrieve(i).unwrap()), db_eles[i]);
+    }
+    client.refresh_params().unwrap();
+    assert_eq!(base64::encode(client.retrieve(5).unwrap()), db_eles[5]);
+
+    let keys: Vec<String> = (0..64).map(|i| format!("key-{}", i)).collect();
+    let values: Vec<String> =
+      (0..64).map(|i| base64::encode([i as u8; 32])).collect();
+    let shard =
+      KVShard::from_base64_strings(&keys, &values, 512, elem_size, 10).unwrap();
+    let mut client =
+      PirClient::connect_kv(InMemoryTransport::new(shard)).unwrap();
+    let value = client
+      .get(keys[17].as_bytes())
+      .unwrap()
+      .into_found()
+      .unwrap();
+    assert_eq!(base64::encode(value), values[17]);
+  }
+}
@@ -0,0 +1,123 @@
+//! The `compression` module provides optional per-record compression,
+//! applied to DB elements before they are packed into rows of the DB
+//! matrix. Compressing records that compress well (e.g. certificates or
+//! JSON blobs) reduces the element size, and hence the row width and the
+//! size of server responses.
+//!
+//! Compressed records have varying lengths, so each one is framed as its
+//! compressed length (a little-endian u32) followed by the compressed
+//! bytes, and zero-padded to the length of the longest framed record.
+//!
+//! Snappy compression requires the `compression` feature.
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{ErrorUnexpectedInputSize, ResultBoxedError};
+
+/// The number of bytes used for the length prefix of a framed record
+const FRAME_LEN_BYTES: usize = 4;
+
+/// The compression that is applied to each record of a DB. This is stored
+/// in the public parameters so that clients know how to decode records.
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum Compression {
+  /// Records are stored as-is
+  #[default]
+  None,
+  /// Records are compressed using Snappy
+  Snappy,
+}
+
+impl Compression {
+  /// Compresses a single record
+  pub fn compress(&self, record: &[u8]) -> ResultBoxedError<Vec<u8>> {
+    match self {
+      Self::None => Ok(record.to_vec()),
+      Self::Snappy => snappy_compress(record),
+    }
+  }
+
+  /// Decompresses a single record
+  pub fn decompress(&self, compressed: &[u8]) -> ResultBoxedError<Vec<u8>> {
+    match self {
+      Self::None => Ok(compressed.to_vec()),
+      Self::Snappy => snappy_decompress(compressed),
+    }
+  }
+}
+
+/// Compresses and frames each record, returning the framed records
+/// (padded to equal length) and the resulting element size in bits. If
+/// `compression` is `Compression::None` the records are returned as-is.
+pub fn compress_records(
+  records: &[Vec<u8>],
+  compression: Compression,
+) -> ResultBoxedError<(Vec<Vec<u8>>, usize)> {
+  if compression == Compression::None {
+    let len = records.iter().map(|r| r.len()).max().unwrap_or(0);
+    return Ok((records.to_vec(), len * 8));
+  }
+  let mut framed = records
+    .iter()
+    .map(|r| {
+      let compressed = compression.compress(r)?;
+      let mut frame = (compressed.len() as u32).to_le_bytes().to_vec();
+      frame.extend(compressed);
+      Ok(frame)
+    })
+    .collect::<ResultBoxedError<Vec<Vec<u8>>>>()?;
+  let len = framed.iter().map(|f| f.len()).max().unwrap_or(0);
+  framed.iter_mut().for_each(|f| f.resize(len, 0));
+  Ok((framed, len * 8))
+}
+
+/// Removes the framing from a decoded record and decompresses it. If
+/// `compression` is `Compression::None` the record is returned as-is.
+pub fn decompress_record(
+  framed: &[u8],
+  compression: Compression,
+) -> ResultBoxedError<Vec<u8>> {
+  if compression == Compression::None {
+    return Ok(framed.to_vec());
+  }
+  if framed.len() < FRAME_LEN_BYTES {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "Framed record has {} bytes, but the length prefix needs {}",
+      framed.len(),
+      FRAME_LEN_BYTES
+    ))));
+  }
+  let mut len_bytes = [0u8; FRAME_LEN_BYTES];
+  len_bytes.copy_from_slice(&framed[..FRAME_LEN_BYTES]);
+  let len = u32::from_le_bytes(len_bytes) as usize;
+  let body = &framed[FRAME_LEN_BYTES..];
+  if len > body.len() {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "Compressed length: {}, available bytes: {}",
+      len,
+      body.len()
+    ))));
+  }
+  compression.decompress(&body[..len])
+}
+
+#[cfg(feature = "compression")]
+fn snappy_compress(record: &[u8]) -> ResultBoxedError<Vec<u8>> {
+  Ok(snap::raw::Encoder::new().compress_vec(record)?)
+}
+
+#[cfg(feature = "compression")]
+fn snappy_decompress(compressed: &[u8]) -> ResultBoxedError<Vec<u8>> {
+  Ok(snap::raw::Decoder::new().decompress_vec(compressed)?)
+}
+
+#[cfg(not(feature = "compression"))]
+fn snappy_compress(_: &[u8]) -> ResultBoxedError<Vec<u8>> {
+  Err("Snappy compression requires the `compression` feature".into())
+}
+
+#[cfg(not(feature = "compression"))]
+fn snappy_decompress(_: &[u8]) -> ResultBoxedError<Vec<u8>> {
+  Err("Snappy compression requires the `compression` feature".into())
+}
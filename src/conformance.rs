@@ -0,0 +1,824 @@
+//! The `conformance` module checks that a PIR server implements the
+//! protocol of this crate, so that third-party server implementations can
+//! be validated against it. The checks run over any `PirTransport`; the
+//! `conformance` binary runs them against a server over HTTP.
+//!
+//! The checks do not need to know the served DB: valid queries must be
+//! answered with responses that decode within the noise bound of the
+//! params, and since responses are linear in the queries, the response to
+//! the sum of two queries must be the sum of their responses. Servers
+//! that advertise noise flooding in their `ServerInfo` (see
+//! `ServingConfig::noise_flooding_bits`) are allowed to deviate from exact
+//! responses by the flooding noise. Records that are known to be served
+//! (see `ConformanceConfig::expected_rows`) are additionally compared with
+//! the decoded rows. Malformed queries must be rejected, if the transport
+//! can send them (see `PirTransport::send_raw_query`). If the server
+//! serves its `ServerInfo`, the advertised protocol versions and shard
+//! are checked against the params, and the epoch is checked to advance
+//! when the params rotate.
+use std::fmt;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::api::{
+  generate_index_query_params, generate_kv_query_params, BaseParams,
+  CommonParams, EmptyAuxParams, Modulus, Query, QueryParams, Response,
+};
+use crate::builder::noise_bound;
+use crate::db::{
+  FilterParams, IndexDatabase, IndexParams, KVDatabase, KVParams,
+};
+use crate::delta::params_digest;
+use crate::errors::{
+  ErrorOverflownAdd, ErrorTransportUnsupported, ResultBoxedError,
+};
+use crate::serving::{ServerInfo, ShardInfo, SUPPORTED_PROTOCOL_VERSIONS};
+use crate::transport::PirTransport;
+use crate::utils::lwe::get_plaintext_size;
+
+/// The outcome of a single conformance check
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheckOutcome {
+  Pass,
+  /// The server violates the protocol, for the given reason
+  Fail(String),
+  /// The check could not be run, for the given reason (e.g. because the
+  /// server does not serve its `ServerInfo`)
+  Skipped(String),
+}
+
+/// The outcome of the check `name`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckResult {
+  pub name: &'static str,
+  pub outcome: CheckOutcome,
+}
+
+/// A `ConformanceReport` holds the outcome of every check, in the order
+/// they were run
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConformanceReport {
+  pub results: Vec<CheckResult>,
+}
+impl ConformanceReport {
+  /// Returns whether no check failed. Skipped checks do not fail the
+  /// report.
+  pub fn passed(&self) -> bool {
+    self.failures() == 0
+  }
+
+  /// Returns the number of failed checks
+  pub fn failures(&self) -> usize {
+    self
+      .results
+      .iter()
+      .filter(|r| matches!(r.outcome, CheckOutcome::Fail(_)))
+      .count()
+  }
+
+  /// Returns the outcome of the check `name`, if it was run
+  pub fn outcome(&self, name: &str) -> Option<&CheckOutcome> {
+    self
+      .results
+      .iter()
+      .find(|r| r.name == name)
+      .map(|r| &r.outcome)
+  }
+
+  fn record(&mut self, name: &'static str, outcome: CheckOutcome) {
+    self.results.push(CheckResult { name, outcome });
+  }
+
+  fn record_result(&mut self, name: &'static str, res: ResultBoxedError<()>) {
+    let outcome = match res {
+      Ok(()) => CheckOutcome::Pass,
+      Err(e) => CheckOutcome::Fail(e.to_string()),
+    };
+    self.record(name, outcome);
+  }
+}
+
+impl fmt::Display for ConformanceReport {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for result in &self.results {
+      match &result.outcome {
+        CheckOutcome::Pass => writeln!(f, "PASS {}", result.name)?,
+        CheckOutcome::Fail(reason) => {
+          writeln!(f, "FAIL {}: {}", result.name, reason)?
+        }
+        CheckOutcome::Skipped(reason) => {
+          writeln!(f, "SKIP {}: {}", result.name, reason)?
+        }
+      }
+    }
+    write!(
+      f,
+      "{} checks, {} failed",
+      self.results.len(),
+      self.failures()
+    )
+  }
+}
+
+/// The configuration of a conformance run
+#[derive(Clone, Debug, Default)]
+pub struct ConformanceConfig {
+  /// Rows of an index DB that are known to be served, with their record
+  /// bytes
+  pub expected_rows: Vec<(usize, Vec<u8>)>,
+  /// How long to wait for the server to rotate its params before
+  /// checking the epoch, e.g. one rotation period. Without a wait, the
+  /// epoch is only checked to be consistent over the run.
+  pub rotation_wait: Option<Duration>,
+}
+
+/// The number of attempts at generating a query, which fails with
+/// probability about 2^-10 (see `QueryParams::generate_query`)
+const QUERY_ATTEMPTS: usize = 4;
+
+/// The number of attempts at fetching server info and params of the same
+/// epoch, which fails if the params rotate in between
+const FETCH_ATTEMPTS: usize = 3;
+
+/// The params fetched from the server, which serves either DB type
+enum ServedParams {
+  Index(IndexParams),
+  Kv(KVParams),
+}
+
+/// The query params of a query to a row of the DB matrix
+enum RowQuery {
+  Index(QueryParams<IndexDatabase, EmptyAuxParams>),
+  Kv(QueryParams<KVDatabase, FilterParams>),
+}
+
+impl ServedParams {
+  /// Parses params in the JSON format of `PirShard::params_json`. KV
+  /// params are told apart by their filter params.
+  fn parse(json: &[u8]) -> ResultBoxedError<Self> {
+    let value: serde_json::Value = serde_json::from_slice(json)?;
+    if value.get("filter_params").is_some() {
+      return Ok(Self::Kv(serde_json::from_value(value)?));
+    }
+    Ok(Self::Index(serde_json::from_value(value)?))
+  }
+
+  fn digest(&self) -> ResultBoxedError<[u8; 32]> {
+    match self {
+      Self::Index(params) => params_digest(params),
+      Self::Kv(params) => params_digest(params),
+    }
+  }
+
+  fn common_params(&self) -> CommonParams {
+    match self {
+      Self::Index(params) => CommonParams::from(params),
+      Self::Kv(params) => CommonParams::from(params),
+    }
+  }
+
+  /// Returns the LWE dimension, the plaintext bits and the modulus
+  fn lwe_params(&self) -> (usize, usize, Modulus) {
+    match self {
+      Self::Index(params) => lwe_params(params),
+      Self::Kv(params) => lwe_params(params),
+    }
+  }
+
+  fn is_expired_at(&self, now: SystemTime) -> bool {
+    match self {
+      Self::Index(params) => params.is_expired_at(now),
+      Self::Kv(params) => params.is_expired_at(now),
+    }
+  }
+
+  fn height(&self) -> usize {
+    match self {
+      Self::Index(params) => params.get_total_records(),
+      Self::Kv(params) => params.get_total_records(),
+    }
+  }
+
+  fn width(&self) -> usize {
+    match self {
+      Self::Index(params) => params.get_rhs().len(),
+      Self::Kv(params) => params.get_rhs().len(),
+    }
+  }
+
+  /// Prepares a query for the row `row` of the DB matrix, retrying with
+  /// fresh query params if query generation overflows
+  fn query(
+    &self,
+    cp: &CommonParams,
+    row: usize,
+  ) -> ResultBoxedError<(Query, RowQuery)> {
+    let mut attempts = 1;
+    loop {
+      match self.query_once(cp, row) {
+        Err(e) if e.is::<ErrorOverflownAdd>() && attempts < QUERY_ATTEMPTS => {
+          attempts += 1
+        }
+        res => return res,
+      }
+    }
+  }
+
+  fn query_once(
+    &self,
+    cp: &CommonParams,
+    row: usize,
+  ) -> ResultBoxedError<(Query, RowQuery)> {
+    match self {
+      Self::Index(params) => {
+        let mut qp = generate_index_query_params(cp, params)?;
+        Ok((qp.generate_query(row)?, RowQuery::Index(qp)))
+      }
+      Self::Kv(params) => {
+        let mut qp = generate_kv_query_params(cp, params)?;
+        Ok((qp.generate_query_by_index(row)?, RowQuery::Kv(qp)))
+      }
+    }
+  }
+
+  /// Returns the smallest noise margin that responses to valid queries
+  /// have with overwhelming probability, given the flooding noise that
+  /// the server advertises, or `None` if the params leave no margin to
+  /// check
+  fn min_margin(&self, flooding_bits: Option<u32>) -> Option<u32> {
+    let (_, plaintext_bits, modulus) = self.lwe_params();
+    let max_entry = get_plaintext_size(plaintext_bits) - 1;
+    let floor = modulus.rounding_floor(plaintext_bits) as f64;
+    let margin = floor
+      - noise_bound(max_entry, self.height())
+      - flooding_bits.map_or(0.0, |bits| 2f64.powi(bits as i32));
+    (margin > 0.0).then_some(margin as u32)
+  }
+}
+
+/// Returns the distance between `a` and `b` modulo `modulus`
+fn distance(modulus: Modulus, a: u32, b: u32) -> u32 {
+  modulus
+    .reduce(a.wrapping_sub(b))
+    .min(modulus.reduce(b.wrapping_sub(a)))
+}
+
+/// Returns the largest distance between the sum of `responses` values and
+/// the exact sum, given the flooding noise that the server advertises
+fn flooding_tolerance(flooding_bits: Option<u32>, responses: u32) -> u32 {
+  flooding_bits.map_or(0, |bits| {
+    1u32
+      .checked_shl(bits)
+      .map_or(u32::MAX, |bound| bound.saturating_mul(responses))
+  })
+}
+
+fn lwe_params<P: BaseParams>(params: &P) -> (usize, usize, Modulus) {
+  (
+    params.get_dim(),
+    params.get_plaintext_bits(),
+    params.get_modulus(),
+  )
+}
+
+impl RowQuery {
+  fn margins(&self, resp: &Response, width: usize) -> Vec<u32> {
+    match self {
+      Self::Index(qp) => qp.parse_resp_margins(resp, width),
+      Self::Kv(qp) => qp.parse_resp_margins(resp, width),
+    }
+  }
+}
+
+/// Runs every conformance check against the server behind `transport`
+pub fn run_conformance<T: PirTransport + ?Sized>(
+  transport: &mut T,
+  config: &ConformanceConfig,
+) -> ConformanceReport {
+  let mut report = ConformanceReport::default();
+  let params = match fetch_params(transport) {
+    Ok(params) => {
+      report.record("params", CheckOutcome::Pass);
+      params
+    }
+    Err(e) => {
+      report.record("params", CheckOutcome::Fail(e.to_string()));
+      return report;
+    }
+  };
+  let cp = params.common_params();
+  let info = fetch_info(transport);
+  let flooding_bits =
+    info.as_ref().ok().and_then(|info| info.noise_flooding_bits);
+  report.record_result(
+    "valid queries",
+    check_valid_queries(transport, &params, &cp, flooding_bits),
+  );
+  report.record_result(
+    "deterministic responses",
+    check_deterministic(transport, &params, &cp, flooding_bits),
+  );
+  report.record_result(
+    "linear responses",
+    check_linear(transport, &params, &cp, flooding_bits),
+  );
+  report.record_result(
+    "invalid queries",
+    check_invalid_queries(transport, &params),
+  );
+  match check_malformed_queries(transport, &params, &cp) {
+    Ok(Some(())) => report.record("malformed queries", CheckOutcome::Pass),
+    Ok(None) => report.record(
+      "malformed queries",
+      CheckOutcome::Skipped(
+        "the transport cannot send raw queries".to_string(),
+      ),
+    ),
+    Err(e) => {
+      report.record("malformed queries", CheckOutcome::Fail(e.to_string()))
+    }
+  }
+  match &params {
+    ServedParams::Index(_) => report.record_result(
+      "expected rows",
+      check_expected_rows(transport, &params, &cp, &config.expected_rows),
+    ),
+    ServedParams::Kv(_) if !config.expected_rows.is_empty() => report.record(
+      "expected rows",
+      CheckOutcome::Fail("the server serves a KV DB".to_string()),
+    ),
+    ServedParams::Kv(_) => (),
+  }
+
+  let info = match info {
+    Ok(info) => info,
+    Err(e) => {
+      let reason = format!("no server info: {}", e);
+      report.record("wire version", CheckOutcome::Skipped(reason.clone()));
+      report.record("epoch rotation", CheckOutcome::Skipped(reason));
+      return report;
+    }
+  };
+  report.record_result("wire version", check_wire_version(&info, &params));
+  report.record_result(
+    "epoch rotation",
+    check_epoch_rotation(transport, config.rotation_wait),
+  );
+  report
+}
+
+fn fetch_params<T: PirTransport + ?Sized>(
+  transport: &mut T,
+) -> ResultBoxedError<ServedParams> {
+  let params = ServedParams::parse(&transport.fetch_params()?)?;
+  if params.height() == 0 || params.width() == 0 {
+    return Err(
+      format!(
+        "params of a {}x{} DB matrix",
+        params.height(),
+        params.width()
+      )
+      .into(),
+    );
+  }
+  if params.lwe_params().0 == 0 {
+    return Err("params of LWE dimension 0".into());
+  }
+  if params.is_expired_at(SystemTime::now()) {
+    return Err("the served params have expired".into());
+  }
+  Ok(params)
+}
+
+fn fetch_info<T: PirTransport + ?Sized>(
+  transport: &mut T,
+) -> ResultBoxedError<ServerInfo> {
+  let info = transport.fetch_server_info()?;
+  ServerInfo::from_json(std::str::from_utf8(&info)?)
+}
+
+/// Returns the info of the first shard of the server
+fn first_shard(info: &ServerInfo) -> ResultBoxedError<&ShardInfo> {
+  Ok(
+    info
+      .shards
+      .first()
+      .ok_or("the server info lists no shards")?,
+  )
+}
+
+/// Fetches the server info and the params of a single epoch, retrying if
+/// the params rotate between the two requests
+fn fetch_consistent<T: PirTransport + ?Sized>(
+  transport: &mut T,
+) -> ResultBoxedError<(ServerInfo, ServedParams)> {
+  for _ in 0..FETCH_ATTEMPTS {
+    let before = fetch_info(transport)?;
+    let params = fetch_params(transport)?;
+    let after = fetch_info(transport)?;
+    if first_shard(&before)?.params_digest == first_shard(&after)?.params_digest
+    {
+      return Ok((after, params));
+    }
+  }
+  Err(
+    format!(
+      "the params rotated during each of {} attempts to fetch them",
+      FETCH_ATTEMPTS
+    )
+    .into(),
+  )
+}
+
+/// Sends `q`, parsing the serialized response
+fn send<T: PirTransport + ?Sized>(
+  transport: &mut T,
+  q: &Query,
+  width: usize,
+) -> ResultBoxedError<Response> {
+  let resp: Response = bincode::deserialize(&transport.send_query(q)?)?;
+  // responses may be padded (see `ShardCluster`)
+  if resp.as_slice().len() < width {
+    return Err(
+      format!(
+        "response of {} values to params of width {}",
+        resp.as_slice().len(),
+        width
+      )
+      .into(),
+    );
+  }
+  Ok(resp)
+}
+
+/// Returns the first and last row of the DB matrix
+fn boundary_rows(params: &ServedParams) -> [usize; 2] {
+  [0, params.height() - 1]
+}
+
+fn check_valid_queries<T: PirTransport + ?Sized>(
+  transport: &mut T,
+  params: &ServedParams,
+  cp: &CommonParams,
+  flooding_bits: Option<u32>,
+) -> ResultBoxedError<()> {
+  let min_margin = params.min_margin(flooding_bits);
+  for row in boundary_rows(params) {
+    let (q, qp) = params.query(cp, row)?;
+    let resp = send(transport, &q, params.width())?;
+    let margin = qp.margins(&resp, params.width()).into_iter().min();
+    if let (Some(min_margin), Some(margin)) = (min_margin, margin) {
+      if margin < min_margin {
+        return Err(
+          format!(
+            "response to row {} has noise margin {}, expected at least {}",
+            row, margin, min_margin
+          )
+          .into(),
+        );
+      }
+    }
+  }
+  Ok(())
+}
+
+fn check_deterministic<T: PirTransport + ?Sized>(
+  transport: &mut T,
+  params: &ServedParams,
+  cp: &CommonParams,
+  flooding_bits: Option<u32>,
+) -> ResultBoxedError<()> {
+  let width = params.width();
+  let (_, _, modulus) = params.lwe_params();
+  // both responses may be flooded
+  let tolerance = flooding_tolerance(flooding_bits, 2);
+  let (q, _) = params.query(cp, 0)?;
+  let first = send(transport, &q, width)?;
+  let second = send(transport, &q, width)?;
+  let deterministic = (0..width).all(|i| {
+    distance(modulus, first.as_slice()[i], second.as_slice()[i]) <= tolerance
+  });
+  if !deterministic {
+    return Err("the same query was answered differently".into());
+  }
+  Ok(())
+}
+
+fn check_linear<T: PirTransport + ?Sized>(
+  transport: &mut T,
+  params: &ServedParams,
+  cp: &CommonParams,
+  flooding_bits: Option<u32>,
+) -> ResultBoxedError<()> {
+  let width = params.width();
+  let (_, _, modulus) = params.lwe_params();
+  let [first, last] = boundary_rows(params);
+  let (q1, _) = params.query(cp, first)?;
+  let (q2, _) = params.query(cp, last)?;
+  let sum = Query::from_values(
+    q1.as_slice()
+      .iter()
+      .zip(q2.as_slice())
+      .map(|(a, b)| a.wrapping_add(*b))
+      .collect(),
+  );
+  let r1 = send(transport, &q1, width)?;
+  let r2 = send(transport, &q2, width)?;
+  let r_sum = send(transport, &sum, width)?;
+  // all three responses may be flooded
+  let tolerance = flooding_tolerance(flooding_bits, 3);
+  let linear = (0..width).all(|i| {
+    let sum = r1.as_slice()[i].wrapping_add(r2.as_slice()[i]);
+    distance(modulus, sum, r_sum.as_slice()[i]) <= tolerance
+  });
+  if !linear {
+    return Err(
+      "the response to a sum of queries is not the sum of their responses"
+        .into(),
+    );
+  }
+  Ok(())
+}
+
+fn check_invalid_queries<T: PirTransport + ?Sized>(
+  transport: &mut T,
+  params: &ServedParams,
+) -> ResultBoxedError<()> {
+  for len in [0, params.height() - 1] {
+    if transport.send_query(&Query::random(len)).is_ok() {
+      return Err(
+        format!(
+          "a query of length {} was answered, expected {}",
+          len,
+          params.height()
+        )
+        .into(),
+      );
+    }
+  }
+  Ok(())
+}
+
+/// Sends serialized queries that are malformed, returning `None` if the
+/// transport cannot send them
+fn check_malformed_queries<T: PirTransport + ?Sized>(
+  transport: &mut T,
+  params: &ServedParams,
+  cp: &CommonParams,
+) -> ResultBoxedError<Option<()>> {
+  let (q, _) = params.query(cp, 0)?;
+  let d_query = bincode::serialize(&q)?;
+  // a query whose length prefix claims more entries than it carries
+  let mut overlong = d_query.clone();
+  overlong[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+  let malformed = [
+    ("truncated", d_query[..d_query.len() - 1].to_vec()),
+    ("overlong", overlong),
+    ("empty", Vec::new()),
+  ];
+  for (name, body) in malformed {
+    match transport.send_raw_query(&body) {
+      Err(e) if e.is::<ErrorTransportUnsupported>() => return Ok(None),
+      Err(_) => (),
+      Ok(_) => {
+        return Err(format!("a {} query was answered", name).into());
+      }
+    }
+  }
+  Ok(Some(()))
+}
+
+fn check_expected_rows<T: PirTransport + ?Sized>(
+  transport: &mut T,
+  params: &ServedParams,
+  cp: &CommonParams,
+  expected_rows: &[(usize, Vec<u8>)],
+) -> ResultBoxedError<()> {
+  for (row, record) in expected_rows {
+    let (q, qp) = params.query(cp, *row)?;
+    let resp = send(transport, &q, params.width())?;
+    if let RowQuery::Index(qp) = qp {
+      if qp.parse_resp_as_bytes(&resp) != *record {
+        return Err(format!("row {} does not decode as expected", row).into());
+      }
+    }
+  }
+  Ok(())
+}
+
+fn check_wire_version(
+  info: &ServerInfo,
+  params: &ServedParams,
+) -> ResultBoxedError<()> {
+  if !info.protocol_versions.windows(2).all(|w| w[0] < w[1]) {
+    return Err(
+      format!(
+        "protocol versions {:?} are not increasing",
+        info.protocol_versions
+      )
+      .into(),
+    );
+  }
+  if info
+    .negotiate_version(SUPPORTED_PROTOCOL_VERSIONS)
+    .is_none()
+  {
+    return Err(
+      format!(
+        "no protocol version in common: server {:?}, client {:?}",
+        info.protocol_versions, SUPPORTED_PROTOCOL_VERSIONS
+      )
+      .into(),
+    );
+  }
+  let shard = first_shard(info)?;
+  if (shard.height, shard.width) != (params.height(), params.width()) {
+    return Err(
+      format!(
+        "the server info describes a {}x{} DB matrix, the params a {}x{}",
+        shard.height,
+        shard.width,
+        params.height(),
+        params.width()
+      )
+      .into(),
+    );
+  }
+  if info.max_query_bytes < 8 + 4 * params.height() {
+    return Err(
+      format!(
+        "max_query_bytes {} is below the size of a query",
+        info.max_query_bytes
+      )
+      .into(),
+    );
+  }
+  Ok(())
+}
+
+fn check_epoch_rotation<T: PirTransport + ?Sized>(
+  transport: &mut T,
+  wait: Option<Duration>,
+) -> ResultBoxedError<()> {
+  let (info, params) = fetch_consistent(transport)?;
+  let shard = first_shard(&info)?;
+  if shard.params_digest != params.digest()? {
+    return Err("the server info does not match the served params".into());
+  }
+  if let Some(wait) = wait {
+    thread::sleep(wait);
+  }
+  let (info_after, params_after) = fetch_consistent(transport)?;
+  let shard_after = first_shard(&info_after)?;
+  if shard_after.params_digest != params_after.digest()? {
+    return Err("the server info does not match the served params".into());
+  }
+  if shard_after.epoch < shard.epoch {
+    return Err(
+      format!(
+        "the epoch went back from {} to {}",
+        shard.epoch, shard_after.epoch
+      )
+      .into(),
+    );
+  }
+  if shard_after.params_digest != shard.params_digest {
+    if shard_after.epoch == shard.epoch {
+      return Err(
+        format!("the params changed within epoch {}", shard.epoch).into(),
+      );
+    }
+    // the rotated params must answer queries as well
+    let cp = params_after.common_params();
+    check_valid_queries(
+      transport,
+      &params_after,
+      &cp,
+      info_after.noise_flooding_bits,
+    )?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::*;
+  use crate::serving::{ServingConfig, ServingShard};
+  use crate::test_utils::{index_shard, kv_shard};
+  use crate::transport::InMemoryTransport;
+
+  /// A server that answers queries of any length, by truncating or
+  /// zero-padding them to the height of the DB matrix
+  struct LenientServer(InMemoryTransport<Shard>);
+  impl PirTransport for LenientServer {
+    fn fetch_params(&mut self) -> ResultBoxedError<Vec<u8>> {
+      self.0.fetch_params()
+    }
+
+    fn send_query(&mut self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+      let mut values = q.as_slice().to_vec();
+      values.resize(self.0.get_shard().height(), 0);
+      self.0.send_query(&Query::from_values(values))
+    }
+  }
+
+  /// A server that floods its responses with noise, and advertises it in
+  /// its server info
+  struct FloodingServer(ServingShard<Shard>);
+  impl PirTransport for FloodingServer {
+    fn fetch_params(&mut self) -> ResultBoxedError<Vec<u8>> {
+      self.0.get_shard().params_json()
+    }
+
+    fn fetch_server_info(&mut self) -> ResultBoxedError<Vec<u8>> {
+      Ok(self.0.server_info()?.to_json()?.into_bytes())
+    }
+
+    fn send_query(&mut self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+      self.0.respond(q)
+    }
+  }
+
+  #[test]
+  fn conformant_servers_pass() {
+    let (eles, shard) = index_shard();
+    let config = ConformanceConfig {
+      expected_rows: vec![(5, base64::decode(&eles[5]).unwrap())],
+      ..Default::default()
+    };
+    let report =
+      run_conformance(&mut InMemoryTransport::new(shard.clone()), &config);
+    assert!(report.passed(), "{}", report);
+    assert_eq!(report.outcome("wire version"), Some(&CheckOutcome::Pass));
+    assert_eq!(
+      report.outcome("malformed queries"),
+      Some(&CheckOutcome::Pass)
+    );
+
+    let (_, _, kv_shard) = kv_shard();
+    let report = run_conformance(
+      &mut InMemoryTransport::new(kv_shard),
+      &ConformanceConfig::default(),
+    );
+    assert!(report.passed(), "{}", report);
+
+    // the config expects another record
+    let config = ConformanceConfig {
+      expected_rows: vec![(5, base64::decode(&eles[6]).unwrap())],
+      ..Default::default()
+    };
+    let report =
+      run_conformance(&mut InMemoryTransport::new(shard.clone()), &config);
+    assert!(matches!(
+      report.outcome("expected rows"),
+      Some(CheckOutcome::Fail(_))
+    ));
+  }
+
+  #[test]
+  fn flooding_servers_pass() {
+    let (eles, shard) = index_shard();
+    let config = ServingConfig {
+      threads: Some(1),
+      noise_flooding_bits: Some(20),
+      ..Default::default()
+    };
+    let serving = ServingShard::new(shard, &config).unwrap();
+    let report = run_conformance(
+      &mut FloodingServer(serving),
+      &ConformanceConfig {
+        expected_rows: vec![(5, base64::decode(&eles[5]).unwrap())],
+        ..Default::default()
+      },
+    );
+    assert!(report.passed(), "{}", report);
+    assert_eq!(
+      report.outcome("deterministic responses"),
+      Some(&CheckOutcome::Pass)
+    );
+    assert!(matches!(
+      report.outcome("malformed queries"),
+      Some(CheckOutcome::Skipped(_))
+    ));
+  }
+
+  #[test]
+  fn lenient_servers_fail() {
+    let (_, shard) = index_shard();
+    let report = run_conformance(
+      &mut LenientServer(InMemoryTransport::new(shard)),
+      &ConformanceConfig::default(),
+    );
+    assert!(!report.passed());
+    assert_eq!(report.failures(), 1);
+    assert!(matches!(
+      report.outcome("invalid queries"),
+      Some(CheckOutcome::Fail(_))
+    ));
+    assert!(matches!(
+      report.outcome("epoch rotation"),
+      Some(CheckOutcome::Skipped(_))
+    ));
+  }
+}
@@ -1,21 +1,46 @@
-use std::fs;
-use std::io::BufReader;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::db::{BaseParams, DatabaseMatrix};
-use crate::errors::ResultBoxedError;
+use crate::compression::Compression;
+use crate::db::{
+  add_rhs_column_diffs, add_row_deltas, check_row_index, db_summary,
+  params_summary, rhs_column_diffs, BaseParams, DatabaseMatrix,
+  DatabaseMatrixLayout,
+};
+use crate::errors::{
+  ErrorDeduplicatedDb, ErrorUnexpectedInputSize, ResultBoxedError,
+};
+use crate::utils::arith::check_column_entries;
+use crate::utils::files::{
+  read_json_file, read_jsonl_file, read_persisted, write_persisted, Persist,
+};
 use crate::utils::format::*;
+use crate::utils::lwe::Modulus;
 use crate::utils::matrices::*;
 use crate::utils::random::generate_seed;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct IndexDatabase {
-  entries: Vec<Vec<u32>>,
+  entries: ColumnMatrix,
   m: usize,
   elem_size: usize,
   plaintext_bits: usize,
+  /// The number of plaintext bits held by each column, if they differ
+  /// between columns (see `new_with_column_bits`)
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  column_bits: Option<Vec<usize>>,
+  /// The matrix row holding each record, if duplicate records share a
+  /// row (see `new_deduplicated`)
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  row_map: Option<RowMap>,
+  /// A row-major copy of `entries`, if maintained (see `set_dual_layout`)
+  #[serde(skip)]
+  row_major: Option<Vec<Vec<u32>>>,
+  /// Whether `entries` hold the rows rather than the columns of the DB
+  /// matrix, i.e. `switch_fmt` was called an odd number of times
+  #[serde(skip)]
+  transposed: bool,
 }
 impl IndexDatabase {
   pub fn new(
@@ -24,16 +49,117 @@ impl IndexDatabase {
     elem_size: usize,
     plaintext_bits: usize,
   ) -> ResultBoxedError<Self> {
+    Self::new_with_encoding(
+      elements,
+      m,
+      elem_size,
+      plaintext_bits,
+      EncodingConfig::Standard,
+    )
+  }
+
+  /// Constructs the database from elements serialized using `encoding`
+  pub fn new_with_encoding(
+    elements: &[String],
+    m: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+    encoding: EncodingConfig,
+  ) -> ResultBoxedError<Self> {
+    let column_bits =
+      vec![plaintext_bits; Self::get_row_width(elem_size, plaintext_bits)];
     Ok(Self {
       entries: swap_matrix_fmt(&construct_rows(
         elements,
         m,
-        elem_size,
-        plaintext_bits,
-      )?),
+        &column_bits,
+        encoding,
+      )?)
+      .into(),
       m,
       elem_size,
       plaintext_bits,
+      column_bits: None,
+      row_map: None,
+      row_major: None,
+      transposed: false,
+    })
+  }
+
+  /// Same as `new_with_encoding`, storing the value of duplicate elements
+  /// in a single matrix row, so that the number of rows `m` (and thus
+  /// the size of queries) is the number of distinct elements. The
+  /// `RowMap` of the DB maps each element to its row, and is published in
+  /// the params, so that clients query the row of an element rather than
+  /// its index.
+  pub fn new_deduplicated(
+    elements: &[String],
+    elem_size: usize,
+    plaintext_bits: usize,
+    encoding: EncodingConfig,
+  ) -> ResultBoxedError<Self> {
+    let column_bits =
+      vec![plaintext_bits; Self::get_row_width(elem_size, plaintext_bits)];
+    let mut unique_rows = Vec::new();
+    let mut row_indices: HashMap<Vec<u32>, usize> = HashMap::new();
+    let mut row_map = RowMap::default();
+    for element in elements {
+      let row = construct_row(element, &column_bits, encoding)?;
+      let i = *row_indices.entry(row).or_insert_with_key(|row| {
+        unique_rows.push(row.clone());
+        unique_rows.len() - 1
+      });
+      row_map.push(i);
+    }
+    Ok(Self {
+      entries: swap_matrix_fmt(&unique_rows).into(),
+      m: unique_rows.len(),
+      elem_size,
+      plaintext_bits,
+      column_bits: None,
+      row_map: Some(row_map),
+      row_major: None,
+      transposed: false,
+    })
+  }
+
+  /// Same as `new_with_encoding`, splitting each element into columns of
+  /// `column_bits[i]` bits rather than into columns of equal width, e.g.
+  /// so that the narrow fields of a record schema do not each take up a
+  /// full column. The last column may hold fewer bits than allocated.
+  ///
+  /// Queries are scaled for the widest column, whose width is used as the
+  /// plaintext bits of the DB, so the noise budget is that of a DB with
+  /// uniform columns of that width.
+  pub fn new_with_column_bits(
+    elements: &[String],
+    m: usize,
+    elem_size: usize,
+    column_bits: &[usize],
+    encoding: EncodingConfig,
+  ) -> ResultBoxedError<Self> {
+    check_column_bits(column_bits, elem_size, column_bits.len())?;
+    let plaintext_bits =
+      column_bits.iter().copied().max().ok_or_else(|| {
+        ErrorUnexpectedInputSize::new(
+          "column bits must describe at least one column".to_string(),
+        )
+      })?;
+    Ok(Self {
+      entries: swap_matrix_fmt(&construct_rows(
+        elements,
+        m,
+        column_bits,
+        encoding,
+      )?)
+      .into(),
+      m,
+      elem_size,
+      plaintext_bits,
+      column_bits: Some(column_bits.to_vec()),
+      row_map: None,
+      row_major: None,
+      transposed: false,
     })
   }
 
@@ -43,14 +169,305 @@ impl IndexDatabase {
     elem_size: usize,
     plaintext_bits: usize,
   ) -> ResultBoxedError<Self> {
-    let file_contents: String = fs::read_to_string(db_file)?.parse()?;
-    let elements: Vec<String> = serde_json::from_str(&file_contents)?;
+    let elements: Vec<String> = read_json_file(db_file)?;
+    Self::new(&elements, m, elem_size, plaintext_bits)
+  }
+
+  /// Same as `from_file`, for a line-delimited JSON file holding one
+  /// base64-encoded element per line. The lines are parsed in parallel,
+  /// which is considerably faster for large databases.
+  pub fn from_jsonl_file(
+    db_file: &str,
+    m: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+  ) -> ResultBoxedError<Self> {
+    let elements: Vec<String> = read_jsonl_file(db_file)?;
     Self::new(&elements, m, elem_size, plaintext_bits)
   }
+
+  /// Returns the DB matrix, and a copy of the DB without it, so that the
+  /// matrix can be stored separately (see `Shard::write_snapshot`)
+  pub(crate) fn split_entries(&self) -> (&ColumnMatrix, Self) {
+    let rest = Self {
+      entries: ColumnMatrix::default(),
+      m: self.m,
+      elem_size: self.elem_size,
+      plaintext_bits: self.plaintext_bits,
+      column_bits: self.column_bits.clone(),
+      row_map: self.row_map.clone(),
+      row_major: None,
+      transposed: false,
+    };
+    (&self.entries, rest)
+  }
+
+  /// Reverses `split_entries`, checking the dimensions of `entries`
+  pub(crate) fn join_entries(
+    self,
+    entries: ColumnMatrix,
+  ) -> ResultBoxedError<Self> {
+    let width = self.get_row_width_self();
+    if entries.len() != width || entries.iter().any(|c| c.len() != self.m) {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "DB matrix of {} columns does not match {} columns of {} rows",
+        entries.len(),
+        width,
+        self.m
+      ))));
+    }
+    Ok(Self { entries, ..self })
+  }
+
+  /// Touches every memory page of the DB matrix, so that the first
+  /// queries do not incur page-fault latency
+  pub fn prefault(&self) {
+    touch_matrix_pages(&self.entries.slices());
+  }
+
+  /// Maintains a row-major copy of the DB matrix next to the entries if
+  /// `enabled` (or drops it otherwise), so that batched responds (see
+  /// `Shard::respond_batch`) make a single pass over the rows, while
+  /// single responds keep using the columns. This doubles the memory
+  /// used by the DB matrix. The copy is not serialized.
+  pub fn set_dual_layout(&mut self, enabled: bool) {
+    self.row_major = enabled.then(|| match self.transposed {
+      true => self.entries.to_vecs(),
+      false => swap_matrix_fmt(&self.entries.slices()),
+    });
+  }
+
+  /// Returns the mapping of records to matrix rows, if the DB is
+  /// deduplicated (see `new_deduplicated`)
+  pub fn get_row_map(&self) -> Option<&RowMap> {
+    self.row_map.as_ref()
+  }
+
+  /// Load a database that was written using `write_to_file`
+  pub fn load(db_path: &str) -> ResultBoxedError<Self> {
+    Ok(read_persisted(db_path)?)
+  }
+
+  /// Loads a database written by `write_to_file` in the legacy format,
+  /// which holds only the columns of the DB matrix. The element size and
+  /// plaintext bits are not recorded in that format, so they are given.
+  /// Writing the DB again migrates it to the current format.
+  pub fn load_legacy(
+    db_path: &str,
+    elem_size: usize,
+    plaintext_bits: usize,
+  ) -> ResultBoxedError<Self> {
+    let entries: Vec<Vec<u32>> = read_json_file(db_path)?;
+    let width = Self::get_row_width(elem_size, plaintext_bits);
+    let m = entries.first().map_or(0, Vec::len);
+    if entries.len() != width || entries.iter().any(|col| col.len() != m) {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "legacy DB of {} columns does not hold elements of {} bits in {} \
+         columns of equal height",
+        entries.len(),
+        elem_size,
+        width
+      ))));
+    }
+    Ok(Self {
+      entries: entries.into(),
+      m,
+      elem_size,
+      plaintext_bits,
+      column_bits: None,
+      row_map: None,
+      row_major: None,
+      transposed: false,
+    })
+  }
+
+  /// Returns a copy of the DB with the rows at `removed` deleted and the
+  /// base64-encoded `added` elements inserted, together with the
+  /// difference between the new and old value of every row that changed.
+  ///
+  /// Rows keep their index where possible, so that the LHS matrix rows
+  /// they were multiplied with stay the same: the ith added element takes
+  /// the ith smallest removed index, and any further elements are
+  /// appended. If more rows are removed than added, the last rows of the
+  /// DB are moved into the remaining gaps.
+  pub fn with_changes(
+    &self,
+    added: &[String],
+    removed: &[usize],
+  ) -> ResultBoxedError<(Self, RowDeltas)> {
+    if self.row_map.is_some() {
+      return Err(Box::new(ErrorDeduplicatedDb::new(
+        "rows cannot be changed in place".to_string(),
+      )));
+    }
+    let m = self.m;
+    let mut removed = removed.to_vec();
+    removed.sort_unstable();
+    removed.dedup();
+    if let Some(&i) = removed.last().filter(|&&i| i >= m) {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "Removed row {} is out of range for {} rows",
+        i, m
+      ))));
+    }
+    let row_width = self.get_row_width_self();
+    let column_bits = self.get_column_bits_or_uniform();
+    let mut added_rows = added
+      .iter()
+      .map(|e| construct_row(e, &column_bits, EncodingConfig::Standard))
+      .collect::<ResultBoxedError<Vec<Vec<u32>>>>()?
+      .into_iter();
+
+    // The new value of every row that changes, or `None` for rows past
+    // the end of the new DB
+    let mut new_rows: BTreeMap<usize, Option<Vec<u32>>> = BTreeMap::new();
+    let mut holes = Vec::new();
+    for &i in &removed {
+      match added_rows.next() {
+        Some(row) => new_rows.insert(i, Some(row)),
+        None => {
+          holes.push(i);
+          continue;
+        }
+      };
+    }
+    let mut new_m = m;
+    for row in added_rows {
+      new_rows.insert(new_m, Some(row));
+      new_m += 1;
+    }
+    if !holes.is_empty() {
+      new_m = m - holes.len();
+      let hole_set: BTreeSet<usize> = holes.iter().copied().collect();
+      let moved = (new_m..m).filter(|i| !hole_set.contains(i));
+      let gaps = holes.iter().copied().filter(|&i| i < new_m);
+      for (src, dst) in moved.zip(gaps) {
+        new_rows.insert(dst, Some(self.get_matrix_row(src)));
+      }
+      for i in new_m..m {
+        new_rows.insert(i, None);
+      }
+    }
+
+    let mut entries = self.entries.to_vecs();
+    let mut deltas = Vec::with_capacity(new_rows.len());
+    for col in entries.iter_mut() {
+      col.resize(new_m.max(m), 0);
+    }
+    for (i, row) in new_rows {
+      let row = row.unwrap_or_else(|| vec![0; row_width]);
+      let delta: Vec<u32> = entries
+        .iter_mut()
+        .zip(row)
+        .map(|(col, new)| {
+          let old = std::mem::replace(&mut col[i], new);
+          new.wrapping_sub(old)
+        })
+        .collect();
+      if delta.iter().any(|&d| d != 0) {
+        deltas.push((i, delta));
+      }
+    }
+    for col in entries.iter_mut() {
+      col.truncate(new_m);
+    }
+    let mut db = Self {
+      entries: entries.into(),
+      m: new_m,
+      elem_size: self.elem_size,
+      plaintext_bits: self.plaintext_bits,
+      column_bits: self.column_bits.clone(),
+      row_map: None,
+      row_major: None,
+      transposed: false,
+    };
+    db.set_dual_layout(self.row_major.is_some());
+    Ok((db, deltas))
+  }
+
+  /// Returns the number of plaintext bits held by each column
+  fn get_column_bits_or_uniform(&self) -> Vec<usize> {
+    column_bits_or_uniform(
+      self.column_bits.as_deref(),
+      self.elem_size,
+      self.plaintext_bits,
+    )
+  }
+
+  /// Returns the ith row of the (row-major) DB matrix, i.e. the encoded
+  /// ith element
+  fn get_matrix_row(&self, i: usize) -> Vec<u32> {
+    self.entries.iter().map(|col| col[i]).collect()
+  }
+
+  /// Returns the raw bytes of the ith DB entry. Panics if there is no ith
+  /// row.
+  fn entry_bytes(&self, i: usize) -> Vec<u8> {
+    bytes_from_u32_slice_with_column_bits(
+      &self.get_matrix_row(i),
+      &self.get_column_bits_or_uniform(),
+      self.elem_size,
+    )
+  }
+}
+
+/// A `RowMap` maps the records of a deduplicated `IndexDatabase` to the
+/// matrix rows holding their values, and counts the records referencing
+/// each row
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RowMap {
+  rows: Vec<usize>,
+  ref_counts: Vec<usize>,
 }
+impl RowMap {
+  /// Appends a record held by matrix row `row`
+  fn push(&mut self, row: usize) {
+    if row >= self.ref_counts.len() {
+      self.ref_counts.resize(row + 1, 0);
+    }
+    self.ref_counts[row] += 1;
+    self.rows.push(row);
+  }
+
+  /// Returns the matrix row holding the ith record
+  pub fn get_row(&self, i: usize) -> Option<usize> {
+    self.rows.get(i).copied()
+  }
+
+  /// Returns the matrix row of every record, in record order
+  pub fn get_rows(&self) -> &[usize] {
+    &self.rows
+  }
+
+  /// Returns the number of records held by the ith matrix row
+  pub fn get_ref_count(&self, i: usize) -> usize {
+    self.ref_counts.get(i).copied().unwrap_or(0)
+  }
+
+  /// Returns the number of records
+  pub fn num_records(&self) -> usize {
+    self.rows.len()
+  }
+}
+
+/// The rows of an `IndexDatabase` that changed between two versions, as
+/// (row index, new row minus old row) pairs in increasing row order
+pub type RowDeltas = Vec<(usize, Vec<u32>)>;
+impl Persist for IndexDatabase {
+  const KIND: &'static str = "IndexDatabase";
+
+  fn summary(&self) -> serde_json::Value {
+    db_summary(self)
+  }
+}
+
 impl DatabaseMatrix for IndexDatabase {
+  /// Transposes `entries`. The row-major copy of a dual layout DB (see
+  /// `set_dual_layout`) does not depend on the orientation of `entries`,
+  /// so it is kept.
   fn switch_fmt(&mut self) {
-    self.entries = swap_matrix_fmt(&self.entries);
+    self.entries = swap_matrix_fmt(&self.entries.slices()).into();
+    self.transposed = !self.transposed;
   }
 
   fn vec_mult(&self, row: &[u32], col_idx: usize) -> u32 {
@@ -61,43 +478,44 @@ impl DatabaseMatrix for IndexDatabase {
         self.entries[col_idx].len()
       );
     }
+    check_column_entries(&self.entries[col_idx], col_idx, self.plaintext_bits);
     vec_mult_u32_u32(row, &self.entries[col_idx]).unwrap()
   }
 
   fn write_to_file(&self, path: &str) -> ResultBoxedError<()> {
-    let json = json!(self.entries);
-    Ok(serde_json::to_writer(&fs::File::create(path)?, &json)?)
+    write_persisted(path, self)
   }
 
   /// Returns the ith row of the DB matrix
   fn get_row(&self, i: usize) -> Vec<u32> {
-    self.entries[i].clone()
+    self.entries[i].to_vec()
+  }
+
+  fn get_row_slice(&self, i: usize) -> &[u32] {
+    &self.entries[i]
   }
 
   /// Returns the ith DB entry as a base64-encoded string
   fn get_db_entry(&self, i: usize) -> String {
-    base64_from_u32_slice(
-      &get_matrix_second_at(&self.entries, i),
-      self.plaintext_bits,
-      self.elem_size,
-    )
+    base64::encode(self.entry_bytes(i))
   }
 
-  /// Returns the width of each row in the DB matrix
-  fn get_row_width(element_size: usize, plaintext_bits: usize) -> usize {
-    let mut quo = element_size / plaintext_bits;
-    if element_size % plaintext_bits != 0 {
-      quo += 1;
-    }
-    quo
+  /// Returns the raw bytes of the ith DB entry, failing if there is no
+  /// ith row
+  fn get_db_entry_bytes(&self, i: usize) -> ResultBoxedError<Vec<u8>> {
+    check_row_index(i, self.get_matrix_height())?;
+    Ok(self.entry_bytes(i))
   }
 
   /// Returns the width of each row in the DB matrix
   fn get_row_width_self(&self) -> usize {
-    IndexDatabase::get_row_width(
-      self.get_elem_size(),
-      self.get_plaintext_bits(),
-    )
+    match &self.column_bits {
+      Some(column_bits) => column_bits.len(),
+      None => IndexDatabase::get_row_width(
+        self.get_elem_size(),
+        self.get_plaintext_bits(),
+      ),
+    }
   }
 
   /// Get the matrix size
@@ -114,11 +532,37 @@ impl DatabaseMatrix for IndexDatabase {
   fn get_plaintext_bits(&self) -> usize {
     self.plaintext_bits
   }
+
+  fn get_column_bits(&self) -> Option<&[usize]> {
+    self.column_bits.as_deref()
+  }
+
+  fn get_row_major(&self) -> Option<&[Vec<u32>]> {
+    self.row_major.as_deref()
+  }
+
+  fn get_record_rows(&self) -> Option<&[usize]> {
+    self.row_map.as_ref().map(RowMap::get_rows)
+  }
+}
+impl DatabaseMatrixLayout for IndexDatabase {
+  /// Returns the width of each row in the DB matrix
+  fn get_row_width(element_size: usize, plaintext_bits: usize) -> usize {
+    let mut quo = element_size / plaintext_bits;
+    if element_size % plaintext_bits != 0 {
+      quo += 1;
+    }
+    quo
+  }
 }
 
 /// The `BaseParams` object allows loading and interacting with params that
-/// are used by the client for constructing queries
+/// are used by the client for constructing queries.
+///
+/// Params are checked when they are deserialized (see `check`), since
+/// clients download them from the server.
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(remote = "Self")]
 pub struct IndexParams {
   dim: usize,
   m: usize,
@@ -126,10 +570,58 @@ pub struct IndexParams {
   rhs: Vec<Vec<u32>>,
   elem_size: usize,
   plaintext_bits: usize,
+  /// Unix timestamp (in seconds) after which clients should no longer
+  /// use the params, e.g. because the DB is rotated
+  #[serde(default)]
+  expires_at: Option<u64>,
+  /// The compression applied to each record before it was encoded
+  #[serde(default)]
+  compression: Compression,
+  /// Whether each record is prefixed with its expiry timestamp (see
+  /// `expiry`)
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  record_expiry: bool,
+  /// The ciphertext modulus that queries and responses are reduced by.
+  /// It is omitted from the serialization when it is the default 2^32.
+  #[serde(default, skip_serializing_if = "Modulus::is_default")]
+  modulus: Modulus,
+  /// The number of plaintext bits held by each column, if they differ
+  /// between columns (see `IndexDatabase::new_with_column_bits`)
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  column_bits: Option<Vec<usize>>,
+  /// The matrix row holding each record, if the DB is deduplicated (see
+  /// `IndexDatabase::new_deduplicated`)
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  row_map: Option<Vec<usize>>,
+}
+impl Serialize for IndexParams {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    Self::serialize(self, serializer)
+  }
 }
+
+impl<'de> Deserialize<'de> for IndexParams {
+  fn deserialize<D: Deserializer<'de>>(
+    deserializer: D,
+  ) -> Result<Self, D::Error> {
+    let params = Self::deserialize(deserializer)?;
+    params.check().map_err(serde::de::Error::custom)?;
+    Ok(params)
+  }
+}
+
 impl IndexParams {
   pub fn new(db: &IndexDatabase, dim: usize) -> Self {
-    let public_seed = generate_seed();
+    Self::new_with_seed(db, dim, generate_seed())
+  }
+
+  /// Generates the params using a fixed `public_seed` for the LHS matrix,
+  /// so that they are reproducible
+  pub fn new_with_seed(
+    db: &IndexDatabase,
+    dim: usize,
+    public_seed: [u8; 32],
+  ) -> Self {
     Self {
       public_seed,
       rhs: Self::generate_params_rhs(db, public_seed, dim),
@@ -137,15 +629,217 @@ impl IndexParams {
       m: db.get_matrix_height(),
       elem_size: db.get_elem_size(),
       plaintext_bits: db.get_plaintext_bits(),
+      expires_at: None,
+      compression: Compression::None,
+      record_expiry: false,
+      modulus: Modulus::default(),
+      column_bits: db.get_column_bits().map(<[usize]>::to_vec),
+      row_map: db.get_row_map().map(|m| m.get_rows().to_vec()),
+    }
+  }
+
+  /// Checks that the modulus leaves room for the plaintext bits, and that
+  /// the column bits fit the elements and the RHS. The RHS may be stored
+  /// separately (see `split_rhs`), in which case it is checked when it is
+  /// joined.
+  fn check(&self) -> ResultBoxedError<()> {
+    self.modulus.check_plaintext_bits(self.plaintext_bits)?;
+    if let Some(column_bits) = &self.column_bits {
+      let width = match self.rhs.len() {
+        0 => column_bits.len(),
+        width => width,
+      };
+      check_column_bits(column_bits, self.elem_size, width)?;
     }
+    Ok(())
+  }
+
+  /// Returns the number of records of the DB, which exceeds the number of
+  /// matrix rows if the DB is deduplicated
+  pub fn num_records(&self) -> usize {
+    self.row_map.as_ref().map_or(self.m, Vec::len)
+  }
+
+  /// Returns the matrix row holding the ith record, which is `i` itself
+  /// unless the DB is deduplicated. `QueryParams` look the row up when
+  /// generating a query, so clients query records by their index.
+  pub fn get_record_row(&self, i: usize) -> ResultBoxedError<usize> {
+    let row = match &self.row_map {
+      Some(rows) => rows.get(i).copied(),
+      None => (i < self.m).then_some(i),
+    };
+    row.ok_or_else(|| {
+      ErrorUnexpectedInputSize::new(format!(
+        "Record {} is out of range for {} records",
+        i,
+        self.num_records()
+      ))
+      .into()
+    })
+  }
+
+  /// Returns the params for `db`, given that it was derived from the DB
+  /// of these params by `IndexDatabase::with_changes` with `deltas`.
+  ///
+  /// Since the LHS matrix is unchanged, only the contribution of the
+  /// changed rows to the RHS is recomputed, streaming the LHS rows from
+  /// the public seed up to the last changed row.
+  pub fn with_row_deltas(
+    &self,
+    db: &IndexDatabase,
+    deltas: &RowDeltas,
+  ) -> Self {
+    let mut params = self.clone();
+    add_row_deltas(&mut params.rhs, self.public_seed, self.dim, deltas);
+    params.m = db.get_matrix_height();
+    params
+  }
+
+  /// Returns the RHS columns of these params that differ from those of
+  /// `old`, as wrapping differences, or `None` if the params differ in
+  /// more than their RHS, number of rows and expiry (i.e. they were not
+  /// derived from `old` by `with_row_deltas`)
+  pub(crate) fn column_diffs_from(
+    &self,
+    old: &Self,
+  ) -> Option<Vec<(usize, Vec<u32>)>> {
+    let compatible = self.dim == old.dim
+      && self.public_seed == old.public_seed
+      && self.elem_size == old.elem_size
+      && self.plaintext_bits == old.plaintext_bits
+      && self.compression == old.compression
+      && self.record_expiry == old.record_expiry
+      && self.modulus == old.modulus
+      && self.column_bits == old.column_bits
+      && self.row_map == old.row_map
+      && self.rhs.len() == old.rhs.len();
+    compatible.then(|| rhs_column_diffs(&self.rhs, &old.rhs))
+  }
+
+  /// Returns the params with the column differences computed by
+  /// `column_diffs_from` added to the RHS, and the given number of rows
+  /// `m` and expiry
+  pub(crate) fn with_column_diffs(
+    &self,
+    diffs: &[(usize, Vec<u32>)],
+    m: usize,
+    expires_at: Option<u64>,
+  ) -> ResultBoxedError<Self> {
+    let mut params = self.clone();
+    add_rhs_column_diffs(&mut params.rhs, diffs)?;
+    params.m = m;
+    params.expires_at = expires_at;
+    Ok(params)
+  }
+
+  /// Returns the RHS, and a copy of the params without it, so that the
+  /// RHS can be stored separately (see `Shard::write_snapshot`)
+  pub(crate) fn split_rhs(&self) -> (&[Vec<u32>], Self) {
+    let rest = Self {
+      rhs: Vec::new(),
+      public_seed: self.public_seed,
+      dim: self.dim,
+      m: self.m,
+      elem_size: self.elem_size,
+      plaintext_bits: self.plaintext_bits,
+      expires_at: self.expires_at,
+      compression: self.compression,
+      record_expiry: self.record_expiry,
+      modulus: self.modulus,
+      column_bits: self.column_bits.clone(),
+      row_map: self.row_map.clone(),
+    };
+    (&self.rhs, rest)
+  }
+
+  /// Reverses `split_rhs`, checking the dimensions of `rhs`
+  pub(crate) fn join_rhs(self, rhs: Vec<Vec<u32>>) -> ResultBoxedError<Self> {
+    let width = match &self.column_bits {
+      Some(column_bits) => column_bits.len(),
+      None => IndexDatabase::get_row_width(self.elem_size, self.plaintext_bits),
+    };
+    if rhs.len() != width || rhs.iter().any(|c| c.len() != self.dim) {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "RHS of {} columns does not match {} columns of dimension {}",
+        rhs.len(),
+        width,
+        self.dim
+      ))));
+    }
+    Ok(Self { rhs, ..self })
+  }
+
+  /// Sets the Unix timestamp (in seconds) at which the params expire
+  pub fn set_expires_at(&mut self, expires_at: Option<u64>) {
+    self.expires_at = expires_at;
+  }
+
+  /// Records the compression that was applied to each record
+  pub(crate) fn set_compression(&mut self, compression: Compression) {
+    self.compression = compression;
+  }
+
+  /// Records whether each record is prefixed with its expiry timestamp
+  pub(crate) fn set_record_expiry(&mut self, record_expiry: bool) {
+    self.record_expiry = record_expiry;
+  }
+
+  /// Sets the ciphertext modulus, checking that it leaves room for the
+  /// plaintext bits
+  pub(crate) fn set_modulus(
+    &mut self,
+    modulus: Modulus,
+  ) -> ResultBoxedError<()> {
+    modulus.check_plaintext_bits(self.plaintext_bits)?;
+    self.modulus = modulus;
+    Ok(())
   }
 
   /// Load params from a JSON file
   pub fn load(params_path: &str) -> ResultBoxedError<Self> {
-    let reader = BufReader::new(fs::File::open(params_path)?);
-    Ok(serde_json::from_reader(reader)?)
+    Ok(read_persisted(params_path)?)
+  }
+
+  /// Loads params written by `write_to_file` in the legacy format, which
+  /// holds only the public seed and the RHS, for the DB loaded using
+  /// `IndexDatabase::load_legacy`. The remaining params are derived from
+  /// the DB, so they must be checked against it (see `verify_against`).
+  pub fn load_legacy(
+    params_path: &str,
+    db: &IndexDatabase,
+  ) -> ResultBoxedError<Self> {
+    let legacy: LegacyParams = read_json_file(params_path)?;
+    Ok(Self {
+      dim: legacy.rhs.first().map_or(0, Vec::len),
+      m: db.get_matrix_height(),
+      public_seed: legacy.lhs_seed,
+      rhs: legacy.rhs,
+      elem_size: db.get_elem_size(),
+      plaintext_bits: db.get_plaintext_bits(),
+      expires_at: None,
+      compression: Compression::None,
+      record_expiry: false,
+      modulus: Modulus::default(),
+      column_bits: None,
+      row_map: None,
+    })
   }
 }
+
+/// The params as written by `write_to_file` in the legacy format
+#[derive(Deserialize)]
+struct LegacyParams {
+  lhs_seed: [u8; 32],
+  rhs: Vec<Vec<u32>>,
+}
+impl Persist for IndexParams {
+  const KIND: &'static str = "IndexParams";
+
+  fn summary(&self) -> serde_json::Value {
+    params_summary(self)
+  }
+}
+
 impl BaseParams for IndexParams {
   fn get_total_records(&self) -> usize {
     self.m
@@ -170,38 +864,185 @@ impl BaseParams for IndexParams {
   fn get_rhs(&self) -> &Vec<Vec<u32>> {
     &self.rhs
   }
+
+  fn get_expires_at(&self) -> Option<u64> {
+    self.expires_at
+  }
+
+  fn get_compression(&self) -> Compression {
+    self.compression
+  }
+
+  fn get_record_expiry(&self) -> bool {
+    self.record_expiry
+  }
+
+  fn get_modulus(&self) -> Modulus {
+    self.modulus
+  }
+
+  fn get_column_bits(&self) -> Option<&[usize]> {
+    self.column_bits.as_deref()
+  }
+
+  fn get_record_rows(&self) -> Option<&[usize]> {
+    self.row_map.as_deref()
+  }
+
+  /// Writes the full params struct as JSON to file, preceded by a
+  /// `FileHeader`, so that it can be read back using `load`
+  fn write_to_file(&self, path: &str) -> ResultBoxedError<()> {
+    write_persisted(path, self)
+  }
 }
 
+/// Decodes `element` into a row of the DB matrix, whose ith entry holds
+/// the next `column_bits[i]` bits of the element
 fn construct_row(
   element: &str,
-  plaintext_bits: usize,
-  row_width: usize,
+  column_bits: &[usize],
+  encoding: EncodingConfig,
 ) -> ResultBoxedError<Vec<u32>> {
-  let mut row = Vec::with_capacity(row_width);
-  let bytes = base64::decode(element)?;
-  let bits = bytes_to_bits_le(&bytes);
-  for i in 0..row_width {
-    let end_bound = (i + 1) * plaintext_bits;
-    if end_bound < bits.len() {
-      row.push(bits_to_u32_le(&bits[i * plaintext_bits..end_bound])?);
-    } else {
-      row.push(bits_to_u32_le(&bits[i * plaintext_bits..])?);
-    }
-  }
-  Ok(row)
+  Ok(split_bits_le(&encoding.decode(element)?, column_bits)?)
 }
 
 fn construct_rows(
   elements: &[String],
   m: usize,
-  elem_size: usize,
-  plaintext_bits: usize,
+  column_bits: &[usize],
+  encoding: EncodingConfig,
 ) -> ResultBoxedError<Vec<Vec<u32>>> {
-  let row_width = IndexDatabase::get_row_width(elem_size, plaintext_bits);
-
   let result = (0..m).map(|i| -> ResultBoxedError<Vec<u32>> {
-    construct_row(&elements[i], plaintext_bits, row_width)
+    construct_row(&elements[i], column_bits, encoding)
   });
 
   result.collect()
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn column_bits_set_the_plaintext_bits() {
+    // 20-bit elements split into columns of 12 and 8 bits
+    let elements: Vec<String> = (0u8..4)
+      .map(|i| base64::encode([i, 0xf0 | i, 0x0a]))
+      .collect();
+    let db = IndexDatabase::new_with_column_bits(
+      &elements,
+      elements.len(),
+      20,
+      &[12, 8],
+      EncodingConfig::Standard,
+    )
+    .unwrap();
+    assert_eq!(db.get_plaintext_bits(), 12);
+    assert_eq!(db.get_column_bits(), Some(&[12, 8][..]));
+    for (i, element) in elements.iter().enumerate() {
+      assert_eq!(&db.get_db_entry(i), element);
+    }
+
+    for column_bits in [&[][..], &[12, 0, 8], &[20, 8]] {
+      assert!(IndexDatabase::new_with_column_bits(
+        &elements,
+        elements.len(),
+        20,
+        column_bits,
+        EncodingConfig::Standard,
+      )
+      .is_err());
+    }
+  }
+
+  #[test]
+  fn duplicate_records_share_a_row() {
+    let (a, b, c) = (
+      base64::encode([1u8; 4]),
+      base64::encode([2u8; 4]),
+      base64::encode([3u8; 4]),
+    );
+    let elements = [&a, &b, &a, &c, &b, &a].map(String::clone);
+    let db = IndexDatabase::new_deduplicated(
+      &elements,
+      32,
+      10,
+      EncodingConfig::Standard,
+    )
+    .unwrap();
+    assert_eq!(db.get_matrix_height(), 3);
+    let row_map = db.get_row_map().unwrap();
+    assert_eq!(row_map.num_records(), elements.len());
+    // rows are assigned in order of first occurrence
+    assert_eq!(row_map.get_rows(), &[0, 1, 0, 2, 1, 0]);
+    assert_eq!(db.get_record_rows(), Some(&[0, 1, 0, 2, 1, 0][..]));
+    assert_eq!(
+      (0..3).map(|i| row_map.get_ref_count(i)).collect::<Vec<_>>(),
+      [3, 2, 1]
+    );
+    assert_eq!(row_map.get_ref_count(3), 0);
+    assert_eq!(row_map.get_row(6), None);
+    for (i, element) in elements.iter().enumerate() {
+      let row = row_map.get_row(i).unwrap();
+      assert_eq!(&db.get_db_entry(row), element);
+    }
+    assert!(db.get_db_entry_bytes(3).is_err());
+    // rows cannot be changed in place, since records share them
+    assert!(db.with_changes(&[], &[0]).is_err());
+  }
+
+  #[test]
+  fn params_map_records_to_rows() {
+    let elements: Vec<String> = [5u8, 6, 5, 5]
+      .iter()
+      .map(|&b| base64::encode([b; 4]))
+      .collect();
+    let db = IndexDatabase::new_deduplicated(
+      &elements,
+      32,
+      10,
+      EncodingConfig::Standard,
+    )
+    .unwrap();
+    let params = IndexParams::new(&db, 64);
+    assert_eq!(params.num_records(), 4);
+    let rows: Vec<usize> =
+      (0..4).map(|i| params.get_record_row(i).unwrap()).collect();
+    assert_eq!(rows, [0, 1, 0, 0]);
+    assert!(params.get_record_row(4).is_err());
+
+    // without a row map, records are their own rows
+    let db = IndexDatabase::new(&elements, elements.len(), 32, 10).unwrap();
+    assert!(db.get_row_map().is_none());
+    let params = IndexParams::new(&db, 64);
+    assert_eq!(params.num_records(), 4);
+    assert_eq!(params.get_record_row(3).unwrap(), 3);
+    assert!(params.get_record_row(4).is_err());
+  }
+
+  #[test]
+  fn entries_are_returned_as_bytes() {
+    let records: Vec<Vec<u8>> =
+      (0u8..8).map(|i| vec![i, 0x80 | i, 0xff, 0]).collect();
+    let elements: Vec<String> = records.iter().map(base64::encode).collect();
+    let plain = IndexDatabase::new(&elements, 8, 32, 10).unwrap();
+    let split = IndexDatabase::new_with_column_bits(
+      &elements,
+      8,
+      32,
+      &[10, 10, 12],
+      EncodingConfig::Standard,
+    )
+    .unwrap();
+    for db in [&plain as &dyn DatabaseMatrix, &split] {
+      for (i, record) in records.iter().enumerate() {
+        assert_eq!(&db.get_db_entry_bytes(i).unwrap(), record);
+        let decoded = db.get_db_entry_decoded(i).unwrap();
+        assert_eq!(decoded.as_bytes(), record);
+        assert_eq!(decoded.encode(EncodingConfig::Standard), elements[i]);
+      }
+      assert!(db.get_db_entry_bytes(8).is_err());
+      assert!(db.get_db_entry_decoded(8).is_err());
+    }
+  }
+}
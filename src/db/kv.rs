@@ -1,21 +1,32 @@
-use std::fs;
+use std::collections::{BTreeMap, HashMap};
 
-use rand_core::{OsRng, RngCore};
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
 
-use crate::db::{BaseParams, DatabaseMatrix};
+use crate::compression::Compression;
+use crate::db::{
+  add_rhs_column_diffs, add_row_deltas, check_row_index, db_summary,
+  params_summary, rhs_column_diffs, BaseParams, DatabaseMatrix,
+  DatabaseMatrixLayout, HashedKey, RowDeltas,
+};
 
-use crate::errors::ResultBoxedError;
+use crate::errors::{
+  ErrorFilterEpochMismatch, ErrorParamsMismatch, ErrorUnexpectedInputSize,
+  ResultBoxedError,
+};
+use crate::protocol::constants::plaintext_modulus;
+use crate::utils::arith::*;
+use crate::utils::files::{read_persisted, write_persisted, Persist};
 use crate::utils::format::*;
+use crate::utils::lwe::Modulus;
 use crate::utils::matrices::*;
-use crate::utils::random::generate_seed;
+use crate::utils::random::{derive_seed, generate_seed};
 
-use xorf::BinaryFuseP32;
+use xorf::{BinaryFuseP32, FilterConfig, FingerprintPrf, MixPrf, SipHashPrf};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct KeyValue {
-  pub key: [u64; 4],
+  pub key: HashedKey,
   pub value: Vec<u32>,
 }
 
@@ -26,10 +37,89 @@ impl KeyValue {
     elem_size: usize,
     plaintext_bits: usize,
   ) -> ResultBoxedError<Self> {
-    let key = sha256_into_u64_sized(k.as_bytes())?;
-    let value = construct_row(v, plaintext_bits, elem_size)?;
+    Self::from_encoded_strings(
+      k,
+      v,
+      elem_size,
+      plaintext_bits,
+      EncodingConfig::Standard,
+    )
+  }
+
+  /// As `from_base64_strings`, but the value is decoded using `encoding`.
+  /// The key string is hashed as-is, regardless of the encoding.
+  pub fn from_encoded_strings(
+    k: &str,
+    v: &str,
+    elem_size: usize,
+    plaintext_bits: usize,
+    encoding: EncodingConfig,
+  ) -> ResultBoxedError<Self> {
+    let key = HashedKey::from_bytes(k.as_bytes());
+    let value = construct_row(v, plaintext_bits, elem_size, encoding)?;
     Ok(Self { key, value })
   }
+
+  /// Same as `from_base64_strings`, for a key that was already hashed
+  /// (i.e. the words of the SHA-256 digest of the key string, see
+  /// `HashedKey::from_bytes`) and the raw bytes of the value
+  pub fn from_hashed_key(
+    key: [u64; 4],
+    value: &[u8],
+    elem_size: usize,
+    plaintext_bits: usize,
+  ) -> ResultBoxedError<Self> {
+    Ok(Self {
+      key: HashedKey::from(key),
+      value: construct_row_from_bytes(value, plaintext_bits, elem_size)?,
+    })
+  }
+}
+
+/// The number of bytes of the length prefix of chunked values
+pub const CHUNK_HEADER_BYTES: usize = 4;
+
+/// Returns the key that the `i`th chunk of the value of `key` is stored
+/// under in a chunked KV DB, i.e. `key#i`
+pub fn chunk_key(key: &[u8], i: usize) -> Vec<u8> {
+  let mut chunk_key = key.to_vec();
+  chunk_key.extend(format!("#{}", i).as_bytes());
+  chunk_key
+}
+
+/// Splits `value` into chunks of `chunk_bytes` bytes, prefixed with the
+/// length of `value` as a little-endian u32 and zero-padded to a whole
+/// number of chunks, so that values of any length can be stored in a KV
+/// DB with a fixed element size
+pub fn split_into_chunks(
+  value: &[u8],
+  chunk_bytes: usize,
+) -> ResultBoxedError<Vec<Vec<u8>>> {
+  if chunk_bytes <= CHUNK_HEADER_BYTES || value.len() > u32::MAX as usize {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "value of {} bytes cannot be split into chunks of {} bytes",
+      value.len(),
+      chunk_bytes
+    ))));
+  }
+  let mut bytes = (value.len() as u32).to_le_bytes().to_vec();
+  bytes.extend(value);
+  bytes.resize(bytes.len().div_ceil(chunk_bytes) * chunk_bytes, 0);
+  Ok(bytes.chunks(chunk_bytes).map(|c| c.to_vec()).collect())
+}
+
+/// Reassembles a value from its chunks (see `split_into_chunks`), which
+/// may be followed by further chunks. Returns `None` if the length
+/// prefix exceeds the chunks, e.g. because the key was never stored.
+pub fn join_chunks(chunks: &[Vec<u8>]) -> Option<Vec<u8>> {
+  let bytes = chunks.concat();
+  let header = bytes.get(..CHUNK_HEADER_BYTES)?;
+  let len = u32::from_le_bytes(header.try_into().ok()?) as usize;
+  Some(
+    bytes
+      .get(CHUNK_HEADER_BYTES..CHUNK_HEADER_BYTES + len)?
+      .to_vec(),
+  )
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -39,6 +129,7 @@ struct StorageFilters {
   segment_length: u32,
   segment_length_mask: u32,
   segment_count_length: u32,
+  ptxt_mod: u64,
 }
 
 impl StorageFilters {
@@ -46,29 +137,52 @@ impl StorageFilters {
     kvs: &[KeyValue],
     row_width: usize,
     plaintext_bits: usize,
+    seed: [u8; 32],
+    config: &FilterConfig,
+    prf: &FilterPrf,
   ) -> ResultBoxedError<StorageFilters> {
-    let keys: Vec<[u64; 4]> = kvs.iter().map(|kv| kv.key).collect();
-    let mut seed = [0u8; 32];
-    OsRng.fill_bytes(&mut seed);
+    if kvs.is_empty() || row_width == 0 {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "cannot construct filters for {} KV pairs of {} columns",
+        kvs.len(),
+        row_width
+      ))));
+    }
+    let keys: Vec<[u64; 4]> = kvs.iter().map(|kv| *kv.key.as_words()).collect();
+    // the filter seed is public, so unused entries are filled using
+    // separately derived (secret) seeds
+    let fill_seed = derive_seed(seed, b"fill");
+    let seed = derive_seed(seed, b"filter");
     let filters: Vec<BinaryFuseP32> = (0..row_width)
       .map(|i| {
         let column: Vec<u32> = kvs.iter().map(|kv| kv.value[i]).collect();
-        BinaryFuseP32::from_slice(
+        BinaryFuseP32::from_slice_with_prf(
           seed,
           &keys,
           &column,
           i as u64,
-          2u64.pow(plaintext_bits as u32),
+          plaintext_modulus(plaintext_bits),
+          Some(derive_seed(fill_seed, &(i as u64).to_le_bytes())),
+          config,
+          prf,
         )
-        .unwrap()
       })
-      .collect();
+      .collect::<Result<Vec<BinaryFuseP32>, &'static str>>()?;
+    // Construction succeeds for the same (derived) seed on every column,
+    // as it only depends on the keys
+    let seed = filters[0].seed();
+    if filters.iter().any(|f| f.seed() != seed) {
+      return Err(
+        "Storage filters were constructed with distinct seeds".into(),
+      );
+    }
     Ok(StorageFilters {
       filters: filters.clone(),
       seed,
       segment_length: filters[0].segment_length,
       segment_length_mask: filters[0].segment_length_mask,
       segment_count_length: filters[0].segment_count_length,
+      ptxt_mod: filters[0].ptxt_mod(),
     })
   }
 
@@ -81,17 +195,131 @@ impl StorageFilters {
   }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// The keyed PRF used to mask the values stored in the storage filters
+/// (see `xorf::FingerprintPrf`). It is part of the `FilterParams`, so
+/// that clients unmask values using the same PRF as the server.
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum FilterPrf {
+  /// The MurmurHash3 finalizer mix (`xorf::MixPrf`)
+  #[default]
+  Mix,
+  /// SipHash-2-4 with the given key (`xorf::SipHashPrf`)
+  SipHash24([u8; 16]),
+}
+
+impl FilterPrf {
+  pub fn is_default(&self) -> bool {
+    *self == Self::default()
+  }
+}
+
+impl FingerprintPrf for FilterPrf {
+  fn fingerprint(&self, hash: u64, label: u64) -> u64 {
+    match self {
+      FilterPrf::Mix => MixPrf.fingerprint(hash, label),
+      FilterPrf::SipHash24(key) => {
+        SipHashPrf { key: *key }.fingerprint(hash, label)
+      }
+    }
+  }
+}
+
+/// Returns the seed that the filters of `epoch` are derived from, given
+/// the `seed` of the DB. Re-seeding the filters in every epoch changes
+/// the positions of all keys and the masks of all values, so the filter
+/// params of one epoch are useless for querying the DB of another. Epoch
+/// zero uses `seed` as is.
+pub fn epoch_seed(seed: [u8; 32], epoch: u64) -> [u8; 32] {
+  if epoch == 0 {
+    return seed;
+  }
+  derive_seed(seed, &[b"epoch".as_slice(), &epoch.to_le_bytes()].concat())
+}
+
+fn is_initial_epoch(epoch: &u64) -> bool {
+  *epoch == 0
+}
+
+/// The public params of the filters of a KV DB, which clients need to
+/// locate the filter slots of a key and unmask its value. Fields may be
+/// added in later versions, so params are constructed using `new`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct FilterParams {
   pub seed: [u8; 32],
   pub segment_length: u32,
   pub segment_length_mask: u32,
   pub segment_count_length: u32,
+  /// It is omitted from the serialization when it is the default
+  #[serde(default, skip_serializing_if = "FilterPrf::is_default")]
+  pub prf: FilterPrf,
+  /// The plaintext modulus that values are stored modulo. It is `None`
+  /// for params serialized before it was recorded.
+  #[serde(default)]
+  pub ptxt_mod: Option<u64>,
+  /// The epoch that the filter seed was derived for (see `epoch_seed`).
+  /// It is omitted from the serialization when it is the initial epoch.
+  #[serde(default, skip_serializing_if = "is_initial_epoch")]
+  pub epoch: u64,
 }
 impl FilterParams {
-  pub fn get_hash_evals(&self, key: &[u64; 4]) -> Vec<usize> {
+  /// Returns the params of filters with the given seed and segments, the
+  /// default PRF, no recorded plaintext modulus and the initial epoch
+  pub fn new(
+    seed: [u8; 32],
+    segment_length: u32,
+    segment_length_mask: u32,
+    segment_count_length: u32,
+  ) -> Self {
+    Self {
+      seed,
+      segment_length,
+      segment_length_mask,
+      segment_count_length,
+      prf: FilterPrf::default(),
+      ptxt_mod: None,
+      epoch: 0,
+    }
+  }
+
+  /// Checks that the filter params are of the `epoch` that is served,
+  /// since the params of other epochs decode garbage
+  pub fn check_epoch(&self, epoch: u64) -> ResultBoxedError<()> {
+    if self.epoch != epoch {
+      return Err(Box::new(ErrorFilterEpochMismatch {
+        expected: epoch,
+        found: self.epoch,
+      }));
+    }
+    Ok(())
+  }
+
+  /// Checks that the filters store values modulo `2^plaintext_bits`, if
+  /// the plaintext modulus was recorded
+  pub fn check_plaintext_bits(
+    &self,
+    plaintext_bits: usize,
+  ) -> ResultBoxedError<()> {
+    // plaintext moduli of 2^64 and above do not fit a u64
+    let expected = u32::try_from(plaintext_bits)
+      .ok()
+      .and_then(|b| 1u64.checked_shl(b));
+    match self.ptxt_mod {
+      Some(ptxt_mod) if Some(ptxt_mod) != expected => {
+        Err(Box::new(ErrorParamsMismatch::new(format!(
+          "filter plaintext modulus {} does not match plaintext_bits {}",
+          ptxt_mod, plaintext_bits
+        ))))
+      }
+      _ => Ok(()),
+    }
+  }
+
+  pub fn get_hash_evals(&self, key: &HashedKey) -> Vec<usize> {
     BinaryFuseP32::hash_eval(
-      key,
+      key.as_words(),
       self.seed,
       self.segment_length,
       self.segment_length_mask,
@@ -99,37 +327,163 @@ impl FilterParams {
     )
   }
 
-  fn get_key_fingerprint(&self, key: &[u64; 4], label: u64) -> u64 {
-    BinaryFuseP32::get_key_fingerprint(key, self.seed, label)
+  /// Same as `get_hash_evals`, for many keys at once
+  pub fn get_hash_evals_batch(&self, keys: &[HashedKey]) -> Vec<Vec<usize>> {
+    let keys: Vec<[u64; 4]> = keys.iter().map(|k| *k.as_words()).collect();
+    BinaryFuseP32::hash_eval_batch(
+      &keys,
+      self.seed,
+      self.segment_length,
+      self.segment_length_mask,
+      self.segment_count_length,
+    )
   }
 
-  pub fn unmask_value(&self, masked: u32, key: &[u64; 4], label: u64) -> u32 {
+  fn get_key_fingerprint(&self, key: &HashedKey, label: u64) -> u64 {
+    BinaryFuseP32::get_key_fingerprint_with_prf(
+      key.as_words(),
+      self.seed,
+      label,
+      &self.prf,
+    )
+  }
+
+  pub fn unmask_value(&self, masked: u32, key: &HashedKey, label: u64) -> u32 {
     masked.wrapping_add(self.get_key_fingerprint(key, label) as u32)
   }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct KVDatabase {
-  pub entries: Vec<Vec<u32>>,
-  m: usize,
+  pub(crate) entries: Vec<Vec<u32>>,
+  #[serde(alias = "m")]
+  num_keys: usize,
   elem_size: usize,
   plaintext_bits: usize,
   filter_params: FilterParams,
+  /// A row-major copy of `entries`, if maintained (see `set_dual_layout`)
+  #[serde(skip)]
+  row_major: Option<Vec<Vec<u32>>>,
+  /// Whether `entries` hold the rows rather than the columns of the DB
+  /// matrix, i.e. `switch_fmt` was called an odd number of times
+  #[serde(skip)]
+  transposed: bool,
+  /// The digest of the set of keys stored in the DB (see
+  /// `key_set_digest`), absent for DBs serialized before it was recorded
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  key_digest: Option<[u8; 32]>,
+  /// The config that the filters were constructed with
+  #[serde(
+    default,
+    skip_serializing_if = "is_default_config",
+    with = "FilterConfigDef"
+  )]
+  filter_config: FilterConfig,
+}
+
+/// Serializes `FilterConfig`, which is defined in `xorf`
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "FilterConfig")]
+struct FilterConfigDef {
+  max_iterations: usize,
+  size_factor_override: Option<f64>,
+}
+
+fn is_default_config(config: &FilterConfig) -> bool {
+  *config == FilterConfig::default()
+}
+
+/// Digests the set of `keys`, independently of their order
+fn key_set_digest<'a>(keys: impl Iterator<Item = &'a HashedKey>) -> [u8; 32] {
+  let mut sorted: Vec<&HashedKey> = keys.collect();
+  sorted.sort_unstable();
+  let mut h = Sha256::new();
+  for key in sorted {
+    key
+      .as_words()
+      .iter()
+      .for_each(|w| h.update(w.to_le_bytes()));
+  }
+  h.finalize().into()
 }
 
 impl KVDatabase {
   pub fn new(
     kvs: &[KeyValue],
-    m: usize,
     elem_size: usize,
     plaintext_bits: usize,
   ) -> ResultBoxedError<Self> {
-    let row_width = kvs[0].value.len();
-    let filters = StorageFilters::from_kvs(kvs, row_width, plaintext_bits)?;
+    Self::new_with_seed(kvs, elem_size, plaintext_bits, generate_seed())
+  }
+
+  /// Constructs the database deriving all randomness of the filters from
+  /// `seed`, so that they are reproducible. The seed must be kept secret,
+  /// as it determines the unused filter entries.
+  pub fn new_with_seed(
+    kvs: &[KeyValue],
+    elem_size: usize,
+    plaintext_bits: usize,
+    seed: [u8; 32],
+  ) -> ResultBoxedError<Self> {
+    Self::new_with_config(
+      kvs,
+      elem_size,
+      plaintext_bits,
+      seed,
+      &FilterConfig::default(),
+    )
+  }
+
+  /// Same as `new_with_seed`, constructing the filters according to
+  /// `config` (e.g. to bound the construction time for pathological key
+  /// sets)
+  pub fn new_with_config(
+    kvs: &[KeyValue],
+    elem_size: usize,
+    plaintext_bits: usize,
+    seed: [u8; 32],
+    config: &FilterConfig,
+  ) -> ResultBoxedError<Self> {
+    Self::new_with_prf(
+      kvs,
+      elem_size,
+      plaintext_bits,
+      seed,
+      config,
+      FilterPrf::default(),
+    )
+  }
+
+  /// Same as `new_with_config`, masking the values stored in the filters
+  /// using `prf`
+  pub fn new_with_prf(
+    kvs: &[KeyValue],
+    elem_size: usize,
+    plaintext_bits: usize,
+    seed: [u8; 32],
+    config: &FilterConfig,
+    prf: FilterPrf,
+  ) -> ResultBoxedError<Self> {
+    let row_width = match kvs.first() {
+      Some(kv) => kv.value.len(),
+      None => {
+        return Err(Box::new(ErrorUnexpectedInputSize::new(
+          "cannot construct a KV DB without any KV pairs".to_string(),
+        )))
+      }
+    };
+    let filters = StorageFilters::from_kvs(
+      kvs,
+      row_width,
+      plaintext_bits,
+      seed,
+      config,
+      &prf,
+    )?;
 
     Ok(Self {
       entries: filters.get_columns(),
-      m,
+      num_keys: kvs.len(),
       elem_size,
       plaintext_bits,
       filter_params: FilterParams {
@@ -137,16 +491,59 @@ impl KVDatabase {
         segment_length: filters.segment_length,
         segment_length_mask: filters.segment_length_mask,
         segment_count_length: filters.segment_count_length,
+        prf,
+        ptxt_mod: Some(filters.ptxt_mod),
+        epoch: 0,
       },
+      row_major: None,
+      transposed: false,
+      key_digest: Some(key_set_digest(kvs.iter().map(|kv| &kv.key))),
+      filter_config: *config,
     })
   }
 
   pub fn from_base64_strings(
     keys: &[String],
     values: &[String],
-    m: usize,
     elem_size: usize,
     plaintext_bits: usize,
+  ) -> ResultBoxedError<Self> {
+    Self::from_encoded_strings(
+      keys,
+      values,
+      elem_size,
+      plaintext_bits,
+      EncodingConfig::Standard,
+    )
+  }
+
+  /// Constructs the database from values serialized using `encoding`
+  pub fn from_encoded_strings(
+    keys: &[String],
+    values: &[String],
+    elem_size: usize,
+    plaintext_bits: usize,
+    encoding: EncodingConfig,
+  ) -> ResultBoxedError<Self> {
+    Self::from_encoded_strings_with_seed(
+      keys,
+      values,
+      elem_size,
+      plaintext_bits,
+      encoding,
+      generate_seed(),
+    )
+  }
+
+  /// Same as `from_encoded_strings`, deriving all randomness of the
+  /// filters from `seed` (see `new_with_seed`)
+  pub fn from_encoded_strings_with_seed(
+    keys: &[String],
+    values: &[String],
+    elem_size: usize,
+    plaintext_bits: usize,
+    encoding: EncodingConfig,
+    seed: [u8; 32],
   ) -> ResultBoxedError<Self> {
     if keys.len() != values.len() {
       return Err(
@@ -160,11 +557,12 @@ impl KVDatabase {
     }
     let res: ResultBoxedError<Vec<KeyValue>> = (0..keys.len())
       .map(|i| {
-        KeyValue::from_base64_strings(
+        KeyValue::from_encoded_strings(
           &keys[i],
           &values[i],
           elem_size,
           plaintext_bits,
+          encoding,
         )
       })
       .collect::<Vec<ResultBoxedError<KeyValue>>>()
@@ -176,26 +574,290 @@ impl KVDatabase {
       );
     }
     let kvs = res.unwrap();
-    KVDatabase::new(&kvs, m, elem_size, plaintext_bits)
+    KVDatabase::new_with_seed(&kvs, elem_size, plaintext_bits, seed)
+  }
+
+  /// Constructs the database from keys that were already hashed (see
+  /// `KeyValue::from_hashed_key`) and the raw bytes of the values, e.g.
+  /// for pipelines that store the SHA-256 digests of the keys rather
+  /// than the key strings. Clients query the original key strings as
+  /// usual.
+  pub fn from_hashed_keys(
+    keys: &[[u64; 4]],
+    values: &[Vec<u8>],
+    elem_size: usize,
+    plaintext_bits: usize,
+  ) -> ResultBoxedError<Self> {
+    Self::from_hashed_keys_with_seed(
+      keys,
+      values,
+      elem_size,
+      plaintext_bits,
+      generate_seed(),
+    )
+  }
+
+  /// Same as `from_hashed_keys`, deriving all randomness of the filters
+  /// from `seed` (see `new_with_seed`)
+  pub fn from_hashed_keys_with_seed(
+    keys: &[[u64; 4]],
+    values: &[Vec<u8>],
+    elem_size: usize,
+    plaintext_bits: usize,
+    seed: [u8; 32],
+  ) -> ResultBoxedError<Self> {
+    if keys.len() != values.len() {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "Number of keys ({}) does not match number of values ({})",
+        keys.len(),
+        values.len()
+      ))));
+    }
+    let kvs = keys
+      .iter()
+      .zip(values)
+      .map(|(&k, v)| KeyValue::from_hashed_key(k, v, elem_size, plaintext_bits))
+      .collect::<ResultBoxedError<Vec<KeyValue>>>()?;
+    KVDatabase::new_with_seed(&kvs, elem_size, plaintext_bits, seed)
+  }
+
+  /// Returns a copy of the DB in which the values of `changes` replace
+  /// the values stored for their keys, together with the rows of the DB
+  /// matrix that changed, or `None` if the values cannot be changed in
+  /// place.
+  ///
+  /// `keys` must be all keys stored in the DB. Each key owns the filter
+  /// slot it was assigned when the filters were solved, so a changed
+  /// value is absorbed by the slot of its key, which in turn changes the
+  /// values of the keys that hash to that slot and were assigned after
+  /// it. Only the slots along these chains are re-solved, keeping the
+  /// filter seed, and `KVParams::with_row_deltas` recomputes the params
+  /// from the changed rows. The chains of a handful of changes typically
+  /// reach a fraction of the slots, so large changesets are better served
+  /// by rebuilding the DB. `None` is returned if `keys` are not the keys
+  /// of the DB (e.g. keys were added, removed or replaced), or if the DB
+  /// was serialized without a digest of its keys, in which case the DB
+  /// must be rebuilt.
+  pub fn with_value_changes(
+    &self,
+    keys: &[HashedKey],
+    changes: &[KeyValue],
+  ) -> ResultBoxedError<Option<(Self, RowDeltas)>> {
+    let row_width = self.get_row_width_self();
+    if let Some(kv) = changes.iter().find(|kv| kv.value.len() != row_width) {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "value of width {} for DB of row width {}",
+        kv.value.len(),
+        row_width
+      ))));
+    }
+    if keys.len() != self.num_keys
+      || self.key_digest != Some(key_set_digest(keys.iter()))
+    {
+      return Ok(None);
+    }
+    let fp = &self.filter_params;
+    let slots = fp.get_hash_evals_batch(keys);
+    let m = self.get_matrix_height();
+    let Some(assigned) = assignment_order(&slots, m) else {
+      return Ok(None);
+    };
+    let mut position = vec![0; keys.len()];
+    for (p, &(k, _)) in assigned.iter().enumerate() {
+      position[k] = p;
+    }
+    let mut slot_keys = vec![Vec::new(); m];
+    for (k, evals) in slots.iter().enumerate() {
+      evals.iter().for_each(|&s| slot_keys[s].push(k));
+    }
+    let key_indices: HashMap<&HashedKey, usize> =
+      keys.iter().enumerate().map(|(k, key)| (key, k)).collect();
+    let mut changed = Vec::with_capacity(changes.len());
+    for kv in changes {
+      match key_indices.get(&kv.key) {
+        Some(&k) => changed.push((k, kv)),
+        None => return Ok(None),
+      }
+    }
+
+    // values are reduced using a mask, as a plaintext modulus of 2^32
+    // does not fit a u32
+    let ptxt_mask = (plaintext_modulus(self.plaintext_bits) - 1) as u32;
+    let mut db = self.clone();
+    let mut deltas: BTreeMap<usize, Vec<u32>> = BTreeMap::new();
+    for (i, col) in db.entries.iter_mut().enumerate() {
+      // the change required of the sum of the slots of each key, in
+      // assignment order
+      let mut pending: BTreeMap<usize, u32> = BTreeMap::new();
+      for &(k, kv) in &changed {
+        let masked = slots[k]
+          .iter()
+          .fold(0u32, |acc, &s| acc.wrapping_add(col[s]));
+        let fingerprint = fp.get_key_fingerprint(&kv.key, i as u64) as u32;
+        let target = kv.value[i].wrapping_sub(fingerprint);
+        pending.insert(position[k], target.wrapping_sub(masked) & ptxt_mask);
+      }
+      let mut col_deltas: HashMap<usize, u32> = HashMap::new();
+      while let Some((p, required)) = pending.pop_first() {
+        let (k, own) = assigned[p];
+        let others = slots[k]
+          .iter()
+          .filter_map(|s| col_deltas.get(s))
+          .fold(0u32, |acc, d| acc.wrapping_add(*d));
+        let delta = required.wrapping_sub(others) & ptxt_mask;
+        if delta == 0 {
+          continue;
+        }
+        col_deltas.insert(own, delta);
+        // the keys sharing the slot were assigned after this key
+        for &other in slot_keys[own].iter().filter(|&&other| other != k) {
+          pending.entry(position[other]).or_insert(0);
+        }
+      }
+      for (s, delta) in col_deltas {
+        let new = col[s].wrapping_add(delta) & ptxt_mask;
+        deltas.entry(s).or_insert_with(|| vec![0; row_width])[i] =
+          new.wrapping_sub(col[s]);
+        col[s] = new;
+        if let Some(rows) = &mut db.row_major {
+          rows[s][i] = new;
+        }
+      }
+    }
+    Ok(Some((db, deltas.into_iter().collect())))
   }
 
   pub fn get_filter_params(&self) -> &FilterParams {
     &self.filter_params
   }
+
+  /// Returns the columns of the DB matrix. They are read-only, so that
+  /// the row-major copy of a dual layout DB cannot go stale.
+  pub fn get_entries(&self) -> &[Vec<u32>] {
+    &self.entries
+  }
+
+  /// Records the epoch that the filter seed was derived for
+  pub(crate) fn set_filter_epoch(&mut self, epoch: u64) {
+    self.filter_params.epoch = epoch;
+  }
+
+  /// Returns the number of KV pairs stored in the database
+  pub fn num_keys(&self) -> usize {
+    self.num_keys
+  }
+
+  /// Returns the config that the filters were constructed with
+  pub fn get_filter_config(&self) -> FilterConfig {
+    self.filter_config
+  }
+
+  /// Returns the raw bytes of the ith DB entry. Panics if there is no ith
+  /// row.
+  fn entry_bytes(&self, i: usize) -> Vec<u8> {
+    bytes_from_u32_slice(
+      &get_matrix_second_at(&self.entries, i),
+      self.plaintext_bits,
+      self.elem_size,
+    )
+  }
+
+  /// Returns the number of rows in the DB matrix, i.e. the length of the
+  /// filters that the KV pairs are stored in. This is the value of `m`
+  /// that clients and public parameters operate over.
+  pub fn matrix_height(&self) -> usize {
+    self.get_matrix_height()
+  }
+
+  /// Returns the ratio of stored KV pairs to matrix rows
+  pub fn load_factor(&self) -> f64 {
+    self.num_keys as f64 / self.matrix_height() as f64
+  }
+
+  /// Estimates the number of rows of the DB matrix for `num_keys` KV
+  /// pairs from the sizing of the binary fuse filters, without building
+  /// the DB
+  pub fn estimate_matrix_height(num_keys: usize) -> usize {
+    Self::estimate_matrix_height_with_config(num_keys, &FilterConfig::default())
+  }
+
+  /// Same as `estimate_matrix_height`, for filters constructed according
+  /// to `config` (see `new_with_config`)
+  pub fn estimate_matrix_height_with_config(
+    num_keys: usize,
+    config: &FilterConfig,
+  ) -> usize {
+    BinaryFuseP32::array_len(num_keys, config)
+  }
+
+  /// Estimates the sizes of a DB of `num_keys` values of `elem_size` bits
+  /// and of the traffic of its clients, for LWE dimension `lwe_dim`
+  pub fn estimate_capacity(
+    num_keys: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+    lwe_dim: usize,
+  ) -> KVCapacity {
+    let matrix_height = Self::estimate_matrix_height(num_keys);
+    let row_width = Self::get_row_width(elem_size, plaintext_bits);
+    KVCapacity {
+      matrix_height,
+      row_width,
+      load_factor: num_keys as f64 / matrix_height as f64,
+      db_bytes: matrix_height * row_width * 4,
+      hint_bytes: lwe_dim * row_width * 4,
+      query_bytes: matrix_height * 4,
+      response_bytes: row_width * 4,
+    }
+  }
+
+  /// Touches every memory page of the DB matrix, so that the first
+  /// queries do not incur page-fault latency
+  pub fn prefault(&self) {
+    touch_matrix_pages(&self.entries);
+  }
+
+  /// Maintains a row-major copy of the DB matrix next to the entries if
+  /// `enabled` (see `IndexDatabase::set_dual_layout`)
+  pub fn set_dual_layout(&mut self, enabled: bool) {
+    self.row_major = enabled.then(|| match self.transposed {
+      true => self.entries.clone(),
+      false => swap_matrix_fmt(&self.entries),
+    });
+  }
+
+  /// Load a database that was written using `write_to_file`
+  pub fn load(db_path: &str) -> ResultBoxedError<Self> {
+    Ok(read_persisted(db_path)?)
+  }
+}
+
+impl Persist for KVDatabase {
+  const KIND: &'static str = "KVDatabase";
+
+  fn summary(&self) -> serde_json::Value {
+    db_summary(self)
+  }
 }
 
 impl DatabaseMatrix for KVDatabase {
+  /// Transposes `entries`. The row-major copy of a dual layout DB (see
+  /// `set_dual_layout`) does not depend on the orientation of `entries`,
+  /// so it is kept.
   fn switch_fmt(&mut self) {
     self.entries = swap_matrix_fmt(&self.entries);
+    self.transposed = !self.transposed;
   }
 
   fn vec_mult(&self, row: &[u32], col_idx: usize) -> u32 {
-    vec_mult_u32_u32(row, &self.entries[col_idx]).unwrap()
+    let col = &self.entries[col_idx];
+    check_mult_lengths(row, col, col_idx);
+    check_column_entries(col, col_idx, self.plaintext_bits);
+    vec_mult_u32_u32(row, col).unwrap()
   }
 
   fn write_to_file(&self, path: &str) -> ResultBoxedError<()> {
-    let json = json!(self.entries);
-    Ok(serde_json::to_writer(&fs::File::create(path)?, &json)?)
+    write_persisted(path, self)
   }
 
   /// Returns the ith row of the DB matrix
@@ -203,22 +865,20 @@ impl DatabaseMatrix for KVDatabase {
     self.entries[i].clone()
   }
 
+  fn get_row_slice(&self, i: usize) -> &[u32] {
+    &self.entries[i]
+  }
+
   /// Returns the ith DB entry as a base64-encoded string
   fn get_db_entry(&self, i: usize) -> String {
-    base64_from_u32_slice(
-      &get_matrix_second_at(&self.entries, i),
-      self.plaintext_bits,
-      self.elem_size,
-    )
+    base64::encode(self.entry_bytes(i))
   }
 
-  /// Returns the width of each row in the DB matrix
-  fn get_row_width(element_size: usize, plaintext_bits: usize) -> usize {
-    let mut quo = element_size / plaintext_bits;
-    if element_size % plaintext_bits != 0 {
-      quo += 1;
-    }
-    quo
+  /// Returns the raw bytes of the ith DB entry, failing if there is no
+  /// ith row
+  fn get_db_entry_bytes(&self, i: usize) -> ResultBoxedError<Vec<u8>> {
+    check_row_index(i, self.get_matrix_height())?;
+    Ok(self.entry_bytes(i))
   }
 
   /// Returns the width of each row in the DB matrix
@@ -240,31 +900,111 @@ impl DatabaseMatrix for KVDatabase {
   fn get_plaintext_bits(&self) -> usize {
     self.plaintext_bits
   }
+
+  fn get_row_major(&self) -> Option<&[Vec<u32>]> {
+    self.row_major.as_deref()
+  }
+}
+impl DatabaseMatrixLayout for KVDatabase {
+  /// Returns the width of each row in the DB matrix
+  fn get_row_width(element_size: usize, plaintext_bits: usize) -> usize {
+    let mut quo = element_size / plaintext_bits;
+    if element_size % plaintext_bits != 0 {
+      quo += 1;
+    }
+    quo
+  }
 }
 
 fn construct_row(
   element: &str,
   plaintext_bits: usize,
   elem_size: usize,
+  encoding: EncodingConfig,
+) -> ResultBoxedError<Vec<u32>> {
+  construct_row_from_bytes(
+    &encoding.decode(element)?,
+    plaintext_bits,
+    elem_size,
+  )
+}
+
+fn construct_row_from_bytes(
+  bytes: &[u8],
+  plaintext_bits: usize,
+  elem_size: usize,
 ) -> ResultBoxedError<Vec<u32>> {
   let row_width = KVDatabase::get_row_width(elem_size, plaintext_bits);
-  let mut row = Vec::with_capacity(row_width);
-  let bytes = base64::decode(element)?;
-  let bits = bytes_to_bits_le(&bytes);
-  for i in 0..row_width {
-    let end_bound = (i + 1) * plaintext_bits;
-    if end_bound < bits.len() {
-      row.push(bits_to_u32_le(&bits[i * plaintext_bits..end_bound])?);
-    } else {
-      row.push(bits_to_u32_le(&bits[i * plaintext_bits..])?);
+  Ok(split_bits_le(bytes, &vec![plaintext_bits; row_width])?)
+}
+
+/// Returns the keys with the slots of `slots` in the order in which
+/// their filter slots are assigned, i.e. the reverse of the order in
+/// which they are peeled off the filter hypergraph, each with the slot
+/// that it owns: none of the keys assigned before it hashes to that
+/// slot. Returns `None` if the keys do not peel, e.g. because some key
+/// is repeated.
+fn assignment_order(
+  slots: &[Vec<usize>],
+  m: usize,
+) -> Option<Vec<(usize, usize)>> {
+  let mut counts = vec![0usize; m];
+  let mut xors = vec![0usize; m];
+  for (k, evals) in slots.iter().enumerate() {
+    for &s in evals {
+      counts[s] += 1;
+      xors[s] ^= k;
+    }
+  }
+  let mut stack: Vec<usize> = (0..m).filter(|&s| counts[s] == 1).collect();
+  let mut peeled = Vec::with_capacity(slots.len());
+  while let Some(own) = stack.pop() {
+    if counts[own] != 1 {
+      continue;
+    }
+    let k = xors[own];
+    peeled.push((k, own));
+    for &s in &slots[k] {
+      counts[s] -= 1;
+      xors[s] ^= k;
+      if counts[s] == 1 {
+        stack.push(s);
+      }
     }
   }
-  Ok(row)
+  if peeled.len() != slots.len() {
+    return None;
+  }
+  peeled.reverse();
+  Some(peeled)
+}
+
+/// A `KVCapacity` is an estimate of the size of a KV DB before it is
+/// built (see `KVDatabase::estimate_capacity`). Sizes are in bytes, and
+/// count the u32 entries of each matrix or vector.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KVCapacity {
+  /// The number of rows of the DB matrix, i.e. the length of queries
+  pub matrix_height: usize,
+  /// The number of columns of the DB matrix, i.e. the length of responses
+  pub row_width: usize,
+  /// The ratio of stored KV pairs to matrix rows
+  pub load_factor: f64,
+  /// The size of the DB matrix held by the server
+  pub db_bytes: usize,
+  /// The size of the RHS of the params, i.e. the hint downloaded by
+  /// clients
+  pub hint_bytes: usize,
+  /// The size of a query
+  pub query_bytes: usize,
+  /// The size of a response
+  pub response_bytes: usize,
 }
 
 /// The `KVBaseParams` struct maintains additional functions compared
 /// with `IndexParams`, for interacting with KV databases.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(remote = "Self")]
 pub struct KVParams {
   dim: usize,
   m: usize,
@@ -273,7 +1013,46 @@ pub struct KVParams {
   elem_size: usize,
   plaintext_bits: usize,
   filter_params: FilterParams,
+  /// Unix timestamp (in seconds) after which clients should no longer
+  /// use the params, e.g. because the DB is rotated
+  #[serde(default)]
+  expires_at: Option<u64>,
+  /// The compression applied to each record before it was encoded
+  #[serde(default)]
+  compression: Compression,
+  /// Whether each record is prefixed with its expiry timestamp (see
+  /// `expiry`)
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  record_expiry: bool,
+  /// The ciphertext modulus that queries and responses are reduced by.
+  /// It is omitted from the serialization when it is the default 2^32.
+  #[serde(default, skip_serializing_if = "Modulus::is_default")]
+  modulus: Modulus,
+  /// The number of chunks that clients query for each key, if values
+  /// longer than the element size were split into chunks (see
+  /// `KVShard::from_encoded_strings_for_epoch`)
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  chunks: Option<usize>,
+}
+impl Serialize for KVParams {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    Self::serialize(self, serializer)
+  }
+}
+
+impl<'de> Deserialize<'de> for KVParams {
+  fn deserialize<D: Deserializer<'de>>(
+    deserializer: D,
+  ) -> Result<Self, D::Error> {
+    let params = Self::deserialize(deserializer)?;
+    params
+      .modulus
+      .check_plaintext_bits(params.plaintext_bits)
+      .map_err(serde::de::Error::custom)?;
+    Ok(params)
+  }
 }
+
 impl KVParams {
   pub fn new(
     db: &KVDatabase,
@@ -283,7 +1062,28 @@ impl KVParams {
     segment_length_mask: u32,
     segment_count_length: u32,
   ) -> Self {
-    let public_seed = generate_seed();
+    Self::new_with_seed(
+      db,
+      dim,
+      seed,
+      segment_length,
+      segment_length_mask,
+      segment_count_length,
+      generate_seed(),
+    )
+  }
+
+  /// Generates the params using a fixed `public_seed` for the LHS matrix,
+  /// so that they are reproducible
+  pub fn new_with_seed(
+    db: &KVDatabase,
+    dim: usize,
+    seed: [u8; 32],
+    segment_length: u32,
+    segment_length_mask: u32,
+    segment_count_length: u32,
+    public_seed: [u8; 32],
+  ) -> Self {
     Self {
       public_seed,
       rhs: Self::generate_params_rhs(db, public_seed, dim),
@@ -291,11 +1091,19 @@ impl KVParams {
       m: db.get_matrix_height(),
       elem_size: db.get_elem_size(),
       plaintext_bits: db.get_plaintext_bits(),
+      expires_at: None,
+      compression: Compression::None,
+      record_expiry: false,
+      modulus: Modulus::default(),
+      chunks: None,
       filter_params: FilterParams {
         seed,
         segment_length,
         segment_length_mask,
         segment_count_length,
+        prf: db.get_filter_params().prf,
+        ptxt_mod: db.get_filter_params().ptxt_mod,
+        epoch: db.get_filter_params().epoch,
       },
     }
   }
@@ -303,7 +1111,101 @@ impl KVParams {
   pub fn get_filter_params(&self) -> FilterParams {
     self.filter_params.clone()
   }
+
+  /// Sets the Unix timestamp (in seconds) at which the params expire
+  pub fn set_expires_at(&mut self, expires_at: Option<u64>) {
+    self.expires_at = expires_at;
+  }
+
+  /// Records the compression that was applied to each record
+  pub(crate) fn set_compression(&mut self, compression: Compression) {
+    self.compression = compression;
+  }
+
+  /// Records whether each record is prefixed with its expiry timestamp
+  pub(crate) fn set_record_expiry(&mut self, record_expiry: bool) {
+    self.record_expiry = record_expiry;
+  }
+
+  /// Sets the ciphertext modulus, checking that it leaves room for the
+  /// plaintext bits
+  pub(crate) fn set_modulus(
+    &mut self,
+    modulus: Modulus,
+  ) -> ResultBoxedError<()> {
+    modulus.check_plaintext_bits(self.plaintext_bits)?;
+    self.modulus = modulus;
+    Ok(())
+  }
+
+  /// Records the number of chunks that each value was split into
+  pub(crate) fn set_chunks(&mut self, chunks: Option<usize>) {
+    self.chunks = chunks;
+  }
+
+  /// Returns the number of chunks that each value was split into, if
+  /// values longer than the element size were split into chunks
+  pub fn get_chunks(&self) -> Option<usize> {
+    self.chunks
+  }
+
+  /// Returns the params for `db`, given that it was derived from the DB
+  /// of these params by `KVDatabase::with_value_changes` with `deltas`
+  /// (see `IndexParams::with_row_deltas`)
+  pub fn with_row_deltas(&self, deltas: &RowDeltas) -> Self {
+    let mut params = self.clone();
+    add_row_deltas(&mut params.rhs, self.public_seed, self.dim, deltas);
+    params
+  }
+
+  /// Returns the RHS columns of these params that differ from those of
+  /// `old` (see `IndexParams::column_diffs_from`)
+  pub(crate) fn column_diffs_from(
+    &self,
+    old: &Self,
+  ) -> Option<Vec<(usize, Vec<u32>)>> {
+    let compatible = self.dim == old.dim
+      && self.public_seed == old.public_seed
+      && self.elem_size == old.elem_size
+      && self.plaintext_bits == old.plaintext_bits
+      && self.compression == old.compression
+      && self.record_expiry == old.record_expiry
+      && self.modulus == old.modulus
+      && self.chunks == old.chunks
+      && self.filter_params == old.filter_params
+      && self.rhs.len() == old.rhs.len();
+    compatible.then(|| rhs_column_diffs(&self.rhs, &old.rhs))
+  }
+
+  /// Returns the params with the column differences computed by
+  /// `column_diffs_from` added to the RHS, and the given number of rows
+  /// `m` and expiry
+  pub(crate) fn with_column_diffs(
+    &self,
+    diffs: &[(usize, Vec<u32>)],
+    m: usize,
+    expires_at: Option<u64>,
+  ) -> ResultBoxedError<Self> {
+    let mut params = self.clone();
+    add_rhs_column_diffs(&mut params.rhs, diffs)?;
+    params.m = m;
+    params.expires_at = expires_at;
+    Ok(params)
+  }
+
+  /// Load params from a JSON file
+  pub fn load(params_path: &str) -> ResultBoxedError<Self> {
+    Ok(read_persisted(params_path)?)
+  }
 }
+impl Persist for KVParams {
+  const KIND: &'static str = "KVParams";
+
+  fn summary(&self) -> serde_json::Value {
+    params_summary(self)
+  }
+}
+
 impl BaseParams for KVParams {
   fn get_total_records(&self) -> usize {
     self.m
@@ -328,43 +1230,228 @@ impl BaseParams for KVParams {
   fn get_rhs(&self) -> &Vec<Vec<u32>> {
     &self.rhs
   }
+
+  fn get_expires_at(&self) -> Option<u64> {
+    self.expires_at
+  }
+
+  fn get_compression(&self) -> Compression {
+    self.compression
+  }
+
+  fn get_record_expiry(&self) -> bool {
+    self.record_expiry
+  }
+
+  fn get_modulus(&self) -> Modulus {
+    self.modulus
+  }
+
+  /// Writes the full params struct as JSON to file, preceded by a
+  /// `FileHeader`, so that it can be read back using `load`
+  fn write_to_file(&self, path: &str) -> ResultBoxedError<()> {
+    write_persisted(path, self)
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::db::KeyBytes;
+  use rand_core::{OsRng, RngCore};
+  use xorf::RetrievalFilter;
 
   #[test]
   fn check_consistent_retrieval() {
     let key = [1u64, 2, 3, 4];
     let value = vec![1u32, 2u32, 3u32];
     let row_width = value.len();
-    let kv = KeyValue { key, value };
+    let kv = KeyValue {
+      key: key.into(),
+      value,
+    };
     let plaintext_bits = 10;
-    let sfs =
-      StorageFilters::from_kvs(&[kv.clone()], row_width, plaintext_bits)
-        .unwrap();
+    let sfs = StorageFilters::from_kvs(
+      &[kv.clone()],
+      row_width,
+      plaintext_bits,
+      generate_seed(),
+      &FilterConfig::default(),
+      &FilterPrf::default(),
+    )
+    .unwrap();
 
     (0..row_width).for_each(|i| {
-      assert!(sfs.filters[i].retrieve(&kv.key, i as u64) == kv.value[i])
+      assert!(
+        sfs.filters[i].retrieve(kv.key.as_words(), i as u64) == kv.value[i]
+      )
     });
   }
 
+  #[test]
+  fn empty_input_is_rejected() {
+    let kv = KeyValue {
+      key: [1u64, 2, 3, 4].into(),
+      value: Vec::new(),
+    };
+    let from_kvs = |kvs: &[KeyValue], row_width| {
+      StorageFilters::from_kvs(
+        kvs,
+        row_width,
+        10,
+        generate_seed(),
+        &FilterConfig::default(),
+        &FilterPrf::default(),
+      )
+    };
+    assert!(from_kvs(&[], 3).is_err());
+    assert!(from_kvs(std::slice::from_ref(&kv), 0).is_err());
+    assert!(KVDatabase::new(&[], 8, 10).is_err());
+    assert!(KVDatabase::new(std::slice::from_ref(&kv), 0, 10).is_err());
+  }
+
+  #[test]
+  fn value_changes_with_32_plaintext_bits() {
+    let elem_size = 64;
+    let plaintext_bits = 32;
+    let row_width = KVDatabase::get_row_width(elem_size, plaintext_bits);
+    let mut kvs: Vec<KeyValue> = (0..300)
+      .map(|i| KeyValue {
+        key: [i as u64, 1, 2, 3].into(),
+        value: (0..row_width).map(|_| OsRng.next_u32()).collect(),
+      })
+      .collect();
+    let db = KVDatabase::new(&kvs, elem_size, plaintext_bits).unwrap();
+    kvs[5].value = vec![u32::MAX; row_width];
+    kvs[200].value = (0..row_width).map(|_| OsRng.next_u32()).collect();
+    let keys: Vec<HashedKey> = kvs.iter().map(|kv| kv.key).collect();
+    let (db, _) = db
+      .with_value_changes(&keys, &[kvs[5].clone(), kvs[200].clone()])
+      .unwrap()
+      .unwrap();
+
+    let fp = db.get_filter_params();
+    for kv in &kvs {
+      let evals = fp.get_hash_evals(&kv.key);
+      for (i, col) in db.entries.iter().enumerate() {
+        let masked =
+          evals.iter().fold(0u32, |acc, r| acc.wrapping_add(col[*r]));
+        assert_eq!(fp.unmask_value(masked, &kv.key, i as u64), kv.value[i]);
+      }
+    }
+  }
+
+  #[test]
+  fn filter_config_is_applied() {
+    let elem_size = 2u32.pow(8) as usize;
+    let plaintext_bits = 10;
+    let kvs: Vec<KeyValue> = (0..300)
+      .map(|i| KeyValue {
+        key: [i as u64, 1, 2, 3].into(),
+        value: (0..KVDatabase::get_row_width(elem_size, plaintext_bits))
+          .map(|_| OsRng.next_u32() % (1 << plaintext_bits))
+          .collect(),
+      })
+      .collect();
+    let seed = generate_seed();
+    let default =
+      KVDatabase::new_with_seed(&kvs, elem_size, plaintext_bits, seed).unwrap();
+    let config = FilterConfig {
+      size_factor_override: Some(2.0),
+      ..Default::default()
+    };
+    let larger = KVDatabase::new_with_config(
+      &kvs,
+      elem_size,
+      plaintext_bits,
+      seed,
+      &config,
+    )
+    .unwrap();
+    assert!(larger.get_matrix_height() > default.get_matrix_height());
+    let config = FilterConfig {
+      max_iterations: 0,
+      ..Default::default()
+    };
+    assert!(KVDatabase::new_with_config(
+      &kvs,
+      elem_size,
+      plaintext_bits,
+      seed,
+      &config
+    )
+    .is_err());
+  }
+
+  #[test]
+  fn params_rhs_matches_materialized_lhs() {
+    let m = 300;
+    let elem_size = 2u32.pow(8) as usize;
+    let plaintext_bits = 10;
+    let dim = 64;
+    let kvs: Vec<KeyValue> = (0..m)
+      .map(|i| KeyValue {
+        key: [i as u64, 1, 2, 3].into(),
+        value: (0..KVDatabase::get_row_width(elem_size, plaintext_bits))
+          .map(|_| OsRng.next_u32() % (1 << plaintext_bits))
+          .collect(),
+      })
+      .collect();
+    let db = KVDatabase::new(&kvs, elem_size, plaintext_bits).unwrap();
+    let seed = generate_seed();
+    let lhs = swap_matrix_fmt(&generate_lwe_matrix_from_seed(
+      seed,
+      dim,
+      db.get_matrix_height(),
+    ));
+    let expected: Vec<Vec<u32>> = (0..db.get_row_width_self())
+      .map(|i| lhs.iter().map(|r| db.vec_mult(r, i)).collect())
+      .collect();
+    assert_eq!(KVParams::generate_params_rhs(&db, seed, dim), expected);
+  }
+
+  #[test]
+  fn hashed_keys_match_key_bytes() {
+    let key = KeyBytes::from("key-1");
+    let hashed = key.hash();
+    assert_eq!(hashed, HashedKey::from_bytes(b"key-1"));
+    assert_eq!(
+      hashed.as_words(),
+      &sha256_into_u64_sized(key.as_bytes()).unwrap()
+    );
+    // hashed keys serialize as their words
+    assert_eq!(
+      serde_json::to_string(&hashed).unwrap(),
+      serde_json::to_string(hashed.as_words()).unwrap()
+    );
+    let kv = KeyValue::from_base64_strings("key-1", "AAAA", 24, 8).unwrap();
+    assert_eq!(kv.key, hashed);
+  }
+
   #[test]
   fn check_consistent_hashes() {
     let key = [1u64, 2, 3, 4];
     let value = vec![1u32, 2u32, 3u32];
     let row_width = value.len();
-    let kv = KeyValue { key, value };
+    let kv = KeyValue {
+      key: key.into(),
+      value,
+    };
     let plaintext_bits = 10;
-    let sfs =
-      StorageFilters::from_kvs(&[kv.clone()], row_width, plaintext_bits)
-        .unwrap();
+    let sfs = StorageFilters::from_kvs(
+      &[kv.clone()],
+      row_width,
+      plaintext_bits,
+      generate_seed(),
+      &FilterConfig::default(),
+      &FilterPrf::default(),
+    )
+    .unwrap();
 
     let v: Vec<Vec<usize>> = (0..row_width)
       .map(|_| {
         BinaryFuseP32::hash_eval(
-          &kv.key,
+          kv.key.as_words(),
           sfs.seed,
           sfs.segment_length,
           sfs.segment_length_mask,
@@ -377,6 +1464,59 @@ mod tests {
     }
   }
 
+  #[test]
+  fn keyed_prf_values_unmask_consistently() {
+    let elem_size = 2u32.pow(8) as usize;
+    let plaintext_bits = 10;
+    let row_width = KVDatabase::get_row_width(elem_size, plaintext_bits);
+    let kvs: Vec<KeyValue> = (0..300)
+      .map(|i| KeyValue {
+        key: [i as u64, 1, 2, 3].into(),
+        value: (0..row_width)
+          .map(|_| OsRng.next_u32() % (1 << plaintext_bits))
+          .collect(),
+      })
+      .collect();
+    let prf = FilterPrf::SipHash24([4u8; 16]);
+    let db = KVDatabase::new_with_prf(
+      &kvs,
+      elem_size,
+      plaintext_bits,
+      generate_seed(),
+      &FilterConfig::default(),
+      prf,
+    )
+    .unwrap();
+    let params = KVParams::new(
+      &db,
+      64,
+      db.get_filter_params().seed,
+      db.get_filter_params().segment_length,
+      db.get_filter_params().segment_length_mask,
+      db.get_filter_params().segment_count_length,
+    );
+    let fp = params.get_filter_params();
+    assert_eq!(fp.prf, prf);
+    for kv in kvs.iter().step_by(17) {
+      let evals = fp.get_hash_evals(&kv.key);
+      for (i, col) in db.entries.iter().enumerate() {
+        let masked =
+          evals.iter().fold(0u32, |acc, r| acc.wrapping_add(col[*r]));
+        let unmasked = fp.unmask_value(masked, &kv.key, i as u64);
+        assert_eq!(unmasked % (1 << plaintext_bits), kv.value[i]);
+      }
+    }
+
+    // the default PRF is omitted from the serialized params
+    let json = serde_json::to_string(&params).unwrap();
+    assert!(json.contains("SipHash24"));
+    let decoded: KVParams = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded.get_filter_params().prf, prf);
+    let mut fp = fp;
+    fp.prf = FilterPrf::default();
+    assert!(!serde_json::to_string(&fp).unwrap().contains("prf"));
+  }
+
   #[test]
   fn db_check_consistent_retrieval() {
     let key = [1u64, 2, 3, 4];
@@ -385,16 +1525,16 @@ mod tests {
     let plaintext_bits = 10;
     let elem_size = plaintext_bits * len;
     let kv = KeyValue {
-      key,
+      key: key.into(),
       value: value.clone(),
     };
     let kvdb =
-      KVDatabase::new(&[kv.clone()], 1, elem_size, plaintext_bits).unwrap();
+      KVDatabase::new(&[kv.clone()], elem_size, plaintext_bits).unwrap();
 
     let v: Vec<Vec<usize>> = (0..len)
       .map(|_| {
         BinaryFuseP32::hash_eval(
-          &kv.key,
+          kv.key.as_words(),
           kvdb.get_filter_params().seed,
           kvdb.get_filter_params().segment_length,
           kvdb.get_filter_params().segment_length_mask,
@@ -409,14 +1549,99 @@ mod tests {
       let masked = col
         .iter()
         .fold(0u32, |acc, r| acc.wrapping_add(kvdb.entries[i][*r]));
-      let unmasked = kvdb
-        .get_filter_params()
-        .unmask_value(masked, &key, i as u64);
+      let unmasked =
+        kvdb
+          .get_filter_params()
+          .unmask_value(masked, &key.into(), i as u64);
       let add_modp = unmasked % 2u32.pow(plaintext_bits as u32);
       assert_eq!(add_modp, value[i]);
     }
   }
 
+  #[test]
+  fn filter_rows_are_returned_as_bytes() {
+    let kvs: Vec<KeyValue> = (0..16)
+      .map(|i| KeyValue {
+        key: [i as u64, 1, 2, 3].into(),
+        value: vec![1, 2, 3],
+      })
+      .collect();
+    let db = KVDatabase::new(&kvs, 30, 10).unwrap();
+    let height = db.get_matrix_height();
+    for i in [0, 7, height - 1] {
+      let bytes = db.get_db_entry_bytes(i).unwrap();
+      assert_eq!(bytes, base64::decode(db.get_db_entry(i)).unwrap());
+      assert_eq!(db.get_db_entry_decoded(i).unwrap().into_bytes(), bytes);
+    }
+    assert!(db.get_db_entry_bytes(height).is_err());
+    assert!(db.get_db_entry_decoded(height).is_err());
+  }
+
+  #[test]
+  fn matrix_height_is_derived_from_the_filters() {
+    for num_keys in [1, 64, 300] {
+      let kvs: Vec<KeyValue> = (0..num_keys)
+        .map(|i| KeyValue {
+          key: [i as u64, 1, 2, 3].into(),
+          value: vec![1, 2, 3],
+        })
+        .collect();
+      let db = KVDatabase::new(&kvs, 30, 10).unwrap();
+      assert_eq!(db.num_keys(), num_keys);
+      // the matrix spans the whole filter, which exceeds the number of keys
+      assert_eq!(db.matrix_height(), db.entries[0].len());
+      assert_eq!(db.matrix_height(), db.get_matrix_height());
+      assert!(db.matrix_height() > num_keys);
+      let load_factor = db.load_factor();
+      assert_eq!(load_factor, num_keys as f64 / db.matrix_height() as f64);
+      assert!(load_factor > 0.0 && load_factor < 1.0);
+    }
+  }
+
+  #[test]
+  fn estimated_matrix_height_matches_built_db() {
+    let (elem_size, plaintext_bits) = (40, 10);
+    for num_keys in [1, 50, 1000] {
+      let kvs: Vec<KeyValue> = (0..num_keys)
+        .map(|i| KeyValue {
+          key: [i as u64, 7, 8, 9].into(),
+          value: vec![1, 2, 3, 4],
+        })
+        .collect();
+      let db = KVDatabase::new(&kvs, elem_size, plaintext_bits).unwrap();
+      let capacity =
+        KVDatabase::estimate_capacity(num_keys, elem_size, plaintext_bits, 512);
+      assert_eq!(capacity.matrix_height, db.matrix_height());
+      assert_eq!(capacity.row_width, db.get_row_width_self());
+      assert_eq!(capacity.load_factor, db.load_factor());
+      assert_eq!(capacity.query_bytes, db.matrix_height() * 4);
+      assert_eq!(capacity.hint_bytes, 512 * 4 * 4);
+    }
+    let config = FilterConfig {
+      size_factor_override: Some(2.0),
+      ..Default::default()
+    };
+    assert!(
+      KVDatabase::estimate_matrix_height_with_config(1000, &config)
+        > KVDatabase::estimate_matrix_height(1000)
+    );
+  }
+
+  #[test]
+  #[cfg(feature = "debug-arith")]
+  #[should_panic(expected = "at column 1, row 3 exceeds the plaintext")]
+  fn debug_arith_reports_unbounded_entries() {
+    let kv = KeyValue {
+      key: [1u64, 2, 3, 4].into(),
+      value: vec![1u32, 2u32, 3u32],
+    };
+    let mut kvdb = KVDatabase::new(std::slice::from_ref(&kv), 30, 10).unwrap();
+    kvdb.entries[1][3] = u32::MAX;
+    let row = vec![1u32; kvdb.get_matrix_height()];
+    kvdb.vec_mult(&row, 0);
+    kvdb.vec_mult(&row, 1);
+  }
+
   #[test]
   fn attempt_actual_mult() {
     let key = [1u64, 2, 3, 4];
@@ -425,17 +1650,17 @@ mod tests {
     let plaintext_bits = 10;
     let elem_size = plaintext_bits * len;
     let kv = KeyValue {
-      key,
+      key: key.into(),
       value: value.clone(),
     };
     let kvdb =
-      KVDatabase::new(&[kv.clone()], 1, elem_size, plaintext_bits).unwrap();
+      KVDatabase::new(&[kv.clone()], elem_size, plaintext_bits).unwrap();
 
     let mut mult_v = vec![0u32; kvdb.get_matrix_height()];
     let indices: Vec<Vec<usize>> = (0..len)
       .map(|_| {
         BinaryFuseP32::hash_eval(
-          &kv.key,
+          kv.key.as_words(),
           kvdb.get_filter_params().seed,
           kvdb.get_filter_params().segment_length,
           kvdb.get_filter_params().segment_length_mask,
@@ -451,9 +1676,10 @@ mod tests {
     });
     for (i, y) in value.iter().enumerate().take(indices.len()) {
       let masked = kvdb.vec_mult(&mult_v, i);
-      let unmasked = kvdb
-        .get_filter_params()
-        .unmask_value(masked, &key, i as u64);
+      let unmasked =
+        kvdb
+          .get_filter_params()
+          .unmask_value(masked, &key.into(), i as u64);
       assert_eq!(unmasked % 2u32.pow(plaintext_bits as u32), *y);
     }
   }
@@ -1,60 +1,265 @@
 mod index;
 mod kv;
 
-use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use rand::seq::index::sample;
+use rand_core::{OsRng, RngCore};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use sha2::{Digest, Sha256};
 
-use crate::errors::ResultBoxedError;
+use crate::compression::Compression;
+use crate::errors::{
+  ErrorParamsMismatch, ErrorUnexpectedInputSize, ResultBoxedError,
+};
+use crate::utils::files::{read_persisted, write_persisted, Persist};
+use crate::utils::format::EncodingConfig;
+use crate::utils::lwe::Modulus;
 use crate::utils::matrices::*;
 
+pub use index::{IndexDatabase, IndexParams, RowDeltas, RowMap};
+pub use kv::{
+  chunk_key, epoch_seed, join_chunks, split_into_chunks, FilterParams,
+  FilterPrf, KVCapacity, KVDatabase, KVParams, KeyValue, CHUNK_HEADER_BYTES,
+};
+pub use xorf::FilterConfig;
+
+/// The number of RHS columns that are recomputed by
+/// `BaseParams::verify_against`
+pub const VERIFY_SAMPLE_COLUMNS: usize = 8;
+
+/// The number of RHS columns that are computed together by
+/// `BaseParams::generate_params_rhs` from a single pass over the LHS
+pub const RHS_COLUMN_BLOCK: usize = 4;
+
+/// The `DatabaseMatrix` trait is object-safe, so that serving code can
+/// hold either DB type as a `Box<dyn DatabaseMatrix>`. Functions that do
+/// not take a DB instance live in `DatabaseMatrixLayout`.
 pub trait DatabaseMatrix {
   fn switch_fmt(&mut self);
   fn vec_mult(&self, row: &[u32], col_idx: usize) -> u32;
   fn write_to_file(&self, path: &str) -> ResultBoxedError<()>;
   fn get_row(&self, i: usize) -> Vec<u32>;
+  /// Returns the ith row of the DB matrix without copying it
+  fn get_row_slice(&self, i: usize) -> &[u32];
   fn get_db_entry(&self, i: usize) -> String;
-  fn get_row_width(element_size: usize, plaintext_bits: usize) -> usize;
+  /// Returns the raw bytes of the ith DB entry, failing if `get_db_entry`
+  /// does not return a base64-encoded string
+  fn get_db_entry_bytes(&self, i: usize) -> ResultBoxedError<Vec<u8>> {
+    Ok(base64::decode(self.get_db_entry(i))?)
+  }
+  /// Returns the ith DB entry as a `RecordBytes`
+  fn get_db_entry_decoded(&self, i: usize) -> ResultBoxedError<RecordBytes> {
+    Ok(RecordBytes::from(self.get_db_entry_bytes(i)?))
+  }
   fn get_row_width_self(&self) -> usize;
   fn get_matrix_height(&self) -> usize;
   fn get_elem_size(&self) -> usize;
   fn get_plaintext_bits(&self) -> usize;
+  /// Returns the number of plaintext bits held by each column, if they
+  /// differ between columns (see `IndexDatabase::new_with_column_bits`).
+  /// Otherwise every column holds `get_plaintext_bits()` bits.
+  fn get_column_bits(&self) -> Option<&[usize]> {
+    None
+  }
+  /// Returns the row-major copy of the DB matrix, if one is maintained
+  /// (see `IndexDatabase::set_dual_layout`)
+  fn get_row_major(&self) -> Option<&[Vec<u32>]> {
+    None
+  }
+  /// Returns the matrix row holding each record, if duplicate records
+  /// share a row (see `IndexDatabase::new_deduplicated`)
+  fn get_record_rows(&self) -> Option<&[usize]> {
+    None
+  }
+}
+
+/// Companion trait of `DatabaseMatrix` for functions that describe the
+/// layout of a DB type without an instance
+pub trait DatabaseMatrixLayout: DatabaseMatrix {
+  /// Returns the width of each row in the DB matrix
+  fn get_row_width(element_size: usize, plaintext_bits: usize) -> usize;
+}
+
+/// A `RecordBytes` holds the bytes of a single DB element (for KV DBs,
+/// of a single value)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordBytes(Vec<u8>);
+impl RecordBytes {
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.0
+  }
+
+  pub fn into_bytes(self) -> Vec<u8> {
+    self.0
+  }
+
+  /// Returns the record serialized using `encoding`
+  pub fn encode(&self, encoding: EncodingConfig) -> String {
+    encoding.encode(&self.0)
+  }
+}
+impl From<Vec<u8>> for RecordBytes {
+  fn from(bytes: Vec<u8>) -> Self {
+    Self(bytes)
+  }
+}
+
+/// A `KeyBytes` holds the bytes of a key of a KV DB, before hashing, i.e.
+/// the bytes of the key string used to build the DB
+#[derive(
+  Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct KeyBytes(Vec<u8>);
+impl KeyBytes {
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.0
+  }
+
+  /// Returns the key as it is stored in the filters of a KV DB
+  pub fn hash(&self) -> HashedKey {
+    HashedKey::from_bytes(&self.0)
+  }
+}
+impl From<Vec<u8>> for KeyBytes {
+  fn from(bytes: Vec<u8>) -> Self {
+    Self(bytes)
+  }
+}
+impl From<&[u8]> for KeyBytes {
+  fn from(bytes: &[u8]) -> Self {
+    Self(bytes.to_vec())
+  }
+}
+impl From<&str> for KeyBytes {
+  fn from(key: &str) -> Self {
+    Self(key.as_bytes().to_vec())
+  }
+}
+impl AsRef<[u8]> for KeyBytes {
+  fn as_ref(&self) -> &[u8] {
+    &self.0
+  }
+}
+
+/// A `HashedKey` is the SHA-256 digest of a `KeyBytes`, as four u64
+/// words. This is what KV queries are generated and decoded for.
+#[derive(
+  Clone,
+  Copy,
+  Debug,
+  PartialEq,
+  Eq,
+  PartialOrd,
+  Ord,
+  Hash,
+  Serialize,
+  Deserialize,
+)]
+#[serde(transparent)]
+pub struct HashedKey([u64; 4]);
+impl HashedKey {
+  /// Hashes the bytes of a key string (see `KeyBytes::hash`)
+  pub fn from_bytes(key: &[u8]) -> Self {
+    // the digest is a `[u8; 32]`, so each word reads 8 of its bytes
+    let digest: [u8; 32] = Sha256::digest(key).into();
+    Self(std::array::from_fn(|i| {
+      u64::from_le_bytes(std::array::from_fn(|j| digest[8 * i + j]))
+    }))
+  }
+
+  pub fn as_words(&self) -> &[u64; 4] {
+    &self.0
+  }
+}
+impl From<[u64; 4]> for HashedKey {
+  fn from(words: [u64; 4]) -> Self {
+    Self(words)
+  }
 }
-pub use index::IndexDatabase;
-pub use kv::KVDatabase;
 
 pub trait BaseParams {
   /// Generates the RHS of the params using the database and the seed
-  /// for the LHS
-  fn generate_params_rhs<T: DatabaseMatrix>(
+  /// for the LHS.
+  ///
+  /// Columns are processed in parallel, in blocks of `RHS_COLUMN_BLOCK`.
+  /// Rather than materializing the LHS matrix, each block regenerates its
+  /// rows from the seed one at a time, so that peak memory per worker is
+  /// O(height) on top of the returned RHS.
+  fn generate_params_rhs<T: DatabaseMatrix + Sync + ?Sized>(
     db: &T,
     public_seed: [u8; 32],
     dim: usize,
   ) -> Vec<Vec<u32>> {
-    let lhs = swap_matrix_fmt(&generate_lwe_matrix_from_seed(
-      public_seed,
-      dim,
-      db.get_matrix_height(),
-    ));
-    (0..T::get_row_width(db.get_elem_size(), db.get_plaintext_bits()))
-      .map(|i| {
-        let mut col = Vec::with_capacity(db.get_matrix_height());
-        for r in &lhs {
-          col.push(db.vec_mult(r, i));
-        }
-        col
-      })
+    let width = db.get_row_width_self();
+    let blocks: Vec<Vec<usize>> = (0..width)
+      .collect::<Vec<usize>>()
+      .chunks(RHS_COLUMN_BLOCK)
+      .map(|c| c.to_vec())
+      .collect();
+    blocks
+      .par_iter()
+      .flat_map_iter(|block| generate_rhs_block(db, public_seed, dim, block))
       .collect()
   }
-  /// Writes the params struct as JSON to file
-  fn write_to_file(&self, path: &str) -> ResultBoxedError<()> {
-    let json = json!({
-      "lhs_seed": self.get_public_seed(),
-      "rhs": self.get_rhs(),
-    });
-    Ok(serde_json::to_writer(&fs::File::create(path)?, &json)?)
+  /// Checks that the params were generated for `db`, by comparing the
+  /// dimensions and then recomputing a random subset of the RHS columns
+  /// from the public seed and the DB, and comparing their digests
+  fn verify_against<T: DatabaseMatrix + ?Sized>(
+    &self,
+    db: &T,
+  ) -> ResultBoxedError<()> {
+    let width = db.get_row_width_self();
+    let expected = [
+      ("records", self.get_total_records(), db.get_matrix_height()),
+      ("element size", self.get_elem_size(), db.get_elem_size()),
+      (
+        "plaintext bits",
+        self.get_plaintext_bits(),
+        db.get_plaintext_bits(),
+      ),
+      ("RHS columns", self.get_rhs().len(), width),
+    ];
+    for (name, params_val, db_val) in expected {
+      if params_val != db_val {
+        return Err(Box::new(ErrorParamsMismatch::new(format!(
+          "{} differ (params: {}, db: {})",
+          name, params_val, db_val
+        ))));
+      }
+    }
+    if self.get_column_bits() != db.get_column_bits() {
+      return Err(Box::new(ErrorParamsMismatch::new(format!(
+        "column bits differ (params: {:?}, db: {:?})",
+        self.get_column_bits(),
+        db.get_column_bits()
+      ))));
+    }
+    if self.get_record_rows() != db.get_record_rows() {
+      return Err(Box::new(ErrorParamsMismatch::new(
+        "record rows of the deduplicated DB differ".to_string(),
+      )));
+    }
+
+    let cols = sample(&mut OsRng, width, VERIFY_SAMPLE_COLUMNS.min(width));
+    let cols = cols.into_vec();
+    let recomputed =
+      generate_rhs_block(db, self.get_public_seed(), self.get_dim(), &cols);
+    for (i, col) in cols.into_iter().zip(recomputed) {
+      if column_digest(&col) != column_digest(&self.get_rhs()[i]) {
+        return Err(Box::new(ErrorParamsMismatch::new(format!(
+          "RHS column {} does not match the database",
+          i
+        ))));
+      }
+    }
+    Ok(())
   }
+  /// Writes the params struct as JSON to file, preceded by a
+  /// `FileHeader`, so that it can be read back using `load`
+  fn write_to_file(&self, path: &str) -> ResultBoxedError<()>;
   /// Computes s*(A*DB) using the RHS of the public parameters
   fn mult_right(&self, s: &[u32]) -> ResultBoxedError<Vec<u32>> {
     let cols = self.get_rhs();
@@ -62,48 +267,360 @@ pub trait BaseParams {
       .map(|i| vec_mult_u32_u32(s, &cols[i]))
       .collect()
   }
+  /// Computes s*A + e as in `CommonParams::mult_left_from_rng`, expanding
+  /// the LHS matrix from the public seed one column at a time, so that
+  /// the full matrix is never held in memory
+  fn mult_left_streamed<R: RngCore>(
+    &self,
+    s: &[u32],
+    rng: &mut R,
+  ) -> ResultBoxedError<Vec<u32>> {
+    let dim = self.get_dim();
+    if s.len() != dim {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "secret of length {} does not match the LWE dimension {}",
+        s.len(),
+        dim
+      ))));
+    }
+    let mut matrix_rng = get_seeded_rng(self.get_public_seed());
+    let mut col = vec![0u32; dim];
+    (0..self.get_total_records())
+      .map(|_| {
+        col.iter_mut().for_each(|v| *v = matrix_rng.next_u32());
+        let s_a = vec_mult_u32_u32(s, &col)?;
+        Ok(s_a.wrapping_add(random_ternary_from(rng)))
+      })
+      .collect()
+  }
   fn get_total_records(&self) -> usize;
   fn get_dim(&self) -> usize;
   fn get_elem_size(&self) -> usize;
   fn get_plaintext_bits(&self) -> usize;
   fn get_public_seed(&self) -> [u8; 32];
   fn get_rhs(&self) -> &Vec<Vec<u32>>;
+  /// Returns the Unix timestamp (in seconds) at which the params expire,
+  /// if any
+  fn get_expires_at(&self) -> Option<u64> {
+    None
+  }
+  /// Returns the compression applied to each record of the DB
+  fn get_compression(&self) -> Compression {
+    Compression::None
+  }
+  /// Returns whether each record of the DB is prefixed with its expiry
+  /// timestamp (see `expiry`)
+  fn get_record_expiry(&self) -> bool {
+    false
+  }
+  /// Returns the ciphertext modulus of the scheme
+  fn get_modulus(&self) -> Modulus {
+    Modulus::default()
+  }
+  /// Returns the number of plaintext bits held by each column of the DB,
+  /// if they differ between columns (see `DatabaseMatrix::get_column_bits`)
+  fn get_column_bits(&self) -> Option<&[usize]> {
+    None
+  }
+  /// Returns the matrix row holding each record, if the DB is
+  /// deduplicated (see `IndexDatabase::new_deduplicated`)
+  fn get_record_rows(&self) -> Option<&[usize]> {
+    None
+  }
+  /// Returns whether the params have expired at `now`
+  fn is_expired_at(&self, now: SystemTime) -> bool {
+    // expiry times beyond the range of `SystemTime` are never reached
+    match self.get_expires_at() {
+      Some(expires_at) => UNIX_EPOCH
+        .checked_add(Duration::from_secs(expires_at))
+        .is_some_and(|expiry| now >= expiry),
+      None => false,
+    }
+  }
+}
+
+/// Checks that the DB matrix has an ith row, given its `height`
+pub(crate) fn check_row_index(i: usize, height: usize) -> ResultBoxedError<()> {
+  if i >= height {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "row {} is out of range for a DB matrix of height {}",
+      i, height
+    ))));
+  }
+  Ok(())
+}
+
+/// Computes the RHS columns in `block`, streaming the rows of the LHS
+/// matrix from `public_seed` in the same order as
+/// `generate_lwe_matrix_from_seed`
+fn generate_rhs_block<T: DatabaseMatrix + ?Sized>(
+  db: &T,
+  public_seed: [u8; 32],
+  dim: usize,
+  block: &[usize],
+) -> Vec<Vec<u32>> {
+  let cols: Vec<Vec<u32>> = block.iter().map(|&i| db.get_row(i)).collect();
+  let mut acc = vec![vec![0u32; dim]; block.len()];
+  let mut rng = get_seeded_rng(public_seed);
+  let mut lhs_row = vec![0u32; dim];
+  for j in 0..db.get_matrix_height() {
+    lhs_row.iter_mut().for_each(|x| *x = rng.next_u32());
+    for (col, a) in cols.iter().zip(acc.iter_mut()) {
+      let d = col[j];
+      for (a_k, &l_k) in a.iter_mut().zip(lhs_row.iter()) {
+        *a_k = a_k.wrapping_add(l_k.wrapping_mul(d));
+      }
+    }
+  }
+  acc
+}
+
+/// Adds the contribution of the changed DB rows `deltas` to the RHS
+/// columns `rhs`, streaming the LHS rows from `public_seed` up to the
+/// last changed row
+pub(crate) fn add_row_deltas(
+  rhs: &mut [Vec<u32>],
+  public_seed: [u8; 32],
+  dim: usize,
+  deltas: &RowDeltas,
+) {
+  let mut rng = get_seeded_rng(public_seed);
+  let mut lhs_row = vec![0u32; dim];
+  let mut next_row = 0;
+  for (i, delta) in deltas {
+    while next_row <= *i {
+      lhs_row.iter_mut().for_each(|x| *x = rng.next_u32());
+      next_row += 1;
+    }
+    for (col, &d) in rhs.iter_mut().zip(delta) {
+      for (a_k, &l_k) in col.iter_mut().zip(lhs_row.iter()) {
+        *a_k = a_k.wrapping_add(l_k.wrapping_mul(d));
+      }
+    }
+  }
+}
+
+/// Returns the columns of `new` that differ from those of `old`, as
+/// wrapping differences
+pub(crate) fn rhs_column_diffs(
+  new: &[Vec<u32>],
+  old: &[Vec<u32>],
+) -> Vec<(usize, Vec<u32>)> {
+  new
+    .iter()
+    .zip(old)
+    .enumerate()
+    .filter(|(_, (new, old))| new != old)
+    .map(|(i, (new, old))| {
+      (
+        i,
+        new
+          .iter()
+          .zip(old)
+          .map(|(n, o)| n.wrapping_sub(*o))
+          .collect(),
+      )
+    })
+    .collect()
+}
+
+/// Adds the column differences computed by `rhs_column_diffs` to `rhs`
+pub(crate) fn add_rhs_column_diffs(
+  rhs: &mut [Vec<u32>],
+  diffs: &[(usize, Vec<u32>)],
+) -> ResultBoxedError<()> {
+  let width = rhs.len();
+  for (i, diff) in diffs {
+    let col = match rhs.get_mut(*i) {
+      Some(col) if col.len() == diff.len() => col,
+      _ => {
+        return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+          "RHS column {} of length {} does not fit params of width {}",
+          i,
+          diff.len(),
+          width
+        ))))
+      }
+    };
+    for (c, d) in col.iter_mut().zip(diff) {
+      *c = c.wrapping_add(*d);
+    }
+  }
+  Ok(())
+}
+
+fn column_digest(col: &[u32]) -> [u8; 32] {
+  let mut h = Sha256::new();
+  for x in col {
+    h.update(x.to_le_bytes());
+  }
+  h.finalize().into()
 }
-pub use index::IndexParams;
-pub use kv::KVParams;
 
 /// `CommonParams` holds the derived uniform matrix that is used for
 /// constructing server public parameters and the client query.
+///
+/// Expanding the matrix is expensive on slow devices, so it can be
+/// written to file and loaded again later. It records a digest of the
+/// public seed and dimensions that it was expanded from, so that it is
+/// only ever loaded for the params it belongs to.
 #[derive(Serialize, Deserialize)]
-pub struct CommonParams(Vec<Vec<u32>>);
+pub struct CommonParams {
+  matrix: Vec<Vec<u32>>,
+  origin: [u8; 32],
+}
+
+impl Persist for CommonParams {
+  const KIND: &'static str = "CommonParams";
+
+  fn summary(&self) -> serde_json::Value {
+    json!({
+      "dim": self.get_dim(),
+      "m": self.matrix.len(),
+      "origin": self.origin,
+    })
+  }
+}
+
 impl CommonParams {
   // Returns the internal matrix
   pub fn as_matrix(&self) -> &[Vec<u32>] {
-    &self.0
+    &self.matrix
+  }
+
+  /// Returns the digest of the public seed, LWE dimension and number of
+  /// DB rows that the matrix was expanded from
+  pub fn get_origin(&self) -> [u8; 32] {
+    self.origin
+  }
+
+  /// Returns whether the matrix was expanded from the public seed and
+  /// dimensions of `params`
+  pub fn matches<T: BaseParams>(&self, params: &T) -> bool {
+    self.origin == common_params_origin(params)
+  }
+
+  /// Writes the matrix as JSON to file, preceded by a `FileHeader`
+  pub fn write_to_file(&self, path: &str) -> ResultBoxedError<()> {
+    write_persisted(path, self)
+  }
+
+  /// Loads a matrix that was written using `write_to_file`, checking
+  /// that it was expanded for `params`
+  pub fn load<T: BaseParams>(path: &str, params: &T) -> ResultBoxedError<Self> {
+    let cp: Self = read_persisted(path)?;
+    if !cp.matches(params) {
+      return Err(Box::new(ErrorParamsMismatch::new(
+        "common params were expanded from other public params".to_string(),
+      )));
+    }
+    Ok(cp)
   }
 
   /// Computes s*A + e using the seed used to generate the LHS matrix of
   /// the public parameters
   pub fn mult_left(&self, s: &[u32]) -> ResultBoxedError<Vec<u32>> {
+    self.mult_left_from_rng(s, &mut OsRng)
+  }
+
+  /// Computes s*A + e as in `mult_left`, sampling e using `rng`
+  pub fn mult_left_from_rng<R: RngCore>(
+    &self,
+    s: &[u32],
+    rng: &mut R,
+  ) -> ResultBoxedError<Vec<u32>> {
     let cols = self.as_matrix();
     (0..cols.len())
       .map(|i| {
         let s_a = vec_mult_u32_u32(s, &cols[i])?;
-        let e = random_ternary();
+        let e = random_ternary_from(rng);
         Ok(s_a.wrapping_add(e))
       })
       .collect()
   }
+
+  /// Computes s*A + e as in `mult_left` for each of `secrets`, in a
+  /// single (parallel) pass over A rather than one pass per secret. This
+  /// amortizes the memory traffic of A when generating several
+  /// `QueryParams` at once, e.g. for a `ClientHint`.
+  pub fn mult_left_batch(
+    &self,
+    secrets: &[Vec<u32>],
+  ) -> ResultBoxedError<Vec<Vec<u32>>> {
+    let dim = self.get_dim();
+    if let Some(s) = secrets.iter().find(|s| s.len() != dim) {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "secret of length {} does not match the LWE dimension {}",
+        s.len(),
+        dim
+      ))));
+    }
+    // the lengths were checked above, so the inner products cannot fail
+    let cols: Vec<Vec<u32>> = self
+      .as_matrix()
+      .par_iter()
+      .map(|col| {
+        let mut rng = OsRng;
+        secrets
+          .iter()
+          .map(|s| {
+            let s_a = vec_mult_u32_u32(s, col).unwrap();
+            s_a.wrapping_add(random_ternary_from(&mut rng))
+          })
+          .collect()
+      })
+      .collect();
+    Ok(
+      (0..secrets.len())
+        .map(|j| cols.iter().map(|col| col[j]).collect())
+        .collect(),
+    )
+  }
+
+  /// Returns the LWE dimension of the matrix
+  pub fn get_dim(&self) -> usize {
+    self.matrix.first().map_or(0, |col| col.len())
+  }
 }
 impl<T: BaseParams> From<&T> for CommonParams {
   fn from(params: &T) -> Self {
-    Self(generate_lwe_matrix_from_seed(
-      params.get_public_seed(),
-      params.get_dim(),
-      params.get_total_records(),
-    ))
+    Self {
+      matrix: generate_lwe_matrix_from_seed(
+        params.get_public_seed(),
+        params.get_dim(),
+        params.get_total_records(),
+      ),
+      origin: common_params_origin(params),
+    }
   }
 }
 
-pub use kv::FilterParams;
-pub use kv::KeyValue;
+/// Digests the inputs that `CommonParams` are expanded from
+fn common_params_origin<T: BaseParams>(params: &T) -> [u8; 32] {
+  let mut h = Sha256::new();
+  h.update(params.get_public_seed());
+  h.update((params.get_dim() as u64).to_le_bytes());
+  h.update((params.get_total_records() as u64).to_le_bytes());
+  h.finalize().into()
+}
+
+/// The summary of a DB recorded in the header of its file (see
+/// `utils::files::Persist`)
+fn db_summary<D: DatabaseMatrix>(db: &D) -> serde_json::Value {
+  json!({
+    "height": db.get_matrix_height(),
+    "width": db.get_row_width_self(),
+    "elem_size": db.get_elem_size(),
+    "plaintext_bits": db.get_plaintext_bits(),
+  })
+}
+
+/// The summary of params recorded in the header of their file
+fn params_summary<P: BaseParams>(params: &P) -> serde_json::Value {
+  json!({
+    "dim": params.get_dim(),
+    "m": params.get_total_records(),
+    "elem_size": params.get_elem_size(),
+    "plaintext_bits": params.get_plaintext_bits(),
+  })
+}
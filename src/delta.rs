@@ -0,0 +1,381 @@
+//! The `delta` module implements a client-pull protocol for keeping
+//! params current across DB updates, without downloading the full params
+//! after every update.
+//!
+//! A client sends a `ParamsSyncRequest` holding the epoch and digest (see
+//! `PirShard::params_digest`) of the params it holds. The server answers
+//! from its `ParamsHistory` with a `ParamsSyncResponse`, which is either
+//! `UpToDate`, a `ParamsDelta` from the params of the client to the
+//! current ones, or the full current params when the params of the client
+//! are unknown or no delta can be computed (e.g. because the DB was
+//! rebuilt with a fresh public seed). Requests and responses are
+//! exchanged in their bincode serialization.
+//!
+//! A delta holds the (wrapping) differences of the RHS columns that
+//! changed. Since every changed row of the DB contributes to the RHS
+//! columns that it has non-zero differences in, deltas are small when
+//! updates only touch part of each record (e.g. a status field), and
+//! the server sends the full params when every column changed.
+//!
+//! Deltas are computed for both `IndexParams` and `KVParams` (see
+//! `DeltaParams`), e.g. for KV shards whose values were changed in place
+//! by `KVShard::rebuild_with_value_changes`. The sync protocol itself
+//! serves `IndexParams`.
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::db::{BaseParams, IndexParams, KVParams};
+use crate::errors::{
+  ErrorDeltaMismatch, ErrorUnexpectedInputSize, ResultBoxedError,
+};
+
+/// Returns the SHA-256 digest of the serialized params, which matches
+/// `PirShard::params_digest` of the shard serving them
+pub fn params_digest<P: Serialize>(params: &P) -> ResultBoxedError<[u8; 32]> {
+  Ok(Sha256::digest(bincode::serialize(params)?).into())
+}
+
+/// The `DeltaParams` trait is implemented by the params that
+/// `ParamsDelta`s can be computed for
+pub trait DeltaParams: BaseParams + Serialize + Sized {
+  /// Returns the RHS columns of these params that differ from those of
+  /// `old`, as wrapping differences, or `None` if the params differ in
+  /// more than their RHS, number of rows and expiry
+  fn column_diffs_from(&self, old: &Self) -> Option<Vec<(usize, Vec<u32>)>>;
+  /// Returns the params with the column differences computed by
+  /// `column_diffs_from` added to the RHS, and the given number of rows
+  /// `m` and expiry
+  fn with_column_diffs(
+    &self,
+    diffs: &[(usize, Vec<u32>)],
+    m: usize,
+    expires_at: Option<u64>,
+  ) -> ResultBoxedError<Self>;
+}
+
+impl DeltaParams for IndexParams {
+  fn column_diffs_from(&self, old: &Self) -> Option<Vec<(usize, Vec<u32>)>> {
+    IndexParams::column_diffs_from(self, old)
+  }
+
+  fn with_column_diffs(
+    &self,
+    diffs: &[(usize, Vec<u32>)],
+    m: usize,
+    expires_at: Option<u64>,
+  ) -> ResultBoxedError<Self> {
+    IndexParams::with_column_diffs(self, diffs, m, expires_at)
+  }
+}
+
+impl DeltaParams for KVParams {
+  fn column_diffs_from(&self, old: &Self) -> Option<Vec<(usize, Vec<u32>)>> {
+    KVParams::column_diffs_from(self, old)
+  }
+
+  fn with_column_diffs(
+    &self,
+    diffs: &[(usize, Vec<u32>)],
+    m: usize,
+    expires_at: Option<u64>,
+  ) -> ResultBoxedError<Self> {
+    KVParams::with_column_diffs(self, diffs, m, expires_at)
+  }
+}
+
+/// A `ParamsDelta` turns the params of one epoch into those of a later
+/// epoch
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParamsDelta {
+  /// The digest of the params that the delta applies to
+  pub from_digest: [u8; 32],
+  /// The digest of the params that the delta produces
+  pub to_digest: [u8; 32],
+  /// The epoch of the params that the delta produces
+  pub epoch: u64,
+  m: usize,
+  expires_at: Option<u64>,
+  columns: Vec<(usize, Vec<u32>)>,
+}
+impl ParamsDelta {
+  /// Computes the delta from `old` to `new`, which are served in `epoch`,
+  /// if `new` only differs from `old` in its RHS, number of rows and
+  /// expiry (e.g. when built by `Shard::rebuild_with_changes`)
+  pub fn between<P: DeltaParams>(
+    old: &P,
+    new: &P,
+    epoch: u64,
+  ) -> ResultBoxedError<Option<Self>> {
+    let columns = match new.column_diffs_from(old) {
+      Some(columns) => columns,
+      None => return Ok(None),
+    };
+    Ok(Some(Self {
+      from_digest: params_digest(old)?,
+      to_digest: params_digest(new)?,
+      epoch,
+      m: new.get_total_records(),
+      expires_at: new.get_expires_at(),
+      columns,
+    }))
+  }
+
+  /// Returns the number of RHS columns that changed
+  pub fn changed_columns(&self) -> usize {
+    self.columns.len()
+  }
+
+  /// Applies the delta to `params`, checking that they are the params
+  /// that the delta was computed from, and that the result matches the
+  /// digest of the new params
+  pub fn apply<P: DeltaParams>(&self, params: &P) -> ResultBoxedError<P> {
+    if params_digest(params)? != self.from_digest {
+      return Err(Box::new(ErrorDeltaMismatch::new(
+        "the delta was computed from other params".to_string(),
+      )));
+    }
+    let updated =
+      params.with_column_diffs(&self.columns, self.m, self.expires_at)?;
+    if params_digest(&updated)? != self.to_digest {
+      return Err(Box::new(ErrorDeltaMismatch::new(
+        "the updated params do not match the digest of the delta".to_string(),
+      )));
+    }
+    Ok(updated)
+  }
+}
+
+/// A `ParamsSyncRequest` identifies the params that a client holds
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParamsSyncRequest {
+  /// The epoch in which the params were served
+  pub epoch: u64,
+  /// The digest of the params (see `params_digest`)
+  pub params_digest: [u8; 32],
+}
+impl ParamsSyncRequest {
+  /// Returns the request of a client holding `params` of `epoch`
+  pub fn new(params: &IndexParams, epoch: u64) -> ResultBoxedError<Self> {
+    Ok(Self {
+      epoch,
+      params_digest: params_digest(params)?,
+    })
+  }
+
+  /// Serializes the request
+  pub fn to_bytes(&self) -> ResultBoxedError<Vec<u8>> {
+    Ok(bincode::serialize(self)?)
+  }
+
+  /// Parses a request that was serialized using `to_bytes`
+  pub fn from_bytes(bytes: &[u8]) -> ResultBoxedError<Self> {
+    Ok(bincode::deserialize(bytes)?)
+  }
+}
+
+/// A `ParamsSyncResponse` brings the params of a client up to date
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ParamsSyncResponse {
+  /// The params of the client are current
+  UpToDate,
+  /// The delta from the params of the client to the current params
+  Delta(ParamsDelta),
+  /// The current params, served in `epoch`
+  Full { epoch: u64, params: IndexParams },
+}
+impl ParamsSyncResponse {
+  /// Returns the current epoch and params, given the `params` of `epoch`
+  /// that the client sent the request for
+  pub fn apply(
+    self,
+    params: &IndexParams,
+    epoch: u64,
+  ) -> ResultBoxedError<(u64, IndexParams)> {
+    match self {
+      Self::UpToDate => Ok((epoch, params.clone())),
+      Self::Delta(delta) => Ok((delta.epoch, delta.apply(params)?)),
+      Self::Full { epoch, params } => Ok((epoch, params)),
+    }
+  }
+
+  /// Serializes the response
+  pub fn to_bytes(&self) -> ResultBoxedError<Vec<u8>> {
+    Ok(bincode::serialize(self)?)
+  }
+
+  /// Parses a response that was serialized using `to_bytes`
+  pub fn from_bytes(bytes: &[u8]) -> ResultBoxedError<Self> {
+    Ok(bincode::deserialize(bytes)?)
+  }
+}
+
+/// A `ParamsHistory` holds the params of the most recent epochs, from
+/// which the server answers `ParamsSyncRequest`s
+pub struct ParamsHistory {
+  capacity: usize,
+  versions: VecDeque<(u64, [u8; 32], IndexParams)>,
+}
+impl ParamsHistory {
+  /// Creates a history that keeps the params of the last `capacity`
+  /// epochs. Clients holding older params receive the full params.
+  pub fn new(capacity: usize) -> ResultBoxedError<Self> {
+    if capacity == 0 {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(
+        "params history must keep at least one epoch".to_string(),
+      )));
+    }
+    Ok(Self {
+      capacity,
+      versions: VecDeque::with_capacity(capacity),
+    })
+  }
+
+  /// Records the params served in `epoch`, which must be later than the
+  /// epochs recorded so far
+  pub fn push(
+    &mut self,
+    epoch: u64,
+    params: IndexParams,
+  ) -> ResultBoxedError<()> {
+    if let Some(current) = self.current_epoch() {
+      if epoch <= current {
+        return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+          "epoch {} is not later than the current epoch {}",
+          epoch, current
+        ))));
+      }
+    }
+    if self.versions.len() == self.capacity {
+      self.versions.pop_front();
+    }
+    self
+      .versions
+      .push_back((epoch, params_digest(&params)?, params));
+    Ok(())
+  }
+
+  /// Returns the current epoch, if any params were recorded
+  pub fn current_epoch(&self) -> Option<u64> {
+    self.versions.back().map(|(epoch, _, _)| *epoch)
+  }
+
+  /// Returns the current params, if any were recorded
+  pub fn current(&self) -> Option<&IndexParams> {
+    self.versions.back().map(|(_, _, params)| params)
+  }
+
+  /// Answers `request`, preferring a delta over the full params whenever
+  /// the delta leaves some RHS columns unchanged
+  pub fn respond(
+    &self,
+    request: &ParamsSyncRequest,
+  ) -> ResultBoxedError<ParamsSyncResponse> {
+    let (epoch, digest, current) = match self.versions.back() {
+      Some(version) => version,
+      None => {
+        return Err(Box::new(ErrorUnexpectedInputSize::new(
+          "no params were recorded".to_string(),
+        )))
+      }
+    };
+    if request.params_digest == *digest {
+      return Ok(ParamsSyncResponse::UpToDate);
+    }
+    let known = self
+      .versions
+      .iter()
+      .find(|(e, d, _)| *e == request.epoch && *d == request.params_digest);
+    if let Some((_, _, old)) = known {
+      if let Some(delta) = ParamsDelta::between(old, current, *epoch)? {
+        if delta.changed_columns() < current.get_rhs().len() {
+          return Ok(ParamsSyncResponse::Delta(delta));
+        }
+      }
+    }
+    Ok(ParamsSyncResponse::Full {
+      epoch: *epoch,
+      params: current.clone(),
+    })
+  }
+
+  /// Answers a serialized request with a serialized response
+  pub fn respond_bytes(&self, request: &[u8]) -> ResultBoxedError<Vec<u8>> {
+    self
+      .respond(&ParamsSyncRequest::from_bytes(request)?)?
+      .to_bytes()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::*;
+  use crate::test_utils::generate_db_bytes;
+
+  #[test]
+  fn clients_pull_params_deltas() {
+    let elem_size = 2u32.pow(8) as usize;
+    let mut eles = generate_db_bytes(64, elem_size / 8);
+    let encoded: Vec<String> = eles.iter().map(base64::encode).collect();
+    let shard =
+      Shard::from_base64_strings(&encoded, 512, eles.len(), elem_size, 10)
+        .unwrap();
+    // only the first byte of a record changes, i.e. the first column
+    eles[3][0] ^= 0xff;
+    let updated = shard
+      .rebuild_with_changes(&[base64::encode(&eles[3])], &[3])
+      .unwrap();
+    let old = shard.get_base_params().clone();
+    let new = updated.get_base_params().clone();
+
+    let mut history = ParamsHistory::new(4).unwrap();
+    history.push(1, old.clone()).unwrap();
+    history.push(2, new.clone()).unwrap();
+    assert!(history.push(2, new.clone()).is_err());
+
+    // a client holding the params of epoch 1 receives a delta
+    let request = ParamsSyncRequest::new(&old, 1).unwrap();
+    let response = ParamsSyncResponse::from_bytes(
+      &history.respond_bytes(&request.to_bytes().unwrap()).unwrap(),
+    )
+    .unwrap();
+    let delta = match &response {
+      ParamsSyncResponse::Delta(delta) => delta.clone(),
+      other => panic!("expected a delta, got {:?}", other),
+    };
+    assert_eq!(delta.changed_columns(), 1);
+    let (epoch, synced) = response.apply(&old, 1).unwrap();
+    assert_eq!(epoch, 2);
+    assert_eq!(synced.get_rhs(), new.get_rhs());
+    assert_eq!(
+      params_digest(&synced).unwrap(),
+      updated.params_digest().unwrap()
+    );
+    let err = delta.apply(&new).unwrap_err();
+    assert!(err.downcast_ref::<ErrorDeltaMismatch>().is_some());
+
+    // current clients are up to date
+    let request = ParamsSyncRequest::new(&new, 2).unwrap();
+    assert!(matches!(
+      history.respond(&request).unwrap(),
+      ParamsSyncResponse::UpToDate
+    ));
+
+    // unknown params, and params with another seed, are replaced in full
+    let other = Shard::from_base64_strings(&encoded, 512, 64, elem_size, 10)
+      .unwrap()
+      .get_base_params()
+      .clone();
+    assert!(ParamsDelta::between(&other, &new, 2).unwrap().is_none());
+    let request = ParamsSyncRequest::new(&other, 1).unwrap();
+    let response = history.respond(&request).unwrap();
+    assert!(matches!(
+      response,
+      ParamsSyncResponse::Full { epoch: 2, .. }
+    ));
+    let (_, synced) = response.apply(&other, 1).unwrap();
+    assert_eq!(synced.get_rhs(), new.get_rhs());
+  }
+}
@@ -0,0 +1,98 @@
+//! The `entropy` module defines the source of randomness that the crate
+//! samples secrets, errors and seeds from. By default this is `OsRng`;
+//! other sources (e.g. a seeded RNG for deterministic simulations, or an
+//! HSM) can be injected through `ShardBuilder::entropy` and the
+//! `*_with_entropy` functions of the `api` module.
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::{Arc, Mutex};
+
+use rand_core::{CryptoRng, Error, OsRng, RngCore};
+
+/// An `Entropy` source is a cryptographically secure RNG that can be
+/// moved between threads. It is implemented for every such RNG.
+pub trait Entropy: RngCore + CryptoRng + Send {}
+impl<R: RngCore + CryptoRng + Send> Entropy for R {}
+
+/// A `SharedEntropy` is a handle to an `Entropy` source that can be
+/// cloned, e.g. along with a builder. All clones draw from the same
+/// source.
+#[derive(Clone)]
+pub struct SharedEntropy(Arc<Mutex<dyn Entropy>>);
+impl SharedEntropy {
+  /// Shares `entropy`
+  pub fn new<E: Entropy + 'static>(entropy: E) -> Self {
+    Self(Arc::new(Mutex::new(entropy)))
+  }
+}
+
+impl Default for SharedEntropy {
+  fn default() -> Self {
+    Self::new(OsRng)
+  }
+}
+
+impl Debug for SharedEntropy {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "SharedEntropy")
+  }
+}
+
+impl RngCore for SharedEntropy {
+  fn next_u32(&mut self) -> u32 {
+    self.0.lock().unwrap().next_u32()
+  }
+
+  fn next_u64(&mut self) -> u64 {
+    self.0.lock().unwrap().next_u64()
+  }
+
+  fn fill_bytes(&mut self, dest: &mut [u8]) {
+    self.0.lock().unwrap().fill_bytes(dest)
+  }
+
+  fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+    self.0.lock().unwrap().try_fill_bytes(dest)
+  }
+}
+
+impl CryptoRng for SharedEntropy {}
+
+#[cfg(test)]
+mod tests {
+  use crate::api::*;
+  use crate::builder::ShardBuilder;
+  use rand::rngs::StdRng;
+  use rand::SeedableRng;
+
+  #[test]
+  fn seeded_entropy_is_reproducible() {
+    let eles: Vec<String> =
+      (0..64).map(|i| base64::encode([i as u8; 32])).collect();
+    let build = |seed| {
+      ShardBuilder::new(512, 256)
+        .entropy(StdRng::seed_from_u64(seed))
+        .build(&eles)
+        .unwrap()
+    };
+    let (shard, same, other) = (build(7), build(7), build(8));
+    let digest = |s: &Shard| s.params_digest().unwrap();
+    assert_eq!(digest(&shard), digest(&same));
+    assert_ne!(digest(&shard), digest(&other));
+
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let query = |seed| {
+      let mut entropy = StdRng::seed_from_u64(seed);
+      let mut qp =
+        generate_index_query_params_with_entropy(&cp, bp, &mut entropy)
+          .unwrap();
+      let q = qp.generate_query(9).unwrap();
+      let resp: Response =
+        bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+      assert_eq!(qp.parse_resp_as_base64(&resp), eles[9]);
+      q.as_slice().to_vec()
+    };
+    assert_eq!(query(1), query(1));
+    assert_ne!(query(1), query(2));
+  }
+}
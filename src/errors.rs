@@ -1,5 +1,6 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::time::Duration;
 
 // ResultBoxedError returns a result of a given type or a boxed error, in order to encapsulate
 // generic error types without requiring an explicit implementation for each error type
@@ -55,3 +56,430 @@ impl Display for ErrorOverflownAdd {
 }
 
 impl Error for ErrorOverflownAdd {}
+
+// ErrorHintExhausted is returned when all precomputed entries of a
+// `ClientHint` have already been consumed.
+#[derive(Debug)]
+pub struct ErrorHintExhausted;
+impl Display for ErrorHintExhausted {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "All precomputed client hint entries were used already")
+  }
+}
+
+impl Error for ErrorHintExhausted {}
+
+// ErrorHintMismatch blocks attempts to combine a `ClientHint` with
+// public parameters that it was not computed for.
+#[derive(Debug)]
+pub struct ErrorHintMismatch;
+impl Display for ErrorHintMismatch {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(
+      f,
+      "Client hint was not computed for the provided public parameters"
+    )
+  }
+}
+
+impl Error for ErrorHintMismatch {}
+
+// ErrorRetriesExhausted is returned when a query could not be decoded
+// reliably within the configured retry budget.
+#[derive(Debug)]
+pub struct ErrorRetriesExhausted {
+  pub attempts: usize,
+}
+impl Display for ErrorRetriesExhausted {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(
+      f,
+      "Failed to reliably decode response after {} attempts",
+      self.attempts
+    )
+  }
+}
+
+impl Error for ErrorRetriesExhausted {}
+
+// ErrorLoadFile is returned when a file-based constructor cannot read or
+// parse its input. For JSON syntax/data errors the line and column of the
+// offending input are recorded.
+#[derive(Debug)]
+pub struct ErrorLoadFile {
+  pub path: String,
+  pub line: Option<usize>,
+  pub column: Option<usize>,
+  details: String,
+}
+
+impl ErrorLoadFile {
+  pub fn new(path: &str, details: String) -> Self {
+    Self {
+      path: path.to_string(),
+      line: None,
+      column: None,
+      details,
+    }
+  }
+
+  pub fn from_io(path: &str, err: &std::io::Error) -> Self {
+    Self {
+      path: path.to_string(),
+      line: None,
+      column: None,
+      details: err.to_string(),
+    }
+  }
+
+  pub fn from_json(path: &str, err: &serde_json::Error) -> Self {
+    // serde_json reports line 0 for errors not tied to a position (e.g.
+    // I/O failures during streaming)
+    let (line, column) = match err.line() {
+      0 => (None, None),
+      l => (Some(l), Some(err.column())),
+    };
+    Self {
+      path: path.to_string(),
+      line,
+      column,
+      details: err.to_string(),
+    }
+  }
+
+  /// An error for a JSON record that is stored on `line` of the file, as
+  /// in line-delimited JSON
+  pub fn from_json_line(
+    path: &str,
+    line: usize,
+    err: &serde_json::Error,
+  ) -> Self {
+    // serde_json positions are relative to the record, so drop them from
+    // the message
+    let details = err.to_string();
+    let details = match details.rsplit_once(" at line ") {
+      Some((msg, _)) => msg.to_string(),
+      None => details,
+    };
+    Self {
+      path: path.to_string(),
+      line: Some(line),
+      column: (err.line() != 0).then(|| err.column()),
+      details,
+    }
+  }
+}
+
+impl Display for ErrorLoadFile {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    match (self.line, self.column) {
+      (Some(l), Some(c)) => write!(
+        f,
+        "Failed to load file {} at line {}, column {}: {}",
+        self.path, l, c, self.details
+      ),
+      _ => write!(f, "Failed to load file {}: {}", self.path, self.details),
+    }
+  }
+}
+
+impl Error for ErrorLoadFile {}
+
+// ErrorParamsMismatch is returned when public parameters were not
+// generated for the database they are being used with.
+#[derive(Debug)]
+pub struct ErrorParamsMismatch {
+  details: String,
+}
+
+impl ErrorParamsMismatch {
+  pub fn new(details: String) -> Self {
+    Self { details }
+  }
+}
+
+impl Display for ErrorParamsMismatch {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "Params do not match database: {}", self.details)
+  }
+}
+
+impl Error for ErrorParamsMismatch {}
+
+// ErrorOverflownMul blocks attempts to overflown multiplication.
+#[derive(Debug)]
+pub struct ErrorOverflownMul;
+impl Display for ErrorOverflownMul {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "Attempted to overflow multiplication")
+  }
+}
+
+impl Error for ErrorOverflownMul {}
+
+// ErrorParamsExpired blocks attempts to generate queries using params
+// that have passed their expiry time.
+#[derive(Debug)]
+pub struct ErrorParamsExpired;
+impl Display for ErrorParamsExpired {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "Attempted to use params that have expired")
+  }
+}
+
+impl Error for ErrorParamsExpired {}
+
+// ErrorRecordExpired is returned when a decoded record has passed its
+// expiry time, or was deleted (see `expiry::TOMBSTONE`), together with
+// the Unix timestamp (in seconds) at which it expired.
+#[derive(Debug)]
+pub struct ErrorRecordExpired {
+  expires_at: u64,
+}
+impl ErrorRecordExpired {
+  pub fn new(expires_at: u64) -> Self {
+    Self { expires_at }
+  }
+
+  /// Returns the Unix timestamp (in seconds) at which the record expired
+  pub fn expires_at(&self) -> u64 {
+    self.expires_at
+  }
+
+  /// Returns whether the record was deleted, rather than aged out
+  pub fn is_tombstone(&self) -> bool {
+    self.expires_at == crate::expiry::TOMBSTONE
+  }
+}
+impl Display for ErrorRecordExpired {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    if self.is_tombstone() {
+      write!(f, "Record has been deleted")
+    } else {
+      write!(f, "Record expired at {}", self.expires_at)
+    }
+  }
+}
+
+impl Error for ErrorRecordExpired {}
+
+// ErrorDeduplicatedDb is returned by operations that are not supported
+// for DBs whose duplicate records share a matrix row (see
+// `IndexDatabase::new_deduplicated`).
+#[derive(Debug)]
+pub struct ErrorDeduplicatedDb {
+  details: String,
+}
+
+impl ErrorDeduplicatedDb {
+  pub fn new(details: String) -> Self {
+    Self { details }
+  }
+}
+
+impl Display for ErrorDeduplicatedDb {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "Unsupported for deduplicated DBs: {}", self.details)
+  }
+}
+
+impl Error for ErrorDeduplicatedDb {}
+
+// ErrorQuotaExceeded is returned when a client has used up its query
+// quota, together with the time until its next query is admitted.
+#[derive(Debug)]
+pub struct ErrorQuotaExceeded {
+  pub retry_after: Duration,
+}
+impl Display for ErrorQuotaExceeded {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(
+      f,
+      "Query quota exceeded, retry after {} ms",
+      self.retry_after.as_millis()
+    )
+  }
+}
+
+impl Error for ErrorQuotaExceeded {}
+
+// ErrorSchemaMismatch is returned when a record does not match the
+// `RecordSchema` it is being encoded or decoded with.
+#[derive(Debug)]
+pub struct ErrorSchemaMismatch {
+  details: String,
+}
+
+impl ErrorSchemaMismatch {
+  pub fn new(details: String) -> Self {
+    Self { details }
+  }
+}
+
+impl Display for ErrorSchemaMismatch {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "Record does not match schema: {}", self.details)
+  }
+}
+
+impl Error for ErrorSchemaMismatch {}
+
+// ErrorInvalidSchema is returned when a `RecordSchema` is constructed
+// with an invalid layout, e.g. with duplicate field names.
+#[derive(Debug)]
+pub struct ErrorInvalidSchema {
+  details: String,
+}
+
+impl ErrorInvalidSchema {
+  pub fn new(details: String) -> Self {
+    Self { details }
+  }
+}
+
+impl Display for ErrorInvalidSchema {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "Invalid record schema: {}", self.details)
+  }
+}
+
+impl Error for ErrorInvalidSchema {}
+
+// ErrorManifestInvalid is returned when downloaded params are not covered
+// by a valid signed manifest.
+#[derive(Debug)]
+pub struct ErrorManifestInvalid {
+  details: String,
+}
+
+impl ErrorManifestInvalid {
+  pub fn new(details: String) -> Self {
+    Self { details }
+  }
+}
+
+impl Display for ErrorManifestInvalid {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "Manifest verification failed: {}", self.details)
+  }
+}
+
+impl Error for ErrorManifestInvalid {}
+
+// ErrorUnknownNamespace is returned when a request targets a namespace
+// that is not registered with a `ShardRegistry`.
+#[derive(Debug)]
+pub struct ErrorUnknownNamespace {
+  pub namespace: String,
+}
+impl Display for ErrorUnknownNamespace {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "Unknown namespace: {}", self.namespace)
+  }
+}
+
+impl Error for ErrorUnknownNamespace {}
+
+// ErrorDeltaMismatch is returned when a `ParamsDelta` is applied to params
+// other than the ones it was computed from.
+#[derive(Debug)]
+pub struct ErrorDeltaMismatch {
+  details: String,
+}
+
+impl ErrorDeltaMismatch {
+  pub fn new(details: String) -> Self {
+    Self { details }
+  }
+}
+
+impl Display for ErrorDeltaMismatch {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "Params delta does not apply: {}", self.details)
+  }
+}
+
+impl Error for ErrorDeltaMismatch {}
+
+// ErrorFilterEpochMismatch is returned when a client holds the filter
+// params of another epoch of the filter seed than the one that is served.
+#[derive(Debug)]
+pub struct ErrorFilterEpochMismatch {
+  pub expected: u64,
+  pub found: u64,
+}
+impl Display for ErrorFilterEpochMismatch {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(
+      f,
+      "Filter params are of epoch {}, the server is at epoch {}",
+      self.found, self.expected
+    )
+  }
+}
+
+impl Error for ErrorFilterEpochMismatch {}
+
+// ErrorInclusionInvalid is returned when an `InclusionProof` does not show
+// that a record is part of a committed DB.
+#[derive(Debug)]
+pub struct ErrorInclusionInvalid {
+  details: String,
+}
+
+impl ErrorInclusionInvalid {
+  pub fn new(details: String) -> Self {
+    Self { details }
+  }
+}
+
+impl Display for ErrorInclusionInvalid {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "Inclusion proof verification failed: {}", self.details)
+  }
+}
+
+impl Error for ErrorInclusionInvalid {}
+
+// ErrorTransportUnsupported is returned when a `PirTransport` does not
+// support an operation.
+#[derive(Debug)]
+pub struct ErrorTransportUnsupported {
+  details: String,
+}
+
+impl ErrorTransportUnsupported {
+  pub fn new(details: String) -> Self {
+    Self { details }
+  }
+}
+
+impl Display for ErrorTransportUnsupported {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "Unsupported by the transport: {}", self.details)
+  }
+}
+
+impl Error for ErrorTransportUnsupported {}
+
+// ErrorSnapshotFormat is returned when loading a snapshot (see
+// `Shard::load_snapshot`) from input that is not a complete snapshot of the
+// supported version.
+#[derive(Debug)]
+pub struct ErrorSnapshotFormat {
+  details: String,
+}
+
+impl ErrorSnapshotFormat {
+  pub fn new(details: String) -> Self {
+    Self { details }
+  }
+}
+
+impl Display for ErrorSnapshotFormat {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "Invalid snapshot: {}", self.details)
+  }
+}
+
+impl Error for ErrorSnapshotFormat {}
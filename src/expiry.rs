@@ -0,0 +1,91 @@
+//! The `expiry` module provides optional per-record expiry metadata, for
+//! datasets whose records age out (e.g. breach notifications). Each
+//! record is prefixed with the Unix timestamp (in seconds) at which it
+//! expires, as a little-endian u64, before it is compressed (see
+//! `compression`) and packed into the DB matrix.
+//!
+//! Records that never expire carry `NEVER_EXPIRES`, and deleted records
+//! are kept as tombstones, which carry `TOMBSTONE`. Clients strip the
+//! timestamp when parsing a record (see `parse_resp_as_record`), and
+//! report records past their expiry as an `ErrorRecordExpired`. The
+//! timestamp is encrypted along with the record, so the server does not
+//! learn which of the records that clients retrieve have expired.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::errors::{
+  ErrorRecordExpired, ErrorUnexpectedInputSize, ResultBoxedError,
+};
+
+/// The number of bytes used for the expiry prefix of a record
+pub const EXPIRY_BYTES: usize = 8;
+
+/// The expiry timestamp of records that never expire
+pub const NEVER_EXPIRES: u64 = u64::MAX;
+
+/// The expiry timestamp of deleted records, which are expired at any time
+pub const TOMBSTONE: u64 = 0;
+
+/// Prefixes `record` with its expiry timestamp, where `None` means that
+/// the record never expires
+pub fn stamp_record(record: &[u8], expires_at: Option<u64>) -> Vec<u8> {
+  let expires_at = expires_at.unwrap_or(NEVER_EXPIRES);
+  let mut stamped = expires_at.to_le_bytes().to_vec();
+  stamped.extend_from_slice(record);
+  stamped
+}
+
+/// Prefixes each record with the expiry timestamp at the same index
+pub fn stamp_records(
+  records: &[Vec<u8>],
+  expiries: &[Option<u64>],
+) -> ResultBoxedError<Vec<Vec<u8>>> {
+  if records.len() != expiries.len() {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "{} records, but {} expiry timestamps",
+      records.len(),
+      expiries.len()
+    ))));
+  }
+  Ok(
+    records
+      .iter()
+      .zip(expiries)
+      .map(|(r, e)| stamp_record(r, *e))
+      .collect(),
+  )
+}
+
+/// Returns the expiry timestamp that `stamped` is prefixed with
+pub fn record_expires_at(stamped: &[u8]) -> ResultBoxedError<u64> {
+  if stamped.len() < EXPIRY_BYTES {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "Record has {} bytes, but the expiry prefix needs {}",
+      stamped.len(),
+      EXPIRY_BYTES
+    ))));
+  }
+  let mut expiry_bytes = [0u8; EXPIRY_BYTES];
+  expiry_bytes.copy_from_slice(&stamped[..EXPIRY_BYTES]);
+  Ok(u64::from_le_bytes(expiry_bytes))
+}
+
+/// Removes the expiry prefix from `stamped`, whether or not the record
+/// has expired
+pub fn strip_expiry(stamped: &[u8]) -> ResultBoxedError<&[u8]> {
+  record_expires_at(stamped)?;
+  Ok(&stamped[EXPIRY_BYTES..])
+}
+
+/// Removes the expiry prefix from `stamped`, returning an
+/// `ErrorRecordExpired` if the record has expired at time `now`
+pub fn unstamp_record(
+  stamped: &[u8],
+  now: SystemTime,
+) -> ResultBoxedError<Vec<u8>> {
+  let expires_at = record_expires_at(stamped)?;
+  let now_secs = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+  if now_secs >= expires_at {
+    return Err(Box::new(ErrorRecordExpired::new(expires_at)));
+  }
+  Ok(stamped[EXPIRY_BYTES..].to_vec())
+}
@@ -0,0 +1,273 @@
+//! The `ffi` module exports the client-side API (ingesting the public
+//! params, generating queries and decoding responses) through
+//! [UniFFI](https://mozilla.github.io/uniffi-rs/), from which Kotlin and
+//! Swift bindings are generated for mobile apps (see the `uniffi-bindgen`
+//! binary).
+//!
+//! The exported clients wrap a `ClientSession`, so that queries are
+//! identified by id and the session can be persisted across restarts of
+//! the app. Params are ingested in the JSON format served by
+//! `PirShard::params_json`, and queries and responses are exchanged in
+//! their bincode serialization, as over `transport::HttpTransport`.
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use serde::de::DeserializeOwned;
+
+use crate::client::ClientSession;
+use crate::db::{BaseParams, CommonParams, IndexParams, KVParams};
+use crate::errors::ResultBoxedError;
+
+/// The errors surfaced to foreign code, carrying the message of the
+/// underlying error
+#[derive(Debug, uniffi::Error)]
+#[uniffi(flat_error)]
+pub enum PirError {
+  /// The params could not be parsed
+  InvalidParams(String),
+  /// The query could not be generated
+  Query(String),
+  /// The response could not be decoded
+  Decode(String),
+  /// The session could not be persisted or restored
+  Session(String),
+}
+
+impl fmt::Display for PirError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Self::InvalidParams(msg) => write!(f, "Invalid params: {}", msg),
+      Self::Query(msg) => write!(f, "Query failed: {}", msg),
+      Self::Decode(msg) => write!(f, "Decoding failed: {}", msg),
+      Self::Session(msg) => write!(f, "Session failed: {}", msg),
+    }
+  }
+}
+
+impl std::error::Error for PirError {}
+
+/// A query that has been generated by a client, to be sent to the server
+/// as `query`, and whose response must be decoded using `id`
+#[derive(uniffi::Record)]
+pub struct PreparedQuery {
+  pub id: u64,
+  pub query: Vec<u8>,
+}
+
+/// The state shared by the exported clients
+struct ClientState<P> {
+  cp: CommonParams,
+  params: P,
+  session: ClientSession,
+}
+
+impl<P: BaseParams + DeserializeOwned> ClientState<P> {
+  fn new(params_json: &[u8], session: ClientSession) -> Result<Self, PirError> {
+    let params: P = serde_json::from_slice(params_json)
+      .map_err(|e| PirError::InvalidParams(e.to_string()))?;
+    Ok(Self {
+      cp: CommonParams::from(&params),
+      params,
+      session,
+    })
+  }
+
+  fn set_params(&mut self, params_json: &[u8]) -> Result<(), PirError> {
+    let session = std::mem::take(&mut self.session);
+    *self = Self::new(params_json, session)?;
+    Ok(())
+  }
+
+  fn decode(&mut self, id: u64, response: &[u8]) -> Result<Vec<u8>, PirError> {
+    self
+      .session
+      .complete(id, response)
+      .map_err(|e| PirError::Decode(e.to_string()))
+  }
+
+  fn save_session(&self, path: &str) -> Result<(), PirError> {
+    self
+      .session
+      .write_to_file(path)
+      .map_err(|e| PirError::Session(e.to_string()))
+  }
+}
+
+/// Loads the session written to `path` by `save_session`
+fn load_session(path: &str) -> Result<ClientSession, PirError> {
+  ClientSession::load(path).map_err(|e| PirError::Session(e.to_string()))
+}
+
+/// Serializes a query generated by the session as a `PreparedQuery`
+fn prepare<Q: serde::Serialize>(
+  generated: ResultBoxedError<(u64, Q)>,
+) -> Result<PreparedQuery, PirError> {
+  let (id, q) = generated.map_err(|e| PirError::Query(e.to_string()))?;
+  let query =
+    bincode::serialize(&q).map_err(|e| PirError::Query(e.to_string()))?;
+  Ok(PreparedQuery { id, query })
+}
+
+/// A client of an index DB, retrieving rows by index
+#[derive(uniffi::Object)]
+pub struct IndexClient(Mutex<ClientState<IndexParams>>);
+
+#[uniffi::export]
+impl IndexClient {
+  /// Creates a client from the JSON params of an index DB
+  #[uniffi::constructor]
+  pub fn new(params_json: Vec<u8>) -> Result<Arc<Self>, PirError> {
+    let state = ClientState::new(&params_json, ClientSession::new())?;
+    Ok(Arc::new(Self(Mutex::new(state))))
+  }
+
+  /// Same as `new`, restoring the pending queries of the session saved
+  /// to `session_path`
+  #[uniffi::constructor]
+  pub fn with_session(
+    params_json: Vec<u8>,
+    session_path: String,
+  ) -> Result<Arc<Self>, PirError> {
+    let state = ClientState::new(&params_json, load_session(&session_path)?)?;
+    Ok(Arc::new(Self(Mutex::new(state))))
+  }
+
+  /// Replaces the params, e.g. after the DB has been updated
+  pub fn set_params(&self, params_json: Vec<u8>) -> Result<(), PirError> {
+    self.state().set_params(&params_json)
+  }
+
+  /// Generates a query for the row at `row_index`
+  pub fn query(&self, row_index: u64) -> Result<PreparedQuery, PirError> {
+    let state = &mut *self.state();
+    prepare(state.session.query_index(
+      &state.cp,
+      &state.params,
+      row_index as usize,
+    ))
+  }
+
+  /// Decodes the response to the query with `id` into the row
+  pub fn decode(
+    &self,
+    id: u64,
+    response: Vec<u8>,
+  ) -> Result<Vec<u8>, PirError> {
+    self.state().decode(id, &response)
+  }
+
+  /// Drops the query with `id`, returning whether it was pending
+  pub fn cancel(&self, id: u64) -> bool {
+    self.state().session.cancel(id)
+  }
+
+  /// Writes the pending queries to `session_path` (see
+  /// `ClientSession::write_to_file`)
+  pub fn save_session(&self, session_path: String) -> Result<(), PirError> {
+    self.state().save_session(&session_path)
+  }
+}
+
+impl IndexClient {
+  fn state(&self) -> MutexGuard<'_, ClientState<IndexParams>> {
+    self.0.lock().unwrap()
+  }
+}
+
+/// A client of a KV DB, retrieving values by key
+#[derive(uniffi::Object)]
+pub struct KvClient(Mutex<ClientState<KVParams>>);
+
+#[uniffi::export]
+impl KvClient {
+  /// Creates a client from the JSON params of a KV DB
+  #[uniffi::constructor]
+  pub fn new(params_json: Vec<u8>) -> Result<Arc<Self>, PirError> {
+    let state = ClientState::new(&params_json, ClientSession::new())?;
+    Ok(Arc::new(Self(Mutex::new(state))))
+  }
+
+  /// Same as `new`, restoring the pending queries of the session saved
+  /// to `session_path`
+  #[uniffi::constructor]
+  pub fn with_session(
+    params_json: Vec<u8>,
+    session_path: String,
+  ) -> Result<Arc<Self>, PirError> {
+    let state = ClientState::new(&params_json, load_session(&session_path)?)?;
+    Ok(Arc::new(Self(Mutex::new(state))))
+  }
+
+  /// Replaces the params, e.g. after the DB has been updated
+  pub fn set_params(&self, params_json: Vec<u8>) -> Result<(), PirError> {
+    self.state().set_params(&params_json)
+  }
+
+  /// Generates a query for `key` (the bytes of the key string used to
+  /// build the DB)
+  pub fn query(&self, key: Vec<u8>) -> Result<PreparedQuery, PirError> {
+    let state = &mut *self.state();
+    prepare(state.session.query_kv(&state.cp, &state.params, &key))
+  }
+
+  /// Decodes the response to the query with `id` into the value
+  pub fn decode(
+    &self,
+    id: u64,
+    response: Vec<u8>,
+  ) -> Result<Vec<u8>, PirError> {
+    self.state().decode(id, &response)
+  }
+
+  /// Drops the query with `id`, returning whether it was pending
+  pub fn cancel(&self, id: u64) -> bool {
+    self.state().session.cancel(id)
+  }
+
+  /// Writes the pending queries to `session_path` (see
+  /// `ClientSession::write_to_file`)
+  pub fn save_session(&self, session_path: String) -> Result<(), PirError> {
+    self.state().save_session(&session_path)
+  }
+}
+
+impl KvClient {
+  fn state(&self) -> MutexGuard<'_, ClientState<KVParams>> {
+    self.0.lock().unwrap()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::{KVShard, PirShard, Shard};
+  use crate::test_utils::generate_db_eles;
+
+  #[test]
+  fn ffi_clients_retrieve_records() {
+    let eles = generate_db_eles(64, 8);
+    let shard = Shard::from_base64_strings(&eles, 512, 64, 64, 10).unwrap();
+    let client = IndexClient::new(shard.params_json().unwrap()).unwrap();
+    let pq = client.query(7).unwrap();
+    let q = bincode::deserialize(&pq.query).unwrap();
+    let resp = shard.respond(&q).unwrap();
+    let row = client.decode(pq.id, resp).unwrap();
+    assert_eq!(base64::encode(row), eles[7]);
+    assert!(!client.cancel(pq.id));
+
+    let keys: Vec<String> = (0..64).map(|i| format!("key-{}", i)).collect();
+    let shard =
+      KVShard::from_base64_strings(&keys, &eles, 512, 64, 10).unwrap();
+    let client = KvClient::new(shard.params_json().unwrap()).unwrap();
+    let pq = client.query(keys[3].as_bytes().to_vec()).unwrap();
+    let q = bincode::deserialize(&pq.query).unwrap();
+    let resp = shard.respond(&q).unwrap();
+    let value = client.decode(pq.id, resp).unwrap();
+    assert_eq!(base64::encode(value), eles[3]);
+
+    assert!(matches!(
+      KvClient::new(b"{}".to_vec()),
+      Err(PirError::InvalidParams(_))
+    ));
+  }
+}
@@ -0,0 +1,560 @@
+//! The `grid` module provides index PIR over a DB that is arranged as a
+//! grid of about √m × √m records, with two-level querying, to shrink
+//! queries for large m.
+//!
+//! A query consists of two LWE queries: one that privately selects the
+//! grid row of a record, and one that privately selects its column.
+//!
+//! - The first level is a `Shard` whose rows are the rows of the grid,
+//!   where each record takes up its own columns. The server answers the
+//!   row query over it as any other `Shard`, but does not return the
+//!   answer, which holds a ciphertext for every record of the queried row.
+//! - The second level selects the column of the record from the answer
+//!   of the first level. Since the answer and the hint that decrypts it
+//!   are values modulo q, they are decomposed into digits of
+//!   `plaintext_bits` bits, so that they can be queried like a DB. The
+//!   digits of the hint do not depend on the query, and are held by a
+//!   second `Shard`, whose params are the hint that clients download.
+//!   The digits of the answer are multiplied by the column query (and its
+//!   LWE matrix) per query.
+//!
+//! The client decrypts the digits with the secret of the column query,
+//! recomposes the first-level ciphertext and its hint for the record, and
+//! decrypts them with the secret of the row query.
+//!
+//! Queries hold about 2√m instead of m elements. Responses and hints do
+//! not depend on m, but are about `2 * lwe_dim * 32 / plaintext_bits`
+//! and `lwe_dim * 32 / plaintext_bits` times larger than those of a
+//! `Shard`, and the server performs the extra computation of the second
+//! level. The grid therefore pays off for DBs with many small records.
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+
+use crate::api::{EncodingConfig, Query, Response, Shard};
+use crate::db::{BaseParams, CommonParams, IndexParams, RecordBytes};
+use crate::errors::{
+  ErrorParamsMismatch, ErrorQueryParamsReused, ErrorUnexpectedInputSize,
+  ResultBoxedError,
+};
+use crate::utils::format::{bytes_from_u32_slice, split_bits_le};
+use crate::utils::lwe::{Modulus, RoundingMode};
+use crate::utils::matrices::{
+  generate_lwe_matrix_from_seed, random_ternary_from, random_ternary_vector,
+  vec_mult_u32_u32,
+};
+
+/// A `GridLayout` describes how the records of a DB are arranged in a
+/// grid. Clients need it to generate queries for a `GridShard`, so it is
+/// published as part of the `GridParams`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GridLayout {
+  num_records: usize,
+  rows: usize,
+  cols: usize,
+  elem_size: usize,
+}
+impl GridLayout {
+  /// Arranges `num_records` records of `elem_size` bits in a grid of
+  /// ⌈√num_records⌉ columns, and as many rows as needed
+  pub fn new(num_records: usize, elem_size: usize) -> Self {
+    let mut cols = (num_records as f64).sqrt() as usize;
+    while cols * cols < num_records {
+      cols += 1;
+    }
+    let cols = cols.max(1);
+    Self {
+      num_records,
+      rows: num_records.div_ceil(cols),
+      cols,
+      elem_size,
+    }
+  }
+
+  /// Returns the number of records in the grid
+  pub fn num_records(&self) -> usize {
+    self.num_records
+  }
+
+  /// Returns the number of rows of the grid, i.e. the length of row
+  /// queries
+  pub fn get_rows(&self) -> usize {
+    self.rows
+  }
+
+  /// Returns the number of records in each row of the grid, i.e. the
+  /// length of column queries
+  pub fn get_cols(&self) -> usize {
+    self.cols
+  }
+
+  /// Returns the size (in bits) of each record
+  pub fn get_elem_size(&self) -> usize {
+    self.elem_size
+  }
+
+  /// Returns the grid row and column of the record at `index`
+  pub fn position(&self, index: usize) -> ResultBoxedError<(usize, usize)> {
+    if index >= self.num_records {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "record index: {} out of range for {} records",
+        index, self.num_records
+      ))));
+    }
+    Ok((index / self.cols, index % self.cols))
+  }
+
+  /// Returns the number of DB columns that each record takes up
+  fn record_width(&self, plaintext_bits: usize) -> usize {
+    self.elem_size.div_ceil(plaintext_bits)
+  }
+}
+
+/// Returns the number of digits of `plaintext_bits` bits that a value
+/// modulo q is decomposed into
+fn num_digits(plaintext_bits: usize) -> usize {
+  (Modulus::default().bits() as usize).div_ceil(plaintext_bits)
+}
+
+/// Decomposes `x` into `num_digits` digits of `plaintext_bits` bits,
+/// starting with the least significant one
+fn decompose(x: u32, plaintext_bits: usize) -> impl Iterator<Item = u32> {
+  let mask = (1u32 << plaintext_bits) - 1;
+  (0..num_digits(plaintext_bits))
+    .map(move |d| (x >> (plaintext_bits * d)) & mask)
+}
+
+/// Recomposes a value modulo q from the digits returned by `decompose`
+fn recompose(digits: &[u32], plaintext_bits: usize) -> u32 {
+  digits.iter().enumerate().fold(0u32, |x, (d, &digit)| {
+    x.wrapping_add(digit << (plaintext_bits * d))
+  })
+}
+
+/// `GridParams` are the public params of a `GridShard`, which clients
+/// download to query it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GridParams {
+  layout: GridLayout,
+  /// The seed of the LWE matrix of row queries
+  row_seed: [u8; 32],
+  /// The params of the digits of the first-level hint, whose RHS is the
+  /// hint that decrypts the second level
+  col_params: IndexParams,
+}
+impl GridParams {
+  /// Returns the layout of the grid
+  pub fn get_layout(&self) -> GridLayout {
+    self.layout
+  }
+
+  /// Returns the params of the second level, which clients decrypt the
+  /// digits of the selected column with
+  pub fn get_col_params(&self) -> &IndexParams {
+    &self.col_params
+  }
+
+  /// Checks that the params of the second level were generated for the
+  /// layout of the grid
+  fn check(&self) -> ResultBoxedError<()> {
+    let plaintext_bits = self.col_params.get_plaintext_bits();
+    let digits = self.col_params.get_dim()
+      * self.layout.record_width(plaintext_bits)
+      * num_digits(plaintext_bits);
+    if self.col_params.get_total_records() != self.layout.cols
+      || self.col_params.get_elem_size() != digits * plaintext_bits
+    {
+      return Err(Box::new(ErrorParamsMismatch::new(format!(
+        "params are not for a grid of {} columns of {} bits",
+        self.layout.cols, self.layout.elem_size
+      ))));
+    }
+    Ok(())
+  }
+}
+
+/// A `GridQuery` holds the row and column queries for a record of a
+/// `GridShard`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GridQuery {
+  row: Query,
+  col: Query,
+}
+impl GridQuery {
+  /// Returns the query selecting the grid row
+  pub fn get_row(&self) -> &Query {
+    &self.row
+  }
+
+  /// Returns the query selecting the grid column
+  pub fn get_col(&self) -> &Query {
+    &self.col
+  }
+}
+
+/// A `GridResponse` is the response to a `GridQuery`
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GridResponse {
+  /// The second-level answer for the digits of the first-level answer,
+  /// followed by that for the digits of the first-level hint
+  answer: Response,
+  /// The hint that decrypts the first part of `answer`, i.e. the LWE
+  /// matrix of column queries times the digits of the first-level answer
+  hint: Vec<Vec<u32>>,
+}
+impl GridResponse {
+  /// Returns the number of values held by the response
+  pub fn len(&self) -> usize {
+    self.answer.as_slice().len() + self.hint.iter().map(Vec::len).sum::<usize>()
+  }
+
+  /// Returns whether the response holds no values
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+/// A `GridShard` answers two-level queries for the records of a DB that
+/// is arranged in a grid (see `GridLayout`)
+pub struct GridShard {
+  /// The rows of the grid
+  rows: Shard,
+  /// The digits of the hint of `rows`, with a row per grid column
+  cols: Shard,
+  /// The LWE matrix of column queries
+  col_matrix: CommonParams,
+  layout: GridLayout,
+}
+impl GridShard {
+  /// Expects a non-empty array of base64-encoded records of at most
+  /// `elem_size` bits each, where `elem_size` is a multiple of 8. Shorter
+  /// records are padded with zero bytes, as is the last row of the grid.
+  pub fn from_base64_strings(
+    base64_strs: &[String],
+    lwe_dim: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+  ) -> ResultBoxedError<Self> {
+    if base64_strs.is_empty() {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(
+        "a grid must hold at least one record".to_string(),
+      )));
+    }
+    if elem_size % 8 != 0 {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "grid element size: {} is not a multiple of 8",
+        elem_size
+      ))));
+    }
+    let layout = GridLayout::new(base64_strs.len(), elem_size);
+    let record_len = elem_size / 8;
+    let record_width = layout.record_width(plaintext_bits);
+    let column_bits = vec![plaintext_bits; record_width];
+    // each record takes up its own columns of the grid row, so that the
+    // second level selects whole records
+    let row_size = layout.cols * record_width * plaintext_bits;
+    let mut rows = Vec::with_capacity(layout.rows);
+    for chunk in base64_strs.chunks(layout.cols) {
+      let mut row = vec![0u32; layout.cols * record_width];
+      for (i, s) in chunk.iter().enumerate() {
+        let record = base64::decode(s)?;
+        if record.len() > record_len {
+          return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+            "record length: {} bytes, grid element size: {} bytes",
+            record.len(),
+            record_len
+          ))));
+        }
+        row[i * record_width..(i + 1) * record_width]
+          .copy_from_slice(&split_bits_le(&record, &column_bits)?);
+      }
+      rows.push(base64::encode(bytes_from_u32_slice(
+        &row,
+        plaintext_bits,
+        row_size,
+      )));
+    }
+    let rows = Shard::from_encoded_strings(
+      &rows,
+      lwe_dim,
+      layout.rows,
+      row_size,
+      plaintext_bits,
+      EncodingConfig::Standard,
+    )?;
+
+    let rhs = rows.get_base_params().get_rhs();
+    let col_width = lwe_dim * record_width * num_digits(plaintext_bits);
+    let col_size = col_width * plaintext_bits;
+    let cols: Vec<String> = rhs
+      .chunks(record_width)
+      .map(|hint| {
+        let digits: Vec<u32> = hint
+          .iter()
+          .flatten()
+          .flat_map(|&x| decompose(x, plaintext_bits))
+          .collect();
+        base64::encode(bytes_from_u32_slice(&digits, plaintext_bits, col_size))
+      })
+      .collect();
+    let cols = Shard::from_encoded_strings(
+      &cols,
+      lwe_dim,
+      layout.cols,
+      col_size,
+      plaintext_bits,
+      EncodingConfig::Standard,
+    )?;
+    let col_matrix = CommonParams::from(cols.get_base_params());
+    Ok(Self {
+      rows,
+      cols,
+      col_matrix,
+      layout,
+    })
+  }
+
+  /// Responds to a query for a record with a serialized `GridResponse`.
+  /// The answer to the row query is decomposed into digits, and the
+  /// column query selects the digits of the queried record, along with
+  /// those of its hint.
+  pub fn respond(&self, q: &GridQuery) -> ResultBoxedError<Vec<u8>> {
+    let plaintext_bits = self.cols.get_base_params().get_plaintext_bits();
+    let record_width = self.layout.record_width(plaintext_bits);
+    let row_answer = self.rows.inner_products(q.row.as_slice())?;
+    let hint_answer = self.cols.inner_products(q.col.as_slice())?;
+
+    let width = record_width * num_digits(plaintext_bits);
+    let mut answer = vec![0u32; width];
+    let mut hint = vec![vec![0u32; self.col_matrix.get_dim()]; width];
+    let col_query = q.col.as_slice();
+    let records = row_answer.chunks(record_width);
+    for (c, record) in records.enumerate() {
+      let digits = record.iter().flat_map(|&x| decompose(x, plaintext_bits));
+      let a = &self.col_matrix.as_matrix()[c];
+      for ((d, out), h) in digits.zip(answer.iter_mut()).zip(hint.iter_mut()) {
+        *out = out.wrapping_add(col_query[c].wrapping_mul(d));
+        for (h, &a) in h.iter_mut().zip(a) {
+          *h = h.wrapping_add(a.wrapping_mul(d));
+        }
+      }
+    }
+    answer.extend(hint_answer);
+    Ok(bincode::serialize(&GridResponse {
+      answer: Response::from_values(answer),
+      hint,
+    })?)
+  }
+
+  /// Returns the layout of the grid
+  pub fn get_layout(&self) -> GridLayout {
+    self.layout
+  }
+
+  /// Returns the params that clients query the grid with
+  pub fn get_params(&self) -> GridParams {
+    GridParams {
+      layout: self.layout,
+      row_seed: self.rows.get_base_params().get_public_seed(),
+      col_params: self.cols.get_base_params().clone(),
+    }
+  }
+}
+
+/// `GridQueryParams` hold the client secrets for querying a single
+/// record of a `GridShard`
+pub struct GridQueryParams {
+  layout: GridLayout,
+  plaintext_bits: usize,
+  row_secret: Vec<u32>,
+  col_secret: Vec<u32>,
+  row_lhs: Vec<u32>,
+  col_lhs: Vec<u32>,
+  /// The secret of the column query times the second-level hint
+  col_rhs: Vec<u32>,
+  used: bool,
+}
+impl GridQueryParams {
+  /// Generates query params for the grid that `params` were published
+  /// for
+  pub fn new(params: &GridParams) -> ResultBoxedError<Self> {
+    params.check()?;
+    let col_params = params.get_col_params();
+    let dim = col_params.get_dim();
+    let row_secret = random_ternary_vector(dim);
+    let col_secret = random_ternary_vector(dim);
+    let row_matrix =
+      generate_lwe_matrix_from_seed(params.row_seed, dim, params.layout.rows);
+    let row_lhs = row_matrix
+      .iter()
+      .map(|a| {
+        let s_a = vec_mult_u32_u32(&row_secret, a)?;
+        Ok(s_a.wrapping_add(random_ternary_from(&mut OsRng)))
+      })
+      .collect::<ResultBoxedError<_>>()?;
+    let col_lhs = CommonParams::from(col_params).mult_left(&col_secret)?;
+    Ok(Self {
+      layout: params.layout,
+      plaintext_bits: col_params.get_plaintext_bits(),
+      col_rhs: col_params.mult_right(&col_secret)?,
+      row_secret,
+      col_secret,
+      row_lhs,
+      col_lhs,
+      used: false,
+    })
+  }
+
+  /// Generates a query for the record at `index`. The params are used up
+  /// by the query, since queries under the same secrets would reveal the
+  /// relation between the queried records.
+  pub fn generate_query(
+    &mut self,
+    index: usize,
+  ) -> ResultBoxedError<GridQuery> {
+    if self.used {
+      return Err(Box::new(ErrorQueryParamsReused {}));
+    }
+    let (row, col) = self.layout.position(index)?;
+    self.used = true;
+    let indicator = Modulus::default().rounding_factor(self.plaintext_bits);
+    let mut row_query = self.row_lhs.clone();
+    row_query[row] = row_query[row].wrapping_add(indicator);
+    let mut col_query = self.col_lhs.clone();
+    col_query[col] = col_query[col].wrapping_add(indicator);
+    Ok(GridQuery {
+      row: Query::from_values(row_query),
+      col: Query::from_values(col_query),
+    })
+  }
+
+  /// Parses the response to the generated query as the bytes of the
+  /// queried record
+  pub fn parse_resp_as_bytes(
+    &self,
+    resp: &GridResponse,
+  ) -> ResultBoxedError<Vec<u8>> {
+    if !self.used {
+      return Err("No query generated for the grid params".into());
+    }
+    let plaintext_bits = self.plaintext_bits;
+    let record_width = self.layout.record_width(plaintext_bits);
+    let digits = num_digits(plaintext_bits);
+    let answer = resp.answer.as_slice();
+    if resp.hint.len() != record_width * digits
+      || answer.len() != resp.hint.len() + self.col_rhs.len()
+    {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "grid response of {} values, expected {}",
+        answer.len(),
+        record_width * digits + self.col_rhs.len()
+      ))));
+    }
+    let mut rhs = resp
+      .hint
+      .iter()
+      .map(|h| vec_mult_u32_u32(&self.col_secret, h))
+      .collect::<ResultBoxedError<Vec<u32>>>()?;
+    rhs.extend_from_slice(&self.col_rhs);
+    let modulus = Modulus::default();
+    let unscaled: Vec<u32> = answer
+      .iter()
+      .zip(&rhs)
+      .map(|(a, r)| a.wrapping_sub(*r))
+      .collect();
+    let col_digits =
+      modulus.round_residues(&unscaled, plaintext_bits, RoundingMode::Nearest);
+
+    let (answer_digits, hint_digits) = col_digits.split_at(resp.hint.len());
+    let hint_len = self.row_secret.len() * digits;
+    let unscaled = answer_digits
+      .chunks(digits)
+      .zip(hint_digits.chunks(hint_len))
+      .map(|(a, h)| {
+        let hint: Vec<u32> = h
+          .chunks(digits)
+          .map(|x| recompose(x, plaintext_bits))
+          .collect();
+        let s_h = vec_mult_u32_u32(&self.row_secret, &hint)?;
+        Ok(recompose(a, plaintext_bits).wrapping_sub(s_h))
+      })
+      .collect::<ResultBoxedError<Vec<u32>>>()?;
+    let record =
+      modulus.round_residues(&unscaled, plaintext_bits, RoundingMode::Nearest);
+    Ok(bytes_from_u32_slice(
+      &record,
+      plaintext_bits,
+      self.layout.elem_size,
+    ))
+  }
+
+  /// Same as `parse_resp_as_bytes`, returning a `RecordBytes`
+  pub fn parse_resp_as_record(
+    &self,
+    resp: &GridResponse,
+  ) -> ResultBoxedError<RecordBytes> {
+    Ok(RecordBytes::from(self.parse_resp_as_bytes(resp)?))
+  }
+
+  /// Same as `parse_resp_as_bytes`, returning a base64-encoded string
+  pub fn parse_resp_as_base64(
+    &self,
+    resp: &GridResponse,
+  ) -> ResultBoxedError<String> {
+    Ok(base64::encode(self.parse_resp_as_bytes(resp)?))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utils::generate_db_eles;
+
+  #[test]
+  fn grid_queries_shrink_with_sqrt_m() {
+    let elem_size = 32;
+    let eles = generate_db_eles(150, elem_size / 8);
+    let shard =
+      GridShard::from_base64_strings(&eles, 512, elem_size, 10).unwrap();
+    let layout = shard.get_layout();
+    assert_eq!((layout.get_rows(), layout.get_cols()), (12, 13));
+    let params = shard.get_params();
+    let mut resp_len = None;
+    for &i in &[0, 13, 77, 149] {
+      let mut qp = GridQueryParams::new(&params).unwrap();
+      let q = qp.generate_query(i).unwrap();
+      assert_eq!(q.get_row().as_slice().len(), layout.get_rows());
+      assert_eq!(q.get_col().as_slice().len(), layout.get_cols());
+      let resp: GridResponse =
+        bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+      assert_eq!(qp.parse_resp_as_base64(&resp).unwrap(), eles[i]);
+      assert!(qp.generate_query(i).is_err());
+      resp_len = Some(resp.len());
+    }
+    let mut qp = GridQueryParams::new(&params).unwrap();
+    assert!(qp.generate_query(150).is_err());
+
+    // responses do not grow with the number of records
+    let more = generate_db_eles(600, elem_size / 8);
+    let shard =
+      GridShard::from_base64_strings(&more, 512, elem_size, 10).unwrap();
+    let mut qp = GridQueryParams::new(&shard.get_params()).unwrap();
+    let q = qp.generate_query(599).unwrap();
+    let resp: GridResponse =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp).unwrap(), more[599]);
+    assert_eq!(Some(resp.len()), resp_len);
+    assert!(GridQueryParams::new(&GridParams {
+      layout: GridLayout::new(150, elem_size),
+      ..shard.get_params()
+    })
+    .is_err());
+  }
+
+  #[test]
+  fn grid_rejects_invalid_input() {
+    assert!(GridShard::from_base64_strings(&[], 512, 32, 10).is_err());
+    let eles = generate_db_eles(4, 4);
+    assert!(GridShard::from_base64_strings(&eles, 512, 30, 10).is_err());
+    assert!(GridShard::from_base64_strings(&eles, 512, 16, 10).is_err());
+  }
+}
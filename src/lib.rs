@@ -1,5 +1,39 @@
+// the original code rounds up divisions and builds one-element slices by
+// hand, which is kept as is
+#![allow(clippy::manual_div_ceil, clippy::cloned_ref_to_slice_refs)]
+pub mod adapters;
 pub mod api;
+pub mod audit;
+pub mod builder;
+pub mod client;
+pub mod compression;
+pub mod conformance;
 // TODO: make this private ideally, need it for benchmarking atm
 pub mod db;
+pub mod delta;
+pub mod entropy;
 pub mod errors;
+pub mod expiry;
+#[cfg(feature = "uniffi")]
+pub mod ffi;
+pub mod grid;
+pub mod linalg;
+pub mod manifest;
+#[cfg(feature = "packing")]
+pub mod packing;
+pub mod parity;
+pub mod prelude;
+pub mod protocol;
+pub mod proxy;
+pub mod schema;
+pub mod serving;
+pub mod simulate;
+pub mod synthetic;
+#[cfg(test)]
+mod test_utils;
+pub mod transport;
+pub mod tuning;
 mod utils;
+
+#[cfg(feature = "uniffi")]
+uniffi::setup_scaffolding!();
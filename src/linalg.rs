@@ -0,0 +1,144 @@
+//! The `linalg` module exposes the low-level matrix operations that the
+//! PIR scheme is built from, for use in research and tooling code.
+//!
+//! Matrices are represented as `Vec<Vec<u32>>`, either in row format
+//! (`matrix[row][col]`) or in column format (`matrix[col][row]`). The
+//! scheme itself operates modulo 2^32, so the default operations use
+//! wrapping arithmetic; the `checked_*` variants instead return an error
+//! whenever a value would overflow a u32, which is useful when working
+//! with plaintext values that are expected to stay small.
+use crate::errors::{
+  ErrorOverflownAdd, ErrorOverflownMul, ErrorUnexpectedInputSize,
+  ResultBoxedError,
+};
+
+pub use crate::utils::matrices::{
+  generate_lwe_matrix_flat, generate_lwe_matrix_from_seed,
+  get_matrix_second_at, swap_matrix_fmt, vec_mult_u32_u32, FillOrder,
+};
+
+/// As `swap_matrix_fmt`, but returns an error if `matrix` is empty or its
+/// rows are not all the same length, rather than panicking or truncating
+pub fn checked_swap_matrix_fmt(
+  matrix: &[Vec<u32>],
+) -> ResultBoxedError<Vec<Vec<u32>>> {
+  let width = match matrix.first() {
+    Some(row) => row.len(),
+    None => {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(
+        "Matrix should have at least one row".to_string(),
+      )))
+    }
+  };
+  if let Some(i) = matrix.iter().position(|row| row.len() != width) {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "Row {} has length {}, but row 0 has length {}",
+      i,
+      matrix[i].len(),
+      width
+    ))));
+  }
+  Ok(swap_matrix_fmt(matrix))
+}
+
+/// As `vec_mult_u32_u32`, but returns an error if any product or partial
+/// sum overflows a u32, rather than reducing modulo 2^32
+pub fn checked_vec_mult_u32_u32(
+  row: &[u32],
+  col: &[u32],
+) -> ResultBoxedError<u32> {
+  if row.len() != col.len() {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "row_len: {}, col_len: {}",
+      row.len(),
+      col.len(),
+    ))));
+  }
+  let mut acc = 0u32;
+  for (r, c) in row.iter().zip(col) {
+    let prod = r.checked_mul(*c).ok_or(ErrorOverflownMul)?;
+    acc = acc.checked_add(prod).ok_or(ErrorOverflownAdd)?;
+  }
+  Ok(acc)
+}
+
+/// Multiplies the vector `v` with each column of `cols` (a matrix in
+/// column format), modulo 2^32
+pub fn vec_mult_matrix_u32(
+  v: &[u32],
+  cols: &[Vec<u32>],
+) -> ResultBoxedError<Vec<u32>> {
+  cols.iter().map(|col| vec_mult_u32_u32(v, col)).collect()
+}
+
+/// As `vec_mult_matrix_u32`, but returns an error on u32 overflow
+pub fn checked_vec_mult_matrix_u32(
+  v: &[u32],
+  cols: &[Vec<u32>],
+) -> ResultBoxedError<Vec<u32>> {
+  cols
+    .iter()
+    .map(|col| checked_vec_mult_u32_u32(v, col))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn swap_matrix_fmt_round_trip() {
+    let matrix = vec![vec![1, 2, 3], vec![4, 5, 6]];
+    let swapped = checked_swap_matrix_fmt(&matrix).unwrap();
+    assert_eq!(swapped, vec![vec![1, 4], vec![2, 5], vec![3, 6]]);
+    assert_eq!(swap_matrix_fmt(&swapped), matrix);
+    assert_eq!(get_matrix_second_at(&matrix, 1), swapped[1]);
+
+    assert!(checked_swap_matrix_fmt(&[]).is_err());
+    assert!(checked_swap_matrix_fmt(&[vec![1, 2], vec![3]]).is_err());
+  }
+
+  #[test]
+  fn checked_mult_detects_overflow() {
+    let row = [1u32, 2, 3];
+    let cols = vec![vec![4u32, 5, 6], vec![u32::MAX, 0, 0]];
+    assert_eq!(
+      vec_mult_matrix_u32(&row, &cols).unwrap(),
+      vec![32, u32::MAX]
+    );
+    assert_eq!(
+      checked_vec_mult_matrix_u32(&row, &cols).unwrap(),
+      vec![32, u32::MAX]
+    );
+
+    let overflow_mul = checked_vec_mult_u32_u32(&[2], &[u32::MAX]);
+    assert!(overflow_mul.unwrap_err().is::<ErrorOverflownMul>());
+    let overflow_add = checked_vec_mult_u32_u32(&[1, 1], &[u32::MAX, 1]);
+    assert!(overflow_add.unwrap_err().is::<ErrorOverflownAdd>());
+    assert_eq!(vec_mult_u32_u32(&[1, 1], &[u32::MAX, 1]).unwrap(), 0);
+    assert!(checked_vec_mult_u32_u32(&[1], &[1, 2]).is_err());
+  }
+
+  #[test]
+  fn lwe_matrix_is_deterministic() {
+    let seed = [7u8; 32];
+    let a = generate_lwe_matrix_from_seed(seed, 16, 8);
+    assert_eq!(a.len(), 8);
+    assert!(a.iter().all(|row| row.len() == 16));
+    assert_eq!(a, generate_lwe_matrix_from_seed(seed, 16, 8));
+    // generating more rows extends the matrix rather than changing it
+    assert_eq!(a[..], generate_lwe_matrix_from_seed(seed, 16, 10)[..8]);
+    assert_ne!(a, generate_lwe_matrix_from_seed([8u8; 32], 16, 8));
+  }
+
+  #[test]
+  fn flat_lwe_matrix_matches_nested() {
+    let seed = [7u8; 32];
+    let a = generate_lwe_matrix_from_seed(seed, 16, 8);
+    let col_major =
+      generate_lwe_matrix_flat(seed, 16, 8, FillOrder::ColumnMajor);
+    assert_eq!(col_major, a.concat());
+    let row_major = generate_lwe_matrix_flat(seed, 16, 8, FillOrder::RowMajor);
+    assert_eq!(row_major, swap_matrix_fmt(&a).concat());
+  }
+}
@@ -0,0 +1,198 @@
+//! The `manifest` module lets servers sign the params they publish, so
+//! that clients can detect params that were tampered with on the way,
+//! e.g. when params and hints are distributed through a CDN.
+//!
+//! A `Manifest` commits to the digest of the params (see
+//! `PirShard::params_digest`) and to an epoch, and is signed with the
+//! ed25519 key of the server. Clients that know the verifying key check
+//! the signature and the digest before using downloaded params (see
+//! `PirClient::connect_index_verified`), and reject manifests of earlier
+//! epochs, so that stale params cannot be replayed.
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+use ed25519_dalek::{Signature, Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::api::PirShard;
+use crate::errors::{ErrorManifestInvalid, ResultBoxedError};
+
+/// The domain separator prepended to signed manifests
+const MANIFEST_DOMAIN: &[u8] = b"chalamet-params-manifest-v1";
+
+/// A `Manifest` identifies the params served in an epoch
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+  /// The SHA-256 digest of the base params (see `PirShard::params_digest`)
+  pub params_digest: [u8; 32],
+  /// The epoch in which the params are served, e.g. the number of DB
+  /// reloads (see `ReloadableShard::epoch`)
+  pub epoch: u64,
+}
+impl Manifest {
+  /// Returns the manifest of the params of `shard` in `epoch`
+  pub fn for_shard<S: PirShard>(
+    shard: &S,
+    epoch: u64,
+  ) -> ResultBoxedError<Self> {
+    Ok(Self {
+      params_digest: shard.params_digest()?,
+      epoch,
+    })
+  }
+
+  /// Signs the manifest with `key`
+  pub fn sign(&self, key: &SigningKey) -> ResultBoxedError<SignedManifest> {
+    Ok(SignedManifest {
+      manifest: *self,
+      signature: key.sign(&self.signed_bytes()?).to_bytes().to_vec(),
+    })
+  }
+
+  /// Returns the bytes covered by the signature
+  fn signed_bytes(&self) -> ResultBoxedError<Vec<u8>> {
+    let mut bytes = MANIFEST_DOMAIN.to_vec();
+    bytes.extend(bincode::serialize(self)?);
+    Ok(bytes)
+  }
+}
+
+/// A `SignedManifest` is a `Manifest` along with the ed25519 signature of
+/// the server. It is published as JSON next to the params.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedManifest {
+  manifest: Manifest,
+  signature: Vec<u8>,
+}
+impl SignedManifest {
+  /// Returns the signed manifest, without checking the signature
+  pub fn get_manifest(&self) -> &Manifest {
+    &self.manifest
+  }
+
+  /// Returns the manifest if it was signed by `key`
+  pub fn verify(&self, key: &VerifyingKey) -> ResultBoxedError<&Manifest> {
+    let signature = Signature::from_slice(&self.signature).map_err(|_| {
+      ErrorManifestInvalid::new("malformed signature".to_string())
+    })?;
+    key
+      .verify(&self.manifest.signed_bytes()?, &signature)
+      .map_err(|_| {
+        ErrorManifestInvalid::new("invalid signature".to_string())
+      })?;
+    Ok(&self.manifest)
+  }
+
+  /// Returns the manifest if it was signed by `key` and covers `params`
+  pub fn verify_params<P: Serialize>(
+    &self,
+    key: &VerifyingKey,
+    params: &P,
+  ) -> ResultBoxedError<&Manifest> {
+    let manifest = self.verify(key)?;
+    let digest: [u8; 32] = Sha256::digest(bincode::serialize(params)?).into();
+    if digest != manifest.params_digest {
+      return Err(Box::new(ErrorManifestInvalid::new(
+        "params digest does not match the manifest".to_string(),
+      )));
+    }
+    Ok(manifest)
+  }
+}
+
+/// A `ManifestVerifier` checks the manifests that a client downloads
+/// along with params, remembering the latest verified epoch
+#[derive(Clone, Debug)]
+pub struct ManifestVerifier {
+  key: VerifyingKey,
+  epoch: Option<u64>,
+}
+impl ManifestVerifier {
+  /// Verifies manifests signed by `key`
+  pub fn new(key: VerifyingKey) -> Self {
+    Self { key, epoch: None }
+  }
+
+  /// Returns the epoch of the latest verified manifest
+  pub fn epoch(&self) -> Option<u64> {
+    self.epoch
+  }
+
+  /// Checks that the serialized `manifest` is signed, covers `params`,
+  /// and is not older than the latest verified manifest
+  pub fn verify<P: Serialize>(
+    &mut self,
+    manifest: &[u8],
+    params: &P,
+  ) -> ResultBoxedError<()> {
+    let signed: SignedManifest = serde_json::from_slice(manifest)?;
+    let manifest = signed.verify_params(&self.key, params)?;
+    if self.epoch.is_some_and(|epoch| manifest.epoch < epoch) {
+      return Err(Box::new(ErrorManifestInvalid::new(format!(
+        "manifest epoch: {}, latest verified epoch: {}",
+        manifest.epoch,
+        self.epoch.unwrap()
+      ))));
+    }
+    self.epoch = Some(manifest.epoch);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::client::PirClient;
+  use crate::test_utils::index_shard;
+  use crate::transport::{InMemoryTransport, Loopback};
+  use rand_core::OsRng;
+
+  #[test]
+  fn clients_verify_signed_manifests() {
+    let (eles, shard) = index_shard();
+    let key = SigningKey::generate(&mut OsRng);
+    let manifest = Manifest::for_shard(&shard, 3).unwrap().sign(&key).unwrap();
+
+    let transport = Loopback::new(shard.clone()).with_manifest(&manifest);
+    let mut client =
+      PirClient::connect_index_verified(transport, key.verifying_key())
+        .unwrap();
+    assert_eq!(
+      client.retrieve(5).unwrap(),
+      base64::decode(&eles[5]).unwrap()
+    );
+
+    // params that the manifest does not cover are rejected
+    let (_, other) = index_shard();
+    let transport = InMemoryTransport::new(other).with_manifest(&manifest);
+    let err = PirClient::connect_index_verified(transport, key.verifying_key())
+      .err()
+      .unwrap();
+    assert!(err.is::<ErrorManifestInvalid>());
+
+    // as are manifests signed by other keys
+    let other_key = SigningKey::generate(&mut OsRng);
+    let transport = InMemoryTransport::new(shard.clone()).with_manifest(
+      &Manifest::for_shard(&shard, 3)
+        .unwrap()
+        .sign(&other_key)
+        .unwrap(),
+    );
+    assert!(
+      PirClient::connect_index_verified(transport, key.verifying_key())
+        .is_err()
+    );
+
+    // and manifests of earlier epochs
+    let mut verifier = ManifestVerifier::new(key.verifying_key());
+    let params = shard.get_base_params();
+    let signed = |epoch| {
+      let m = Manifest::for_shard(&shard, epoch).unwrap();
+      serde_json::to_vec(&m.sign(&key).unwrap()).unwrap()
+    };
+    verifier.verify(&signed(3), params).unwrap();
+    verifier.verify(&signed(4), params).unwrap();
+    assert!(verifier.verify(&signed(3), params).is_err());
+    assert_eq!(verifier.epoch(), Some(4));
+  }
+}
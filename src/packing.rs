@@ -0,0 +1,290 @@
+//! The `packing` module is an EXPERIMENTAL research mode for shrinking
+//! responses to wide rows. It requires the `packing` feature, and its wire
+//! format may change between releases.
+//!
+//! Each slot of a response is an LWE sample modulo q = 2^32 (or the
+//! modulus of the params), of which only the top plaintext bits carry
+//! information. The server switches every slot to a smaller modulus 2^b,
+//! and packs the resulting b-bit slots densely into 32-bit words, so that
+//! a response shrinks by a factor of about 32 / b. The client lifts the
+//! slots back to the original modulus before decoding as usual, where the
+//! switch adds at most 2^(32 - b - 1) to the noise of each slot.
+//!
+//! The slot size is adjustable: `slot_bits_for` returns a size that keeps
+//! `margin_bits` bits between the plaintext and the rounding error. Fewer
+//! margin bits give smaller responses, but decoding fails once the added
+//! rounding error and the LWE noise exceed the rounding threshold, so the
+//! margin should be checked for the deployed params (see
+//! `decode_with_margin`).
+//!
+//! Servers answer queries with `Shard::respond_packed` (or
+//! `KVShard::respond_packed`), and clients recover the response with
+//! `PackedResponse::unpack_bytes`, which rejects malformed packed
+//! responses with an error.
+//!
+//! LWE-to-RLWE packing is not used: it shrinks the `a` parts of many LWE
+//! ciphertexts into a single RLWE ciphertext, but responses of this
+//! scheme carry no `a` parts, since the client derives them from the
+//! downloaded hint. Every slot of a response is already the `b` part of
+//! an LWE sample, so switching the modulus of the slots is the packing
+//! that applies.
+use serde::{Deserialize, Serialize};
+
+use crate::api::{KVShard, Modulus, Query, Response, Shard};
+use crate::db::BaseParams;
+use crate::errors::{ErrorUnexpectedInputSize, ResultBoxedError};
+
+/// A `PackedResponse` holds the slots of a `Response` after switching
+/// them to a modulus of 2^`slot_bits`, packed into 32-bit words
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackedResponse {
+  modulus: Modulus,
+  slot_bits: u32,
+  len: usize,
+  words: Vec<u32>,
+}
+impl PackedResponse {
+  /// Switches the slots of `resp` from `modulus` to 2^`slot_bits` and
+  /// packs them. Fails unless `slot_bits` is positive and below the bits
+  /// of `modulus`.
+  pub fn pack(
+    resp: &Response,
+    modulus: Modulus,
+    slot_bits: u32,
+  ) -> ResultBoxedError<Self> {
+    check_slot_bits(modulus, slot_bits)?;
+    let shift = modulus.bits() - slot_bits;
+    let slot_mask = (1u64 << slot_bits) - 1;
+    let values = resp.as_slice();
+    let mut words = vec![0u32; packed_words(values.len(), slot_bits)?];
+    for (i, &v) in values.iter().enumerate() {
+      // round to the nearest multiple of 2^shift
+      let v = modulus.reduce(v) as u64;
+      let slot = ((v + (1 << (shift - 1))) >> shift) & slot_mask;
+      let bit = i * slot_bits as usize;
+      let (word, offset) = (bit / 32, bit % 32);
+      let spread = slot << offset;
+      words[word] |= spread as u32;
+      if offset + slot_bits as usize > 32 {
+        words[word + 1] |= (spread >> 32) as u32;
+      }
+    }
+    Ok(Self {
+      modulus,
+      slot_bits,
+      len: values.len(),
+      words,
+    })
+  }
+
+  /// Returns the number of slots in the response
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  /// Returns whether the response holds no slots
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  /// Returns the number of bits of each packed slot
+  pub fn get_slot_bits(&self) -> u32 {
+    self.slot_bits
+  }
+
+  /// Returns the packed words, i.e. the data sent to the client
+  pub fn as_words(&self) -> &[u32] {
+    &self.words
+  }
+
+  /// Lifts the packed slots back to the original modulus, returning a
+  /// `Response` that parses as the unpacked response, up to the noise
+  /// added by packing. Fails if the slot size or the number of packed
+  /// words (e.g. of a response received from a server) are inconsistent.
+  pub fn unpack(&self) -> ResultBoxedError<Response> {
+    check_slot_bits(self.modulus, self.slot_bits)?;
+    let expected = packed_words(self.len, self.slot_bits)?;
+    if self.words.len() != expected {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "{} packed words for {} slots of {} bits, expected {}",
+        self.words.len(),
+        self.len,
+        self.slot_bits,
+        expected
+      ))));
+    }
+    let shift = self.modulus.bits() - self.slot_bits;
+    let slot_mask = (1u64 << self.slot_bits) - 1;
+    let values = (0..self.len)
+      .map(|i| {
+        let bit = i * self.slot_bits as usize;
+        let (word, offset) = (bit / 32, bit % 32);
+        let mut spread = (self.words[word] as u64) >> offset;
+        if offset + self.slot_bits as usize > 32 {
+          spread |= (self.words[word + 1] as u64) << (32 - offset);
+        }
+        ((spread & slot_mask) << shift) as u32
+      })
+      .collect();
+    Ok(Response::from_values(values))
+  }
+
+  /// Deserializes a packed response produced by `respond_packed`, and
+  /// unpacks it (see `unpack`)
+  pub fn unpack_bytes(bytes: &[u8]) -> ResultBoxedError<Response> {
+    let packed: Self = bincode::deserialize(bytes)?;
+    packed.unpack()
+  }
+}
+
+impl Shard {
+  /// Produces a serialized `PackedResponse` to a client query, with the
+  /// slots of the response packed into `slot_bits` bits each (see
+  /// `slot_bits_for`)
+  pub fn respond_packed(
+    &self,
+    q: &Query,
+    slot_bits: u32,
+  ) -> ResultBoxedError<Vec<u8>> {
+    let resp = Response::from_values(self.inner_products(q.as_slice())?);
+    let modulus = self.get_base_params().get_modulus();
+    Ok(bincode::serialize(&PackedResponse::pack(
+      &resp, modulus, slot_bits,
+    )?)?)
+  }
+}
+
+impl KVShard {
+  /// Same as `Shard::respond_packed`
+  pub fn respond_packed(
+    &self,
+    q: &Query,
+    slot_bits: u32,
+  ) -> ResultBoxedError<Vec<u8>> {
+    let resp = Response::from_values(self.inner_products(q.as_slice())?);
+    let modulus = self.get_base_params().get_modulus();
+    Ok(bincode::serialize(&PackedResponse::pack(
+      &resp, modulus, slot_bits,
+    )?)?)
+  }
+}
+
+/// Checks that slots of `slot_bits` bits are smaller than `modulus`
+fn check_slot_bits(modulus: Modulus, slot_bits: u32) -> ResultBoxedError<()> {
+  if slot_bits == 0 || slot_bits >= modulus.bits() {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "slot bits: {}, modulus bits: {}",
+      slot_bits,
+      modulus.bits()
+    ))));
+  }
+  Ok(())
+}
+
+/// Returns the number of 32-bit words that hold `len` slots of
+/// `slot_bits` bits
+fn packed_words(len: usize, slot_bits: u32) -> ResultBoxedError<usize> {
+  len
+    .checked_mul(slot_bits as usize)
+    .map(|bits| bits.div_ceil(32))
+    .ok_or_else(|| {
+      Box::new(ErrorUnexpectedInputSize::new(format!(
+        "{} slots of {} bits overflow",
+        len, slot_bits
+      ))) as Box<dyn std::error::Error>
+    })
+}
+
+/// Returns the slot size that keeps `margin_bits` bits of room for
+/// rounding errors below `plaintext_bits` bits of plaintext
+pub fn slot_bits_for(plaintext_bits: usize, margin_bits: usize) -> u32 {
+  (plaintext_bits + margin_bits) as u32
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::*;
+  use crate::test_utils::generate_db_eles;
+  use rand_core::{OsRng, RngCore};
+
+  #[test]
+  fn packed_responses_decode() {
+    let elem_size = 2u32.pow(10) as usize;
+    let plaintext_bits = 10;
+    let eles = generate_db_eles(64, elem_size / 8);
+    let shard =
+      Shard::from_base64_strings(&eles, 512, 64, elem_size, plaintext_bits)
+        .unwrap();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let slot_bits = slot_bits_for(plaintext_bits, 6);
+    for i in [0, 31, 63] {
+      let mut qp = generate_index_query_params(&cp, bp).unwrap();
+      let q = qp.generate_query(i).unwrap();
+      let resp: Response =
+        bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+      let packed =
+        PackedResponse::pack(&resp, qp.get_modulus(), slot_bits).unwrap();
+      assert_eq!(packed.len(), resp.as_slice().len());
+      assert!(packed.as_words().len() * 32 < resp.as_slice().len() * 20);
+      assert_eq!(qp.parse_resp_as_base64(&packed.unpack().unwrap()), eles[i]);
+      let unpacked = PackedResponse::unpack_bytes(
+        &shard.respond_packed(&q, slot_bits).unwrap(),
+      )
+      .unwrap();
+      assert_eq!(qp.parse_resp_as_base64(&unpacked), eles[i]);
+    }
+    let resp = Response::from_values(vec![0; 4]);
+    assert!(PackedResponse::pack(&resp, Modulus::default(), 0).is_err());
+    assert!(PackedResponse::pack(&resp, Modulus::default(), 32).is_err());
+  }
+
+  #[test]
+  fn slots_round_trip_across_words() {
+    let values: Vec<u32> = (0..100).map(|_| OsRng.next_u32()).collect();
+    let resp = Response::from_values(values.clone());
+    for slot_bits in [1, 7, 13, 31] {
+      let packed =
+        PackedResponse::pack(&resp, Modulus::default(), slot_bits).unwrap();
+      let shift = 32 - slot_bits;
+      let unpacked = packed.unpack().unwrap();
+      for (v, u) in values.iter().zip(unpacked.as_slice()) {
+        // the lifted slot is within half a step of the original value
+        let diff = v.wrapping_sub(*u).min(u.wrapping_sub(*v));
+        assert!(diff <= 1 << (shift - 1));
+      }
+    }
+  }
+
+  #[test]
+  fn malformed_packed_responses_are_rejected() {
+    let resp = Response::from_values(vec![7; 10]);
+    let packed = PackedResponse::pack(&resp, Modulus::default(), 13).unwrap();
+    let malformed = [
+      PackedResponse {
+        words: packed.words[1..].to_vec(),
+        ..packed.clone()
+      },
+      PackedResponse {
+        slot_bits: 32,
+        ..packed.clone()
+      },
+      PackedResponse {
+        slot_bits: 0,
+        ..packed.clone()
+      },
+      PackedResponse {
+        len: usize::MAX,
+        ..packed.clone()
+      },
+    ];
+    for m in malformed {
+      assert!(m.unpack().is_err());
+      assert!(
+        PackedResponse::unpack_bytes(&bincode::serialize(&m).unwrap()).is_err()
+      );
+    }
+    assert!(PackedResponse::unpack_bytes(&[1, 2, 3]).is_err());
+  }
+}
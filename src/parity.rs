@@ -0,0 +1,260 @@
+//! The `parity` module adds parity shards to a `ShardCluster` of index
+//! shards, so that clients can still retrieve the records of a shard that
+//! is missing (or serves corrupted responses).
+//!
+//! Queries to different shards are answered under different public
+//! matrices, so responses cannot be combined directly. Instead, the
+//! parity is computed over the records: the ith record of the parity
+//! shard encodes the ith records of all data shards (see `ParityCode`).
+//! To retrieve the ith record of a missing shard, a client retrieves the
+//! ith record of the parity shard and of every other data shard (each
+//! with its own private query), and reconstructs the missing record from
+//! them. Shards with fewer records contribute all-zero records beyond
+//! their last record.
+//!
+//! Parity is only supported for index shards: the rows of a KV shard are
+//! filter slots at positions that depend on the keys of the shard. Shards
+//! whose records are compressed or carry expiry timestamps are rejected
+//! too, since clients decode those records to other bytes than the ones
+//! the parity is computed over.
+use crate::api::{EncodingConfig, Shard};
+use crate::compression::Compression;
+use crate::db::{BaseParams, DatabaseMatrix};
+use crate::errors::{ErrorUnexpectedInputSize, ResultBoxedError};
+
+/// A `ParityCode` computes the parity of records, and reconstructs a
+/// missing record from the parity and the remaining records
+pub trait ParityCode {
+  /// Returns the parity of `records`, which all have the same length
+  fn encode(&self, records: &[&[u8]]) -> Vec<u8>;
+  /// Reconstructs the single record missing from `others`, given the
+  /// `parity` of all records
+  fn reconstruct(&self, parity: &[u8], others: &[&[u8]]) -> Vec<u8>;
+}
+
+/// `XorParity` is the parity code of RAID-5: the parity is the XOR of
+/// the records, which tolerates a single missing shard
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct XorParity;
+
+impl ParityCode for XorParity {
+  fn encode(&self, records: &[&[u8]]) -> Vec<u8> {
+    let len = records.iter().map(|r| r.len()).max().unwrap_or(0);
+    let mut parity = vec![0u8; len];
+    for record in records {
+      for (p, b) in parity.iter_mut().zip(record.iter()) {
+        *p ^= b;
+      }
+    }
+    parity
+  }
+
+  fn reconstruct(&self, parity: &[u8], others: &[&[u8]]) -> Vec<u8> {
+    let mut records = others.to_vec();
+    records.push(parity);
+    self.encode(&records)
+  }
+}
+
+/// Builds the parity shard of the index shards `shards`, which must share
+/// their element size, plaintext bits and LWE dimension, and store their
+/// records without compression or expiry timestamps. The parity is
+/// computed over the records that clients retrieve, so deduplicated
+/// shards (see `Shard::from_encoded_strings_deduplicated`) and shards
+/// with columns of different widths are supported. The parity shard has
+/// one row per record of the largest shard, and is generated with fresh
+/// public params.
+pub fn build_parity_shard<C: ParityCode>(
+  shards: &[Shard],
+  code: &C,
+) -> ResultBoxedError<Shard> {
+  let first = match shards.first() {
+    Some(first) => first.get_base_params(),
+    None => {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(
+        "no shards to compute the parity of".to_string(),
+      )))
+    }
+  };
+  let (elem_size, plaintext_bits, lwe_dim) = (
+    first.get_elem_size(),
+    first.get_plaintext_bits(),
+    first.get_dim(),
+  );
+  if shards.iter().any(|s| {
+    let bp = s.get_base_params();
+    (bp.get_elem_size(), bp.get_plaintext_bits(), bp.get_dim())
+      != (elem_size, plaintext_bits, lwe_dim)
+  }) {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(
+      "shards differ in element size, plaintext bits or LWE dimension"
+        .to_string(),
+    )));
+  }
+  if shards.iter().any(|s| {
+    let bp = s.get_base_params();
+    bp.get_compression() != Compression::None || bp.get_record_expiry()
+  }) {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(
+      "parity is not supported for shards with compressed or expiring \
+       records"
+        .to_string(),
+    )));
+  }
+  let height = shards
+    .iter()
+    .map(|s| s.get_base_params().num_records())
+    .max()
+    .unwrap_or(0);
+  let parity = (0..height)
+    .map(|i| {
+      let records = shards
+        .iter()
+        .map(|s| (s.get_base_params(), s.get_db()))
+        .filter(|(bp, _)| i < bp.num_records())
+        .map(|(bp, db)| db.get_db_entry_bytes(bp.get_record_row(i)?))
+        .collect::<ResultBoxedError<Vec<Vec<u8>>>>()?;
+      let records: Vec<&[u8]> = records.iter().map(|r| r.as_slice()).collect();
+      Ok(EncodingConfig::Standard.encode(&code.encode(&records)))
+    })
+    .collect::<ResultBoxedError<Vec<String>>>()?;
+  Shard::from_base64_strings(
+    &parity,
+    lwe_dim,
+    height,
+    elem_size,
+    plaintext_bits,
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::*;
+  use crate::serving::ShardCluster;
+  use crate::test_utils::generate_db_eles;
+
+  /// Retrieves `row` of `shard`, which is the shard at `idx` of `cluster`
+  /// or its parity shard
+  fn retrieve(
+    cluster: &ShardCluster,
+    shard: &Shard,
+    idx: Option<usize>,
+    row: usize,
+  ) -> Vec<u8> {
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    // query generation overflows with a small probability, in which case
+    // it is retried with fresh query params
+    let (qp, q) = loop {
+      let mut qp = generate_index_query_params(&cp, bp).unwrap();
+      if let Ok(q) = qp.generate_query(row) {
+        break (qp, q);
+      }
+    };
+    let resp = match idx {
+      Some(idx) => cluster.respond(idx, &q).unwrap(),
+      None => cluster.respond_parity(&q).unwrap(),
+    };
+    qp.parse_resp_as_bytes(&bincode::deserialize(&resp).unwrap())
+  }
+
+  #[test]
+  fn missing_shards_are_reconstructed_from_parity() {
+    let elem_size = 2u32.pow(8) as usize;
+    let heights = [64, 64, 48];
+    let eles: Vec<Vec<String>> = heights
+      .iter()
+      .map(|&m| generate_db_eles(m, elem_size / 8))
+      .collect();
+    let shards: Vec<Shard> = eles
+      .iter()
+      .map(|e| {
+        Shard::from_base64_strings(e, 512, e.len(), elem_size, 10).unwrap()
+      })
+      .collect();
+    let parity = build_parity_shard(&shards, &XorParity).unwrap();
+    assert_eq!(parity.height(), 64);
+    let cluster = ShardCluster::new(shards.clone()).with_parity(parity);
+    let parity = cluster.get_parity().unwrap();
+    let info = cluster.server_info().unwrap();
+    assert_eq!(info.parity.unwrap().height, 64);
+
+    // shard 1 is missing: its rows are rebuilt from the parity and the
+    // other shards, which only contribute the rows that they hold
+    for row in [0, 50] {
+      let others: Vec<Vec<u8>> = [0, 2]
+        .iter()
+        .filter(|&&i| row < heights[i])
+        .map(|&i| retrieve(&cluster, &shards[i], Some(i), row))
+        .collect();
+      let others: Vec<&[u8]> = others.iter().map(|r| r.as_slice()).collect();
+      let parity_record = retrieve(&cluster, parity, None, row);
+      let record = XorParity.reconstruct(&parity_record, &others);
+      assert_eq!(base64::encode(record), eles[1][row]);
+    }
+
+    let other =
+      Shard::from_base64_strings(&generate_db_eles(8, 16), 512, 8, 128, 10)
+        .unwrap();
+    assert!(
+      build_parity_shard(&[shards[0].clone(), other], &XorParity).is_err()
+    );
+    assert!(build_parity_shard(&[], &XorParity).is_err());
+  }
+
+  #[test]
+  fn parity_covers_the_records_clients_retrieve() {
+    let (m, elem_size) = (32, 2u32.pow(8) as usize);
+    // every record is stored twice in the deduplicated shard
+    let unique = generate_db_eles(m / 2, elem_size / 8);
+    let eles = [
+      [unique.clone(), unique].concat(),
+      generate_db_eles(m, elem_size / 8),
+      generate_db_eles(m, elem_size / 8),
+    ];
+    let encoding = EncodingConfig::Standard;
+    let column_bits = [vec![10; 25], vec![6]].concat();
+    let shards = vec![
+      Shard::from_encoded_strings_deduplicated(
+        &eles[0], 512, elem_size, 10, encoding,
+      )
+      .unwrap(),
+      Shard::from_encoded_strings_with_column_bits(
+        &eles[1],
+        512,
+        m,
+        elem_size,
+        &column_bits,
+        encoding,
+      )
+      .unwrap(),
+      Shard::from_base64_strings(&eles[2], 512, m, elem_size, 10).unwrap(),
+    ];
+    assert_eq!(shards[0].height(), m / 2);
+    let parity = build_parity_shard(&shards, &XorParity).unwrap();
+    assert_eq!(parity.height(), m);
+    let cluster = ShardCluster::new(shards.clone()).with_parity(parity);
+    let parity = cluster.get_parity().unwrap();
+
+    for (missing, missing_eles) in eles.iter().enumerate() {
+      for row in [0, m / 2 + 3, m - 1] {
+        let others: Vec<Vec<u8>> = (0..shards.len())
+          .filter(|&i| i != missing)
+          .map(|i| retrieve(&cluster, &shards[i], Some(i), row))
+          .collect();
+        let others: Vec<&[u8]> = others.iter().map(|r| r.as_slice()).collect();
+        let parity_record = retrieve(&cluster, parity, None, row);
+        let record = XorParity.reconstruct(&parity_record, &others);
+        assert_eq!(base64::encode(record), missing_eles[row]);
+      }
+    }
+
+    // clients decode compressed or expiring records to other bytes
+    let mut expiring = shards[2].clone();
+    expiring.set_params_record_expiry(true);
+    assert!(
+      build_parity_shard(&[shards[0].clone(), expiring], &XorParity).is_err()
+    );
+  }
+}
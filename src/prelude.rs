@@ -0,0 +1,58 @@
+//! The `prelude` module re-exports the stable types and functions that
+//! most PIR clients and servers need, so that downstream code has a single
+//! import path:
+//!
+//! ```
+//! use keyword_pir_lwe::prelude::*;
+//!
+//! # fn main() -> ResultBoxedError<()> {
+//! let records: Vec<String> =
+//!   (0..64u8).map(|i| base64::encode([i; 32])).collect();
+//! let shard = ShardBuilder::new(512, 256).build(&records)?;
+//! let mut client = Loopback::index_client(shard)?;
+//! assert_eq!(base64::encode(client.retrieve(5)?), records[5]);
+//!
+//! let keys: Vec<String> = (0..64).map(|i| format!("key-{}", i)).collect();
+//! let kv_shard = ShardBuilder::new(512, 256).build_kv(&keys, &records)?;
+//! let bp = kv_shard.get_base_params();
+//! let mut qp = generate_kv_query_params(&CommonParams::from(bp), bp)?;
+//! let q = qp.generate_query_for_key(&KeyBytes::from("key-9"))?;
+//! let resp: Response = bincode::deserialize(&kv_shard.respond(&q)?)?;
+//! let key = HashedKey::from_bytes(b"key-9");
+//! assert_eq!(qp.parse_resp_as_base64(&resp, &key)?, records[9]);
+//!
+//! // query params are single use
+//! let err = qp.generate_query_for_key(&KeyBytes::from("key-9")).unwrap_err();
+//! assert!(err.is::<ErrorQueryParamsReused>());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Lower-level functionality (e.g. `db`, `linalg` or `tuning`) is still
+//! imported from its own module.
+pub use crate::api::{
+  generate_dummy_query, generate_index_query_params,
+  generate_index_query_params_from_hint, generate_index_query_params_from_seed,
+  generate_kv_query_params, generate_kv_query_params_from_hint,
+  generate_kv_query_params_from_seed, generate_sparse_index_query_params,
+  AnyShard, BaseParams, ClientHint, CommonParams, DecodeKey, DecodedRow,
+  EmptyAuxParams, EncodingConfig, HashedKey, IndexQueryParams, KVShard,
+  KeyBytes, KvQueryParams, PirShard, Query, QueryEnvelope, QueryParams,
+  QueryReceipt, QueryTarget, RecordBytes, Response, ResponseEnvelope,
+  ResponseFrame, Shard, SparseQuery,
+};
+pub use crate::builder::{Profile, ShardBuilder};
+pub use crate::client::{KvPirClient, KvValue, PirClient, ReliableClient};
+pub use crate::db::{
+  FilterParams, IndexDatabase, IndexParams, KVDatabase, KVParams,
+};
+pub use crate::errors::{
+  ErrorHintExhausted, ErrorHintMismatch, ErrorLoadFile, ErrorOverflownAdd,
+  ErrorParamsExpired, ErrorParamsMismatch, ErrorQueryParamsReused,
+  ErrorRecordExpired, ErrorRetriesExhausted, ErrorUnexpectedInputSize,
+  ResultBoxedError,
+};
+pub use crate::serving::{ReloadableShard, ServingConfig, ServingShard};
+pub use crate::transport::{
+  HttpTransport, InMemoryTransport, Loopback, PirTransport,
+};
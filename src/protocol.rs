@@ -0,0 +1,81 @@
+//! The `protocol` module holds the values that servers and clients must
+//! agree on for queries to be answered and decoded correctly.
+//!
+//! The constants are part of the wire format: changing any of them
+//! changes the queries that clients generate or the way that responses
+//! are decoded, so they must only change together with the protocol
+//! version (see `serving::SUPPORTED_PROTOCOL_VERSIONS`).
+
+/// The constants of the protocol, which query generation and decoding
+/// (see `utils::lwe::Modulus`) both derive their values from
+pub mod constants {
+  /// The number of bits of the largest supported ciphertext modulus,
+  /// which is the modulus of the wrapping u32 arithmetic
+  pub const MAX_MODULUS_BITS: u32 = 32;
+
+  /// The encoding of the ternary value -1, i.e. -1 modulo 2^32. Ternary
+  /// secrets and errors take values in {0, 1, `TERNARY_MINUS_ONE`}.
+  pub const TERNARY_MINUS_ONE: u32 = u32::MAX;
+
+  /// The size of each of the three intervals of u32 values that are
+  /// mapped to 0, 1 and -1 when sampling ternary values
+  pub const TERNARY_INTERVAL_SIZE: u32 = (u32::MAX - 2) / 3;
+
+  /// The largest u32 value that is accepted when sampling ternary values
+  /// by rejection sampling. Note that it is not `u32::MAX`.
+  pub const TERNARY_REJECTION_SAMPLING_MAX: u32 = TERNARY_INTERVAL_SIZE * 3;
+
+  /// Returns the size of the plaintext space, 2^`plaintext_bits`
+  pub const fn plaintext_size(plaintext_bits: usize) -> u32 {
+    1 << plaintext_bits
+  }
+
+  /// Same as `plaintext_size`, as the u64 plaintext modulus that the
+  /// storage filters of KV DBs reduce values by
+  pub const fn plaintext_modulus(plaintext_bits: usize) -> u64 {
+    1 << plaintext_bits
+  }
+
+  /// Returns the rounding factor q / 2^`plaintext_bits` for the
+  /// ciphertext modulus q = 2^`modulus_bits`, which scales plaintext
+  /// values, and indicates the queried row in queries
+  pub const fn rounding_factor(
+    modulus_bits: u32,
+    plaintext_bits: usize,
+  ) -> u32 {
+    ((1u64 << modulus_bits) >> plaintext_bits) as u32
+  }
+
+  /// Returns half of the rounding factor, i.e. the largest noise that
+  /// nearest rounding decodes correctly
+  pub const fn rounding_floor(modulus_bits: u32, plaintext_bits: usize) -> u32 {
+    rounding_factor(modulus_bits, plaintext_bits) / 2
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::constants::*;
+  use crate::api::Modulus;
+
+  #[test]
+  fn constants_match_moduli() {
+    assert_eq!(plaintext_size(10), 1024);
+    assert_eq!(plaintext_modulus(10), 1024);
+    assert_eq!(rounding_factor(MAX_MODULUS_BITS, 10), 1 << 22);
+    assert_eq!(rounding_floor(MAX_MODULUS_BITS, 10), 1 << 21);
+    for bits in [16, 24, MAX_MODULUS_BITS] {
+      let modulus = Modulus::new(bits).unwrap();
+      for plaintext_bits in [1, 9, 10, 15] {
+        let factor = rounding_factor(bits, plaintext_bits);
+        assert_eq!(modulus.rounding_factor(plaintext_bits), factor);
+        assert_eq!(
+          factor as u64 * plaintext_size(plaintext_bits) as u64,
+          modulus.value()
+        );
+      }
+    }
+    assert_eq!(TERNARY_MINUS_ONE.wrapping_add(1), 0);
+    assert_eq!(TERNARY_REJECTION_SAMPLING_MAX, 3 * TERNARY_INTERVAL_SIZE);
+  }
+}
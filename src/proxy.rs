@@ -0,0 +1,202 @@
+//! The `proxy` module lets a gateway aggregate the queries of many
+//! clients into a single request to a PIR server (see
+//! `PirShard::respond_many`), and split the answer back into the
+//! responses of the individual clients.
+//!
+//! The gateway does not need to be trusted: it only handles
+//! `QueryEnvelope`s, whose queries are LWE samples that look uniformly
+//! random to anyone without the client secret, and the serialized
+//! responses, which it forwards without decoding. The ids of the client
+//! envelopes stay with the gateway, so the server only learns the
+//! position of each query in the batch.
+use serde::{Deserialize, Serialize};
+
+use crate::api::{Query, QueryEnvelope, ResponseEnvelope};
+use crate::errors::{ErrorUnexpectedInputSize, ResultBoxedError};
+
+/// An `AggregatedQuery` carries the queries of several clients on the
+/// wire, from a gateway to the server
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AggregatedQuery {
+  pub queries: Vec<Query>,
+}
+
+/// An `AggregatedResponse` carries the serialized responses to an
+/// `AggregatedQuery` on the wire, in the order of its queries
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AggregatedResponse {
+  pub responses: Vec<Vec<u8>>,
+}
+
+/// A `ProxyAggregator` queues the queries of clients until a batch of
+/// `max_batch` queries is collected (or the gateway decides to flush
+/// early, e.g. after a timeout)
+#[derive(Clone, Debug)]
+pub struct ProxyAggregator {
+  max_batch: usize,
+  query_len: Option<usize>,
+  ids: Vec<Option<u128>>,
+  queries: Vec<Query>,
+}
+impl ProxyAggregator {
+  /// Creates an aggregator that fills batches of `max_batch` queries
+  pub fn new(max_batch: usize) -> Self {
+    Self {
+      max_batch: max_batch.max(1),
+      query_len: None,
+      ids: Vec::new(),
+      queries: Vec::new(),
+    }
+  }
+
+  /// Rejects queries that do not have `query_len` entries (i.e. the
+  /// height of the served DB matrix), so that a single malformed query
+  /// does not fail the batch of every other client
+  pub fn with_query_len(mut self, query_len: usize) -> Self {
+    self.query_len = Some(query_len);
+    self
+  }
+
+  /// Queues the query of `envelope`, returning whether the batch is full
+  pub fn push(&mut self, envelope: QueryEnvelope) -> ResultBoxedError<bool> {
+    let len = envelope.query.as_slice().len();
+    if let Some(query_len) = self.query_len.filter(|&l| l != len) {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "query_len: {}, db height: {}",
+        len, query_len
+      ))));
+    }
+    self.ids.push(envelope.id);
+    self.queries.push(envelope.query);
+    Ok(self.is_full())
+  }
+
+  /// Returns the number of queued queries
+  pub fn len(&self) -> usize {
+    self.queries.len()
+  }
+
+  /// Returns whether no queries are queued
+  pub fn is_empty(&self) -> bool {
+    self.queries.is_empty()
+  }
+
+  /// Returns whether `max_batch` queries are queued
+  pub fn is_full(&self) -> bool {
+    self.len() >= self.max_batch
+  }
+
+  /// Takes the queued queries as an `AggregatedQuery` to send to the
+  /// server, together with the `PendingBatch` for demultiplexing its
+  /// response
+  pub fn take_batch(&mut self) -> (AggregatedQuery, PendingBatch) {
+    let batch = AggregatedQuery {
+      queries: std::mem::take(&mut self.queries),
+    };
+    let pending = PendingBatch {
+      ids: std::mem::take(&mut self.ids),
+    };
+    (batch, pending)
+  }
+
+  /// Sends the queued queries in a single request using `send`, which
+  /// returns the serialized `AggregatedResponse` of the server, and
+  /// returns the serialized `ResponseEnvelope` of every queued query, in
+  /// the order they were pushed
+  pub fn flush<F>(&mut self, send: F) -> ResultBoxedError<Vec<Vec<u8>>>
+  where
+    F: FnOnce(&AggregatedQuery) -> ResultBoxedError<Vec<u8>>,
+  {
+    let (batch, pending) = self.take_batch();
+    pending.demultiplex(&send(&batch)?)
+  }
+}
+
+/// A `PendingBatch` holds the client ids of an `AggregatedQuery` that
+/// was sent to the server
+#[derive(Clone, Debug)]
+pub struct PendingBatch {
+  ids: Vec<Option<u128>>,
+}
+impl PendingBatch {
+  /// Returns the number of queries in the batch
+  pub fn len(&self) -> usize {
+    self.ids.len()
+  }
+
+  /// Returns whether the batch holds no queries
+  pub fn is_empty(&self) -> bool {
+    self.ids.is_empty()
+  }
+
+  /// Splits the serialized `AggregatedResponse` to the batch into the
+  /// serialized `ResponseEnvelope`s of the clients, in the order their
+  /// queries were pushed
+  pub fn demultiplex(&self, resp: &[u8]) -> ResultBoxedError<Vec<Vec<u8>>> {
+    let resp: AggregatedResponse = bincode::deserialize(resp)?;
+    if resp.responses.len() != self.ids.len() {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "{} responses to a batch of {} queries",
+        resp.responses.len(),
+        self.ids.len()
+      ))));
+    }
+    self
+      .ids
+      .iter()
+      .zip(resp.responses)
+      .map(|(&id, response)| {
+        Ok(bincode::serialize(&ResponseEnvelope {
+          id,
+          response: bincode::deserialize(&response)?,
+        })?)
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::*;
+  use crate::test_utils::{index_shard, M};
+
+  #[test]
+  fn aggregated_responses_reach_their_clients() {
+    let (eles, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut aggregator = ProxyAggregator::new(3).with_query_len(M);
+    let mut clients = Vec::new();
+    for i in [3, 17, 3] {
+      let mut qp = generate_index_query_params(&cp, bp).unwrap();
+      let envelope = QueryEnvelope::new(qp.generate_query(i).unwrap());
+      let full = aggregator.push(envelope.clone()).unwrap();
+      clients.push((i, qp, envelope));
+      assert_eq!(full, clients.len() == 3);
+    }
+    assert!(aggregator
+      .push(QueryEnvelope::new(Query::random(8)))
+      .is_err());
+
+    let resps = aggregator.flush(|batch| shard.respond_many(batch)).unwrap();
+    assert!(aggregator.is_empty());
+    assert_eq!(resps.len(), clients.len());
+    for ((i, qp, envelope), resp) in clients.iter().zip(resps) {
+      let resp: ResponseEnvelope = bincode::deserialize(&resp).unwrap();
+      assert!(resp.answers(envelope));
+      assert_eq!(qp.parse_resp_as_base64(&resp.response), eles[*i]);
+    }
+  }
+
+  #[test]
+  fn demultiplexing_checks_the_batch_size() {
+    let mut aggregator = ProxyAggregator::new(2);
+    aggregator
+      .push(QueryEnvelope::new(Query::random(8)))
+      .unwrap();
+    let (_, pending) = aggregator.take_batch();
+    let resp = bincode::serialize(&AggregatedResponse::default()).unwrap();
+    assert!(pending.demultiplex(&resp).is_err());
+  }
+}
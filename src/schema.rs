@@ -0,0 +1,367 @@
+//! The `schema` module maps typed records into DB elements, so that
+//! applications can build shards from (and decode responses into) their
+//! own serde types rather than packing bytes by hand.
+//!
+//! A `RecordSchema` lists the fields of a record in order. Each record is
+//! laid out as the concatenation of its fields: `u32` fields take four
+//! little-endian bytes, fixed-size byte fields take their length, and
+//! `bool` fields take a single byte. The element size of the DB is the
+//! size of this layout, so all records take the same space.
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::api::{EmptyAuxParams, KVShard, QueryParams, Response, Shard};
+use crate::db::{FilterParams, HashedKey, IndexDatabase, KVDatabase};
+use crate::errors::{
+  ErrorInvalidSchema, ErrorSchemaMismatch, ResultBoxedError,
+};
+
+/// The type of a field of a `RecordSchema`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FieldType {
+  U32,
+  /// A byte array of exactly the given length
+  Bytes(usize),
+  Bool,
+}
+
+impl FieldType {
+  /// Returns the number of bytes the field takes in a record
+  pub fn byte_len(&self) -> usize {
+    match self {
+      FieldType::U32 => 4,
+      FieldType::Bytes(len) => *len,
+      FieldType::Bool => 1,
+    }
+  }
+}
+
+/// A named field of a `RecordSchema`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Field {
+  pub name: String,
+  pub ty: FieldType,
+}
+
+/// A `RecordSchema` describes the layout of typed records in a DB. It
+/// can be serialized and published alongside the params, so that clients
+/// can decode responses into the same types.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordSchema {
+  fields: Vec<Field>,
+}
+
+impl RecordSchema {
+  /// Returns an empty schema
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Appends a `u32` field (see `with_field`)
+  pub fn with_u32(self, name: &str) -> ResultBoxedError<Self> {
+    self.with_field(name, FieldType::U32)
+  }
+
+  /// Appends a byte array field of exactly `len` bytes (see
+  /// `with_field`)
+  pub fn with_bytes(self, name: &str, len: usize) -> ResultBoxedError<Self> {
+    self.with_field(name, FieldType::Bytes(len))
+  }
+
+  /// Appends a `bool` field (see `with_field`)
+  pub fn with_bool(self, name: &str) -> ResultBoxedError<Self> {
+    self.with_field(name, FieldType::Bool)
+  }
+
+  /// Appends a field of type `ty`, failing if the schema already has a
+  /// field called `name`
+  pub fn with_field(
+    mut self,
+    name: &str,
+    ty: FieldType,
+  ) -> ResultBoxedError<Self> {
+    if self.fields.iter().any(|f| f.name == name) {
+      return Err(Box::new(ErrorInvalidSchema::new(format!(
+        "duplicate field {}",
+        name
+      ))));
+    }
+    self.fields.push(Field {
+      name: name.to_string(),
+      ty,
+    });
+    Ok(self)
+  }
+
+  /// Returns the fields of the schema, in layout order
+  pub fn get_fields(&self) -> &[Field] {
+    &self.fields
+  }
+
+  /// Returns the number of bytes of an encoded record
+  pub fn record_bytes(&self) -> usize {
+    self.fields.iter().map(|f| f.ty.byte_len()).sum()
+  }
+
+  /// Returns the element size (in bits) of a DB holding records of this
+  /// schema
+  pub fn elem_size(&self) -> usize {
+    self.record_bytes() * 8
+  }
+
+  /// Encodes a record into its fixed-size layout. The record must
+  /// serialize as a struct (or map) with exactly the fields of the
+  /// schema.
+  pub fn encode<T: Serialize>(&self, record: &T) -> ResultBoxedError<Vec<u8>> {
+    let mut obj = match serde_json::to_value(record)? {
+      Value::Object(obj) => obj,
+      _ => return Err(mismatch("record is not a struct".to_string())),
+    };
+    let mut out = Vec::with_capacity(self.record_bytes());
+    for field in &self.fields {
+      let value = obj
+        .remove(&field.name)
+        .ok_or_else(|| mismatch(format!("missing field {}", field.name)))?;
+      encode_field(field, &value, &mut out)?;
+    }
+    if let Some(name) = obj.keys().next() {
+      return Err(mismatch(format!("unknown field {}", name)));
+    }
+    Ok(out)
+  }
+
+  /// Decodes a record from its fixed-size layout. Any bytes after the
+  /// layout (e.g. padding) are ignored.
+  pub fn decode<T: DeserializeOwned>(
+    &self,
+    bytes: &[u8],
+  ) -> ResultBoxedError<T> {
+    if bytes.len() < self.record_bytes() {
+      return Err(mismatch(format!(
+        "expected {} bytes, got {}",
+        self.record_bytes(),
+        bytes.len()
+      )));
+    }
+    let mut obj = Map::new();
+    let mut offset = 0;
+    for field in &self.fields {
+      let len = field.ty.byte_len();
+      let value = decode_field(field, &bytes[offset..offset + len])?;
+      obj.insert(field.name.clone(), value);
+      offset += len;
+    }
+    Ok(serde_json::from_value(Value::Object(obj))?)
+  }
+
+  /// Builds a `Shard` whose rows are the encoded `records`
+  pub fn build_shard<T: Serialize>(
+    &self,
+    records: &[T],
+    lwe_dim: usize,
+    plaintext_bits: usize,
+  ) -> ResultBoxedError<Shard> {
+    let rows = records
+      .iter()
+      .map(|r| Ok(base64::encode(self.encode(r)?)))
+      .collect::<ResultBoxedError<Vec<String>>>()?;
+    Shard::from_base64_strings(
+      &rows,
+      lwe_dim,
+      rows.len(),
+      self.elem_size(),
+      plaintext_bits,
+    )
+  }
+
+  /// Builds a `KVShard` whose values are the encoded `records`
+  pub fn build_kv_shard<T: Serialize>(
+    &self,
+    records: &[(String, T)],
+    lwe_dim: usize,
+    plaintext_bits: usize,
+  ) -> ResultBoxedError<KVShard> {
+    let keys: Vec<String> = records.iter().map(|(k, _)| k.clone()).collect();
+    let values = records
+      .iter()
+      .map(|(_, r)| Ok(base64::encode(self.encode(r)?)))
+      .collect::<ResultBoxedError<Vec<String>>>()?;
+    KVShard::from_base64_strings(
+      &keys,
+      &values,
+      lwe_dim,
+      self.elem_size(),
+      plaintext_bits,
+    )
+  }
+
+  /// Decodes the response to an index query into a record
+  pub fn parse_index_resp<T: DeserializeOwned>(
+    &self,
+    qp: &QueryParams<IndexDatabase, EmptyAuxParams>,
+    resp: &Response,
+  ) -> ResultBoxedError<T> {
+    self.decode(&qp.parse_resp_as_bytes(resp))
+  }
+
+  /// Decodes the response to a KV query for `key` into a record. For keys
+  /// that are not in the DB this usually fails, as the response decodes
+  /// to random bytes, but it may also return a garbage record.
+  pub fn parse_kv_resp<T: DeserializeOwned>(
+    &self,
+    qp: &QueryParams<KVDatabase, FilterParams>,
+    resp: &Response,
+    key: &HashedKey,
+  ) -> ResultBoxedError<T> {
+    self.decode(&qp.parse_resp_as_bytes(resp, key)?)
+  }
+}
+
+fn mismatch(details: String) -> Box<dyn std::error::Error> {
+  Box::new(ErrorSchemaMismatch::new(details))
+}
+
+fn encode_field(
+  field: &Field,
+  value: &Value,
+  out: &mut Vec<u8>,
+) -> ResultBoxedError<()> {
+  let wrong_type =
+    || mismatch(format!("field {} is not {:?}", field.name, field.ty));
+  match field.ty {
+    FieldType::U32 => {
+      let v = value.as_u64().ok_or_else(wrong_type)?;
+      let v = u32::try_from(v).map_err(|_| wrong_type())?;
+      out.extend(v.to_le_bytes());
+    }
+    FieldType::Bytes(len) => {
+      let arr = value.as_array().ok_or_else(wrong_type)?;
+      if arr.len() != len {
+        return Err(mismatch(format!(
+          "field {} has {} bytes, expected {}",
+          field.name,
+          arr.len(),
+          len
+        )));
+      }
+      for b in arr {
+        let b = b.as_u64().ok_or_else(wrong_type)?;
+        out.push(u8::try_from(b).map_err(|_| wrong_type())?);
+      }
+    }
+    FieldType::Bool => {
+      out.push(value.as_bool().ok_or_else(wrong_type)? as u8);
+    }
+  }
+  Ok(())
+}
+
+fn decode_field(field: &Field, bytes: &[u8]) -> ResultBoxedError<Value> {
+  Ok(match field.ty {
+    FieldType::U32 => {
+      Value::from(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+    FieldType::Bytes(_) => Value::from(bytes.to_vec()),
+    FieldType::Bool => match bytes[0] {
+      0 => Value::Bool(false),
+      1 => Value::Bool(true),
+      b => {
+        return Err(mismatch(format!(
+          "field {} holds {}, which is not a bool",
+          field.name, b
+        )))
+      }
+    },
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::*;
+
+  #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+  struct Account {
+    balance: u32,
+    owner: [u8; 8],
+    frozen: bool,
+  }
+
+  fn schema() -> RecordSchema {
+    RecordSchema::new()
+      .with_u32("balance")
+      .and_then(|s| s.with_bytes("owner", 8))
+      .and_then(|s| s.with_bool("frozen"))
+      .unwrap()
+  }
+
+  #[test]
+  fn typed_records_round_trip() {
+    let schema = schema();
+    assert_eq!(schema.elem_size(), 13 * 8);
+    let records: Vec<Account> = (0..64u32)
+      .map(|i| Account {
+        balance: i * 1000 + 7,
+        owner: [i as u8; 8],
+        frozen: i % 3 == 0,
+      })
+      .collect();
+
+    let shard = schema.build_shard(&records, 512, 10).unwrap();
+    let bp = shard.get_base_params();
+    let mut qp =
+      generate_index_query_params(&CommonParams::from(bp), bp).unwrap();
+    let q = qp.generate_query(9).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    let record: Account = schema.parse_index_resp(&qp, &resp).unwrap();
+    assert_eq!(record, records[9]);
+
+    let kvs: Vec<(String, Account)> = records
+      .iter()
+      .enumerate()
+      .map(|(i, r)| (format!("acct-{}", i), r.clone()))
+      .collect();
+    let shard = schema.build_kv_shard(&kvs, 512, 10).unwrap();
+    let bp = shard.get_base_params();
+    let mut qp = generate_kv_query_params(&CommonParams::from(bp), bp).unwrap();
+    let key = HashedKey::from_bytes(b"acct-21");
+    let q = qp.generate_query(&key).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+    let record: Account = schema.parse_kv_resp(&qp, &resp, &key).unwrap();
+    assert_eq!(record, records[21]);
+  }
+
+  #[test]
+  fn schema_rejects_mismatched_records() {
+    #[derive(Serialize)]
+    struct Wrong {
+      balance: i64,
+      owner: [u8; 8],
+      frozen: bool,
+    }
+    let schema = schema();
+    let wrong = Wrong {
+      balance: -1,
+      owner: [0; 8],
+      frozen: false,
+    };
+    assert!(schema.encode(&wrong).is_err());
+    assert!(schema.encode(&(1u32, 2u32)).is_err());
+    let extra = schema.clone().with_u32("extra").unwrap();
+    let account = Account {
+      balance: 1,
+      owner: [2; 8],
+      frozen: true,
+    };
+    assert!(extra.encode(&account).is_err());
+    let err = schema.clone().with_bool("owner").unwrap_err();
+    assert!(err.is::<ErrorInvalidSchema>());
+    assert!(schema.decode::<Account>(&[0u8; 12]).is_err());
+    let mut bytes = schema.encode(&account).unwrap();
+    bytes[12] = 2;
+    assert!(schema.decode::<Account>(&bytes).is_err());
+  }
+}
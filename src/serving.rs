@@ -0,0 +1,1541 @@
+//! The `serving` module provides helpers for running a `Shard` inside a
+//! long-lived server process.
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rand_core::{OsRng, RngCore};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
+use static_assertions::assert_impl_all;
+
+use crate::api::{
+  EncodingConfig, KVShard, Modulus, PirShard, Query, Response, Shard,
+};
+use crate::builder::{noise_bound, rounding_threshold};
+use crate::db::KVDatabase;
+use crate::errors::{
+  ErrorQuotaExceeded, ErrorUnexpectedInputSize, ErrorUnknownNamespace,
+  ResultBoxedError,
+};
+use crate::utils::lwe::get_plaintext_size;
+
+/// The versions of the query and response wire format that this crate
+/// can serve, in increasing order
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[u32] = &[1];
+
+/// A `ReloadableShard` allows replacing the shard that is used for
+/// answering queries (e.g. when a new DB snapshot is published) without
+/// interrupting in-flight queries. Each new shard is prefaulted before
+/// it is swapped in, so that queries never hit a cold snapshot.
+///
+/// Every reload starts a new epoch, so that clients can tell (see
+/// `ServerInfo`) whether the hints they downloaded are still current.
+pub struct ReloadableShard<S = Shard> {
+  current: RwLock<Arc<S>>,
+  epoch: AtomicU64,
+}
+
+impl<S: PirShard> ReloadableShard<S> {
+  /// Prefaults `shard` and uses it for answering queries
+  pub fn new(shard: S) -> Self {
+    shard.prefault();
+    Self {
+      current: RwLock::new(Arc::new(shard)),
+      epoch: AtomicU64::new(0),
+    }
+  }
+
+  /// Returns the current epoch, i.e. the number of reloads so far
+  pub fn epoch(&self) -> u64 {
+    self.epoch.load(Ordering::SeqCst)
+  }
+
+  /// Describes the current shard and epoch to clients
+  pub fn server_info(&self) -> ResultBoxedError<ServerInfo> {
+    let current = self.current.read().unwrap();
+    let info = ShardInfo::new(current.as_ref(), self.epoch())?;
+    Ok(ServerInfo::new(vec![info], None))
+  }
+
+  /// Returns the shard that is currently used for answering queries
+  pub fn current(&self) -> Arc<S> {
+    self.current.read().unwrap().clone()
+  }
+
+  /// Responds to `q` using the current shard
+  pub fn respond(&self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    self.current().respond(q)
+  }
+
+  /// Prefaults `shard` and then swaps it in. Queries that are already
+  /// being answered complete using the previous shard.
+  pub fn reload(&self, shard: S) {
+    shard.prefault();
+    let mut current = self.current.write().unwrap();
+    *current = Arc::new(shard);
+    self.epoch.fetch_add(1, Ordering::SeqCst);
+  }
+}
+
+impl ReloadableShard<Shard> {
+  /// Loads a persisted `Shard` (see `Shard::load`) and swaps it in
+  pub fn reload_from_files(
+    &self,
+    db_path: &str,
+    params_path: &str,
+  ) -> ResultBoxedError<()> {
+    self.reload(Shard::load(db_path, params_path)?);
+    Ok(())
+  }
+}
+
+/// A `RotationSchedule` divides time into consecutive epochs of a fixed
+/// period (e.g. a day), starting at an origin. The DB served by a
+/// `RotatingShard` is rebuilt for every epoch, and its params expire at
+/// the end of the epoch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RotationSchedule {
+  period_secs: u64,
+  origin_secs: u64,
+}
+
+impl RotationSchedule {
+  /// Creates a schedule with epochs of `period_secs` seconds, starting at
+  /// the Unix epoch. The period must be positive.
+  pub fn new(period_secs: u64) -> ResultBoxedError<Self> {
+    if period_secs == 0 {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(
+        "rotation period must be positive".to_string(),
+      )));
+    }
+    Ok(Self {
+      period_secs,
+      origin_secs: 0,
+    })
+  }
+
+  /// Creates a schedule that rotates daily at midnight UTC
+  pub fn daily() -> Self {
+    Self {
+      period_secs: 24 * 60 * 60,
+      origin_secs: 0,
+    }
+  }
+
+  /// Starts the first epoch at `origin_secs` seconds since the Unix epoch
+  /// instead, e.g. to rotate daily at a time other than midnight UTC
+  pub fn with_origin(mut self, origin_secs: u64) -> Self {
+    self.origin_secs = origin_secs;
+    self
+  }
+
+  /// Returns the epoch that `now` falls into. Times before the origin
+  /// fall into epoch zero.
+  pub fn epoch_at(&self, now: SystemTime) -> u64 {
+    let secs = now
+      .duration_since(UNIX_EPOCH)
+      .map_or(0, |since| since.as_secs());
+    secs.saturating_sub(self.origin_secs) / self.period_secs
+  }
+
+  /// Returns the start of `epoch`, in seconds since the Unix epoch.
+  /// Epochs that start past `u64::MAX` seconds start at `u64::MAX`, i.e.
+  /// never.
+  pub fn epoch_start(&self, epoch: u64) -> u64 {
+    epoch
+      .checked_mul(self.period_secs)
+      .and_then(|offset| offset.checked_add(self.origin_secs))
+      .unwrap_or(u64::MAX)
+  }
+
+  /// Returns the time left until the epoch after the one that `now` falls
+  /// into starts, which is `Duration::MAX` if it starts past the range of
+  /// `SystemTime`
+  pub fn until_next(&self, now: SystemTime) -> Duration {
+    let next = self.epoch_start(self.epoch_at(now).saturating_add(1));
+    match UNIX_EPOCH.checked_add(Duration::from_secs(next)) {
+      Some(next) => next.duration_since(now).unwrap_or_default(),
+      None => Duration::MAX,
+    }
+  }
+}
+
+/// A `RotatingShard` serves a DB that is rebuilt for every epoch of a
+/// `RotationSchedule`, e.g. with a fresh public seed every day. The base
+/// params of the shard of each epoch expire at the end of the epoch, so
+/// that clients (see `client::PirClient`) download new params before
+/// querying the next shard.
+///
+/// Shards are swapped in atomically (see `ReloadableShard`), so queries
+/// that are being answered during a rotation complete using the previous
+/// shard. Since clients cannot query with expired params, the shard of
+/// the next epoch should be built ahead of time (see `prepare_next`), so
+/// that it is served as soon as the epoch starts. Clients that query
+/// with the params of the previous epoch after a rotation (e.g. due to
+/// clock skew) obtain garbage, which clients should guard against with a
+/// refresh margin. KV shards that are built
+/// with `KVShard::from_encoded_strings_for_epoch` also re-seed their
+/// filters, and advertise the filter epoch in their `ShardInfo`.
+pub struct RotatingShard<S = Shard> {
+  shard: ReloadableShard<S>,
+  schedule: RotationSchedule,
+  // the epoch of the current shard, locked while a rotation is running
+  epoch: Mutex<u64>,
+  // a shard that was built ahead of time, and its epoch
+  next: Mutex<Option<(u64, S)>>,
+}
+
+impl<S: PirShard> RotatingShard<S> {
+  /// Builds the shard of the epoch that `now` falls into, using `build`
+  /// (which is passed the epoch), and serves it
+  pub fn new<F>(
+    schedule: RotationSchedule,
+    now: SystemTime,
+    build: F,
+  ) -> ResultBoxedError<Self>
+  where
+    F: FnOnce(u64) -> ResultBoxedError<S>,
+  {
+    let epoch = schedule.epoch_at(now);
+    let shard = Self::build_epoch(&schedule, epoch, build)?;
+    Ok(Self {
+      shard: ReloadableShard::new(shard),
+      schedule,
+      epoch: Mutex::new(epoch),
+      next: Mutex::new(None),
+    })
+  }
+
+  fn build_epoch<F>(
+    schedule: &RotationSchedule,
+    epoch: u64,
+    build: F,
+  ) -> ResultBoxedError<S>
+  where
+    F: FnOnce(u64) -> ResultBoxedError<S>,
+  {
+    let mut shard = build(epoch)?;
+    shard.set_params_expires_at(Some(schedule.epoch_start(epoch + 1)));
+    Ok(shard)
+  }
+
+  /// Returns the schedule of the rotations
+  pub fn get_schedule(&self) -> &RotationSchedule {
+    &self.schedule
+  }
+
+  /// Returns the epoch of the shard that is currently served
+  pub fn epoch(&self) -> u64 {
+    *self.epoch.lock().unwrap()
+  }
+
+  /// Returns the shard that is currently used for answering queries
+  pub fn current(&self) -> Arc<S> {
+    self.shard.current()
+  }
+
+  /// Responds to `q` using the current shard
+  pub fn respond(&self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    self.shard.respond(q)
+  }
+
+  /// Returns the base params of the current shard, serialized as JSON
+  pub fn params_json(&self) -> ResultBoxedError<Vec<u8>> {
+    self.current().params_json()
+  }
+
+  /// Builds the shard of the epoch after the current one using `build`,
+  /// to be swapped in by `rotate_if_due` once that epoch starts
+  pub fn prepare_next<F>(&self, build: F) -> ResultBoxedError<()>
+  where
+    F: FnOnce(u64) -> ResultBoxedError<S>,
+  {
+    let epoch = self.epoch() + 1;
+    let shard = Self::build_epoch(&self.schedule, epoch, build)?;
+    *self.next.lock().unwrap() = Some((epoch, shard));
+    Ok(())
+  }
+
+  /// If `now` falls into a later epoch than the current shard, swaps in
+  /// the shard of that epoch: the one built by `prepare_next`, if it is
+  /// for that epoch, or else one built using `build`. Returns whether a
+  /// rotation happened. Queries keep being answered by the current shard
+  /// while the next one is built.
+  pub fn rotate_if_due<F>(
+    &self,
+    now: SystemTime,
+    build: F,
+  ) -> ResultBoxedError<bool>
+  where
+    F: FnOnce(u64) -> ResultBoxedError<S>,
+  {
+    let mut current = self.epoch.lock().unwrap();
+    let epoch = self.schedule.epoch_at(now);
+    if epoch <= *current {
+      return Ok(false);
+    }
+    let shard = match self.next.lock().unwrap().take() {
+      Some((next_epoch, shard)) if next_epoch == epoch => shard,
+      _ => Self::build_epoch(&self.schedule, epoch, build)?,
+    };
+    self.shard.reload(shard);
+    *current = epoch;
+    Ok(true)
+  }
+}
+
+/// A `ServingConfig` bounds the CPU resources used for answering queries
+/// in parallel (see `ServingShard`).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServingConfig {
+  /// The number of worker threads. Defaults to the number of available
+  /// cores (on `numa_node`, if set).
+  pub threads: Option<usize>,
+  /// Whether each worker thread is pinned to a single core (assigned
+  /// round-robin)
+  pub pin_cores: bool,
+  /// Restricts the worker threads to the cores of this NUMA node, so that
+  /// a DB allocated on the node is not read across the interconnect.
+  /// Threads are pinned round-robin to the cores of the node, as if
+  /// `pin_cores` was set. Only supported on Linux.
+  pub numa_node: Option<usize>,
+  /// Floods every response slot with extra noise drawn uniformly from
+  /// [-2^b, 2^b], for `b` bits, e.g. to hedge against leakage of the DB
+  /// through the LWE noise of responses. `ServingShard::new` rejects
+  /// values that would break decoding (see `check_noise_flooding`).
+  #[serde(default)]
+  pub noise_flooding_bits: Option<u32>,
+}
+
+impl ServingConfig {
+  /// Checks that flooding the responses of `shard` with the configured
+  /// noise keeps decoding correct, i.e. that the flooding noise and a
+  /// bound on the LWE noise (see `builder::ShardBuilder`) together stay
+  /// below the rounding threshold
+  pub fn check_noise_flooding<S: PirShard>(
+    &self,
+    shard: &S,
+  ) -> ResultBoxedError<()> {
+    let bits = match self.noise_flooding_bits {
+      Some(bits) => bits,
+      None => return Ok(()),
+    };
+    let plaintext_bits = shard.plaintext_bits();
+    let max_entry = get_plaintext_size(plaintext_bits) - 1;
+    let noise = noise_bound(max_entry, shard.height());
+    let threshold = rounding_threshold(plaintext_bits, shard.modulus());
+    if bits >= shard.modulus().bits()
+      || noise + 2f64.powi(bits as i32) >= threshold
+    {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "flooding noise of {} bits exceeds the noise budget of {:.0}",
+        bits,
+        threshold - noise
+      ))));
+    }
+    Ok(())
+  }
+
+  /// Returns the ids of the cores that worker threads may run on
+  pub fn cores(&self) -> ResultBoxedError<Vec<usize>> {
+    match self.numa_node {
+      Some(node) => {
+        let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+        let cpulist = fs::read_to_string(&path).map_err(|e| {
+          format!("Cannot read cores of NUMA node {}: {}", node, e)
+        })?;
+        parse_cpulist(&cpulist)
+      }
+      None => Ok(
+        core_affinity::get_core_ids()
+          .ok_or("Cannot determine the available cores")?
+          .iter()
+          .map(|c| c.id)
+          .collect(),
+      ),
+    }
+  }
+
+  /// Builds the thread pool described by the config. Pinning is best
+  /// effort: worker threads that cannot be pinned run unpinned.
+  pub fn build_pool(&self) -> ResultBoxedError<ThreadPool> {
+    let cores = self.cores()?;
+    if cores.is_empty() {
+      return Err("No cores available for serving".into());
+    }
+    let threads = self.threads.unwrap_or(cores.len());
+    let mut builder = ThreadPoolBuilder::new()
+      .num_threads(threads)
+      .thread_name(|i| format!("pir-respond-{}", i));
+    if self.pin_cores || self.numa_node.is_some() {
+      builder = builder.start_handler(move |i| {
+        let core = core_affinity::CoreId {
+          id: cores[i % cores.len()],
+        };
+        core_affinity::set_for_current(core);
+      });
+    }
+    Ok(builder.build()?)
+  }
+}
+
+/// A `ServingShard` answers queries to a shard in parallel, on a thread
+/// pool built from a `ServingConfig`
+pub struct ServingShard<S = Shard> {
+  shard: S,
+  pool: ThreadPool,
+  noise_flooding_bits: Option<u32>,
+}
+
+impl<S: PirShard + Sync> ServingShard<S> {
+  /// Builds the thread pool described by `config` for serving `shard`,
+  /// checking the noise flooding of the config against the shard
+  pub fn new(shard: S, config: &ServingConfig) -> ResultBoxedError<Self> {
+    config.check_noise_flooding(&shard)?;
+    Ok(Self {
+      shard,
+      pool: config.build_pool()?,
+      noise_flooding_bits: config.noise_flooding_bits,
+    })
+  }
+
+  /// Returns the served shard
+  pub fn get_shard(&self) -> &S {
+    &self.shard
+  }
+
+  /// Describes the served shard to clients, advertising the noise
+  /// flooding of responses
+  pub fn server_info(&self) -> ResultBoxedError<ServerInfo> {
+    let mut info = ServerInfo::new(vec![ShardInfo::new(&self.shard, 0)?], None);
+    info.noise_flooding_bits = self.noise_flooding_bits;
+    Ok(info)
+  }
+
+  /// Returns the number of worker threads
+  pub fn threads(&self) -> usize {
+    self.pool.current_num_threads()
+  }
+
+  /// Responds to `q`, computing the response columns on the pool, and
+  /// flooding the response with noise if configured
+  pub fn respond(&self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    // boxed errors are not `Send`, so they cannot leave the pool as-is
+    let resp = self
+      .pool
+      .install(|| self.shard.respond_par(q).map_err(|e| e.to_string()))?;
+    match self.noise_flooding_bits {
+      Some(bits) => flood_response(&resp, bits, self.shard.modulus()),
+      None => Ok(resp),
+    }
+  }
+
+  /// Warms up the server before it receives traffic: touches every page
+  /// of the DB (see `PirShard::prefault`), and answers `n` synthetic
+  /// queries to warm the CPU caches and the thread pool. Returns the
+  /// latencies of the synthetic queries, so that health checks can
+  /// assert that the server is ready.
+  pub fn prime(&self, n: usize) -> ResultBoxedError<PrimeReport> {
+    if n == 0 {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(
+        "priming requires at least one query".to_string(),
+      )));
+    }
+    self.shard.prefault();
+    let mut latencies = Vec::with_capacity(n);
+    for _ in 0..n {
+      let q = Query::random(self.shard.height());
+      let start = Instant::now();
+      self.respond(&q)?;
+      latencies.push(start.elapsed());
+    }
+    Ok(PrimeReport::from_latencies(latencies))
+  }
+}
+
+/// A `PrimeReport` summarizes the respond latencies of the synthetic
+/// queries run by `ServingShard::prime`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrimeReport {
+  /// The number of synthetic queries
+  pub queries: usize,
+  /// The median latency
+  pub p50: Duration,
+  /// The 99th percentile latency
+  pub p99: Duration,
+  /// The largest latency
+  pub max: Duration,
+}
+impl PrimeReport {
+  fn from_latencies(mut latencies: Vec<Duration>) -> Self {
+    latencies.sort();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    Self {
+      queries: latencies.len(),
+      p50: percentile(50),
+      p99: percentile(99),
+      max: latencies[latencies.len() - 1],
+    }
+  }
+
+  /// Returns whether the p99 latency is within `bound`
+  pub fn is_ready(&self, bound: Duration) -> bool {
+    self.p99 <= bound
+  }
+}
+
+/// Adds noise drawn uniformly from [-2^bits, 2^bits] to every slot of the
+/// serialized response `resp`
+fn flood_response(
+  resp: &[u8],
+  bits: u32,
+  modulus: Modulus,
+) -> ResultBoxedError<Vec<u8>> {
+  let resp: Response = bincode::deserialize(resp)?;
+  let bound = 1u64 << bits;
+  let values = resp
+    .as_slice()
+    .iter()
+    .map(|&v| {
+      // the modulo bias is negligible for bounds far below 2^64
+      let noise = (OsRng.next_u64() % (2 * bound + 1)) as u32;
+      modulus.reduce(v.wrapping_add(noise).wrapping_sub(bound as u32))
+    })
+    .collect();
+  Ok(bincode::serialize(&Response::from_values(values))?)
+}
+
+/// Parses a Linux cpulist (e.g. `0-3,8,10-11`) into core ids
+fn parse_cpulist(cpulist: &str) -> ResultBoxedError<Vec<usize>> {
+  let mut cores = Vec::new();
+  for range in cpulist.trim().split(',').filter(|r| !r.is_empty()) {
+    match range.split_once('-') {
+      Some((start, end)) => {
+        cores.extend(start.parse::<usize>()?..=end.parse::<usize>()?)
+      }
+      None => cores.push(range.parse()?),
+    }
+  }
+  Ok(cores)
+}
+
+/// `ClusterPadding` describes the uniform query and response lengths that
+/// are used by a padded `ShardCluster`. It is public, and clients pad
+/// their queries to `query_len` using `Query::pad_to`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClusterPadding {
+  pub query_len: usize,
+  pub response_len: usize,
+}
+
+/// A `ShardCluster` serves a DB that is split across multiple shards,
+/// with each query targeting a single shard.
+///
+/// Since the length of a query equals the number of rows of the targeted
+/// shard (and the length of a response its row width), shards of
+/// different sizes produce differently shaped traffic. With padding
+/// enabled, the cluster only accepts queries padded to the maximum number
+/// of rows across shards, and pads all responses with random values to
+/// the maximum row width, so that a network observer cannot tell which
+/// shard a client targets.
+///
+/// A cluster of index shards can also serve a parity shard, from which
+/// clients reconstruct the records of a missing shard (see `parity`).
+pub struct ShardCluster<S = Shard> {
+  shards: Vec<S>,
+  padded: bool,
+  parity: Option<S>,
+}
+
+impl<S: PirShard> ShardCluster<S> {
+  /// Creates an unpadded cluster from `shards`
+  pub fn new(shards: Vec<S>) -> Self {
+    Self {
+      shards,
+      padded: false,
+      parity: None,
+    }
+  }
+
+  /// Serves `parity` as the parity shard of the cluster (see
+  /// `parity::build_parity_shard`)
+  pub fn with_parity(mut self, parity: S) -> Self {
+    self.parity = Some(parity);
+    self
+  }
+
+  /// Returns the parity shard, if any
+  pub fn get_parity(&self) -> Option<&S> {
+    self.parity.as_ref()
+  }
+
+  /// Enables or disables padding of queries and responses
+  pub fn with_padding(mut self, padded: bool) -> Self {
+    self.padded = padded;
+    self
+  }
+
+  /// Returns the number of shards in the cluster
+  pub fn len(&self) -> usize {
+    self.shards.len()
+  }
+
+  /// Returns whether the cluster contains no shards
+  pub fn is_empty(&self) -> bool {
+    self.shards.is_empty()
+  }
+
+  /// Returns the shard at `shard_idx`
+  pub fn get_shard(&self, shard_idx: usize) -> Option<&S> {
+    self.shards.get(shard_idx)
+  }
+
+  /// Returns the padded query and response lengths, if padding is enabled
+  pub fn padding(&self) -> Option<ClusterPadding> {
+    if !self.padded {
+      return None;
+    }
+    let all = || self.shards.iter().chain(self.parity.as_ref());
+    Some(ClusterPadding {
+      query_len: all().map(|s| s.height()).max().unwrap_or(0),
+      response_len: all().map(|s| s.width()).max().unwrap_or(0),
+    })
+  }
+
+  /// Describes the shards of the cluster (and its padding) to clients
+  pub fn server_info(&self) -> ResultBoxedError<ServerInfo> {
+    let shards = self
+      .shards
+      .iter()
+      .map(|s| ShardInfo::new(s, 0))
+      .collect::<ResultBoxedError<Vec<ShardInfo>>>()?;
+    let mut info = ServerInfo::new(shards, self.padding());
+    info.parity = match &self.parity {
+      Some(parity) => Some(ShardInfo::new(parity, 0)?),
+      None => None,
+    };
+    Ok(info)
+  }
+
+  /// Responds to `q` using the shard at `shard_idx`. If padding is
+  /// enabled, `q` must be padded to `ClusterPadding::query_len`.
+  pub fn respond(
+    &self,
+    shard_idx: usize,
+    q: &Query,
+  ) -> ResultBoxedError<Vec<u8>> {
+    let shard = self.shards.get(shard_idx).ok_or_else(|| {
+      format!(
+        "Shard index {} out of range for cluster of {} shards",
+        shard_idx,
+        self.shards.len()
+      )
+    })?;
+    self.respond_on(shard, q)
+  }
+
+  /// Responds to `q` using the parity shard (see `respond`)
+  pub fn respond_parity(&self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    match &self.parity {
+      Some(parity) => self.respond_on(parity, q),
+      None => Err("Cluster has no parity shard".into()),
+    }
+  }
+
+  fn respond_on(&self, shard: &S, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    let padding = match self.padding() {
+      Some(padding) => padding,
+      None => return shard.respond(q),
+    };
+    if q.as_slice().len() != padding.query_len {
+      return Err(
+        format!(
+          "Query length {} does not match padded length {}",
+          q.as_slice().len(),
+          padding.query_len
+        )
+        .into(),
+      );
+    }
+    let q = q.truncated(shard.height())?;
+    let mut resp: Response = bincode::deserialize(&shard.respond(&q)?)?;
+    resp.pad_to(padding.response_len);
+    Ok(bincode::serialize(&resp)?)
+  }
+}
+
+impl ShardCluster<KVShard> {
+  /// Returns the smallest number of shards that `num_keys` KV pairs can
+  /// be split across evenly, such that the estimated matrix height of
+  /// every shard (see `KVDatabase::estimate_matrix_height`) is at most
+  /// `max_matrix_height`, e.g. to bound the query size and the memory of
+  /// each shard
+  pub fn plan_partitions(
+    num_keys: usize,
+    max_matrix_height: usize,
+  ) -> ResultBoxedError<usize> {
+    if KVDatabase::estimate_matrix_height(1) > max_matrix_height {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "no partition fits a matrix height of {}",
+        max_matrix_height
+      ))));
+    }
+    // the matrix height is never below the number of keys
+    let mut partitions = (num_keys / max_matrix_height).max(1);
+    while KVDatabase::estimate_matrix_height(num_keys.div_ceil(partitions))
+      > max_matrix_height
+    {
+      partitions += 1;
+    }
+    Ok(partitions)
+  }
+}
+
+/// A `ShardInfo` describes a single served shard
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardInfo {
+  /// The SHA-256 digest of the base params (see `PirShard::params_digest`)
+  pub params_digest: [u8; 32],
+  /// The number of rows of the DB matrix, i.e. the length of queries
+  pub height: usize,
+  /// The row width of the DB matrix, i.e. the length of responses
+  pub width: usize,
+  /// The epoch of the shard, which is bumped whenever it is reloaded
+  pub epoch: u64,
+  /// The epoch of the filter seed of KV shards (see `db::epoch_seed`)
+  #[serde(default)]
+  pub filter_epoch: Option<u64>,
+}
+
+impl ShardInfo {
+  /// Describes `shard`, which is served in `epoch`
+  pub fn new<S: PirShard + ?Sized>(
+    shard: &S,
+    epoch: u64,
+  ) -> ResultBoxedError<Self> {
+    Ok(Self {
+      params_digest: shard.params_digest()?,
+      height: shard.height(),
+      width: shard.width(),
+      epoch,
+      filter_epoch: shard.filter_epoch(),
+    })
+  }
+}
+
+/// A `ServerInfo` advertises the capabilities of a server, so that
+/// clients can negotiate the protocol version and encoding, and check
+/// that their hints are current, before downloading any params. It is
+/// published as JSON (see `to_json`).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+  /// The supported protocol versions, in increasing order
+  pub protocol_versions: Vec<u32>,
+  /// The served shards, by shard index
+  pub shards: Vec<ShardInfo>,
+  /// The size (in bytes) of the largest serialized query that is
+  /// accepted
+  pub max_query_bytes: usize,
+  /// The encodings that DB elements and records can be requested in
+  pub encodings: Vec<EncodingConfig>,
+  /// The uniform query and response lengths, if the shards are padded
+  pub padding: Option<ClusterPadding>,
+  /// The parity shard of a `ShardCluster`, if any
+  #[serde(default)]
+  pub parity: Option<ShardInfo>,
+  /// The bits of noise that responses are flooded with, if any (see
+  /// `ServingConfig::noise_flooding_bits`)
+  #[serde(default)]
+  pub noise_flooding_bits: Option<u32>,
+}
+
+impl ServerInfo {
+  /// Describes a server for `shards`, supporting all protocol versions
+  /// and encodings of this crate
+  pub fn new(shards: Vec<ShardInfo>, padding: Option<ClusterPadding>) -> Self {
+    let max_height = match padding {
+      Some(padding) => padding.query_len,
+      None => shards.iter().map(|s| s.height).max().unwrap_or(0),
+    };
+    Self {
+      protocol_versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+      shards,
+      // a bincode-serialized query is a u64 length followed by the
+      // u32 entries
+      max_query_bytes: 8 + 4 * max_height,
+      encodings: EncodingConfig::ALL.to_vec(),
+      padding,
+      parity: None,
+      noise_flooding_bits: None,
+    }
+  }
+
+  /// Returns the number of served shards
+  pub fn shard_count(&self) -> usize {
+    self.shards.len()
+  }
+
+  /// Returns the epoch of each shard, by shard index
+  pub fn epochs(&self) -> Vec<u64> {
+    self.shards.iter().map(|s| s.epoch).collect()
+  }
+
+  /// Returns the highest protocol version that is supported by both the
+  /// server and the client, if any
+  pub fn negotiate_version(&self, client_versions: &[u32]) -> Option<u32> {
+    self
+      .protocol_versions
+      .iter()
+      .rev()
+      .find(|v| client_versions.contains(v))
+      .copied()
+  }
+
+  /// Returns whether the server supports `encoding`
+  pub fn supports_encoding(&self, encoding: EncodingConfig) -> bool {
+    self.encodings.contains(&encoding)
+  }
+
+  /// Serializes the info as JSON
+  pub fn to_json(&self) -> ResultBoxedError<String> {
+    Ok(serde_json::to_string(self)?)
+  }
+
+  /// Parses info that was serialized using `to_json`
+  pub fn from_json(json: &str) -> ResultBoxedError<Self> {
+    Ok(serde_json::from_str(json)?)
+  }
+}
+
+/// `NamespaceMetrics` count the traffic of a single namespace of a
+/// `ShardRegistry`
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct NamespaceMetrics {
+  /// The number of queries answered successfully
+  pub queries_answered: u64,
+  /// The number of queries that could not be answered (e.g. malformed
+  /// queries)
+  pub queries_failed: u64,
+  /// The number of response bytes sent
+  pub response_bytes: u64,
+}
+
+/// The atomic counters behind `NamespaceMetrics`
+#[derive(Default)]
+struct NamespaceCounters {
+  queries_answered: AtomicU64,
+  queries_failed: AtomicU64,
+  response_bytes: AtomicU64,
+}
+
+/// A single namespace of a `ShardRegistry`
+struct Namespace<S> {
+  shard: ReloadableShard<S>,
+  counters: NamespaceCounters,
+}
+
+/// A `ShardRegistry` hosts multiple independent DBs in one server
+/// process, each under its own namespace (e.g. the name of the DB).
+/// Every namespace is a `ReloadableShard` with its own epoch, and its own
+/// metrics, so reloading or querying one DB does not affect the others.
+///
+/// Requests are routed by path (see `handle`): the params, server info
+/// and query endpoints of namespace `ns` are `/ns/params`, `/ns/info` and
+/// `/ns/query`, which clients reach with `HttpTransport::with_paths`.
+pub struct ShardRegistry<S = Shard> {
+  namespaces: RwLock<BTreeMap<String, Arc<Namespace<S>>>>,
+}
+
+impl<S: PirShard> Default for ShardRegistry<S> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<S: PirShard> ShardRegistry<S> {
+  /// Creates a registry without any namespaces
+  pub fn new() -> Self {
+    Self {
+      namespaces: RwLock::new(BTreeMap::new()),
+    }
+  }
+
+  /// Serves `shard` under `namespace`, which must be a non-empty string
+  /// of ASCII letters, digits, `-` and `_`, that is not registered yet
+  pub fn register(&self, namespace: &str, shard: S) -> ResultBoxedError<()> {
+    let valid = !namespace.is_empty()
+      && namespace
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+      return Err(
+        format!("Invalid namespace: {:?}", namespace.to_string()).into(),
+      );
+    }
+    let mut namespaces = self.namespaces.write().unwrap();
+    if namespaces.contains_key(namespace) {
+      return Err(
+        format!("Namespace already registered: {}", namespace).into(),
+      );
+    }
+    let ns = Namespace {
+      shard: ReloadableShard::new(shard),
+      counters: NamespaceCounters::default(),
+    };
+    namespaces.insert(namespace.to_string(), Arc::new(ns));
+    Ok(())
+  }
+
+  /// Stops serving `namespace`, returning whether it was registered.
+  /// Queries that are already being answered complete.
+  pub fn remove(&self, namespace: &str) -> bool {
+    self.namespaces.write().unwrap().remove(namespace).is_some()
+  }
+
+  /// Returns the registered namespaces, in order
+  pub fn namespaces(&self) -> Vec<String> {
+    self.namespaces.read().unwrap().keys().cloned().collect()
+  }
+
+  /// Returns the namespace, or an `ErrorUnknownNamespace`
+  fn get(&self, namespace: &str) -> ResultBoxedError<Arc<Namespace<S>>> {
+    self
+      .namespaces
+      .read()
+      .unwrap()
+      .get(namespace)
+      .cloned()
+      .ok_or_else(|| {
+        ErrorUnknownNamespace {
+          namespace: namespace.to_string(),
+        }
+        .into()
+      })
+  }
+
+  /// Returns the shard currently served under `namespace`
+  pub fn current(&self, namespace: &str) -> ResultBoxedError<Arc<S>> {
+    Ok(self.get(namespace)?.shard.current())
+  }
+
+  /// Returns the epoch of `namespace` (see `ReloadableShard::epoch`)
+  pub fn epoch(&self, namespace: &str) -> ResultBoxedError<u64> {
+    Ok(self.get(namespace)?.shard.epoch())
+  }
+
+  /// Swaps in `shard` for `namespace`, starting a new epoch of the
+  /// namespace only
+  pub fn reload(&self, namespace: &str, shard: S) -> ResultBoxedError<()> {
+    self.get(namespace)?.shard.reload(shard);
+    Ok(())
+  }
+
+  /// Describes the shard and epoch of `namespace` to clients
+  pub fn server_info(&self, namespace: &str) -> ResultBoxedError<ServerInfo> {
+    self.get(namespace)?.shard.server_info()
+  }
+
+  /// Returns the serialized base params of `namespace`
+  pub fn params_json(&self, namespace: &str) -> ResultBoxedError<Vec<u8>> {
+    self.current(namespace)?.params_json()
+  }
+
+  /// Responds to `q` using the shard of `namespace`
+  pub fn respond(
+    &self,
+    namespace: &str,
+    q: &Query,
+  ) -> ResultBoxedError<Vec<u8>> {
+    let ns = self.get(namespace)?;
+    let resp = ns.shard.respond(q);
+    match &resp {
+      Ok(bytes) => {
+        ns.counters.queries_answered.fetch_add(1, Ordering::Relaxed);
+        let len = bytes.len() as u64;
+        ns.counters.response_bytes.fetch_add(len, Ordering::Relaxed);
+      }
+      Err(_) => {
+        ns.counters.queries_failed.fetch_add(1, Ordering::Relaxed);
+      }
+    }
+    resp
+  }
+
+  /// Returns the metrics of `namespace`
+  pub fn metrics(&self, namespace: &str) -> ResultBoxedError<NamespaceMetrics> {
+    let counters = &self.get(namespace)?.counters;
+    Ok(NamespaceMetrics {
+      queries_answered: counters.queries_answered.load(Ordering::Relaxed),
+      queries_failed: counters.queries_failed.load(Ordering::Relaxed),
+      response_bytes: counters.response_bytes.load(Ordering::Relaxed),
+    })
+  }
+
+  /// Answers a request for `path` (see `ShardRegistry`), returning the
+  /// response body. Queries are expected as the bincode-serialized body
+  /// of a `POST` request, and all other endpoints as `GET` requests.
+  pub fn handle(
+    &self,
+    method: &str,
+    path: &str,
+    body: &[u8],
+  ) -> ResultBoxedError<Vec<u8>> {
+    let (namespace, endpoint) = path
+      .strip_prefix('/')
+      .and_then(|p| p.split_once('/'))
+      .ok_or_else(|| {
+        ErrorUnexpectedInputSize::new(format!("Unroutable path: {}", path))
+      })?;
+    match (method, endpoint) {
+      ("GET", "params") => self.params_json(namespace),
+      ("GET", "info") => Ok(self.server_info(namespace)?.to_json()?.into()),
+      ("POST", "query") => match bincode::deserialize(body) {
+        Ok(q) => self.respond(namespace, &q),
+        Err(e) => {
+          let ns = self.get(namespace)?;
+          ns.counters.queries_failed.fetch_add(1, Ordering::Relaxed);
+          Err(e.into())
+        }
+      },
+      _ => Err(
+        ErrorUnexpectedInputSize::new(format!(
+          "Unroutable request: {} {}",
+          method, path
+        ))
+        .into(),
+      ),
+    }
+  }
+}
+
+/// The token bucket of a single client
+struct TokenBucket {
+  tokens: f64,
+  updated_at: Instant,
+}
+
+/// A `QuotaEnforcer` limits the rate of queries per client, using a token
+/// bucket for each (opaque) client id. Every query consumes one token, and
+/// tokens are refilled at a constant rate up to the bucket capacity.
+///
+/// PIR servers cannot cache or dedupe queries by content, since each query
+/// is freshly randomized, so every admitted query costs a full pass over
+/// the DB. Quotas are therefore checked before the query is answered.
+pub struct QuotaEnforcer {
+  capacity: f64,
+  refill_per_sec: f64,
+  buckets: Mutex<HashMap<Vec<u8>, TokenBucket>>,
+}
+
+impl QuotaEnforcer {
+  /// Creates an enforcer that admits bursts of up to `burst` queries per
+  /// client, refilled at `refill_per_sec` queries per second
+  pub fn new(burst: u32, refill_per_sec: f64) -> Self {
+    Self {
+      capacity: burst as f64,
+      refill_per_sec,
+      buckets: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Consumes a token for `client_id`, or returns `ErrorQuotaExceeded` if
+  /// the client has no tokens left
+  pub fn acquire(&self, client_id: &[u8]) -> Result<(), ErrorQuotaExceeded> {
+    self.acquire_at(client_id, Instant::now())
+  }
+
+  /// Same as `acquire`, at the given time
+  pub fn acquire_at(
+    &self,
+    client_id: &[u8],
+    now: Instant,
+  ) -> Result<(), ErrorQuotaExceeded> {
+    let mut buckets = self.buckets.lock().unwrap();
+    let bucket =
+      buckets
+        .entry(client_id.to_vec())
+        .or_insert_with(|| TokenBucket {
+          tokens: self.capacity,
+          updated_at: now,
+        });
+    self.refill(bucket, now);
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      return Ok(());
+    }
+    // rates that are not positive, or so small that the wait does not
+    // fit a `Duration`, never refill the bucket
+    let retry_after = if self.refill_per_sec > 0.0 {
+      Duration::try_from_secs_f64((1.0 - bucket.tokens) / self.refill_per_sec)
+        .unwrap_or(Duration::MAX)
+    } else {
+      Duration::MAX
+    };
+    Err(ErrorQuotaExceeded { retry_after })
+  }
+
+  /// Responds to a query from `client_id` using `respond`, if the client
+  /// is within its quota. `respond` is not called otherwise.
+  pub fn respond_with<F>(
+    &self,
+    client_id: &[u8],
+    respond: F,
+  ) -> ResultBoxedError<Vec<u8>>
+  where
+    F: FnOnce() -> ResultBoxedError<Vec<u8>>,
+  {
+    self.acquire(client_id)?;
+    respond()
+  }
+
+  /// Responds to `q` from `client_id` using `shard`, if the client is
+  /// within its quota
+  pub fn respond<S: PirShard>(
+    &self,
+    client_id: &[u8],
+    shard: &S,
+    q: &Query,
+  ) -> ResultBoxedError<Vec<u8>> {
+    self.respond_with(client_id, || shard.respond(q))
+  }
+
+  /// Drops the buckets of clients that have been refilled completely, as
+  /// these are indistinguishable from clients that were never seen. This
+  /// bounds the memory used by the enforcer.
+  pub fn evict_idle(&self, now: Instant) {
+    let mut buckets = self.buckets.lock().unwrap();
+    buckets.retain(|_, bucket| {
+      self.refill(bucket, now);
+      bucket.tokens < self.capacity
+    });
+  }
+
+  /// Returns the number of clients that are currently tracked
+  pub fn tracked_clients(&self) -> usize {
+    self.buckets.lock().unwrap().len()
+  }
+
+  fn refill(&self, bucket: &mut TokenBucket, now: Instant) {
+    let elapsed = now.saturating_duration_since(bucket.updated_at);
+    bucket.tokens = (bucket.tokens
+      + elapsed.as_secs_f64() * self.refill_per_sec)
+      .min(self.capacity);
+    bucket.updated_at = bucket.updated_at.max(now);
+  }
+}
+
+// The serving wrappers are shared by the threads that answer queries
+assert_impl_all!(ReloadableShard<Shard>: Send, Sync);
+assert_impl_all!(RotatingShard<Shard>: Send, Sync);
+assert_impl_all!(ServingShard<Shard>: Send, Sync);
+assert_impl_all!(ShardCluster<Shard>: Send, Sync);
+assert_impl_all!(ShardRegistry<Shard>: Send, Sync);
+assert_impl_all!(QuotaEnforcer: Send, Sync);
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::*;
+  use crate::db::{BaseParams, IndexParams};
+  use crate::test_utils::{generate_db_eles, index_shard, kv_shard};
+
+  #[test]
+  fn reloadable_shard_swaps_snapshots() {
+    let (snapshots, mut shards): (Vec<_>, Vec<_>) =
+      (0..2).map(|_| index_shard()).unzip();
+    let reloadable = ReloadableShard::new(shards.remove(0));
+    let check = |eles: &[String]| {
+      let current = reloadable.current();
+      let bp = current.get_base_params();
+      let cp = CommonParams::from(bp);
+      let mut qp = generate_index_query_params(&cp, bp).unwrap();
+      let q = qp.generate_query(4).unwrap();
+      let resp: Response =
+        bincode::deserialize(&reloadable.respond(&q).unwrap()).unwrap();
+      assert_eq!(qp.parse_resp_as_base64(&resp), eles[4]);
+    };
+    check(&snapshots[0]);
+    let before = reloadable.server_info().unwrap();
+    reloadable.reload(shards.remove(0));
+    check(&snapshots[1]);
+    let after = reloadable.server_info().unwrap();
+    assert_eq!((before.epochs(), after.epochs()), (vec![0], vec![1]));
+    assert_ne!(
+      before.shards[0].params_digest,
+      after.shards[0].params_digest
+    );
+  }
+
+  #[test]
+  fn shard_registry_isolates_namespaces() {
+    let (dbs, shards): (Vec<_>, Vec<_>) = (0..3).map(|_| index_shard()).unzip();
+    let shard = |i: usize| shards[i].clone();
+    let registry = ShardRegistry::new();
+    registry.register("contacts", shard(0)).unwrap();
+    registry.register("blocklist", shard(1)).unwrap();
+    assert!(registry.register("contacts", shard(1)).is_err());
+    assert!(registry.register("a/b", shard(1)).is_err());
+    assert_eq!(registry.namespaces(), vec!["blocklist", "contacts"]);
+
+    let query = |namespace: &str, eles: &[String]| {
+      let path = format!("/{}/params", namespace);
+      let params: IndexParams =
+        serde_json::from_slice(&registry.handle("GET", &path, &[]).unwrap())
+          .unwrap();
+      let cp = CommonParams::from(&params);
+      let mut qp = generate_index_query_params(&cp, &params).unwrap();
+      let q = bincode::serialize(&qp.generate_query(8).unwrap()).unwrap();
+      let path = format!("/{}/query", namespace);
+      let resp: Response =
+        bincode::deserialize(&registry.handle("POST", &path, &q).unwrap())
+          .unwrap();
+      assert_eq!(qp.parse_resp_as_base64(&resp), eles[8]);
+    };
+    query("contacts", &dbs[0]);
+    query("blocklist", &dbs[1]);
+    query("blocklist", &dbs[1]);
+
+    // reloading one namespace leaves the epochs of the others unchanged
+    registry.reload("contacts", shard(2)).unwrap();
+    query("contacts", &dbs[2]);
+    assert_eq!(registry.epoch("contacts").unwrap(), 1);
+    assert_eq!(registry.epoch("blocklist").unwrap(), 0);
+    let info = registry.handle("GET", "/blocklist/info", &[]).unwrap();
+    let info = ServerInfo::from_json(std::str::from_utf8(&info).unwrap());
+    assert_eq!(info.unwrap().epochs(), vec![0]);
+
+    assert!(registry.handle("POST", "/contacts/query", &[1]).is_err());
+    let metrics = registry.metrics("contacts").unwrap();
+    assert_eq!((metrics.queries_answered, metrics.queries_failed), (2, 1));
+    assert_eq!(registry.metrics("blocklist").unwrap().queries_answered, 2);
+
+    assert!(registry.remove("blocklist"));
+    let err = registry
+      .handle("GET", "/blocklist/params", &[])
+      .unwrap_err();
+    assert!(err.is::<ErrorUnknownNamespace>());
+  }
+
+  #[test]
+  fn rotating_shard_follows_schedule() {
+    // query params are not generated for expired params, so the schedule
+    // starts in the present
+    let t0 = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap()
+      .as_secs();
+    let schedule = RotationSchedule::daily().with_origin(t0);
+    let at = |secs: u64| UNIX_EPOCH + Duration::from_secs(secs);
+    assert_eq!(schedule.epoch_at(at(0)), 0);
+    assert_eq!(schedule.epoch_at(at(t0 + 86_399)), 0);
+    assert_eq!(schedule.epoch_at(at(t0 + 86_400)), 1);
+    assert_eq!(schedule.epoch_start(2), t0 + 2 * 86_400);
+    assert_eq!(schedule.epoch_start(u64::MAX), u64::MAX);
+    assert!(RotationSchedule::new(0).is_err());
+    let long = RotationSchedule::new(u64::MAX - 10).unwrap();
+    assert_eq!(long.epoch_start(2), u64::MAX);
+    assert_eq!(long.until_next(at(t0)), Duration::MAX);
+    assert_eq!(
+      schedule.until_next(at(t0 + 86_000)),
+      Duration::from_secs(400)
+    );
+
+    let m = 2u32.pow(6) as usize;
+    let elem_size = 2u32.pow(8) as usize;
+    let snapshots: Vec<Vec<String>> =
+      (0..2).map(|_| generate_db_eles(m, elem_size / 8)).collect();
+    let build = |epoch: u64| {
+      Shard::from_base64_strings(
+        &snapshots[epoch as usize],
+        512,
+        m,
+        elem_size,
+        10,
+      )
+    };
+    let rotating = RotatingShard::new(schedule, at(t0 + 10), build).unwrap();
+    assert_eq!(rotating.epoch(), 0);
+    let expires_at = |shard: &Shard| shard.get_base_params().get_expires_at();
+    assert_eq!(expires_at(&rotating.current()), Some(t0 + 86_400));
+
+    assert!(!rotating.rotate_if_due(at(t0 + 20), build).unwrap());
+    rotating.prepare_next(build).unwrap();
+    let unused = |_| -> ResultBoxedError<Shard> { unreachable!() };
+    assert!(rotating.rotate_if_due(at(t0 + 86_400), unused).unwrap());
+    assert_eq!(rotating.epoch(), 1);
+    assert_eq!(expires_at(&rotating.current()), Some(t0 + 2 * 86_400));
+
+    let params: IndexParams =
+      serde_json::from_slice(&rotating.params_json().unwrap()).unwrap();
+    let cp = CommonParams::from(&params);
+    let mut qp = generate_index_query_params(&cp, &params).unwrap();
+    let q = qp.generate_query(5).unwrap();
+    let resp: Response =
+      bincode::deserialize(&rotating.respond(&q).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), snapshots[1][5]);
+  }
+
+  #[test]
+  fn padded_cluster_hides_shard_shape() {
+    let lwe_dim = 512;
+    let plaintext_bits = 10usize;
+    let dims = [(2u32.pow(8) as usize, 256), (2u32.pow(7) as usize, 128)];
+    let snapshots: Vec<Vec<String>> = dims
+      .iter()
+      .map(|&(m, elem_size)| generate_db_eles(m, elem_size / 8))
+      .collect();
+    let shards = dims
+      .iter()
+      .zip(&snapshots)
+      .map(|(&(m, elem_size), eles)| {
+        Shard::from_base64_strings(eles, lwe_dim, m, elem_size, plaintext_bits)
+          .unwrap()
+      })
+      .collect();
+    let cluster = ShardCluster::new(shards).with_padding(true);
+    let padding = cluster.padding().unwrap();
+    assert_eq!(padding.query_len, dims[0].0);
+
+    let mut lens = Vec::new();
+    for (i, eles) in snapshots.iter().enumerate() {
+      let bp = cluster.get_shard(i).unwrap().get_base_params();
+      let cp = CommonParams::from(bp);
+      let mut qp = generate_index_query_params(&cp, bp).unwrap();
+      let mut q = qp.generate_query(3).unwrap();
+      q.pad_to(padding.query_len);
+      let d_q = bincode::serialize(&q).unwrap();
+      let d_resp = cluster.respond(i, &q).unwrap();
+      lens.push((d_q.len(), d_resp.len()));
+      let resp: Response = bincode::deserialize(&d_resp).unwrap();
+      assert_eq!(qp.parse_resp_as_base64(&resp), eles[3]);
+    }
+    assert_eq!(lens[0], lens[1]);
+
+    let info = cluster.server_info().unwrap();
+    assert_eq!(info.shard_count(), 2);
+    assert_eq!(info.padding, Some(padding));
+    assert_eq!(info.max_query_bytes, lens[0].0);
+    assert_eq!(info.negotiate_version(&[0, 1, 2]), Some(1));
+    assert_eq!(info.negotiate_version(&[2]), None);
+    assert!(info.supports_encoding(EncodingConfig::Hex));
+    let parsed = ServerInfo::from_json(&info.to_json().unwrap()).unwrap();
+    assert_eq!(parsed, info);
+  }
+
+  #[test]
+  fn kv_clusters_plan_partitions_by_matrix_height() {
+    let max_height = KVDatabase::estimate_matrix_height(10_000);
+    assert_eq!(
+      ShardCluster::<KVShard>::plan_partitions(10_000, max_height).unwrap(),
+      1
+    );
+    let partitions =
+      ShardCluster::<KVShard>::plan_partitions(1_000_000, max_height).unwrap();
+    assert!(partitions > 1);
+    assert!(
+      KVDatabase::estimate_matrix_height(1_000_000usize.div_ceil(partitions))
+        <= max_height
+    );
+    assert!(
+      KVDatabase::estimate_matrix_height(
+        1_000_000usize.div_ceil(partitions - 1)
+      ) > max_height
+    );
+    assert!(ShardCluster::<KVShard>::plan_partitions(10, 1).is_err());
+  }
+
+  #[test]
+  fn server_info_describes_served_shards() {
+    let (_, shard) = index_shard();
+    let config = ServingConfig {
+      threads: Some(1),
+      noise_flooding_bits: Some(12),
+      ..Default::default()
+    };
+    let serving = ServingShard::new(shard.clone(), &config).unwrap();
+    let info = serving.server_info().unwrap();
+    assert_eq!(info.protocol_versions, SUPPORTED_PROTOCOL_VERSIONS);
+    assert_eq!(info.shard_count(), 1);
+    assert_eq!(info.shards[0], ShardInfo::new(&shard, 0).unwrap());
+    assert_eq!(info.shards[0].params_digest, shard.params_digest().unwrap());
+    assert_eq!(info.shards[0].filter_epoch, None);
+    assert!(info.padding.is_none() && info.parity.is_none());
+    assert_eq!(info.noise_flooding_bits, Some(12));
+    assert!(EncodingConfig::ALL
+      .iter()
+      .all(|&e| info.supports_encoding(e)));
+
+    // the largest accepted query is exactly a serialized query
+    let bp = shard.get_base_params();
+    let mut qp =
+      generate_index_query_params(&CommonParams::from(bp), bp).unwrap();
+    let q = qp.generate_query(0).unwrap();
+    assert_eq!(info.max_query_bytes, bincode::serialize(&q).unwrap().len());
+
+    assert_eq!(info.negotiate_version(&[0, 1, 99]), Some(1));
+    assert_eq!(info.negotiate_version(&[99]), None);
+    let json = info.to_json().unwrap();
+    assert_eq!(ServerInfo::from_json(&json).unwrap(), info);
+    // fields added after the first protocol version are optional
+    let mut value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    let fields = value.as_object_mut().unwrap();
+    fields.remove("parity");
+    fields.remove("noise_flooding_bits");
+    let parsed = ServerInfo::from_json(&value.to_string()).unwrap();
+    assert_eq!(parsed.noise_flooding_bits, None);
+    assert!(ServerInfo::from_json("{}").is_err());
+
+    let (_, _, kv_shard) = kv_shard();
+    let info = ShardInfo::new(&kv_shard, 3).unwrap();
+    assert_eq!(info.epoch, 3);
+    assert_eq!(info.filter_epoch, Some(0));
+    assert_eq!(info.height, kv_shard.matrix_height());
+  }
+
+  #[test]
+  fn quota_enforcer_rejects_excess_queries() {
+    let (eles, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    let enforcer = QuotaEnforcer::new(2, 0.1);
+
+    for _ in 0..2 {
+      let mut qp = generate_index_query_params(&cp, bp).unwrap();
+      let q = qp.generate_query(5).unwrap();
+      let resp: Response =
+        bincode::deserialize(&enforcer.respond(b"alice", &shard, &q).unwrap())
+          .unwrap();
+      assert_eq!(qp.parse_resp_as_base64(&resp), eles[5]);
+    }
+    let mut called = false;
+    let err = enforcer.respond_with(b"alice", || {
+      called = true;
+      Ok(Vec::new())
+    });
+    assert!(err.is_err() && !called);
+    assert!(enforcer.acquire(b"bob").is_ok());
+
+    let now = Instant::now();
+    let err = enforcer.acquire_at(b"alice", now).unwrap_err();
+    assert!(err.retry_after <= Duration::from_secs(10));
+    let later = now + Duration::from_secs(30);
+    assert!(enforcer.acquire_at(b"alice", later).is_ok());
+    enforcer.evict_idle(later);
+    assert_eq!(enforcer.tracked_clients(), 1);
+
+    // waits that do not fit a `Duration` are reported as unbounded
+    for rate in [1e-300, f64::MIN_POSITIVE, f64::NAN] {
+      let enforcer = QuotaEnforcer::new(0, rate);
+      let err = enforcer.acquire_at(b"alice", now).unwrap_err();
+      assert_eq!(err.retry_after, Duration::MAX);
+    }
+  }
+
+  #[test]
+  fn serving_shard_responds_on_configured_pool() {
+    let (eles, shard) = index_shard();
+    let config = ServingConfig {
+      threads: Some(2),
+      pin_cores: true,
+      numa_node: None,
+      noise_flooding_bits: None,
+    };
+    let serving = ServingShard::new(shard, &config).unwrap();
+    assert_eq!(serving.threads(), 2);
+
+    let bp = serving.get_shard().get_base_params();
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_index_query_params(&cp, bp).unwrap();
+    let q = qp.generate_query(9).unwrap();
+    let d_resp = serving.respond(&q).unwrap();
+    assert_eq!(d_resp, serving.get_shard().respond(&q).unwrap());
+    let resp: Response = bincode::deserialize(&d_resp).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), eles[9]);
+
+    assert_eq!(
+      parse_cpulist("0-3,8,10-11\n").unwrap(),
+      [0, 1, 2, 3, 8, 10, 11]
+    );
+  }
+
+  #[test]
+  fn serving_shard_floods_responses_with_noise() {
+    let (eles, shard) = index_shard();
+    let config = |bits| ServingConfig {
+      threads: Some(1),
+      noise_flooding_bits: Some(bits),
+      ..Default::default()
+    };
+    assert!(ServingShard::new(shard.clone(), &config(21)).is_err());
+    let serving = ServingShard::new(shard, &config(20)).unwrap();
+
+    let bp = serving.get_shard().get_base_params();
+    let cp = CommonParams::from(bp);
+    for i in [0, 9, 255] {
+      let mut qp = generate_index_query_params(&cp, bp).unwrap();
+      let q = qp.generate_query(i).unwrap();
+      let d_resp = serving.respond(&q).unwrap();
+      assert_ne!(d_resp, serving.get_shard().respond(&q).unwrap());
+      let resp: Response = bincode::deserialize(&d_resp).unwrap();
+      assert_eq!(qp.parse_resp_as_base64(&resp), eles[i]);
+    }
+  }
+
+  #[test]
+  fn serving_shards_are_primed() {
+    let (_, shard) = index_shard();
+    let config = ServingConfig {
+      threads: Some(2),
+      ..Default::default()
+    };
+    let serving = ServingShard::new(shard, &config).unwrap();
+    assert!(serving.prime(0).is_err());
+    let report = serving.prime(20).unwrap();
+    assert_eq!(report.queries, 20);
+    assert!(report.p50 <= report.p99 && report.p99 <= report.max);
+    assert!(report.is_ready(report.max));
+    assert!(!report.is_ready(Duration::ZERO) || report.p99.is_zero());
+    let json = serde_json::to_string(&report).unwrap();
+    assert_eq!(serde_json::from_str::<PrimeReport>(&json).unwrap(), report);
+  }
+
+  #[test]
+  fn cluster_of_any_shards() {
+    let (eles, shard) = index_shard();
+    let (keys, values, kv_shard) = kv_shard();
+    let shards: Vec<AnyShard> = vec![shard.into(), kv_shard.into()];
+    assert_ne!(
+      shards[0].params_digest().unwrap(),
+      shards[1].params_digest().unwrap()
+    );
+    let cluster = ShardCluster::new(shards).with_padding(true);
+    let padding = cluster.padding().unwrap();
+
+    let shard = cluster.get_shard(0).unwrap().as_index().unwrap();
+    let bp = shard.get_base_params();
+    let mut qp =
+      generate_index_query_params(&CommonParams::from(bp), bp).unwrap();
+    let mut q = qp.generate_query(8).unwrap();
+    q.pad_to(padding.query_len);
+    let resp: Response =
+      bincode::deserialize(&cluster.respond(0, &q).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), eles[8]);
+
+    let shard = cluster.get_shard(1).unwrap().as_kv().unwrap();
+    let bp = shard.get_base_params();
+    let mut qp = generate_kv_query_params(&CommonParams::from(bp), bp).unwrap();
+    let mut q = qp
+      .generate_query_for_key(&KeyBytes::from(keys[8].as_str()))
+      .unwrap();
+    q.pad_to(padding.query_len);
+    let resp: Response =
+      bincode::deserialize(&cluster.respond(1, &q).unwrap()).unwrap();
+    let key = HashedKey::from_bytes(keys[8].as_bytes());
+    assert_eq!(qp.parse_resp_as_base64(&resp, &key).unwrap(), values[8]);
+  }
+}
@@ -0,0 +1,318 @@
+//! The `simulate` module models the costs of the protocol for given
+//! parameters and network conditions, so that users can choose parameters
+//! (e.g. the element size and LWE dimension) without building DBs.
+//!
+//! The costs are split into the offline transfer of the hint (the RHS of
+//! the params), and the online upload of a query, computation of the
+//! response and download of the response. Sizes count the bincode
+//! serialization of queries and responses, and 4 bytes per entry of the
+//! hint. Server compute is modelled as one multiply-add per entry of the
+//! DB matrix, at a given throughput (see `measure_server_throughput` for
+//! measuring it on the current machine).
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::db::{
+  BaseParams, DatabaseMatrix, DatabaseMatrixLayout, IndexDatabase, KVDatabase,
+};
+use crate::errors::{ErrorUnexpectedInputSize, ResultBoxedError};
+
+/// The default server throughput (in multiply-adds per second) of a
+/// single core computing responses
+pub const DEFAULT_SERVER_THROUGHPUT: f64 = 1e9;
+
+/// The bincode length prefix of serialized queries and responses
+const LENGTH_PREFIX_BYTES: usize = 8;
+
+/// The parameters that determine the costs of the protocol
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SimulatedParams {
+  /// The number of rows of the DB matrix, i.e. the length of queries
+  pub m: usize,
+  /// The size (in bits) of DB elements
+  pub elem_size: usize,
+  /// The number of plaintext bits packed into each DB entry
+  pub plaintext_bits: usize,
+  /// The LWE dimension
+  pub lwe_dim: usize,
+}
+impl SimulatedParams {
+  /// Describes an index DB of `m` elements
+  pub fn new(
+    m: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+    lwe_dim: usize,
+  ) -> Self {
+    Self {
+      m,
+      elem_size,
+      plaintext_bits,
+      lwe_dim,
+    }
+  }
+
+  /// Describes a KV DB of `num_keys` values, whose matrix height is
+  /// estimated by `KVDatabase::estimate_matrix_height`
+  pub fn for_kv(
+    num_keys: usize,
+    elem_size: usize,
+    plaintext_bits: usize,
+    lwe_dim: usize,
+  ) -> Self {
+    Self::new(
+      KVDatabase::estimate_matrix_height(num_keys),
+      elem_size,
+      plaintext_bits,
+      lwe_dim,
+    )
+  }
+
+  /// Describes the DB that `params` were generated for
+  pub fn from_params<P: BaseParams>(params: &P) -> Self {
+    Self::new(
+      params.get_total_records(),
+      params.get_elem_size(),
+      params.get_plaintext_bits(),
+      params.get_dim(),
+    )
+  }
+
+  /// Returns the number of columns of the DB matrix
+  pub fn row_width(&self) -> usize {
+    IndexDatabase::get_row_width(self.elem_size, self.plaintext_bits)
+  }
+}
+
+/// A `NetworkProfile` describes the connection between a client and the
+/// server
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NetworkProfile {
+  /// The round-trip time
+  pub rtt: Duration,
+  /// The upload bandwidth of the client, in bytes per second
+  pub upload_bytes_per_sec: f64,
+  /// The download bandwidth of the client, in bytes per second
+  pub download_bytes_per_sec: f64,
+}
+impl NetworkProfile {
+  /// A mobile connection (50 ms RTT, 5 Mbit/s up, 20 Mbit/s down)
+  pub const MOBILE: Self = Self {
+    rtt: Duration::from_millis(50),
+    upload_bytes_per_sec: 5e6 / 8.0,
+    download_bytes_per_sec: 20e6 / 8.0,
+  };
+
+  /// A broadband connection (20 ms RTT, 20 Mbit/s up, 100 Mbit/s down)
+  pub const BROADBAND: Self = Self {
+    rtt: Duration::from_millis(20),
+    upload_bytes_per_sec: 20e6 / 8.0,
+    download_bytes_per_sec: 100e6 / 8.0,
+  };
+
+  /// A connection within a datacenter (0.5 ms RTT, 10 Gbit/s)
+  pub const DATACENTER: Self = Self {
+    rtt: Duration::from_micros(500),
+    upload_bytes_per_sec: 10e9 / 8.0,
+    download_bytes_per_sec: 10e9 / 8.0,
+  };
+
+  fn upload(&self, bytes: usize) -> ResultBoxedError<Duration> {
+    seconds(bytes as f64 / self.upload_bytes_per_sec)
+  }
+
+  fn download(&self, bytes: usize) -> ResultBoxedError<Duration> {
+    seconds(bytes as f64 / self.download_bytes_per_sec)
+  }
+}
+
+/// Converts `secs` to a `Duration`, failing for modelled times that are
+/// not finite or do not fit a `Duration` (e.g. for tiny bandwidths)
+fn seconds(secs: f64) -> ResultBoxedError<Duration> {
+  Duration::try_from_secs_f64(secs).map_err(|e| {
+    ErrorUnexpectedInputSize::new(format!(
+      "modelled time of {} seconds: {}",
+      secs, e
+    ))
+    .into()
+  })
+}
+
+/// Adds the modelled times `a` and `b`, failing on overflow
+fn add_times(a: Duration, b: Duration) -> ResultBoxedError<Duration> {
+  a.checked_add(b).ok_or_else(|| {
+    ErrorUnexpectedInputSize::new(format!(
+      "modelled times {:?} and {:?} overflow",
+      a, b
+    ))
+    .into()
+  })
+}
+
+/// Multiplies the sizes `a` and `b`, failing on overflow
+fn mul_sizes(a: usize, b: usize) -> ResultBoxedError<usize> {
+  a.checked_mul(b).ok_or_else(|| {
+    ErrorUnexpectedInputSize::new(format!("sizes {} * {} overflow", a, b))
+      .into()
+  })
+}
+
+/// The modelled `ProtocolCosts` of a DB, for a single client
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolCosts {
+  /// The size of the DB matrix held by the server
+  pub db_bytes: usize,
+  /// The size of the hint downloaded by clients
+  pub hint_bytes: usize,
+  /// The size of a serialized query
+  pub query_bytes: usize,
+  /// The size of a serialized response
+  pub response_bytes: usize,
+  /// The time to download the hint (offline)
+  pub hint_transfer: Duration,
+  /// The time to upload a query
+  pub query_upload: Duration,
+  /// The time to download a response
+  pub response_download: Duration,
+  /// The time for the server to compute a response
+  pub server_compute: Duration,
+}
+impl ProtocolCosts {
+  /// Returns the latency of a query, from sending the query until the
+  /// response is received
+  pub fn online_latency(&self) -> Duration {
+    self
+      .query_upload
+      .saturating_add(self.server_compute)
+      .saturating_add(self.response_download)
+  }
+}
+
+/// Models the costs of the protocol for `params` over `network`, for a
+/// server computing responses at `DEFAULT_SERVER_THROUGHPUT`
+pub fn protocol_costs(
+  params: &SimulatedParams,
+  network: &NetworkProfile,
+) -> ResultBoxedError<ProtocolCosts> {
+  protocol_costs_with_throughput(params, network, DEFAULT_SERVER_THROUGHPUT)
+}
+
+/// Same as `protocol_costs`, for a server computing `server_throughput`
+/// multiply-adds per second
+pub fn protocol_costs_with_throughput(
+  params: &SimulatedParams,
+  network: &NetworkProfile,
+  server_throughput: f64,
+) -> ResultBoxedError<ProtocolCosts> {
+  let valid = params.m > 0
+    && params.elem_size > 0
+    && params.plaintext_bits > 0
+    && network.upload_bytes_per_sec > 0.0
+    && network.download_bytes_per_sec > 0.0
+    && server_throughput > 0.0;
+  if !valid {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "cannot simulate {:?} over {:?} at {} multiply-adds per second",
+      params, network, server_throughput
+    ))));
+  }
+  let width = params.row_width();
+  let entries = mul_sizes(params.m, width)?;
+  let hint_bytes = mul_sizes(mul_sizes(params.lwe_dim, width)?, 4)?;
+  let query_bytes = LENGTH_PREFIX_BYTES + mul_sizes(params.m, 4)?;
+  let response_bytes = LENGTH_PREFIX_BYTES + mul_sizes(width, 4)?;
+  // requests are answered after half a round trip, and responses arrive
+  // after the other half
+  let half_rtt = network.rtt / 2;
+  Ok(ProtocolCosts {
+    db_bytes: mul_sizes(entries, 4)?,
+    hint_bytes,
+    query_bytes,
+    response_bytes,
+    hint_transfer: add_times(network.rtt, network.download(hint_bytes)?)?,
+    query_upload: add_times(half_rtt, network.upload(query_bytes)?)?,
+    response_download: add_times(half_rtt, network.download(response_bytes)?)?,
+    server_compute: seconds(entries as f64 / server_throughput)?,
+  })
+}
+
+/// Measures the throughput (in multiply-adds per second) of computing
+/// responses to `num_queries` synthetic queries to `db` on the current
+/// thread
+pub fn measure_server_throughput<T: DatabaseMatrix + ?Sized>(
+  db: &T,
+  num_queries: usize,
+) -> f64 {
+  let height = db.get_matrix_height();
+  let width = db.get_row_width_self();
+  let q: Vec<u32> = (0..height as u32).collect();
+  let start = Instant::now();
+  for _ in 0..num_queries.max(1) {
+    for i in 0..width {
+      std::hint::black_box(db.vec_mult(&q, i));
+    }
+  }
+  let elapsed = start.elapsed().as_secs_f64().max(f64::EPSILON);
+  (num_queries.max(1) * height * width) as f64 / elapsed
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::*;
+  use crate::test_utils::{index_shard, ELEM_SIZE, M};
+
+  #[test]
+  fn simulated_costs_match_built_shards() {
+    let (_, shard) = index_shard();
+    let bp = shard.get_base_params();
+    let params = SimulatedParams::from_params(bp);
+    assert_eq!(params, SimulatedParams::new(M, ELEM_SIZE, 10, 512));
+    let costs = protocol_costs(&params, &NetworkProfile::BROADBAND).unwrap();
+
+    let cp = CommonParams::from(bp);
+    let mut qp = generate_index_query_params(&cp, bp).unwrap();
+    let q = qp.generate_query(0).unwrap();
+    assert_eq!(bincode::serialize(&q).unwrap().len(), costs.query_bytes);
+    assert_eq!(shard.respond(&q).unwrap().len(), costs.response_bytes);
+    assert_eq!(
+      costs.hint_bytes,
+      bp.get_rhs().iter().map(|c| c.len() * 4).sum::<usize>()
+    );
+
+    // costs grow with the DB, and shrink with faster networks
+    let larger = SimulatedParams::new(16 * M, ELEM_SIZE, 10, 512);
+    let larger = protocol_costs(&larger, &NetworkProfile::BROADBAND).unwrap();
+    assert!(larger.online_latency() > costs.online_latency());
+    assert_eq!(larger.hint_bytes, costs.hint_bytes);
+    let fast = protocol_costs(&params, &NetworkProfile::DATACENTER).unwrap();
+    assert!(fast.online_latency() < costs.online_latency());
+    assert!(fast.hint_transfer < costs.hint_transfer);
+
+    let kv = SimulatedParams::for_kv(M, ELEM_SIZE, 10, 512);
+    assert!(kv.m > M);
+    assert!(protocol_costs(
+      &SimulatedParams::new(0, ELEM_SIZE, 10, 512),
+      &NetworkProfile::MOBILE
+    )
+    .is_err());
+
+    // costs that do not fit are reported as errors
+    let slow = NetworkProfile {
+      rtt: Duration::from_millis(20),
+      upload_bytes_per_sec: 1e-300,
+      download_bytes_per_sec: f64::MIN_POSITIVE,
+    };
+    assert!(protocol_costs(&params, &slow).is_err());
+    let huge = SimulatedParams::new(usize::MAX / 2, ELEM_SIZE, 10, 512);
+    assert!(protocol_costs(&huge, &NetworkProfile::MOBILE).is_err());
+    assert!(protocol_costs_with_throughput(
+      &params,
+      &NetworkProfile::MOBILE,
+      f64::MIN_POSITIVE
+    )
+    .is_err());
+    assert!(measure_server_throughput(shard.get_db(), 1) > 0.0);
+  }
+}
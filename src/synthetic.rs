@@ -0,0 +1,347 @@
+//! The `synthetic` module generates synthetic KV datasets for benchmarks
+//! and tests, shaped like real workloads rather than uniformly random
+//! ones.
+//!
+//! Real lookups are skewed: a few keys (e.g. common breached passwords,
+//! or popular users of a key directory) receive most of the queries. The
+//! popularity of keys therefore follows a Zipf distribution, from which
+//! query workloads are sampled (see `SyntheticDataset::sample_workload`).
+//! Values have sizes drawn from a `ValueSize` distribution, and are
+//! zero-padded to the largest value when building a DB.
+//!
+//! Generation is parallel, and deterministic given the seed of the
+//! `SyntheticConfig`: each record is generated from its own RNG, derived
+//! from the seed and the index of the record.
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_core::RngCore;
+use rayon::prelude::*;
+
+use crate::errors::{ErrorUnexpectedInputSize, ResultBoxedError};
+use crate::utils::random::{derive_seed, generate_seed};
+
+/// The distribution of the sizes (in bytes) of generated values
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueSize {
+  /// Every value has the given size
+  Fixed(usize),
+  /// Sizes are uniform in `min..=max`
+  Uniform { min: usize, max: usize },
+  /// Sizes follow a Pareto distribution with scale `min` and shape
+  /// `alpha`, truncated at `max`, i.e. most values are close to `min`
+  /// and a few are much larger (e.g. certificate chains)
+  Pareto { min: usize, max: usize, alpha: f64 },
+}
+impl ValueSize {
+  /// Returns the largest size that can be sampled
+  pub fn max_bytes(&self) -> usize {
+    match *self {
+      Self::Fixed(size) => size,
+      Self::Uniform { max, .. } | Self::Pareto { max, .. } => max,
+    }
+  }
+
+  /// Samples a size using `rng`
+  pub fn sample<R: RngCore>(&self, rng: &mut R) -> usize {
+    match *self {
+      Self::Fixed(size) => size,
+      Self::Uniform { min, max } => rng.gen_range(min..=max),
+      Self::Pareto { min, max, alpha } => {
+        // inverse transform sampling, where 1 - u lies in (0, 1]
+        let u: f64 = rng.gen();
+        let size = min as f64 / (1.0 - u).powf(1.0 / alpha);
+        (size as usize).clamp(min, max)
+      }
+    }
+  }
+
+  fn check(&self) -> ResultBoxedError<()> {
+    let valid = match *self {
+      Self::Fixed(size) => size > 0,
+      Self::Uniform { min, max } => min > 0 && min <= max,
+      Self::Pareto { min, max, alpha } => min > 0 && min <= max && alpha > 0.0,
+    };
+    if !valid {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "invalid value size distribution: {:?}",
+        self
+      ))));
+    }
+    Ok(())
+  }
+}
+
+/// A `Zipf` distribution over the ranks `0..n`, where rank `r` has
+/// probability proportional to 1 / (r + 1)^`exponent`
+#[derive(Clone, Debug)]
+pub struct Zipf {
+  cdf: Vec<f64>,
+}
+impl Zipf {
+  /// Fails unless `n` is positive and `exponent` is non-negative. An
+  /// exponent of 0 gives the uniform distribution.
+  pub fn new(n: usize, exponent: f64) -> ResultBoxedError<Self> {
+    if n == 0 || exponent.is_nan() || exponent < 0.0 {
+      return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+        "Zipf distribution over {} ranks with exponent {}",
+        n, exponent
+      ))));
+    }
+    let mut total = 0.0;
+    let mut cdf: Vec<f64> = (0..n)
+      .map(|r| {
+        total += 1.0 / ((r + 1) as f64).powf(exponent);
+        total
+      })
+      .collect();
+    cdf.iter_mut().for_each(|c| *c /= total);
+    Ok(Self { cdf })
+  }
+
+  /// Returns the number of ranks
+  pub fn len(&self) -> usize {
+    self.cdf.len()
+  }
+
+  /// Returns whether the distribution has no ranks, which never holds
+  pub fn is_empty(&self) -> bool {
+    self.cdf.is_empty()
+  }
+
+  /// Returns the probability of rank `r`
+  pub fn probability(&self, r: usize) -> f64 {
+    match r {
+      0 => self.cdf[0],
+      _ => self.cdf[r] - self.cdf[r - 1],
+    }
+  }
+
+  /// Samples a rank using `rng`
+  pub fn sample<R: RngCore>(&self, rng: &mut R) -> usize {
+    let u: f64 = rng.gen();
+    self
+      .cdf
+      .partition_point(|&c| c <= u)
+      .min(self.cdf.len() - 1)
+  }
+}
+
+/// A `SyntheticConfig` describes a synthetic KV dataset
+#[derive(Clone, Debug)]
+pub struct SyntheticConfig {
+  /// The number of records
+  pub num_records: usize,
+  /// The number of random bytes of each key (before base64-encoding)
+  pub key_bytes: usize,
+  /// The distribution of the sizes of values
+  pub value_size: ValueSize,
+  /// The exponent of the Zipf distribution of key popularity
+  pub zipf_exponent: f64,
+  /// The seed that the dataset is derived from
+  pub seed: [u8; 32],
+}
+impl SyntheticConfig {
+  /// Configures `num_records` records with 32-byte keys and a Zipf
+  /// exponent of 1, generated from a random seed
+  pub fn new(num_records: usize, value_size: ValueSize) -> Self {
+    Self {
+      num_records,
+      key_bytes: 32,
+      value_size,
+      zipf_exponent: 1.0,
+      seed: generate_seed(),
+    }
+  }
+
+  /// Sets the exponent of the Zipf distribution of key popularity
+  pub fn zipf_exponent(mut self, exponent: f64) -> Self {
+    self.zipf_exponent = exponent;
+    self
+  }
+
+  /// Sets the seed, making the generated dataset reproducible
+  pub fn seed(mut self, seed: [u8; 32]) -> Self {
+    self.seed = seed;
+    self
+  }
+
+  /// Generates the dataset, in parallel
+  pub fn generate(&self) -> ResultBoxedError<SyntheticDataset> {
+    self.value_size.check()?;
+    let popularity = Zipf::new(self.num_records, self.zipf_exponent)?;
+    let records: Vec<(String, Vec<u8>)> = (0..self.num_records)
+      .into_par_iter()
+      .map(|i| {
+        let mut rng =
+          StdRng::from_seed(derive_seed(self.seed, &(i as u64).to_le_bytes()));
+        let mut key = vec![0u8; self.key_bytes];
+        rng.fill_bytes(&mut key);
+        let mut value = vec![0u8; self.value_size.sample(&mut rng)];
+        rng.fill_bytes(&mut value);
+        (base64::encode(key), value)
+      })
+      .collect();
+    // popular keys are spread over the dataset, rather than being the
+    // first records
+    let mut ranking: Vec<usize> = (0..self.num_records).collect();
+    ranking.shuffle(&mut StdRng::from_seed(derive_seed(self.seed, b"ranking")));
+    let mut ranks = vec![0; self.num_records];
+    for (r, &record) in ranking.iter().enumerate() {
+      ranks[record] = r;
+    }
+    let (keys, values) = records.into_iter().unzip();
+    Ok(SyntheticDataset {
+      keys,
+      values,
+      value_bytes: self.value_size.max_bytes(),
+      ranking,
+      ranks,
+      popularity,
+    })
+  }
+}
+
+/// A `SyntheticDataset` holds the records generated for a
+/// `SyntheticConfig`, along with the popularity of their keys
+#[derive(Clone, Debug)]
+pub struct SyntheticDataset {
+  keys: Vec<String>,
+  values: Vec<Vec<u8>>,
+  value_bytes: usize,
+  ranking: Vec<usize>,
+  /// The popularity rank of each record, i.e. the inverse of `ranking`
+  ranks: Vec<usize>,
+  popularity: Zipf,
+}
+impl SyntheticDataset {
+  /// Returns the base64-encoded keys
+  pub fn get_keys(&self) -> &[String] {
+    &self.keys
+  }
+
+  /// Returns the unpadded values
+  pub fn get_values(&self) -> &[Vec<u8>] {
+    &self.values
+  }
+
+  /// Returns the element size (in bits) of a DB holding the padded
+  /// values
+  pub fn get_elem_size(&self) -> usize {
+    self.value_bytes * 8
+  }
+
+  /// Returns the values zero-padded to the largest value size and
+  /// base64-encoded, e.g. for `KVShard::from_base64_strings`
+  pub fn padded_values(&self) -> Vec<String> {
+    self
+      .values
+      .par_iter()
+      .map(|v| {
+        let mut padded = v.clone();
+        padded.resize(self.value_bytes, 0);
+        base64::encode(padded)
+      })
+      .collect()
+  }
+
+  /// Returns the index of the record whose key has popularity rank `r`
+  pub fn record_of_rank(&self, r: usize) -> usize {
+    self.ranking[r]
+  }
+
+  /// Returns the probability that a query is for the key of `record`,
+  /// or `None` if there is no such record
+  pub fn popularity(&self, record: usize) -> Option<f64> {
+    let r = *self.ranks.get(record)?;
+    Some(self.popularity.probability(r))
+  }
+
+  /// Samples the records of `num_queries` queries, according to the
+  /// popularity of their keys
+  pub fn sample_workload<R: RngCore>(
+    &self,
+    num_queries: usize,
+    rng: &mut R,
+  ) -> Vec<usize> {
+    (0..num_queries)
+      .map(|_| self.ranking[self.popularity.sample(rng)])
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::*;
+
+  #[test]
+  fn synthetic_datasets_follow_their_distributions() {
+    let value_size = ValueSize::Pareto {
+      min: 8,
+      max: 64,
+      alpha: 1.5,
+    };
+    let config = SyntheticConfig::new(1000, value_size)
+      .zipf_exponent(1.2)
+      .seed([7u8; 32]);
+    let dataset = config.generate().unwrap();
+    let same = config.generate().unwrap();
+    assert_eq!(dataset.get_keys(), same.get_keys());
+    assert_eq!(dataset.get_values(), same.get_values());
+    assert!(dataset
+      .get_values()
+      .iter()
+      .all(|v| (8..=64).contains(&v.len())));
+    // most values are close to the minimum size
+    let small = dataset.get_values().iter().filter(|v| v.len() < 16).count();
+    assert!(small > 500);
+
+    // the most popular key receives about 1 / H(1000, 1.2) of queries
+    let top = dataset.record_of_rank(0);
+    let expected = dataset.popularity(top).unwrap();
+    let total: f64 = (0..1000).filter_map(|i| dataset.popularity(i)).sum();
+    assert!((total - 1.0).abs() < 1e-9);
+    assert!(dataset.popularity(1000).is_none());
+    let workload =
+      dataset.sample_workload(20000, &mut StdRng::seed_from_u64(1));
+    let hits = workload.iter().filter(|&&i| i == top).count();
+    assert!((hits as f64 / 20000.0 - expected).abs() < 0.02);
+    assert!(hits > workload.iter().filter(|&&i| i == 999).count());
+
+    assert!(
+      SyntheticConfig::new(10, ValueSize::Uniform { min: 4, max: 2 })
+        .generate()
+        .is_err()
+    );
+    assert!(Zipf::new(0, 1.0).is_err());
+    assert!(Zipf::new(10, -1.0).is_err());
+  }
+
+  #[test]
+  fn synthetic_datasets_build_kv_shards() {
+    let dataset =
+      SyntheticConfig::new(64, ValueSize::Uniform { min: 16, max: 32 })
+        .generate()
+        .unwrap();
+    let elem_size = dataset.get_elem_size();
+    let keys = dataset.get_keys();
+    let values = dataset.padded_values();
+    let shard =
+      KVShard::from_base64_strings(keys, &values, 512, elem_size, 10).unwrap();
+    let bp = shard.get_base_params();
+    let cp = CommonParams::from(bp);
+    for i in dataset.sample_workload(3, &mut StdRng::seed_from_u64(2)) {
+      let mut qp = generate_kv_query_params(&cp, bp).unwrap();
+      let key = HashedKey::from_bytes(keys[i].as_bytes());
+      let q = qp.generate_query(&key).unwrap();
+      let resp: Response =
+        bincode::deserialize(&shard.respond(&q).unwrap()).unwrap();
+      let value = qp.parse_resp_as_bytes(&resp, &key).unwrap();
+      assert_eq!(
+        &value[..dataset.get_values()[i].len()],
+        &dataset.get_values()[i][..]
+      );
+    }
+  }
+}
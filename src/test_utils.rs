@@ -0,0 +1,110 @@
+//! Fixtures shared by the unit tests of the crate
+use crate::api::{KVShard, Shard};
+use rand_core::{OsRng, RngCore};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The number of entries in the fixture DBs
+pub(crate) const M: usize = 256;
+/// The bit length of the fixture DB entries
+pub(crate) const ELEM_SIZE: usize = 256;
+/// The plaintext modulus bits of the fixture shards
+pub(crate) const PLAINTEXT_BITS: usize = 10;
+/// The LWE dimension of the fixture shards
+pub(crate) const LWE_DIM: usize = 512;
+
+/// Generates `num_eles` random entries of `ele_byte_len` bytes
+pub(crate) fn generate_db_bytes(
+  num_eles: usize,
+  ele_byte_len: usize,
+) -> Vec<Vec<u8>> {
+  (0..num_eles)
+    .map(|_| {
+      let mut ele = vec![0u8; ele_byte_len];
+      OsRng.fill_bytes(&mut ele);
+      ele
+    })
+    .collect()
+}
+
+/// Generates `num_eles` random base64-encoded entries of `ele_byte_len`
+/// bytes
+pub(crate) fn generate_db_eles(
+  num_eles: usize,
+  ele_byte_len: usize,
+) -> Vec<String> {
+  generate_db_bytes(num_eles, ele_byte_len)
+    .into_iter()
+    .map(base64::encode)
+    .collect()
+}
+
+/// Generates `num_eles` random base64-encoded pairs of 32-byte keys and
+/// values of `ele_byte_len` bytes
+pub(crate) fn generate_kv_db_elems(
+  num_eles: usize,
+  ele_byte_len: usize,
+) -> Vec<(String, String)> {
+  generate_db_eles(num_eles, 32)
+    .into_iter()
+    .zip(generate_db_eles(num_eles, ele_byte_len))
+    .collect()
+}
+
+/// Builds an index shard over `M` random entries of `ELEM_SIZE` bits,
+/// returning the entries alongside it
+pub(crate) fn index_shard() -> (Vec<String>, Shard) {
+  let eles = generate_db_eles(M, ELEM_SIZE / 8);
+  let shard =
+    Shard::from_base64_strings(&eles, LWE_DIM, M, ELEM_SIZE, PLAINTEXT_BITS)
+      .unwrap();
+  (eles, shard)
+}
+
+/// Builds a KV shard over `M` random keys and values of `ELEM_SIZE` bits,
+/// returning the keys and values alongside it
+pub(crate) fn kv_shard() -> (Vec<String>, Vec<String>, KVShard) {
+  let (keys, values): (Vec<String>, Vec<String>) =
+    generate_kv_db_elems(M, ELEM_SIZE / 8).into_iter().unzip();
+  let shard = KVShard::from_base64_strings(
+    &keys,
+    &values,
+    LWE_DIM,
+    ELEM_SIZE,
+    PLAINTEXT_BITS,
+  )
+  .unwrap();
+  (keys, values, shard)
+}
+
+/// A file path under the temporary directory that is unique to the test
+/// process, removing the file (if any) when dropped
+pub(crate) struct TempPath(PathBuf);
+
+impl TempPath {
+  pub(crate) fn new(name: &str) -> Self {
+    let file = format!(
+      "chalamet-{}-{:016x}-{}",
+      std::process::id(),
+      OsRng.next_u64(),
+      name
+    );
+    Self(std::env::temp_dir().join(file))
+  }
+
+  pub(crate) fn as_str(&self) -> &str {
+    self.0.to_str().unwrap()
+  }
+}
+
+impl AsRef<Path> for TempPath {
+  fn as_ref(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl Drop for TempPath {
+  fn drop(&mut self) {
+    let _ = fs::remove_file(&self.0);
+  }
+}
@@ -0,0 +1,483 @@
+//! The `transport` module abstracts how a client reaches a PIR server,
+//! so that the orchestration in `client::PirClient` (params caching,
+//! retries, batching) can run over any channel. An in-memory transport
+//! (for tests and embedded servers) and a minimal HTTP/1.1 transport are
+//! provided; other channels (e.g. QUIC or message queues) implement
+//! `PirTransport` directly.
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::api::{PirShard, Query};
+use crate::client::{KvPirClient, PirClient, ReliableClient};
+use crate::db::IndexParams;
+use crate::errors::{ErrorTransportUnsupported, ResultBoxedError};
+use crate::manifest::SignedManifest;
+use crate::serving::{ServerInfo, ShardInfo};
+
+/// The default path of the params endpoint of an `HttpTransport`
+pub const DEFAULT_PARAMS_PATH: &str = "/params";
+
+/// The default path of the query endpoint of an `HttpTransport`
+pub const DEFAULT_QUERY_PATH: &str = "/query";
+
+/// The default path of the manifest endpoint of an `HttpTransport`
+pub const DEFAULT_MANIFEST_PATH: &str = "/manifest";
+
+/// The default path of the server info endpoint of an `HttpTransport`
+pub const DEFAULT_INFO_PATH: &str = "/info";
+
+/// A `PirTransport` carries queries to a PIR server and its responses
+/// back. Queries and responses are exchanged in their bincode
+/// serialization, and params as JSON.
+pub trait PirTransport {
+  /// Fetches the serialized base params of the served DB
+  fn fetch_params(&mut self) -> ResultBoxedError<Vec<u8>>;
+  /// Fetches the JSON serialization of the `SignedManifest` covering the
+  /// params (see `manifest`). Transports without manifests return an
+  /// error.
+  fn fetch_manifest(&mut self) -> ResultBoxedError<Vec<u8>> {
+    Err("Transport does not serve manifests".into())
+  }
+  /// Fetches the JSON serialization of the `ServerInfo` of the server
+  /// (see `serving`). Transports without server info return an error.
+  fn fetch_server_info(&mut self) -> ResultBoxedError<Vec<u8>> {
+    Err("Transport does not serve server info".into())
+  }
+  /// Sends a query, returning the serialized response
+  fn send_query(&mut self, q: &Query) -> ResultBoxedError<Vec<u8>>;
+  /// Sends `body` as a serialized query as-is, e.g. to check that a
+  /// server rejects malformed queries (see `conformance`). Transports
+  /// that cannot send raw queries return an `ErrorTransportUnsupported`.
+  fn send_raw_query(&mut self, _body: &[u8]) -> ResultBoxedError<Vec<u8>> {
+    Err(Box::new(ErrorTransportUnsupported::new(
+      "raw queries".to_string(),
+    )))
+  }
+  /// Sends a batch of queries, returning the serialized responses in
+  /// order. Transports that can pipeline or batch queries should
+  /// override this.
+  fn send_queries(
+    &mut self,
+    queries: &[Query],
+  ) -> ResultBoxedError<Vec<Vec<u8>>> {
+    queries.iter().map(|q| self.send_query(q)).collect()
+  }
+}
+
+/// An `InMemoryTransport` answers queries using a shard in the same
+/// process
+pub struct InMemoryTransport<S> {
+  shard: S,
+  manifest: Option<Vec<u8>>,
+}
+
+impl<S: PirShard> InMemoryTransport<S> {
+  pub fn new(shard: S) -> Self {
+    Self {
+      shard,
+      manifest: None,
+    }
+  }
+
+  /// Serves `manifest` along with the params
+  pub fn with_manifest(mut self, manifest: &SignedManifest) -> Self {
+    self.manifest = serde_json::to_vec(manifest).ok();
+    self
+  }
+
+  /// Returns the shard that answers queries
+  pub fn get_shard(&self) -> &S {
+    &self.shard
+  }
+}
+
+impl<S: PirShard> PirTransport for InMemoryTransport<S> {
+  fn fetch_params(&mut self) -> ResultBoxedError<Vec<u8>> {
+    self.shard.params_json()
+  }
+
+  fn fetch_manifest(&mut self) -> ResultBoxedError<Vec<u8>> {
+    Ok(self.manifest.clone().ok_or("No manifest served")?)
+  }
+
+  /// Describes the shard as the only shard of the server, in epoch zero
+  fn fetch_server_info(&mut self) -> ResultBoxedError<Vec<u8>> {
+    let info = ServerInfo::new(vec![ShardInfo::new(&self.shard, 0)?], None);
+    Ok(info.to_json()?.into_bytes())
+  }
+
+  fn send_query(&mut self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    self.shard.respond(q)
+  }
+
+  fn send_raw_query(&mut self, body: &[u8]) -> ResultBoxedError<Vec<u8>> {
+    self.shard.respond(&bincode::deserialize(body)?)
+  }
+
+  fn send_queries(
+    &mut self,
+    queries: &[Query],
+  ) -> ResultBoxedError<Vec<Vec<u8>>> {
+    self.shard.respond_batch(queries)
+  }
+}
+
+/// A `Loopback` wires a `PirClient` directly to a shard in the same
+/// process. Unlike `InMemoryTransport`, queries are passed through their
+/// bincode serialization, exactly as over a network, so end-to-end tests
+/// also cover the wire format. The traffic exchanged is recorded.
+pub struct Loopback<S> {
+  shard: S,
+  manifest: Option<Vec<u8>>,
+  queries_answered: usize,
+  bytes_sent: usize,
+  bytes_received: usize,
+}
+
+impl<S: PirShard> Loopback<S> {
+  pub fn new(shard: S) -> Self {
+    Self {
+      shard,
+      manifest: None,
+      queries_answered: 0,
+      bytes_sent: 0,
+      bytes_received: 0,
+    }
+  }
+
+  /// Serves `manifest` along with the params
+  pub fn with_manifest(mut self, manifest: &SignedManifest) -> Self {
+    self.manifest = serde_json::to_vec(manifest).ok();
+    self
+  }
+
+  /// Returns the shard that answers queries
+  pub fn get_shard(&self) -> &S {
+    &self.shard
+  }
+
+  /// Returns the number of queries answered so far
+  pub fn queries_answered(&self) -> usize {
+    self.queries_answered
+  }
+
+  /// Returns the number of bytes sent by the client so far
+  pub fn bytes_sent(&self) -> usize {
+    self.bytes_sent
+  }
+
+  /// Returns the number of bytes received by the client so far,
+  /// including params
+  pub fn bytes_received(&self) -> usize {
+    self.bytes_received
+  }
+
+  /// Connects a `PirClient` to an index shard
+  pub fn index_client(
+    shard: S,
+  ) -> ResultBoxedError<PirClient<Self, ReliableClient<IndexParams>>> {
+    PirClient::connect_index(Self::new(shard))
+  }
+
+  /// Connects a `PirClient` to a KV shard
+  pub fn kv_client(shard: S) -> ResultBoxedError<PirClient<Self, KvPirClient>> {
+    PirClient::connect_kv(Self::new(shard))
+  }
+}
+
+impl<S: PirShard> PirTransport for Loopback<S> {
+  fn fetch_params(&mut self) -> ResultBoxedError<Vec<u8>> {
+    let params = self.shard.params_json()?;
+    self.bytes_received += params.len();
+    Ok(params)
+  }
+
+  fn fetch_manifest(&mut self) -> ResultBoxedError<Vec<u8>> {
+    let manifest = self.manifest.clone().ok_or("No manifest served")?;
+    self.bytes_received += manifest.len();
+    Ok(manifest)
+  }
+
+  fn send_query(&mut self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    self.send_raw_query(&bincode::serialize(q)?)
+  }
+
+  fn send_raw_query(&mut self, body: &[u8]) -> ResultBoxedError<Vec<u8>> {
+    self.bytes_sent += body.len();
+    let resp = self.shard.respond(&bincode::deserialize(body)?)?;
+    self.bytes_received += resp.len();
+    self.queries_answered += 1;
+    Ok(resp)
+  }
+}
+
+/// An `HttpTransport` fetches params (and manifests and server info) with
+/// `GET` requests to the params (and manifest and info) path, and sends
+/// each query as the body of a `POST` request to the query path, over
+/// plain HTTP/1.1 with one connection per request.
+///
+/// TLS is not supported, so servers should be reached through a local
+/// proxy (or a trusted network) that terminates TLS.
+#[derive(Clone, Debug)]
+pub struct HttpTransport {
+  addr: String,
+  params_path: String,
+  query_path: String,
+  manifest_path: String,
+  info_path: String,
+  timeout: Option<Duration>,
+}
+
+impl HttpTransport {
+  /// Creates a transport for the server at `addr` (`host:port`), using
+  /// the default paths
+  pub fn new(addr: &str) -> Self {
+    Self {
+      addr: addr.to_string(),
+      params_path: DEFAULT_PARAMS_PATH.to_string(),
+      query_path: DEFAULT_QUERY_PATH.to_string(),
+      manifest_path: DEFAULT_MANIFEST_PATH.to_string(),
+      info_path: DEFAULT_INFO_PATH.to_string(),
+      timeout: None,
+    }
+  }
+
+  /// Sets the paths of the params and query endpoints
+  pub fn with_paths(mut self, params_path: &str, query_path: &str) -> Self {
+    self.params_path = params_path.to_string();
+    self.query_path = query_path.to_string();
+    self
+  }
+
+  /// Sets the path of the manifest endpoint
+  pub fn with_manifest_path(mut self, manifest_path: &str) -> Self {
+    self.manifest_path = manifest_path.to_string();
+    self
+  }
+
+  /// Sets the path of the server info endpoint
+  pub fn with_info_path(mut self, info_path: &str) -> Self {
+    self.info_path = info_path.to_string();
+    self
+  }
+
+  /// Sets the read and write timeout of each request
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = Some(timeout);
+    self
+  }
+
+  /// Sends a request and returns the body of a `200 OK` response
+  fn request(
+    &self,
+    method: &str,
+    path: &str,
+    body: &[u8],
+  ) -> ResultBoxedError<Vec<u8>> {
+    let mut stream = TcpStream::connect(&self.addr)?;
+    stream.set_read_timeout(self.timeout)?;
+    stream.set_write_timeout(self.timeout)?;
+    write!(
+      stream,
+      "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+      method,
+      path,
+      self.addr,
+      body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw)?;
+    parse_http_response(&raw)
+  }
+}
+
+impl PirTransport for HttpTransport {
+  fn fetch_params(&mut self) -> ResultBoxedError<Vec<u8>> {
+    self.request("GET", &self.params_path, &[])
+  }
+
+  fn fetch_manifest(&mut self) -> ResultBoxedError<Vec<u8>> {
+    self.request("GET", &self.manifest_path, &[])
+  }
+
+  fn fetch_server_info(&mut self) -> ResultBoxedError<Vec<u8>> {
+    self.request("GET", &self.info_path, &[])
+  }
+
+  fn send_query(&mut self, q: &Query) -> ResultBoxedError<Vec<u8>> {
+    self.send_raw_query(&bincode::serialize(q)?)
+  }
+
+  fn send_raw_query(&mut self, body: &[u8]) -> ResultBoxedError<Vec<u8>> {
+    self.request("POST", &self.query_path, body)
+  }
+}
+
+/// Returns the body of a raw HTTP response, if its status is `200`.
+/// Chunked transfer encoding is not supported.
+fn parse_http_response(raw: &[u8]) -> ResultBoxedError<Vec<u8>> {
+  let header_end = raw
+    .windows(4)
+    .position(|w| w == b"\r\n\r\n")
+    .ok_or("Incomplete HTTP response")?;
+  let head = std::str::from_utf8(&raw[..header_end])?;
+  let mut lines = head.split("\r\n");
+  let status = lines
+    .next()
+    .and_then(|l| l.split_whitespace().nth(1))
+    .ok_or("Malformed HTTP status line")?;
+  if status != "200" {
+    return Err(format!("HTTP request failed with status {}", status).into());
+  }
+  let mut body = raw[header_end + 4..].to_vec();
+  for line in lines {
+    let (name, value) = match line.split_once(':') {
+      Some((name, value)) => (name.trim(), value.trim()),
+      None => continue,
+    };
+    if name.eq_ignore_ascii_case("transfer-encoding")
+      && value.eq_ignore_ascii_case("chunked")
+    {
+      return Err("Chunked HTTP responses are not supported".into());
+    }
+    if name.eq_ignore_ascii_case("content-length") {
+      let len: usize = value.parse()?;
+      if body.len() < len {
+        return Err("Truncated HTTP response body".into());
+      }
+      body.truncate(len);
+    }
+  }
+  Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::*;
+  use crate::client::KvValue;
+  use crate::db::IndexParams;
+  use crate::test_utils::kv_shard;
+  use std::io::{BufRead, BufReader};
+  use std::net::TcpListener;
+  use std::thread;
+
+  /// Serves `requests` HTTP requests for `shard` on a local port
+  fn serve_http(shard: Shard, requests: usize) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+      for stream in listener.incoming().take(requests) {
+        let mut stream = stream.unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut content_len = 0;
+        loop {
+          let mut line = String::new();
+          reader.read_line(&mut line).unwrap();
+          if line == "\r\n" {
+            break;
+          }
+          if let Some(len) = line.strip_prefix("Content-Length: ") {
+            content_len = len.trim().parse().unwrap();
+          }
+        }
+        let mut body = vec![0u8; content_len];
+        reader.read_exact(&mut body).unwrap();
+        let (status, out) = if request_line.starts_with("GET /params ") {
+          ("200 OK", shard.params_json().unwrap())
+        } else if request_line.starts_with("POST /query ") {
+          let q: Query = bincode::deserialize(&body).unwrap();
+          ("200 OK", shard.respond(&q).unwrap())
+        } else {
+          ("404 Not Found", Vec::new())
+        };
+        write!(
+          stream,
+          "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n",
+          status,
+          out.len()
+        )
+        .unwrap();
+        stream.write_all(&out).unwrap();
+      }
+    });
+    addr
+  }
+
+  #[test]
+  fn transports_answer_queries() {
+    let m = 2u32.pow(8) as usize;
+    let elem_size = 2u32.pow(8) as usize;
+    let db_eles: Vec<String> =
+      (0..m).map(|i| base64::encode([i as u8; 32])).collect();
+    let shard =
+      Shard::from_base64_strings(&db_eles, 512, m, elem_size, 10).unwrap();
+
+    let addr = serve_http(shard.clone(), 4);
+    let mut transports: Vec<Box<dyn PirTransport>> = vec![
+      Box::new(InMemoryTransport::new(shard.clone())),
+      Box::new(HttpTransport::new(&addr).with_timeout(Duration::from_secs(10))),
+    ];
+    for transport in transports.iter_mut() {
+      let params: IndexParams =
+        serde_json::from_slice(&transport.fetch_params().unwrap()).unwrap();
+      let cp = CommonParams::from(&params);
+      let mut qps: Vec<_> = (0..2)
+        .map(|_| generate_index_query_params(&cp, &params).unwrap())
+        .collect();
+      let queries: Vec<Query> = qps
+        .iter_mut()
+        .zip([3, 77])
+        .map(|(qp, i)| qp.generate_query(i).unwrap())
+        .collect();
+      let responses = transport.send_queries(&queries).unwrap();
+      for ((qp, i), d_resp) in qps.iter().zip([3, 77]).zip(responses) {
+        let resp: Response = bincode::deserialize(&d_resp).unwrap();
+        assert_eq!(qp.parse_resp_as_base64(&resp), db_eles[i]);
+      }
+    }
+    let mut client = Loopback::index_client(shard.clone()).unwrap();
+    assert_eq!(base64::encode(client.retrieve(200).unwrap()), db_eles[200]);
+    let loopback = client.get_transport_mut();
+    assert_eq!(loopback.queries_answered(), 1);
+    assert!(loopback.bytes_sent() > 0);
+    assert!(loopback.bytes_received() > loopback.bytes_sent());
+
+    let mut missing =
+      HttpTransport::new(&addr).with_paths("/missing", DEFAULT_QUERY_PATH);
+    assert!(missing.fetch_params().is_err());
+  }
+
+  #[test]
+  fn loopback_serves_kv_clients_over_the_wire_format() {
+    let (keys, values, shard) = kv_shard();
+    let mut client = Loopback::kv_client(shard.clone()).unwrap();
+    let params_len = shard.params_json().unwrap().len();
+    let loopback = client.get_transport_mut();
+    assert_eq!(loopback.bytes_received(), params_len);
+    assert_eq!(loopback.queries_answered(), 0);
+    assert!(loopback.fetch_manifest().is_err());
+    assert!(loopback.fetch_server_info().is_err());
+
+    let value = client.get(keys[9].as_bytes()).unwrap();
+    assert_eq!(value, KvValue::Found(base64::decode(&values[9]).unwrap()));
+    let loopback = client.get_transport_mut();
+    assert_eq!(loopback.queries_answered(), 1);
+    let query_len = 8 + 4 * shard.height();
+    let response_len = 8 + 4 * shard.width();
+    assert_eq!(loopback.bytes_sent(), query_len);
+    assert_eq!(loopback.bytes_received(), params_len + response_len);
+
+    // malformed queries are rejected without being answered
+    assert!(loopback.send_raw_query(&[1, 2, 3]).is_err());
+    let short = Query::random(shard.height() - 1);
+    assert!(loopback.send_query(&short).is_err());
+    assert_eq!(loopback.queries_answered(), 1);
+    assert_eq!(loopback.get_shard().num_keys(), keys.len());
+  }
+}
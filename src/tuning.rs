@@ -0,0 +1,182 @@
+//! The `tuning` module selects the loop structure used for computing
+//! responses, based on a quick micro-benchmark on the serving machine
+//! (see `Shard::tune`).
+//!
+//! Responses are the product of the query with the column-major DB
+//! matrix. For tall matrices the query does not fit in cache, so it can
+//! pay off to process the rows in tiles (keeping a tile of the query in
+//! cache while all columns are streamed), and to process several columns
+//! per pass over a tile (for instruction-level parallelism). Which
+//! configuration is fastest depends on the cache sizes of the machine.
+use std::time::{Duration, Instant};
+
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::db::DatabaseMatrix;
+use crate::errors::{ErrorUnexpectedInputSize, ResultBoxedError};
+
+/// The number of synthetic queries that each configuration is timed on
+pub const TUNE_QUERIES: usize = 3;
+
+/// The row tile sizes that are considered by `tune`
+const ROW_TILES: [usize; 3] = [1 << 12, 1 << 14, 1 << 16];
+
+/// The column block sizes that are considered by `tune`
+const COLUMN_BLOCKS: [usize; 3] = [1, 4, 8];
+
+/// The loop structure used for computing responses
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct RespondConfig {
+  /// The number of rows processed per tile, or `None` for all rows
+  pub row_tile: Option<usize>,
+  /// The number of columns processed per pass over a tile (at least 1)
+  pub column_block: usize,
+}
+
+impl RespondConfig {
+  /// Returns whether this is the configuration that computes one inner
+  /// product per column, which `DatabaseMatrix::vec_mult` implements
+  pub fn is_untiled(&self) -> bool {
+    self.row_tile.is_none() && self.column_block <= 1
+  }
+}
+
+/// Computes the response columns to query `q` using `config`
+pub fn respond_columns<T: DatabaseMatrix + ?Sized>(
+  db: &T,
+  q: &[u32],
+  config: RespondConfig,
+) -> ResultBoxedError<Vec<u32>> {
+  let width = db.get_row_width_self();
+  if config.is_untiled() {
+    return Ok((0..width).map(|i| db.vec_mult(q, i)).collect());
+  }
+  let height = db.get_matrix_height();
+  if q.len() != height {
+    return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+      "query length: {}, matrix height: {}",
+      q.len(),
+      height
+    ))));
+  }
+  let tile = config.row_tile.unwrap_or(height).max(1);
+  let block = config.column_block.max(1);
+  let cols: Vec<&[u32]> = (0..width).map(|i| db.get_row_slice(i)).collect();
+  let mut acc = vec![0u32; width];
+  for start in (0..height).step_by(tile) {
+    let end = (start + tile).min(height);
+    let q_tile = &q[start..end];
+    for (block_cols, block_acc) in cols.chunks(block).zip(acc.chunks_mut(block))
+    {
+      for (j, &q_j) in q_tile.iter().enumerate() {
+        for (col, a) in block_cols.iter().zip(block_acc.iter_mut()) {
+          *a = a.wrapping_add(q_j.wrapping_mul(col[start + j]));
+        }
+      }
+    }
+  }
+  Ok(acc)
+}
+
+/// Times each candidate configuration on `TUNE_QUERIES` random queries
+/// and returns the fastest one. Configurations whose results differ from
+/// the untiled computation are discarded.
+pub fn tune<T: DatabaseMatrix + ?Sized>(
+  db: &T,
+) -> ResultBoxedError<RespondConfig> {
+  let height = db.get_matrix_height();
+  let queries: Vec<Vec<u32>> = (0..TUNE_QUERIES)
+    .map(|_| (0..height).map(|_| OsRng.next_u32()).collect())
+    .collect();
+  let baseline = RespondConfig::default();
+  let expected = queries
+    .iter()
+    .map(|q| respond_columns(db, q, baseline))
+    .collect::<ResultBoxedError<Vec<Vec<u32>>>>()?;
+
+  let mut candidates = vec![baseline];
+  for &column_block in &COLUMN_BLOCKS {
+    candidates.push(RespondConfig {
+      row_tile: None,
+      column_block,
+    });
+    for &row_tile in ROW_TILES.iter().filter(|&&t| t < height) {
+      candidates.push(RespondConfig {
+        row_tile: Some(row_tile),
+        column_block,
+      });
+    }
+  }
+  candidates.dedup();
+
+  let mut best = (baseline, Duration::MAX);
+  for config in candidates {
+    let start = Instant::now();
+    let mut matches = true;
+    for (q, exp) in queries.iter().zip(&expected) {
+      matches &= &respond_columns(db, q, config)? == exp;
+    }
+    let elapsed = start.elapsed();
+    if matches && elapsed < best.1 {
+      best = (config, elapsed);
+    }
+  }
+  Ok(best.0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::*;
+
+  #[test]
+  fn tuned_configs_match_untiled_responses() {
+    let m = 2u32.pow(13) as usize;
+    let elem_size = 2u32.pow(7) as usize;
+    let db_eles: Vec<String> = (0..m)
+      .map(|_| {
+        let mut ele = vec![0u8; elem_size / 8];
+        OsRng.fill_bytes(&mut ele);
+        base64::encode(ele)
+      })
+      .collect();
+    let mut shard =
+      Shard::from_base64_strings(&db_eles, 512, m, elem_size, 10).unwrap();
+    let q: Vec<u32> = (0..m).map(|_| OsRng.next_u32()).collect();
+    let expected =
+      respond_columns(shard.get_db(), &q, RespondConfig::default()).unwrap();
+    for (row_tile, column_block) in
+      [(None, 4), (Some(1 << 12), 1), (Some(1000), 8)]
+    {
+      let config = RespondConfig {
+        row_tile,
+        column_block,
+      };
+      assert_eq!(
+        respond_columns(shard.get_db(), &q, config).unwrap(),
+        expected
+      );
+    }
+    assert!(respond_columns(
+      shard.get_db(),
+      &q[1..],
+      RespondConfig {
+        row_tile: Some(1 << 12),
+        column_block: 1,
+      }
+    )
+    .is_err());
+
+    shard.tune().unwrap();
+    let bp = shard.get_base_params();
+    let mut qp =
+      generate_index_query_params(&CommonParams::from(bp), bp).unwrap();
+    let query = qp.generate_query(17).unwrap();
+    let resp: Response =
+      bincode::deserialize(&shard.respond(&query).unwrap()).unwrap();
+    assert_eq!(qp.parse_resp_as_base64(&resp), db_eles[17]);
+  }
+}
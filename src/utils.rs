@@ -3,33 +3,303 @@
 
 /// Functionality specific to the LWE setup that is used
 pub mod lwe {
-  const MODULUS: u64 = u32::MAX as u64 + 1;
+  use rand_core::{OsRng, RngCore};
+  use serde::{Deserialize, Serialize};
+
+  use crate::errors::{ErrorUnexpectedInputSize, ResultBoxedError};
+  use crate::protocol::constants;
+  use crate::protocol::constants::MAX_MODULUS_BITS;
 
-  /// Returns a value indicating the indicator value which is used to reveal
-  /// the DB row that is queried.
-  pub fn get_rounding_factor(plaintext_bits: usize) -> u32 {
-    (MODULUS / get_plaintext_size(plaintext_bits) as u64) as u32
+  /// The ciphertext modulus q = 2^bits of the scheme.
+  ///
+  /// All arithmetic is performed on wrapping u32 values, i.e. modulo
+  /// 2^32, which is consistent modulo any smaller power of two. A smaller
+  /// modulus therefore only changes how queries and residues are reduced
+  /// and rounded, and allows encodings of queries and responses that use
+  /// `bits` bits per entry (see `Query::to_packed_bytes`).
+  ///
+  /// Moduli above 2^32 (up to 2^64) are not supported: they would require
+  /// u64 entries in the DB matrix, queries, responses and their wire
+  /// formats, and are rejected with an error saying so.
+  #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+  #[serde(try_from = "u32", into = "u32")]
+  pub struct Modulus {
+    bits: u32,
+  }
+
+  impl Default for Modulus {
+    fn default() -> Self {
+      Self {
+        bits: MAX_MODULUS_BITS,
+      }
+    }
   }
 
-  /// This value indicates the bound which indicates whether a bit in the
-  /// row queried to the server is set to 0 (below), or 1 (above).
-  pub fn get_rounding_floor(plaintext_bits: usize) -> u32 {
-    get_rounding_factor(plaintext_bits) / 2
+  impl TryFrom<u32> for Modulus {
+    type Error = String;
+
+    fn try_from(bits: u32) -> Result<Self, Self::Error> {
+      if bits > MAX_MODULUS_BITS && bits <= 64 {
+        return Err(format!(
+          "Modulus 2^{} requires 64-bit arithmetic, which is not \
+           implemented, expected at most 2^{}",
+          bits, MAX_MODULUS_BITS
+        ));
+      }
+      if bits == 0 || bits > MAX_MODULUS_BITS {
+        return Err(format!(
+          "Modulus 2^{} is not supported, expected at most 2^{}",
+          bits, MAX_MODULUS_BITS
+        ));
+      }
+      Ok(Self { bits })
+    }
+  }
+
+  impl From<Modulus> for u32 {
+    fn from(modulus: Modulus) -> u32 {
+      modulus.bits
+    }
+  }
+
+  impl Modulus {
+    /// Returns the modulus 2^bits
+    pub fn new(bits: u32) -> ResultBoxedError<Self> {
+      Ok(Self::try_from(bits).map_err(ErrorUnexpectedInputSize::new)?)
+    }
+
+    /// Returns the number of bits of the modulus
+    pub fn bits(&self) -> u32 {
+      self.bits
+    }
+
+    /// Returns the value of the modulus
+    pub fn value(&self) -> u64 {
+      1u64 << self.bits
+    }
+
+    /// Returns whether this is the default modulus 2^32
+    pub fn is_default(&self) -> bool {
+      self.bits == MAX_MODULUS_BITS
+    }
+
+    /// Checks that `plaintext_bits` leaves room for noise below the
+    /// modulus
+    pub fn check_plaintext_bits(
+      &self,
+      plaintext_bits: usize,
+    ) -> ResultBoxedError<()> {
+      if plaintext_bits >= self.bits as usize {
+        return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+          "{} plaintext bits do not fit below the modulus 2^{}",
+          plaintext_bits, self.bits
+        ))));
+      }
+      Ok(())
+    }
+
+    /// Reduces `x` modulo the modulus
+    pub fn reduce(&self, x: u32) -> u32 {
+      (x as u64 % self.value()) as u32
+    }
+
+    /// Adds `a` and `b` modulo the modulus, returning whether the sum
+    /// wrapped around
+    pub fn overflowing_add(&self, a: u32, b: u32) -> (u32, bool) {
+      let sum = a as u64 + b as u64;
+      ((sum % self.value()) as u32, sum >= self.value())
+    }
+
+    /// Returns the scaling factor q / 2^plaintext_bits that encodes
+    /// plaintext values, and indicates the queried row
+    pub fn rounding_factor(&self, plaintext_bits: usize) -> u32 {
+      constants::rounding_factor(self.bits, plaintext_bits)
+    }
+
+    /// Returns half of the rounding factor
+    pub fn rounding_floor(&self, plaintext_bits: usize) -> u32 {
+      constants::rounding_floor(self.bits, plaintext_bits)
+    }
+
+    /// Rounds an unscaled residue to a plaintext value, using the
+    /// specified `RoundingMode`
+    pub fn round_residue(
+      &self,
+      unscaled_res: u32,
+      plaintext_bits: usize,
+      mode: RoundingMode,
+    ) -> u32 {
+      let unscaled_res = self.reduce(unscaled_res);
+      let rounding_factor = self.rounding_factor(plaintext_bits);
+      let scaled_res = unscaled_res / rounding_factor;
+      let scaled_rem = unscaled_res % rounding_factor;
+      let round_up = match mode {
+        RoundingMode::Nearest => {
+          scaled_rem > self.rounding_floor(plaintext_bits)
+        }
+        RoundingMode::Floor => false,
+        RoundingMode::Randomized => {
+          OsRng.next_u32() % rounding_factor < scaled_rem
+        }
+      };
+      let mut rounded_res = scaled_res;
+      if round_up {
+        rounded_res += 1;
+      }
+      rounded_res % get_plaintext_size(plaintext_bits)
+    }
+
+    /// Same as `round_residue`, for a row of unscaled residues. Since the
+    /// modulus and the plaintext space are powers of two, the division
+    /// and remainder of `round_residue` reduce to a shift and masks,
+    /// which the compiler vectorizes for nearest and floor rounding.
+    /// Randomized rounding samples every slot, and is not vectorized.
+    pub fn round_residues(
+      &self,
+      unscaled: &[u32],
+      plaintext_bits: usize,
+      mode: RoundingMode,
+    ) -> Vec<u32> {
+      let offset = match mode {
+        // remainders above the rounding floor carry into the next value
+        RoundingMode::Nearest => {
+          self.rounding_factor(plaintext_bits)
+            - self.rounding_floor(plaintext_bits)
+            - 1
+        }
+        RoundingMode::Floor => 0,
+        RoundingMode::Randomized => {
+          return unscaled
+            .iter()
+            .map(|&r| self.round_residue(r, plaintext_bits, mode))
+            .collect()
+        }
+      };
+      let shift = self.bits - plaintext_bits as u32;
+      let modulus_mask = (self.value() - 1) as u32;
+      let plaintext_mask = get_plaintext_size(plaintext_bits) - 1;
+      // a carry out of the top plaintext value wraps modulo 2^32 for the
+      // default modulus, and is masked for smaller ones, which both
+      // round to zero
+      unscaled
+        .iter()
+        .map(|&r| {
+          ((r & modulus_mask).wrapping_add(offset) >> shift) & plaintext_mask
+        })
+        .collect()
+    }
+
+    /// Returns the distance of an unscaled residue to the closest
+    /// boundary at which nearest rounding would flip to a different
+    /// plaintext value
+    pub fn rounding_margin(
+      &self,
+      unscaled_res: u32,
+      plaintext_bits: usize,
+    ) -> u32 {
+      let scaled_rem =
+        self.reduce(unscaled_res) % self.rounding_factor(plaintext_bits);
+      self.rounding_floor(plaintext_bits).abs_diff(scaled_rem)
+    }
+  }
+
+  /// The strategy used for rounding an unscaled residue (i.e. the
+  /// plaintext scaled by the rounding factor, plus noise) back to the
+  /// plaintext space
+  #[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+  )]
+  pub enum RoundingMode {
+    /// Rounds to the closest multiple of the rounding factor
+    #[default]
+    Nearest,
+    /// Always rounds down to the previous multiple of the rounding factor
+    Floor,
+    /// Rounds up with probability proportional to the remainder
+    Randomized,
   }
 
   /// Returns the modulus for the plaintext space
   pub fn get_plaintext_size(plaintext_bits: usize) -> u32 {
-    2u32.pow(plaintext_bits as u32)
+    constants::plaintext_size(plaintext_bits)
+  }
+}
+
+/// Overflow checks for the `debug-arith` feature. Arithmetic modulo 2^32
+/// wraps by design, so only overflows that indicate a parameter bug are
+/// checked. Without the feature every check compiles to nothing.
+pub mod arith {
+  use super::lwe::get_plaintext_size;
+
+  /// Asserts that every entry of the DB column `col_idx` is below the
+  /// plaintext modulus, since larger entries overflow into the
+  /// neighbouring plaintext value when the response is rounded
+  #[inline]
+  pub fn check_column_entries(
+    col: &[u32],
+    col_idx: usize,
+    plaintext_bits: usize,
+  ) {
+    if !cfg!(feature = "debug-arith") {
+      return;
+    }
+    let plaintext_size = get_plaintext_size(plaintext_bits);
+    if let Some((row_idx, entry)) =
+      col.iter().enumerate().find(|(_, &x)| x >= plaintext_size)
+    {
+      panic!(
+        "DB entry {} at column {}, row {} exceeds the plaintext modulus 2^{}",
+        entry, col_idx, row_idx, plaintext_bits
+      );
+    }
+  }
+
+  /// Asserts that a query row and the DB column `col_idx` have the same
+  /// length, since the inner product would otherwise silently ignore
+  /// entries of the longer one
+  #[inline]
+  pub fn check_mult_lengths(row: &[u32], col: &[u32], col_idx: usize) {
+    if cfg!(feature = "debug-arith") && row.len() != col.len() {
+      panic!(
+        "Query of length {} does not match column {} of length {}",
+        row.len(),
+        col_idx,
+        col.len()
+      );
+    }
+  }
+
+  /// Asserts that a response has at least as many columns as the RHS
+  /// matrix of the public parameters that it is decoded with (responses
+  /// may be padded, see `ShardCluster`)
+  #[inline]
+  pub fn check_response_width(resp_len: usize, rhs_len: usize) {
+    if cfg!(feature = "debug-arith") && resp_len < rhs_len {
+      panic!(
+        "Response has {} columns, but the public parameters have {}",
+        resp_len, rhs_len
+      );
+    }
   }
 }
 
 /// Functionality for matrix and vector manipulation
 pub mod matrices {
+  use memmap2::Mmap;
   use rand::rngs::StdRng;
   use rand_core::{OsRng, RngCore, SeedableRng};
+  use serde::{Deserialize, Deserializer, Serialize, Serializer};
+  use std::fmt;
+  use std::ops::Index;
+  use std::sync::Arc;
+
+  use super::files::FlatMatrix;
 
   use crate::errors::ErrorUnexpectedInputSize;
   use crate::errors::ResultBoxedError;
+  use crate::protocol::constants::{
+    TERNARY_INTERVAL_SIZE, TERNARY_MINUS_ONE, TERNARY_REJECTION_SAMPLING_MAX,
+  };
 
   /// Takes a matrix and returns the [*][i] elements
   /// equivalent to `swap_matrix_fmt(xys)[i]`, but much faster
@@ -38,11 +308,12 @@ pub mod matrices {
   }
 
   /// Takes a matrix in row (column) format, and returns it in column (row) format
-  pub fn swap_matrix_fmt(matrix: &[Vec<u32>]) -> Vec<Vec<u32>> {
+  pub fn swap_matrix_fmt<V: AsRef<[u32]>>(matrix: &[V]) -> Vec<Vec<u32>> {
     let height = matrix.len();
-    let width = matrix[0].len(); // assumes all entries are the same size
+    let width = matrix[0].as_ref().len(); // assumes all entries are the same size
     let mut swapped_row = vec![Vec::with_capacity(height); width];
     for current_row in matrix {
+      let current_row = current_row.as_ref();
       for i in 0..width {
         swapped_row[i].push(current_row[i]);
       }
@@ -68,7 +339,164 @@ pub mod matrices {
     lhs
   }
 
-  /// Multiplies a u32 vector with a u32 column vector
+  /// The layout of a flat LWE matrix (see `generate_lwe_matrix_flat`)
+  #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+  pub enum FillOrder {
+    /// Entry `[i][j]` (of row `i < lwe_dim`, column `j < width`) is at
+    /// `i * width + j`
+    RowMajor,
+    /// Entry `[i][j]` is at `j * lwe_dim + i`, i.e. the layout of the
+    /// flattened output of `generate_lwe_matrix_from_seed`
+    ColumnMajor,
+  }
+
+  /// Generates the same LWE matrix as `generate_lwe_matrix_from_seed`,
+  /// into a single buffer of `lwe_dim * width` entries laid out in
+  /// `order`. Column-major filling writes the RNG output sequentially,
+  /// whereas row-major filling writes it with a stride of `width`.
+  pub fn generate_lwe_matrix_flat(
+    seed: [u8; 32],
+    lwe_dim: usize,
+    width: usize,
+    order: FillOrder,
+  ) -> Vec<u32> {
+    let mut rng = get_seeded_rng(seed);
+    let mut flat = vec![0u32; lwe_dim * width];
+    match order {
+      FillOrder::ColumnMajor => {
+        for v in flat.iter_mut() {
+          *v = rng.next_u32();
+        }
+      }
+      FillOrder::RowMajor => {
+        for j in 0..width {
+          for i in 0..lwe_dim {
+            flat[i * width + j] = rng.next_u32();
+          }
+        }
+      }
+    }
+    flat
+  }
+
+  /// The columns of a DB matrix, either held in memory or borrowed from a
+  /// memory-mapped snapshot (see `Shard::load_snapshot`). Mapped columns
+  /// are served straight from the page cache, and only copied when the
+  /// matrix is modified.
+  #[derive(Clone)]
+  pub enum ColumnMatrix {
+    Owned(Vec<Vec<u32>>),
+    Mapped { map: Arc<Mmap>, matrix: FlatMatrix },
+  }
+  impl ColumnMatrix {
+    /// Borrows the columns of `matrix` from `map`, or copies them if they
+    /// are not aligned to u32s or the platform is not little-endian
+    pub fn mapped(map: Arc<Mmap>, matrix: FlatMatrix) -> Self {
+      let bytes = &map[matrix.offset()..matrix.end()];
+      if cfg!(target_endian = "little")
+        && bytemuck::try_cast_slice::<u8, u32>(bytes).is_ok()
+      {
+        return Self::Mapped { map, matrix };
+      }
+      Self::Owned(matrix.read_vecs(&map))
+    }
+
+    /// Returns the number of columns
+    pub fn len(&self) -> usize {
+      match self {
+        Self::Owned(columns) => columns.len(),
+        Self::Mapped { matrix, .. } => matrix.count(),
+      }
+    }
+
+    /// Returns the ith column. Panics if there is no ith column.
+    pub fn column(&self, i: usize) -> &[u32] {
+      match self {
+        Self::Owned(columns) => &columns[i],
+        Self::Mapped { map, matrix } => {
+          bytemuck::cast_slice(&map[matrix.vector_range(i)])
+        }
+      }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[u32]> {
+      (0..self.len()).map(move |i| self.column(i))
+    }
+
+    /// Returns the columns as slices, e.g. for `swap_matrix_fmt`
+    pub fn slices(&self) -> Vec<&[u32]> {
+      self.iter().collect()
+    }
+
+    /// Copies the columns into memory
+    pub fn to_vecs(&self) -> Vec<Vec<u32>> {
+      match self {
+        Self::Owned(columns) => columns.clone(),
+        Self::Mapped { .. } => self.iter().map(<[u32]>::to_vec).collect(),
+      }
+    }
+  }
+  impl Default for ColumnMatrix {
+    fn default() -> Self {
+      Self::Owned(Vec::new())
+    }
+  }
+  impl From<Vec<Vec<u32>>> for ColumnMatrix {
+    fn from(columns: Vec<Vec<u32>>) -> Self {
+      Self::Owned(columns)
+    }
+  }
+  impl Index<usize> for ColumnMatrix {
+    type Output = [u32];
+
+    fn index(&self, i: usize) -> &[u32] {
+      self.column(i)
+    }
+  }
+  impl fmt::Debug for ColumnMatrix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      f.debug_list().entries(self.iter()).finish()
+    }
+  }
+  impl Serialize for ColumnMatrix {
+    fn serialize<S: Serializer>(
+      &self,
+      serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+      serializer.collect_seq(self.iter())
+    }
+  }
+  impl<'de> Deserialize<'de> for ColumnMatrix {
+    fn deserialize<D: Deserializer<'de>>(
+      deserializer: D,
+    ) -> Result<Self, D::Error> {
+      Vec::deserialize(deserializer).map(Self::Owned)
+    }
+  }
+
+  /// Reads one value from every memory page backing `matrix`, so that
+  /// the pages are resident before the matrix is used
+  pub fn touch_matrix_pages<V: AsRef<[u32]>>(matrix: &[V]) {
+    const PAGE_VALUES: usize = 4096 / std::mem::size_of::<u32>();
+    for row in matrix {
+      let row = row.as_ref();
+      for i in (0..row.len()).step_by(PAGE_VALUES) {
+        std::hint::black_box(row[i]);
+      }
+      if let Some(last) = row.last() {
+        std::hint::black_box(*last);
+      }
+    }
+  }
+
+  /// Multiplies a u32 vector with a u32 column vector.
+  ///
+  /// The products and their sum wrap around modulo 2^32, which is the
+  /// ciphertext modulus of the scheme (or a multiple of it, see
+  /// `Modulus`), so the result is the exact inner product modulo 2^32.
+  /// Wrapping is therefore intended and never loses information that
+  /// decoding relies on: whether a response decodes correctly depends
+  /// only on the noise bound of the parameters, not on overflow.
   pub fn vec_mult_u32_u32(row: &[u32], col: &[u32]) -> ResultBoxedError<u32> {
     if row.len() != col.len() {
       //panic!("row_len: {}, col_len: {}", row.len(), col.len());
@@ -87,30 +515,23 @@ pub mod matrices {
   }
 
   /// Returns a seeded RNG for sampling values
-  fn get_seeded_rng(s: [u8; 32]) -> StdRng {
+  pub fn get_seeded_rng(s: [u8; 32]) -> StdRng {
     StdRng::from_seed(s)
   }
 
-  // Values used to denote the size of intervals that are used for
-  // sampling ternary values, and a max bound that dictates when
-  // randomly sampled values should be rejected.
-  const TERNARY_INTERVAL_SIZE: u32 = (u32::MAX - 2) / 3;
-  // Note `TERNARY_REJECTION_SAMPLING_MAX ≠ u32::MAX`
-  const TERNARY_REJECTION_SAMPLING_MAX: u32 = TERNARY_INTERVAL_SIZE * 3;
-
-  /// Simulates a ternary error by sampling randomly, using rejection
-  /// sampling, from {0,1,u32::MAX} which is equivalent to {0,1,-1} when
-  /// performing modular reduction.
-  pub fn random_ternary() -> u32 {
+  /// Simulates a ternary error by sampling randomly from `rng`, using
+  /// rejection sampling, from {0,1,u32::MAX} which is equivalent to
+  /// {0,1,-1} when performing modular reduction.
+  pub fn random_ternary_from<R: RngCore>(rng: &mut R) -> u32 {
     // We need to do rejection sampling for sampling randomly from 3
     // possible values: we first divide the full interval by 3, noting
     // that rounding is performed to the next _lowest_ integer.
-    let mut val = OsRng.next_u32();
+    let mut val = rng.next_u32();
     // If the value sampled sits in the interval:
     //                `interval*3 < val < U32::MAX`
     // then we need to reject it and resample until it firs below `interval*3`
     while val > TERNARY_REJECTION_SAMPLING_MAX {
-      val = OsRng.next_u32();
+      val = rng.next_u32();
     }
     // Now we return {0,1,-1} depending on whether the sampled value
     // sits in the first, second or third sampling interval
@@ -118,7 +539,7 @@ pub mod matrices {
     if val > TERNARY_INTERVAL_SIZE && val <= TERNARY_INTERVAL_SIZE * 2 {
       tern = 1;
     } else if val > TERNARY_INTERVAL_SIZE * 2 {
-      tern = u32::MAX;
+      tern = TERNARY_MINUS_ONE;
     }
     tern
   }
@@ -126,9 +547,18 @@ pub mod matrices {
   /// Simulates a ternary error vector of width size by sampling randomly,
   /// using rejection sampling, from {0,1,u32::MAX}
   pub fn random_ternary_vector(width: usize) -> Vec<u32> {
+    random_ternary_vector_from(&mut OsRng, width)
+  }
+
+  /// Simulates a ternary error vector as in `random_ternary_vector`,
+  /// using `rng` as the source of randomness
+  pub fn random_ternary_vector_from<R: RngCore>(
+    rng: &mut R,
+    width: usize,
+  ) -> Vec<u32> {
     let mut row = Vec::new();
     for _ in 0..width {
-      row.push(random_ternary());
+      row.push(random_ternary_from(rng));
     }
     row
   }
@@ -136,93 +566,543 @@ pub mod matrices {
 
 pub mod random {
   use rand_core::{OsRng, RngCore};
+  use sha2::{Digest, Sha256};
 
   pub fn generate_seed() -> [u8; 32] {
+    generate_seed_from(&mut OsRng)
+  }
+
+  /// Samples a seed from `rng`
+  pub fn generate_seed_from<R: RngCore + ?Sized>(rng: &mut R) -> [u8; 32] {
     let mut seed = [0u8; 32];
-    OsRng.fill_bytes(&mut seed);
+    rng.fill_bytes(&mut seed);
     seed
   }
+
+  /// Derives an independent seed for the purpose given by `label`
+  pub fn derive_seed(seed: [u8; 32], label: &[u8]) -> [u8; 32] {
+    Sha256::new()
+      .chain_update(label)
+      .chain_update(seed)
+      .finalize()
+      .into()
+  }
+}
+
+/// Functionality related to reading crate inputs from the file system
+pub mod files {
+  use rayon::prelude::*;
+  use serde::de::DeserializeOwned;
+  use serde::{Deserialize, Serialize};
+  use serde_json::value::RawValue;
+  use serde_json::Value;
+  use sha2::{Digest, Sha256};
+  use std::fs;
+  use std::io::{BufReader, BufWriter, Write};
+  use std::ops::Range;
+
+  use crate::errors::{ErrorLoadFile, ResultBoxedError};
+
+  /// Identifies files written by this crate
+  pub const FILE_MAGIC: &str = "chalamet-pir";
+
+  /// The version of the format of persisted files. Files of other
+  /// versions are rejected on load.
+  pub const FILE_VERSION: u32 = 1;
+
+  /// The header that precedes the body of every persisted file. It
+  /// records the kind of the body (e.g. `IndexDatabase`), a summary of its
+  /// parameters, and the SHA-256 digest of the kind, summary and body, so
+  /// that loading a file of another kind, version or a modified file fails
+  /// instead of producing garbage.
+  #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+  pub struct FileHeader {
+    pub magic: String,
+    pub version: u32,
+    pub kind: String,
+    pub summary: Value,
+    pub checksum: [u8; 32],
+  }
+
+  impl FileHeader {
+    fn checksum(kind: &str, summary: &Value, body: &str) -> [u8; 32] {
+      let mut hasher = Sha256::new();
+      for part in [kind, &summary.to_string(), body] {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+      }
+      hasher.finalize().into()
+    }
+  }
+
+  /// A type that is persisted with a `FileHeader`
+  pub trait Persist: Serialize + DeserializeOwned {
+    /// The kind recorded in the header
+    const KIND: &'static str;
+    /// Summarizes the parameters of the value, e.g. its dimensions. The
+    /// summary in the header must match the summary of the loaded value.
+    fn summary(&self) -> Value;
+  }
+
+  #[derive(Serialize)]
+  struct FileOut<'a> {
+    header: FileHeader,
+    body: &'a RawValue,
+  }
+
+  #[derive(Deserialize)]
+  struct FileIn {
+    header: FileHeader,
+    body: Box<RawValue>,
+  }
+
+  /// Writes `value` to the file at `path`, preceded by a `FileHeader`
+  pub fn write_persisted<T: Persist>(
+    path: &str,
+    value: &T,
+  ) -> ResultBoxedError<()> {
+    let body = RawValue::from_string(serde_json::to_string(value)?)?;
+    let summary = value.summary();
+    let header = FileHeader {
+      magic: FILE_MAGIC.to_string(),
+      version: FILE_VERSION,
+      kind: T::KIND.to_string(),
+      checksum: FileHeader::checksum(T::KIND, &summary, body.get()),
+      summary,
+    };
+    let mut writer = BufWriter::new(fs::File::create(path)?);
+    serde_json::to_writer(
+      &mut writer,
+      &FileOut {
+        header,
+        body: &body,
+      },
+    )?;
+    Ok(writer.flush()?)
+  }
+
+  /// Reads a value written using `write_persisted`, validating the header.
+  ///
+  /// Files written before the header was introduced hold the bare JSON
+  /// of the value. They carry no checksum, so they are only checked by
+  /// deserializing the value, and are migrated by persisting the value
+  /// again.
+  pub fn read_persisted<T: Persist>(path: &str) -> Result<T, ErrorLoadFile> {
+    let bytes = fs::read(path).map_err(|e| ErrorLoadFile::from_io(path, &e))?;
+    parse_persisted(path, &bytes)
+  }
+
+  /// Same as `read_persisted`, for the `bytes` of a file read from
+  /// `source`
+  pub fn parse_persisted<T: Persist>(
+    source: &str,
+    bytes: &[u8],
+  ) -> Result<T, ErrorLoadFile> {
+    match serde_json::from_slice::<FileIn>(bytes) {
+      Ok(file) => validate_persisted(source, file),
+      Err(e) => serde_json::from_slice::<T>(bytes)
+        .map_err(|_| ErrorLoadFile::from_json(source, &e)),
+    }
+  }
+
+  /// Fetches a value written using `write_persisted` from the object
+  /// store addressed by `url` (e.g. `s3://bucket/db.json`), validating the
+  /// header. The store is configured with `options`, e.g.
+  /// `("aws_region", "eu-west-1")` (see `object_store::parse_url_opts`).
+  #[cfg(feature = "object-store")]
+  pub fn fetch_persisted<T: Persist>(
+    url: &str,
+    options: &[(&str, &str)],
+  ) -> ResultBoxedError<T> {
+    let (store, path) = object_store::parse_url_opts(
+      &url::Url::parse(url)?,
+      options.iter().copied(),
+    )?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+      .enable_all()
+      .build()?;
+    let bytes =
+      runtime.block_on(async { store.get(&path).await?.bytes().await })?;
+    Ok(parse_persisted(url, &bytes)?)
+  }
+
+  fn validate_persisted<T: Persist>(
+    path: &str,
+    file: FileIn,
+  ) -> Result<T, ErrorLoadFile> {
+    let header = file.header;
+    let invalid = |details: String| ErrorLoadFile::new(path, details);
+    if header.magic != FILE_MAGIC {
+      return Err(invalid(format!("unknown file magic {}", header.magic)));
+    }
+    if header.version != FILE_VERSION {
+      return Err(invalid(format!(
+        "unsupported file version {} (expected {})",
+        header.version, FILE_VERSION
+      )));
+    }
+    if header.kind != T::KIND {
+      return Err(invalid(format!(
+        "file holds a {}, expected a {}",
+        header.kind,
+        T::KIND
+      )));
+    }
+    let checksum =
+      FileHeader::checksum(&header.kind, &header.summary, file.body.get());
+    if checksum != header.checksum {
+      return Err(invalid("checksum mismatch".to_string()));
+    }
+    let value: T = serde_json::from_str(file.body.get())
+      .map_err(|e| ErrorLoadFile::from_json(path, &e))?;
+    if value.summary() != header.summary {
+      return Err(invalid("summary does not match the body".to_string()));
+    }
+    Ok(value)
+  }
+
+  /// The length of the header of a matrix written using
+  /// `write_flat_matrix`
+  pub const FLAT_MATRIX_HEADER_LEN: usize = 16;
+
+  /// Writes `matrix` in a flat layout: the number of vectors and their
+  /// length as little-endian u64s, followed by the entries of each vector
+  /// as little-endian u32s
+  pub fn write_flat_matrix<W: Write, V: AsRef<[u32]>>(
+    w: &mut W,
+    matrix: &[V],
+  ) -> std::io::Result<()> {
+    let len = matrix.first().map_or(0, |v| v.as_ref().len());
+    w.write_all(&(matrix.len() as u64).to_le_bytes())?;
+    w.write_all(&(len as u64).to_le_bytes())?;
+    let mut buf = Vec::with_capacity(4 * len);
+    for v in matrix {
+      let v = v.as_ref();
+      if v.len() != len {
+        return Err(std::io::Error::new(
+          std::io::ErrorKind::InvalidInput,
+          "vectors of the matrix differ in length",
+        ));
+      }
+      buf.clear();
+      v.iter()
+        .for_each(|x| buf.extend_from_slice(&x.to_le_bytes()));
+      w.write_all(&buf)?;
+    }
+    Ok(())
+  }
+
+  /// The position of the entries of a matrix written using
+  /// `write_flat_matrix` within a buffer
+  #[derive(Clone, Copy, Debug)]
+  pub struct FlatMatrix {
+    offset: usize,
+    count: usize,
+    len: usize,
+  }
+  impl FlatMatrix {
+    /// Parses the header of the matrix at `offset` of `bytes`. Returns
+    /// `None` if `bytes` does not hold the header and all the entries it
+    /// claims, so corrupted lengths never cause out-of-bounds reads or
+    /// large allocations.
+    pub fn parse(bytes: &[u8], offset: usize) -> Option<Self> {
+      let header =
+        bytes.get(offset..offset.checked_add(FLAT_MATRIX_HEADER_LEN)?)?;
+      let count = u64::from_le_bytes(header[..8].try_into().ok()?);
+      let len = u64::from_le_bytes(header[8..].try_into().ok()?);
+      let matrix = Self {
+        offset: offset + FLAT_MATRIX_HEADER_LEN,
+        count: usize::try_from(count).ok()?,
+        len: usize::try_from(len).ok()?,
+      };
+      let size = matrix.count.checked_mul(matrix.len)?.checked_mul(4)?;
+      (size <= bytes.len() - matrix.offset).then_some(matrix)
+    }
+
+    /// Returns the offset of the first entry
+    pub fn offset(&self) -> usize {
+      self.offset
+    }
+
+    /// Returns the offset following the last entry
+    pub fn end(&self) -> usize {
+      self.offset + 4 * self.count * self.len
+    }
+
+    /// Returns the number of vectors
+    pub fn count(&self) -> usize {
+      self.count
+    }
+
+    /// Returns the byte range of the ith vector
+    pub fn vector_range(&self, i: usize) -> Range<usize> {
+      let start = self.offset + 4 * i * self.len;
+      start..start + 4 * self.len
+    }
+
+    /// Copies the vectors out of `bytes`, which must be the buffer the
+    /// matrix was parsed from
+    pub fn read_vecs(self, bytes: &[u8]) -> Vec<Vec<u32>> {
+      (0..self.count)
+        .map(|i| {
+          bytes[self.vector_range(i)]
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+        })
+        .collect()
+    }
+  }
+
+  /// Deserializes JSON from the file at `path`, returning an error that
+  /// records the path (and position, for malformed JSON) on failure
+  pub fn read_json_file<T: DeserializeOwned>(
+    path: &str,
+  ) -> Result<T, ErrorLoadFile> {
+    let file =
+      fs::File::open(path).map_err(|e| ErrorLoadFile::from_io(path, &e))?;
+    serde_json::from_reader(BufReader::new(file))
+      .map_err(|e| ErrorLoadFile::from_json(path, &e))
+  }
+
+  /// Deserializes a line-delimited JSON file, holding one record per
+  /// line, from `path`. Lines are parsed in parallel on the current rayon
+  /// thread pool, and blank lines are skipped. On failure, the error
+  /// records the line of the first malformed record.
+  pub fn read_jsonl_file<T: DeserializeOwned + Send>(
+    path: &str,
+  ) -> Result<Vec<T>, ErrorLoadFile> {
+    let bytes = fs::read(path).map_err(|e| ErrorLoadFile::from_io(path, &e))?;
+    let lines: Vec<(usize, &[u8])> = bytes
+      .split(|&b| b == b'\n')
+      .enumerate()
+      .filter(|(_, line)| !line.iter().all(u8::is_ascii_whitespace))
+      .collect();
+    lines
+      .par_iter()
+      .map(|&(i, line)| {
+        serde_json::from_slice(line)
+          .map_err(|e| ErrorLoadFile::from_json_line(path, i + 1, &e))
+      })
+      .collect()
+  }
 }
 
 /// Functionality related to manipulation of data formats that are used
 pub mod format {
-  use crate::errors::ErrorUnexpectedInputSize;
+  use crate::errors::{ErrorUnexpectedInputSize, ResultBoxedError};
+  use crate::protocol::constants::MAX_MODULUS_BITS;
+  use serde::{Deserialize, Serialize};
   use sha2::{Digest, Sha256};
   use std::convert::TryInto;
 
-  fn u8_to_bits_le(byte: u8) -> Vec<bool> {
-    let mut ret = Vec::new();
-    for i in 0..8 {
-      ret.push(2u8.pow(i as u32) & byte > 0);
+  /// The text encoding used for serialized DB elements and decoded
+  /// records. The crate historically used standard, padded base64.
+  #[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+  )]
+  pub enum EncodingConfig {
+    /// Standard base64 alphabet with padding
+    #[default]
+    Standard,
+    /// Standard base64 alphabet without padding
+    StandardNoPad,
+    /// URL-safe base64 alphabet with padding
+    UrlSafe,
+    /// URL-safe base64 alphabet without padding
+    UrlSafeNoPad,
+    /// Lowercase hexadecimal (decoding accepts either case)
+    Hex,
+  }
+
+  impl EncodingConfig {
+    /// All supported encodings
+    pub const ALL: [EncodingConfig; 5] = [
+      Self::Standard,
+      Self::StandardNoPad,
+      Self::UrlSafe,
+      Self::UrlSafeNoPad,
+      Self::Hex,
+    ];
+
+    fn base64_config(&self) -> Option<base64::Config> {
+      match self {
+        Self::Standard => Some(base64::STANDARD),
+        Self::StandardNoPad => Some(base64::STANDARD_NO_PAD),
+        Self::UrlSafe => Some(base64::URL_SAFE),
+        Self::UrlSafeNoPad => Some(base64::URL_SAFE_NO_PAD),
+        Self::Hex => None,
+      }
+    }
+
+    pub fn encode(&self, bytes: &[u8]) -> String {
+      match self.base64_config() {
+        Some(config) => base64::encode_config(bytes, config),
+        None => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+      }
+    }
+
+    pub fn decode(&self, s: &str) -> ResultBoxedError<Vec<u8>> {
+      if let Some(config) = self.base64_config() {
+        return Ok(base64::decode_config(s, config)?);
+      }
+      s.as_bytes()
+        .chunks(2)
+        .map(|pair| -> ResultBoxedError<u8> {
+          if pair.len() != 2 {
+            return Err(Box::new(ErrorUnexpectedInputSize::new(format!(
+              "Hex string should have even length, but has length {}",
+              s.len()
+            ))));
+          }
+          Ok(u8::from_str_radix(std::str::from_utf8(pair)?, 16)?)
+        })
+        .collect()
     }
-    ret
   }
 
-  pub fn u32_to_bits_le(x: u32, bit_len: usize) -> Vec<bool> {
-    let bytes = x.to_le_bytes();
-    let mut bits = Vec::with_capacity(bytes.len());
-    for byte in bytes {
-      bits.extend(u8_to_bits_le(byte));
+  /// Returns a mask of the `bit_len` least significant bits of a word
+  fn low_bits_mask(bit_len: usize) -> u64 {
+    u32::try_from(bit_len)
+      .ok()
+      .and_then(|b| 1u64.checked_shl(b))
+      .map_or(u64::MAX, |b| b - 1)
+  }
+
+  /// Returns the `bit_len` bits of `bytes` starting at bit `start` (in
+  /// little-endian bit order) as a u32, where bits past the end of `bytes`
+  /// are zero. The bits are read directly from the spanned bytes, without
+  /// expanding them.
+  pub fn read_bits_le(
+    bytes: &[u8],
+    start: usize,
+    bit_len: usize,
+  ) -> Result<u32, ErrorUnexpectedInputSize> {
+    if bit_len > 32 {
+      return Err(ErrorUnexpectedInputSize::new(format!(
+        "bits are too long to parse as u32, length: {}",
+        bit_len
+      )));
     }
-    bits[..bit_len].to_vec()
+    // the spanned bytes fit in a u64, since the first bit is at an offset
+    // of at most 7 bits into the first byte
+    let first = (start / 8).min(bytes.len());
+    let last = start.saturating_add(bit_len).div_ceil(8).min(bytes.len());
+    let word = bytes[first..last]
+      .iter()
+      .enumerate()
+      .fold(0u64, |word, (k, &b)| word | (b as u64) << (8 * k));
+    Ok(((word >> (start % 8)) & low_bits_mask(bit_len)) as u32)
   }
 
+  /// Splits `bytes` into entries of `column_bits[i]` bits each (in
+  /// little-endian bit order), where the last entry holds all of the
+  /// remaining bits. Entries past the end of `bytes` are zero.
+  pub fn split_bits_le(
+    bytes: &[u8],
+    column_bits: &[usize],
+  ) -> Result<Vec<u32>, ErrorUnexpectedInputSize> {
+    let total_bit_len = 8 * bytes.len();
+    let mut start = 0;
+    let mut row = Vec::with_capacity(column_bits.len());
+    for (i, &bit_len) in column_bits.iter().enumerate() {
+      let bit_len = if i == column_bits.len() - 1 {
+        bit_len.max(total_bit_len.saturating_sub(start))
+      } else {
+        bit_len
+      };
+      row.push(read_bits_le(bytes, start, bit_len)?);
+      start += bit_len;
+    }
+    Ok(row)
+  }
+
+  /// Packs the entries of `v`, of which the ith holds `column_bits[i]`
+  /// bits, back to back into bytes (in little-endian bit order), keeping
+  /// at most `total_bit_len` bits. Entries are u32, so bits of a column
+  /// beyond the 32nd are zero.
+  fn pack_bits_le(
+    v: &[u32],
+    column_bits: &[usize],
+    total_bit_len: usize,
+  ) -> Vec<u8> {
+    let column_bit_len =
+      column_bits.iter().fold(0usize, |n, &b| n.saturating_add(b));
+    let packed_bit_len = total_bit_len.min(column_bit_len);
+    let packed_len = packed_bit_len.div_ceil(8);
+    let mut bytes = Vec::with_capacity(packed_len);
+    let mut word = 0u64;
+    let mut filled = 0usize;
+    for (&x, &bit_len) in v.iter().zip(column_bits) {
+      if bytes.len() == packed_len {
+        break;
+      }
+      // fewer than 8 bits are pending, so the entry fits in the word
+      word |= (x as u64 & low_bits_mask(bit_len.min(32))) << filled;
+      filled = filled.saturating_add(bit_len);
+      while filled >= 8 && bytes.len() < packed_len {
+        bytes.push(word as u8);
+        word >>= 8;
+        filled -= 8;
+      }
+    }
+    if filled > 0 && bytes.len() < packed_len {
+      bytes.push(word as u8);
+    }
+    if let Some(last) = bytes.last_mut() {
+      if packed_bit_len % 8 != 0 {
+        *last &= low_bits_mask(packed_bit_len % 8) as u8;
+      }
+    }
+    bytes
+  }
+
+  #[deprecated(note = "use `read_bits_le` instead")]
+  pub fn u32_to_bits_le(x: u32, bit_len: usize) -> Vec<bool> {
+    (0..bit_len.min(32)).map(|i| (x >> i) & 1 == 1).collect()
+  }
+
+  #[deprecated(note = "use `bytes_from_u32_slice_with_column_bits` instead")]
   pub fn bits_to_bytes_le(bits: &[bool]) -> Vec<u8> {
     let mut bytes = vec![0u8; (bits.len() + 7) / 8];
     for (i, &bit) in bits.iter().enumerate() {
-      if bit {
-        let idx = ((i as f64) / 8f64).floor() as usize;
-        let exp = (i % 8) as u32;
-        bytes[idx] += 2u8.pow(exp);
-      }
+      bytes[i / 8] |= (bit as u8) << (i % 8);
     }
     bytes
   }
 
+  #[deprecated(note = "use `split_bits_le` instead")]
   pub fn bytes_to_bits_le(bytes: &[u8]) -> Vec<bool> {
-    bytes
-      .iter()
-      .map(|b| u8_to_bits_le(*b))
-      .collect::<Vec<Vec<bool>>>()
-      .iter()
-      .fold(Vec::new(), |mut acc, next| {
-        acc.extend(next);
-        acc
-      })
+    (0..8 * bytes.len())
+      .map(|i| (bytes[i / 8] >> (i % 8)) & 1 == 1)
+      .collect()
   }
 
+  #[deprecated(note = "use `read_bits_le` instead")]
   pub fn bits_to_u32_le(
     bits: &[bool],
   ) -> Result<u32, ErrorUnexpectedInputSize> {
-    let mut bytes = bits_to_bytes_le(bits);
-    let u32_len = std::mem::size_of::<u32>();
-    let byte_len = bytes.len();
-    if byte_len > u32_len {
+    if bits.len() > 32 {
       return Err(ErrorUnexpectedInputSize::new(format!(
-        "bytes are too long to parse as u16, length: {}",
-        byte_len
+        "bits are too long to parse as u32, length: {}",
+        bits.len()
       )));
     }
-    let padding = vec![0u8; u32_len - byte_len];
-    bytes.extend(padding);
-
-    Ok(u32::from_le_bytes(u32_sized_bytes_from_vec(bytes)?))
+    Ok(
+      bits
+        .iter()
+        .enumerate()
+        .fold(0u32, |x, (i, &bit)| x | (bit as u32) << i),
+    )
   }
 
+  #[deprecated(note = "use `<[u8; 4]>::try_from` instead")]
   pub fn u32_sized_bytes_from_vec(
     bytes: Vec<u8>,
   ) -> Result<[u8; 4], ErrorUnexpectedInputSize> {
-    let sized_vec: [u8; 4] = match bytes.try_into() {
-      Ok(b) => b,
-      Err(e) => {
-        return Err(ErrorUnexpectedInputSize::new(format!(
-          "Unexpected vector size: {:?}",
-          e,
-        )))
-      }
-    };
-
-    Ok(sized_vec)
+    bytes.try_into().map_err(|e| {
+      ErrorUnexpectedInputSize::new(format!("Unexpected vector size: {:?}", e))
+    })
   }
 
   pub fn bytes_from_u32_slice(
@@ -230,18 +1110,71 @@ pub mod format {
     entry_bit_len: usize,
     total_bit_len: usize,
   ) -> Vec<u8> {
-    let remainder = total_bit_len % entry_bit_len;
-    let mut bits = Vec::with_capacity(entry_bit_len * v.len());
-    for i in 0..v.len() {
-      // We extract either the full amount of bits, or the remainder from
-      // the last index
-      if i != v.len() - 1 {
-        bits.extend(u32_to_bits_le(v[i], entry_bit_len));
-      } else {
-        bits.extend(u32_to_bits_le(v[i], remainder));
-      }
+    // the last entry holds the remaining bits, which is a full entry if
+    // the total length is a multiple of the entry length
+    let remainder = match total_bit_len % entry_bit_len {
+      0 => entry_bit_len,
+      r => r,
+    };
+    let mut column_bits = vec![entry_bit_len; v.len()];
+    if let Some(last) = column_bits.last_mut() {
+      *last = remainder;
+    }
+    pack_bits_le(v, &column_bits, usize::MAX)
+  }
+
+  /// Same as `bytes_from_u32_slice`, for a row whose ith entry holds
+  /// `column_bits[i]` bits
+  pub fn bytes_from_u32_slice_with_column_bits(
+    v: &[u32],
+    column_bits: &[usize],
+    total_bit_len: usize,
+  ) -> Vec<u8> {
+    pack_bits_le(v, column_bits, total_bit_len)
+  }
+
+  /// Checks that `column_bits` describe a row of `width` entries, and
+  /// allocate each entry at least one bit and fewer bits than the largest
+  /// modulus, and that the entries are all needed to hold elements of
+  /// `elem_size` bits. Column bits from an untrusted source (e.g.
+  /// downloaded params) must be checked before they are used for
+  /// decoding.
+  pub fn check_column_bits(
+    column_bits: &[usize],
+    elem_size: usize,
+    width: usize,
+  ) -> Result<(), ErrorUnexpectedInputSize> {
+    let total = column_bits
+      .iter()
+      .try_fold(0usize, |total, &b| total.checked_add(b));
+    let last = column_bits.last().copied().unwrap_or(0);
+    let valid = column_bits.len() == width
+      && column_bits
+        .iter()
+        .all(|&b| (1..MAX_MODULUS_BITS as usize).contains(&b))
+      && total
+        .is_some_and(|total| total >= elem_size && total - last < elem_size);
+    if !valid {
+      return Err(ErrorUnexpectedInputSize::new(format!(
+        "column bits {:?} do not fit elements of {} bits in {} columns",
+        column_bits, elem_size, width
+      )));
+    }
+    Ok(())
+  }
+
+  /// Returns the number of bits held by each entry of a row, which is
+  /// `column_bits` if set, and otherwise `plaintext_bits` for each of the
+  /// entries encoding `elem_size` bits
+  pub fn column_bits_or_uniform(
+    column_bits: Option<&[usize]>,
+    elem_size: usize,
+    plaintext_bits: usize,
+  ) -> Vec<usize> {
+    match column_bits {
+      Some(column_bits) => column_bits.to_vec(),
+      None => vec![plaintext_bits; elem_size.div_ceil(plaintext_bits)],
     }
-    bits_to_bytes_le(&bits)
   }
 
   pub fn base64_from_u32_slice(
@@ -249,7 +1182,21 @@ pub mod format {
     entry_bit_len: usize,
     total_bit_len: usize,
   ) -> String {
-    base64::encode(bytes_from_u32_slice(v, entry_bit_len, total_bit_len))
+    encoded_from_u32_slice(
+      v,
+      entry_bit_len,
+      total_bit_len,
+      EncodingConfig::Standard,
+    )
+  }
+
+  pub fn encoded_from_u32_slice(
+    v: &[u32],
+    entry_bit_len: usize,
+    total_bit_len: usize,
+    encoding: EncodingConfig,
+  ) -> String {
+    encoding.encode(&bytes_from_u32_slice(v, entry_bit_len, total_bit_len))
   }
 
   pub fn sha256_into_u64_sized(
@@ -281,3 +1228,240 @@ pub mod format {
     Ok(key)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::files::{
+    parse_persisted, read_persisted, write_flat_matrix, write_persisted,
+    FlatMatrix, Persist, FILE_MAGIC, FILE_VERSION,
+  };
+  use super::format::*;
+  use super::lwe::{Modulus, RoundingMode};
+  use super::matrices::{vec_mult_u32_u32, ColumnMatrix};
+  use crate::test_utils::TempPath;
+  use memmap2::Mmap;
+  use rand_core::{OsRng, RngCore};
+  use serde::{Deserialize, Serialize};
+  use serde_json::{json, Value};
+  use std::sync::Arc;
+
+  #[test]
+  fn batch_rounding_matches_scalar_rounding() {
+    for bits in [16, 24, 32] {
+      let modulus = Modulus::new(bits).unwrap();
+      for plaintext_bits in [1, 10, 15] {
+        let factor = modulus.rounding_factor(plaintext_bits);
+        let floor = modulus.rounding_floor(plaintext_bits);
+        // the boundaries of the rounding intervals, around zero and the
+        // top of the modulus, and random residues
+        let mut residues: Vec<u32> = [0, floor, floor + 1, factor - 1]
+          .into_iter()
+          .flat_map(|r| [r, r.wrapping_sub(factor)])
+          .chain([u32::MAX, (modulus.value() - 1) as u32])
+          .collect();
+        residues.extend((0..256).map(|_| OsRng.next_u32()));
+        for mode in [RoundingMode::Nearest, RoundingMode::Floor] {
+          let expected: Vec<u32> = residues
+            .iter()
+            .map(|&r| modulus.round_residue(r, plaintext_bits, mode))
+            .collect();
+          assert_eq!(
+            modulus.round_residues(&residues, plaintext_bits, mode),
+            expected
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn rounding_margins_measure_distance_to_boundaries() {
+    for bits in [16, 32] {
+      let modulus = Modulus::new(bits).unwrap();
+      let factor = modulus.rounding_factor(10);
+      let floor = modulus.rounding_floor(10);
+      // residues on a scaled plaintext are furthest from a boundary, and
+      // the margin shrinks by one for every unit of noise on either side
+      for plaintext in [0, 1, 1023] {
+        let scaled = plaintext * factor;
+        assert_eq!(modulus.rounding_margin(scaled, 10), floor);
+        assert_eq!(modulus.rounding_margin(scaled + 5, 10), floor - 5);
+        let below = scaled.wrapping_sub(5);
+        assert_eq!(modulus.rounding_margin(below, 10), floor - 5);
+        assert_eq!(modulus.rounding_margin(scaled + floor, 10), 0);
+      }
+    }
+  }
+
+  #[test]
+  fn bits_are_split_and_packed() {
+    let mut bytes = vec![0u8; 23];
+    OsRng.fill_bytes(&mut bytes);
+    let bit = |i: usize| (bytes.get(i / 8).unwrap_or(&0) >> (i % 8)) & 1;
+    for (start, bit_len) in [(0, 0), (0, 32), (3, 10), (13, 32), (180, 9)] {
+      let expected =
+        (0..bit_len).fold(0u32, |x, k| x | (bit(start + k) as u32) << k);
+      assert_eq!(read_bits_le(&bytes, start, bit_len).unwrap(), expected);
+    }
+    assert!(read_bits_le(&bytes, 0, 33).is_err());
+
+    let elem_size = 8 * bytes.len();
+    for plaintext_bits in [1, 7, 8, 10, 16] {
+      let width = elem_size.div_ceil(plaintext_bits);
+      let row = split_bits_le(&bytes, &vec![plaintext_bits; width]).unwrap();
+      assert!(row.iter().all(|&x| x < 1 << plaintext_bits));
+      assert_eq!(bytes_from_u32_slice(&row, plaintext_bits, elem_size), bytes);
+    }
+    let column_bits = [10, 1, 3, 32, 20, 9, 8, 20, 20, 20, 20, 20, 7];
+    let row = split_bits_le(&bytes, &column_bits).unwrap();
+    let packed =
+      bytes_from_u32_slice_with_column_bits(&row, &column_bits, elem_size);
+    assert_eq!(packed, bytes);
+  }
+
+  #[test]
+  fn oversized_column_bits_do_not_overflow() {
+    let packed = bytes_from_u32_slice_with_column_bits(
+      &[0xff, 0xff],
+      &[64, usize::MAX],
+      12,
+    );
+    assert_eq!(packed, vec![0xff, 0x00]);
+    assert_eq!(read_bits_le(&[0xff], usize::MAX - 3, 8).unwrap(), 0);
+  }
+
+  #[test]
+  #[allow(deprecated)]
+  fn deprecated_bit_conversions_round_trip() {
+    let bits = u32_to_bits_le(0x16d, 9);
+    assert_eq!(bits_to_u32_le(&bits).unwrap(), 0x16d);
+    let bytes = bits_to_bytes_le(&bits);
+    assert_eq!(bytes, vec![0x6d, 0x01]);
+    assert_eq!(bytes_to_bits_le(&bytes)[..9], bits[..]);
+    assert!(bits_to_u32_le(&[false; 33]).is_err());
+    assert_eq!(
+      u32_sized_bytes_from_vec(vec![1, 2, 3, 4]).unwrap(),
+      [1, 2, 3, 4]
+    );
+    assert!(u32_sized_bytes_from_vec(vec![1, 2, 3]).is_err());
+  }
+
+  #[test]
+  fn flat_matrices_are_mapped_in_place() {
+    let matrix = vec![vec![1u32, 2, 3], vec![4, 5, 6]];
+    // an unaligned copy of the matrix follows an aligned one
+    let mut bytes = Vec::new();
+    write_flat_matrix(&mut bytes, &matrix).unwrap();
+    bytes.push(0);
+    write_flat_matrix(&mut bytes, &matrix).unwrap();
+    let path = TempPath::new("flat-matrix.bin");
+    std::fs::write(&path, &bytes).unwrap();
+    let file = std::fs::File::open(&path).unwrap();
+    let map = Arc::new(unsafe { Mmap::map(&file) }.unwrap());
+
+    let aligned = FlatMatrix::parse(&map, 0).unwrap();
+    let unaligned = FlatMatrix::parse(&map, aligned.end() + 1).unwrap();
+    assert_eq!(unaligned.end(), map.len());
+    let mapped = ColumnMatrix::mapped(map.clone(), aligned);
+    assert_eq!(
+      matches!(mapped, ColumnMatrix::Mapped { .. }),
+      cfg!(target_endian = "little")
+    );
+    let copied = ColumnMatrix::mapped(map.clone(), unaligned);
+    assert!(matches!(copied, ColumnMatrix::Owned(_)));
+    for columns in [mapped, copied] {
+      assert_eq!(columns.len(), 2);
+      assert_eq!(&columns[1], &[4, 5, 6]);
+      assert_eq!(columns.to_vecs(), matrix);
+    }
+
+    // lengths beyond the end of the buffer are rejected
+    assert!(FlatMatrix::parse(&map[..aligned.end() - 1], 0).is_none());
+    assert!(FlatMatrix::parse(&map, map.len() - 8).is_none());
+    let mut huge = bytes.clone();
+    huge[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(FlatMatrix::parse(&huge, 0).is_none());
+  }
+
+  #[derive(Debug, PartialEq, Serialize, Deserialize)]
+  struct Sample {
+    values: Vec<u32>,
+  }
+  impl Persist for Sample {
+    const KIND: &'static str = "Sample";
+
+    fn summary(&self) -> Value {
+      json!({ "len": self.values.len() })
+    }
+  }
+
+  #[derive(Debug, Serialize, Deserialize)]
+  struct OtherSample {
+    values: Vec<u32>,
+  }
+  impl Persist for OtherSample {
+    const KIND: &'static str = "OtherSample";
+
+    fn summary(&self) -> Value {
+      json!({ "len": self.values.len() })
+    }
+  }
+
+  #[test]
+  fn persisted_files_are_validated() {
+    let sample = Sample {
+      values: vec![1, 2, 3],
+    };
+    let temp = TempPath::new("persisted.json");
+    let path = temp.as_str();
+    write_persisted(path, &sample).unwrap();
+    assert_eq!(read_persisted::<Sample>(path).unwrap(), sample);
+    let bytes = std::fs::read(path).unwrap();
+    let file: Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(file["header"]["magic"], FILE_MAGIC);
+    assert_eq!(file["header"]["kind"], Sample::KIND);
+    assert_eq!(file["header"]["summary"], sample.summary());
+
+    // files of other kinds, versions or with modified contents fail
+    let err = parse_persisted::<OtherSample>(path, &bytes).unwrap_err();
+    assert!(err.to_string().contains("expected a OtherSample"));
+    let modified = |edit: &dyn Fn(&mut Value)| {
+      let mut file = file.clone();
+      edit(&mut file);
+      let bytes = serde_json::to_vec(&file).unwrap();
+      parse_persisted::<Sample>(path, &bytes)
+        .unwrap_err()
+        .to_string()
+    };
+    assert!(
+      modified(&|f| f["header"]["magic"] = json!("other")).contains("magic")
+    );
+    assert!(
+      modified(&|f| f["header"]["version"] = json!(FILE_VERSION + 1))
+        .contains("unsupported file version")
+    );
+    assert!(modified(&|f| f["body"]["values"][0] = json!(7))
+      .contains("checksum mismatch"));
+    assert!(modified(&|f| f["header"]["summary"]["len"] = json!(4))
+      .contains("checksum mismatch"));
+
+    // files written before the header was introduced are loaded
+    let bare = serde_json::to_vec(&sample).unwrap();
+    assert_eq!(parse_persisted::<Sample>(path, &bare).unwrap(), sample);
+    assert!(parse_persisted::<Sample>(path, b"{\"values\":").is_err());
+  }
+
+  #[test]
+  fn inner_products_wrap_modulo_2_32() {
+    let row: Vec<u32> = (0..1024).map(|_| OsRng.next_u32()).collect();
+    let mut col: Vec<u32> = (0..1024).map(|_| OsRng.next_u32()).collect();
+    col[0] = u32::MAX;
+    let exact: u128 = row
+      .iter()
+      .zip(&col)
+      .map(|(&r, &c)| r as u128 * c as u128)
+      .sum();
+    assert_eq!(vec_mult_u32_u32(&row, &col).unwrap(), exact as u32);
+    assert!(vec_mult_u32_u32(&row, &col[1..]).is_err());
+  }
+}
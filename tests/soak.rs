@@ -0,0 +1,201 @@
+//! Soak test for the respond path of long-running servers.
+//!
+//! Runs a `ServingShard` under sustained concurrent query load, and
+//! reports the latency percentiles of responses along with the growth of
+//! the resident memory of the process, failing if any response is wrong
+//! or if memory keeps growing after a warm-up period (e.g. because of a
+//! leak or fragmentation in the respond path).
+//!
+//! The load is configured through environment variables:
+//!
+//! ```text
+//! SOAK_DURATION_SECS=2 (how long the load is sustained)
+//! SOAK_CLIENTS=4 (the number of concurrent client threads)
+//! SOAK_MAX_RSS_GROWTH_MB=64 (the allowed memory growth after warm-up)
+//! ```
+//!
+//! The defaults keep the test short enough for `cargo test`. For an
+//! actual soak run, showing the report, e.g.:
+//!
+//! ```text
+//! SOAK_DURATION_SECS=3600 SOAK_CLIENTS=16 \
+//!   cargo test --release --test soak -- --nocapture
+//! ```
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use keyword_pir_lwe::api::*;
+use keyword_pir_lwe::serving::{ServingConfig, ServingShard};
+use rand_core::{OsRng, RngCore};
+
+/// The number of distinct queries that the clients cycle through
+const NUM_QUERIES: usize = 32;
+
+/// The fraction of the duration after which memory is expected to be
+/// stable
+const WARM_UP_FRACTION: u32 = 4;
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+  env::var(name)
+    .ok()
+    .and_then(|v| v.parse().ok())
+    .unwrap_or(default)
+}
+
+/// Returns the resident set size of the process in kB, if available
+fn rss_kb() -> Option<u64> {
+  let status = fs::read_to_string("/proc/self/status").ok()?;
+  let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+  line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Returns the `p`th percentile of the sorted `latencies`
+fn percentile(latencies: &[Duration], p: usize) -> Duration {
+  latencies[(latencies.len() - 1) * p / 100]
+}
+
+#[test]
+fn respond_path_survives_sustained_load() {
+  let duration = Duration::from_secs(env_or("SOAK_DURATION_SECS", 2));
+  let clients: usize = env_or("SOAK_CLIENTS", 4);
+  let max_growth_kb = env_or("SOAK_MAX_RSS_GROWTH_MB", 64u64) * 1024;
+
+  let elem_size = 2u32.pow(8) as usize;
+  let eles: Vec<String> = (0..256)
+    .map(|_| {
+      let mut ele = vec![0u8; elem_size / 8];
+      OsRng.fill_bytes(&mut ele);
+      base64::encode(ele)
+    })
+    .collect();
+  let shard =
+    Shard::from_base64_strings(&eles, 512, eles.len(), elem_size, 10).unwrap();
+  let bp = shard.get_base_params().clone();
+  let cp = CommonParams::from(&bp);
+
+  // each query is checked once, and then replayed by the clients
+  let queries: Vec<(usize, Query, DecodeKey)> = (0..NUM_QUERIES)
+    .map(|i| {
+      let row = (i * 7) % eles.len();
+      // fresh params are drawn if adding the query indicator overflows
+      let (q, dk) = (0..8)
+        .find_map(|_| {
+          let qp = generate_index_query_params(&cp, &bp).unwrap();
+          qp.into_query(row).ok()
+        })
+        .unwrap();
+      (row, q, dk)
+    })
+    .collect();
+  let server =
+    Arc::new(ServingShard::new(shard, &ServingConfig::default()).unwrap());
+  let queries = Arc::new(queries);
+  let eles = Arc::new(eles);
+
+  let start = Instant::now();
+  let warm_up = duration / WARM_UP_FRACTION;
+  let handles: Vec<_> = (0..clients)
+    .map(|c| {
+      let (server, queries, eles) =
+        (server.clone(), queries.clone(), eles.clone());
+      thread::spawn(move || {
+        let mut latencies = Vec::new();
+        let mut i = c;
+        while start.elapsed() < duration {
+          let (row, q, dk) = &queries[i % queries.len()];
+          let sent = Instant::now();
+          let resp = server.respond(q).unwrap();
+          latencies.push(sent.elapsed());
+          if i < queries.len() {
+            let resp: Response = bincode::deserialize(&resp).unwrap();
+            let decoded =
+              dk.clone().into_index_params().parse_resp_as_base64(&resp);
+            assert_eq!(decoded, eles[*row]);
+          }
+          i += clients;
+        }
+        latencies
+      })
+    })
+    .collect();
+
+  // sample memory while the clients are running
+  let mut warm_rss = None;
+  let mut peak_rss = 0;
+  while start.elapsed() < duration {
+    thread::sleep(Duration::from_millis(50));
+    if let Some(rss) = rss_kb() {
+      if start.elapsed() >= warm_up && warm_rss.is_none() {
+        warm_rss = Some(rss);
+      }
+      peak_rss = peak_rss.max(rss);
+    }
+  }
+
+  let mut latencies: Vec<Duration> = handles
+    .into_iter()
+    .flat_map(|h| h.join().expect("client thread panicked"))
+    .collect();
+  latencies.sort();
+  assert!(!latencies.is_empty(), "no queries were answered");
+  let elapsed = start.elapsed().as_secs_f64();
+  println!(
+    "soak: {} clients, {} queries in {:.1}s ({:.0} queries/s)",
+    clients,
+    latencies.len(),
+    elapsed,
+    latencies.len() as f64 / elapsed
+  );
+  println!(
+    "soak: latency p50: {:?}, p90: {:?}, p99: {:?}, max: {:?}",
+    percentile(&latencies, 50),
+    percentile(&latencies, 90),
+    percentile(&latencies, 99),
+    latencies[latencies.len() - 1]
+  );
+  match warm_rss {
+    Some(warm_rss) => {
+      let growth = peak_rss.saturating_sub(warm_rss);
+      println!(
+        "soak: RSS after warm-up: {} kB, peak: {} kB, growth: {} kB",
+        warm_rss, peak_rss, growth
+      );
+      assert!(
+        growth <= max_growth_kb,
+        "memory grew by {} kB after warm-up (limit: {} kB)",
+        growth,
+        max_growth_kb
+      );
+    }
+    None => println!("soak: RSS is not available, skipping the memory check"),
+  }
+}
+
+#[test]
+fn percentiles_index_sorted_latencies() {
+  let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+  assert_eq!(percentile(&latencies, 0), Duration::from_millis(1));
+  assert_eq!(percentile(&latencies, 50), Duration::from_millis(50));
+  assert_eq!(percentile(&latencies, 99), Duration::from_millis(99));
+  assert_eq!(percentile(&latencies, 100), Duration::from_millis(100));
+  let single = [Duration::from_millis(7)];
+  assert_eq!(percentile(&single, 50), single[0]);
+}
+
+#[test]
+fn settings_fall_back_to_defaults() {
+  env::set_var("SOAK_TEST_SETTING_VALID", "16");
+  env::set_var("SOAK_TEST_SETTING_INVALID", "many");
+  assert_eq!(env_or("SOAK_TEST_SETTING_VALID", 4usize), 16);
+  assert_eq!(env_or("SOAK_TEST_SETTING_INVALID", 4usize), 4);
+  assert_eq!(env_or("SOAK_TEST_SETTING_UNSET", 2u64), 2);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn rss_is_read_from_procfs() {
+  assert!(rss_kb().is_some_and(|rss| rss > 0));
+}